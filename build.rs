@@ -1,12 +1,52 @@
 fn main() {
     extern crate cc;
 
-    cc::Build::new()
-        .cuda(true)
-        .flag("-cudart=shared")
+    // Compute capabilities of the target GPUs, e.g. 89 for Ada or 80,90 for
+    // Ampere and Hopper. Machine code is embedded for each of them and PTX for
+    // the newest, which the driver compiles for later GPUs.
+    let mut archs = std::env::var("CUDA_ARCH")
+        .unwrap_or_else(|_| "70,75,80,86,89,90".to_string())
+        .split(',')
+        .map(|arch| {
+            arch.trim()
+                .parse::<u32>()
+                .expect("CUDA_ARCH should be like 89 or 80,86,90")
+        })
+        .collect::<Vec<_>>();
+    archs.sort();
+    archs.dedup();
+    // Limb width of the bn254 scalar field, shared by all of the archs. The
+    // 32-bit limbs are tuned for the full speed 32-bit IMAD of Volta and later
+    // but stay opt-in until they are checked against the 64-bit path end to end.
+    let limbs = std::env::var("ZKWASM_FR_LIMBS")
+        .map(|limbs| {
+            limbs
+                .parse::<u32>()
+                .expect("ZKWASM_FR_LIMBS should be 32 or 64")
+        })
+        .unwrap_or(64);
+    assert!(
+        limbs == 32 || limbs == 64,
+        "ZKWASM_FR_LIMBS should be 32 or 64"
+    );
+
+    let mut build = cc::Build::new();
+    build.cuda(true).flag("-cudart=shared");
+    for arch in archs.iter() {
+        build
+            .flag("-gencode")
+            .flag(&format!("arch=compute_{},code=sm_{}", arch, arch));
+    }
+    let newest = archs[archs.len() - 1];
+    build
         .flag("-gencode")
-        .flag("arch=compute_89,code=sm_89")
+        .flag(&format!("arch=compute_{},code=compute_{}", newest, newest));
+    if limbs == 64 {
+        build.define("BN254_FR_LIMB64", None);
+    }
+    build
         .file("cuda/bn254.cu")
+        .file("cuda/pasta.cu")
         .compile("libzkwasm_prover_kernel.a");
 
     /* Link CUDA Runtime (libcudart.so) */
@@ -15,11 +55,37 @@ fn main() {
     // - This path depends on where you install CUDA (i.e. depends on your Linux distribution)
     // - This should be set by `$LIBRARY_PATH`
     println!("cargo:rerun-if-changed={}", "cuda");
+    println!("cargo:rerun-if-env-changed=CUDA_ARCH");
+    println!("cargo:rerun-if-env-changed=ZKWASM_FR_LIMBS");
     println!("cargo:rustc-link-search=native=/usr/local/cuda/lib64");
     println!("cargo:rustc-link-lib=cudart");
+    // checked against the devices at runtime, see CudaDevice::check_kernel_arch
+    println!(
+        "cargo:rustc-env=ZKWASM_CUDA_ARCHS={}",
+        archs
+            .iter()
+            .map(|arch| arch.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    // read by the kernels compiled at runtime, see src/cuda/jit.rs
+    println!("cargo:rustc-env=ZKWASM_FR_LIMBS={}", limbs);
+
+    if std::env::var_os("CARGO_FEATURE_NVRTC").is_some() {
+        println!("cargo:rustc-link-lib=nvrtc");
+        println!("cargo:rustc-link-search=native=/usr/local/cuda/lib64/stubs");
+        println!("cargo:rustc-link-lib=cuda");
+    }
+
+    #[cfg(feature = "server")]
+    {
+        println!("cargo:rerun-if-changed=proto");
+        tonic_build::compile_protos("proto/prover.proto")
+            .expect("fail to compile proto/prover.proto");
+    }
 
     /* Optional: Link CUDA Driver API (libcuda.so) */
 
     // println!("cargo:rustc-link-search=native=/usr/local/cuda/lib64/stub");
     // println!("cargo:rustc-link-lib=cuda");
-}
\ No newline at end of file
+}
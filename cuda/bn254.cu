@@ -4,7 +4,7 @@
 #include <assert.h>
 #include <cuda/semaphore>
 
-#include "zprize_ff_wrapper.cuh"
+#include "bn254_fr.cuh"
 
 #if false
 #include "ec.cuh"
@@ -14,14 +14,195 @@ typedef Curve<Bn254FpField> Bn254G1;
 #include "zprize_ec_wrapper.cuh"
 #endif
 
-__global__ void _eval_lookup_z_step1(
-    Bn254FrField *z,
-    const Bn254FrField *permuted_input,
-    const Bn254FrField *permuted_table,
-    Bn254FrField *beta_gamma)
+// res = (a + beta) * (b + gamma), or res *= (a + beta) * (b + gamma) with accumulate
+__global__ void _field_beta_gamma_mul(
+    Bn254FrField *res,
+    const Bn254FrField *a,
+    const Bn254FrField *b,
+    const Bn254FrField *beta_gamma,
+    bool accumulate)
 {
     int i = blockIdx.x * blockDim.x + threadIdx.x;
-    z[i] = (permuted_input[i] + beta_gamma[0]) * (permuted_table[i] + beta_gamma[1]);
+    Bn254FrField t = (a[i] + beta_gamma[0]) * (b[i] + beta_gamma[1]);
+    res[i] = accumulate ? res[i] * t : t;
+}
+
+// res = a * x + b + y, or res *= a * x + b + y unless first, with a_b = [a, b]
+__global__ void _permutation_product_step(
+    Bn254FrField *res,
+    const Bn254FrField *x,
+    const Bn254FrField *y,
+    const Bn254FrField *a_b,
+    bool first)
+{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    Bn254FrField t = a_b[0] * x[i] + a_b[1] + y[i];
+    res[i] = first ? t : res[i] * t;
+}
+
+// Order of the raw limbs, the order the host sorts lookup columns by (compare_scalar).
+__device__ int _scalar_cmp(const Bn254FrField *a, const Bn254FrField *b)
+{
+    const unsigned long long *x = (const unsigned long long *)a;
+    const unsigned long long *y = (const unsigned long long *)b;
+    for (int k = 0; k < 4; k++)
+    {
+        if (x[k] != y[k])
+        {
+            return x[k] < y[k] ? -1 : 1;
+        }
+    }
+    return 0;
+}
+
+// Copies the first occurrences of the sorted input to the permuted table. flags[i] bit 0 marks
+// row i as a hole (the input repeats the previous row), bit 1 marks table[i] as left over (it is
+// not the first occurrence of an input value in the sorted table).
+__global__ void _lookup_table_flags(
+    Bn254FrField *permuted_table,
+    const Bn254FrField *input,
+    const Bn254FrField *table,
+    unsigned char *flags,
+    int n)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    int worker = blockDim.x * gridDim.x;
+
+    for (int i = gid; i < n; i += worker)
+    {
+        unsigned char flag = 0;
+        if (i > 0 && _scalar_cmp(&input[i], &input[i - 1]) == 0)
+        {
+            flag |= 1;
+        }
+        else
+        {
+            permuted_table[i] = input[i];
+        }
+
+        bool used = false;
+        if (i == 0 || _scalar_cmp(&table[i], &table[i - 1]) != 0)
+        {
+            int lo = 0;
+            int hi = n;
+            while (lo < hi)
+            {
+                int mid = lo + (hi - lo) / 2;
+                if (_scalar_cmp(&input[mid], &table[i]) < 0)
+                {
+                    lo = mid + 1;
+                }
+                else
+                {
+                    hi = mid;
+                }
+            }
+            used = lo < n && _scalar_cmp(&input[lo], &table[i]) == 0;
+        }
+        if (!used)
+        {
+            flag |= 2;
+        }
+        flags[i] = flag;
+    }
+}
+
+// counts[2 * s] and counts[2 * s + 1] = holes and left over entries of the rows [s * seg_len, (s + 1) * seg_len)
+__global__ void _lookup_table_count(
+    const unsigned char *flags,
+    int *counts,
+    int n,
+    int seg_len,
+    int segments)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    int worker = blockDim.x * gridDim.x;
+
+    for (int s = gid; s < segments; s += worker)
+    {
+        int holes = 0;
+        int left = 0;
+        int end = min(n, (s + 1) * seg_len);
+        for (int i = s * seg_len; i < end; i++)
+        {
+            holes += flags[i] & 1;
+            left += (flags[i] >> 1) & 1;
+        }
+        counts[2 * s] = holes;
+        counts[2 * s + 1] = left;
+    }
+}
+
+// Exclusive prefix sums of the segment counts in place, the total numbers of holes and of
+// left over entries in counts[2 * segments] and counts[2 * segments + 1].
+__global__ void _lookup_table_scan(int *counts, int segments)
+{
+    int holes = 0;
+    int left = 0;
+    for (int s = 0; s < segments; s++)
+    {
+        int h = counts[2 * s];
+        int l = counts[2 * s + 1];
+        counts[2 * s] = holes;
+        counts[2 * s + 1] = left;
+        holes += h;
+        left += l;
+    }
+    counts[2 * segments] = holes;
+    counts[2 * segments + 1] = left;
+}
+
+// Rows of the holes and of the left over table entries, both in row order.
+__global__ void _lookup_table_compact(
+    const unsigned char *flags,
+    const int *counts,
+    int *holes,
+    int *left,
+    int n,
+    int seg_len,
+    int segments)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    int worker = blockDim.x * gridDim.x;
+
+    for (int s = gid; s < segments; s += worker)
+    {
+        int h = counts[2 * s];
+        int l = counts[2 * s + 1];
+        int end = min(n, (s + 1) * seg_len);
+        for (int i = s * seg_len; i < end; i++)
+        {
+            if (flags[i] & 1)
+            {
+                holes[h++] = i;
+            }
+            if (flags[i] & 2)
+            {
+                left[l++] = i;
+            }
+        }
+    }
+}
+
+// Fills the k-th hole of the permuted table with the k-th left over table entry. The counts
+// differ when an input is missing from the table, the caller rejects that, only the rows
+// both of them have are written.
+__global__ void _lookup_table_scatter(
+    Bn254FrField *permuted_table,
+    const Bn254FrField *table,
+    const int *counts,
+    const int *holes,
+    const int *left,
+    int segments)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    int worker = blockDim.x * gridDim.x;
+    int total = min(counts[2 * segments], counts[2 * segments + 1]);
+
+    for (int k = gid; k < total; k += worker)
+    {
+        permuted_table[holes[k]] = table[left[k]];
+    }
 }
 
 __global__ void _eval_lookup_z_batch_invert(
@@ -50,15 +231,6 @@ __global__ void _eval_lookup_z_batch_invert(
     }
 }
 
-__global__ void _eval_lookup_z_step2(
-    Bn254FrField *input,
-    Bn254FrField *table,
-    Bn254FrField *beta_gamma)
-{
-    int i = blockIdx.x * blockDim.x + threadIdx.x;
-    input[i] = (input[i] + beta_gamma[0]) * (table[i] + beta_gamma[1]);
-}
-
 __global__ void _eval_lookup_z_step3(
     Bn254FrField *z,
     Bn254FrField *input,
@@ -165,6 +337,33 @@ __global__ void _msm_unmont(
     }
 }
 
+// Signed 8-bit digits in [-128, 127]: a digit of d and -d shares a bucket, the point being
+// negated, so a window needs 128 buckets instead of 255.
+#define MSM_BUCKETS 128
+
+// Recodes every unmont scalar into `windows` signed digits, window-major. A digit above 127
+// borrows 256 from the next window, the extra window taking the carry out of the top byte.
+__global__ void _msm_signed_digits(
+    const Bn254FrField *unmont_scalars,
+    signed char *digits,
+    int n,
+    int windows)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    int worker = blockDim.x * gridDim.x;
+
+    for (int i = gid; i < n; i += worker)
+    {
+        int carry = 0;
+        for (int w = 0; w < windows; w++)
+        {
+            int d = (int)unmont_scalars[i].get_8bits(w) + carry;
+            carry = d >= 128;
+            digits[w * n + i] = (signed char)(carry ? d - 256 : d);
+        }
+    }
+}
+
 __global__ void _msm_merge_groups(
     Bn254G1 *tmp_res,
     int groups)
@@ -174,11 +373,11 @@ __global__ void _msm_merge_groups(
     int inner_worker = blockDim.x;
     int shift_idx = blockIdx.y * inner_worker + inner_idx;
 
-    int start = window_idx * groups * 256;
+    int start = window_idx * groups * MSM_BUCKETS;
 
     for (int i = 1; i < groups; i++)
     {
-        tmp_res[start + shift_idx] = tmp_res[start + shift_idx] + tmp_res[start + i * 256 + shift_idx];
+        tmp_res[start + shift_idx] = tmp_res[start + shift_idx] + tmp_res[start + i * MSM_BUCKETS + shift_idx];
     }
 }
 
@@ -192,11 +391,11 @@ __global__ void _msm_merge_groups_v2(
     int inner_worker = blockDim.x;
     int inner_idx = threadIdx.x;
 
-    int start = window_idx * groups * 256 + inner_idx * 256 + shift_idx;
+    int start = window_idx * groups * MSM_BUCKETS + inner_idx * MSM_BUCKETS + shift_idx;
 
     for (int i = inner_worker; i + inner_idx < groups; i += inner_worker)
     {
-        tmp_res[start] = tmp_res[start] + tmp_res[start + i * 256];
+        tmp_res[start] = tmp_res[start] + tmp_res[start + i * MSM_BUCKETS];
     }
 
     __syncthreads();
@@ -204,7 +403,7 @@ __global__ void _msm_merge_groups_v2(
     {
         for (int i = 1; i < inner_worker; i++)
         {
-            tmp_res[start] = tmp_res[start] + tmp_res[start + i * 256];
+            tmp_res[start] = tmp_res[start] + tmp_res[start + i * MSM_BUCKETS];
         }
     }
 }
@@ -216,11 +415,11 @@ __global__ void _msm_merge_inner(
     int window_idx = threadIdx.x;
     int windows = blockDim.x;
 
-    int start = window_idx * groups * 256;
+    int start = window_idx * groups * MSM_BUCKETS;
 
-    Bn254G1 *round = &tmp_res[start + 254];
+    Bn254G1 *round = &tmp_res[start + MSM_BUCKETS - 1];
     Bn254G1 acc = *round;
-    for (int i = 253; i >= 0; i--)
+    for (int i = MSM_BUCKETS - 2; i >= 0; i--)
     {
         *round = *round + tmp_res[start + i];
         acc = acc + *round;
@@ -230,7 +429,7 @@ __global__ void _msm_merge_inner(
     __syncthreads();
     if (window_idx == 0)
     {
-        Bn254G1 res = tmp_res[(windows - 1) * groups * 256];
+        Bn254G1 res = tmp_res[(windows - 1) * groups * MSM_BUCKETS];
         for (int j = windows - 2; j >= 0; j--)
         {
             for (int i = 0; i < 8; i++)
@@ -238,82 +437,148 @@ __global__ void _msm_merge_inner(
                 res = res.ec_double();
             }
 
-            res = res + tmp_res[j * groups * 256];
+            res = res + tmp_res[j * groups * MSM_BUCKETS];
         }
         tmp_res[0] = res;
     }
 }
 
-__global__ void _msm_core(
+// Double-and-add msm for inputs too short to amortize the bucket setup of _msm_core.
+// Each thread accumulates a strided subset, thread 0 sums the partial results into tmp_res[0].
+__global__ void _msm_tiny(
     const Bn254G1Affine *p,
     Bn254FrField *s,
     Bn254G1 *tmp_res,
     int n)
 {
-    int inner_worker = blockDim.x;
-    int groups = gridDim.y * inner_worker;
-    int size_per_worker = (n + groups - 1) / groups;
-    int window_idx = blockIdx.x;
-    int inner_idx = threadIdx.x;
-    int group_idx = blockIdx.y * inner_worker + inner_idx;
-    int start = group_idx * size_per_worker;
-    int end = start + size_per_worker;
-    end = end > n ? n : end;
-
-    Bn254G1 *buckets = &tmp_res[window_idx * groups * 256 + group_idx * 256];
+    int worker_idx = threadIdx.x;
+    int workers = blockDim.x;
 
-#if true
-    for (int i = start; i < end; i++)
+    Bn254G1 acc = tmp_res[worker_idx];
+    for (int bit = 253; bit >= 0; bit--)
     {
-        int v = s[i].get_8bits(window_idx);
-        if (v--)
+        acc = acc.ec_double();
+        for (int i = worker_idx; i < n; i += workers)
         {
-            buckets[v] = buckets[v] + p[i];
+            if ((s[i].get_8bits(bit >> 3) >> (bit & 7)) & 1)
+            {
+                acc = acc + p[i];
+            }
         }
     }
+    tmp_res[worker_idx] = acc;
 
-#else
-    const int BATCH = 4;
-
-    __shared__ Bn254G1 tmp[16];
-    unsigned short idx[256][BATCH];
-    unsigned char count[256] = {0};
-
-    for (int i = start; i < end; i++)
+    __syncthreads();
+    if (worker_idx == 0)
     {
-        *((volatile int *)&s[i]);
-        int v = s[i].get_8bits(window_idx);
-        if (v--)
+        for (int i = 1; i < workers; i++)
         {
+            acc = acc + tmp_res[i];
+        }
+        tmp_res[0] = acc;
+    }
+}
 
-            idx[v][count[v]++] = i - start;
+// Moves the points of nonzero scalars to the front of out_p/out_s, those of scalars equal to
+// one to ones_p. counts[0] and counts[1] end up with the totals, writes past capacity are dropped.
+__global__ void _msm_sparse_compact(
+    const Bn254G1Affine *p,
+    const Bn254FrField *s,
+    int n,
+    Bn254G1Affine *out_p,
+    Bn254FrField *out_s,
+    Bn254G1Affine *ones_p,
+    int *counts,
+    int capacity)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    int worker = blockDim.x * gridDim.x;
+    const Bn254FrField one(1);
 
-            if (count[v] == BATCH)
+    for (int i = gid; i < n; i += worker)
+    {
+        if (s[i].is_zero())
+        {
+            continue;
+        }
+        if (s[i] == one)
+        {
+            int idx = atomicAdd(&counts[1], 1);
+            if (idx < capacity)
             {
-                tmp[inner_idx] = buckets[v];
-                for (int j = 0; j < BATCH; j++)
-                {
-                    tmp[inner_idx] = tmp[inner_idx] + p[start + idx[v][j]];
-                }
-                buckets[v] = tmp[inner_idx];
-                count[v] = 0;
+                ones_p[idx] = p[i];
             }
         }
+        else
+        {
+            int idx = atomicAdd(&counts[0], 1);
+            if (idx < capacity)
+            {
+                out_p[idx] = p[i];
+                out_s[idx] = s[i];
+            }
+        }
+    }
+}
+
+// Sum of the points, each thread accumulates a strided subset, thread 0 sums the partial results into tmp_res[0].
+__global__ void _msm_sum_points(
+    const Bn254G1Affine *p,
+    Bn254G1 *tmp_res,
+    int n)
+{
+    int worker_idx = threadIdx.x;
+    int workers = blockDim.x;
+
+    Bn254G1 acc = tmp_res[worker_idx];
+    for (int i = worker_idx; i < n; i += workers)
+    {
+        acc = acc + p[i];
     }
+    tmp_res[worker_idx] = acc;
 
-    for (int i = 0; i < 255; i++)
+    __syncthreads();
+    if (worker_idx == 0)
     {
-        if (count[i] > 0)
+        for (int i = 1; i < workers; i++)
         {
-            tmp[inner_idx] = buckets[i];
-            for (int j = 0; j < count[i]; j++)
-            {
-                tmp[inner_idx] = tmp[inner_idx] + p[start + idx[i][j]];
-            }
-            buckets[i] = tmp[inner_idx];
+            acc = acc + tmp_res[i];
+        }
+        tmp_res[0] = acc;
+    }
+}
+
+__global__ void _msm_core(
+    const Bn254G1Affine *p,
+    const signed char *digits,
+    Bn254G1 *tmp_res,
+    int n)
+{
+    int inner_worker = blockDim.x;
+    int groups = gridDim.y * inner_worker;
+    int size_per_worker = (n + groups - 1) / groups;
+    int window_idx = blockIdx.x;
+    int inner_idx = threadIdx.x;
+    int group_idx = blockIdx.y * inner_worker + inner_idx;
+    int start = group_idx * size_per_worker;
+    int end = start + size_per_worker;
+    end = end > n ? n : end;
+
+    Bn254G1 *buckets = &tmp_res[window_idx * groups * MSM_BUCKETS + group_idx * MSM_BUCKETS];
+    const signed char *window_digits = &digits[window_idx * n];
+
+    for (int i = start; i < end; i++)
+    {
+        int d = window_digits[i];
+        if (d > 0)
+        {
+            buckets[d - 1] = buckets[d - 1] + p[i];
+        }
+        else if (d < 0)
+        {
+            buckets[-d - 1] = buckets[-d - 1] - p[i];
         }
     }
-#endif
 }
 
 __device__ uint bit_reverse(uint n, uint bits)
@@ -341,18 +606,37 @@ __device__ Bn254FrField pow_lookup(const Bn254FrField *bases, uint exponent)
     return res;
 }
 
+static_assert(sizeof(Bn254FrField) == 32, "twiddles are loaded as two 16-byte words");
+
+// Twiddle read through the read-only data cache. The threads of a warp read
+// different twiddles, which constant memory would serialize, while the cache
+// keeps the pq table and the low powers of omega resident across the rounds.
+__device__ __forceinline__ Bn254FrField ldg_field(const Bn254FrField *p)
+{
+    Bn254FrField res;
+    const uint4 *src = (const uint4 *)p;
+    uint4 *dst = (uint4 *)&res;
+    dst[0] = __ldg(src);
+    dst[1] = __ldg(src + 1);
+    return res;
+}
+
 // Learn from ec-gpu
 __global__ void _ntt_core(
     const Bn254FrField *_x,
     Bn254FrField *_y,
-    const Bn254FrField *pq,
-    const Bn254FrField *omegas,
+    const Bn254FrField *__restrict__ pq,
+    const Bn254FrField *__restrict__ omegas,
     uint n,     // Number of elements
     uint log_p, // Log2 of `p` (Read more in the link above)
     uint deg,   // 1=>radix2, 2=>radix4, 3=>radix8, ...
     uint max_deg,
     uint grids) // Maximum degree supported, according to `pq` and `omegas`
 {
+    // the columns of a batch are back to back, one per grid row
+    _x += (size_t)blockIdx.y * n;
+    _y += (size_t)blockIdx.y * n;
+
     uint lid = threadIdx.x;
     uint lsize = blockDim.x;
     uint t = n >> deg;
@@ -377,7 +661,7 @@ __global__ void _ntt_core(
         uint base_exp = (n >> log_p >> deg) * k;
         for (uint i = counts; i < counte; i++)
         {
-            u[i] = omegas[base_exp * i] * x[i * t];
+            u[i] = ldg_field(&omegas[base_exp * i]) * x[i * t];
         }
         __syncthreads();
 
@@ -394,7 +678,7 @@ __global__ void _ntt_core(
                 u[i1] = tmp - u[i1];
 
                 if (di != 0)
-                    u[i1] = pq[di << rnd << pqshift] * u[i1];
+                    u[i1] = ldg_field(&pq[di << rnd << pqshift]) * u[i1];
             }
 
             __syncthreads();
@@ -895,8 +1179,245 @@ __global__ void _shplonk_h_x_div_points(
     values[i] = values[i] * t.inv();
 }
 
+// dst[c * rows + r] = src[r * cols + c]
+__global__ void _four_step_transpose(
+    Bn254FrField *dst,
+    const Bn254FrField *src,
+    int rows,
+    int cols)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    int r = gid / cols;
+    int c = gid % cols;
+
+    dst[c * rows + r] = src[gid];
+}
+
+// buf[r * cols + c] *= omega ^ ((row_start + r) * c)
+__global__ void _four_step_twiddle(
+    Bn254FrField *buf,
+    const Bn254FrField *bases,
+    int row_start,
+    int cols)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    uint r = row_start + gid / cols;
+    uint c = gid % cols;
+
+    if (r != 0 && c != 0)
+    {
+        buf[gid] = buf[gid] * pow_lookup(bases, r * c);
+    }
+}
+
+__device__ const ulong FILL_RANDOM_FR_MODULUS[4] = {
+    0x43e1f593f0000001ul,
+    0x2833e84879b97091ul,
+    0xb85045b68181585dul,
+    0x30644e72e131a029ul,
+};
+
+__device__ ulong _splitmix64(ulong seed, ulong counter)
+{
+    ulong z = seed + (counter + 1) * 0x9e3779b97f4a7c15ul;
+    z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9ul;
+    z = (z ^ (z >> 27)) * 0x94d049bb133111ebul;
+    return z ^ (z >> 31);
+}
+
+// Raw limbs below the modulus are a valid montgomery form of a uniform element,
+// so rejection sampling on 254 bits is enough. splitmix64 is not a cryptographic
+// generator: this fills benchmark inputs only, blinders are drawn on the host.
+__global__ void _fill_random(
+    ulong *buf,
+    ulong seed,
+    int n)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    if (gid >= n)
+    {
+        return;
+    }
+
+    ulong counter = (ulong)gid << 32;
+    while (true)
+    {
+        ulong limbs[4];
+        for (int i = 0; i < 4; i++)
+        {
+            limbs[i] = _splitmix64(seed, counter++);
+        }
+        limbs[3] &= 0x3ffffffffffffffful;
+
+        for (int i = 3; i >= 0; i--)
+        {
+            if (limbs[i] < FILL_RANDOM_FR_MODULUS[i])
+            {
+                for (int j = 0; j < 4; j++)
+                {
+                    buf[gid * 4 + j] = limbs[j];
+                }
+                return;
+            }
+            if (limbs[i] > FILL_RANDOM_FR_MODULUS[i])
+            {
+                break;
+            }
+        }
+    }
+}
+
+// Multiset count over raw 256-bit keys with an open-addressing table of
+// power-of-two capacity. Slot states: 0 empty, 1 key being written, 2 ready.
+__global__ void _histogram(
+    const ulong *keys,
+    int n,
+    ulong *table_keys,
+    uint *table_states,
+    uint *table_counts,
+    int capacity)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    if (gid >= n)
+    {
+        return;
+    }
+
+    ulong key[4];
+    for (int j = 0; j < 4; j++)
+    {
+        key[j] = keys[gid * 4 + j];
+    }
+
+    uint slot = (uint)_splitmix64(key[0] ^ key[1] ^ key[2] ^ key[3], 0) & (capacity - 1);
+    while (true)
+    {
+        uint state = atomicCAS(&table_states[slot], 0, 1);
+        if (state == 0)
+        {
+            for (int j = 0; j < 4; j++)
+            {
+                table_keys[slot * 4 + j] = key[j];
+            }
+            __threadfence();
+            atomicExch(&table_states[slot], 2);
+            atomicAdd(&table_counts[slot], 1);
+            return;
+        }
+
+        while (state != 2)
+        {
+            state = atomicAdd(&table_states[slot], 0);
+        }
+        __threadfence();
+
+        bool equal = true;
+        for (int j = 0; j < 4; j++)
+        {
+            equal = equal && ((volatile ulong *)table_keys)[slot * 4 + j] == key[j];
+        }
+        if (equal)
+        {
+            atomicAdd(&table_counts[slot], 1);
+            return;
+        }
+
+        slot = (slot + 1) & (capacity - 1);
+    }
+}
+
+// Differential check of the two scalar field implementations, out gets
+// a + b, a - b, a * b, a^2, -a, a^-1 and unmont(a) for every pair.
+#define FIELD_OPS_OUTPUTS 7
+
+template <class F>
+__global__ void _field_ops(
+    const F *a,
+    const F *b,
+    F *out,
+    int n)
+{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i >= n)
+    {
+        return;
+    }
+
+    F x = a[i];
+    F y = b[i];
+    F *res = &out[i * FIELD_OPS_OUTPUTS];
+    res[0] = x + y;
+    res[1] = x - y;
+    res[2] = x * y;
+    res[3] = x.sqr();
+    res[4] = -x;
+    res[5] = x.inv();
+    res[6] = x;
+    res[6].unmont_assign();
+}
+
+// Launch configurations picked by the autotuner, per device, kernel and log2
+// of the problem size, 0 keeps the built-in choice. Written by
+// set_launch_config before the proof, only read by the launchers.
+#define LAUNCH_MAX_DEVICES 16
+#define LAUNCH_FIELD 0
+#define LAUNCH_NTT 1
+#define LAUNCH_KERNELS 2
+static int launch_table[LAUNCH_MAX_DEVICES][LAUNCH_KERNELS][32];
+
+static int tuned_launch(int kernel, int n, int fallback)
+{
+    int device = 0;
+    if (n <= 0 || cudaGetDevice(&device) != cudaSuccess || device >= LAUNCH_MAX_DEVICES)
+    {
+        return fallback;
+    }
+    int v = launch_table[device][kernel][31 - __builtin_clz(n)];
+    return v ? v : fallback;
+}
+
+// threads per block of the elementwise kernels, dividing n
+static int field_threads(int n)
+{
+    int threads = tuned_launch(LAUNCH_FIELD, n, 64);
+    if (n < threads)
+    {
+        return 1;
+    }
+    while (threads > 1 && n % threads)
+    {
+        threads >>= 1;
+    }
+    return threads;
+}
+
+template <class F>
+cudaError_t field_ops(const F *a, const F *b, F *out, int n, CUstream_st *stream)
+{
+    int threads = n >= 64 ? 64 : 1;
+    int blocks = (n + threads - 1) / threads;
+    _field_ops<F><<<blocks, threads, 0, stream>>>(a, b, out, n);
+    return cudaGetLastError();
+}
+
 extern "C"
 {
+    cudaError_t set_launch_config(int device, int kernel, int log_n, int value)
+    {
+        if (device < 0 || device >= LAUNCH_MAX_DEVICES || kernel < 0 || kernel >= LAUNCH_KERNELS || log_n < 0 || log_n >= 32 || value < 0)
+        {
+            return cudaErrorInvalidValue;
+        }
+        launch_table[device][kernel][log_n] = value;
+        return cudaSuccess;
+    }
+
+    cudaError_t field_op_max_block_size(int *block_size)
+    {
+        int min_grid_size = 0;
+        return cudaOccupancyMaxPotentialBlockSize(&min_grid_size, block_size, _field_op, 0, 0);
+    }
+
     cudaError_t field_sum(
         Bn254FrField *res,
         Bn254FrField **v,
@@ -921,7 +1442,7 @@ extern "C"
         int to_coset,
         CUstream_st *stream)
     {
-        int threads = size >= 64 ? 64 : 1;
+        int threads = field_threads(size);
         int blocks = size / threads;
         if (to_coset)
         {
@@ -940,11 +1461,28 @@ extern "C"
         Bn254FrField **v, // coeff0, a00, a01, null, coeff1, a10, a11, null,
         int *rot,
         int n_v,
-        int n)
+        int n,
+        CUstream_st *stream)
     {
-        int threads = n >= 64 ? 64 : 1;
+        int threads = field_threads(n);
         int blocks = n / threads;
-        _field_op_batch_mul_sum<<<blocks, threads>>>(res, v, rot, n_v, n);
+        _field_op_batch_mul_sum<<<blocks, threads, 0, stream>>>(res, v, rot, n_v, n);
+        return cudaGetLastError();
+    }
+
+    cudaError_t field_beta_gamma_mul(
+        Bn254FrField *res,
+        const Bn254FrField *a,
+        const Bn254FrField *b,
+        const Bn254FrField *beta_gamma,
+        bool accumulate,
+        int n,
+        CUstream_st *stream)
+    {
+        int threads = field_threads(n);
+        int blocks = n / threads;
+        assert(threads * blocks == n);
+        _field_beta_gamma_mul<<<blocks, threads, 0, stream>>>(res, a, b, beta_gamma, accumulate);
         return cudaGetLastError();
     }
 
@@ -960,7 +1498,7 @@ extern "C"
         int op,
         CUstream_st *stream)
     {
-        int threads = n >= 64 ? 64 : 1;
+        int threads = field_threads(n);
         int blocks = n / threads;
         assert(threads * blocks == n);
         _field_op<<<blocks, threads, 0, stream>>>(res, l, l_rot, l_c, r, r_rot, r_c, n, op);
@@ -1011,13 +1549,14 @@ extern "C"
         return cudaGetLastError();
     }
 
-    cudaError_t ntt(
+    cudaError_t batch_ntt(
         Bn254FrField *buf,
         Bn254FrField *tmp,
         const Bn254FrField *pq,
         const Bn254FrField *omegas,
         int log_n,
         int max_deg,
+        int batch,
         bool *swap,
         CUstream_st *stream)
     {
@@ -1027,17 +1566,20 @@ extern "C"
         Bn254FrField *dst = tmp;
         int len = 1 << log_n;
         int total = 1 << (log_n - 1);
+        // radix of the rounds, the twiddles in pq stay laid out for max_deg
+        int round_deg = tuned_launch(LAUNCH_NTT, len, max_deg);
+        round_deg = round_deg < 1 ? 1 : round_deg > max_deg ? max_deg : round_deg;
         while (p < log_n)
         {
             int res = log_n - p;
-            int round = (res + max_deg - 1) / max_deg;
+            int round = (res + round_deg - 1) / round_deg;
             int deg = (res + round - 1) / round;
 
             int threads = 1 << (deg - 1);
             int blocks = total >> (deg - 1);
             blocks = blocks > 65536 ? 65536 : blocks;
             int grids = (total / blocks) >> (deg - 1);
-            _ntt_core<<<blocks, threads, 0, stream>>>(src, dst, pq, omegas, len, p, deg, max_deg, grids);
+            _ntt_core<<<dim3(blocks, batch), threads, 0, stream>>>(src, dst, pq, omegas, len, p, deg, max_deg, grids);
 
             Bn254FrField *t = src;
             src = dst;
@@ -1048,6 +1590,99 @@ extern "C"
         return cudaGetLastError();
     }
 
+    cudaError_t ntt(
+        Bn254FrField *buf,
+        Bn254FrField *tmp,
+        const Bn254FrField *pq,
+        const Bn254FrField *omegas,
+        int log_n,
+        int max_deg,
+        bool *swap,
+        CUstream_st *stream)
+    {
+        return batch_ntt(buf, tmp, pq, omegas, log_n, max_deg, 1, swap, stream);
+    }
+
+    // Points the nodes of `graph`, captured from extended_prepare and ntt, at
+    // other buffers in `exec` instantiated from it. Each of the `n_ranges`
+    // buffers the capture ran on, `captured[i]` of `bytes[i]`, is replaced by
+    // `bound[i]`. The pointer arguments of both kernels come first (buf and
+    // coset powers, buf, tmp, pq and omegas), the memset clears the tail of buf.
+    // Node params always hold the captured addresses, whatever was bound since.
+    cudaError_t extend_graph_bind(
+        cudaGraph_t graph,
+        cudaGraphExec_t exec,
+        char *const *captured,
+        char *const *bound,
+        const size_t *bytes,
+        int n_ranges)
+    {
+        auto rebind = [&](void *p) -> void *
+        {
+            char *c = (char *)p;
+            for (int i = 0; i < n_ranges; i++)
+            {
+                if (c >= captured[i] && c < captured[i] + bytes[i])
+                    return bound[i] + (c - captured[i]);
+            }
+            return p;
+        };
+
+        size_t count = 0;
+        cudaError_t err = cudaGraphGetNodes(graph, NULL, &count);
+        if (err != cudaSuccess)
+            return err;
+        cudaGraphNode_t *nodes = new cudaGraphNode_t[count];
+        err = cudaGraphGetNodes(graph, nodes, &count);
+        for (size_t i = 0; err == cudaSuccess && i < count; i++)
+        {
+            cudaGraphNodeType type;
+            err = cudaGraphNodeGetType(nodes[i], &type);
+            if (err != cudaSuccess)
+                break;
+            if (type == cudaGraphNodeTypeMemset)
+            {
+                cudaMemsetParams params;
+                err = cudaGraphMemsetNodeGetParams(nodes[i], &params);
+                if (err != cudaSuccess)
+                    break;
+                params.dst = rebind(params.dst);
+                err = cudaGraphExecMemsetNodeSetParams(exec, nodes[i], &params);
+            }
+            else if (type == cudaGraphNodeTypeKernel)
+            {
+                cudaKernelNodeParams params;
+                err = cudaGraphKernelNodeGetParams(nodes[i], &params);
+                if (err != cudaSuccess)
+                    break;
+                bool ntt = params.func == (void *)_ntt_core;
+                if (!ntt && params.func != (void *)_extended_prepare)
+                {
+                    err = cudaErrorInvalidValue;
+                    break;
+                }
+                int n_args = ntt ? 9 : 4;
+                int n_pointers = ntt ? 4 : 2;
+                void *args[9];
+                void *addresses[4];
+                for (int j = 0; j < n_args; j++)
+                {
+                    args[j] = params.kernelParams[j];
+                }
+                for (int j = 0; j < n_pointers; j++)
+                {
+                    addresses[j] = rebind(*(void **)params.kernelParams[j]);
+                    args[j] = &addresses[j];
+                }
+                params.kernelParams = args;
+                params.extra = NULL;
+                err = cudaGraphExecKernelNodeSetParams(exec, nodes[i], &params);
+            }
+        }
+        delete[] nodes;
+        return err;
+    }
+
     cudaError_t msm(
         Bn254G1 *res,
         Bn254G1Affine *points,
@@ -1055,11 +1690,6 @@ extern "C"
         int n,
         cudaStream_t stream)
     {
-        cudaDeviceProp properties;
-        cudaGetDeviceProperties(&properties, 0);
-        int smCount = properties.multiProcessorCount;
-        printf("smCount is %d\n", smCount);
-
         int threads = n >= 32 ? 32 : 1;
         int blocks = (n + threads - 1) / threads;
 
@@ -1091,17 +1721,87 @@ extern "C"
                 break;
             }
         }
+        // the carry out of the top nonzero byte, bn254 scalars are below 2^254 so the last byte never carries
+        windows = windows < 32 ? windows + 1 : windows;
+
+        signed char *digits = NULL;
+        err = cudaMallocAsync(&digits, (size_t)n * windows, stream);
+        if (err)
+        {
+            cudaFreeAsync(unmont_scalars, stream);
+            cudaFreeAsync(none_zero_bytes, stream);
+            return err;
+        }
+        _msm_signed_digits<<<blocks, threads, 0, stream>>>(unmont_scalars, digits, n, windows);
+        cudaFreeAsync(unmont_scalars, stream);
 
-        cudaMemsetAsync(res, 0, sizeof(Bn254G1) << 22, stream);
         int groups = 512 / windows;
-        _msm_core<<<dim3(windows, groups), 16, 0, stream>>>(points, unmont_scalars, res, n);
-        _msm_merge_groups_v2<<<dim3(windows, 256), 16, 0, stream>>>(res, groups * 16);
+        cudaMemsetAsync(res, 0, sizeof(Bn254G1) * windows * groups * 16 * MSM_BUCKETS, stream);
+        _msm_core<<<dim3(windows, groups), 16, 0, stream>>>(points, digits, res, n);
+        _msm_merge_groups_v2<<<dim3(windows, MSM_BUCKETS), 16, 0, stream>>>(res, groups * 16);
         _msm_merge_inner<<<1, windows, 0, stream>>>(res, groups * 16);
-        cudaFreeAsync(unmont_scalars, stream);
+        cudaFreeAsync(digits, stream);
         cudaFreeAsync(none_zero_bytes, stream);
         return cudaGetLastError();
     }
 
+    cudaError_t msm_tiny(
+        Bn254G1 *res,
+        Bn254G1Affine *points,
+        Bn254FrField *scalars,
+        int n,
+        cudaStream_t stream)
+    {
+        int threads = n >= 128 ? 128 : n;
+        int blocks = (n + threads - 1) / threads;
+
+        int *none_zero_bytes = NULL;
+        cudaError_t err = cudaMallocAsync(&none_zero_bytes, 64 * sizeof(int), stream);
+        if (err)
+        {
+            return err;
+        }
+
+        _msm_unmont<<<blocks, threads, 0, stream>>>(scalars, scalars, none_zero_bytes, n);
+        cudaMemsetAsync(res, 0, sizeof(Bn254G1) * threads, stream);
+        _msm_tiny<<<1, threads, 0, stream>>>(points, scalars, res, n);
+        cudaFreeAsync(none_zero_bytes, stream);
+        return cudaGetLastError();
+    }
+
+    cudaError_t msm_sparse_compact(
+        Bn254G1Affine *points,
+        Bn254FrField *scalars,
+        int n,
+        Bn254G1Affine *out_points,
+        Bn254FrField *out_scalars,
+        Bn254G1Affine *ones_points,
+        int *counts,
+        int capacity,
+        cudaStream_t stream)
+    {
+        int threads = n >= 128 ? 128 : 1;
+        int blocks = (n + threads - 1) / threads;
+        blocks = blocks > 1024 ? 1024 : blocks;
+
+        cudaMemsetAsync(counts, 0, 2 * sizeof(int), stream);
+        _msm_sparse_compact<<<blocks, threads, 0, stream>>>(
+            points, scalars, n, out_points, out_scalars, ones_points, counts, capacity);
+        return cudaGetLastError();
+    }
+
+    cudaError_t msm_sum_points(
+        Bn254G1 *res,
+        Bn254G1Affine *points,
+        int n,
+        cudaStream_t stream)
+    {
+        int threads = 128;
+        cudaMemsetAsync(res, 0, sizeof(Bn254G1) * threads, stream);
+        _msm_sum_points<<<1, threads, 0, stream>>>(points, res, n);
+        return cudaGetLastError();
+    }
+
     cudaError_t lookup_eval_h(
         Bn254FrField *res,
         const Bn254FrField *input,
@@ -1245,43 +1945,259 @@ extern "C"
         return cudaGetLastError();
     }
 
-    cudaError_t eval_lookup_z(
+    // z = running product of numerator / z starting from one, z holding the
+    // denominators, numerator and tmp are clobbered
+    cudaError_t eval_fraction_product_z(
         Bn254FrField *z,
-        Bn254FrField *input,
-        Bn254FrField *table,
-        const Bn254FrField *permuted_input,
-        const Bn254FrField *permuted_table,
-        Bn254FrField *beta_gamma,
+        Bn254FrField *numerator,
+        Bn254FrField *tmp,
         int n,
         CUstream_st *stream)
     {
         int threads = n >= 64 ? 64 : 1;
         int blocks = n / threads;
-        _eval_lookup_z_step1<<<blocks, threads, 0, stream>>>(
-            z, permuted_input, permuted_table, beta_gamma);
-        _eval_lookup_z_step2<<<blocks, threads, 0, stream>>>(
-            input, table, beta_gamma);
-
         int worker = 64 * 128;
+
+        // fewer rows than workers: one thread inverts and multiplies them all
+        if (n < worker)
+        {
+            _eval_lookup_z_batch_invert<<<1, 1, 0, stream>>>(z, tmp, n);
+            _eval_lookup_z_step3<<<blocks, threads, 0, stream>>>(
+                z, numerator, NULL);
+            // numerator[0] = 1, then z = the running product from it
+            _eval_lookup_z_product_batch<<<1, 1, 0, stream>>>(z, numerator, n);
+            _eval_lookup_z_product_batch_spread_skip<<<1, 1, 0, stream>>>(
+                z, numerator, n);
+            return cudaGetLastError();
+        }
+
         int size_per_worker = n / worker;
         _eval_lookup_z_batch_invert<<<128, 64, 0, stream>>>(
-            z, table, size_per_worker);
+            z, tmp, size_per_worker);
         _eval_lookup_z_step3<<<blocks, threads, 0, stream>>>(
-            z, input, beta_gamma);
+            z, numerator, NULL);
 
         worker = 64 * 64;
         size_per_worker = n / worker;
         _eval_lookup_z_product_batch<<<64, 64, 0, stream>>>(
-            z, input, size_per_worker);
+            z, numerator, size_per_worker);
         _eval_lookup_z_product_batch<<<8, 8, 0, stream>>>(
-            input, table, 64);
+            numerator, tmp, 64);
         _eval_lookup_z_product_single_spread<<<1, 1, 0, stream>>>(
-            table, 64);
+            tmp, 64);
         _eval_lookup_z_product_batch_spread<<<8, 8, 0, stream>>>(
-            input, table, 64);
+            numerator, tmp, 64);
         _eval_lookup_z_product_batch_spread_skip<<<64, 64, 0, stream>>>(
-            z, input, size_per_worker);
+            z, numerator, size_per_worker);
+
+        return cudaGetLastError();
+    }
+
+    cudaError_t eval_lookup_z(
+        Bn254FrField *z,
+        Bn254FrField *input,
+        Bn254FrField *table,
+        const Bn254FrField *permuted_input,
+        const Bn254FrField *permuted_table,
+        Bn254FrField *beta_gamma,
+        int n,
+        CUstream_st *stream)
+    {
+        int threads = n >= 64 ? 64 : 1;
+        int blocks = n / threads;
+        _field_beta_gamma_mul<<<blocks, threads, 0, stream>>>(
+            z, permuted_input, permuted_table, beta_gamma, false);
+        _field_beta_gamma_mul<<<blocks, threads, 0, stream>>>(
+            input, input, table, beta_gamma, false);
+
+        return eval_fraction_product_z(z, input, table, n, stream);
+    }
+
+    cudaError_t permutation_product_step(
+        Bn254FrField *res,
+        const Bn254FrField *x,
+        const Bn254FrField *y,
+        const Bn254FrField *a_b,
+        bool first,
+        int n,
+        CUstream_st *stream)
+    {
+        int threads = n >= 64 ? 64 : 1;
+        int blocks = n / threads;
+        _permutation_product_step<<<blocks, threads, 0, stream>>>(res, x, y, a_b, first);
+        return cudaGetLastError();
+    }
+
+    // Permuted table of a lookup from its sorted input and sorted table, the first n rows.
+    // flags holds n bytes, holes and left n ints, counts 2 * segments + 2 ints.
+    cudaError_t lookup_permute_table(
+        Bn254FrField *permuted_table,
+        const Bn254FrField *input,
+        const Bn254FrField *table,
+        unsigned char *flags,
+        int *counts,
+        int *holes,
+        int *left,
+        int n,
+        int seg_len,
+        CUstream_st *stream)
+    {
+        int segments = (n + seg_len - 1) / seg_len;
+        int threads = n >= 128 ? 128 : 1;
+        int blocks = (n + threads - 1) / threads;
+        blocks = blocks > 1024 ? 1024 : blocks;
+        int seg_threads = segments >= 64 ? 64 : 1;
+        int seg_blocks = (segments + seg_threads - 1) / seg_threads;
+
+        _lookup_table_flags<<<blocks, threads, 0, stream>>>(permuted_table, input, table, flags, n);
+        _lookup_table_count<<<seg_blocks, seg_threads, 0, stream>>>(flags, counts, n, seg_len, segments);
+        _lookup_table_scan<<<1, 1, 0, stream>>>(counts, segments);
+        _lookup_table_compact<<<seg_blocks, seg_threads, 0, stream>>>(
+            flags, counts, holes, left, n, seg_len, segments);
+        _lookup_table_scatter<<<blocks, threads, 0, stream>>>(
+            permuted_table, table, counts, holes, left, segments);
+        return cudaGetLastError();
+    }
+
+    cudaError_t fill_random(
+        Bn254FrField *buf,
+        int n,
+        ulong seed,
+        CUstream_st *stream)
+    {
+        int threads = n >= 64 ? 64 : 1;
+        int blocks = (n + threads - 1) / threads;
+        _fill_random<<<blocks, threads, 0, stream>>>((ulong *)buf, seed, n);
+        return cudaGetLastError();
+    }
 
+    cudaError_t histogram(
+        const Bn254FrField *keys,
+        int n,
+        Bn254FrField *table_keys,
+        uint *table_states,
+        uint *table_counts,
+        int capacity,
+        CUstream_st *stream)
+    {
+        cudaMemsetAsync(table_states, 0, capacity * sizeof(uint), stream);
+        cudaMemsetAsync(table_counts, 0, capacity * sizeof(uint), stream);
+        int threads = n >= 64 ? 64 : 1;
+        int blocks = (n + threads - 1) / threads;
+        _histogram<<<blocks, threads, 0, stream>>>(
+            (const ulong *)keys, n, (ulong *)table_keys, table_states, table_counts, capacity);
         return cudaGetLastError();
     }
+
+    cudaError_t four_step_transpose(
+        Bn254FrField *dst,
+        const Bn254FrField *src,
+        int rows,
+        int cols,
+        CUstream_st *stream)
+    {
+        int n = rows * cols;
+        int threads = n >= 64 ? 64 : 1;
+        int blocks = n / threads;
+        _four_step_transpose<<<blocks, threads, 0, stream>>>(dst, src, rows, cols);
+        return cudaGetLastError();
+    }
+
+    cudaError_t four_step_twiddle(
+        Bn254FrField *buf,
+        const Bn254FrField *bases,
+        int row_start,
+        int rows,
+        int cols,
+        CUstream_st *stream)
+    {
+        int n = rows * cols;
+        int threads = n >= 64 ? 64 : 1;
+        int blocks = n / threads;
+        _four_step_twiddle<<<blocks, threads, 0, stream>>>(buf, bases, row_start, cols);
+        return cudaGetLastError();
+    }
+
+    cudaError_t field_ops_limb32(
+        const Bn254FrField32 *a,
+        const Bn254FrField32 *b,
+        Bn254FrField32 *out,
+        int n,
+        CUstream_st *stream)
+    {
+        return field_ops(a, b, out, n, stream);
+    }
+
+    cudaError_t field_ops_limb64(
+        const Bn254FrField64 *a,
+        const Bn254FrField64 *b,
+        Bn254FrField64 *out,
+        int n,
+        CUstream_st *stream)
+    {
+        return field_ops(a, b, out, n, stream);
+    }
+
+    // Query every kernel once so the module is loaded before the first timed launch.
+    cudaError_t preload_kernels()
+    {
+        const void *kernels[] = {
+            (const void *)_field_beta_gamma_mul,
+            (const void *)_permutation_product_step,
+            (const void *)_eval_lookup_z_batch_invert,
+            (const void *)_eval_lookup_z_step3,
+            (const void *)_eval_lookup_z_product_batch,
+            (const void *)_eval_lookup_z_product_single_spread,
+            (const void *)_eval_lookup_z_product_batch_spread,
+            (const void *)_eval_lookup_z_product_batch_spread_skip,
+            (const void *)_poly_eval,
+            (const void *)_msm_unmont,
+            (const void *)_msm_signed_digits,
+            (const void *)_msm_merge_groups,
+            (const void *)_msm_merge_groups_v2,
+            (const void *)_msm_merge_inner,
+            (const void *)_msm_core,
+            (const void *)_msm_tiny,
+            (const void *)_msm_sparse_compact,
+            (const void *)_msm_sum_points,
+            (const void *)_ntt_core,
+            (const void *)_field_sum,
+            (const void *)_field_op_batch_mul_sum,
+            (const void *)_field_mul_unaligned,
+            (const void *)_field_op,
+            (const void *)_extended_prepare,
+            (const void *)_permutation_eval_h_p1,
+            (const void *)_permutation_eval_h_p2,
+            (const void *)_permutation_eval_h_l,
+            (const void *)_permutation_eval_h_r,
+            (const void *)_lookup_eval_h,
+            (const void *)_shuffle_eval_h,
+            (const void *)_expand_omega_buffer,
+            (const void *)_field_mul_zip,
+            (const void *)_shplonk_h_x_merge,
+            (const void *)_shplonk_h_x_div_points,
+            (const void *)_four_step_transpose,
+            (const void *)_four_step_twiddle,
+            (const void *)_lookup_table_flags,
+            (const void *)_lookup_table_count,
+            (const void *)_lookup_table_scan,
+            (const void *)_lookup_table_compact,
+            (const void *)_lookup_table_scatter,
+            (const void *)_fill_random,
+            (const void *)_histogram,
+            (const void *)_field_ops<Bn254FrField32>,
+            (const void *)_field_ops<Bn254FrField64>,
+        };
+
+        for (const void *kernel : kernels)
+        {
+            cudaFuncAttributes attr;
+            cudaError_t err = cudaFuncGetAttributes(&attr, kernel);
+            if (err != cudaSuccess)
+            {
+                return err;
+            }
+        }
+        return cudaSuccess;
+    }
 }
@@ -0,0 +1,13 @@
+#pragma once
+
+#include "zprize_ff_wrapper.cuh"
+#include "bn254_fr64.cuh"
+
+// Both implementations share the montgomery representation (R = 2^256), so
+// buffers written by the host are valid for either of them.
+// build.rs defines BN254_FR_LIMB64 for architectures where 64-bit limbs are faster.
+#ifdef BN254_FR_LIMB64
+typedef Bn254FrField64 Bn254FrField;
+#else
+typedef Bn254FrField32 Bn254FrField;
+#endif
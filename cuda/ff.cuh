@@ -13,7 +13,7 @@ template <
     const FieldLimb R[LIMBS],
     const FieldLimb R2[LIMBS],
     const FieldLimb INV>
-class Field
+class Field64
 {
 private:
     // <http://cacr.uwaterloo.ca/hac/about/chap14.pdf>.
@@ -84,9 +84,9 @@ private:
         }
     }
 
-    __device__ static void _pow_at_leading(Field *acc, const Field *base, ulong exp)
+    __device__ static void _pow_at_leading(Field64 *acc, const Field64 *base, ulong exp)
     {
-        Field t = *base;
+        Field64 t = *base;
 
         while (exp > 0)
         {
@@ -104,7 +104,7 @@ private:
         }
     }
 
-    __device__ static void _pow_at_nonleading(Field *acc, const Field *base, ulong exp)
+    __device__ static void _pow_at_nonleading(Field64 *acc, const Field64 *base, ulong exp)
     {
         for (ulong bit = 1ul << (sizeof(ulong) * 8 - 1); bit != 0; bit >>= 1)
         {
@@ -120,11 +120,12 @@ public:
     // little-endian
     FieldLimb limbs_le[LIMBS];
 
-    __device__ Field()
+    __device__ Field64()
     {
+        memset(limbs_le, 0, sizeof(FieldLimb) * LIMBS);
     }
 
-    __device__ Field(ulong v)
+    __device__ Field64(ulong v)
     {
         if (v == 0)
         {
@@ -137,18 +138,18 @@ public:
         else
         {
             FieldLimb t[LIMBS] = {v};
-            Field::_mul(limbs_le, t, R);
+            Field64::_mul(limbs_le, t, R);
         }
     }
 
-    __device__ ~Field() {}
+    __device__ ~Field64() {}
 
-    __device__ static bool gte(const Field *a, const Field *b)
+    __device__ static bool gte(const Field64 *a, const Field64 *b)
     {
         return _gte(a->limbs_le, b->limbs_le);
     }
 
-    __device__ static bool eq(const Field *a, const Field *b)
+    __device__ static bool eq(const Field64 *a, const Field64 *b)
     {
 #pragma unroll
         for (uint i = 0; i < LIMBS; i++)
@@ -157,7 +158,7 @@ public:
         return true;
     }
 
-    __device__ static void add_no_copy(Field *out, const Field *a, const Field *b)
+    __device__ static void add_no_copy(Field64 *out, const Field64 *a, const Field64 *b)
     {
         if (LIMBS == 4)
         {
@@ -173,7 +174,7 @@ public:
         }
     }
 
-    __device__ static void sub_no_copy(Field *out, const Field *a, const Field *b)
+    __device__ static void sub_no_copy(Field64 *out, const Field64 *a, const Field64 *b)
     {
         if (LIMBS == 4)
         {
@@ -190,33 +191,33 @@ public:
     }
 
     // out can't overlap with a or b
-    __device__ static void mul_no_copy(Field *out, const Field *a, const Field *b)
+    __device__ static void mul_no_copy(Field64 *out, const Field64 *a, const Field64 *b)
     {
         _mul((FieldLimb *)&out->limbs_le, a->limbs_le, b->limbs_le);
     }
 
-    __device__ static Field add(const Field *a, const Field *b)
+    __device__ static Field64 add(const Field64 *a, const Field64 *b)
     {
-        Field tmp;
+        Field64 tmp;
         add_no_copy(&tmp, a, b);
         return tmp;
     }
 
-    __device__ static Field sub(const Field *a, const Field *b)
+    __device__ static Field64 sub(const Field64 *a, const Field64 *b)
     {
-        Field tmp;
+        Field64 tmp;
         sub_no_copy(&tmp, a, b);
         return tmp;
     }
 
-    __device__ static Field mul(const Field *a, const Field *b)
+    __device__ static Field64 mul(const Field64 *a, const Field64 *b)
     {
-        Field tmp;
+        Field64 tmp;
         _mul((FieldLimb *)&tmp.limbs_le, a->limbs_le, b->limbs_le);
         return tmp;
     }
 
-    __device__ static void unmont(Field *a)
+    __device__ static void unmont(Field64 *a)
     {
         if (LIMBS == 4)
         {
@@ -233,18 +234,18 @@ public:
         unmont(this);
     }
 
-    __device__ Field unmont() const
+    __device__ Field64 unmont() const
     {
-        Field t(*this);
+        Field64 t(*this);
         unmont(&t);
         return t;
     }
 
-    __device__ static void mont(Field *a)
+    __device__ static void mont(Field64 *a)
     {
         if (LIMBS == 4)
         {
-            Field tmp;
+            Field64 tmp;
             _mul((FieldLimb *)&tmp.limbs_le, a->limbs_le, R2);
             *a = tmp;
         }
@@ -259,26 +260,26 @@ public:
         mont(this);
     }
 
-    __device__ Field mont() const
+    __device__ Field64 mont() const
     {
-        Field t(*this);
+        Field64 t(*this);
         mont(&t);
         return t;
     }
 
-    __device__ static Field sqr(const Field *a)
+    __device__ static Field64 sqr(const Field64 *a)
     {
         return mul(a, a);
     }
 
-    __device__ Field sqr() const
+    __device__ Field64 sqr() const
     {
         return sqr(this);
     }
 
-    __device__ static Field pow(const Field *a, ulong exp)
+    __device__ static Field64 pow(const Field64 *a, ulong exp)
     {
-        Field acc = Field(1);
+        Field64 acc = Field64(1);
         if (exp > 0)
         {
             _pow_at_leading(&acc, a, exp);
@@ -286,9 +287,9 @@ public:
         return acc;
     }
 
-    __device__ static Field pow(const Field *a, const FieldLimb *exp_le, int exp_len)
+    __device__ static Field64 pow(const Field64 *a, const FieldLimb *exp_le, int exp_len)
     {
-        Field acc = Field(1);
+        Field64 acc = Field64(1);
         int i = exp_len - 1;
         for (; i >= 0; i--)
         {
@@ -306,19 +307,19 @@ public:
         return acc;
     }
 
-    __device__ static Field inv(const Field *a)
+    __device__ static Field64 inv(const Field64 *a)
     {
         return pow(a, NEG_TOW, LIMBS);
     }
 
-    __device__ Field inv() const
+    __device__ Field64 inv() const
     {
         return inv(this);
     }
 
-    __device__ Field neg() const
+    __device__ Field64 neg() const
     {
-        Field tmp;
+        Field64 tmp;
         if (this->is_zero())
         {
             return *this;
@@ -330,7 +331,7 @@ public:
         }
     }
 
-    __device__ Field neg_assign()
+    __device__ Field64 neg_assign()
     {
         if (!this->is_zero())
         {
@@ -367,6 +368,16 @@ public:
         return (this->limbs_le[limb] >> (idx * 8)) & (0x00fful);
     }
     
+    __device__ uint nonzero_bytes() const
+    {
+        uint i = LIMBS * sizeof(FieldLimb);
+        while (i > 0 && this->get_8bits(i - 1) == 0)
+        {
+            i--;
+        }
+        return i;
+    }
+
     __device__ ulong get_4bits(uint i) const
     {
         uint limb = i / (sizeof(FieldLimb) * 2);
@@ -377,42 +388,42 @@ public:
 
     // operator
 
-    __device__ Field operator+(const Field &b) const
+    __device__ Field64 operator+(const Field64 &b) const
     {
         return add(this, &b);
     }
 
-    __device__ Field operator-(const Field &b) const
+    __device__ Field64 operator-(const Field64 &b) const
     {
         return sub(this, &b);
     }
 
-    __device__ void operator+=(const Field &b)
+    __device__ void operator+=(const Field64 &b)
     {
         return add_no_copy(this, this, &b);
     }
 
-    __device__ void operator-=(const Field &b)
+    __device__ void operator-=(const Field64 &b)
     {
         return sub_no_copy(this, this, &b);
     }
 
-    __device__ Field operator*(const Field &b) const
+    __device__ Field64 operator*(const Field64 &b) const
     {
         return mul(this, &b);
     }
 
-    __device__ void operator*=(const Field &b)
+    __device__ void operator*=(const Field64 &b)
     {
         *this = mul(this, &b);
     }
 
-    __device__ bool operator==(const Field &b) const
+    __device__ bool operator==(const Field64 &b) const
     {
         return eq(this, &b);
     }
 
-    __device__ Field operator-() const
+    __device__ Field64 operator-() const
     {
         return this->neg();
     }
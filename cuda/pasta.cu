@@ -0,0 +1,481 @@
+#include "cuda_runtime.h"
+
+#include <stdio.h>
+#include <assert.h>
+
+// Pallas/Vesta kernels. Both moduli are just above 2^254, so the lazy
+// reduction of the zprize field (which keeps values below 4p) doesn't fit in
+// 256 bits; this file carries a plain fully reduced 4x64 montgomery field.
+
+__device__ __constant__ ulong PASTA_FP_MODULUS[4] = {
+    0x992d30ed00000001ul,
+    0x224698fc094cf91bul,
+    0x0000000000000000ul,
+    0x4000000000000000ul,
+};
+__device__ __constant__ ulong PASTA_FP_R[4] = {
+    0x34786d38fffffffdul,
+    0x992c350be41914adul,
+    0xfffffffffffffffful,
+    0x3ffffffffffffffful,
+};
+
+__device__ __constant__ ulong PASTA_FQ_MODULUS[4] = {
+    0x8c46eb2100000001ul,
+    0x224698fc0994a8ddul,
+    0x0000000000000000ul,
+    0x4000000000000000ul,
+};
+__device__ __constant__ ulong PASTA_FQ_R[4] = {
+    0x5b2b3e9cfffffffdul,
+    0x992c350be3420567ul,
+    0xfffffffffffffffful,
+    0x3ffffffffffffffful,
+};
+
+struct PastaFpConfig
+{
+    static constexpr ulong INV = 0x992d30ecfffffffful;
+    __device__ static ulong modulus(int i) { return PASTA_FP_MODULUS[i]; }
+    __device__ static ulong r(int i) { return PASTA_FP_R[i]; }
+};
+
+struct PastaFqConfig
+{
+    static constexpr ulong INV = 0x8c46eb20fffffffful;
+    __device__ static ulong modulus(int i) { return PASTA_FQ_MODULUS[i]; }
+    __device__ static ulong r(int i) { return PASTA_FQ_R[i]; }
+};
+
+__device__ __forceinline__ ulong _mac(ulong a, ulong b, ulong c, ulong &carry)
+{
+    ulong lo = b * c;
+    ulong hi = __umul64hi(b, c);
+    lo += a;
+    hi += lo < a;
+    lo += carry;
+    hi += lo < carry;
+    carry = hi;
+    return lo;
+}
+
+__device__ __forceinline__ ulong _adc(ulong a, ulong b, ulong &carry)
+{
+    ulong r = a + b;
+    ulong c = r < a;
+    r += carry;
+    c += r < carry;
+    carry = c;
+    return r;
+}
+
+__device__ __forceinline__ ulong _sbb(ulong a, ulong b, ulong &borrow)
+{
+    ulong r = a - b;
+    ulong c = a < b;
+    ulong t = r;
+    r -= borrow;
+    c += t < borrow;
+    borrow = c;
+    return r;
+}
+
+template <class CFG>
+class PastaField
+{
+public:
+    ulong v[4];
+
+    __device__ static PastaField zero()
+    {
+        PastaField r;
+        for (int i = 0; i < 4; i++)
+            r.v[i] = 0;
+        return r;
+    }
+
+    __device__ static PastaField one()
+    {
+        PastaField r;
+        for (int i = 0; i < 4; i++)
+            r.v[i] = CFG::r(i);
+        return r;
+    }
+
+    __device__ bool is_zero() const
+    {
+        return (v[0] | v[1] | v[2] | v[3]) == 0;
+    }
+
+    __device__ bool gte_modulus() const
+    {
+        for (int i = 3; i >= 0; i--)
+        {
+            if (v[i] > CFG::modulus(i))
+                return true;
+            if (v[i] < CFG::modulus(i))
+                return false;
+        }
+        return true;
+    }
+
+    __device__ void sub_modulus()
+    {
+        ulong borrow = 0;
+        for (int i = 0; i < 4; i++)
+            v[i] = _sbb(v[i], CFG::modulus(i), borrow);
+    }
+
+    __device__ PastaField operator+(const PastaField &b) const
+    {
+        PastaField r;
+        ulong carry = 0;
+        for (int i = 0; i < 4; i++)
+            r.v[i] = _adc(v[i], b.v[i], carry);
+        if (carry || r.gte_modulus())
+            r.sub_modulus();
+        return r;
+    }
+
+    __device__ PastaField operator-(const PastaField &b) const
+    {
+        PastaField r;
+        ulong borrow = 0;
+        for (int i = 0; i < 4; i++)
+            r.v[i] = _sbb(v[i], b.v[i], borrow);
+        if (borrow)
+        {
+            ulong carry = 0;
+            for (int i = 0; i < 4; i++)
+                r.v[i] = _adc(r.v[i], CFG::modulus(i), carry);
+        }
+        return r;
+    }
+
+    // CIOS montgomery multiplication
+    __device__ PastaField operator*(const PastaField &b) const
+    {
+        ulong t[6] = {0, 0, 0, 0, 0, 0};
+        for (int i = 0; i < 4; i++)
+        {
+            ulong carry = 0;
+            for (int j = 0; j < 4; j++)
+                t[j] = _mac(t[j], v[j], b.v[i], carry);
+            ulong c = 0;
+            t[4] = _adc(t[4], carry, c);
+            t[5] = c;
+
+            ulong m = t[0] * CFG::INV;
+            carry = 0;
+            _mac(t[0], m, CFG::modulus(0), carry);
+            for (int j = 1; j < 4; j++)
+                t[j - 1] = _mac(t[j], m, CFG::modulus(j), carry);
+            c = 0;
+            t[3] = _adc(t[4], carry, c);
+            t[4] = t[5] + c;
+        }
+
+        PastaField r;
+        for (int i = 0; i < 4; i++)
+            r.v[i] = t[i];
+        if (t[4] || r.gte_modulus())
+            r.sub_modulus();
+        return r;
+    }
+
+    __device__ PastaField unmont() const
+    {
+        PastaField one = zero();
+        one.v[0] = 1;
+        return *this * one;
+    }
+
+    __device__ bool bit(int i) const
+    {
+        return (v[i >> 6] >> (i & 63)) & 1;
+    }
+};
+
+typedef PastaField<PastaFpConfig> PastaFpField;
+typedef PastaField<PastaFqConfig> PastaFqField;
+
+// y^2 = x^3 + 5 for both curves, so a = 0 formulas apply.
+template <class F>
+struct PastaAffine
+{
+    F x;
+    F y;
+};
+
+template <class F>
+struct PastaJacobian
+{
+    F x;
+    F y;
+    F z;
+
+    __device__ static PastaJacobian identity()
+    {
+        return {F::zero(), F::zero(), F::zero()};
+    }
+
+    __device__ PastaJacobian dbl() const
+    {
+        if (z.is_zero())
+            return *this;
+
+        F a = x * x;
+        F b = y * y;
+        F c = b * b;
+        F d = x + b;
+        d = d * d - a - c;
+        d = d + d;
+        F e = a + a + a;
+        F f = e * e;
+
+        PastaJacobian r;
+        r.x = f - d - d;
+        F c8 = c + c;
+        c8 = c8 + c8;
+        c8 = c8 + c8;
+        r.y = e * (d - r.x) - c8;
+        F yz = y * z;
+        r.z = yz + yz;
+        return r;
+    }
+
+    __device__ PastaJacobian add_affine(const PastaAffine<F> &q) const
+    {
+        if (q.x.is_zero() && q.y.is_zero())
+            return *this;
+
+        if (z.is_zero())
+            return {q.x, q.y, F::one()};
+
+        F z1z1 = z * z;
+        F u2 = q.x * z1z1;
+        F s2 = q.y * z * z1z1;
+        F h = u2 - x;
+        F rr = s2 - y;
+
+        if (h.is_zero())
+        {
+            if (rr.is_zero())
+                return dbl();
+            return identity();
+        }
+
+        rr = rr + rr;
+        F hh = h * h;
+        F i = hh + hh;
+        i = i + i;
+        F j = h * i;
+        F v = x * i;
+
+        PastaJacobian r;
+        r.x = rr * rr - j - v - v;
+        F y1j = y * j;
+        r.y = rr * (v - r.x) - (y1j + y1j);
+        F zh = z + h;
+        r.z = zh * zh - z1z1 - hh;
+        return r;
+    }
+};
+
+template <class F>
+__global__ void _pasta_field_op(F *res, const F *l, const F *r, int op)
+{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (op == 0)
+        res[i] = l[i] + r[i];
+    else if (op == 1)
+        res[i] = l[i] * r[i];
+    else
+        res[i] = l[i] - r[i];
+}
+
+template <class F>
+__global__ void _pasta_field_scale(F *buf, const F *c)
+{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    buf[i] = buf[i] * c[0];
+}
+
+template <class F>
+__global__ void _pasta_field_unmont(F *dst, const F *src)
+{
+    int i = blockIdx.x * blockDim.x + threadIdx.x;
+    dst[i] = src[i].unmont();
+}
+
+template <class F>
+__global__ void _pasta_ntt_bit_reverse(F *buf, int log_n)
+{
+    unsigned i = blockIdx.x * blockDim.x + threadIdx.x;
+    unsigned j = __brev(i) >> (32 - log_n);
+    if (i < j)
+    {
+        F t = buf[i];
+        buf[i] = buf[j];
+        buf[j] = t;
+    }
+}
+
+// twiddles[i] = omega ^ i for i < n / 2
+template <class F>
+__global__ void _pasta_ntt_stage(F *buf, const F *twiddles, int log_n, int stage)
+{
+    unsigned gid = blockIdx.x * blockDim.x + threadIdx.x;
+    unsigned half_len = 1u << stage;
+    unsigned pos = gid & (half_len - 1);
+    unsigned i = ((gid >> stage) << (stage + 1)) + pos;
+    unsigned j = i + half_len;
+
+    F t = buf[j] * twiddles[pos << (log_n - 1 - stage)];
+    buf[j] = buf[i] - t;
+    buf[i] = buf[i] + t;
+}
+
+// Every thread folds sum(scalars[i] * points[i]) over its chunk with shared
+// doublings; scalars must already be in canonical form.
+template <class B, class S>
+__global__ void _pasta_msm_chunk(
+    PastaJacobian<B> *res,
+    const PastaAffine<B> *points,
+    const S *scalars,
+    int n,
+    int chunk)
+{
+    int gid = blockIdx.x * blockDim.x + threadIdx.x;
+    int start = gid * chunk;
+    int end = min(start + chunk, n);
+
+    PastaJacobian<B> acc = PastaJacobian<B>::identity();
+    for (int bit = 254; bit >= 0; bit--)
+    {
+        acc = acc.dbl();
+        for (int i = start; i < end; i++)
+        {
+            if (scalars[i].bit(bit))
+            {
+                acc = acc.add_affine(points[i]);
+            }
+        }
+    }
+    res[gid] = acc;
+}
+
+static void _launch_dims(int n, int &blocks, int &threads)
+{
+    threads = n % 64 == 0 ? 64 : 1;
+    blocks = n / threads;
+}
+
+template <class F>
+static cudaError_t pasta_field_op_t(void *res, void *l, void *r, int n, int op, CUstream_st *stream)
+{
+    int blocks, threads;
+    _launch_dims(n, blocks, threads);
+    _pasta_field_op<F><<<blocks, threads, 0, stream>>>((F *)res, (const F *)l, (const F *)r, op);
+    return cudaGetLastError();
+}
+
+template <class F>
+static cudaError_t pasta_ntt_t(void *buf, void *twiddles, void *divisor, int log_n, CUstream_st *stream)
+{
+    int n = 1 << log_n;
+    int blocks, threads;
+    _launch_dims(n, blocks, threads);
+    _pasta_ntt_bit_reverse<F><<<blocks, threads, 0, stream>>>((F *)buf, log_n);
+
+    _launch_dims(n >> 1, blocks, threads);
+    for (int stage = 0; stage < log_n; stage++)
+    {
+        _pasta_ntt_stage<F><<<blocks, threads, 0, stream>>>((F *)buf, (const F *)twiddles, log_n, stage);
+    }
+
+    if (divisor)
+    {
+        _launch_dims(n, blocks, threads);
+        _pasta_field_scale<F><<<blocks, threads, 0, stream>>>((F *)buf, (const F *)divisor);
+    }
+    return cudaGetLastError();
+}
+
+template <class B, class S>
+static cudaError_t pasta_msm_t(
+    void *res,
+    void *points,
+    void *scalars,
+    void *tmp,
+    int n,
+    int work_items,
+    CUstream_st *stream)
+{
+    int blocks, threads;
+    _launch_dims(n, blocks, threads);
+    _pasta_field_unmont<S><<<blocks, threads, 0, stream>>>((S *)tmp, (const S *)scalars);
+
+    int chunk = (n + work_items - 1) / work_items;
+    _launch_dims(work_items, blocks, threads);
+    _pasta_msm_chunk<B, S><<<blocks, threads, 0, stream>>>(
+        (PastaJacobian<B> *)res,
+        (const PastaAffine<B> *)points,
+        (const S *)tmp,
+        n,
+        chunk);
+    return cudaGetLastError();
+}
+
+extern "C"
+{
+    // field: 0 for pallas base (vesta scalar), 1 for pallas scalar (vesta base)
+    // op: 0 add, 1 mul, 3 sub, same encoding as the bn254 field_op
+    cudaError_t pasta_field_op(
+        int field,
+        void *res,
+        void *l,
+        void *r,
+        int n,
+        int op,
+        CUstream_st *stream)
+    {
+        if (field == 0)
+            return pasta_field_op_t<PastaFpField>(res, l, r, n, op, stream);
+        else
+            return pasta_field_op_t<PastaFqField>(res, l, r, n, op, stream);
+    }
+
+    // in place radix-2 ntt, followed by a scale when divisor is not null
+    cudaError_t pasta_ntt(
+        int field,
+        void *buf,
+        void *twiddles,
+        void *divisor,
+        int log_n,
+        CUstream_st *stream)
+    {
+        if (field == 0)
+            return pasta_ntt_t<PastaFpField>(buf, twiddles, divisor, log_n, stream);
+        else
+            return pasta_ntt_t<PastaFqField>(buf, twiddles, divisor, log_n, stream);
+    }
+
+    // curve: 0 for pallas, 1 for vesta
+    // res receives one jacobian point per work item, tmp holds n scalars
+    cudaError_t pasta_msm(
+        int curve,
+        void *res,
+        void *points,
+        void *scalars,
+        void *tmp,
+        int n,
+        int work_items,
+        CUstream_st *stream)
+    {
+        if (curve == 0)
+            return pasta_msm_t<PastaFpField, PastaFqField>(res, points, scalars, tmp, n, work_items, stream);
+        else
+            return pasta_msm_t<PastaFqField, PastaFpField>(res, points, scalars, tmp, n, work_items, stream);
+    }
+}
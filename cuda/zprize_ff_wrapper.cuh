@@ -134,5 +134,5 @@ public:
     }
 };
 
-typedef Field<fd_q, fd_q::storage> Bn254FrField;
+typedef Field<fd_q, fd_q::storage> Bn254FrField32;
 typedef Field<fd_p, fd_p::storage> Bn254FpField;
@@ -0,0 +1,61 @@
+/* C interface of zkwasm-prover, see src/ffi.rs. */
+#ifndef ZKWASM_PROVER_H
+#define ZKWASM_PROVER_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum {
+    ZKW_OK = 0,
+    ZKW_INVALID_ARGUMENT = 1,
+    ZKW_IO = 2,
+    ZKW_DEVICE = 3,
+    ZKW_OUT_OF_MEMORY = 4,
+    ZKW_HOST_OUT_OF_MEMORY = 5,
+    ZKW_KERNEL = 6,
+    ZKW_CANCELLED = 7,
+    ZKW_PANIC = 8,
+    ZKW_TIMEOUT = 9,
+} ZkwStatus;
+
+typedef struct ZkwParams ZkwParams;
+typedef struct ZkwProvingKey ZkwProvingKey;
+typedef struct ZkwAdvices ZkwAdvices;
+
+typedef struct {
+    uint8_t *ptr;
+    size_t len;
+} ZkwBytes;
+
+/* Scalars are 32-byte little endian canonical encodings. */
+
+const char *zkw_last_error(void);
+
+ZkwStatus zkw_params_read(const uint8_t *data, size_t len, ZkwParams **out);
+void zkw_params_free(ZkwParams *params);
+
+/* Reads a proving key file with the reader registered by the circuit's library. */
+ZkwStatus zkw_proving_key_read(const ZkwParams *params, const char *path, ZkwProvingKey **out);
+void zkw_proving_key_free(ZkwProvingKey *pk);
+
+ZkwStatus zkw_advices_new(const ZkwProvingKey *pk, ZkwAdvices **out);
+ZkwStatus zkw_advices_set_column(ZkwAdvices *advices, size_t column, const uint8_t *data,
+                                 size_t rows);
+void zkw_advices_free(ZkwAdvices *advices);
+
+ZkwStatus zkw_create_proof(const ZkwParams *params, const ZkwProvingKey *pk,
+                           const uint8_t *const *instances, const size_t *instance_rows,
+                           size_t instance_columns, const ZkwAdvices *advices, bool use_gwc,
+                           int32_t device_id, ZkwBytes *proof);
+void zkw_bytes_free(ZkwBytes bytes);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,378 @@
+// Portable bn254 kernels for the OpenCL fallback backend.
+// Field elements are 4 little-endian 64-bit limbs in montgomery form,
+// the same layout as halo2's Fr/Fq.
+
+typedef struct
+{
+    ulong v[4];
+} field_t;
+
+typedef struct
+{
+    field_t x;
+    field_t y;
+} affine_t;
+
+typedef struct
+{
+    field_t x;
+    field_t y;
+    field_t z;
+} jacobian_t;
+
+__constant ulong FR_MODULUS[4] = {
+    0x43e1f593f0000001ul,
+    0x2833e84879b97091ul,
+    0xb85045b68181585dul,
+    0x30644e72e131a029ul,
+};
+__constant ulong FR_INV = 0xc2e1f593effffffful;
+
+__constant ulong FQ_MODULUS[4] = {
+    0x3c208c16d87cfd47ul,
+    0x97816a916871ca8dul,
+    0xb85045b68181585dul,
+    0x30644e72e131a029ul,
+};
+__constant ulong FQ_INV = 0x87d20782e4866389ul;
+__constant ulong FQ_R[4] = {
+    0xd35d438dc58f0d9dul,
+    0x0a78eb28f5c70b3dul,
+    0x666ea36f7879462cul,
+    0x0e0a77c19a07df2ful,
+};
+
+static inline ulong mac(ulong a, ulong b, ulong c, ulong *carry)
+{
+    ulong lo = b * c;
+    ulong hi = mul_hi(b, c);
+    lo += a;
+    hi += lo < a;
+    lo += *carry;
+    hi += lo < *carry;
+    *carry = hi;
+    return lo;
+}
+
+static inline ulong adc(ulong a, ulong b, ulong *carry)
+{
+    ulong r = a + b;
+    ulong c = r < a;
+    r += *carry;
+    c += r < *carry;
+    *carry = c;
+    return r;
+}
+
+static inline ulong sbb(ulong a, ulong b, ulong *borrow)
+{
+    ulong r = a - b;
+    ulong c = a < b;
+    ulong t = r;
+    r -= *borrow;
+    c += t < *borrow;
+    *borrow = c;
+    return r;
+}
+
+static inline bool field_gte(const field_t *a, __constant const ulong *p)
+{
+    for (int i = 3; i >= 0; i--)
+    {
+        if (a->v[i] > p[i])
+            return true;
+        if (a->v[i] < p[i])
+            return false;
+    }
+    return true;
+}
+
+static inline field_t field_sub_mod(field_t a, __constant const ulong *p)
+{
+    ulong borrow = 0;
+    for (int i = 0; i < 4; i++)
+    {
+        a.v[i] = sbb(a.v[i], p[i], &borrow);
+    }
+    return a;
+}
+
+static inline field_t field_add(field_t a, field_t b, __constant const ulong *p)
+{
+    ulong carry = 0;
+    field_t r;
+    for (int i = 0; i < 4; i++)
+    {
+        r.v[i] = adc(a.v[i], b.v[i], &carry);
+    }
+    if (carry || field_gte(&r, p))
+    {
+        r = field_sub_mod(r, p);
+    }
+    return r;
+}
+
+static inline field_t field_sub(field_t a, field_t b, __constant const ulong *p)
+{
+    ulong borrow = 0;
+    field_t r;
+    for (int i = 0; i < 4; i++)
+    {
+        r.v[i] = sbb(a.v[i], b.v[i], &borrow);
+    }
+    if (borrow)
+    {
+        ulong carry = 0;
+        for (int i = 0; i < 4; i++)
+        {
+            r.v[i] = adc(r.v[i], p[i], &carry);
+        }
+    }
+    return r;
+}
+
+// CIOS montgomery multiplication
+static inline field_t field_mul(field_t a, field_t b, __constant const ulong *p, ulong inv)
+{
+    ulong t[6] = {0, 0, 0, 0, 0, 0};
+    for (int i = 0; i < 4; i++)
+    {
+        ulong carry = 0;
+        for (int j = 0; j < 4; j++)
+        {
+            t[j] = mac(t[j], a.v[j], b.v[i], &carry);
+        }
+        ulong c = 0;
+        t[4] = adc(t[4], carry, &c);
+        t[5] = c;
+
+        ulong m = t[0] * inv;
+        carry = 0;
+        mac(t[0], m, p[0], &carry);
+        for (int j = 1; j < 4; j++)
+        {
+            t[j - 1] = mac(t[j], m, p[j], &carry);
+        }
+        c = 0;
+        t[3] = adc(t[4], carry, &c);
+        t[4] = t[5] + c;
+    }
+
+    field_t r;
+    for (int i = 0; i < 4; i++)
+    {
+        r.v[i] = t[i];
+    }
+    if (t[4] || field_gte(&r, p))
+    {
+        r = field_sub_mod(r, p);
+    }
+    return r;
+}
+
+static inline bool field_is_zero(field_t a)
+{
+    return (a.v[0] | a.v[1] | a.v[2] | a.v[3]) == 0;
+}
+
+#define FR_ADD(a, b) field_add(a, b, FR_MODULUS)
+#define FR_SUB(a, b) field_sub(a, b, FR_MODULUS)
+#define FR_MUL(a, b) field_mul(a, b, FR_MODULUS, FR_INV)
+#define FQ_ADD(a, b) field_add(a, b, FQ_MODULUS)
+#define FQ_SUB(a, b) field_sub(a, b, FQ_MODULUS)
+#define FQ_MUL(a, b) field_mul(a, b, FQ_MODULUS, FQ_INV)
+
+// ---------------- field ops ----------------
+
+// op: 0 add, 1 mul, 3 sub, same encoding as the cuda field_op
+__kernel void field_op(
+    __global field_t *res,
+    __global const field_t *l,
+    __global const field_t *r,
+    uint op)
+{
+    uint gid = get_global_id(0);
+    if (op == 0)
+    {
+        res[gid] = FR_ADD(l[gid], r[gid]);
+    }
+    else if (op == 1)
+    {
+        res[gid] = FR_MUL(l[gid], r[gid]);
+    }
+    else
+    {
+        res[gid] = FR_SUB(l[gid], r[gid]);
+    }
+}
+
+__kernel void field_scale(
+    __global field_t *buf,
+    __global const field_t *c)
+{
+    uint gid = get_global_id(0);
+    buf[gid] = FR_MUL(buf[gid], c[0]);
+}
+
+// montgomery to canonical form, i.e. multiply by 1
+__kernel void field_unmont(
+    __global field_t *dst,
+    __global const field_t *src)
+{
+    uint gid = get_global_id(0);
+    field_t one = {{1, 0, 0, 0}};
+    dst[gid] = FR_MUL(src[gid], one);
+}
+
+// ---------------- ntt ----------------
+
+static inline uint reverse_bits(uint x)
+{
+    x = ((x >> 1) & 0x55555555u) | ((x & 0x55555555u) << 1);
+    x = ((x >> 2) & 0x33333333u) | ((x & 0x33333333u) << 2);
+    x = ((x >> 4) & 0x0f0f0f0fu) | ((x & 0x0f0f0f0fu) << 4);
+    x = ((x >> 8) & 0x00ff00ffu) | ((x & 0x00ff00ffu) << 8);
+    return (x >> 16) | (x << 16);
+}
+
+__kernel void ntt_bit_reverse(
+    __global field_t *buf,
+    uint log_n)
+{
+    uint i = get_global_id(0);
+    uint j = reverse_bits(i) >> (32 - log_n);
+    if (i < j)
+    {
+        field_t t = buf[i];
+        buf[i] = buf[j];
+        buf[j] = t;
+    }
+}
+
+// twiddles[i] = omega ^ i for i < n / 2
+__kernel void ntt_stage(
+    __global field_t *buf,
+    __global const field_t *twiddles,
+    uint log_n,
+    uint stage)
+{
+    uint gid = get_global_id(0);
+    uint half_len = 1u << stage;
+    uint pos = gid & (half_len - 1);
+    uint i = ((gid >> stage) << (stage + 1)) + pos;
+    uint j = i + half_len;
+
+    field_t w = twiddles[pos << (log_n - 1 - stage)];
+    field_t t = FR_MUL(buf[j], w);
+    buf[j] = FR_SUB(buf[i], t);
+    buf[i] = FR_ADD(buf[i], t);
+}
+
+// ---------------- msm ----------------
+
+static inline jacobian_t jacobian_double(jacobian_t p)
+{
+    if (field_is_zero(p.z))
+    {
+        return p;
+    }
+
+    field_t a = FQ_MUL(p.x, p.x);
+    field_t b = FQ_MUL(p.y, p.y);
+    field_t c = FQ_MUL(b, b);
+    field_t d = FQ_ADD(p.x, b);
+    d = FQ_MUL(d, d);
+    d = FQ_SUB(FQ_SUB(d, a), c);
+    d = FQ_ADD(d, d);
+    field_t e = FQ_ADD(FQ_ADD(a, a), a);
+    field_t f = FQ_MUL(e, e);
+
+    jacobian_t r;
+    r.x = FQ_SUB(FQ_SUB(f, d), d);
+    field_t c8 = FQ_ADD(c, c);
+    c8 = FQ_ADD(c8, c8);
+    c8 = FQ_ADD(c8, c8);
+    r.y = FQ_SUB(FQ_MUL(e, FQ_SUB(d, r.x)), c8);
+    field_t yz = FQ_MUL(p.y, p.z);
+    r.z = FQ_ADD(yz, yz);
+    return r;
+}
+
+static inline jacobian_t jacobian_add_affine(jacobian_t p, affine_t q)
+{
+    if (field_is_zero(q.x) && field_is_zero(q.y))
+    {
+        return p;
+    }
+
+    if (field_is_zero(p.z))
+    {
+        jacobian_t r;
+        r.x = q.x;
+        r.y = q.y;
+        for (int i = 0; i < 4; i++)
+        {
+            r.z.v[i] = FQ_R[i];
+        }
+        return r;
+    }
+
+    field_t z1z1 = FQ_MUL(p.z, p.z);
+    field_t u2 = FQ_MUL(q.x, z1z1);
+    field_t s2 = FQ_MUL(FQ_MUL(q.y, p.z), z1z1);
+    field_t h = FQ_SUB(u2, p.x);
+    field_t rr = FQ_SUB(s2, p.y);
+
+    if (field_is_zero(h))
+    {
+        if (field_is_zero(rr))
+        {
+            return jacobian_double(p);
+        }
+        jacobian_t inf = {{{{0, 0, 0, 0}}, {{0, 0, 0, 0}}, {{0, 0, 0, 0}}}};
+        return inf;
+    }
+
+    rr = FQ_ADD(rr, rr);
+    field_t hh = FQ_MUL(h, h);
+    field_t i = FQ_ADD(hh, hh);
+    i = FQ_ADD(i, i);
+    field_t j = FQ_MUL(h, i);
+    field_t v = FQ_MUL(p.x, i);
+
+    jacobian_t r;
+    r.x = FQ_SUB(FQ_SUB(FQ_SUB(FQ_MUL(rr, rr), j), v), v);
+    field_t y1j = FQ_MUL(p.y, j);
+    r.y = FQ_SUB(FQ_MUL(rr, FQ_SUB(v, r.x)), FQ_ADD(y1j, y1j));
+    field_t z = FQ_ADD(p.z, h);
+    r.z = FQ_SUB(FQ_SUB(FQ_MUL(z, z), z1z1), hh);
+    return r;
+}
+
+// Every work item accumulates sum(scalars[i] * points[i]) over its chunk with
+// shared doublings; scalars must already be in canonical form.
+__kernel void msm_chunk(
+    __global const affine_t *points,
+    __global const field_t *scalars,
+    __global jacobian_t *res,
+    uint n,
+    uint chunk)
+{
+    uint gid = get_global_id(0);
+    uint start = gid * chunk;
+    uint end = min(start + chunk, n);
+
+    jacobian_t acc = {{{{0, 0, 0, 0}}, {{0, 0, 0, 0}}, {{0, 0, 0, 0}}}};
+    for (int bit = 253; bit >= 0; bit--)
+    {
+        acc = jacobian_double(acc);
+        for (uint i = start; i < end; i++)
+        {
+            if ((scalars[i].v[bit >> 6] >> (bit & 63)) & 1)
+            {
+                acc = jacobian_add_affine(acc, points[i]);
+            }
+        }
+    }
+    res[gid] = acc;
+}
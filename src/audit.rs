@@ -0,0 +1,47 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::scoped::FlagGuard;
+use crate::scoped::ScopedFlag;
+
+/// Process wide, a proof with `ProverConfig::audit` adds to it while it runs.
+static AUDIT_MODE: ScopedFlag = ScopedFlag::new(false);
+
+lazy_static! {
+    static ref WHITELIST: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+}
+
+/// In audit mode challenges, evaluations, msm results and witness buffers are
+/// never written to logs or `Debug` output, only a `<redacted name>` marker is.
+pub fn set_audit_mode(enabled: bool) {
+    AUDIT_MODE.set(enabled);
+}
+
+pub fn audit_mode() -> bool {
+    AUDIT_MODE.get()
+}
+
+/// Audit mode for the duration of one proof.
+pub(crate) fn enter_audit_mode() -> FlagGuard {
+    AUDIT_MODE.enter()
+}
+
+/// Lets the values tagged `name` through in audit mode.
+/// Tags: "challenge", "evaluation", "msm_result", "poly".
+pub fn whitelist(name: &'static str) {
+    WHITELIST.lock().unwrap().insert(name);
+}
+
+/// `Debug` wrapper of a secret-dependent value tagged `name`.
+pub(crate) struct Secret<'a, T: ?Sized>(pub(crate) &'static str, pub(crate) &'a T);
+
+impl<'a, T: fmt::Debug + ?Sized> fmt::Debug for Secret<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if audit_mode() && !WHITELIST.lock().unwrap().contains(self.0) {
+            write!(f, "<redacted {}>", self.0)
+        } else {
+            self.1.fmt(f)
+        }
+    }
+}
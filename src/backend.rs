@@ -0,0 +1,687 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use ark_std::rand::RngCore;
+use halo2_proofs::arithmetic::best_fft_cpu;
+use halo2_proofs::arithmetic::best_multiexp;
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::pairing::group::Curve as _;
+use halo2_proofs::poly::commitment::Params;
+use halo2_proofs::poly::EvaluationDomain;
+use rayon::iter::IndexedParallelIterator as _;
+use rayon::iter::IntoParallelIterator as _;
+use rayon::iter::IntoParallelRefIterator as _;
+use rayon::iter::IntoParallelRefMutIterator as _;
+use rayon::iter::ParallelIterator as _;
+
+use crate::cuda::autotune::autotune_field_ops;
+use crate::cuda::autotune::autotune_msm;
+use crate::cuda::autotune::autotune_ntt;
+use crate::cuda::bn254::msm_window_bits;
+use crate::cuda::bn254::FieldOp;
+use crate::cuda::curve::gpu_curve;
+use crate::cuda::curve::is_bn254;
+use crate::cuda::curve::GpuCurve;
+use crate::cuda::precompute::attach_precomputed;
+use crate::cuda::precompute::detach_precomputed;
+use crate::cuda::precompute::precompute_bases;
+use crate::device;
+use crate::device::cuda::tag_buffer;
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::cuda::DeviceManager;
+#[cfg(feature = "opencl")]
+use crate::device::opencl::OpenCLDevice;
+#[cfg(feature = "opencl")]
+use crate::device::opencl::OpenCLDeviceBuf;
+use crate::device::Device as _;
+use crate::Error;
+
+/// Overrides the backend choice of `select_backend`: `cuda`, `opencl` or `cpu`.
+pub const BACKEND_ENV: &str = "ZKWASM_PROVER_BACKEND";
+
+/// A backend to prove with, see `ProverConfig::backend`. `Cuda` still falls
+/// back to OpenCL and the host when there is no CUDA device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Cuda,
+    OpenCL,
+    Cpu,
+}
+
+impl BackendKind {
+    fn from_env() -> Option<Self> {
+        match std::env::var(BACKEND_ENV).ok()?.as_str() {
+            "cuda" => Some(BackendKind::Cuda),
+            "opencl" => Some(BackendKind::OpenCL),
+            "cpu" => Some(BackendKind::Cpu),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommitmentBasis {
+    Lagrange,
+    Monomial,
+}
+
+/// Host-facing MSM/NTT/field operations used by the prover control flow.
+pub trait ProverBackend<C: CurveAffine>: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn commit(&self, basis: CommitmentBasis, values: Vec<&[C::Scalar]>) -> Result<Vec<C>, Error>;
+
+    /// Commits pairs of columns, e.g. the permuted input and table of a lookup.
+    fn commit_pairs(
+        &self,
+        basis: CommitmentBasis,
+        pairs: Vec<[&[C::Scalar]; 2]>,
+    ) -> Result<Vec<[C; 2]>, Error> {
+        let res = self.commit(basis, pairs.concat())?;
+        Ok(res.chunks(2).map(|x| [x[0], x[1]]).collect())
+    }
+
+    /// Lagrange form to coefficient form, in place.
+    fn batch_intt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error>;
+
+    /// Coefficient form to Lagrange form, in place.
+    fn batch_ntt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error>;
+
+    fn field_mul(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error>;
+
+    fn field_add(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error>;
+
+    /// Overwrites `column[start..]` of every column with random scalars drawn
+    /// on the host from `rng`, the caller's CSPRNG, one full field element per
+    /// cell. Blinders are never generated on device.
+    fn blind_tails(
+        &self,
+        columns: Vec<&mut [C::Scalar]>,
+        start: usize,
+        rng: &mut dyn RngCore,
+    ) -> Result<(), Error> {
+        for column in columns {
+            for cell in column.iter_mut().skip(start) {
+                *cell = C::Scalar::random(&mut *rng);
+            }
+        }
+        Ok(())
+    }
+
+    fn as_cuda(&self) -> Option<&CudaBackend<C>> {
+        None
+    }
+}
+
+pub struct CudaBackend<C: CurveAffine> {
+    pub(crate) device: CudaDevice,
+    pub(crate) k: usize,
+    pub(crate) curve: Arc<dyn GpuCurve<C>>,
+    pub(crate) g_lagrange_buf: Arc<CudaDeviceBufRaw>,
+    pub(crate) g_buf: Arc<CudaDeviceBufRaw>,
+    pub(crate) s_buf: CudaDeviceBufRaw,
+    pub(crate) t_buf: CudaDeviceBufRaw,
+    pub(crate) ntt_omegas_buf: Arc<CudaDeviceBufRaw>,
+    pub(crate) ntt_pq_buf: Arc<CudaDeviceBufRaw>,
+    pub(crate) intt_omegas_buf: Arc<CudaDeviceBufRaw>,
+    pub(crate) intt_pq_buf: Arc<CudaDeviceBufRaw>,
+    pub(crate) intt_divisor_buf: CudaDeviceBufRaw,
+}
+
+// Device buffers are only touched through the owning CudaDevice context.
+unsafe impl<C: CurveAffine> Send for CudaBackend<C> {}
+unsafe impl<C: CurveAffine> Sync for CudaBackend<C> {}
+
+impl<C: CurveAffine> CudaBackend<C> {
+    pub fn new(
+        device: CudaDevice,
+        params: &Params<C>,
+        domain: &EvaluationDomain<C::Scalar>,
+    ) -> Result<Self, Error> {
+        let g_lagrange_buf = device.alloc_device_buffer_from_slice(&params.g_lagrange[..])?;
+        let g_buf = device.alloc_device_buffer_from_slice(&params.g[..])?;
+        Self::with_bases(device, domain, [Arc::new(g_lagrange_buf), Arc::new(g_buf)])
+    }
+
+    /// Like `new`, with the `[g_lagrange, g]` bases already on `device`, see `CudaParams`.
+    pub fn with_bases(
+        device: CudaDevice,
+        domain: &EvaluationDomain<C::Scalar>,
+        bases: [Arc<CudaDeviceBufRaw>; 2],
+    ) -> Result<Self, Error> {
+        let curve = gpu_curve::<C>().ok_or_else(|| {
+            device::Error::DeviceError(format!(
+                "no gpu kernels registered for curve with base modulus {}",
+                C::Base::MODULUS
+            ))
+        })?;
+
+        // probed up front so that features are downgraded before the first allocation
+        device.capabilities()?;
+        device.check_kernel_arch()?;
+        device.preload_kernels()?;
+
+        let k = domain.k() as usize;
+        let size = 1 << k;
+
+        let [g_lagrange_buf, g_buf] = bases;
+        let s_buf = device.alloc_device_buffer::<C::Scalar>(size)?;
+        let t_buf = device.alloc_device_buffer::<C::Scalar>(size)?;
+        let (ntt_omegas_buf, ntt_pq_buf) = curve.ntt_prepare(&device, domain.get_omega(), k)?;
+        let (intt_omegas_buf, intt_pq_buf) =
+            curve.ntt_prepare(&device, domain.get_omega_inv(), k)?;
+        let intt_divisor_buf =
+            device.alloc_device_buffer_from_slice::<C::Scalar>(&[domain.ifft_divisor])?;
+
+        Ok(Self {
+            device,
+            k,
+            curve,
+            g_lagrange_buf,
+            g_buf,
+            s_buf,
+            t_buf,
+            ntt_omegas_buf,
+            ntt_pq_buf,
+            intt_omegas_buf,
+            intt_pq_buf,
+            intt_divisor_buf,
+        })
+    }
+
+    /// Commits over the Lagrange bases with a table of `factor` precomputed
+    /// multiples per base from now on, see `cuda::precompute::precompute_bases`.
+    /// Only the bn254 msm uses the table, other curves ignore it.
+    pub fn use_precomputed_lagrange(
+        &self,
+        params: &Params<C>,
+        factor: usize,
+        dir: Option<&Path>,
+    ) -> Result<(), Error> {
+        if self.curve.name() != "bn254" || factor < 2 {
+            return Ok(());
+        }
+        let c = match msm_window_bits::<C>(&self.device, 1 << self.k, 1) {
+            0 => 16,
+            c => c as usize,
+        };
+        let table = precompute_bases(&self.device, &params.g_lagrange[..], factor, c, dir)?;
+        attach_precomputed(&self.g_lagrange_buf, table);
+        Ok(())
+    }
+
+    /// Picks the launch configurations of the field kernels and the ntt on the
+    /// domain and its extension, and the window bits of the msm over the
+    /// Lagrange bases, see `cuda::autotune`. Runs before the bases are
+    /// precomputed, their table is laid out for the window bits.
+    pub fn autotune(&self, domain: &EvaluationDomain<C::Scalar>) -> Result<(), Error> {
+        if self.curve.name() != "bn254" {
+            return Ok(());
+        }
+        let extended_k = domain.extended_k() as usize;
+        autotune_field_ops::<C::Scalar>(&self.device, self.k)?;
+        autotune_field_ops::<C::Scalar>(&self.device, extended_k)?;
+        autotune_ntt(&self.device, domain.get_omega(), self.k)?;
+        autotune_ntt(&self.device, domain.get_extended_omega(), extended_k)?;
+        autotune_msm::<C>(&self.g_lagrange_buf, 1 << self.k)?;
+        Ok(())
+    }
+
+    fn field_op(&self, res: &mut [C::Scalar], rhs: &[C::Scalar], op: FieldOp) -> Result<(), Error> {
+        let res_buf = self.device.alloc_device_buffer_from_slice(res)?;
+        let rhs_buf = self.device.alloc_device_buffer_from_slice(rhs)?;
+        self.curve
+            .field_op(&self.device, &res_buf, &res_buf, &rhs_buf, res.len(), op)?;
+        self.device.copy_from_device_to_host(res, &res_buf)?;
+        Ok(())
+    }
+
+    /// `commit_pairs` over the Lagrange bases that leaves each pair on device,
+    /// back to back in one buffer, see `ProverConfig::resident_permuted`.
+    pub(crate) fn commit_pairs_resident(
+        &self,
+        pairs: Vec<[&[C::Scalar]; 2]>,
+    ) -> Result<Vec<([C; 2], CudaDeviceBufRaw)>, Error> {
+        let len = 1 << self.k;
+        pairs
+            .into_iter()
+            .map(|pair| {
+                let buf = self.device.alloc_device_buffer::<C::Scalar>(2 * len)?;
+                tag_buffer(&buf, "resident permuted lookup");
+                for (i, column) in pair.into_iter().enumerate() {
+                    let dst = buf.slice::<C::Scalar>(i * len, len)?;
+                    self.device.copy_from_host_to_device(&dst, column)?;
+                }
+                let commitments = self.curve.batch_msm_device(
+                    &self.device,
+                    &self.g_lagrange_buf,
+                    [&self.s_buf, &self.t_buf],
+                    &buf,
+                    2,
+                    len,
+                )?;
+                Ok::<_, Error>(([commitments[0], commitments[1]], buf))
+            })
+            .collect()
+    }
+}
+
+impl<C: CurveAffine> Drop for CudaBackend<C> {
+    fn drop(&mut self) {
+        // shared bases keep their table for the next proof, `CudaParams` detaches it
+        if Arc::strong_count(&self.g_lagrange_buf) == 1 {
+            detach_precomputed(&self.g_lagrange_buf);
+        }
+    }
+}
+
+/// `params` with its bases uploaded once to each of a set of devices, so
+/// proofs on those devices don't upload `g_lagrange` and `g` again.
+pub struct CudaParams<'a, C: CurveAffine> {
+    pub params: &'a Params<C>,
+    // device -> [g_lagrange, g]
+    bases: BTreeMap<usize, [Arc<CudaDeviceBufRaw>; 2]>,
+}
+
+unsafe impl<'a, C: CurveAffine> Send for CudaParams<'a, C> {}
+unsafe impl<'a, C: CurveAffine> Sync for CudaParams<'a, C> {}
+
+impl<'a, C: CurveAffine> CudaParams<'a, C> {
+    /// Uploads the bases of `params` to every device of `device_ids`.
+    pub fn new(params: &'a Params<C>, device_ids: &[usize]) -> Result<Self, Error> {
+        let mut bases = BTreeMap::new();
+        for &device_id in device_ids {
+            let device = CudaDevice::get_device(device_id)?;
+            let g_lagrange_buf = device.alloc_device_buffer_from_slice(&params.g_lagrange[..])?;
+            tag_buffer(&g_lagrange_buf, "srs g_lagrange");
+            let g_buf = device.alloc_device_buffer_from_slice(&params.g[..])?;
+            tag_buffer(&g_buf, "srs g");
+            bases.insert(device_id, [Arc::new(g_lagrange_buf), Arc::new(g_buf)]);
+        }
+        Ok(CudaParams { params, bases })
+    }
+
+    pub fn device_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bases.keys().cloned()
+    }
+
+    // none once the device was reset, the proof then uploads its own copy
+    pub(crate) fn bases(&self, device_id: usize) -> Option<[Arc<CudaDeviceBufRaw>; 2]> {
+        self.bases
+            .get(&device_id)
+            .filter(|[g_lagrange, _]| !g_lagrange.is_stale())
+            .cloned()
+    }
+}
+
+impl<'a, C: CurveAffine> Drop for CudaParams<'a, C> {
+    fn drop(&mut self) {
+        for [g_lagrange_buf, _] in self.bases.values() {
+            detach_precomputed(g_lagrange_buf);
+        }
+    }
+}
+
+impl<C: CurveAffine> ProverBackend<C> for CudaBackend<C> {
+    fn name(&self) -> &'static str {
+        "cuda"
+    }
+
+    fn commit(&self, basis: CommitmentBasis, values: Vec<&[C::Scalar]>) -> Result<Vec<C>, Error> {
+        let p_buf = match basis {
+            CommitmentBasis::Lagrange => &self.g_lagrange_buf,
+            CommitmentBasis::Monomial => &self.g_buf,
+        };
+        Ok(self.curve.batch_msm(
+            &self.device,
+            p_buf,
+            [&self.s_buf, &self.t_buf],
+            values,
+            1 << self.k,
+        )?)
+    }
+
+    fn commit_pairs(
+        &self,
+        basis: CommitmentBasis,
+        pairs: Vec<[&[C::Scalar]; 2]>,
+    ) -> Result<Vec<[C; 2]>, Error> {
+        let p_buf = match basis {
+            CommitmentBasis::Lagrange => &self.g_lagrange_buf,
+            CommitmentBasis::Monomial => &self.g_buf,
+        };
+        Ok(self.curve.batch_msm_paired(
+            &self.device,
+            p_buf,
+            [&self.s_buf, &self.t_buf],
+            pairs,
+            1 << self.k,
+        )?)
+    }
+
+    fn batch_intt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error> {
+        self.curve.batch_intt(
+            &self.device,
+            values,
+            &self.intt_omegas_buf,
+            &self.intt_pq_buf,
+            &self.intt_divisor_buf,
+            self.k,
+        )?;
+        Ok(())
+    }
+
+    fn batch_ntt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error> {
+        self.curve.batch_ntt(
+            &self.device,
+            values,
+            &self.ntt_omegas_buf,
+            &self.ntt_pq_buf,
+            self.k,
+        )?;
+        Ok(())
+    }
+
+    fn field_mul(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error> {
+        self.field_op(res, rhs, FieldOp::Mul)
+    }
+
+    fn field_add(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error> {
+        self.field_op(res, rhs, FieldOp::Add)
+    }
+
+    fn as_cuda(&self) -> Option<&CudaBackend<C>> {
+        Some(self)
+    }
+}
+
+/// Pure host implementation on top of halo2's arithmetic.
+pub struct CpuBackend<'a, C: CurveAffine> {
+    params: &'a Params<C>,
+    k: u32,
+    omega: C::Scalar,
+    omega_inv: C::Scalar,
+    ifft_divisor: C::Scalar,
+}
+
+impl<'a, C: CurveAffine> CpuBackend<'a, C> {
+    pub fn new(params: &'a Params<C>, domain: &EvaluationDomain<C::Scalar>) -> Self {
+        Self {
+            params,
+            k: domain.k(),
+            omega: domain.get_omega(),
+            omega_inv: domain.get_omega_inv(),
+            ifft_divisor: domain.ifft_divisor,
+        }
+    }
+}
+
+impl<'a, C: CurveAffine> ProverBackend<C> for CpuBackend<'a, C> {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn commit(&self, basis: CommitmentBasis, values: Vec<&[C::Scalar]>) -> Result<Vec<C>, Error> {
+        let bases = match basis {
+            CommitmentBasis::Lagrange => &self.params.g_lagrange[..],
+            CommitmentBasis::Monomial => &self.params.g[..],
+        };
+        Ok(values
+            .into_iter()
+            .map(|value| best_multiexp(value, &bases[..value.len()]).to_affine())
+            .collect())
+    }
+
+    fn batch_intt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error> {
+        values.into_par_iter().for_each(|value| {
+            best_fft_cpu(value, self.omega_inv, self.k);
+            value
+                .par_iter_mut()
+                .for_each(|x| *x = *x * self.ifft_divisor);
+        });
+        Ok(())
+    }
+
+    fn batch_ntt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error> {
+        values.into_par_iter().for_each(|value| {
+            best_fft_cpu(value, self.omega, self.k);
+        });
+        Ok(())
+    }
+
+    fn field_mul(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error> {
+        res.par_iter_mut()
+            .zip(rhs.par_iter())
+            .for_each(|(l, r)| *l = *l * r);
+        Ok(())
+    }
+
+    fn field_add(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error> {
+        res.par_iter_mut()
+            .zip(rhs.par_iter())
+            .for_each(|(l, r)| *l = *l + r);
+        Ok(())
+    }
+}
+
+/// OpenCL fallback for hosts without a CUDA runtime. Its kernels only
+/// implement bn254.
+#[cfg(feature = "opencl")]
+pub struct OpenCLBackend {
+    device: OpenCLDevice,
+    k: usize,
+    g_lagrange_buf: OpenCLDeviceBuf,
+    g_buf: OpenCLDeviceBuf,
+    ntt_twiddles_buf: OpenCLDeviceBuf,
+    intt_twiddles_buf: OpenCLDeviceBuf,
+    intt_divisor_buf: OpenCLDeviceBuf,
+}
+
+#[cfg(feature = "opencl")]
+impl OpenCLBackend {
+    pub fn new<C: CurveAffine>(
+        device: OpenCLDevice,
+        params: &Params<C>,
+        domain: &EvaluationDomain<C::Scalar>,
+    ) -> Result<Self, Error> {
+        use crate::opencl::bn254::ntt_prepare;
+
+        if !is_bn254::<C>() {
+            return Err(Error::InvalidInput(
+                "the opencl backend only proves on bn254".to_string(),
+            ));
+        }
+
+        let k = domain.k() as usize;
+        let g_lagrange_buf = device.alloc_device_buffer_from_slice(&params.g_lagrange[..])?;
+        let g_buf = device.alloc_device_buffer_from_slice(&params.g[..])?;
+        let ntt_twiddles_buf = ntt_prepare(&device, domain.get_omega(), k)?;
+        let intt_twiddles_buf = ntt_prepare(&device, domain.get_omega_inv(), k)?;
+        let intt_divisor_buf =
+            device.alloc_device_buffer_from_slice::<C::Scalar>(&[domain.ifft_divisor])?;
+
+        Ok(Self {
+            device,
+            k,
+            g_lagrange_buf,
+            g_buf,
+            ntt_twiddles_buf,
+            intt_twiddles_buf,
+            intt_divisor_buf,
+        })
+    }
+
+    fn field_op<F: FieldExt>(&self, res: &mut [F], rhs: &[F], op: FieldOp) -> Result<(), Error> {
+        let res_buf = self.device.alloc_device_buffer_from_slice(res)?;
+        let rhs_buf = self.device.alloc_device_buffer_from_slice(rhs)?;
+        crate::opencl::bn254::field_op(&self.device, &res_buf, &res_buf, &rhs_buf, res.len(), op)?;
+        self.device.copy_from_device_to_host(res, &res_buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "opencl")]
+impl<C: CurveAffine> ProverBackend<C> for OpenCLBackend {
+    fn name(&self) -> &'static str {
+        "opencl"
+    }
+
+    fn commit(&self, basis: CommitmentBasis, values: Vec<&[C::Scalar]>) -> Result<Vec<C>, Error> {
+        let p_buf = match basis {
+            CommitmentBasis::Lagrange => &self.g_lagrange_buf,
+            CommitmentBasis::Monomial => &self.g_buf,
+        };
+        let mut res = vec![];
+        for value in values {
+            res.push(crate::opencl::bn254::msm::<C>(&self.device, p_buf, value)?);
+        }
+        Ok(res)
+    }
+
+    fn batch_intt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error> {
+        let buf = self.device.alloc_device_buffer::<C::Scalar>(1 << self.k)?;
+        for value in values {
+            self.device.copy_from_host_to_device(&buf, value)?;
+            crate::opencl::bn254::intt_raw(
+                &self.device,
+                &buf,
+                &self.intt_twiddles_buf,
+                &self.intt_divisor_buf,
+                self.k,
+            )?;
+            self.device.copy_from_device_to_host(value, &buf)?;
+        }
+        Ok(())
+    }
+
+    fn batch_ntt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error> {
+        let buf = self.device.alloc_device_buffer::<C::Scalar>(1 << self.k)?;
+        for value in values {
+            self.device.copy_from_host_to_device(&buf, value)?;
+            crate::opencl::bn254::ntt_raw(&self.device, &buf, &self.ntt_twiddles_buf, self.k)?;
+            self.device.copy_from_device_to_host(value, &buf)?;
+        }
+        Ok(())
+    }
+
+    fn field_mul(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error> {
+        self.field_op(res, rhs, FieldOp::Mul)
+    }
+
+    fn field_add(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error> {
+        self.field_op(res, rhs, FieldOp::Add)
+    }
+}
+
+// On `device_id` when given, like the CUDA backend, otherwise the first device.
+// `None` for curves other than bn254.
+#[cfg(feature = "opencl")]
+fn opencl_backend<'a, C: CurveAffine>(
+    params: &'a Params<C>,
+    domain: &EvaluationDomain<C::Scalar>,
+    device_id: Option<usize>,
+) -> Result<Option<Box<dyn ProverBackend<C> + 'a>>, Error> {
+    if !is_bn254::<C>() {
+        return Ok(None);
+    }
+    let count = OpenCLDevice::get_device_count().unwrap_or(0);
+    if count == 0 {
+        return Ok(None);
+    }
+    let idx = device_id.unwrap_or(0);
+    if idx >= count {
+        return Err(Error::InvalidInput(format!(
+            "opencl device index {} out of range, {} devices",
+            idx, count
+        )));
+    }
+    let device = OpenCLDevice::get_device(idx)?;
+    tracing::info!("use opencl device {}", device.name());
+    Ok(Some(Box::new(OpenCLBackend::new(device, params, domain)?)))
+}
+
+#[cfg(not(feature = "opencl"))]
+fn opencl_backend<'a, C: CurveAffine>(
+    _params: &'a Params<C>,
+    _domain: &EvaluationDomain<C::Scalar>,
+    _device_id: Option<usize>,
+) -> Result<Option<Box<dyn ProverBackend<C> + 'a>>, Error> {
+    Ok(None)
+}
+
+/// CUDA when a device is available, then OpenCL, otherwise the host backend.
+/// `ZKWASM_PROVER_BACKEND` forces a specific one.
+pub fn select_backend<'a, C: CurveAffine>(
+    params: &'a Params<C>,
+    domain: &EvaluationDomain<C::Scalar>,
+) -> Result<Box<dyn ProverBackend<C> + 'a>, Error> {
+    select_backend_on_device(params, domain, None)
+}
+
+/// Like `select_backend`, a CUDA backend runs on `device_id` instead of the
+/// device picked by the `DeviceManager` policy.
+pub fn select_backend_on_device<'a, C: CurveAffine>(
+    params: &'a Params<C>,
+    domain: &EvaluationDomain<C::Scalar>,
+    device_id: Option<usize>,
+) -> Result<Box<dyn ProverBackend<C> + 'a>, Error> {
+    select_backend_with_params(params, domain, device_id, None, None)
+}
+
+/// Like `select_backend_on_device`, a CUDA backend on a device of `cuda_params`
+/// reuses the bases uploaded there. Other devices get their own copy. `kind`
+/// takes precedence over `ZKWASM_PROVER_BACKEND`.
+pub(crate) fn select_backend_with_params<'a, C: CurveAffine>(
+    params: &'a Params<C>,
+    domain: &EvaluationDomain<C::Scalar>,
+    device_id: Option<usize>,
+    cuda_params: Option<&CudaParams<C>>,
+    kind: Option<BackendKind>,
+) -> Result<Box<dyn ProverBackend<C> + 'a>, Error> {
+    match kind.or_else(BackendKind::from_env) {
+        Some(BackendKind::Cpu) => return Ok(Box::new(CpuBackend::new(params, domain))),
+        Some(BackendKind::OpenCL) => {
+            if !is_bn254::<C>() {
+                return Err(Error::InvalidInput(
+                    "the opencl backend only proves on bn254".to_string(),
+                ));
+            }
+            if let Some(backend) = opencl_backend(params, domain, device_id)? {
+                return Ok(backend);
+            }
+            tracing::warn!(
+                "no opencl device found or feature opencl disabled, fallback to cpu backend"
+            );
+            return Ok(Box::new(CpuBackend::new(params, domain)));
+        }
+        Some(BackendKind::Cuda) | None => {}
+    }
+
+    if gpu_curve::<C>().is_none() {
+        tracing::warn!("no gpu kernels for this curve, fallback to cpu backend");
+        return Ok(Box::new(CpuBackend::new(params, domain)));
+    }
+
+    let device_count = CudaDevice::get_device_count().unwrap_or(0);
+    if device_count == 0 {
+        if let Some(backend) = opencl_backend(params, domain, device_id)? {
+            tracing::warn!("no cuda device found, fallback to opencl backend");
+            return Ok(backend);
+        }
+        tracing::warn!("no cuda device found, fallback to cpu backend");
+        return Ok(Box::new(CpuBackend::new(params, domain)));
+    }
+
+    let device = match device_id {
+        Some(idx) => CudaDevice::get_device(idx)?,
+        None => DeviceManager::global().select_device()?,
+    };
+    match cuda_params.and_then(|x| x.bases(device.device_id())) {
+        Some(bases) => Ok(Box::new(CudaBackend::with_bases(device, domain, bases)?)),
+        None => Ok(Box::new(CudaBackend::new(device, params, domain)?)),
+    }
+}
@@ -0,0 +1,33 @@
+use std::process::exit;
+
+use zkwasm_prover::selftest::selftest;
+
+const USAGE: &str = "usage: zkwasm-prover selftest [--device <id>]";
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    match args.first().map(|x| x.as_str()) {
+        Some("selftest") => {
+            let device_id = match &args[1..] {
+                [] => 0,
+                [flag, id] if flag == "--device" => id.parse().unwrap_or_else(|_| {
+                    eprintln!("invalid device id {}", id);
+                    exit(2)
+                }),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    exit(2)
+                }
+            };
+
+            let report = selftest(device_id);
+            println!("{}", report);
+            exit(if report.passed() { 0 } else { 1 })
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2)
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Device buffers kept across proofs by a long-running process, keyed by what
+//! they are computed from. A buffer is computed by the first proof needing it
+//! on a device and shared by the following ones. Clearing a cache only drops
+//! its references, buffers still used by a running proof are freed when it
+//! completes.
+
+use std::any::TypeId;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::pairing::group::ff::PrimeField as _;
+
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::Device as _;
+use crate::device::DeviceResult;
+
+struct CachedBuf(Arc<CudaDeviceBufRaw>);
+
+unsafe impl Send for CachedBuf {}
+unsafe impl Sync for CachedBuf {}
+
+pub(crate) struct DeviceBufCache<K> {
+    bufs: Mutex<HashMap<K, Vec<CachedBuf>>>,
+}
+
+impl<K: Eq + Hash> DeviceBufCache<K> {
+    pub(crate) fn new() -> Self {
+        DeviceBufCache {
+            bufs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The buffers of `key`, computed by `init` when they aren't cached yet.
+    pub(crate) fn get_or_try_insert<const N: usize>(
+        &self,
+        key: K,
+        init: impl FnOnce() -> DeviceResult<[CudaDeviceBufRaw; N]>,
+    ) -> DeviceResult<[Arc<CudaDeviceBufRaw>; N]> {
+        // held while `init` runs, so concurrent proofs don't compute the same buffers twice
+        let mut bufs = self.bufs.lock().unwrap();
+        let cached = match bufs.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                init()?
+                    .into_iter()
+                    .map(|buf| CachedBuf(Arc::new(buf)))
+                    .collect(),
+            ),
+        };
+        Ok(std::array::from_fn(|i| cached[i].0.clone()))
+    }
+
+    pub(crate) fn clear(&self) {
+        self.bufs.lock().unwrap().clear();
+    }
+
+    /// Drops the buffers of the keys `on_device` selects.
+    pub(crate) fn clear_where(&self, on_device: impl Fn(&K) -> bool) {
+        // called after a failed proof, whose panic may have poisoned the lock
+        self.bufs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|key, _| !on_device(key));
+    }
+}
+
+// (device, scalar, log size, omega)
+type NttKey = (usize, TypeId, usize, Vec<u8>);
+
+lazy_static! {
+    // (omegas, pq) of the ntt
+    static ref NTT_TABLES: DeviceBufCache<NttKey> = DeviceBufCache::new();
+}
+
+/// The `(omegas, pq)` tables of the ntt of size `1 << len_log` over `omega` on
+/// `device`, computed by `prepare` on the first proof and kept afterwards.
+pub(crate) fn ntt_tables<F: FieldExt>(
+    device: &CudaDevice,
+    omega: F,
+    len_log: usize,
+    prepare: impl FnOnce() -> DeviceResult<(CudaDeviceBufRaw, CudaDeviceBufRaw)>,
+) -> DeviceResult<(Arc<CudaDeviceBufRaw>, Arc<CudaDeviceBufRaw>)> {
+    let key = (
+        device.device_id(),
+        TypeId::of::<F>(),
+        len_log,
+        omega.to_repr().as_ref().to_vec(),
+    );
+    let [omegas, pq] = NTT_TABLES.get_or_try_insert(key, || {
+        let (omegas, pq) = prepare()?;
+        Ok([omegas, pq])
+    })?;
+    Ok((omegas, pq))
+}
+
+/// Frees the ntt twiddle tables kept across proofs, for every device and size.
+pub fn clear_ntt_cache() {
+    NTT_TABLES.clear();
+}
+
+/// Frees the ntt twiddle tables of `device_id`.
+pub(crate) fn clear_device_ntt_cache(device_id: usize) {
+    NTT_TABLES.clear_where(|key| key.0 == device_id);
+}
@@ -0,0 +1,184 @@
+//! The `prove` command: params, a proving key and a witness written by
+//! `write_witness` in, a proof file out, so a proof can be made or debugged
+//! without writing Rust. Reading a proving key back needs the constraint
+//! system of its circuit, which this crate does not have: the binary of a
+//! circuit passes a `read_pk` for it and forwards its arguments to
+//! `prove_command`, and registers a bn254 one with `set_pk_reader` for the
+//! language bindings.
+
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write as _;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::pairing::bn256::G1Affine;
+use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::poly::commitment::Params;
+use halo2_proofs::transcript::Blake2bWrite;
+use halo2_proofs::transcript::Challenge255;
+use halo2_proofs::transcript::TranscriptWrite as _;
+use rand::rngs::OsRng;
+
+use crate::config::ProverConfig;
+use crate::create_proof_from_advices_with_config;
+use crate::metrics::ProofMetrics;
+use crate::proof::Proof;
+use crate::witness::map_witness;
+use crate::Error;
+
+pub const PROVE_USAGE: &str = "usage: prove --params <file> --pk <file> --witness <file> \
+                               --proof <file> [--device <id>] [--gwc]";
+
+/// Reads a bn254 proving key, for the bindings which cannot be generic over
+/// the circuit.
+pub type PkReader = fn(&Params<G1Affine>, &mut BufReader<File>) -> io::Result<ProvingKey<G1Affine>>;
+
+static PK_READER: OnceLock<PkReader> = OnceLock::new();
+
+/// Sets how `read_proving_key` reads a proving key, once per process.
+pub fn set_pk_reader(read_pk: PkReader) {
+    let _ = PK_READER.set(read_pk);
+}
+
+/// Reads params and a proving key with the reader given to `set_pk_reader`.
+pub fn read_proving_key(
+    params: &Path,
+    pk: &Path,
+) -> Result<(Params<G1Affine>, ProvingKey<G1Affine>), Error> {
+    let params = Params::read(&mut BufReader::new(File::open(params)?))?;
+    let pk = read_registered_pk(&params, pk)?;
+    Ok((params, pk))
+}
+
+/// Reads a proving key for `params` with the reader given to `set_pk_reader`.
+pub fn read_registered_pk(
+    params: &Params<G1Affine>,
+    pk: &Path,
+) -> Result<ProvingKey<G1Affine>, Error> {
+    let read_pk = PK_READER.get().ok_or(Error::InvalidInput(
+        "no proving key reader registered".to_string(),
+    ))?;
+    Ok(read_pk(params, &mut BufReader::new(File::open(pk)?))?)
+}
+
+/// Arguments of the `prove` command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProveArgs {
+    pub params: PathBuf,
+    pub pk: PathBuf,
+    pub witness: PathBuf,
+    /// Written in the `Proof` format, transcript hashed with blake2b.
+    pub proof: PathBuf,
+    pub device_id: usize,
+    pub use_gwc: bool,
+}
+
+impl ProveArgs {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (mut params, mut pk, mut witness, mut proof) = (None, None, None, None);
+        let mut device_id = 0;
+        let mut use_gwc = false;
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            if flag == "--gwc" {
+                use_gwc = true;
+                continue;
+            }
+            let value = match flag.as_str() {
+                "--params" | "--pk" | "--witness" | "--proof" | "--device" => args
+                    .next()
+                    .ok_or_else(|| format!("missing value of {}", flag))?,
+                _ => return Err(format!("unknown argument {}", flag)),
+            };
+            match flag.as_str() {
+                "--params" => params = Some(PathBuf::from(value)),
+                "--pk" => pk = Some(PathBuf::from(value)),
+                "--witness" => witness = Some(PathBuf::from(value)),
+                "--proof" => proof = Some(PathBuf::from(value)),
+                _ => {
+                    device_id = value
+                        .parse()
+                        .map_err(|_| format!("invalid device id {}", value))?
+                }
+            }
+        }
+
+        let required = |path: Option<PathBuf>, flag: &str| path.ok_or(format!("missing {}", flag));
+        Ok(ProveArgs {
+            params: required(params, "--params")?,
+            pk: required(pk, "--pk")?,
+            witness: required(witness, "--witness")?,
+            proof: required(proof, "--proof")?,
+            device_id,
+            use_gwc,
+        })
+    }
+}
+
+/// Proves the witness of `args` on the device of `args` and writes the proof.
+pub fn prove<C, P>(args: &ProveArgs, read_pk: P) -> Result<ProofMetrics, Error>
+where
+    C: CurveAffine,
+    P: FnOnce(&Params<C>, &mut BufReader<File>) -> io::Result<ProvingKey<C>>,
+{
+    let params = Params::<C>::read(&mut BufReader::new(File::open(&args.params)?))?;
+    let pk = read_pk(&params, &mut BufReader::new(File::open(&args.pk)?))?;
+    let witness = map_witness(&args.witness, &pk)?;
+
+    let config = ProverConfig {
+        device_id: Some(args.device_id),
+        ..Default::default()
+    };
+    let mut transcript = Blake2bWrite::<_, C, Challenge255<C>>::init(vec![]);
+    let metrics = create_proof_from_advices_with_config(
+        &params,
+        &pk,
+        &witness.instance_slices()[..],
+        witness.advices.clone(),
+        &mut transcript,
+        OsRng,
+        args.use_gwc,
+        &config,
+    )?;
+
+    let mut writer = BufWriter::new(File::create(&args.proof)?);
+    Proof::new(transcript.finalize(), args.use_gwc, &config).write(&mut writer)?;
+    writer.flush()?;
+    Ok(metrics)
+}
+
+/// Runs the `prove` command with the arguments following it, reporting to
+/// stderr, and returns the exit code: 2 for bad arguments, 1 if proving failed.
+pub fn prove_command<C, P>(args: &[String], read_pk: P) -> i32
+where
+    C: CurveAffine,
+    P: FnOnce(&Params<C>, &mut BufReader<File>) -> io::Result<ProvingKey<C>>,
+{
+    let args = match ProveArgs::parse(args) {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}\n{}", msg, PROVE_USAGE);
+            return 2;
+        }
+    };
+    match prove(&args, read_pk) {
+        Ok(metrics) => {
+            eprintln!(
+                "proof written to {} in {:?}",
+                args.proof.display(),
+                metrics.total_time
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("fail to prove: {}", e);
+            1
+        }
+    }
+}
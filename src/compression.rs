@@ -0,0 +1,114 @@
+//! Optional compression of what the prover stores: proofs, and artifacts
+//! written through a `CompressedWriter`. Extended-domain buffers of large
+//! circuits are tens of GB uncompressed.
+//!
+//! zstd is only available with the `zstd` feature, asking for it otherwise
+//! fails with `io::ErrorKind::Unsupported`.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// zstd at `level`, 1 (fastest) to 22, 0 being zstd's default.
+    Zstd(i32),
+}
+
+impl Compression {
+    pub(crate) fn tag(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd(_) => 1,
+        }
+    }
+
+    /// The compression of a stored tag, levels aren't stored and don't matter to decompress.
+    pub(crate) fn from_tag(tag: u8) -> io::Result<Self> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd(0)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression {}", tag),
+            )),
+        }
+    }
+
+    /// Wraps `writer`, data written goes through the compressor as it comes.
+    pub fn writer<W: Write>(&self, writer: W) -> io::Result<CompressedWriter<W>> {
+        match self {
+            Compression::None => Ok(CompressedWriter::Plain(writer)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Ok(CompressedWriter::Zstd(zstd::stream::Encoder::new(
+                writer, *level,
+            )?)),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd(_) => Err(unsupported()),
+        }
+    }
+
+    /// Wraps `reader` of data written through `writer` with the same compression.
+    pub fn reader<'a, R: Read + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        match self {
+            Compression::None => Ok(Box::new(reader)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => Ok(Box::new(zstd::stream::Decoder::new(reader)?)),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd(_) => Err(unsupported()),
+        }
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "zstd compression needs the zstd feature",
+    )
+}
+
+/// Streaming writer of `Compression::writer`. `finish` has to be called to
+/// complete the compressed stream, dropping the writer truncates it.
+pub enum CompressedWriter<W: Write> {
+    Plain(W),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    /// Writes the end of the compressed stream and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            CompressedWriter::Plain(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+            #[cfg(feature = "zstd")]
+            CompressedWriter::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Plain(writer) => writer.write(buf),
+            #[cfg(feature = "zstd")]
+            CompressedWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Plain(writer) => writer.flush(),
+            #[cfg(feature = "zstd")]
+            CompressedWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
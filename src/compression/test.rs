@@ -0,0 +1,34 @@
+use super::Compression;
+use crate::proof::Proof;
+
+fn roundtrip(compression: Compression) {
+    let proof = Proof {
+        use_gwc: true,
+        domain_separation: Some(b"chain 1".to_vec()),
+        transcript: (0..1 << 16).map(|i| (i % 7) as u8).collect(),
+    };
+
+    let mut bytes = vec![];
+    proof.write_compressed(&mut bytes, compression).unwrap();
+    assert_eq!(Proof::read(&mut &bytes[..]).unwrap(), proof);
+
+    bytes.truncate(bytes.len() - 1);
+    assert!(Proof::read(&mut &bytes[..]).is_err());
+}
+
+#[test]
+fn test_proof_roundtrip() {
+    roundtrip(Compression::None);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_proof_roundtrip_zstd() {
+    roundtrip(Compression::Zstd(3));
+}
+
+#[cfg(not(feature = "zstd"))]
+#[test]
+fn test_zstd_unsupported() {
+    assert!(Compression::Zstd(3).writer(vec![]).is_err());
+}
@@ -0,0 +1,187 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::backend::BackendKind;
+use crate::device::cuda::StreamPriority;
+use crate::metrics::SharedObserver;
+use crate::state::ProofState;
+use crate::task::CancelToken;
+
+/// Tunables of `create_proof_from_advices_with_config`.
+///
+/// `ProverConfig::default()` matches the behaviour of the other entry points.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProverConfig {
+    /// Device to prove on, `None` defers to the `DeviceManager` policy, or the
+    /// first device with the OpenCL backend.
+    pub device_id: Option<usize>,
+    /// Backend to prove with, `None` defers to `ZKWASM_PROVER_BACKEND` and then
+    /// to the first of CUDA, OpenCL and the host that is available.
+    pub backend: Option<BackendKind>,
+    /// Lookups whose z polynomials are generated concurrently, one CUDA stream each.
+    pub streams: usize,
+    /// Window bits `c` of the msm over precomputed tables, i.e. `2^c` bucket
+    /// groups per window. `None` lets icicle pick from the input length. The
+    /// signed-digit msm of the other commitments always uses 8-bit windows.
+    /// Applies during the proof, concurrent proofs use the smallest setting.
+    pub msm_window_bits: Option<usize>,
+    /// Randomize advice tails, lookup permuted columns, z polynomials and the
+    /// vanishing random poly. Disabling it is only meant for benchmarking, the
+    /// proof is no longer zero-knowledge.
+    pub blinding: bool,
+    /// Upper bound in bytes on device memory allocated on the proof's device while
+    /// the proof runs, `None` is unbounded. Concurrent proofs on one device are
+    /// held to the lowest of their caps.
+    pub memory_cap: Option<usize>,
+    /// `(preferred, minimum)` bytes of device memory to negotiate with the proofs
+    /// already running on the device, see `CudaDevice::negotiate_reservation`.
+    /// The lower of the negotiated cap and `memory_cap` applies.
+    pub memory_reservation: Option<(usize, usize)>,
+    /// Size of the rayon pool running the host side, `None` uses the global pool.
+    pub cpu_threads: Option<usize>,
+    /// Priority of the proof's CUDA streams. The default `Critical` lets the proof
+    /// in flight preempt background work sharing the device.
+    pub stream_priority: StreamPriority,
+    /// Enables `audit::set_audit_mode` while the proof runs: challenges,
+    /// evaluations and witness buffers are redacted from logs and debug output.
+    pub audit: bool,
+    /// Evaluate lookup and shuffle expressions whose terms have degree at most 2
+    /// on the 2n domain before lifting them to the extended domain, instead of
+    /// extending every column they query.
+    pub intermediate_domain: bool,
+    /// Enables `set_stream_ordered_alloc` while the proof runs: device buffers
+    /// come from the CUDA stream-ordered memory pool rather than the buffer cache.
+    pub stream_ordered_alloc: bool,
+    /// Tag absorbed into the transcript before the verifying key, binding the
+    /// proof to a deployment, see `proof::absorb_domain_separation`.
+    pub domain_separation: Option<Vec<u8>>,
+    /// Ceiling in bytes on the host buffers of the huge page allocators while the
+    /// proof runs, on top of `set_host_memory_limit`; concurrent proofs are held
+    /// to the lowest of their ceilings. Proofs whose estimated host buffers don't
+    /// fit are rejected up front, `None` is unbounded.
+    pub host_memory_limit: Option<usize>,
+    /// Zero-pad advice columns shorter than the domain of the proving key instead
+    /// of rejecting them, e.g. a witness generated for a smaller segment size.
+    /// Only sound when the gates and lookups of the circuit hold on all-zero rows,
+    /// and the columns must still fit in the usable rows.
+    pub pad_short_advices: bool,
+    /// Points per Lagrange base in the precomputed msm table, see
+    /// `cuda::precompute`; 1 disables it. The table takes this many times the
+    /// device memory of the bases and is kept across proofs.
+    pub msm_precompute_factor: usize,
+    /// Directory the precomputed tables are stored in, so later processes load
+    /// them instead of computing them again. `None` keeps them in memory only.
+    /// Files that fail their version, SRS, checksum or on-curve checks are
+    /// recomputed and overwritten.
+    pub msm_precompute_dir: Option<PathBuf>,
+    /// Keep the advice columns on device from their intt to the end of the gates,
+    /// permutation and lookup parts of evaluate_h, instead of uploading them again.
+    /// Takes one domain sized buffer per advice column during evaluate_h.
+    pub resident_advices: bool,
+    /// Evaluate h on each coset of the domain making up the extended coset in
+    /// turn, with domain sized buffers, and recombine the quotient pieces. Cuts
+    /// the extended buffers of evaluate_h by the extension factor, at the cost
+    /// of extending every column once per coset.
+    pub coset_sliced_h: bool,
+    /// When the device can't hold the scratch buffer of a column extension in
+    /// evaluate_h, extend the column with the four-step fft sharded over the
+    /// peer devices, see `eval_h::do_extended_fft_multi`. Only the scratch
+    /// buffer is saved: the extended column comes back to the device as a full
+    /// buffer, so one extended buffer must still fit there.
+    pub multi_device_fft: bool,
+    /// Compile a kernel for each shape of gate group with NVRTC and evaluate
+    /// the group with it, see `cuda::jit`. Needs the `nvrtc` feature, without
+    /// it the groups run on the generic kernel with a warning.
+    pub jit_gates: bool,
+    /// Gate groups of evaluate_h evaluated concurrently, one CUDA stream and
+    /// one extended accumulator each. 1 keeps them on the default stream.
+    pub expr_streams: usize,
+    /// Keep the permuted input and table of each lookup on device from their
+    /// commitment until the lookup z polynomials are generated, instead of
+    /// uploading them twice. Takes two domain sized buffers per lookup meanwhile.
+    pub resident_permuted: bool,
+    /// Compute the permutation product polynomials on the cuda device instead
+    /// of on the host, for curves the full prover supports.
+    pub gpu_permutation_products: bool,
+    /// Build the permuted lookup tables on the cuda device from the sorted
+    /// input and table instead of merging them on the host.
+    pub gpu_permuted_table: bool,
+    /// Checked between the phases of the proof, which fails with
+    /// `Error::Cancelled` once it is set, see `task::create_proof_async`.
+    pub cancel: Option<CancelToken>,
+    /// Told about every phase boundary of the proof, with timing and memory use.
+    pub observer: Option<SharedObserver>,
+    /// Records the commitments and challenges of the proof, and supplies the
+    /// commitment groups already in it instead of committing them, see
+    /// `state::ProofState`. Binding it hashes the inputs once. One from
+    /// `ProofState::replay_file` records the commitments to its file.
+    pub state: Option<ProofState>,
+    /// Times `create_proof_with_failover` restarts a proof on another device
+    /// after its device failed with an error its context does not recover from.
+    pub failover_attempts: usize,
+    /// Longest wait for the device at a synchronization point before it is
+    /// considered hung, marked unhealthy, and the proof fails with
+    /// `Error::Timeout`; the device is reset once the proof returned, see
+    /// `reset_failed_devices`. Applies during the proof, on top of the timeout
+    /// set for the process with `set_sync_timeout`. `None` waits forever.
+    pub sync_timeout: Option<Duration>,
+    /// Enables `set_sync_debug` while the proof runs: the device is
+    /// synchronized after every kernel launch and copy so errors name the call
+    /// that caused them. Very slow, for the proofs running meanwhile too.
+    pub sync_debug: bool,
+    /// Benchmark the launch configurations of the field kernels, ntt and msm
+    /// at the sizes of the proof before proving, once per device and size,
+    /// see `CudaBackend::autotune`.
+    pub autotune: bool,
+    /// Enables `set_l2_persistence` while the proof runs: the msm bases are
+    /// kept in the persisting part of L2 on Ampere and later devices, which is
+    /// given back when the last proof using it on the device ends.
+    pub l2_persistence: bool,
+    /// Extend the columns of evaluate_h to the extended coset by replaying a
+    /// CUDA graph captured on the first column of the first proof of the
+    /// domain on the device, one launch per column instead of one per kernel.
+    /// Only the column extension is captured, the gate, permutation, lookup
+    /// and shuffle kernels are launched one by one. Needs CUDA 12.
+    pub cuda_graphs: bool,
+}
+
+impl Default for ProverConfig {
+    fn default() -> Self {
+        ProverConfig {
+            device_id: None,
+            backend: None,
+            streams: 3,
+            msm_window_bits: None,
+            blinding: true,
+            memory_cap: None,
+            memory_reservation: None,
+            cpu_threads: None,
+            stream_priority: StreamPriority::Critical,
+            audit: false,
+            intermediate_domain: false,
+            stream_ordered_alloc: false,
+            domain_separation: None,
+            host_memory_limit: None,
+            pad_short_advices: false,
+            msm_precompute_factor: 1,
+            msm_precompute_dir: None,
+            resident_advices: true,
+            coset_sliced_h: false,
+            multi_device_fft: true,
+            jit_gates: false,
+            expr_streams: 1,
+            resident_permuted: false,
+            gpu_permutation_products: false,
+            gpu_permuted_table: false,
+            cancel: None,
+            observer: None,
+            state: None,
+            failover_attempts: 1,
+            sync_timeout: None,
+            sync_debug: false,
+            autotune: false,
+            l2_persistence: false,
+            cuda_graphs: false,
+        }
+    }
+}
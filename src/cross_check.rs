@@ -0,0 +1,189 @@
+//! Host recomputation of sampled device results, with the `cross-check`
+//! feature, for bringing up new kernels or new GPUs.
+//!
+//! `CrossCheckBackend` wraps the backend of the proof: a few columns of every
+//! commitment batch are committed again with a host msm, and a few rows of
+//! every ntt or intt output are compared with the polynomial evaluated on the
+//! host. The evaluations at x, h(x) among them, are sampled the same way. The
+//! first mismatch fails the proof with a `KernelError` naming the phase.
+
+use ark_std::rand::RngCore;
+use halo2_proofs::arithmetic::best_multiexp;
+use halo2_proofs::arithmetic::eval_polynomial;
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::pairing::group::Curve as _;
+use halo2_proofs::poly::commitment::Params;
+use halo2_proofs::poly::EvaluationDomain;
+use rand::Rng as _;
+
+use crate::backend::CommitmentBasis;
+use crate::backend::CudaBackend;
+use crate::backend::ProverBackend;
+use crate::device::cuda::buffer_phase;
+use crate::Error;
+
+/// Columns of a batch recomputed on the host.
+pub const CROSS_CHECK_COLUMNS: usize = 2;
+/// Rows of an ntt output compared per sampled column.
+pub const CROSS_CHECK_ROWS: usize = 4;
+
+fn mismatch(what: &str) -> Error {
+    Error::KernelError(format!(
+        "cross-check: {} differs from the host in phase {}",
+        what,
+        buffer_phase()
+    ))
+}
+
+// up to CROSS_CHECK_COLUMNS indices spread over 0..len
+fn sample_indices(len: usize) -> Vec<usize> {
+    let mut indices = (0..CROSS_CHECK_COLUMNS.min(len))
+        .map(|i| i * len / CROSS_CHECK_COLUMNS.min(len))
+        .collect::<Vec<_>>();
+    indices.dedup();
+    indices
+}
+
+pub(crate) struct CrossCheckBackend<'a, C: CurveAffine> {
+    inner: Box<dyn ProverBackend<C> + 'a>,
+    params: &'a Params<C>,
+    omega: C::Scalar,
+}
+
+impl<'a, C: CurveAffine> CrossCheckBackend<'a, C> {
+    pub(crate) fn new(
+        inner: Box<dyn ProverBackend<C> + 'a>,
+        params: &'a Params<C>,
+        domain: &EvaluationDomain<C::Scalar>,
+    ) -> Self {
+        CrossCheckBackend {
+            inner,
+            params,
+            omega: domain.get_omega(),
+        }
+    }
+
+    fn check_commitments(
+        &self,
+        basis: CommitmentBasis,
+        values: &[&[C::Scalar]],
+        commitments: &[C],
+    ) -> Result<(), Error> {
+        let bases = match basis {
+            CommitmentBasis::Lagrange => &self.params.g_lagrange[..],
+            CommitmentBasis::Monomial => &self.params.g[..],
+        };
+        for i in sample_indices(values.len()) {
+            let expect = best_multiexp(values[i], &bases[..values[i].len()]).to_affine();
+            if expect != commitments[i] {
+                return Err(mismatch("msm commitment"));
+            }
+        }
+        Ok(())
+    }
+
+    // `inverse` maps Lagrange to coefficient form, which is checked by
+    // evaluating the output, else the input is evaluated
+    fn checked_ntt(
+        &self,
+        mut values: Vec<&mut [C::Scalar]>,
+        inverse: bool,
+        run: impl FnOnce(Vec<&mut [C::Scalar]>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let sampled = sample_indices(values.len())
+            .into_iter()
+            .map(|i| (i, values[i].to_vec()))
+            .collect::<Vec<_>>();
+        let ptrs = values
+            .iter_mut()
+            .map(|x| (x.as_mut_ptr(), x.len()))
+            .collect::<Vec<_>>();
+        run(values)?;
+
+        let mut rng = rand::thread_rng();
+        for (i, before) in sampled {
+            // the columns were handed to `run` and are only read once it returned
+            let after = unsafe { std::slice::from_raw_parts(ptrs[i].0, ptrs[i].1) };
+            for _ in 0..CROSS_CHECK_ROWS {
+                let row = rng.gen_range(0..before.len());
+                let point = self.omega.pow_vartime([row as u64]);
+                let ok = if inverse {
+                    eval_polynomial(after, point) == before[row]
+                } else {
+                    eval_polynomial(&before[..], point) == after[row]
+                };
+                if !ok {
+                    return Err(mismatch(if inverse { "intt output" } else { "ntt output" }));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, C: CurveAffine> ProverBackend<C> for CrossCheckBackend<'a, C> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn commit(&self, basis: CommitmentBasis, values: Vec<&[C::Scalar]>) -> Result<Vec<C>, Error> {
+        let commitments = self.inner.commit(basis, values.clone())?;
+        self.check_commitments(basis, &values[..], &commitments[..])?;
+        Ok(commitments)
+    }
+
+    fn commit_pairs(
+        &self,
+        basis: CommitmentBasis,
+        pairs: Vec<[&[C::Scalar]; 2]>,
+    ) -> Result<Vec<[C; 2]>, Error> {
+        let commitments = self.inner.commit_pairs(basis, pairs.clone())?;
+        self.check_commitments(basis, &pairs.concat()[..], &commitments.concat()[..])?;
+        Ok(commitments)
+    }
+
+    fn batch_intt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error> {
+        self.checked_ntt(values, true, |values| self.inner.batch_intt(values))
+    }
+
+    fn batch_ntt(&self, values: Vec<&mut [C::Scalar]>) -> Result<(), Error> {
+        self.checked_ntt(values, false, |values| self.inner.batch_ntt(values))
+    }
+
+    fn field_mul(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error> {
+        self.inner.field_mul(res, rhs)
+    }
+
+    fn field_add(&self, res: &mut [C::Scalar], rhs: &[C::Scalar]) -> Result<(), Error> {
+        self.inner.field_add(res, rhs)
+    }
+
+    fn blind_tails(
+        &self,
+        columns: Vec<&mut [C::Scalar]>,
+        start: usize,
+        rng: &mut dyn RngCore,
+    ) -> Result<(), Error> {
+        self.inner.blind_tails(columns, start, rng)
+    }
+
+    fn as_cuda(&self) -> Option<&CudaBackend<C>> {
+        self.inner.as_cuda()
+    }
+}
+
+/// Compares sampled evaluations of `inputs`, polynomials and points, with `evals`.
+pub(crate) fn check_evaluations<F: halo2_proofs::arithmetic::FieldExt>(
+    inputs: &[(&[F], F)],
+    evals: &[F],
+) -> Result<(), Error> {
+    // h(x) is the first input, always checked
+    for i in std::iter::once(0).chain(sample_indices(inputs.len())) {
+        let (poly, x) = inputs[i];
+        if eval_polynomial(poly, x) != evals[i] {
+            return Err(mismatch(if i == 0 { "h(x)" } else { "evaluation" }));
+        }
+    }
+    Ok(())
+}
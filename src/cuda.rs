@@ -1,5 +1,11 @@
+pub mod autotune;
 pub mod bn254;
 pub mod bn254_c;
+pub mod curve;
+pub mod jit;
+pub mod pasta;
+pub mod pasta_c;
+pub mod precompute;
 
 #[cfg(test)]
-mod test;
\ No newline at end of file
+mod test;
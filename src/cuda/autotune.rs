@@ -0,0 +1,212 @@
+//! Launch configurations of the bn254 kernels, picked per device and problem
+//! size by one-shot micro-benchmarks and kept for the life of the process.
+//!
+//! The elementwise field kernels get their threads per block, bounded by the
+//! occupancy API, the ntt the radix of its rounds and the msm its window bits,
+//! timed next to the pick of the cost model in `plan::msm_window_bits`. Sizes
+//! never tuned keep the built-in configurations, see `CudaBackend::autotune`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::FieldExt;
+
+use super::bn254::field_op;
+use super::bn254::fill_random;
+use super::bn254::msm_device_batch;
+use super::bn254::msm_window_bits;
+use super::bn254::ntt_prepare_cached;
+use super::bn254::ntt_raw;
+use super::bn254::set_msm_window_tuning;
+use super::bn254::FieldOp;
+use super::bn254::MAX_DEG;
+use super::bn254_c;
+use super::precompute::attached_precomputed;
+use crate::device::cuda::to_result;
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::Device as _;
+use crate::device::DeviceResult;
+
+pub(crate) const LAUNCH_FIELD: i32 = 0;
+pub(crate) const LAUNCH_NTT: i32 = 1;
+const LAUNCH_MSM: i32 = 2;
+
+// timed runs per candidate, after one warm up
+const RUNS: usize = 3;
+
+lazy_static! {
+    // (device, kernel, log2 size) -> picked configuration
+    static ref LAUNCH_CONFIGS: Mutex<HashMap<(usize, i32, usize), usize>> =
+        Mutex::new(HashMap::new());
+}
+
+pub(crate) fn set_launch_config(
+    device: &CudaDevice,
+    kernel: i32,
+    log_n: usize,
+    value: usize,
+) -> DeviceResult<()> {
+    let err = unsafe {
+        bn254_c::set_launch_config(
+            device.device_id() as i32,
+            kernel,
+            log_n as i32,
+            value as i32,
+        )
+    };
+    to_result((), err, "fail to set launch config")
+}
+
+// the fastest of `candidates`, each applied before timing `run`
+fn pick(
+    device: &CudaDevice,
+    candidates: &[usize],
+    mut apply: impl FnMut(usize) -> DeviceResult<()>,
+    mut run: impl FnMut() -> DeviceResult<()>,
+) -> DeviceResult<usize> {
+    let mut best = (Duration::MAX, candidates[0]);
+    for &candidate in candidates {
+        apply(candidate)?;
+        run()?;
+        device.synchronize()?;
+        let start = Instant::now();
+        for _ in 0..RUNS {
+            run()?;
+        }
+        device.synchronize()?;
+        let elapsed = start.elapsed();
+        tracing::debug!(candidate, elapsed = ?elapsed, "launch candidate timed");
+        if elapsed < best.0 {
+            best = (elapsed, candidate);
+        }
+    }
+    apply(best.1)?;
+    Ok(best.1)
+}
+
+fn tuned(
+    device: &CudaDevice,
+    kernel: i32,
+    log_n: usize,
+    tune: impl FnOnce() -> DeviceResult<usize>,
+) -> DeviceResult<usize> {
+    let key = (device.device_id(), kernel, log_n);
+    if let Some(value) = LAUNCH_CONFIGS.lock().unwrap().get(&key) {
+        return Ok(*value);
+    }
+    let value = tune()?;
+    tracing::info!(
+        device = key.0,
+        kernel,
+        log_n,
+        value,
+        "launch configuration autotuned"
+    );
+    LAUNCH_CONFIGS.lock().unwrap().insert(key, value);
+    Ok(value)
+}
+
+/// Threads per block of the elementwise field kernels on `2^log_n` elements.
+pub fn autotune_field_ops<F: FieldExt>(device: &CudaDevice, log_n: usize) -> DeviceResult<usize> {
+    tuned(device, LAUNCH_FIELD, log_n, || {
+        device.acitve_ctx()?;
+        let mut max_block_size = 0;
+        let err = unsafe { bn254_c::field_op_max_block_size(&mut max_block_size) };
+        to_result((), err, "fail to query the occupancy of field_op")?;
+
+        let n = 1 << log_n;
+        let candidates = (5..=10)
+            .map(|x| 1 << x)
+            .filter(|threads| *threads <= (max_block_size as usize).max(32) && *threads <= n)
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Ok(1);
+        }
+        let l = device.alloc_device_buffer::<F>(n)?;
+        let r = device.alloc_device_buffer::<F>(n)?;
+        fill_random(device, &l, n, 1, None)?;
+        fill_random(device, &r, n, 2, None)?;
+        pick(
+            device,
+            &candidates,
+            |threads| set_launch_config(device, LAUNCH_FIELD, log_n, threads),
+            || {
+                field_op::<F>(
+                    device,
+                    &l,
+                    Some(&l),
+                    0,
+                    None,
+                    Some(&r),
+                    0,
+                    None,
+                    n,
+                    FieldOp::Mul,
+                    None,
+                )
+            },
+        )
+    })
+}
+
+/// Radix, as log2, of the rounds of the ntt of size `2^log_n` over `omega`.
+pub fn autotune_ntt<F: FieldExt>(
+    device: &CudaDevice,
+    omega: F,
+    log_n: usize,
+) -> DeviceResult<usize> {
+    tuned(device, LAUNCH_NTT, log_n, || {
+        let max_deg = MAX_DEG.min(log_n);
+        let candidates = (max_deg.min(4)..=max_deg).collect::<Vec<_>>();
+        let (omegas_buf, pq_buf) = ntt_prepare_cached(device, omega, log_n)?;
+        let mut s_buf = device.alloc_device_buffer::<F>(1 << log_n)?;
+        let mut t_buf = device.alloc_device_buffer::<F>(1 << log_n)?;
+        fill_random(device, &s_buf, 1 << log_n, 3, None)?;
+        pick(
+            device,
+            &candidates,
+            |deg| set_launch_config(device, LAUNCH_NTT, log_n, deg),
+            || {
+                ntt_raw(
+                    device,
+                    &mut s_buf,
+                    &mut t_buf,
+                    &pq_buf,
+                    &omegas_buf,
+                    log_n,
+                    None,
+                )
+            },
+        )
+    })
+}
+
+/// Window bits of the msm of `len` scalars over the bases in `p_buf`. The pick
+/// of the cost model is kept when precomputed bases are laid out for it, 0
+/// leaves the choice to icicle.
+pub fn autotune_msm<C: CurveAffine>(p_buf: &CudaDeviceBufRaw, len: usize) -> DeviceResult<usize> {
+    let device = &p_buf.device;
+    let log_n = len.next_power_of_two().trailing_zeros() as usize;
+    tuned(device, LAUNCH_MSM, log_n, || {
+        let c = msm_window_bits::<C>(device, len, 1) as usize;
+        if c == 0 || attached_precomputed(p_buf, len).is_some() {
+            return Ok(c);
+        }
+        let candidates = (c.saturating_sub(1).max(1)..=c + 1).collect::<Vec<_>>();
+        let s_buf = device.alloc_device_buffer::<C::Scalar>(len)?;
+        fill_random(device, &s_buf, len, 4, None)?;
+        pick(
+            device,
+            &candidates,
+            |c| {
+                set_msm_window_tuning(device, len, 1, c);
+                Ok(())
+            },
+            || msm_device_batch::<C>(p_buf, &s_buf, 1, len).map(|_| ()),
+        )
+    })
+}
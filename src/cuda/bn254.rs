@@ -1,7 +1,15 @@
 use super::bn254_c;
-use crate::device::cuda::{to_result, CudaBuffer, CudaDevice, CudaDeviceBufRaw};
+use super::precompute::attached_precomputed;
+use super::precompute::PrecomputedBases;
+use crate::cache::ntt_tables;
+use crate::device::cuda::CudaStream as DeviceStream;
+use crate::device::cuda::{to_result, CudaBuffer, CudaDevice, CudaDeviceBufRaw, TypedBuffer};
 use crate::device::Error;
 use crate::device::{Device, DeviceResult};
+use crate::metrics::{count_ntt, time_kernel, MsmTimer};
+use crate::plan;
+use crate::scoped::LimitGuard;
+use crate::scoped::ScopedLimit;
 
 use core::mem::ManuallyDrop;
 use cuda_runtime_sys::{cudaDeviceSynchronize, cudaStream_t, CUstream_st};
@@ -14,6 +22,10 @@ use icicle_core::msm;
 use icicle_core::traits::FieldImpl;
 use icicle_cuda_runtime::memory::HostOrDeviceSlice;
 use icicle_cuda_runtime::stream::CudaStream;
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
 
 pub(crate) fn extended_prepare(
     device: &CudaDevice,
@@ -65,8 +77,129 @@ pub(crate) fn extended_intt_after(
     }
 }
 
+/// Fills `buf[..n]` with scalars from a splitmix64 stream of `seed`, as input
+/// data of benchmarks such as `autotune`. Not a cryptographic generator, never
+/// use it for blinding.
+pub(crate) fn fill_random(
+    device: &CudaDevice,
+    buf: &CudaDeviceBufRaw,
+    n: usize,
+    seed: u64,
+    stream: Option<cudaStream_t>,
+) -> Result<(), Error> {
+    unsafe {
+        device.acitve_ctx()?;
+        let err = bn254_c::fill_random(buf.ptr(), n as i32, seed, stream.unwrap_or(0usize as _));
+        to_result((), err, "fail to run fill_random")?;
+        Ok(())
+    }
+}
+
+/// Counts the occurrences of every distinct value among the first `n` elements
+/// of `buf`, comparing raw 256-bit representations. The result is unordered.
+pub fn histogram<F: FieldExt>(
+    device: &CudaDevice,
+    buf: &CudaDeviceBufRaw,
+    n: usize,
+) -> DeviceResult<Vec<(F, u32)>> {
+    if n * core::mem::size_of::<F>() > buf.size {
+        return Err(Error::DeviceError(format!(
+            "histogram over {} elements exceeds buffer of {} bytes",
+            n, buf.size
+        )));
+    }
+    if n == 0 {
+        return Ok(vec![]);
+    }
+
+    // load factor stays below 1/2 to keep probe sequences short
+    let capacity = (n * 2).next_power_of_two();
+    let keys_buf = device.alloc_device_buffer::<F>(capacity)?;
+    let states_buf = device.alloc_device_buffer::<u32>(capacity)?;
+    let counts_buf = device.alloc_device_buffer::<u32>(capacity)?;
+    unsafe {
+        device.acitve_ctx()?;
+        let err = bn254_c::histogram(
+            buf.ptr(),
+            n as i32,
+            keys_buf.ptr(),
+            states_buf.ptr(),
+            counts_buf.ptr(),
+            capacity as i32,
+            0usize as _,
+        );
+        to_result((), err, "fail to run histogram")?;
+    }
+
+    let mut keys = vec![F::zero(); capacity];
+    let mut counts = vec![0u32; capacity];
+    device.copy_from_device_to_host(&mut keys[..], &keys_buf)?;
+    device.copy_from_device_to_host(&mut counts[..], &counts_buf)?;
+
+    Ok(keys
+        .into_iter()
+        .zip(counts.into_iter())
+        .filter(|(_, count)| *count > 0)
+        .collect())
+}
+
+/// Number of distinct values among the first `n` elements of `buf`.
+pub fn count_distinct<F: FieldExt>(
+    device: &CudaDevice,
+    buf: &CudaDeviceBufRaw,
+    n: usize,
+) -> DeviceResult<usize> {
+    Ok(histogram::<F>(device, buf, n)?.len())
+}
+
+pub(crate) fn four_step_transpose(
+    device: &CudaDevice,
+    dst: &CudaDeviceBufRaw,
+    src: &CudaDeviceBufRaw,
+    rows: usize,
+    cols: usize,
+    stream: Option<cudaStream_t>,
+) -> Result<(), Error> {
+    unsafe {
+        device.acitve_ctx()?;
+        let err = bn254_c::four_step_transpose(
+            dst.ptr(),
+            src.ptr(),
+            rows as i32,
+            cols as i32,
+            stream.unwrap_or(0usize as _),
+        );
+        to_result((), err, "fail to run four_step_transpose")?;
+        Ok(())
+    }
+}
+
+pub(crate) fn four_step_twiddle(
+    device: &CudaDevice,
+    buf: &CudaDeviceBufRaw,
+    bases: &CudaDeviceBufRaw,
+    row_start: usize,
+    rows: usize,
+    cols: usize,
+    stream: Option<cudaStream_t>,
+) -> Result<(), Error> {
+    unsafe {
+        device.acitve_ctx()?;
+        let err = bn254_c::four_step_twiddle(
+            buf.ptr(),
+            bases.ptr(),
+            row_start as i32,
+            rows as i32,
+            cols as i32,
+            stream.unwrap_or(0usize as _),
+        );
+        to_result((), err, "fail to run four_step_twiddle")?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq)]
-pub(crate) enum FieldOp {
+pub enum FieldOp {
     Add = 0,
     Mul = 1,
     UOp = 2,
@@ -82,57 +215,159 @@ pub(crate) fn field_op_v2<F: FieldExt>(
     r_c: Option<F>,
     size: usize,
     op: FieldOp,
+    stream: Option<cudaStream_t>,
 ) -> Result<(), Error> {
-    field_op(device, res, l, 0, l_c, r, 0, r_c, size, op, None)?;
+    field_op(device, res, l, 0, l_c, r, 0, r_c, size, op, stream)?;
 
     Ok(())
 }
 
 pub(crate) fn field_sub<F: FieldExt>(
     device: &CudaDevice,
-    res: &CudaDeviceBufRaw,
-    rhs: &CudaDeviceBufRaw,
+    res: &impl TypedBuffer<F>,
+    rhs: &impl TypedBuffer<F>,
     size: usize,
+    stream: Option<cudaStream_t>,
 ) -> Result<(), Error> {
+    res.check_len(size, "field_sub")?;
+    rhs.check_len(size, "field_sub")?;
+    let res = res.raw();
     field_op_v2::<F>(
         device,
         res,
         Some(res),
         None,
-        Some(rhs),
+        Some(rhs.raw()),
         None,
         size,
         FieldOp::Sub,
+        stream,
     )?;
     Ok(())
 }
 
 pub(crate) fn field_mul<F: FieldExt>(
     device: &CudaDevice,
-    res: &CudaDeviceBufRaw,
-    rhs: &CudaDeviceBufRaw,
+    res: &impl TypedBuffer<F>,
+    rhs: &impl TypedBuffer<F>,
     size: usize,
+    stream: Option<cudaStream_t>,
 ) -> Result<(), Error> {
+    res.check_len(size, "field_mul")?;
+    rhs.check_len(size, "field_mul")?;
+    let res = res.raw();
     field_op_v2::<F>(
         device,
         res,
         Some(res),
         None,
-        Some(rhs),
+        Some(rhs.raw()),
         None,
         size,
         FieldOp::Mul,
+        stream,
     )?;
     Ok(())
 }
 
+/// `res = (beta + a) * (gamma + b)` elementwise, or `res *= (beta + a) * (gamma + b)`
+/// with `accumulate`, `beta_gamma` holding `[beta, gamma]` on device. `res` may alias `a` or `b`.
+pub(crate) fn field_beta_gamma_mul<F: FieldExt>(
+    device: &CudaDevice,
+    res: &impl TypedBuffer<F>,
+    a: &impl TypedBuffer<F>,
+    b: &impl TypedBuffer<F>,
+    beta_gamma: &impl TypedBuffer<F>,
+    accumulate: bool,
+    size: usize,
+    stream: Option<cudaStream_t>,
+) -> Result<(), Error> {
+    res.check_len(size, "field_beta_gamma_mul")?;
+    a.check_len(size, "field_beta_gamma_mul")?;
+    b.check_len(size, "field_beta_gamma_mul")?;
+    beta_gamma.check_len(2, "field_beta_gamma_mul")?;
+    unsafe {
+        device.acitve_ctx()?;
+        let err = bn254_c::field_beta_gamma_mul(
+            res.raw().ptr(),
+            a.raw().ptr(),
+            b.raw().ptr(),
+            beta_gamma.raw().ptr(),
+            accumulate,
+            size as i32,
+            stream.unwrap_or(0usize as _),
+        );
+        to_result((), err, "fail to run field_beta_gamma_mul")
+    }
+}
+
+/// Rows per segment of the compaction in `lookup_permute_table`.
+const LOOKUP_TABLE_SEGMENT: usize = 1024;
+
+/// Writes the permuted table of a lookup from its sorted input and sorted
+/// table, all three of the same length: the first occurrences of the input
+/// values are kept in place and the table entries they don't use fill the
+/// remaining rows in order, like `permute_table_on_host`. Fails with
+/// `InvalidInput` if an input value is missing from the table.
+pub(crate) fn lookup_permute_table<F: FieldExt>(
+    device: &CudaDevice,
+    sorted_input: &[F],
+    sorted_table: &[F],
+    permuted_table: &mut [F],
+) -> DeviceResult<()> {
+    let n = sorted_input.len();
+    assert_eq!(sorted_table.len(), n);
+    assert_eq!(permuted_table.len(), n);
+    let segments = n.div_ceil(LOOKUP_TABLE_SEGMENT);
+
+    let stream = DeviceStream::new(device)?;
+    let input = device.alloc_device_buffer_from_slice_async(sorted_input, stream.raw())?;
+    let table = device.alloc_device_buffer_from_slice_async(sorted_table, stream.raw())?;
+    let permuted = device.alloc_device_buffer::<F>(n)?;
+    let flags = device.alloc_device_buffer::<u8>(n)?;
+    let counts = device.alloc_device_buffer::<i32>(2 * segments + 2)?;
+    let holes = device.alloc_device_buffer::<i32>(n)?;
+    let left = device.alloc_device_buffer::<i32>(n)?;
+    unsafe {
+        let err = bn254_c::lookup_permute_table(
+            permuted.ptr(),
+            input.ptr(),
+            table.ptr(),
+            flags.ptr(),
+            counts.ptr(),
+            holes.ptr(),
+            left.ptr(),
+            n as i32,
+            LOOKUP_TABLE_SEGMENT as i32,
+            stream.raw(),
+        );
+        to_result((), err, "fail to run lookup_permute_table")?;
+    }
+    let mut totals = vec![0i32; 2 * segments + 2];
+    device.copy_from_device_to_host_async(&mut totals[..], &counts, stream.raw())?;
+    device.copy_from_device_to_host_async(permuted_table, &permuted, stream.raw())?;
+    stream.synchronize()?;
+
+    // as many unused table entries as rows not taken by an input value
+    let (holes, left) = (totals[2 * segments], totals[2 * segments + 1]);
+    if holes != left {
+        return Err(Error::InvalidInput(format!(
+            "lookup input has {} values missing from the table",
+            left - holes
+        )));
+    }
+    Ok(())
+}
+
 pub(crate) fn pick_from_buf<F: FieldExt>(
     device: &CudaDevice,
-    buf: &CudaDeviceBufRaw,
+    buf: &impl TypedBuffer<F>,
     rot: isize,
     i: isize,
     size: usize,
 ) -> Result<F, Error> {
+    buf.check_len(size, "pick_from_buf")?;
+    let buf = buf.raw();
     let mut v = [F::zero()];
     device.acitve_ctx()?;
     unsafe {
@@ -193,6 +428,10 @@ pub(crate) fn field_op<F: FieldExt>(
     op: FieldOp,
     stream: Option<cudaStream_t>,
 ) -> Result<(), Error> {
+    // the kernel indexes with (i + rot) % size, so keep rotations non-negative
+    let l_rot = l_rot.rem_euclid(size as i32);
+    let r_rot = r_rot.rem_euclid(size as i32);
+
     let l_c = if l_c.is_none() {
         None
     } else {
@@ -223,21 +462,419 @@ pub(crate) fn field_op<F: FieldExt>(
     Ok(())
 }
 
-pub fn batch_msm<C: CurveAffine>(
+// unset autotunes the window size
+static MSM_WINDOW_BITS: ScopedLimit = ScopedLimit::new();
+
+lazy_static! {
+    // (device, points, batch) -> window bits picked by `plan::msm_window_bits`
+    static ref MSM_WINDOW_TUNING: Mutex<HashMap<(usize, usize, usize), usize>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Window bits `c` used by the bucketed msm from now on, `None` restores the
+/// autotuned choice.
+pub fn set_msm_window_bits(c: Option<usize>) {
+    MSM_WINDOW_BITS.set(c);
+}
+
+/// Uses window bits `c` for the duration of one proof. Concurrent proofs with
+/// different settings use the smallest of theirs, the one needing the least
+/// memory for buckets.
+pub(crate) fn scope_msm_window_bits(c: usize) -> LimitGuard {
+    MSM_WINDOW_BITS.enter(c)
+}
+
+/// Window bits for a batch of `batch` msm of `len` points on `device`: the
+/// ones set by `set_msm_window_bits` or held by the running proofs, or those
+/// of the cost model for the device, picked once per size. 0 leaves the choice to icicle.
+pub(crate) fn msm_window_bits<C: CurveAffine>(
+    device: &CudaDevice,
+    len: usize,
+    batch: usize,
+) -> i32 {
+    if let Some(c) = MSM_WINDOW_BITS.get() {
+        return c as i32;
+    }
+
+    let key = (device.device_id(), len, batch);
+    if let Some(c) = MSM_WINDOW_TUNING.lock().unwrap().get(&key) {
+        return *c as i32;
+    }
+    let c = match (device.capabilities(), device.get_memory_info()) {
+        (Ok(capabilities), Ok((free, _))) => {
+            plan::msm_window_bits::<C>(len, batch, capabilities.multiprocessors, free)
+        }
+        _ => return 0,
+    };
+    tracing::debug!(len, batch, c, "msm window bits autotuned");
+    MSM_WINDOW_TUNING.lock().unwrap().insert(key, c);
+    c as i32
+}
+
+/// Overrides the window bits picked for a batch of `batch` msm of `len`
+/// points on `device`, see `autotune::autotune_msm`.
+pub(crate) fn set_msm_window_tuning(device: &CudaDevice, len: usize, batch: usize, c: usize) {
+    MSM_WINDOW_TUNING
+        .lock()
+        .unwrap()
+        .insert((device.device_id(), len, batch), c);
+}
+
+fn msm_config<'a>(stream: &'a CudaStream, c: i32) -> msm::MSMConfig<'a> {
+    let mut cfg = msm::MSMConfig::default();
+    cfg.ctx.stream = stream;
+    cfg.is_async = true;
+    cfg.are_scalars_montgomery_form = true;
+    cfg.are_points_montgomery_form = true;
+    cfg.c = c;
+    cfg
+}
+
+/// The bases of an msm of `len` points over `p_buf`: the precomputed table
+/// attached to it if any, which has to stay alive while the msm runs.
+fn msm_points<'a>(
     p_buf: &CudaDeviceBufRaw,
+    len: usize,
+) -> (
+    ManuallyDrop<HostOrDeviceSlice<'a, icicle_bn254::curve::G1Affine>>,
+    Option<Arc<PrecomputedBases>>,
+) {
+    let precomputed = attached_precomputed(p_buf, len);
+    let (ptr, n) = match &precomputed {
+        Some(table) => (table.ptr(), len * table.factor),
+        None => (p_buf.ptr(), len),
+    };
+    let points = unsafe {
+        ManuallyDrop::new(HostOrDeviceSlice::Device(
+            std::slice::from_raw_parts_mut(ptr as _, n),
+            0,
+        ))
+    };
+    (points, precomputed)
+}
+
+// Keeps the points read by the msm on `stream` persisting in L2, see
+// `set_l2_persistence`. Without the hint the msm still runs, only slower.
+fn persist_points(
+    device: &CudaDevice,
+    stream: &CudaStream,
+    points: &HostOrDeviceSlice<'_, icicle_bn254::curve::G1Affine>,
+) {
+    if let HostOrDeviceSlice::Device(points, _) = points {
+        let raw = unsafe { *(stream as *const _ as *const *mut CUstream_st) };
+        let bytes = points.len() * core::mem::size_of::<icicle_bn254::curve::G1Affine>();
+        if let Err(e) = device.persist_in_l2(raw, points.as_ptr() as _, bytes) {
+            tracing::warn!(error = ?e, "fail to keep msm bases in L2");
+        }
+    }
+}
+
+fn with_precomputed<'a>(
+    mut cfg: msm::MSMConfig<'a>,
+    precomputed: &Option<Arc<PrecomputedBases>>,
+) -> msm::MSMConfig<'a> {
+    if let Some(table) = precomputed {
+        cfg.precompute_factor = table.factor as i32;
+        cfg.c = table.c as i32;
+    }
+    cfg
+}
+
+/// Scalar vectors whose nonzero prefix is no longer than this are committed with
+/// the double-and-add kernel instead of the bucketed msm.
+pub const TINY_MSM_THRESHOLD: usize = 1 << 9;
+
+// the scan of a column is split over the rayon pool as well, the zero tail of a
+// sparse column can be most of it
+fn effective_len<F: FieldExt>(value: &[F]) -> usize {
+    value
+        .par_iter()
+        .position_last(|x| *x != F::zero())
+        .map_or(0, |i| i + 1)
+}
+
+pub fn batch_msm<C: CurveAffine>(
+    p_buf: &impl TypedBuffer<C>,
     s_buf: [&CudaDeviceBufRaw; 2],
     values: Vec<&[C::Scalar]>,
     len: usize,
 ) -> Result<Vec<C>, Error> {
-    for _ in 0..100 {
-        let res = batch_msm_core(p_buf, s_buf, values.clone(), len);
+    p_buf.check_len(len, "batch_msm bases")?;
+    let p_buf = p_buf.raw();
+    let _timer = MsmTimer::start(values.len());
+    let effective_lens = values
+        .par_iter()
+        .map(|x| effective_len(x))
+        .collect::<Vec<_>>();
+    let (tiny, large): (Vec<_>, Vec<_>) =
+        (0..values.len()).partition(|&i| effective_lens[i] <= TINY_MSM_THRESHOLD);
 
-        if res.is_ok() {
-            return res;
+    let mut res_vec = vec![C::identity(); values.len()];
+    for i in tiny {
+        if effective_lens[i] > 0 {
+            res_vec[i] = msm_tiny(p_buf, s_buf[0], &values[i][..effective_lens[i]])?;
         }
     }
 
-    unreachable!()
+    let mut dense = vec![];
+    for i in large {
+        let shares = sparse_shares(&values[i][..effective_lens[i]]);
+        let res = if shares.0 * (effective_lens[i] as f64) < SPARSE_MSM_DENSITY * len as f64 {
+            msm_sparse(p_buf, s_buf[0], &values[i][..effective_lens[i]], shares)?
+        } else {
+            None
+        };
+        match res {
+            Some(res) => res_vec[i] = res,
+            None => dense.push(i),
+        }
+    }
+    let large = dense;
+
+    if !large.is_empty() {
+        let large_values = large.iter().map(|&i| values[i]).collect::<Vec<_>>();
+        let batch = msm_batch_columns(len, large_values.len());
+        // one column per launch fits the scratch buffers of the caller
+        let owned;
+        let batch_bufs = if batch == 1 {
+            s_buf
+        } else {
+            let device = &p_buf.device;
+            owned = [
+                device.alloc_device_buffer::<C::Scalar>(batch * len)?,
+                device.alloc_device_buffer::<C::Scalar>(batch * len)?,
+            ];
+            [&owned[0], &owned[1]]
+        };
+        let res = msm_batch_with_retry(p_buf, batch_bufs, &large_values, len, batch)?;
+        for (i, r) in large.into_iter().zip(res) {
+            res_vec[i] = r;
+        }
+    }
+
+    Ok(res_vec)
+}
+
+/// Columns whose share of scalars other than zero and one, estimated from a
+/// sample, is below this are committed by compacting them first.
+pub const SPARSE_MSM_DENSITY: f64 = 0.25;
+const SPARSE_MSM_SAMPLES: usize = 4096;
+
+// (share of scalars other than 0 and 1, share of ones) among evenly spaced samples of `value`
+fn sparse_shares<F: FieldExt>(value: &[F]) -> (f64, f64) {
+    let step = (value.len() / SPARSE_MSM_SAMPLES).max(1);
+    let (mut general, mut ones, mut samples) = (0, 0, 0);
+    for x in value.iter().step_by(step) {
+        if *x == F::one() {
+            ones += 1;
+        } else if *x != F::zero() {
+            general += 1;
+        }
+        samples += 1;
+    }
+    let samples = samples.max(1) as f64;
+    (general as f64 / samples, ones as f64 / samples)
+}
+
+/// Commits `value` over the nonzero scalars only: a device pass moves them and
+/// their bases to the front of scratch buffers, the msm runs over those, and
+/// the bases of scalars equal to one are summed without an msm. `None` when
+/// the sample underestimated the nonzero scalars and they overflowed the scratch
+/// buffers, the caller then commits the column as a dense one.
+fn msm_sparse<C: CurveAffine>(
+    p_buf: &CudaDeviceBufRaw,
+    s_buf: &CudaDeviceBufRaw,
+    value: &[C::Scalar],
+    (general, ones): (f64, f64),
+) -> Result<Option<C>, Error> {
+    use halo2_proofs::pairing::group::Curve as _;
+
+    let device = &p_buf.device;
+    let capacity = ((general.max(ones) * value.len() as f64) as usize * 2 + 1024).min(value.len());
+    let out_p = device.alloc_typed_buffer::<C>(capacity)?;
+    let out_s = device.alloc_typed_buffer::<C::Scalar>(capacity)?;
+    let ones_p = device.alloc_typed_buffer::<C>(capacity)?;
+    let counts_buf = device.alloc_device_buffer::<i32>(2)?;
+    device.copy_from_host_to_device(s_buf, value)?;
+    unsafe {
+        device.acitve_ctx()?;
+        let err = bn254_c::msm_sparse_compact(
+            p_buf.ptr(),
+            s_buf.ptr(),
+            value.len() as i32,
+            out_p.ptr(),
+            out_s.ptr(),
+            ones_p.ptr(),
+            counts_buf.ptr(),
+            capacity as i32,
+            0usize as _,
+        );
+        to_result((), err, "fail to run msm_sparse_compact")?;
+    }
+    let mut counts = [0i32; 2];
+    device.copy_from_device_to_host(&mut counts[..], &counts_buf)?;
+    let [general, ones] = counts.map(|x| x as usize);
+    if general > capacity || ones > capacity {
+        tracing::debug!(
+            general,
+            ones,
+            capacity,
+            "sparse msm overflowed, committing dense"
+        );
+        return Ok(None);
+    }
+
+    let mut acc = C::identity().to_curve();
+    if general > 0 {
+        let res = batch_msm_core_v2::<C>(out_p.as_raw(), vec![out_s.as_raw()], general, None)?;
+        acc = acc + res[0].to_curve();
+    }
+    if ones > 0 {
+        let res_buf = device.alloc_device_buffer::<[C::Base; 4]>(128)?;
+        unsafe {
+            device.acitve_ctx()?;
+            let err =
+                bn254_c::msm_sum_points(res_buf.ptr(), ones_p.ptr(), ones as i32, 0usize as _);
+            to_result((), err, "fail to run msm_sum_points")?;
+        }
+        acc = acc + xyzz_result::<C>(&res_buf)?.to_curve();
+    }
+    Ok(Some(acc.to_affine()))
+}
+
+fn msm_tiny<C: CurveAffine>(
+    p_buf: &CudaDeviceBufRaw,
+    s_buf: &CudaDeviceBufRaw,
+    value: &[C::Scalar],
+) -> Result<C, Error> {
+    let device = &p_buf.device;
+    // One xyzz partial sum per kernel thread.
+    let res_buf = device.alloc_device_buffer::<[C::Base; 4]>(128)?;
+    device.copy_from_host_to_device(s_buf, value)?;
+    unsafe {
+        device.acitve_ctx()?;
+        let err = bn254_c::msm_tiny(
+            res_buf.ptr(),
+            p_buf.ptr(),
+            s_buf.ptr(),
+            value.len() as i32,
+            0usize as _,
+        );
+        to_result((), err, "fail to run msm_tiny")?;
+    }
+
+    xyzz_result(&res_buf)
+}
+
+// The first point of `res_buf`, in the xyzz coordinates of the kernel accumulators.
+fn xyzz_result<C: CurveAffine>(res_buf: &CudaDeviceBufRaw) -> Result<C, Error> {
+    Ok(xyzz_results(res_buf, 1)?[0])
+}
+
+// The first `n` points of `res_buf`, in xyzz coordinates.
+fn xyzz_results<C: CurveAffine>(res_buf: &CudaDeviceBufRaw, n: usize) -> Result<Vec<C>, Error> {
+    use halo2_proofs::arithmetic::Field;
+
+    let mut res = vec![[C::Base::zero(); 4]; n];
+    res_buf
+        .device
+        .copy_from_device_to_host(&mut res[..], res_buf)?;
+    res.into_iter()
+        .map(|[x, y, zz, zzz]| {
+            if zz == C::Base::zero() {
+                return Ok(C::identity());
+            }
+            let zzz_inv = zzz.invert().unwrap();
+            let z_inv = zz * zzz_inv;
+            Option::<C>::from(C::from_xy(x * z_inv.square(), y * zzz_inv)).ok_or(Error::MsmError)
+        })
+        .collect()
+}
+
+/// Points of the bucket scratch of one `bn254_c::msm` launch: at most 512
+/// window groups of 16 threads, with 128 signed-digit buckets each.
+const SIGNED_MSM_SCRATCH: usize = 512 * 16 * 128;
+
+/// Results of an msm launched on a stream by `launch_msm`, read with
+/// `read_msm_results` once the stream is synchronized.
+enum MsmResults<'a> {
+    Icicle(HostOrDeviceSlice<'a, Projective<CurveCfg>>),
+    // one xyzz point per column, and the bucket scratch the columns share,
+    // kept until the launches are done
+    Signed {
+        results: CudaDeviceBufRaw,
+        _scratch: CudaDeviceBufRaw,
+        columns: usize,
+    },
+}
+
+/// Launches the msm of `columns` columns of `len` scalars, back to back in
+/// `scalars`, over `points` on `stream`. The scalars are recoded to signed
+/// 8-bit digits on device before the bucket accumulation (`bn254_c::msm`),
+/// which halves the buckets per window; one launch per column. Bases with a
+/// precomputed table go through icicle with `window_bits`, the table being
+/// laid out for it.
+fn launch_msm<'a, C: CurveAffine>(
+    device: &CudaDevice,
+    points: &HostOrDeviceSlice<'_, icicle_bn254::curve::G1Affine>,
+    precomputed: &Option<Arc<PrecomputedBases>>,
+    scalars: &CudaDeviceBufRaw,
+    columns: usize,
+    len: usize,
+    stream: &CudaStream,
+    window_bits: i32,
+) -> Result<MsmResults<'a>, Error> {
+    let points_ptr = match points {
+        HostOrDeviceSlice::Device(points, _) => points.as_ptr() as *mut core::ffi::c_void,
+        HostOrDeviceSlice::Host(_) => unreachable!("msm bases are on device"),
+    };
+    if precomputed.is_some() {
+        let scalars = unsafe {
+            ManuallyDrop::new(HostOrDeviceSlice::Device(
+                std::slice::from_raw_parts_mut(scalars.ptr() as _, columns * len),
+                0,
+            ))
+        };
+        let mut results = HostOrDeviceSlice::cuda_malloc(columns).unwrap();
+        let cfg = with_precomputed(msm_config(stream, window_bits), precomputed);
+        msm::msm(&scalars, points, &cfg, &mut results).unwrap();
+        return Ok(MsmResults::Icicle(results));
+    }
+
+    let point_size = core::mem::size_of::<[C::Base; 4]>();
+    let results = device.alloc_device_buffer::<[C::Base; 4]>(columns)?;
+    let scratch = device.alloc_device_buffer::<[C::Base; 4]>(SIGNED_MSM_SCRATCH)?;
+    let raw = unsafe { *(stream as *const _ as *const *mut CUstream_st) };
+    device.acitve_ctx()?;
+    for i in 0..columns {
+        let column = scalars.slice::<C::Scalar>(i * len, len)?;
+        unsafe {
+            let err = bn254_c::msm(scratch.ptr(), points_ptr, column.ptr(), len as i32, raw);
+            to_result((), err, "fail to run msm")?;
+            let err = cuda_runtime_sys::cudaMemcpyAsync(
+                results.ptr().add(i * point_size),
+                scratch.ptr(),
+                point_size,
+                cuda_runtime_sys::cudaMemcpyKind::cudaMemcpyDeviceToDevice,
+                raw as _,
+            );
+            to_result((), err, "fail to copy the msm result")?;
+        }
+    }
+    Ok(MsmResults::Signed {
+        results,
+        _scratch: scratch,
+        columns,
+    })
+}
+
+fn read_msm_results<C: CurveAffine>(results: &MsmResults<'_>) -> DeviceResult<Vec<C>> {
+    match results {
+        MsmResults::Icicle(results) => copy_batch_and_to_affine(results),
+        MsmResults::Signed {
+            results, columns, ..
+        } => xyzz_results(results, *columns),
+    }
 }
 
 pub fn batch_msm_and_intt<C: CurveAffine>(
@@ -251,6 +888,7 @@ pub fn batch_msm_and_intt<C: CurveAffine>(
     len_log: usize,
     mut values: Vec<&mut [C::Scalar]>,
 ) -> Result<Vec<C>, Error> {
+    let _timer = MsmTimer::start(values.len());
     let mut start = 0;
 
     for _ in 0..100 {
@@ -297,46 +935,32 @@ fn batch_msm_and_intt_core<'a, C: CurveAffine>(
 
     let mut res_vec = vec![];
     let mut last_stream: Option<CudaStream> = None;
-    let mut msm_results = [
-        HostOrDeviceSlice::cuda_malloc(1).unwrap(),
-        HostOrDeviceSlice::cuda_malloc(1).unwrap(),
-    ];
+    let mut msm_results: [Option<MsmResults<'_>>; 2] = [None, None];
 
-    let points = {
-        unsafe {
-            ManuallyDrop::new(HostOrDeviceSlice::Device(
-                std::slice::from_raw_parts_mut(p_buf.ptr() as _, len),
-                0,
-            ))
-        }
-    };
+    let (points, precomputed) = msm_points(p_buf, len);
 
     let msm_count = values.len();
 
     for idx in *start..msm_count {
         let value = &mut values[idx];
-        let scalars = {
-            unsafe {
-                ManuallyDrop::new(HostOrDeviceSlice::Device(
-                    std::slice::from_raw_parts_mut(s_buf[idx & 1].ptr() as _, len),
-                    0,
-                ))
-            }
-        };
         let stream = CudaStream::create().unwrap();
+        persist_points(device, &stream, &points);
         let _stream = unsafe { *(&last_stream as *const _ as *const *mut CUstream_st) };
         //let _value = unsafe { core::mem::transmute::<_, _>(&**value) };
         //Use async would cause failure on multi-open;
         //scalars.copy_from_host_async(value, &stream).unwrap();
         //scalars.copy_from_host(_value).unwrap();
-        let mut cfg = msm::MSMConfig::default();
-        cfg.ctx.stream = &stream;
-        cfg.is_async = true;
-        cfg.are_scalars_montgomery_form = true;
-        cfg.are_points_montgomery_form = true;
-
         device.copy_from_host_to_device_async(&s_buf[idx & 1], value, _stream)?;
-        msm::msm(&scalars, &points, &cfg, &mut msm_results[idx & 1]).unwrap();
+        msm_results[idx & 1] = Some(launch_msm::<C>(
+            device,
+            &points,
+            &precomputed,
+            &s_buf[idx & 1],
+            1,
+            len,
+            &stream,
+            msm_window_bits::<C>(device, len, 1),
+        )?);
         intt_raw_async(
             device,
             &mut s_buf[idx & 1],
@@ -351,7 +975,7 @@ fn batch_msm_and_intt_core<'a, C: CurveAffine>(
         if let Some(last_stream) = last_stream {
             let last_idx = 1 - (idx & 1);
             last_stream.synchronize().unwrap();
-            let res = copy_and_to_affine(&msm_results[last_idx])?;
+            let res = read_msm_results(msm_results[last_idx].as_ref().unwrap())?[0];
             device.copy_from_device_to_host_async(
                 values[idx - 1],
                 &s_buf[last_idx & 1],
@@ -369,7 +993,7 @@ fn batch_msm_and_intt_core<'a, C: CurveAffine>(
         let _stream = unsafe { *(&last_stream as *const _ as *const *mut CUstream_st) };
         let last_idx = 1 - (msm_count & 1);
         last_stream.synchronize().unwrap();
-        let res = copy_and_to_affine(&msm_results[last_idx])?;
+        let res = read_msm_results(msm_results[last_idx].as_ref().unwrap())?[0];
         device.copy_from_device_to_host_async(values[msm_count - 1], &s_buf[last_idx], _stream)?;
         last_stream.synchronize().unwrap();
 
@@ -381,12 +1005,35 @@ fn batch_msm_and_intt_core<'a, C: CurveAffine>(
 }
 
 pub fn batch_msm_v2<C: CurveAffine>(
-    p_buf: &CudaDeviceBufRaw,
+    p_buf: &impl TypedBuffer<C>,
     values: Vec<&CudaDeviceBufRaw>,
     len: usize,
 ) -> Result<Vec<C>, Error> {
+    batch_msm_v2_stream(p_buf, values, len, None)
+}
+
+/// `batch_msm_v2` ordered after the work already issued on `stream`, instead of
+/// after everything issued on the device. Only `stream` is synchronized.
+pub fn batch_msm_v2_async<C: CurveAffine>(
+    p_buf: &impl TypedBuffer<C>,
+    values: Vec<&CudaDeviceBufRaw>,
+    len: usize,
+    stream: cudaStream_t,
+) -> Result<Vec<C>, Error> {
+    batch_msm_v2_stream(p_buf, values, len, Some(stream))
+}
+
+fn batch_msm_v2_stream<C: CurveAffine>(
+    p_buf: &impl TypedBuffer<C>,
+    values: Vec<&CudaDeviceBufRaw>,
+    len: usize,
+    stream: Option<cudaStream_t>,
+) -> Result<Vec<C>, Error> {
+    p_buf.check_len(len, "batch_msm_v2 bases")?;
+    let p_buf = p_buf.raw();
+    let _timer = MsmTimer::start(values.len());
     for _ in 0..100 {
-        let res = batch_msm_core_v2(p_buf, values.clone(), len);
+        let res = batch_msm_core_v2(p_buf, values.clone(), len, stream);
 
         if res.is_ok() {
             return res;
@@ -396,148 +1043,345 @@ pub fn batch_msm_v2<C: CurveAffine>(
     unreachable!()
 }
 
+// icicle's stream is a bare handle, borrow one of ours without destroying it
+fn borrow_stream(stream: cudaStream_t) -> ManuallyDrop<CudaStream> {
+    unsafe { ManuallyDrop::new(core::mem::transmute::<cudaStream_t, CudaStream>(stream)) }
+}
+
 fn batch_msm_core_v2<C: CurveAffine>(
     p_buf: &CudaDeviceBufRaw,
     values: Vec<&CudaDeviceBufRaw>,
     len: usize,
+    stream: Option<cudaStream_t>,
 ) -> Result<Vec<C>, Error> {
-    unsafe {
-        cudaDeviceSynchronize();
-    }
-
-    const STREAMS_NR: usize = 1;
-    let streams = [0; STREAMS_NR].map(|_| CudaStream::create().unwrap());
-    let mut msm_results_buf = values
-        .iter()
-        .map(|_| HostOrDeviceSlice::cuda_malloc(1).unwrap())
-        .collect::<Vec<_>>();
-
-    let points = {
-        unsafe {
-            ManuallyDrop::new(HostOrDeviceSlice::Device(
-                std::slice::from_raw_parts_mut(
-                    p_buf.ptr() as *mut icicle_bn254::curve::G1Affine,
-                    len,
-                ),
-                0,
-            ))
+    let streams = match stream {
+        Some(stream) => vec![borrow_stream(stream)],
+        None => {
+            unsafe {
+                cudaDeviceSynchronize();
+            }
+            vec![ManuallyDrop::new(CudaStream::create().unwrap())]
         }
     };
+    let stream_is_owned = stream.is_none();
+    const STREAMS_NR: usize = 1;
+    let mut msm_results_buf = vec![];
+
+    let (points, precomputed) = msm_points(p_buf, len);
 
     for (idx, value) in values.into_iter().enumerate() {
-        let scalars = {
-            unsafe {
-                ManuallyDrop::new(HostOrDeviceSlice::Device(
-                    std::slice::from_raw_parts_mut(value.ptr() as _, len),
-                    0,
-                ))
-            }
-        };
         let stream = &streams[idx % STREAMS_NR];
-        let mut cfg = msm::MSMConfig::default();
-        cfg.ctx.stream = &stream;
-        cfg.is_async = true;
-        cfg.are_scalars_montgomery_form = true;
-        cfg.are_points_montgomery_form = true;
-        msm::msm(&scalars, &points, &cfg, &mut msm_results_buf[idx]).unwrap();
+        msm_results_buf.push(launch_msm::<C>(
+            &p_buf.device,
+            &points,
+            &precomputed,
+            value,
+            1,
+            len,
+            stream,
+            msm_window_bits::<C>(&p_buf.device, len, 1),
+        )?);
     }
 
     for stream in streams {
         stream.synchronize().unwrap();
+        if stream_is_owned {
+            ManuallyDrop::into_inner(stream);
+        }
     }
 
     let res_vec = msm_results_buf
-        .into_iter()
-        .map(|x| copy_and_to_affine(&x).unwrap())
-        .collect();
+        .iter()
+        .map(|x| Ok(read_msm_results(x)?[0]))
+        .collect::<Result<_, Error>>()?;
 
     Ok(res_vec)
 }
 
-fn batch_msm_core<C: CurveAffine>(
-    p_buf: &CudaDeviceBufRaw,
-    s_buf: [&CudaDeviceBufRaw; 2],
+/// Scalars per launch `batch_msm` aims for, a launch commits as many columns as fit.
+/// Small columns don't fill the device with one msm, large ones go one per launch.
+pub const MSM_BATCH_SCALARS: usize = 1 << 22;
+
+fn msm_batch_columns(len: usize, columns: usize) -> usize {
+    (MSM_BATCH_SCALARS / len.max(1)).clamp(1, columns.max(1))
+}
+
+/// Commits `values` over the same `len` bases, `batch` columns at a time. The
+/// columns of a batch are uploaded back to back while the previous batch is
+/// committed.
+pub fn msm_batch<C: CurveAffine>(
+    p_buf: &impl TypedBuffer<C>,
     values: Vec<&[C::Scalar]>,
     len: usize,
+    batch: usize,
+) -> Result<Vec<C>, Error> {
+    p_buf.check_len(len, "msm_batch bases")?;
+    let p_buf = p_buf.raw();
+    let _timer = MsmTimer::start(values.len());
+    let batch = batch.clamp(1, values.len().max(1));
+    let device = &p_buf.device;
+    let batch_bufs = [
+        device.alloc_device_buffer::<C::Scalar>(batch * len)?,
+        device.alloc_device_buffer::<C::Scalar>(batch * len)?,
+    ];
+    msm_batch_with_retry(p_buf, [&batch_bufs[0], &batch_bufs[1]], &values, len, batch)
+}
+
+/// Commits pairs of columns over the same bases, e.g. the permuted input and
+/// permuted table of a lookup, both columns of a pair in the same launch.
+pub fn batch_msm_paired<C: CurveAffine>(
+    p_buf: &impl TypedBuffer<C>,
+    pairs: Vec<[&[C::Scalar]; 2]>,
+    len: usize,
+) -> Result<Vec<[C; 2]>, Error> {
+    let batch = msm_batch_columns(len, 2 * pairs.len()).max(2) & !1;
+    let res = msm_batch(p_buf, pairs.concat(), len, batch)?;
+    Ok(res.chunks(2).map(|x| [x[0], x[1]]).collect())
+}
+
+/// Commits `columns` columns of `len` scalars stored back to back in `s_buf`,
+/// already on device, in one batch.
+pub fn msm_device_batch<C: CurveAffine>(
+    p_buf: &CudaDeviceBufRaw,
+    s_buf: &CudaDeviceBufRaw,
+    columns: usize,
+    len: usize,
+) -> Result<Vec<C>, Error> {
+    let _timer = MsmTimer::start(columns);
+    let device = &p_buf.device;
+    let (points, precomputed) = msm_points(p_buf, len);
+    let stream = CudaStream::create().unwrap();
+    persist_points(device, &stream, &points);
+    let msm_results = launch_msm::<C>(
+        device,
+        &points,
+        &precomputed,
+        s_buf,
+        columns,
+        len,
+        &stream,
+        msm_window_bits::<C>(device, len, columns),
+    )?;
+    stream.synchronize().unwrap();
+    read_msm_results(&msm_results)
+}
+
+fn msm_batch_with_retry<C: CurveAffine>(
+    p_buf: &CudaDeviceBufRaw,
+    batch_bufs: [&CudaDeviceBufRaw; 2],
+    values: &[&[C::Scalar]],
+    len: usize,
+    batch: usize,
+) -> Result<Vec<C>, Error> {
+    for _ in 0..100 {
+        let res = msm_batch_core(p_buf, batch_bufs, values, len, batch);
+
+        if res.is_ok() {
+            return res;
+        }
+    }
+
+    unreachable!()
+}
+
+fn msm_batch_core<C: CurveAffine>(
+    p_buf: &CudaDeviceBufRaw,
+    batch_bufs: [&CudaDeviceBufRaw; 2],
+    values: &[&[C::Scalar]],
+    len: usize,
+    batch: usize,
 ) -> Result<Vec<C>, Error> {
     unsafe {
-        // Ensure s_buf and p_buf are ready
+        // Ensure the batch buffers and p_buf are ready
         cudaDeviceSynchronize();
     }
 
+    // pinned columns are uploaded on a stream of their own, the upload of a batch
+    // then overlaps the msm of the previous one
+    let device = &p_buf.device;
+    let copy_stream = if values.iter().all(|value| device.is_pinned(&value[..])) {
+        Some(DeviceStream::new(device)?)
+    } else {
+        None
+    };
+
     let mut res_vec = vec![];
-    let mut last_stream: Option<CudaStream> = None;
-    let mut msm_results = [
-        HostOrDeviceSlice::cuda_malloc(1).unwrap(),
-        HostOrDeviceSlice::cuda_malloc(1).unwrap(),
+    let mut last: Option<(CudaStream, MsmResults<'_>)> = None;
+
+    let (points, precomputed) = msm_points(p_buf, len);
+
+    for (idx, chunk) in values.chunks(batch).enumerate() {
+        // batch_bufs[idx & 1] was last read by the msm of idx - 2, synchronized below
+        let batch_buf = batch_bufs[idx & 1];
+        let stream = CudaStream::create().unwrap();
+        persist_points(device, &stream, &points);
+        for (i, value) in chunk.iter().enumerate() {
+            let dst = batch_buf.slice::<C::Scalar>(i * len, len)?;
+            match &copy_stream {
+                Some(copy_stream) => {
+                    device.copy_from_host_to_device_async(&dst, &value[..], copy_stream.raw())?
+                }
+                None => device.copy_from_host_to_device(&dst, &value[..])?,
+            }
+        }
+        if let Some(copy_stream) = &copy_stream {
+            let copied = copy_stream.record_event()?;
+            unsafe {
+                let raw = *(&stream as *const _ as *const *mut CUstream_st);
+                let res = cuda_runtime_sys::cudaStreamWaitEvent(raw, copied.raw(), 0);
+                to_result((), res, "fail to wait for the scalar upload")?;
+            }
+        }
+
+        // one result per column
+        let msm_results = launch_msm::<C>(
+            device,
+            &points,
+            &precomputed,
+            batch_buf,
+            chunk.len(),
+            len,
+            &stream,
+            msm_window_bits::<C>(device, len, chunk.len()),
+        )?;
+
+        if let Some((last_stream, last_results)) = last {
+            last_stream.synchronize().unwrap();
+            res_vec.extend(read_msm_results::<C>(&last_results)?);
+        }
+        last = Some((stream, msm_results));
+    }
+
+    if let Some((last_stream, last_results)) = last {
+        last_stream.synchronize().unwrap();
+        res_vec.extend(read_msm_results::<C>(&last_results)?);
+    }
+
+    Ok(res_vec)
+}
+
+/// Commits `values` over `bases` kept on the host, for bases that don't fit on
+/// the device, e.g. k = 26 on an 8GB card. The bases and the scalars of every
+/// column go through two device windows of `window` points: a chunk is
+/// uploaded while the batched msm of the previous one runs, and the partial
+/// sums of the chunks are added on the host. `plan::msm_stream_window` picks
+/// the largest window that fits.
+pub fn msm_streamed<C: CurveAffine>(
+    device: &CudaDevice,
+    bases: &[C],
+    values: Vec<&[C::Scalar]>,
+    window: usize,
+) -> Result<Vec<C>, Error> {
+    use halo2_proofs::pairing::group::Curve as _;
+
+    if let Some(value) = values.iter().find(|value| value.len() != bases.len()) {
+        return Err(Error::DeviceError(format!(
+            "streamed msm of {} scalars over {} bases",
+            value.len(),
+            bases.len()
+        )));
+    }
+    let _timer = MsmTimer::start(values.len());
+    let columns = values.len();
+    let mut acc = vec![C::identity().to_curve(); columns];
+    if columns == 0 || bases.is_empty() {
+        return Ok(vec![C::identity(); columns]);
+    }
+
+    let window = window.clamp(1, bases.len());
+    let points_bufs = [
+        device.alloc_typed_buffer::<C>(window)?,
+        device.alloc_typed_buffer::<C>(window)?,
     ];
+    let scalars_bufs = [
+        device.alloc_device_buffer::<C::Scalar>(columns * window)?,
+        device.alloc_device_buffer::<C::Scalar>(columns * window)?,
+    ];
+    let copy_stream = DeviceStream::new(device)?;
 
-    let points = {
+    unsafe {
+        cudaDeviceSynchronize();
+    }
+    let mut last: Option<(CudaStream, MsmResults<'_>)> = None;
+    for (idx, start) in (0..bases.len()).step_by(window).enumerate() {
+        let n = window.min(bases.len() - start);
+        // the buffers of idx & 1 were last read by the msm of idx - 2, synchronized below
+        let points_buf = &points_bufs[idx & 1];
+        let scalars_buf = &scalars_bufs[idx & 1];
+        device.copy_from_host_to_device_async(
+            points_buf.raw(),
+            &bases[start..start + n],
+            copy_stream.raw(),
+        )?;
+        for (i, value) in values.iter().enumerate() {
+            let dst = scalars_buf.slice::<C::Scalar>(i * n, n)?;
+            device.copy_from_host_to_device_async(
+                &dst,
+                &value[start..start + n],
+                copy_stream.raw(),
+            )?;
+        }
+        let stream = CudaStream::create().unwrap();
+        let copied = copy_stream.record_event()?;
         unsafe {
+            let raw = *(&stream as *const _ as *const *mut CUstream_st);
+            let res = cuda_runtime_sys::cudaStreamWaitEvent(raw, copied.raw(), 0);
+            to_result((), res, "fail to wait for the chunk upload")?;
+        }
+
+        let points = unsafe {
             ManuallyDrop::new(HostOrDeviceSlice::Device(
-                std::slice::from_raw_parts_mut(p_buf.ptr() as _, len),
+                std::slice::from_raw_parts_mut(points_buf.raw().ptr() as _, n),
                 0,
             ))
-        }
-    };
-
-    let msm_count = values.len();
-
-    for (idx, value) in values.iter().enumerate() {
-        let mut scalars = {
-            unsafe {
-                ManuallyDrop::new(HostOrDeviceSlice::Device(
-                    std::slice::from_raw_parts_mut(s_buf[idx & 1].ptr() as _, len),
-                    0,
-                ))
-            }
         };
-        let stream = CudaStream::create().unwrap();
-        let value = unsafe { core::mem::transmute::<_, _>(*value) };
-        //Use async would cause failure on multi-open;
-        //scalars.copy_from_host_async(value, &stream).unwrap();
-        scalars.copy_from_host(value).unwrap();
-        let mut cfg = msm::MSMConfig::default();
-        cfg.ctx.stream = &stream;
-        cfg.is_async = true;
-        cfg.are_scalars_montgomery_form = true;
-        cfg.are_points_montgomery_form = true;
-        msm::msm(&scalars, &points, &cfg, &mut msm_results[idx & 1]).unwrap();
+        let msm_results = launch_msm::<C>(
+            device,
+            &points,
+            &None,
+            scalars_buf,
+            columns,
+            n,
+            &stream,
+            msm_window_bits::<C>(device, n, columns),
+        )?;
 
-        if let Some(last_stream) = last_stream {
-            let last_idx = 1 - (idx & 1);
+        if let Some((last_stream, last_results)) = last {
             last_stream.synchronize().unwrap();
-            let res = copy_and_to_affine(&msm_results[last_idx])?;
-
-            res_vec.push(res);
+            for (acc, partial) in acc.iter_mut().zip(read_msm_results::<C>(&last_results)?) {
+                *acc = *acc + partial.to_curve();
+            }
         }
-        last_stream = Some(stream);
+        last = Some((stream, msm_results));
     }
 
-    if let Some(last_stream) = last_stream {
-        let last_idx = 1 - (msm_count & 1);
+    if let Some((last_stream, last_results)) = last {
         last_stream.synchronize().unwrap();
-        let res = copy_and_to_affine(&msm_results[last_idx])?;
-        res_vec.push(res);
+        for (acc, partial) in acc.iter_mut().zip(read_msm_results::<C>(&last_results)?) {
+            *acc = *acc + partial.to_curve();
+        }
     }
 
-    Ok(res_vec)
+    let mut res = vec![C::identity(); columns];
+    C::Curve::batch_normalize(&acc[..], &mut res[..]);
+    Ok(res)
 }
 
-fn copy_and_to_affine<C: CurveAffine>(
+fn copy_batch_and_to_affine<C: CurveAffine>(
     msm_result: &HostOrDeviceSlice<'_, Projective<CurveCfg>>,
-) -> DeviceResult<C> {
-    let retry_limit = 3;
-
-    for i in 0..retry_limit {
-        let mut msm_host_result = [G1Projective::zero()];
+) -> DeviceResult<Vec<C>> {
+    for i in 0..3 {
+        let mut msm_host_result = vec![G1Projective::zero(); msm_result.len()];
         msm_result.copy_to_host(&mut msm_host_result[..]).unwrap();
-        let res = to_affine(&msm_host_result[0]);
-        if res.is_some() {
-            return Ok(res.unwrap());
+        let res = msm_host_result
+            .iter()
+            .map(|x| to_affine(x))
+            .collect::<Option<Vec<_>>>();
+        if let Some(res) = res {
+            return Ok(res);
         }
 
-        println!("bad msm result at round {} is {:?}", i, msm_host_result);
+        tracing::warn!(round = i, "bad batched msm result, retrying");
     }
 
     Err(Error::MsmError)
@@ -602,6 +1446,18 @@ pub fn ntt_prepare<F: FieldExt>(
     Ok((omegas_buf, pq_buf))
 }
 
+/// `ntt_prepare` computed once per device, size and omega and kept across
+/// proofs, see `clear_ntt_cache`.
+pub fn ntt_prepare_cached<F: FieldExt>(
+    device: &CudaDevice,
+    omega: F,
+    len_log: usize,
+) -> DeviceResult<(Arc<CudaDeviceBufRaw>, Arc<CudaDeviceBufRaw>)> {
+    ntt_tables(device, omega, len_log, || {
+        ntt_prepare(device, omega, len_log)
+    })
+}
+
 pub fn ntt_raw(
     device: &CudaDevice,
     s_buf: &mut CudaDeviceBufRaw,
@@ -610,20 +1466,47 @@ pub fn ntt_raw(
     omegas_buf: &CudaDeviceBufRaw,
     len_log: usize,
     stream: Option<cudaStream_t>,
+) -> Result<(), Error> {
+    batch_ntt_raw(
+        device, s_buf, tmp_buf, pq_buf, omegas_buf, len_log, 1, stream,
+    )
+}
+
+/// Columns an ntt launch transforms together, as many as fit in this many scalars.
+pub const NTT_BATCH_SCALARS: usize = 1 << 22;
+
+/// In place ntt of `batch` columns of `1 << len_log` scalars back to back in
+/// `s_buf`, in one kernel sequence reading the twiddle tables once per round.
+pub fn batch_ntt_raw(
+    device: &CudaDevice,
+    s_buf: &mut CudaDeviceBufRaw,
+    tmp_buf: &mut CudaDeviceBufRaw,
+    pq_buf: &CudaDeviceBufRaw,
+    omegas_buf: &CudaDeviceBufRaw,
+    len_log: usize,
+    batch: usize,
+    stream: Option<cudaStream_t>,
 ) -> Result<(), Error> {
     let mut swap = false;
+    for _ in 0..batch {
+        count_ntt();
+    }
     unsafe {
         device.acitve_ctx()?;
-        let err = crate::cuda::bn254::bn254_c::ntt(
-            s_buf.ptr(),
-            tmp_buf.ptr(),
-            pq_buf.ptr(),
-            omegas_buf.ptr(),
-            len_log as i32,
-            MAX_DEG as i32,
-            &mut swap as *mut _ as _,
-            stream.unwrap_or(0usize as _),
-        );
+        let stream = stream.unwrap_or(0usize as _);
+        let err = time_kernel("ntt", stream, || {
+            crate::cuda::bn254::bn254_c::batch_ntt(
+                s_buf.ptr(),
+                tmp_buf.ptr(),
+                pq_buf.ptr(),
+                omegas_buf.ptr(),
+                len_log as i32,
+                MAX_DEG as i32,
+                batch as i32,
+                &mut swap as *mut _ as _,
+                stream,
+            )
+        });
         to_result((), err, "fail to run ntt")?;
     }
     if swap {
@@ -653,51 +1536,85 @@ pub fn batch_intt_raw<F: FieldExt>(
     omegas_buf: &CudaDeviceBufRaw,
     divisor: &CudaDeviceBufRaw,
     len_log: usize,
+) -> Result<(), Error> {
+    batch_ntt_host(device, value, pq_buf, omegas_buf, Some(divisor), len_log)
+}
+
+/// In place ntt of host columns, or intt when `divisor` is given. Columns are
+/// uploaded back to back and transformed `NTT_BATCH_SCALARS` scalars per
+/// launch, the transfers of a group overlapping the launches of the others.
+pub fn batch_ntt_host<F: FieldExt>(
+    device: &CudaDevice,
+    mut value: Vec<&mut [F]>,
+    pq_buf: &CudaDeviceBufRaw,
+    omegas_buf: &CudaDeviceBufRaw,
+    divisor: Option<&CudaDeviceBufRaw>,
+    len_log: usize,
 ) -> Result<(), Error> {
     const MAX_CONCURRENCY: usize = 3;
 
     let size = 1 << len_log;
-    let mut streams = [None; MAX_CONCURRENCY];
-    let mut t_buf = [0; MAX_CONCURRENCY].map(|_| device.alloc_device_buffer::<F>(size).unwrap());
-    let mut s_buf = [0; MAX_CONCURRENCY].map(|_| device.alloc_device_buffer::<F>(size).unwrap());
-
-    for (i, col) in value.into_iter().enumerate() {
-        let idx = i % MAX_CONCURRENCY;
+    let batch = (NTT_BATCH_SCALARS / size).clamp(1, value.len().max(1));
+    let concurrency = MAX_CONCURRENCY.min((value.len() + batch - 1) / batch);
+    let mut streams: Vec<Option<DeviceStream>> = (0..concurrency).map(|_| None).collect();
+    let mut t_buf = (0..concurrency)
+        .map(|_| device.alloc_device_buffer::<F>(batch * size))
+        .collect::<DeviceResult<Vec<_>>>()?;
+    let mut s_buf = (0..concurrency)
+        .map(|_| device.alloc_device_buffer::<F>(batch * size))
+        .collect::<DeviceResult<Vec<_>>>()?;
+
+    for (i, cols) in value.chunks_mut(batch).enumerate() {
+        let idx = i % concurrency;
         let s_buf = &mut s_buf[idx];
         let t_buf = &mut t_buf[idx];
 
         unsafe {
-            if let Some(last_stream) = streams[idx] {
-                cuda_runtime_sys::cudaStreamSynchronize(last_stream);
-                cuda_runtime_sys::cudaStreamDestroy(last_stream);
+            if let Some(last_stream) = streams[idx].take() {
+                last_stream.synchronize()?;
             }
 
-            let mut stream = std::mem::zeroed();
-            let err = cuda_runtime_sys::cudaStreamCreate(&mut stream);
-            crate::device::cuda::to_result((), err, "fail to run cudaStreamCreate")?;
-            device.copy_from_host_to_device_async(&s_buf, &col[..], stream)?;
-            intt_raw_async(
-                &device,
+            let owned_stream = DeviceStream::new(device)?;
+            let stream = owned_stream.raw();
+            for (j, col) in cols.iter().enumerate() {
+                let dst = s_buf.slice::<F>(j * size, size)?;
+                device.copy_from_host_to_device_async(&dst, &col[..], stream)?;
+            }
+            batch_ntt_raw(
+                device,
                 s_buf,
                 t_buf,
-                &pq_buf,
-                &omegas_buf,
-                &divisor,
+                pq_buf,
+                omegas_buf,
                 len_log,
+                cols.len(),
                 Some(stream),
             )?;
-            device.copy_from_device_to_host_async(&mut col[..], &s_buf, stream)?;
-            streams[idx] = Some(stream);
+            if let Some(divisor) = divisor {
+                let err = bn254_c::field_op(
+                    s_buf.ptr(),
+                    s_buf.ptr(),
+                    0,
+                    0usize as *mut _,
+                    0usize as *mut _,
+                    0,
+                    divisor.ptr(),
+                    (cols.len() * size) as i32,
+                    FieldOp::Mul as i32,
+                    stream,
+                );
+                to_result((), err, "fail to run field_op in batch_ntt_host")?;
+            }
+            for (j, col) in cols.iter_mut().enumerate() {
+                let src = s_buf.slice::<F>(j * size, size)?;
+                device.copy_from_device_to_host_async(&mut col[..], &src, stream)?;
+            }
+            streams[idx] = Some(owned_stream);
         }
     }
 
-    for idx in 0..MAX_CONCURRENCY {
-        if let Some(last_stream) = streams[idx] {
-            unsafe {
-                cuda_runtime_sys::cudaStreamSynchronize(last_stream);
-                cuda_runtime_sys::cudaStreamDestroy(last_stream);
-            }
-        }
+    for last_stream in streams.into_iter().flatten() {
+        last_stream.synchronize()?;
     }
 
     Ok(())
@@ -824,23 +1741,20 @@ pub fn permutation_eval_h_l(
 
 pub fn buffer_copy_with_shift<F: FieldExt>(
     device: &CudaDevice,
-    dst: &CudaDeviceBufRaw,
-    src: &CudaDeviceBufRaw,
+    dst: &impl TypedBuffer<F>,
+    src: &impl TypedBuffer<F>,
     rot: isize,
     size: usize,
 ) -> Result<(), Error> {
+    dst.check_len(size, "buffer_copy_with_shift")?;
+    src.check_len(size, "buffer_copy_with_shift")?;
+    let (dst, src) = (dst.raw(), src.raw());
+    // dst[i] = src[(i + rot) mod size], rotations may exceed one row in either direction
+    let rot = rot.rem_euclid(size as isize) as usize;
     if rot == 0 {
         device.copy_from_device_to_device::<F>(&dst, 0, src, 0, size)?;
         device.synchronize()?;
-    } else if rot > 0 {
-        let rot = rot as usize;
-        let len = size - rot as usize;
-        device.copy_from_device_to_device::<F>(&dst, 0, src, rot, len)?;
-        device.synchronize()?;
-        device.copy_from_device_to_device::<F>(&dst, len, src, 0, rot)?;
-        device.synchronize()?;
     } else {
-        let rot = -rot as usize;
         let len = size - rot;
         device.copy_from_device_to_device::<F>(&dst, 0, src, rot, len)?;
         device.synchronize()?;
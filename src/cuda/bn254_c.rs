@@ -11,6 +11,33 @@ extern "C" {
         stream: *mut CUstream_st,
     ) -> cudaError;
 
+    pub fn msm_tiny(
+        res: *mut c_void,
+        p: *mut c_void,
+        s: *mut c_void,
+        array_len: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn msm_sparse_compact(
+        p: *mut c_void,
+        s: *mut c_void,
+        array_len: i32,
+        out_p: *mut c_void,
+        out_s: *mut c_void,
+        ones_p: *mut c_void,
+        counts: *mut c_void,
+        capacity: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn msm_sum_points(
+        res: *mut c_void,
+        p: *mut c_void,
+        array_len: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
     pub fn ntt(
         buf: *mut c_void,
         tmp: *mut c_void,
@@ -22,6 +49,18 @@ extern "C" {
         stream: *mut CUstream_st,
     ) -> cudaError;
 
+    pub fn batch_ntt(
+        buf: *mut c_void,
+        tmp: *mut c_void,
+        pq: *mut c_void,
+        omega: *mut c_void,
+        array_log: i32,
+        max_deg: i32,
+        batch: i32,
+        swap: *mut c_void,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
     pub fn field_op(
         res: *mut c_void,
         l: *mut c_void,
@@ -35,6 +74,15 @@ extern "C" {
         stream: *mut CUstream_st,
     ) -> cudaError;
 
+    pub fn extend_graph_bind(
+        graph: *mut c_void,
+        exec: *mut c_void,
+        captured: *const *mut c_void,
+        bound: *const *mut c_void,
+        bytes: *const usize,
+        n_ranges: i32,
+    ) -> cudaError;
+
     pub fn extended_prepare(
         s: *mut c_void,
         coset_powers: *mut c_void,
@@ -90,6 +138,7 @@ extern "C" {
         rot: *mut c_void,
         v_n: i32,
         n: i32,
+        stream: *mut CUstream_st,
     ) -> cudaError;
 
     pub fn lookup_eval_h(
@@ -154,6 +203,16 @@ extern "C" {
         n: i32,
     ) -> cudaError;
 
+    pub fn field_beta_gamma_mul(
+        res: *mut c_void,
+        a: *mut c_void,
+        b: *mut c_void,
+        beta_gamma: *mut c_void,
+        accumulate: bool,
+        n: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
     pub fn eval_lookup_z(
         z: *mut c_void,
         input: *mut c_void,
@@ -164,4 +223,86 @@ extern "C" {
         n: i32,
         stream: *mut CUstream_st,
     ) -> cudaError;
+
+    pub fn eval_fraction_product_z(
+        z: *mut c_void,
+        numerator: *mut c_void,
+        tmp: *mut c_void,
+        n: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn permutation_product_step(
+        res: *mut c_void,
+        x: *mut c_void,
+        y: *mut c_void,
+        a_b: *mut c_void,
+        first: bool,
+        n: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn lookup_permute_table(
+        permuted_table: *mut c_void,
+        input: *mut c_void,
+        table: *mut c_void,
+        flags: *mut c_void,
+        counts: *mut c_void,
+        holes: *mut c_void,
+        left: *mut c_void,
+        n: i32,
+        seg_len: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn fill_random(buf: *mut c_void, n: i32, seed: u64, stream: *mut CUstream_st) -> cudaError;
+
+    pub fn set_launch_config(device: i32, kernel: i32, log_n: i32, value: i32) -> cudaError;
+
+    pub fn field_op_max_block_size(block_size: *mut i32) -> cudaError;
+
+    pub fn histogram(
+        keys: *mut c_void,
+        n: i32,
+        table_keys: *mut c_void,
+        table_states: *mut c_void,
+        table_counts: *mut c_void,
+        capacity: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn four_step_transpose(
+        dst: *mut c_void,
+        src: *mut c_void,
+        rows: i32,
+        cols: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn four_step_twiddle(
+        buf: *mut c_void,
+        bases: *mut c_void,
+        row_start: i32,
+        rows: i32,
+        cols: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn field_ops_limb32(
+        a: *mut c_void,
+        b: *mut c_void,
+        out: *mut c_void,
+        n: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn field_ops_limb64(
+        a: *mut c_void,
+        b: *mut c_void,
+        out: *mut c_void,
+        n: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn preload_kernels() -> cudaError;
 }
@@ -0,0 +1,370 @@
+use std::any::Any;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::arithmetic::FieldExt;
+
+use super::bn254;
+use super::bn254::FieldOp;
+use super::pasta;
+use super::pasta::modulus_eq;
+use super::pasta::PastaCurve;
+use crate::cache::ntt_tables;
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::Device as _;
+use crate::device::DeviceResult;
+
+const BN254_BASE_MODULUS: &str =
+    "0x30644e72e131a029b85045b68181585d97816a916871ca8d3c208c16d87cfd47";
+const BN254_SCALAR_MODULUS: &str =
+    "0x30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001";
+
+/// Kernel entry points of one curve. The prover only reaches device kernels
+/// through the implementation returned by `gpu_curve`.
+pub trait GpuCurve<C: CurveAffine>: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the lookup, permutation, evaluate_h and multiopen kernels accept
+    /// this curve, otherwise only the entry points below are available.
+    fn full_prover(&self) -> bool {
+        false
+    }
+
+    /// `(omegas, pq)` device tables of the ntt for `omega`, passed back to `ntt`/`batch_intt`.
+    /// Kept across proofs, see `clear_ntt_cache`.
+    fn ntt_prepare(
+        &self,
+        device: &CudaDevice,
+        omega: C::Scalar,
+        len_log: usize,
+    ) -> DeviceResult<(Arc<CudaDeviceBufRaw>, Arc<CudaDeviceBufRaw>)>;
+
+    /// In place ntt of `s_buf`, `tmp_buf` is scratch of the same size.
+    fn ntt(
+        &self,
+        device: &CudaDevice,
+        s_buf: &mut CudaDeviceBufRaw,
+        tmp_buf: &mut CudaDeviceBufRaw,
+        omegas_buf: &CudaDeviceBufRaw,
+        pq_buf: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()>;
+
+    /// In place ntt of host columns.
+    fn batch_ntt(
+        &self,
+        device: &CudaDevice,
+        values: Vec<&mut [C::Scalar]>,
+        omegas_buf: &CudaDeviceBufRaw,
+        pq_buf: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()> {
+        let size = 1 << len_log;
+        let mut s_buf = device.alloc_device_buffer::<C::Scalar>(size)?;
+        let mut t_buf = device.alloc_device_buffer::<C::Scalar>(size)?;
+        for value in values {
+            device.copy_from_host_to_device(&s_buf, value)?;
+            self.ntt(device, &mut s_buf, &mut t_buf, omegas_buf, pq_buf, len_log)?;
+            device.copy_from_device_to_host(value, &s_buf)?;
+        }
+        Ok(())
+    }
+
+    /// In place intt of host columns, scaled by `divisor`.
+    fn batch_intt(
+        &self,
+        device: &CudaDevice,
+        values: Vec<&mut [C::Scalar]>,
+        omegas_buf: &CudaDeviceBufRaw,
+        pq_buf: &CudaDeviceBufRaw,
+        divisor: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()>;
+
+    fn batch_msm(
+        &self,
+        device: &CudaDevice,
+        p_buf: &CudaDeviceBufRaw,
+        s_buf: [&CudaDeviceBufRaw; 2],
+        values: Vec<&[C::Scalar]>,
+        len: usize,
+    ) -> DeviceResult<Vec<C>>;
+
+    /// `batch_msm` of pairs of columns, in one pass over the bases where the curve supports it.
+    fn batch_msm_paired(
+        &self,
+        device: &CudaDevice,
+        p_buf: &CudaDeviceBufRaw,
+        s_buf: [&CudaDeviceBufRaw; 2],
+        pairs: Vec<[&[C::Scalar]; 2]>,
+        len: usize,
+    ) -> DeviceResult<Vec<[C; 2]>> {
+        let res = self.batch_msm(device, p_buf, s_buf, pairs.concat(), len)?;
+        Ok(res.chunks(2).map(|x| [x[0], x[1]]).collect())
+    }
+
+    /// `batch_msm` of `columns` columns of `len` scalars stored back to back in
+    /// `values` on device. Curves without a device entry point read them back.
+    fn batch_msm_device(
+        &self,
+        device: &CudaDevice,
+        p_buf: &CudaDeviceBufRaw,
+        s_buf: [&CudaDeviceBufRaw; 2],
+        values: &CudaDeviceBufRaw,
+        columns: usize,
+        len: usize,
+    ) -> DeviceResult<Vec<C>> {
+        let mut host = vec![C::Scalar::zero(); columns * len];
+        device.copy_from_device_to_host(&mut host[..], values)?;
+        self.batch_msm(device, p_buf, s_buf, host.chunks(len).collect(), len)
+    }
+
+    fn field_op(
+        &self,
+        device: &CudaDevice,
+        res: &CudaDeviceBufRaw,
+        l: &CudaDeviceBufRaw,
+        r: &CudaDeviceBufRaw,
+        size: usize,
+        op: FieldOp,
+    ) -> DeviceResult<()>;
+}
+
+pub struct Bn254Kernels;
+
+impl<C: CurveAffine> GpuCurve<C> for Bn254Kernels {
+    fn name(&self) -> &'static str {
+        "bn254"
+    }
+
+    fn full_prover(&self) -> bool {
+        true
+    }
+
+    fn ntt_prepare(
+        &self,
+        device: &CudaDevice,
+        omega: C::Scalar,
+        len_log: usize,
+    ) -> DeviceResult<(Arc<CudaDeviceBufRaw>, Arc<CudaDeviceBufRaw>)> {
+        bn254::ntt_prepare_cached(device, omega, len_log)
+    }
+
+    fn ntt(
+        &self,
+        device: &CudaDevice,
+        s_buf: &mut CudaDeviceBufRaw,
+        tmp_buf: &mut CudaDeviceBufRaw,
+        omegas_buf: &CudaDeviceBufRaw,
+        pq_buf: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()> {
+        bn254::ntt_raw(device, s_buf, tmp_buf, pq_buf, omegas_buf, len_log, None)
+    }
+
+    fn batch_ntt(
+        &self,
+        device: &CudaDevice,
+        values: Vec<&mut [C::Scalar]>,
+        omegas_buf: &CudaDeviceBufRaw,
+        pq_buf: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()> {
+        bn254::batch_ntt_host(device, values, pq_buf, omegas_buf, None, len_log)
+    }
+
+    fn batch_intt(
+        &self,
+        device: &CudaDevice,
+        values: Vec<&mut [C::Scalar]>,
+        omegas_buf: &CudaDeviceBufRaw,
+        pq_buf: &CudaDeviceBufRaw,
+        divisor: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()> {
+        bn254::batch_intt_raw(device, values, pq_buf, omegas_buf, divisor, len_log)
+    }
+
+    fn batch_msm(
+        &self,
+        _device: &CudaDevice,
+        p_buf: &CudaDeviceBufRaw,
+        s_buf: [&CudaDeviceBufRaw; 2],
+        values: Vec<&[C::Scalar]>,
+        len: usize,
+    ) -> DeviceResult<Vec<C>> {
+        bn254::batch_msm::<C>(p_buf, s_buf, values, len)
+    }
+
+    fn batch_msm_device(
+        &self,
+        _device: &CudaDevice,
+        p_buf: &CudaDeviceBufRaw,
+        _s_buf: [&CudaDeviceBufRaw; 2],
+        values: &CudaDeviceBufRaw,
+        columns: usize,
+        len: usize,
+    ) -> DeviceResult<Vec<C>> {
+        bn254::msm_device_batch::<C>(p_buf, values, columns, len)
+    }
+
+    fn batch_msm_paired(
+        &self,
+        _device: &CudaDevice,
+        p_buf: &CudaDeviceBufRaw,
+        _s_buf: [&CudaDeviceBufRaw; 2],
+        pairs: Vec<[&[C::Scalar]; 2]>,
+        len: usize,
+    ) -> DeviceResult<Vec<[C; 2]>> {
+        bn254::batch_msm_paired::<C>(p_buf, pairs, len)
+    }
+
+    fn field_op(
+        &self,
+        device: &CudaDevice,
+        res: &CudaDeviceBufRaw,
+        l: &CudaDeviceBufRaw,
+        r: &CudaDeviceBufRaw,
+        size: usize,
+        op: FieldOp,
+    ) -> DeviceResult<()> {
+        bn254::field_op_v2::<C::Scalar>(device, res, Some(l), None, Some(r), None, size, op, None)
+    }
+}
+
+pub struct PastaKernels(pub PastaCurve);
+
+impl<C: CurveAffine> GpuCurve<C> for PastaKernels {
+    fn name(&self) -> &'static str {
+        match self.0 {
+            PastaCurve::Pallas => "pallas",
+            PastaCurve::Vesta => "vesta",
+        }
+    }
+
+    fn ntt_prepare(
+        &self,
+        device: &CudaDevice,
+        omega: C::Scalar,
+        len_log: usize,
+    ) -> DeviceResult<(Arc<CudaDeviceBufRaw>, Arc<CudaDeviceBufRaw>)> {
+        ntt_tables(device, omega, len_log, || {
+            // no pq table, the second buffer is a placeholder
+            Ok((
+                pasta::ntt_prepare(device, omega, len_log)?,
+                device.alloc_device_buffer::<C::Scalar>(1)?,
+            ))
+        })
+    }
+
+    fn ntt(
+        &self,
+        device: &CudaDevice,
+        s_buf: &mut CudaDeviceBufRaw,
+        _tmp_buf: &mut CudaDeviceBufRaw,
+        omegas_buf: &CudaDeviceBufRaw,
+        _pq_buf: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()> {
+        pasta::ntt_raw(
+            device,
+            self.0.scalar_field(),
+            s_buf,
+            omegas_buf,
+            None,
+            len_log,
+            None,
+        )
+    }
+
+    fn batch_intt(
+        &self,
+        device: &CudaDevice,
+        values: Vec<&mut [C::Scalar]>,
+        omegas_buf: &CudaDeviceBufRaw,
+        _pq_buf: &CudaDeviceBufRaw,
+        divisor: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()> {
+        let buf = device.alloc_device_buffer::<C::Scalar>(1 << len_log)?;
+        for value in values {
+            device.copy_from_host_to_device(&buf, value)?;
+            pasta::ntt_raw(
+                device,
+                self.0.scalar_field(),
+                &buf,
+                omegas_buf,
+                Some(divisor),
+                len_log,
+                None,
+            )?;
+            device.copy_from_device_to_host(value, &buf)?;
+        }
+        Ok(())
+    }
+
+    fn batch_msm(
+        &self,
+        device: &CudaDevice,
+        p_buf: &CudaDeviceBufRaw,
+        _s_buf: [&CudaDeviceBufRaw; 2],
+        values: Vec<&[C::Scalar]>,
+        _len: usize,
+    ) -> DeviceResult<Vec<C>> {
+        values
+            .into_iter()
+            .map(|value| pasta::msm::<C>(device, self.0, p_buf, value))
+            .collect()
+    }
+
+    fn field_op(
+        &self,
+        device: &CudaDevice,
+        res: &CudaDeviceBufRaw,
+        l: &CudaDeviceBufRaw,
+        r: &CudaDeviceBufRaw,
+        size: usize,
+        op: FieldOp,
+    ) -> DeviceResult<()> {
+        pasta::field_op(device, self.0.scalar_field(), res, l, r, size, op, None)
+    }
+}
+
+lazy_static! {
+    static ref GPU_CURVES: Mutex<Vec<Box<dyn Any + Send + Sync>>> = Mutex::new(vec![]);
+}
+
+/// Register kernels for curve `C`, taking precedence over the built-in ones.
+pub fn register_gpu_curve<C: CurveAffine>(curve: Arc<dyn GpuCurve<C>>) {
+    GPU_CURVES.lock().unwrap().push(Box::new(curve));
+}
+
+/// Whether `C` has the field moduli of bn254, the only curve of the OpenCL
+/// kernels.
+pub(crate) fn is_bn254<C: CurveAffine>() -> bool {
+    modulus_eq(C::Base::MODULUS, BN254_BASE_MODULUS)
+        && modulus_eq(C::Scalar::MODULUS, BN254_SCALAR_MODULUS)
+}
+
+/// Kernels for `C`: registered ones first, then bn254 and the Pasta cycle
+/// recognized by field moduli. `None` means no kernel accepts `C`'s layout.
+pub fn gpu_curve<C: CurveAffine>() -> Option<Arc<dyn GpuCurve<C>>> {
+    let registered = GPU_CURVES
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find_map(|x| x.downcast_ref::<Arc<dyn GpuCurve<C>>>().cloned());
+    if registered.is_some() {
+        return registered;
+    }
+
+    if is_bn254::<C>() {
+        return Some(Arc::new(Bn254Kernels));
+    }
+
+    pasta::pasta_curve::<C>().map(|curve| Arc::new(PastaKernels(curve)) as Arc<dyn GpuCurve<C>>)
+}
@@ -0,0 +1,458 @@
+//! Gate groups compiled into one kernel each at runtime.
+//!
+//! `field_op_batch_mul_sum` walks a null separated pointer array and a
+//! rotation array for every row, so each term pays for loading its layout and
+//! a column queried by several terms is read once per term. With the `nvrtc`
+//! feature and `ProverConfig::jit_gates`, a group is instead turned into a
+//! kernel with the layout and rotations baked in, every (column, rotation)
+//! read once into a register, and compiled with NVRTC on first use. Groups of
+//! the same shape share the compiled kernel across proofs. Without the
+//! feature, or if NVRTC fails, the group runs on `field_op_batch_mul_sum`.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::fmt::Write as _;
+
+use cuda_runtime_sys::cudaStream_t;
+
+use crate::cuda::bn254_c::field_op_batch_mul_sum;
+use crate::device::cuda::to_result;
+use crate::device::cuda::CudaBuffer as _;
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::Device as _;
+use crate::device::DeviceResult;
+use crate::metrics::count_path;
+
+thread_local! {
+    static JIT_GATES: Cell<bool> = Cell::new(false);
+}
+
+/// Run gate groups through kernels compiled for them, see `ProverConfig::jit_gates`.
+pub(crate) fn set_jit_gates(enabled: bool) {
+    JIT_GATES.with(|x| x.set(enabled));
+}
+
+const KERNEL_NAME: &str = "fused_gate_terms";
+
+/// A group in the layout of `field_op_batch_mul_sum`, with its distinct
+/// pointers numbered.
+#[derive(Debug, PartialEq)]
+pub(crate) struct FusedTerms {
+    // distinct pointers, coefficients and columns
+    pub(crate) slots: Vec<*mut c_void>,
+    // (coefficient slot, [(column slot, rotation)])
+    pub(crate) terms: Vec<(usize, Vec<(usize, i32)>)>,
+}
+
+impl FusedTerms {
+    /// Splits `group` (coeff0, a00, a01, null, coeff1, a10, null, ...) and
+    /// the rotations of its columns.
+    pub(crate) fn new(group: &[*mut c_void], rots: &[i32]) -> Self {
+        let mut slot_of = BTreeMap::new();
+        let mut slots = vec![];
+        let mut slot = |ptr: *mut c_void| {
+            *slot_of.entry(ptr as usize).or_insert_with(|| {
+                slots.push(ptr);
+                slots.len() - 1
+            })
+        };
+
+        let mut terms = vec![];
+        let mut rots = rots.iter();
+        let mut ptrs = group.iter();
+        while let Some(coeff) = ptrs.next() {
+            let coeff = slot(*coeff);
+            let factors = ptrs
+                .by_ref()
+                .take_while(|ptr| !ptr.is_null())
+                .map(|ptr| (slot(*ptr), *rots.next().unwrap()))
+                .collect();
+            terms.push((coeff, factors));
+        }
+
+        FusedTerms { slots, terms }
+    }
+
+    /// CUDA source of a kernel adding the terms to `res`, it only depends on
+    /// the shape of the group and not on the pointers.
+    pub(crate) fn source(&self) -> String {
+        let mut reads = BTreeMap::new();
+        for (_, factors) in self.terms.iter() {
+            for factor in factors {
+                let next = reads.len();
+                reads.entry(*factor).or_insert(next);
+            }
+        }
+
+        let mut src = String::new();
+        writeln!(src, "#include \"bn254_fr.cuh\"\n").unwrap();
+        writeln!(
+            src,
+            "extern \"C\" __global__ void {}(Bn254FrField *res, Bn254FrField **v, int n)",
+            KERNEL_NAME
+        )
+        .unwrap();
+        writeln!(src, "{{").unwrap();
+        writeln!(src, "    int i = blockIdx.x * blockDim.x + threadIdx.x;").unwrap();
+        let mut reads_in_order = reads.iter().collect::<Vec<_>>();
+        reads_in_order.sort_by_key(|(_, idx)| **idx);
+        for ((slot, rot), idx) in reads_in_order {
+            writeln!(
+                src,
+                "    Bn254FrField c{} = v[{}][(n + i + ({})) & (n - 1)];",
+                idx, slot, rot
+            )
+            .unwrap();
+        }
+        writeln!(src, "    Bn254FrField fl(0), fr;").unwrap();
+        for (coeff, factors) in self.terms.iter() {
+            writeln!(src, "    fr = *v[{}];", coeff).unwrap();
+            for factor in factors {
+                writeln!(src, "    fr = fr * c{};", reads[factor]).unwrap();
+            }
+            writeln!(src, "    fl += fr;").unwrap();
+        }
+        writeln!(src, "    res[i] += fl;").unwrap();
+        writeln!(src, "}}").unwrap();
+        src
+    }
+}
+
+/// `res[i] += Σ coeff * Π v[(n + i + rot) & (n - 1)]` over the terms of
+/// `group`, see `field_op_batch_mul_sum`. Returns the pointer arrays the
+/// kernel reads, to be dropped once `stream` is done.
+pub(crate) unsafe fn batch_mul_sum(
+    device: &CudaDevice,
+    res: *mut c_void,
+    group: &[*mut c_void],
+    rots: &[i32],
+    n: usize,
+    stream: Option<cudaStream_t>,
+) -> DeviceResult<Vec<CudaDeviceBufRaw>> {
+    let stream = stream.unwrap_or(0usize as _);
+    if JIT_GATES.with(|x| x.get()) {
+        let terms = FusedTerms::new(group, rots);
+        if let Some(slots_buf) = nvrtc::launch(device, res, &terms, n, stream)? {
+            count_path("jit_gates");
+            return Ok(vec![slots_buf]);
+        }
+    }
+
+    let group_buf = device.alloc_device_buffer_from_slice_async(group, stream)?;
+    let rots_buf = device.alloc_device_buffer_from_slice_async(rots, stream)?;
+    let err = field_op_batch_mul_sum(
+        res,
+        group_buf.ptr(),
+        rots_buf.ptr(),
+        group.len() as i32,
+        n as i32,
+        stream,
+    );
+    to_result(
+        vec![group_buf, rots_buf],
+        err,
+        "fail to run field_op_batch_mul_sum",
+    )
+}
+
+#[cfg(not(feature = "nvrtc"))]
+mod nvrtc {
+    use super::*;
+
+    pub(super) unsafe fn launch(
+        _device: &CudaDevice,
+        _res: *mut c_void,
+        _terms: &FusedTerms,
+        _n: usize,
+        _stream: cudaStream_t,
+    ) -> DeviceResult<Option<CudaDeviceBufRaw>> {
+        thread_local! {
+            static WARNED: Cell<bool> = Cell::new(false);
+        }
+        if !WARNED.with(|x| x.replace(true)) {
+            tracing::warn!("jit_gates needs the nvrtc feature, using field_op_batch_mul_sum");
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn clear_kernels(_device_id: usize) {}
+}
+
+/// Forgets the kernels compiled for a device whose context was reset.
+pub(crate) use nvrtc::clear_kernels;
+
+#[cfg(feature = "nvrtc")]
+mod nvrtc {
+    use std::collections::HashMap;
+    use std::ffi::c_char;
+    use std::ffi::c_int;
+    use std::ffi::CString;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::sync::OnceLock;
+    use std::sync::PoisonError;
+
+    use cuda_runtime_sys::cudaDeviceAttr;
+    use cuda_runtime_sys::cudaDeviceGetAttribute;
+
+    use super::*;
+    use crate::device::Error;
+
+    type NvrtcProgram = *mut c_void;
+    type CuModule = *mut c_void;
+    type CuFunction = *mut c_void;
+
+    extern "C" {
+        fn nvrtcCreateProgram(
+            prog: *mut NvrtcProgram,
+            src: *const c_char,
+            name: *const c_char,
+            num_headers: c_int,
+            headers: *const *const c_char,
+            include_names: *const *const c_char,
+        ) -> c_int;
+        fn nvrtcCompileProgram(
+            prog: NvrtcProgram,
+            num_options: c_int,
+            options: *const *const c_char,
+        ) -> c_int;
+        fn nvrtcGetPTXSize(prog: NvrtcProgram, size: *mut usize) -> c_int;
+        fn nvrtcGetPTX(prog: NvrtcProgram, ptx: *mut c_char) -> c_int;
+        fn nvrtcGetProgramLogSize(prog: NvrtcProgram, size: *mut usize) -> c_int;
+        fn nvrtcGetProgramLog(prog: NvrtcProgram, log: *mut c_char) -> c_int;
+        fn nvrtcDestroyProgram(prog: *mut NvrtcProgram) -> c_int;
+
+        fn cuModuleLoadData(module: *mut CuModule, image: *const c_void) -> c_int;
+        fn cuModuleUnload(module: CuModule) -> c_int;
+        fn cuModuleGetFunction(f: *mut CuFunction, module: CuModule, name: *const c_char) -> c_int;
+        fn cuLaunchKernel(
+            f: CuFunction,
+            grid_x: u32,
+            grid_y: u32,
+            grid_z: u32,
+            block_x: u32,
+            block_y: u32,
+            block_z: u32,
+            shared_mem: u32,
+            stream: *mut c_void,
+            params: *mut *mut c_void,
+            extra: *mut *mut c_void,
+        ) -> c_int;
+    }
+
+    // The kernels only need the field arithmetic, the host headers it pulls in
+    // are replaced by what NVRTC lacks of them.
+    const HEADERS: [(&str, &str); 5] = [
+        (
+            "cstdint",
+            "#pragma once\n\
+             typedef signed char int8_t;\ntypedef short int16_t;\n\
+             typedef int int32_t;\ntypedef long long int64_t;\n\
+             typedef unsigned char uint8_t;\ntypedef unsigned short uint16_t;\n\
+             typedef unsigned int uint32_t;\ntypedef unsigned long long uint64_t;\n",
+        ),
+        ("cstdio", "#pragma once\n"),
+        ("cuda_runtime.h", "#pragma once\n"),
+        ("sm_32_intrinsics.h", "#pragma once\n"),
+        ("assert.h", "#pragma once\n#define assert(x)\n"),
+    ];
+
+    /// Where `bn254_fr.cuh` is read from, `ZKWASM_CUDA_SOURCE_DIR` or the
+    /// `cuda` directory of this crate.
+    fn source_dir() -> String {
+        std::env::var("ZKWASM_CUDA_SOURCE_DIR")
+            .unwrap_or_else(|_| concat!(env!("CARGO_MANIFEST_DIR"), "/cuda").to_owned())
+    }
+
+    // the module is unloaded with the last reference to the kernel
+    struct JitKernel {
+        module: CuModule,
+        function: CuFunction,
+    }
+
+    unsafe impl Send for JitKernel {}
+    unsafe impl Sync for JitKernel {}
+
+    impl Drop for JitKernel {
+        fn drop(&mut self) {
+            unsafe { cuModuleUnload(self.module) };
+        }
+    }
+
+    // None if it failed to compile
+    type CompiledKernel = Arc<OnceLock<Option<Arc<JitKernel>>>>;
+
+    lazy_static! {
+        // (device, source) -> kernel, compiled by the first launch needing it
+        static ref KERNELS: Mutex<HashMap<(usize, String), CompiledKernel>> =
+            Mutex::new(HashMap::new());
+    }
+
+    pub(crate) fn clear_kernels(device_id: usize) {
+        KERNELS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(device, _), _| *device != device_id);
+    }
+
+    unsafe fn program_log(prog: NvrtcProgram) -> String {
+        let mut size = 0;
+        if nvrtcGetProgramLogSize(prog, &mut size) != 0 || size == 0 {
+            return String::new();
+        }
+        let mut log = vec![0u8; size];
+        nvrtcGetProgramLog(prog, log.as_mut_ptr() as _);
+        String::from_utf8_lossy(&log[..size - 1]).into_owned()
+    }
+
+    unsafe fn compute_capability(device: &CudaDevice) -> DeviceResult<(i32, i32)> {
+        let mut major = 0;
+        let mut minor = 0;
+        for (attr, value) in [
+            (
+                cudaDeviceAttr::cudaDevAttrComputeCapabilityMajor,
+                &mut major,
+            ),
+            (
+                cudaDeviceAttr::cudaDevAttrComputeCapabilityMinor,
+                &mut minor,
+            ),
+        ] {
+            let err = cudaDeviceGetAttribute(value, attr, device.device_id() as i32);
+            to_result((), err, "fail to get compute capability")?;
+        }
+        Ok((major, minor))
+    }
+
+    unsafe fn compile(device: &CudaDevice, source: &str) -> DeviceResult<JitKernel> {
+        let (major, minor) = compute_capability(device)?;
+        let mut options = vec![
+            format!("--gpu-architecture=compute_{}{}", major, minor),
+            "-std=c++17".to_owned(),
+            format!("-I{}", source_dir()),
+        ];
+        if env!("ZKWASM_FR_LIMBS") == "64" {
+            options.push("-DBN254_FR_LIMB64".to_owned());
+        }
+
+        let cstr = |s: &str| CString::new(s).unwrap();
+        let src = cstr(source);
+        let name = cstr("fused_gate_terms.cu");
+        let header_src = HEADERS.iter().map(|(_, h)| cstr(h)).collect::<Vec<_>>();
+        let header_names = HEADERS.iter().map(|(n, _)| cstr(n)).collect::<Vec<_>>();
+        let header_src_ptrs = header_src.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+        let header_name_ptrs = header_names.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+        let options = options.iter().map(|x| cstr(x)).collect::<Vec<_>>();
+        let option_ptrs = options.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+
+        let mut prog = std::ptr::null_mut();
+        if nvrtcCreateProgram(
+            &mut prog,
+            src.as_ptr(),
+            name.as_ptr(),
+            HEADERS.len() as c_int,
+            header_src_ptrs.as_ptr(),
+            header_name_ptrs.as_ptr(),
+        ) != 0
+        {
+            return Err(Error::DeviceError(
+                "fail to create nvrtc program".to_owned(),
+            ));
+        }
+
+        let ptx =
+            if nvrtcCompileProgram(prog, option_ptrs.len() as c_int, option_ptrs.as_ptr()) != 0 {
+                Err(Error::DeviceError(format!(
+                    "fail to compile fused gate kernel: {}",
+                    program_log(prog)
+                )))
+            } else {
+                let mut size = 0;
+                nvrtcGetPTXSize(prog, &mut size);
+                let mut ptx = vec![0u8; size];
+                nvrtcGetPTX(prog, ptx.as_mut_ptr() as _);
+                Ok(ptx)
+            };
+        nvrtcDestroyProgram(&mut prog);
+        let ptx = ptx?;
+
+        let mut module = std::ptr::null_mut();
+        if cuModuleLoadData(&mut module, ptx.as_ptr() as _) != 0 {
+            return Err(Error::DeviceError(
+                "fail to load fused gate kernel".to_owned(),
+            ));
+        }
+        let mut function = std::ptr::null_mut();
+        if cuModuleGetFunction(&mut function, module, cstr(KERNEL_NAME).as_ptr()) != 0 {
+            cuModuleUnload(module);
+            return Err(Error::DeviceError(
+                "fail to find fused gate kernel".to_owned(),
+            ));
+        }
+        Ok(JitKernel { module, function })
+    }
+
+    // compiled without holding KERNELS, so only launches of the same shape wait for it
+    fn kernel(device: &CudaDevice, source: String) -> Option<Arc<JitKernel>> {
+        let compiled = KERNELS
+            .lock()
+            .unwrap()
+            .entry((device.device_id(), source.clone()))
+            .or_default()
+            .clone();
+        compiled
+            .get_or_init(|| match unsafe { compile(device, &source) } {
+                Ok(kernel) => Some(Arc::new(kernel)),
+                Err(e) => {
+                    tracing::warn!("{:?}, using field_op_batch_mul_sum", e);
+                    None
+                }
+            })
+            .clone()
+    }
+
+    pub(super) unsafe fn launch(
+        device: &CudaDevice,
+        res: *mut c_void,
+        terms: &FusedTerms,
+        n: usize,
+        stream: cudaStream_t,
+    ) -> DeviceResult<Option<CudaDeviceBufRaw>> {
+        let Some(kernel) = kernel(device, terms.source()) else {
+            return Ok(None);
+        };
+
+        let slots_buf = device.alloc_device_buffer_from_slice_async(&terms.slots[..], stream)?;
+        let mut res = res;
+        let mut v = slots_buf.ptr();
+        let mut n = n as i32;
+        let mut params = [
+            &mut res as *mut _ as *mut c_void,
+            &mut v as *mut _ as *mut c_void,
+            &mut n as *mut _ as *mut c_void,
+        ];
+        let threads = if n >= 64 { 64 } else { 1 };
+        let blocks = n as u32 / threads;
+        if cuLaunchKernel(
+            kernel.function,
+            blocks,
+            1,
+            1,
+            threads,
+            1,
+            1,
+            0,
+            stream as _,
+            params.as_mut_ptr(),
+            std::ptr::null_mut(),
+        ) != 0
+        {
+            return Err(Error::DeviceError(
+                "fail to launch fused gate kernel".to_owned(),
+            ));
+        }
+        Ok(Some(slots_buf))
+    }
+}
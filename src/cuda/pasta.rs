@@ -0,0 +1,184 @@
+use cuda_runtime_sys::cudaStream_t;
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::pairing::group::Curve as _;
+use halo2_proofs::pairing::group::Group as _;
+use rayon::iter::IntoParallelRefIterator as _;
+use rayon::iter::ParallelIterator as _;
+
+use super::bn254::FieldOp;
+use super::pasta_c;
+use crate::device::cuda::{to_result, CudaBuffer, CudaDevice, CudaDeviceBufRaw};
+use crate::device::{Device, DeviceResult};
+
+const PALLAS_BASE_MODULUS: &str =
+    "0x40000000000000000000000000000000224698fc094cf91b992d30ed00000001";
+const PALLAS_SCALAR_MODULUS: &str =
+    "0x40000000000000000000000000000000224698fc0994a8dd8c46eb2100000001";
+
+// threads of the chunked msm kernel, each one folds a contiguous run of points
+const MSM_WORK_ITEMS: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PastaField {
+    /// Pallas base field, the Vesta scalar field.
+    Fp = 0,
+    /// Pallas scalar field, the Vesta base field.
+    Fq = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PastaCurve {
+    Pallas = 0,
+    Vesta = 1,
+}
+
+impl PastaCurve {
+    pub fn scalar_field(&self) -> PastaField {
+        match self {
+            PastaCurve::Pallas => PastaField::Fq,
+            PastaCurve::Vesta => PastaField::Fp,
+        }
+    }
+}
+
+pub(super) fn modulus_eq(modulus: &str, expect: &str) -> bool {
+    modulus.trim_start_matches("0x").to_lowercase() == expect.trim_start_matches("0x")
+}
+
+/// Recognize the Pasta cycle by field moduli, so any `CurveAffine`
+/// implementation with the matching memory layout dispatches here.
+pub fn pasta_curve<C: CurveAffine>() -> Option<PastaCurve> {
+    let base = C::Base::MODULUS;
+    let scalar = C::Scalar::MODULUS;
+    if modulus_eq(base, PALLAS_BASE_MODULUS) && modulus_eq(scalar, PALLAS_SCALAR_MODULUS) {
+        Some(PastaCurve::Pallas)
+    } else if modulus_eq(base, PALLAS_SCALAR_MODULUS) && modulus_eq(scalar, PALLAS_BASE_MODULUS) {
+        Some(PastaCurve::Vesta)
+    } else {
+        None
+    }
+}
+
+/// `omega ^ i` for `i < 2 ^ (len_log - 1)`, the twiddle table of `ntt_raw`.
+pub fn ntt_prepare<F: FieldExt>(
+    device: &CudaDevice,
+    omega: F,
+    len_log: usize,
+) -> DeviceResult<CudaDeviceBufRaw> {
+    let half = 1usize << len_log >> 1;
+    let mut twiddles = vec![F::one(); half.max(1)];
+    for i in 1..half {
+        twiddles[i] = twiddles[i - 1] * omega;
+    }
+    device.alloc_device_buffer_from_slice(&twiddles[..])
+}
+
+pub fn ntt_raw(
+    device: &CudaDevice,
+    field: PastaField,
+    buf: &CudaDeviceBufRaw,
+    twiddles_buf: &CudaDeviceBufRaw,
+    divisor_buf: Option<&CudaDeviceBufRaw>,
+    len_log: usize,
+    stream: Option<cudaStream_t>,
+) -> DeviceResult<()> {
+    if len_log == 0 {
+        return Ok(());
+    }
+
+    unsafe {
+        device.acitve_ctx()?;
+        let err = pasta_c::pasta_ntt(
+            field as i32,
+            buf.ptr(),
+            twiddles_buf.ptr(),
+            divisor_buf.map_or(0usize as *mut _, |x| x.ptr()),
+            len_log as i32,
+            stream.unwrap_or(0usize as _),
+        );
+        to_result((), err, "fail to run pasta_ntt")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn field_op(
+    device: &CudaDevice,
+    field: PastaField,
+    res: &CudaDeviceBufRaw,
+    l: &CudaDeviceBufRaw,
+    r: &CudaDeviceBufRaw,
+    size: usize,
+    op: FieldOp,
+    stream: Option<cudaStream_t>,
+) -> DeviceResult<()> {
+    assert!(op != FieldOp::UOp);
+    unsafe {
+        device.acitve_ctx()?;
+        let err = pasta_c::pasta_field_op(
+            field as i32,
+            res.ptr(),
+            l.ptr(),
+            r.ptr(),
+            size as i32,
+            op as i32,
+            stream.unwrap_or(0usize as _),
+        );
+        to_result((), err, "fail to run pasta_field_op")?;
+    }
+    Ok(())
+}
+
+/// `sum(scalars[i] * points[i])`; the per-thread partial sums are folded on the host.
+pub fn msm<C: CurveAffine>(
+    device: &CudaDevice,
+    curve: PastaCurve,
+    p_buf: &CudaDeviceBufRaw,
+    scalars: &[C::Scalar],
+) -> DeviceResult<C> {
+    let len = scalars.len();
+    if len == 0 {
+        return Ok(C::identity());
+    }
+
+    let work_items = if len >= MSM_WORK_ITEMS {
+        MSM_WORK_ITEMS
+    } else {
+        len
+    };
+
+    let s_buf = device.alloc_device_buffer_from_slice(scalars)?;
+    let tmp_buf = device.alloc_device_buffer::<C::Scalar>(len)?;
+    let res_buf = device.alloc_device_buffer::<[C::Base; 3]>(work_items)?;
+    unsafe {
+        device.acitve_ctx()?;
+        let err = pasta_c::pasta_msm(
+            curve as i32,
+            res_buf.ptr(),
+            p_buf.ptr(),
+            s_buf.ptr(),
+            tmp_buf.ptr(),
+            len as i32,
+            work_items as i32,
+            0usize as _,
+        );
+        to_result((), err, "fail to run pasta_msm")?;
+    }
+
+    let mut res = vec![[C::Base::zero(); 3]; work_items];
+    device.copy_from_device_to_host(&mut res[..], &res_buf)?;
+
+    let sum = res
+        .par_iter()
+        .filter_map(|[x, y, z]| {
+            let z_inv: Option<C::Base> = z.invert().into();
+            z_inv.map(|z_inv| {
+                let z_inv2 = z_inv.square();
+                C::from_xy(*x * z_inv2, *y * z_inv2 * z_inv).unwrap()
+            })
+        })
+        .map(|p| p.to_curve())
+        .reduce(|| C::Curve::identity(), |a, b| a + b);
+    Ok(sum.to_affine())
+}
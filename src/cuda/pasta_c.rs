@@ -0,0 +1,35 @@
+use cuda_runtime_sys::{cudaError, CUstream_st};
+use std::ffi::c_void;
+
+#[link(name = "zkwasm_prover_kernel", kind = "static")]
+extern "C" {
+    pub fn pasta_field_op(
+        field: i32,
+        res: *mut c_void,
+        l: *mut c_void,
+        r: *mut c_void,
+        n: i32,
+        op: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn pasta_ntt(
+        field: i32,
+        buf: *mut c_void,
+        twiddles: *mut c_void,
+        divisor: *mut c_void,
+        log_n: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+
+    pub fn pasta_msm(
+        curve: i32,
+        res: *mut c_void,
+        points: *mut c_void,
+        scalars: *mut c_void,
+        tmp: *mut c_void,
+        n: i32,
+        work_items: i32,
+        stream: *mut CUstream_st,
+    ) -> cudaError;
+}
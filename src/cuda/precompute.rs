@@ -0,0 +1,364 @@
+//! Precomputed multiples of msm bases, e.g. `params.g_lagrange`.
+//!
+//! With a precompute factor `f`, every base `P` is stored with
+//! `P * 2^(shift * j)` for `j < f`, interleaved as icicle's
+//! `precompute_msm_bases` lays them out. The msm then runs `f` times fewer
+//! bucket windows over `f` times more points. Tables are computed on the host
+//! once per SRS, kept on device across proofs and optionally on disk, and
+//! attached to the bases buffer of a backend so the msm picks them up.
+//!
+//! A table file holds a header (magic, version, size, factor, window bits and
+//! the blake2b fingerprint of the whole SRS), the points as the canonical
+//! encodings of their affine coordinates, and a blake2b checksum of those.
+//! Reading it back checks all of them and that every point is on the curve.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read as _;
+use std::io::Write as _;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+use halo2_proofs::arithmetic::Coordinates;
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::pairing::group::ff::PrimeField;
+use halo2_proofs::pairing::group::Curve as _;
+use halo2_proofs::pairing::group::Group as _;
+use halo2_proofs::pairing::group::GroupEncoding as _;
+use rayon::iter::IndexedParallelIterator as _;
+use rayon::iter::IntoParallelRefIterator as _;
+use rayon::iter::ParallelIterator as _;
+use rayon::slice::ParallelSlice as _;
+use rayon::slice::ParallelSliceMut as _;
+
+use crate::device::cuda::tag_buffer;
+use crate::device::cuda::CudaBuffer;
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::Device as _;
+use crate::Error;
+
+const MAGIC: &[u8; 4] = b"ZKPB";
+const VERSION: u32 = 2;
+// bases normalized per batch inversion
+const NORMALIZE_CHUNK: usize = 1 << 10;
+// points per leaf of the fingerprint and checksum hashes, hashed in parallel
+const HASH_CHUNK: usize = 1 << 16;
+
+type Digest = [u8; 32];
+
+/// A precomputed table on device.
+pub struct PrecomputedBases {
+    buf: CudaDeviceBufRaw,
+    /// Bases the table was computed from.
+    pub len: usize,
+    pub factor: usize,
+    /// Window bits the table was computed for, the msm has to use the same.
+    pub c: usize,
+}
+
+unsafe impl Send for PrecomputedBases {}
+unsafe impl Sync for PrecomputedBases {}
+
+impl PrecomputedBases {
+    pub(crate) fn ptr(&self) -> *mut std::ffi::c_void {
+        self.buf.ptr()
+    }
+}
+
+// (device, bases fingerprint, factor, c)
+type TableKey = (usize, Digest, usize, usize);
+
+lazy_static! {
+    // tables kept across proofs
+    static ref PRECOMPUTED_TABLES: Mutex<HashMap<TableKey, Arc<PrecomputedBases>>> =
+        Mutex::new(HashMap::new());
+    // (device, bases buffer) -> table used by the msm over that buffer
+    static ref ATTACHED_TABLES: Mutex<HashMap<(usize, usize), Arc<PrecomputedBases>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Doublings between consecutive multiples of a base, the bit span of one
+/// group of `ceil(bits / c / factor)` windows.
+pub fn precompute_shift(bits: usize, factor: usize, c: usize) -> usize {
+    c * ((bits - 1) / (c * factor) + 1)
+}
+
+fn blake2b() -> blake2b_simd::State {
+    blake2b_simd::Params::new().hash_length(32).to_state()
+}
+
+// blake2b over the digests of the leaves, hashed in parallel
+fn tree_hash<T: Sync>(items: &[T], leaf: impl Fn(&mut blake2b_simd::State, &T) + Sync) -> Digest {
+    let leaves = items
+        .par_chunks(HASH_CHUNK)
+        .map(|chunk| {
+            let mut state = blake2b();
+            for item in chunk {
+                leaf(&mut state, item);
+            }
+            state.finalize()
+        })
+        .collect::<Vec<_>>();
+    let mut state = blake2b();
+    state.update(&(items.len() as u64).to_le_bytes());
+    for digest in leaves {
+        state.update(digest.as_bytes());
+    }
+    state.finalize().as_bytes().try_into().unwrap()
+}
+
+// identifies an SRS by all of its bases
+pub(crate) fn fingerprint<C: CurveAffine>(bases: &[C]) -> Digest {
+    tree_hash(bases, |state, base| {
+        state.update(base.to_bytes().as_ref());
+    })
+}
+
+fn repr_len<C: CurveAffine>() -> usize {
+    <C::Base as PrimeField>::Repr::default().as_ref().len()
+}
+
+// canonical x and y, the identity as zeros, which is not a point of the curve
+fn encode_point<C: CurveAffine>(point: &C, out: &mut [u8]) {
+    let n = repr_len::<C>();
+    match Option::<Coordinates<C>>::from(point.coordinates()) {
+        Some(xy) => {
+            out[..n].copy_from_slice(xy.x().to_repr().as_ref());
+            out[n..].copy_from_slice(xy.y().to_repr().as_ref());
+        }
+        None => out.fill(0),
+    }
+}
+
+fn decode_point<C: CurveAffine>(bytes: &[u8]) -> Option<C> {
+    if bytes.iter().all(|x| *x == 0) {
+        return Some(C::identity());
+    }
+    let n = repr_len::<C>();
+    let coordinate = |bytes: &[u8]| {
+        let mut repr = <C::Base as PrimeField>::Repr::default();
+        repr.as_mut().copy_from_slice(bytes);
+        Option::<C::Base>::from(C::Base::from_repr(repr))
+    };
+    let (x, y) = (coordinate(&bytes[..n])?, coordinate(&bytes[n..])?);
+    Option::from(C::from_xy(x, y))
+}
+
+/// The table of `bases` on the host, `factor` points per base.
+pub fn precompute_bases_host<C: CurveAffine>(bases: &[C], factor: usize, c: usize) -> Vec<C> {
+    let shift = precompute_shift(C::Scalar::NUM_BITS as usize, factor, c);
+    let mut table = vec![C::identity(); bases.len() * factor];
+    table
+        .par_chunks_mut(NORMALIZE_CHUNK * factor)
+        .zip(bases.par_chunks(NORMALIZE_CHUNK))
+        .for_each(|(table, bases)| {
+            let mut projective = Vec::with_capacity(table.len());
+            for base in bases {
+                let mut p = base.to_curve();
+                projective.push(p);
+                for _ in 1..factor {
+                    for _ in 0..shift {
+                        p = p.double();
+                    }
+                    projective.push(p);
+                }
+            }
+            C::Curve::batch_normalize(&projective[..], table);
+        });
+    table
+}
+
+fn table_path(dir: &Path, fingerprint: &Digest, len: usize, factor: usize, c: usize) -> PathBuf {
+    let hex = fingerprint
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<String>();
+    dir.join(format!("bases-{}-{}-{}-{}.bin", hex, len, factor, c))
+}
+
+// magic, version, points of the table, factor, window bits, fingerprint
+fn table_header(fingerprint: &Digest, len: usize, factor: usize, c: usize) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend(VERSION.to_le_bytes());
+    for x in [len, factor, c] {
+        header.extend((x as u64).to_le_bytes());
+    }
+    header.extend(fingerprint);
+    header
+}
+
+fn invalid_table(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn read_table<C: CurveAffine>(
+    path: &Path,
+    fingerprint: &Digest,
+    len: usize,
+    factor: usize,
+    c: usize,
+) -> std::io::Result<Vec<C>> {
+    let mut file = fs::File::open(path)?;
+    let expected = table_header(fingerprint, len, factor, c);
+    let mut header = vec![0u8; expected.len()];
+    file.read_exact(&mut header)?;
+    if header != expected {
+        return Err(invalid_table(
+            "not a precomputed bases table of this version, srs and shape",
+        ));
+    }
+
+    let point_len = 2 * repr_len::<C>();
+    let mut bytes = vec![0u8; len * point_len];
+    file.read_exact(&mut bytes)?;
+    let mut checksum = Digest::default();
+    file.read_exact(&mut checksum)?;
+    let points = bytes.chunks(point_len).collect::<Vec<_>>();
+    if tree_hash(&points[..], |state, point| {
+        state.update(point);
+    }) != checksum
+    {
+        return Err(invalid_table("precomputed bases table is corrupt"));
+    }
+
+    points
+        .par_iter()
+        .map(|point| decode_point::<C>(point))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid_table("precomputed bases table has points off the curve"))
+}
+
+pub(crate) fn write_table<C: CurveAffine>(
+    path: &Path,
+    fingerprint: &Digest,
+    factor: usize,
+    c: usize,
+    table: &[C],
+) -> std::io::Result<()> {
+    let point_len = 2 * repr_len::<C>();
+    let mut bytes = vec![0u8; table.len() * point_len];
+    bytes
+        .par_chunks_mut(point_len)
+        .zip(table.par_iter())
+        .for_each(|(out, point)| encode_point(point, out));
+    let points = bytes.chunks(point_len).collect::<Vec<_>>();
+    let checksum = tree_hash(&points[..], |state, point| {
+        state.update(point);
+    });
+
+    let tmp = path.with_extension("bin.tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&table_header(fingerprint, table.len(), factor, c))?;
+    file.write_all(&bytes)?;
+    file.write_all(&checksum)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+/// The table of `bases` on `device`: from the cache of the process, else from
+/// `dir` when given, else computed on the host and written to `dir`.
+pub fn precompute_bases<C: CurveAffine>(
+    device: &CudaDevice,
+    bases: &[C],
+    factor: usize,
+    c: usize,
+    dir: Option<&Path>,
+) -> Result<Arc<PrecomputedBases>, Error> {
+    if factor < 2 || c == 0 {
+        return Err(Error::InvalidInput(format!(
+            "precompute factor {} and window bits {} don't make a table",
+            factor, c
+        )));
+    }
+    let fingerprint = fingerprint(bases);
+    let key = (device.device_id(), fingerprint, factor, c);
+    if let Some(table) = PRECOMPUTED_TABLES.lock().unwrap().get(&key) {
+        return Ok(table.clone());
+    }
+
+    let path = dir.map(|dir| table_path(dir, &fingerprint, bases.len(), factor, c));
+    let table = match path
+        .as_ref()
+        .map(|path| read_table::<C>(path, &fingerprint, bases.len() * factor, factor, c))
+    {
+        Some(Ok(table)) => table,
+        res => {
+            if let Some(Err(e)) = res {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("ignore precomputed bases table: {}", e);
+                }
+            }
+            let table = precompute_bases_host(bases, factor, c);
+            if let Some(path) = &path {
+                if let Err(e) = write_table(path, &fingerprint, factor, c, &table) {
+                    tracing::warn!("fail to store precomputed bases table: {}", e);
+                }
+            }
+            table
+        }
+    };
+
+    let buf = device.alloc_device_buffer_from_slice(&table[..])?;
+    tag_buffer(&buf, "precomputed bases");
+    let table = Arc::new(PrecomputedBases {
+        buf,
+        len: bases.len(),
+        factor,
+        c,
+    });
+    PRECOMPUTED_TABLES
+        .lock()
+        .unwrap()
+        .insert(key, table.clone());
+    Ok(table)
+}
+
+/// Msm over `bases_buf` use `table` from now on, until `detach_precomputed`.
+pub(crate) fn attach_precomputed(bases_buf: &CudaDeviceBufRaw, table: Arc<PrecomputedBases>) {
+    ATTACHED_TABLES.lock().unwrap().insert(
+        (bases_buf.device.device_id(), bases_buf.ptr() as usize),
+        table,
+    );
+}
+
+pub(crate) fn detach_precomputed(bases_buf: &CudaDeviceBufRaw) {
+    ATTACHED_TABLES
+        .lock()
+        .unwrap()
+        .remove(&(bases_buf.device.device_id(), bases_buf.ptr() as usize));
+}
+
+/// The table attached to `bases_buf`, if it covers msm of `len` points.
+pub(crate) fn attached_precomputed(
+    bases_buf: &CudaDeviceBufRaw,
+    len: usize,
+) -> Option<Arc<PrecomputedBases>> {
+    ATTACHED_TABLES
+        .lock()
+        .unwrap()
+        .get(&(bases_buf.device.device_id(), bases_buf.ptr() as usize))
+        .filter(|table| table.len == len)
+        .cloned()
+}
+
+/// Frees the tables kept on device, those attached to a live backend are
+/// freed when it is dropped.
+pub fn clear_precomputed_bases() {
+    PRECOMPUTED_TABLES.lock().unwrap().clear();
+}
+
+/// Forgets the tables on `device_id`, kept or attached.
+pub(crate) fn clear_device_precomputed_bases(device_id: usize) {
+    PRECOMPUTED_TABLES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|key, _| key.0 != device_id);
+    ATTACHED_TABLES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|key, _| key.0 != device_id);
+}
@@ -269,3 +269,576 @@ fn test_bn254_fft() {
         assert!(s == s_origin);
     }
 }
+
+#[test]
+fn test_bn254_batch_fft() {
+    use crate::cuda::bn254::{batch_intt_raw, batch_ntt_host};
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let len_log = 20;
+    let mut omega = Fr::ROOT_OF_UNITY_INV.invert().unwrap();
+    for _ in len_log..Fr::S {
+        omega = omega.square();
+    }
+    let (omegas_buf, pq_buf) = super::bn254::ntt_prepare(&device, omega, len_log as usize).unwrap();
+    let (intt_omegas_buf, intt_pq_buf) =
+        super::bn254::ntt_prepare(&device, omega.invert().unwrap(), len_log as usize).unwrap();
+    let divisor = Fr::from(1 << len_log).invert().unwrap();
+    let divisor_buf = device
+        .alloc_device_buffer_from_slice(&[divisor][..])
+        .unwrap();
+
+    // more columns than one launch takes, the last group is partial
+    let origin = (0..11)
+        .map(|_| (0..1 << len_log).map(|_| Fr::rand()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let mut columns = origin.clone();
+    batch_ntt_host(
+        &device,
+        columns.iter_mut().map(|x| &mut x[..]).collect(),
+        &pq_buf,
+        &omegas_buf,
+        None,
+        len_log as usize,
+    )
+    .unwrap();
+    for (column, origin) in columns.iter().zip(origin.iter()) {
+        let mut expect = origin.clone();
+        best_fft_cpu(&mut expect[..], omega, len_log);
+        assert!(*column == expect);
+    }
+
+    batch_intt_raw(
+        &device,
+        columns.iter_mut().map(|x| &mut x[..]).collect(),
+        &intt_pq_buf,
+        &intt_omegas_buf,
+        &divisor_buf,
+        len_log as usize,
+    )
+    .unwrap();
+    assert!(columns == origin);
+}
+
+#[test]
+fn test_bn254_four_step_fft() {
+    use halo2_proofs::poly::EvaluationDomain;
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let device_count = CudaDevice::get_device_count().unwrap();
+    let n_devices = 1 << (usize::BITS - 1 - device_count.leading_zeros());
+    let devices = (0..n_devices)
+        .map(|idx| CudaDevice::get_device(idx).unwrap())
+        .collect::<Vec<_>>();
+
+    let k = 18;
+    let domain = EvaluationDomain::<Fr>::new(3, k);
+    let coeffs = (0..1 << k).map(|_| Fr::rand()).collect::<Vec<_>>();
+
+    let timer = start_timer!(|| "st cpu cost");
+    let expected = domain.coeff_to_extended(domain.coeff_from_vec(coeffs.clone()));
+    end_timer!(timer);
+
+    let mut res = vec![Fr::zero(); 1 << domain.extended_k()];
+    crate::eval_h::do_extended_fft(&device, &domain, &coeffs[..], &mut res[..]).unwrap();
+    assert!(res[..] == expected[..]);
+
+    let mut res = vec![Fr::zero(); 1 << domain.extended_k()];
+    crate::eval_h::do_extended_fft_multi(&devices[..], &domain, &coeffs[..], &mut res[..]).unwrap();
+    assert!(res[..] == expected[..]);
+
+    // shapes the four steps can't shard are rejected before touching the devices
+    let three = vec![device.clone(), device.clone(), device.clone()];
+    let err = crate::eval_h::do_extended_fft_multi(&three[..], &domain, &coeffs[..], &mut res[..]);
+    assert!(matches!(err, Err(crate::device::Error::InvalidInput(_))));
+    let err =
+        crate::eval_h::do_extended_fft_multi(&devices[..], &domain, &coeffs[..], &mut res[1..]);
+    assert!(matches!(err, Err(crate::device::Error::InvalidInput(_))));
+}
+
+#[test]
+fn test_bn254_rotation_shift() {
+    use crate::cuda::bn254::{buffer_copy_with_shift, field_op, pick_from_buf, FieldOp};
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let size = 1 << 10;
+    let src = (0..size).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let rhs = (0..size).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let src_buf = device.alloc_device_buffer_from_slice(&src[..]).unwrap();
+    let rhs_buf = device.alloc_device_buffer_from_slice(&rhs[..]).unwrap();
+    let dst_buf = device.alloc_device_buffer::<Fr>(size).unwrap();
+
+    let size_i = size as isize;
+    for rot in [0, 1, -1, 3, -3, 8, -8, size_i - 1, -size_i - 2, size_i + 5] {
+        let at = |i: usize| src[(i as isize + rot).rem_euclid(size_i) as usize];
+
+        buffer_copy_with_shift::<Fr>(&device, &dst_buf, &src_buf, rot, size).unwrap();
+        let mut res = vec![Fr::zero(); size];
+        device
+            .copy_from_device_to_host(&mut res[..], &dst_buf)
+            .unwrap();
+        assert!((0..size).all(|i| res[i] == at(i)), "shift {}", rot);
+
+        field_op::<Fr>(
+            &device,
+            &dst_buf,
+            Some(&src_buf),
+            rot as i32,
+            None,
+            Some(&rhs_buf),
+            0,
+            None,
+            size,
+            FieldOp::Add,
+            None,
+        )
+        .unwrap();
+        device
+            .copy_from_device_to_host(&mut res[..], &dst_buf)
+            .unwrap();
+        assert!(
+            (0..size).all(|i| res[i] == at(i) + rhs[i]),
+            "field_op {}",
+            rot
+        );
+
+        let v = pick_from_buf::<Fr>(&device, &src_buf, rot, 1, size).unwrap();
+        assert_eq!(v, at(1));
+    }
+}
+
+#[test]
+fn test_bn254_histogram() {
+    use crate::cuda::bn254::histogram;
+    use std::collections::HashMap;
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let size = 1 << 16;
+    let table = (0..1000).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let mut rng = rand::thread_rng();
+    let values = (0..size)
+        .map(|_| table[rng.gen_range(0..table.len())])
+        .collect::<Vec<_>>();
+    let values_buf = device.alloc_device_buffer_from_slice(&values[..]).unwrap();
+
+    let mut expect = HashMap::new();
+    for v in values.iter() {
+        *expect.entry(v.to_repr()).or_insert(0u32) += 1;
+    }
+
+    let res = histogram::<Fr>(&device, &values_buf, size).unwrap();
+    assert_eq!(res.len(), expect.len());
+    for (v, count) in res {
+        assert_eq!(expect.get(&v.to_repr()), Some(&count));
+    }
+}
+
+#[test]
+fn test_bn254_field_backends() {
+    const OUTPUTS: usize = 7;
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let size = 1 << 12;
+    let a = (0..size).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let b = (0..size).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let a_buf = device.alloc_device_buffer_from_slice(&a[..]).unwrap();
+    let b_buf = device.alloc_device_buffer_from_slice(&b[..]).unwrap();
+
+    let mut results = vec![];
+    for f in [bn254_c::field_ops_limb32, bn254_c::field_ops_limb64] {
+        let out_buf = device.alloc_device_buffer::<Fr>(size * OUTPUTS).unwrap();
+        unsafe {
+            let err = f(
+                a_buf.ptr(),
+                b_buf.ptr(),
+                out_buf.ptr(),
+                size as i32,
+                0usize as _,
+            );
+            to_result((), err, "fail to run field_ops").unwrap();
+        }
+        let mut out = vec![Fr::zero(); size * OUTPUTS];
+        device
+            .copy_from_device_to_host(&mut out[..], &out_buf)
+            .unwrap();
+        results.push(out);
+    }
+
+    assert!(results[0] == results[1]);
+    for (i, (a, b)) in a.iter().zip(b.iter()).enumerate() {
+        let res = &results[0][i * OUTPUTS..(i + 1) * OUTPUTS];
+        assert_eq!(res[0], *a + b);
+        assert_eq!(res[1], *a - b);
+        assert_eq!(res[2], *a * b);
+        assert_eq!(res[3], a.square());
+        assert_eq!(res[4], -*a);
+        assert_eq!(res[5], a.invert().unwrap());
+        // limbs of the unmont value are the little-endian canonical representation
+        let unmont = unsafe { std::slice::from_raw_parts(&res[6] as *const Fr as *const u8, 32) };
+        assert_eq!(unmont, a.to_repr().as_ref());
+    }
+}
+
+#[test]
+fn test_typed_buffer() {
+    use crate::cuda::bn254::pick_from_buf;
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let size = 1 << 10;
+    let src = (0..size).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let buf = device.alloc_typed_buffer_from_slice(&src[..]).unwrap();
+    assert_eq!(buf.len(), size);
+    assert_eq!(
+        pick_from_buf::<Fr>(&device, &buf, 0, 3, size).unwrap(),
+        src[3]
+    );
+    assert!(pick_from_buf::<Fr>(&device, &buf, 0, 3, size * 2).is_err());
+
+    let limbs = buf.reinterpret::<u64>().unwrap();
+    assert_eq!(limbs.len(), size * 4);
+    // three scalars aren't a whole number of affine points
+    let odd = device.alloc_typed_buffer_from_slice(&src[..3]).unwrap();
+    assert!(odd.reinterpret::<G1Affine>().is_err());
+}
+
+#[test]
+fn test_batch_msm_paired() {
+    use crate::cuda::bn254::{batch_msm, batch_msm_paired, msm_batch};
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let len = 1 << 12;
+    let p = (0..len)
+        .map(|_| (G1Affine::generator() * Fr::rand()).to_affine())
+        .collect::<Vec<_>>();
+    let columns = (0..6)
+        .map(|_| (0..len).map(|_| Fr::rand()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let p_buf = device.alloc_typed_buffer_from_slice(&p[..]).unwrap();
+    let s_buf = device.alloc_device_buffer::<Fr>(len).unwrap();
+    let t_buf = device.alloc_device_buffer::<Fr>(len).unwrap();
+
+    let expect = batch_msm::<G1Affine>(
+        &p_buf,
+        [&s_buf, &t_buf],
+        columns.iter().map(|x| &x[..]).collect(),
+        len,
+    )
+    .unwrap();
+    let paired = batch_msm_paired::<G1Affine>(
+        &p_buf,
+        columns.chunks(2).map(|x| [&x[0][..], &x[1][..]]).collect(),
+        len,
+    )
+    .unwrap();
+    assert_eq!(paired.concat(), expect);
+
+    // the last launch commits a partial batch
+    let batched =
+        msm_batch::<G1Affine>(&p_buf, columns.iter().map(|x| &x[..]).collect(), len, 4).unwrap();
+    assert_eq!(batched, expect);
+}
+
+#[test]
+fn test_batch_msm_sparse() {
+    use crate::cuda::bn254::{batch_msm, msm_batch};
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let len = 1 << 14;
+    let p = (0..len)
+        .map(|_| (G1Affine::generator() * Fr::rand()).to_affine())
+        .collect::<Vec<_>>();
+    let mut rng = rand::thread_rng();
+    // a dense prefix followed by zeros and ones, as in a segment of a long trace
+    let columns = [0, 1, 1 << 10]
+        .iter()
+        .map(|&prefix| {
+            (0..len)
+                .map(|i| match rng.gen_range(0..16) {
+                    _ if i < prefix => Fr::rand(),
+                    0 => Fr::rand(),
+                    1..=4 => Fr::one(),
+                    _ => Fr::zero(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let p_buf = device.alloc_typed_buffer_from_slice(&p[..]).unwrap();
+    let s_buf = device.alloc_device_buffer::<Fr>(len).unwrap();
+    let t_buf = device.alloc_device_buffer::<Fr>(len).unwrap();
+
+    let res = batch_msm::<G1Affine>(
+        &p_buf,
+        [&s_buf, &t_buf],
+        columns.iter().map(|x| &x[..]).collect(),
+        len,
+    )
+    .unwrap();
+    let expect =
+        msm_batch::<G1Affine>(&p_buf, columns.iter().map(|x| &x[..]).collect(), len, 1).unwrap();
+    assert_eq!(res, expect);
+}
+
+#[test]
+fn test_msm_streamed() {
+    use crate::cuda::bn254::{msm_batch, msm_streamed};
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let len = 1 << 14;
+    let p = (0..len)
+        .map(|_| (G1Affine::generator() * Fr::rand()).to_affine())
+        .collect::<Vec<_>>();
+    let columns = (0..3)
+        .map(|_| (0..len).map(|_| Fr::rand()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let p_buf = device.alloc_typed_buffer_from_slice(&p[..]).unwrap();
+    let expect =
+        msm_batch::<G1Affine>(&p_buf, columns.iter().map(|x| &x[..]).collect(), len, 1).unwrap();
+
+    // a window that doesn't divide the bases leaves a short last chunk
+    for window in [len, 1 << 12, 3000] {
+        let res = msm_streamed::<G1Affine>(
+            &device,
+            &p,
+            columns.iter().map(|x| &x[..]).collect(),
+            window,
+        )
+        .unwrap();
+        assert_eq!(res, expect);
+    }
+}
+
+#[test]
+fn test_precompute_bases_host() {
+    use crate::cuda::precompute::{precompute_bases_host, precompute_shift};
+
+    let bases = (0..3000)
+        .map(|_| (G1Affine::generator() * Fr::rand()).to_affine())
+        .collect::<Vec<_>>();
+    let (factor, c) = (3, 13);
+    let table = precompute_bases_host(&bases[..], factor, c);
+    let shift = Fr::from(2).pow_vartime([precompute_shift(254, factor, c) as u64]);
+
+    for (i, base) in bases.iter().enumerate() {
+        let mut multiple = Fr::one();
+        for j in 0..factor {
+            assert_eq!(table[i * factor + j], (*base * multiple).to_affine());
+            multiple *= shift;
+        }
+    }
+}
+
+#[test]
+fn test_precompute_table_file() {
+    use crate::cuda::precompute::{fingerprint, read_table, write_table};
+    use halo2_proofs::pairing::group::Group as _;
+
+    let mut table = (0..1000)
+        .map(|_| (G1Affine::generator() * Fr::rand()).to_affine())
+        .collect::<Vec<_>>();
+    table[7] = G1Affine::identity();
+    let (factor, c) = (2, 13);
+    let srs = fingerprint(&table[..table.len() / factor]);
+    let path = std::env::temp_dir().join(format!("zkwasm-prover-bases-{}.bin", std::process::id()));
+
+    write_table(&path, &srs, factor, c, &table).unwrap();
+    let read = read_table::<G1Affine>(&path, &srs, table.len(), factor, c).unwrap();
+    assert_eq!(read, table);
+    assert!(read_table::<G1Affine>(&path, &srs, table.len(), factor, c + 1).is_err());
+    let other = fingerprint(&table[1..table.len() / factor + 1]);
+    assert!(read_table::<G1Affine>(&path, &other, table.len(), factor, c).is_err());
+
+    let mut bytes = std::fs::read(&path).unwrap();
+    let i = bytes.len() / 2;
+    bytes[i] ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+    assert!(read_table::<G1Affine>(&path, &srs, table.len(), factor, c).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_fused_gate_terms() {
+    use crate::cuda::jit::FusedTerms;
+    use std::ffi::c_void;
+
+    let ptr = |x: usize| x as *mut c_void;
+    let null = std::ptr::null_mut();
+    // 2 * a(X) * a(wX) + 3 * a(X) * b(X) * b(X)
+    let group = [
+        ptr(1),
+        ptr(10),
+        ptr(10),
+        null,
+        ptr(2),
+        ptr(10),
+        ptr(11),
+        ptr(11),
+        null,
+    ];
+    let rots = [0, 8, 0, 0, 0];
+    let terms = FusedTerms::new(&group, &rots);
+    assert_eq!(terms.slots, vec![ptr(1), ptr(10), ptr(2), ptr(11)]);
+    assert_eq!(
+        terms.terms,
+        vec![(0, vec![(1, 0), (1, 8)]), (2, vec![(1, 0), (3, 0), (3, 0)])]
+    );
+
+    // a(X) and b(X) are each read once
+    let source = terms.source();
+    assert_eq!(source.matches("v[1][(n + i + (0))").count(), 1);
+    assert_eq!(source.matches("v[3][(n + i + (0))").count(), 1);
+    assert_eq!(source.matches("fl += fr;").count(), 2);
+
+    // the source only depends on the shape
+    let moved = [
+        ptr(5),
+        ptr(20),
+        ptr(20),
+        null,
+        ptr(6),
+        ptr(20),
+        ptr(21),
+        ptr(21),
+        null,
+    ];
+    assert_eq!(FusedTerms::new(&moved, &rots).source(), source);
+}
+
+#[test]
+fn test_field_beta_gamma_mul() {
+    use crate::cuda::bn254::field_beta_gamma_mul;
+    use crate::device::cuda::TypedBuffer as _;
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let size = 1 << 10;
+    let (beta, gamma) = (Fr::rand(), Fr::rand());
+    let a = (0..size).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let b = (0..size).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let a_buf = device.alloc_typed_buffer_from_slice(&a[..]).unwrap();
+    let b_buf = device.alloc_typed_buffer_from_slice(&b[..]).unwrap();
+    let beta_gamma = device
+        .alloc_typed_buffer_from_slice(&[beta, gamma][..])
+        .unwrap();
+    let res = device.alloc_typed_buffer::<Fr>(size).unwrap();
+
+    field_beta_gamma_mul(
+        &device,
+        &res,
+        &a_buf,
+        &b_buf,
+        &beta_gamma,
+        false,
+        size,
+        None,
+    )
+    .unwrap();
+    // a second pass accumulates the same product
+    field_beta_gamma_mul(&device, &res, &a_buf, &b_buf, &beta_gamma, true, size, None).unwrap();
+
+    let mut got = vec![Fr::zero(); size];
+    device
+        .copy_from_device_to_host(&mut got[..], res.raw())
+        .unwrap();
+    for i in 0..size {
+        let t = (beta + a[i]) * (gamma + b[i]);
+        assert_eq!(got[i], t * t);
+    }
+}
+
+#[test]
+fn test_lookup_permute_table() {
+    use crate::cuda::bn254::lookup_permute_table;
+    use crate::{compare_scalar, permute_table_on_host};
+
+    let device = CudaDevice::get_device(0).unwrap();
+    // not a multiple of the compaction segment
+    let n = 3000;
+    let mut rng = rand::thread_rng();
+    let mut input = (0..n)
+        .map(|_| Fr::from(rng.gen_range(0..100u64)))
+        .collect::<Vec<_>>();
+    let mut table = (0..n).map(|i| Fr::from(i as u64 % 150)).collect::<Vec<_>>();
+    input.sort_unstable_by(compare_scalar);
+    table.sort_unstable_by(compare_scalar);
+
+    let mut expect = vec![Fr::zero(); n];
+    permute_table_on_host(&input[..], &table[..], &mut expect[..]);
+    let mut got = vec![Fr::zero(); n];
+    lookup_permute_table(&device, &input[..], &table[..], &mut got[..]).unwrap();
+    assert_eq!(got, expect);
+}
+
+#[test]
+fn test_lookup_permute_table_edge_cases() {
+    use crate::cuda::bn254::lookup_permute_table;
+    use crate::device::Error;
+    use crate::{compare_scalar, permute_table_on_host};
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let n = 2500;
+
+    // repeated input values, every table entry twice
+    let mut input = (0..n).map(|i| Fr::from(i as u64 % 700)).collect::<Vec<_>>();
+    let mut table = (0..n).map(|i| Fr::from(i as u64 / 2)).collect::<Vec<_>>();
+    input.sort_unstable_by(compare_scalar);
+    table.sort_unstable_by(compare_scalar);
+    let mut expect = vec![Fr::zero(); n];
+    permute_table_on_host(&input[..], &table[..], &mut expect[..]);
+    let mut got = vec![Fr::zero(); n];
+    lookup_permute_table(&device, &input[..], &table[..], &mut got[..]).unwrap();
+    assert_eq!(got, expect);
+
+    // an input value the table doesn't have
+    input[n - 1] = Fr::from(n as u64);
+    let res = lookup_permute_table(&device, &input[..], &table[..], &mut got[..]);
+    assert!(matches!(res, Err(Error::InvalidInput(_))));
+}
+
+#[test]
+fn test_bn254_ntt_launch_configs() {
+    use crate::cuda::autotune::{set_launch_config, LAUNCH_NTT};
+    use crate::cuda::bn254::MAX_DEG;
+
+    let device = CudaDevice::get_device(0).unwrap();
+    let len_log = 14;
+    let mut omega = Fr::ROOT_OF_UNITY_INV.invert().unwrap();
+    for _ in len_log..Fr::S {
+        omega = omega.square();
+    }
+    let (omegas_buf, pq_buf) = super::bn254::ntt_prepare(&device, omega, len_log as usize).unwrap();
+    let origin = (0..1 << len_log).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let mut expect = origin.clone();
+    best_fft_cpu(&mut expect[..], omega, len_log);
+
+    // every radix the autotuner may pick gives the same result
+    for deg in 1..=MAX_DEG {
+        set_launch_config(&device, LAUNCH_NTT, len_log as usize, deg).unwrap();
+        let mut s_buf = device.alloc_device_buffer_from_slice(&origin[..]).unwrap();
+        let mut t_buf = device.alloc_device_buffer::<Fr>(1 << len_log).unwrap();
+        ntt_raw(
+            &device,
+            &mut s_buf,
+            &mut t_buf,
+            &pq_buf,
+            &omegas_buf,
+            len_log as usize,
+            None,
+        )
+        .unwrap();
+        let mut res = vec![Fr::zero(); 1 << len_log];
+        device
+            .copy_from_device_to_host(&mut res[..], &s_buf)
+            .unwrap();
+        assert!(res == expect, "radix 2^{}", deg);
+    }
+    set_launch_config(&device, LAUNCH_NTT, len_log as usize, 0).unwrap();
+}
+
+#[test]
+fn test_stream_priority_on_worker_threads() {
+    use crate::device::cuda::{scope_stream_priority, stream_priority, StreamPriority};
+
+    // other proofs only ever raise the priority, Critical can't be overridden
+    let _priority = scope_stream_priority(StreamPriority::Critical);
+    let priority = std::thread::spawn(stream_priority).join().unwrap();
+    assert_eq!(priority, StreamPriority::Critical);
+}
@@ -0,0 +1,125 @@
+//! A proving key uploaded to a device once for many proofs.
+//!
+//! Each proof otherwise copies the fixed columns, the permutation polynomials
+//! and the extended l0 / l_last from the host and rebuilds the gate groups
+//! from the expression tree. A proving service holding a `CudaProvingKey` pays
+//! for all of it when the key is loaded, see `create_proof_from_advices_with_cuda_pk`.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::plonk::ProvingKey;
+
+use crate::device::cuda::tag_buffer;
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::Device as _;
+use crate::eval_h::extend_polys;
+use crate::eval_h::extended_l_active_row;
+use crate::eval_h::ExtendedLActiveRow;
+use crate::plan::analyze_expr_tree;
+use crate::plan::ExprGroups;
+use crate::Error;
+
+/// `pk` with its columns on one device.
+pub struct CudaProvingKey<'a, C: CurveAffine> {
+    pub pk: &'a ProvingKey<C>,
+    pub(crate) device: CudaDevice,
+    /// Fixed columns in lagrange form, the tables of lookups over one fixed column.
+    pub(crate) fixed_values: Vec<CudaDeviceBufRaw>,
+    /// Fixed columns in coefficient form, read by evaluate_h.
+    pub(crate) fixed_polys: Arc<Vec<CudaDeviceBufRaw>>,
+    /// Permutation polynomials in coefficient form.
+    pub(crate) permutation_polys: Vec<CudaDeviceBufRaw>,
+    // extended coset form
+    pub(crate) l0: CudaDeviceBufRaw,
+    pub(crate) l_last: CudaDeviceBufRaw,
+    pub(crate) l_active_row: ExtendedLActiveRow<C::Scalar>,
+    pub(crate) gates: ExprGroups<C::Scalar>,
+    // lookup -> fixed column of its table
+    lookup_tables: BTreeMap<usize, usize>,
+}
+
+unsafe impl<'a, C: CurveAffine> Send for CudaProvingKey<'a, C> {}
+unsafe impl<'a, C: CurveAffine> Sync for CudaProvingKey<'a, C> {}
+
+impl<'a, C: CurveAffine> CudaProvingKey<'a, C> {
+    /// Uploads `pk` to device `device_id`.
+    pub fn new(pk: &'a ProvingKey<C>, device_id: usize) -> Result<Self, Error> {
+        if pk.ev.gpu_gates_expr.len() != 1 {
+            return Err(Error::InvalidInput(format!(
+                "proving key has gates expressions for {} gpus, expect 1",
+                pk.ev.gpu_gates_expr.len()
+            )));
+        }
+        let device = CudaDevice::get_device(device_id)?;
+        let k = pk.get_vk().domain.k() as usize;
+
+        let upload = |values: &[C::Scalar], tag: &str| {
+            let buf = device.alloc_device_buffer_from_slice(values)?;
+            tag_buffer(&buf, tag);
+            Ok::<_, Error>(buf)
+        };
+        let fixed_values = pk
+            .fixed_values
+            .iter()
+            .map(|x| upload(&x.values[..], "pk fixed values"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let fixed_polys = pk
+            .fixed_polys
+            .iter()
+            .map(|x| upload(&x.values[..], "pk fixed polys"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let permutation_polys = pk
+            .permutation
+            .polys
+            .iter()
+            .map(|x| upload(&x.values[..], "pk permutation polys"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [l0, l_last]: [CudaDeviceBufRaw; 2] =
+            extend_polys(&device, pk, [&pk.l0.values[..], &pk.l_last.values[..]])?
+                .try_into()
+                .unwrap();
+        let l_active_row = extended_l_active_row(&device, pk)?;
+
+        let lookup_tables = pk
+            .vk
+            .cs
+            .lookups
+            .iter()
+            .enumerate()
+            .filter(|(_, lookup)| lookup.table_expressions.len() == 1)
+            .filter_map(|(i, lookup)| Some((i, lookup.table_expressions[0].is_pure_fixed()?)))
+            .collect();
+
+        Ok(CudaProvingKey {
+            pk,
+            fixed_values,
+            fixed_polys: Arc::new(fixed_polys),
+            permutation_polys,
+            l0,
+            l_last,
+            l_active_row,
+            gates: analyze_expr_tree(&pk.ev.gpu_gates_expr[0], k),
+            lookup_tables,
+            device,
+        })
+    }
+
+    pub fn device_id(&self) -> usize {
+        self.device.device_id()
+    }
+
+    /// Whether its device was reset since the upload, which freed the key.
+    pub fn is_stale(&self) -> bool {
+        self.l0.is_stale()
+    }
+
+    /// The table of lookup `i` in lagrange form, if it is a single fixed column.
+    pub(crate) fn lookup_table(&self, i: usize) -> Option<&CudaDeviceBufRaw> {
+        self.lookup_tables
+            .get(&i)
+            .map(|column| &self.fixed_values[*column])
+    }
+}
@@ -1,11 +1,30 @@
 pub mod cuda;
+pub mod nvml;
+#[cfg(feature = "opencl")]
+pub mod opencl;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("{0}")]
     DeviceError(String),
+    #[error("{0}")]
+    OutOfMemory(String),
+    #[error("{0}")]
+    KernelError(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("msm result is not on the curve")]
     MsmError,
 }
 
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(e: std::sync::PoisonError<T>) -> Self {
+        Error::DeviceError(format!("device bookkeeping unusable: {}", e))
+    }
+}
+
 pub type DeviceResult<T> = Result<T, Error>;
 
 pub trait DeviceBuf {}
@@ -33,5 +52,12 @@ pub trait Device<B: DeviceBuf>: Sized {
     fn pin_memory<T>(&self, dst: &[T]) -> DeviceResult<()>;
     fn unpin_memory<T>(&self, dst: &[T]) -> DeviceResult<()>;
 
+    /// Pins `dst` through the pinned memory pool of the device. Pinned regions
+    /// are kept across proofs, pinning memory inside a pooled region again is free.
+    fn pin_memory_pooled<T>(&self, dst: &[T]) -> DeviceResult<()>;
+    /// Unpins every region of the pool, e.g. before shutting down or handing the
+    /// memory back to the system. Buffers pinned again are re-registered lazily.
+    fn release_pinned_memory(&self) -> DeviceResult<()>;
+
     fn print_memory_info(&self) -> DeviceResult<()>;
 }
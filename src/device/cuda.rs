@@ -1,16 +1,43 @@
+use core::cell::Cell;
 use core::cell::RefCell;
+use core::marker::PhantomData;
 use core::mem;
+use core::mem::ManuallyDrop;
+use core::ops::Deref;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::mem::size_of;
+use std::panic::Location;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Once;
+use std::sync::PoisonError;
+use std::time::Duration;
+use std::time::Instant;
 use std::{ffi::c_void, sync::Mutex};
 
 use cuda_runtime_sys::{cudaError, cudaStream_t};
 
 use super::{Device, DeviceBuf, Error};
+use crate::device::nvml::gpu_health;
 use crate::device::DeviceResult;
+use crate::scoped::FlagGuard;
+use crate::scoped::LimitGuard;
+use crate::scoped::ScopedFlag;
+use crate::scoped::ScopedLimit;
 
 thread_local! {
     static ACITVE_CUDA_DEVICE: RefCell<i32> = RefCell::new(-1);
+    static BUFFER_PHASE: RefCell<&'static str> = RefCell::new("none");
+    static LAST_CUDA_CALL: RefCell<&'static str> = RefCell::new("none");
+    static CAPTURING: Cell<bool> = Cell::new(false);
+}
+
+/// Whether this thread is recording work into a graph, see `CudaStream::capture`.
+pub(crate) fn capturing() -> bool {
+    CAPTURING.with(|x| x.get())
 }
 
 const HUGE_BUFFER_SIZE: usize = 1 << 30;
@@ -19,6 +46,501 @@ lazy_static! {
     pub static ref CUDA_BUFFER_CACHE: Mutex<HashMap::<(i32, usize), Vec<usize>>> =
         Mutex::new(HashMap::new());
     pub static ref HUGE_CUDA_BUFFER_CACHE: Mutex<Vec<usize>> = Mutex::new(vec![]);
+    // (device, size) -> (allocations served from CUDA_BUFFER_CACHE, allocations that missed it)
+    static ref CUDA_BUFFER_CACHE_COUNTS: Mutex<HashMap<(i32, usize), (u64, u64)>> =
+        Mutex::new(HashMap::new());
+    static ref LIVE_CUDA_BUFFERS: Mutex<HashMap::<usize, LiveBuffer>> = Mutex::new(HashMap::new());
+    static ref PRELOADED_CUDA_DEVICES: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+    // device -> (bytes obtained from cudaMalloc and not freed, cap)
+    static ref CUDA_MEMORY_USAGE: Mutex<HashMap<i32, (usize, Option<usize>)>> =
+        Mutex::new(HashMap::new());
+    // device -> memory caps of the proofs running on it, see `scope_memory_cap`
+    static ref PROOF_MEMORY_CAPS: Mutex<HashMap<i32, Vec<usize>>> = Mutex::new(HashMap::new());
+    // device -> reservations of the proofs running on it
+    static ref CUDA_MEMORY_RESERVATIONS: Mutex<HashMap<i32, Vec<ReservationEntry>>> =
+        Mutex::new(HashMap::new());
+    // (device, host address) -> region registered with cudaHostRegister
+    static ref PINNED_MEMORY_POOL: Mutex<BTreeMap<(i32, usize), PinnedRegion>> =
+        Mutex::new(BTreeMap::new());
+    // buffers from cudaMallocAsync, freed with cudaFreeAsync instead of being cached
+    static ref STREAM_ORDERED_BUFFERS: Mutex<HashSet<usize>> = Mutex::new(HashSet::new());
+    // device -> default memory pool, configured on first use
+    static ref CUDA_MEM_POOLS: Mutex<HashMap<i32, usize>> = Mutex::new(HashMap::new());
+    // device -> optional features, probed on first use
+    static ref DEVICE_CAPABILITIES: Mutex<HashMap<i32, DeviceCapabilities>> =
+        Mutex::new(HashMap::new());
+    // device -> (bytes of L2 set aside for persisting accesses, proofs persisting)
+    static ref PERSISTING_L2: Mutex<HashMap<i32, (usize, usize)>> = Mutex::new(HashMap::new());
+    // device -> resets so far, buffers of an earlier context aren't freed
+    static ref DEVICE_EPOCHS: Mutex<HashMap<i32, usize>> = Mutex::new(HashMap::new());
+}
+
+static STREAM_ORDERED_ALLOC: ScopedFlag = ScopedFlag::new(false);
+
+/// Allocate device buffers from the stream-ordered memory pool of the device
+/// (cudaMallocAsync) instead of `CUDA_BUFFER_CACHE`. The pool reuses freed
+/// memory without the fragmentation of per-size caches and without the device
+/// synchronization of cudaMalloc/cudaFree. Applies to buffers allocated from now
+/// on, buffers already out keep being returned to where they came from.
+pub fn set_stream_ordered_alloc(enabled: bool) {
+    STREAM_ORDERED_ALLOC.set(enabled);
+}
+
+pub fn stream_ordered_alloc() -> bool {
+    STREAM_ORDERED_ALLOC.get()
+}
+
+/// Stream-ordered allocation for the duration of one proof, see
+/// `CudaDevice::scope_stream_ordered_alloc`.
+pub(crate) struct StreamOrderedAllocGuard {
+    device: CudaDevice,
+    flag: Option<FlagGuard>,
+}
+
+impl Drop for StreamOrderedAllocGuard {
+    fn drop(&mut self) {
+        drop(self.flag.take());
+        // back on the buffer cache, give what the pool holds unused to the driver
+        if !stream_ordered_alloc() {
+            if let Err(e) = self.device.trim_buffer_cache(usize::MAX) {
+                tracing::warn!(
+                    device = self.device.device,
+                    error = ?e,
+                    "fail to trim the memory pool"
+                );
+            }
+        }
+    }
+}
+
+/// Synchronize the device after every checked CUDA call, kernel launches and
+/// async copies included, so a failing kernel is reported by the call that
+/// launched it along with its location in the source, instead of by a later
+/// unrelated call. Very slow, for debugging only. Also enabled by setting
+/// `ZKWASM_SYNC_DEBUG`.
+pub fn set_sync_debug(enabled: bool) {
+    sync_debug_flag().set(enabled);
+}
+
+pub fn sync_debug() -> bool {
+    sync_debug_flag().get()
+}
+
+/// Synchronizes after every call for the duration of one proof.
+pub(crate) fn scope_sync_debug() -> FlagGuard {
+    sync_debug_flag().enter()
+}
+
+static SYNC_DEBUG: ScopedFlag = ScopedFlag::new(false);
+static SYNC_DEBUG_FROM_ENV: Once = Once::new();
+
+// the base is read from `ZKWASM_SYNC_DEBUG` on first use
+fn sync_debug_flag() -> &'static ScopedFlag {
+    SYNC_DEBUG_FROM_ENV.call_once(|| {
+        SYNC_DEBUG.set(std::env::var_os("ZKWASM_SYNC_DEBUG").is_some_and(|x| x != "0"))
+    });
+    &SYNC_DEBUG
+}
+
+static L2_PERSISTENCE: ScopedFlag = ScopedFlag::new(false);
+
+/// Keep the msm bases in the persisting part of L2 on devices that have one
+/// (Ampere and later), so the bucket accumulation of repeated msm over the
+/// same bases reads them from L2 rather than device memory. The part set
+/// aside is kept for the process once a device used it, unlike the one of
+/// `ProverConfig::l2_persistence` which is given back when the proof ends.
+pub fn set_l2_persistence(enabled: bool) {
+    L2_PERSISTENCE.set(enabled);
+}
+
+pub fn l2_persistence() -> bool {
+    L2_PERSISTENCE.get()
+}
+
+/// L2 persistence for the duration of one proof, see
+/// `CudaDevice::scope_l2_persistence`.
+pub(crate) struct L2PersistenceGuard {
+    device: CudaDevice,
+    flag: Option<FlagGuard>,
+}
+
+impl Drop for L2PersistenceGuard {
+    fn drop(&mut self) {
+        drop(self.flag.take());
+        let mut limits = PERSISTING_L2.lock().unwrap();
+        let Some((limit, proofs)) = limits.get_mut(&self.device.device) else {
+            return;
+        };
+        *proofs -= 1;
+        // the last proof on the device gives the set aside part back, unless
+        // the process keeps it
+        if *proofs > 0 || L2_PERSISTENCE.base() || *limit == 0 {
+            return;
+        }
+        *limit = 0;
+        if let Err(e) = self.device.release_persisting_l2() {
+            tracing::warn!(
+                device = self.device.device,
+                error = ?e,
+                "fail to give back persisting L2"
+            );
+        }
+    }
+}
+
+// in milliseconds
+static SYNC_TIMEOUT: ScopedLimit = ScopedLimit::new();
+
+fn timeout_ms(timeout: Duration) -> usize {
+    (timeout.as_millis() as usize).max(1)
+}
+
+/// Bound the time `synchronize` of devices, streams and events waits for the
+/// device. A device not done by then is considered hung: the last CUDA call of
+/// the waiting thread and the live buffers are logged, the device is marked
+/// unhealthy and the wait fails with `Error::Timeout`. The device is reset
+/// once the failed proof has returned and dropped its buffers, see
+/// `reset_failed_devices`. `None` waits forever.
+pub fn set_sync_timeout(timeout: Option<Duration>) {
+    SYNC_TIMEOUT.set(timeout.map(timeout_ms));
+}
+
+/// The timeout in effect: the shortest of the one set with `set_sync_timeout`
+/// and those of the running proofs.
+pub fn sync_timeout() -> Option<Duration> {
+    SYNC_TIMEOUT
+        .get()
+        .map(|ms| Duration::from_millis(ms as u64))
+}
+
+/// Bounds the waits to `timeout` for the duration of one proof.
+pub(crate) fn scope_sync_timeout(timeout: Duration) -> LimitGuard {
+    SYNC_TIMEOUT.enter(timeout_ms(timeout))
+}
+
+static NEXT_RESERVATION_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+struct ReservationEntry {
+    id: usize,
+    granted: usize,
+    minimum: usize,
+    preferred: usize,
+}
+
+/// Share of a device's memory held by one proof, see `CudaDevice::negotiate_reservation`.
+/// The share is returned to the device when dropped.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    device: CudaDevice,
+    id: usize,
+}
+
+impl MemoryReservation {
+    /// Bytes currently granted, lowered when a later proof needs part of them.
+    pub fn granted(&self) -> usize {
+        CUDA_MEMORY_RESERVATIONS
+            .lock()
+            .unwrap()
+            .get(&self.device.device)
+            .and_then(|x| x.iter().find(|x| x.id == self.id))
+            .map_or(0, |x| x.granted)
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        let mut reservations = CUDA_MEMORY_RESERVATIONS.lock().unwrap();
+        let entries = reservations.entry(self.device.device).or_default();
+        let mut freed = entries
+            .iter()
+            .find(|x| x.id == self.id)
+            .map_or(0, |x| x.granted);
+        entries.retain(|x| x.id != self.id);
+        // hand the freed memory back to the proofs that were shrunk for us
+        for entry in entries.iter_mut() {
+            let give = freed.min(entry.preferred - entry.granted);
+            entry.granted += give;
+            freed -= give;
+        }
+        let cap = if entries.is_empty() {
+            None
+        } else {
+            Some(entries.iter().map(|x| x.granted).sum())
+        };
+        self.device.set_memory_cap(cap);
+    }
+}
+
+/// Memory cap of one proof on a device, see `CudaDevice::scope_memory_cap`.
+pub(crate) struct MemoryCapGuard {
+    device: i32,
+    cap: usize,
+}
+
+impl Drop for MemoryCapGuard {
+    fn drop(&mut self) {
+        if let Some(caps) = PROOF_MEMORY_CAPS.lock().unwrap().get_mut(&self.device) {
+            if let Some(i) = caps.iter().position(|x| *x == self.cap) {
+                caps.swap_remove(i);
+            }
+        }
+    }
+}
+
+/// Host region registered with cudaHostRegister, unregistered when dropped.
+#[derive(Debug)]
+struct PinnedRegion {
+    device: CudaDevice,
+    ptr: usize,
+    size: usize,
+}
+
+impl Drop for PinnedRegion {
+    fn drop(&mut self) {
+        let res = self.device.acitve_ctx().and_then(|_| unsafe {
+            let res = cuda_runtime_sys::cudaHostUnregister(self.ptr as *mut _);
+            to_result((), res, "fail to unpin memory")
+        });
+        if let Err(e) = res {
+            tracing::warn!(
+                device = self.device.device,
+                size = self.size,
+                error = %e,
+                "unpin failed"
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LiveBuffer {
+    pub device: usize,
+    pub size: usize,
+    pub tag: String,
+}
+
+/// Set the phase recorded in the tag of buffers allocated by this thread (debug builds only).
+pub fn set_buffer_phase(phase: &'static str) {
+    BUFFER_PHASE.with(|x| *x.borrow_mut() = phase);
+}
+
+/// The phase set by `set_buffer_phase` on this thread.
+pub(crate) fn buffer_phase() -> &'static str {
+    BUFFER_PHASE.with(|x| *x.borrow())
+}
+
+/// Priority of the streams the prover creates, relative to other work on the same device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamPriority {
+    /// Lowest priority, e.g. prefetching the witness of the next proof or warming caches.
+    Background,
+    /// Midpoint of the range supported by the device.
+    Normal,
+    /// Highest priority, kernels queued on it preempt the other streams at block boundaries.
+    Critical,
+}
+
+impl StreamPriority {
+    // the lowest rank held wins in `STREAM_PRIORITY`
+    fn rank(self) -> usize {
+        match self {
+            StreamPriority::Critical => 1,
+            StreamPriority::Normal => 2,
+            StreamPriority::Background => 3,
+        }
+    }
+}
+
+static STREAM_PRIORITY: ScopedLimit = ScopedLimit::new();
+
+/// Set the priority of streams created through `CudaDevice::create_stream`
+/// on any thread, `Normal` if never set. Proofs apply
+/// `ProverConfig::stream_priority` on top of it for their duration.
+pub fn set_stream_priority(priority: StreamPriority) {
+    STREAM_PRIORITY.set(Some(priority.rank()));
+}
+
+/// Applies `priority` to the streams created until the guard is dropped,
+/// including those of worker threads. Streams created while several proofs
+/// run get the highest priority any of them holds.
+pub(crate) fn scope_stream_priority(priority: StreamPriority) -> LimitGuard {
+    STREAM_PRIORITY.enter(priority.rank())
+}
+
+pub fn stream_priority() -> StreamPriority {
+    match STREAM_PRIORITY.get() {
+        Some(1) => StreamPriority::Critical,
+        Some(3) => StreamPriority::Background,
+        _ => StreamPriority::Normal,
+    }
+}
+
+/// Largest number of bytes currently obtained from cudaMalloc on one device.
+pub(crate) fn max_allocated_memory() -> usize {
+    CUDA_MEMORY_USAGE
+        .lock()
+        .unwrap()
+        .values()
+        .map(|x| x.0)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Buffers of one size parked for reuse on one device, see `buffer_cache_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BufferCacheStats {
+    pub device_id: usize,
+    /// Bytes of each buffer.
+    pub size: usize,
+    pub cached_buffers: usize,
+    pub cached_bytes: usize,
+    /// Allocations of this size served from the cache.
+    pub hits: u64,
+    /// Allocations of this size that went to cudaMalloc.
+    pub misses: u64,
+}
+
+fn count_buffer_cache(device: i32, size: usize, hit: bool) {
+    crate::metrics::count_buffer_cache(hit);
+    let mut counts = CUDA_BUFFER_CACHE_COUNTS.lock().unwrap();
+    let (hits, misses) = counts.entry((device, size)).or_insert((0, 0));
+    if hit {
+        *hits += 1;
+    } else {
+        *misses += 1;
+    }
+}
+
+/// What `CUDA_BUFFER_CACHE` holds and how often it served allocations, by
+/// device and buffer size, sorted by both. Sizes allocated since the process
+/// started are listed even when nothing of them is parked.
+pub fn buffer_cache_stats() -> Vec<BufferCacheStats> {
+    let mut stats = BTreeMap::<(i32, usize), BufferCacheStats>::new();
+    for ((device, size), ptrs) in CUDA_BUFFER_CACHE.lock().unwrap().iter() {
+        let entry = stats.entry((*device, *size)).or_default();
+        entry.cached_buffers = ptrs.len();
+        entry.cached_bytes = ptrs.len() * size;
+    }
+    for ((device, size), (hits, misses)) in CUDA_BUFFER_CACHE_COUNTS.lock().unwrap().iter() {
+        let entry = stats.entry((*device, *size)).or_default();
+        entry.hits = *hits;
+        entry.misses = *misses;
+    }
+    stats
+        .into_iter()
+        .map(|((device, size), entry)| BufferCacheStats {
+            device_id: device as usize,
+            size,
+            ..entry
+        })
+        .collect()
+}
+
+/// Frees buffers parked in `CUDA_BUFFER_CACHE` for `device_id`, largest
+/// first, until at most `target_bytes` stay cached, returning the bytes given
+/// back to the driver. Buffers in use are not affected.
+pub fn trim_device_cache(device_id: usize, target_bytes: usize) -> DeviceResult<usize> {
+    let device = CudaDevice::get_device(device_id)?;
+    let mut cache = CUDA_BUFFER_CACHE.lock().unwrap();
+    let mut sizes = cache
+        .iter()
+        .filter(|((id, _), _)| *id == device_id as i32)
+        .map(|((_, size), ptrs)| (*size, ptrs.len()))
+        .collect::<Vec<_>>();
+    sizes.sort_by(|a, b| b.0.cmp(&a.0));
+    let mut cached = sizes
+        .iter()
+        .map(|(size, count)| size * count)
+        .sum::<usize>();
+
+    device.acitve_ctx()?;
+    let mut freed = 0;
+    for (size, _) in sizes {
+        let ptrs = cache.get_mut(&(device_id as i32, size)).unwrap();
+        while cached > target_bytes {
+            let Some(ptr) = ptrs.pop() else {
+                break;
+            };
+            let res = unsafe { cuda_runtime_sys::cudaFree(ptr as *mut c_void) };
+            to_result((), res, "fail to free device memory")?;
+            device.release_memory(size);
+            cached -= size;
+            freed += size;
+        }
+    }
+    tracing::debug!(device_id, freed, cached, "buffer cache trimmed");
+    Ok(freed)
+}
+
+/// Bytes currently obtained from cudaMalloc, by device.
+pub(crate) fn allocated_memory_by_device() -> Vec<(i32, usize)> {
+    CUDA_MEMORY_USAGE
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(device, x)| (*device, x.0))
+        .collect()
+}
+
+/// Replace the purpose part of `buf`'s tag (debug builds only).
+pub fn tag_buffer(buf: &CudaDeviceBufRaw, purpose: &str) {
+    if cfg!(debug_assertions) {
+        let phase = BUFFER_PHASE.with(|x| *x.borrow());
+        let mut live = LIVE_CUDA_BUFFERS.lock().unwrap();
+        if let Some(entry) = live.get_mut(&(buf.ptr as usize)) {
+            entry.tag = format!("{}/{}", phase, purpose);
+        }
+    }
+}
+
+/// Buffers handed out by the allocator and not yet dropped, largest first.
+/// Always empty in release builds.
+pub fn report_live_buffers() -> Vec<LiveBuffer> {
+    let mut res = LIVE_CUDA_BUFFERS
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    res.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.tag.cmp(&b.tag)));
+
+    let mut summary = HashMap::<(usize, &str), (usize, usize)>::new();
+    for buf in res.iter() {
+        let entry = summary.entry((buf.device, &buf.tag[..])).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += buf.size;
+    }
+    for ((device, tag), (count, bytes)) in summary {
+        tracing::info!(device, tag, count, bytes, "live device buffer");
+    }
+
+    res
+}
+
+#[track_caller]
+fn track_live_buffer(buf: &CudaDeviceBufRaw) {
+    if cfg!(debug_assertions) {
+        let phase = BUFFER_PHASE.with(|x| *x.borrow());
+        let location = core::panic::Location::caller();
+        LIVE_CUDA_BUFFERS.lock().unwrap().insert(
+            buf.ptr as usize,
+            LiveBuffer {
+                device: buf.device.device as usize,
+                size: buf.size,
+                tag: format!("{}/{}:{}", phase, location.file(), location.line()),
+            },
+        );
+    }
+}
+
+fn untrack_live_buffer(buf: &CudaDeviceBufRaw) {
+    if cfg!(debug_assertions) {
+        LIVE_CUDA_BUFFERS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(buf.ptr as usize));
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -51,17 +573,918 @@ impl CudaDevice {
             to_result((), res, "fail to set device")
         }
     }
+
+    pub fn device_id(&self) -> usize {
+        self.device as usize
+    }
+
+    /// Resets of this device so far. Buffers allocated in an earlier epoch are
+    /// dropped without being freed, they would release memory of the new context.
+    pub(crate) fn epoch(&self) -> usize {
+        DEVICE_EPOCHS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&self.device)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Destroys the context of this device after an error that left it
+    /// unusable. The buffers parked for reuse on it died with the context and
+    /// are forgotten; buffers still alive are dropped without being freed nor
+    /// counted, but the caches holding them should be cleared first, see
+    /// `invalidate_device`.
+    pub fn reset(&self) -> DeviceResult<()> {
+        // recovering from a failed proof, a panic of its workers mustn't keep
+        // the device from being reset
+        *DEVICE_EPOCHS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(self.device)
+            .or_insert(0) += 1;
+        CUDA_BUFFER_CACHE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(device, _), _| *device != self.device);
+        CUDA_MEM_POOLS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.device);
+        PRELOADED_CUDA_DEVICES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.device);
+        // the limit dies with the context
+        if let Some((limit, _)) = PERSISTING_L2
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&self.device)
+        {
+            *limit = 0;
+        }
+        if let Some(usage) = CUDA_MEMORY_USAGE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(&self.device)
+        {
+            usage.0 = 0;
+        }
+        DEVICE_MANAGER
+            .failed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(self.device as usize));
+        self.acitve_ctx()?;
+        let res = unsafe { cuda_runtime_sys::cudaDeviceReset() };
+        to_result((), res, "fail to reset device")
+    }
+
+    /// Marks the work issued on `stream` so far, the null stream for the legacy default stream.
+    pub(crate) fn record_event(&self, stream: cudaStream_t) -> DeviceResult<CudaEvent> {
+        self.acitve_ctx()?;
+        unsafe {
+            let mut event = mem::zeroed();
+            let res = cuda_runtime_sys::cudaEventCreateWithFlags(
+                &mut event,
+                cuda_runtime_sys::cudaEventDisableTiming,
+            );
+            to_result((), res, "fail to create event")?;
+            let event = CudaEvent {
+                device: self.clone(),
+                event,
+            };
+            let res = cuda_runtime_sys::cudaEventRecord(event.event, stream);
+            to_result(event, res, "fail to record event")
+        }
+    }
+
+    /// Work issued on `stream` from now on, the null stream for the legacy
+    /// default stream, waits for `event`, the host doesn't.
+    pub(crate) fn wait_event(&self, stream: cudaStream_t, event: &CudaEvent) -> DeviceResult<()> {
+        self.acitve_ctx()?;
+        unsafe {
+            let res = cuda_runtime_sys::cudaStreamWaitEvent(stream, event.event, 0);
+            to_result((), res, "fail to wait event")
+        }
+    }
+
+    /// Waits for the work `query` reports on with `sync`, or by polling `query`
+    /// when a sync timeout is set, see `set_sync_timeout`.
+    fn wait_for(
+        &self,
+        query: impl Fn() -> cudaError,
+        sync: impl FnOnce() -> cudaError,
+        msg: &'static str,
+    ) -> DeviceResult<()> {
+        let Some(timeout) = sync_timeout() else {
+            return to_result((), sync(), msg);
+        };
+        let last_call = LAST_CUDA_CALL.with(|x| *x.borrow());
+        let start = Instant::now();
+        let mut backoff = Duration::from_micros(10);
+        loop {
+            match query() {
+                cudaError::cudaErrorNotReady => {}
+                res => return to_result((), res, msg),
+            }
+            if start.elapsed() >= timeout {
+                break;
+            }
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(Duration::from_millis(1));
+        }
+
+        tracing::error!(
+            device = self.device,
+            timeout_ms = timeout.as_millis() as u64,
+            last_call,
+            allocated = self.allocated_memory(),
+            "device hung: {}",
+            msg
+        );
+        report_live_buffers();
+        // resetting here would free the buffers of the proof and of the caches
+        // under them, it is deferred until the proof returned
+        DeviceManager::global().mark_failed(self.device_id());
+        Err(Error::Timeout(format!(
+            "device {} not done after {:?}, last call: {}: {}",
+            self.device, timeout, last_call, msg
+        )))
+    }
+
+    /// Limit the device memory the allocator obtains from cudaMalloc on this device.
+    /// Buffers parked in the reuse caches keep counting against the cap.
+    /// The caps of running proofs, `scope_memory_cap`, apply on top of it.
+    pub fn set_memory_cap(&self, cap: Option<usize>) {
+        CUDA_MEMORY_USAGE
+            .lock()
+            .unwrap()
+            .entry(self.device)
+            .or_insert((0, None))
+            .1 = cap;
+    }
+
+    /// Bytes currently obtained from cudaMalloc by the allocator on this device.
+    pub fn allocated_memory(&self) -> usize {
+        CUDA_MEMORY_USAGE
+            .lock()
+            .unwrap()
+            .get(&self.device)
+            .map_or(0, |x| x.0)
+    }
+
+    /// Lowers the memory cap of this device to `cap` until the guard is dropped.
+    /// Concurrent proofs on the device are held to the lowest of their caps and
+    /// the one set with `set_memory_cap`.
+    pub(crate) fn scope_memory_cap(&self, cap: usize) -> MemoryCapGuard {
+        PROOF_MEMORY_CAPS
+            .lock()
+            .unwrap()
+            .entry(self.device)
+            .or_default()
+            .push(cap);
+        MemoryCapGuard {
+            device: self.device,
+            cap,
+        }
+    }
+
+    // the lowest of `cap` and the caps of the running proofs
+    fn effective_memory_cap(&self, cap: Option<usize>) -> Option<usize> {
+        let proofs = PROOF_MEMORY_CAPS
+            .lock()
+            .unwrap()
+            .get(&self.device)
+            .and_then(|caps| caps.iter().copied().min());
+        match (cap, proofs) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn reserve_memory(&self, size: usize) -> DeviceResult<()> {
+        let mut usage = CUDA_MEMORY_USAGE.lock()?;
+        let (allocated, cap) = usage.entry(self.device).or_insert((0, None));
+        if let Some(cap) = self.effective_memory_cap(*cap) {
+            if *allocated + size > *cap {
+                return Err(Error::OutOfMemory(format!(
+                    "Cuda Error(): device {} memory cap {} exceeded, {} in use, {} requested",
+                    self.device, cap, allocated, size
+                )));
+            }
+        }
+        *allocated += size;
+        crate::metrics::record_device_memory(*allocated);
+        Ok(())
+    }
+
+    /// Reserves between `minimum` and `preferred` bytes of this device for a proof.
+    ///
+    /// If the memory not held by other reservations falls short of `preferred`,
+    /// the running proofs give up what they hold above their own minimum and the
+    /// buffer cache is trimmed to match, so that the proofs don't both allocate
+    /// optimistically and one of them fail mid-phase. Fails without changing any
+    /// reservation if even `minimum` can't be met. While reservations exist, the
+    /// memory cap of the device is the sum of their grants.
+    pub fn negotiate_reservation(
+        &self,
+        preferred: usize,
+        minimum: usize,
+    ) -> DeviceResult<MemoryReservation> {
+        let preferred = preferred.max(minimum);
+        let (_, total) = self.get_memory_info()?;
+        let mut reservations = CUDA_MEMORY_RESERVATIONS.lock().unwrap();
+        let entries = reservations.entry(self.device).or_default();
+
+        let held = entries.iter().map(|x| x.granted).sum::<usize>();
+        let available = total.saturating_sub(held);
+        let reclaimable = entries.iter().map(|x| x.granted - x.minimum).sum::<usize>();
+        if available + reclaimable < minimum {
+            return Err(Error::DeviceError(format!(
+                "Cuda Error(): device {} can't reserve {} bytes, {} available and {} reclaimable",
+                self.device, minimum, available, reclaimable
+            )));
+        }
+
+        let granted = preferred.min(available + reclaimable);
+        let mut shortfall = granted.saturating_sub(available);
+        // shrink the largest surplus first
+        entries.sort_by_key(|x| std::cmp::Reverse(x.granted - x.minimum));
+        for entry in entries.iter_mut() {
+            let give = shortfall.min(entry.granted - entry.minimum);
+            entry.granted -= give;
+            shortfall -= give;
+        }
+
+        let id = NEXT_RESERVATION_ID.fetch_add(1, Ordering::Relaxed);
+        entries.push(ReservationEntry {
+            id,
+            granted,
+            minimum,
+            preferred,
+        });
+        let cap = entries.iter().map(|x| x.granted).sum::<usize>();
+        drop(reservations);
+
+        self.set_memory_cap(Some(cap));
+        self.trim_buffer_cache(cap)?;
+        tracing::debug!(device = self.device, granted, cap, "memory reservation");
+
+        Ok(MemoryReservation {
+            device: self.clone(),
+            id,
+        })
+    }
+
+    /// Frees buffers parked in the reuse cache of this device until at most
+    /// `keep` bytes are obtained from cudaMalloc, or the cache is empty. The
+    /// memory the stream-ordered pool holds unused is released to the driver too.
+    pub fn trim_buffer_cache(&self, keep: usize) -> DeviceResult<()> {
+        let mut cache = CUDA_BUFFER_CACHE.lock().unwrap();
+        self.acitve_ctx()?;
+        for ((device, size), ptrs) in cache.iter_mut() {
+            if *device != self.device {
+                continue;
+            }
+            while self.allocated_memory() > keep {
+                match ptrs.pop() {
+                    Some(ptr) => unsafe {
+                        let res = cuda_runtime_sys::cudaFree(ptr as *mut c_void);
+                        to_result((), res, "fail to free device memory")?;
+                        self.release_memory(*size);
+                    },
+                    None => break,
+                }
+            }
+        }
+        drop(cache);
+
+        let pool = CUDA_MEM_POOLS.lock().unwrap().get(&self.device).copied();
+        if let Some(pool) = pool {
+            unsafe {
+                cuda_runtime_sys::cudaDeviceSynchronize();
+                let res = cudaMemPoolTrimTo(pool as cudaMemPool_t, 0);
+                to_result((), res, "fail to trim the memory pool")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates from the stream-ordered pool until the guard is dropped, see
+    /// `set_stream_ordered_alloc`. Switching over frees the buffers parked in
+    /// the reuse cache of this device, which the pool would otherwise compete
+    /// with for memory; switching back trims the pool.
+    /// Keeps the msm bases in L2 until the guard is dropped, see
+    /// `set_l2_persistence`. When the last proof persisting on this device
+    /// ends, the persisting lines are reset to normal and the part of L2 set
+    /// aside is given back to the other work on the device.
+    pub(crate) fn scope_l2_persistence(&self) -> L2PersistenceGuard {
+        PERSISTING_L2
+            .lock()
+            .unwrap()
+            .entry(self.device)
+            .or_insert((0, 0))
+            .1 += 1;
+        L2PersistenceGuard {
+            device: self.clone(),
+            flag: Some(L2_PERSISTENCE.enter()),
+        }
+    }
+
+    // the access policy windows are on the streams of the msm, gone by now
+    fn release_persisting_l2(&self) -> DeviceResult<()> {
+        self.acitve_ctx()?;
+        let res = unsafe { cudaCtxResetPersistingL2Cache() };
+        to_result((), res, "fail to reset persisting L2 lines")?;
+        let res = unsafe { cudaDeviceSetLimitRaw(CUDA_LIMIT_PERSISTING_L2_CACHE_SIZE, 0) };
+        to_result((), res, "fail to give back persisting L2")
+    }
+
+    pub(crate) fn scope_stream_ordered_alloc(&self) -> DeviceResult<StreamOrderedAllocGuard> {
+        let switched = !stream_ordered_alloc();
+        let guard = StreamOrderedAllocGuard {
+            device: self.clone(),
+            flag: Some(STREAM_ORDERED_ALLOC.enter()),
+        };
+        if switched {
+            self.trim_buffer_cache(0)?;
+        }
+        Ok(guard)
+    }
+
+    /// Bytes this device can still hand out: free memory as seen by the driver,
+    /// further bounded by the memory cap.
+    pub fn available_memory(&self) -> DeviceResult<usize> {
+        let (free, _) = self.get_memory_info()?;
+        let usage = CUDA_MEMORY_USAGE.lock().unwrap();
+        let (allocated, cap) = usage.get(&self.device).copied().unwrap_or((0, None));
+        Ok(match self.effective_memory_cap(cap) {
+            Some(cap) => free.min(cap.saturating_sub(allocated)),
+            None => free,
+        })
+    }
+
+    fn release_memory(&self, size: usize) {
+        let mut usage = CUDA_MEMORY_USAGE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some((allocated, _)) = usage.get_mut(&self.device) {
+            *allocated = allocated.saturating_sub(size);
+        }
+    }
+
+    /// Bytes of host memory held pinned by the pinned memory pool of this device.
+    pub fn pinned_memory(&self) -> usize {
+        PINNED_MEMORY_POOL
+            .lock()
+            .unwrap()
+            .range((self.device, 0)..=(self.device, usize::MAX))
+            .map(|(_, region)| region.size)
+            .sum()
+    }
+
+    /// Whether `data` lies in a region of the pinned memory pool of this device.
+    pub(crate) fn is_pinned<T>(&self, data: &[T]) -> bool {
+        let ptr = data.as_ptr() as usize;
+        PINNED_MEMORY_POOL
+            .lock()
+            .unwrap()
+            .range((self.device, 0)..=(self.device, ptr))
+            .next_back()
+            .map_or(false, |(&(_, start), region)| {
+                ptr + data.len() * size_of::<T>() <= start + region.size
+            })
+    }
+
+    /// Load the module of every prover kernel on this device, so that lazy
+    /// module loading doesn't land in the first timed launch. Runs once per device.
+    pub fn preload_kernels(&self) -> DeviceResult<()> {
+        let mut preloaded = PRELOADED_CUDA_DEVICES.lock().unwrap();
+        if preloaded.contains(&self.device) {
+            return Ok(());
+        }
+
+        self.acitve_ctx()?;
+        unsafe {
+            let res = crate::cuda::bn254_c::preload_kernels();
+            to_result((), res, "fail to preload kernels")?;
+        }
+        preloaded.insert(self.device);
+        Ok(())
+    }
+
+    pub fn get_memory_info(&self) -> DeviceResult<(usize, usize)> {
+        self.acitve_ctx()?;
+        unsafe {
+            let mut free = 0;
+            let mut total = 0;
+            let res = cuda_runtime_sys::cudaMemGetInfo(&mut free, &mut total);
+            to_result((free, total), res, "fail to get memory info")
+        }
+    }
+
+    pub fn enable_peer_access(&self, peer: &CudaDevice) -> DeviceResult<()> {
+        self.acitve_ctx()?;
+        unsafe {
+            let mut can_access = 0;
+            let res = cuda_runtime_sys::cudaDeviceCanAccessPeer(
+                &mut can_access,
+                self.device,
+                peer.device,
+            );
+            to_result((), res, "fail to query peer access")?;
+            if can_access == 0 {
+                return Err(Error::DeviceError(format!(
+                    "Cuda Error(): device {} can't access peer device {}",
+                    self.device, peer.device
+                )));
+            }
+
+            let res = cuda_runtime_sys::cudaDeviceEnablePeerAccess(peer.device, 0);
+            if res == cudaError::cudaErrorPeerAccessAlreadyEnabled {
+                // clear the sticky last error
+                cuda_runtime_sys::cudaGetLastError();
+                return Ok(());
+            }
+            to_result((), res, "fail to enable peer access")
+        }
+    }
+
+    /// Optional features of the device and its driver, probed on the first call.
+    pub fn capabilities(&self) -> DeviceResult<DeviceCapabilities> {
+        if let Some(capabilities) = DEVICE_CAPABILITIES.lock().unwrap().get(&self.device) {
+            return Ok(capabilities.clone());
+        }
+
+        let capabilities = self.probe_capabilities()?;
+        tracing::debug!("capabilities of device {}: {:?}", self.device, capabilities);
+        Ok(DEVICE_CAPABILITIES
+            .lock()
+            .unwrap()
+            .entry(self.device)
+            .or_insert(capabilities)
+            .clone())
+    }
+
+    fn probe_capabilities(&self) -> DeviceResult<DeviceCapabilities> {
+        self.acitve_ctx()?;
+        // older drivers reject attributes they don't know, that means unsupported
+        let value = |attr| unsafe {
+            let mut value = 0;
+            let res = cudaDeviceGetAttributeRaw(&mut value, attr, self.device);
+            if res != cudaError::cudaSuccess {
+                cuda_runtime_sys::cudaGetLastError();
+                return 0;
+            }
+            value
+        };
+        let attribute = |attr| value(attr) != 0;
+
+        let mut driver_version = 0;
+        let mut runtime_version = 0;
+        unsafe {
+            let res = cuda_runtime_sys::cudaDriverGetVersion(&mut driver_version);
+            to_result((), res, "fail to get driver version")?;
+            let res = cuda_runtime_sys::cudaRuntimeGetVersion(&mut runtime_version);
+            to_result((), res, "fail to get runtime version")?;
+        }
+
+        let mut peers = vec![];
+        for peer in 0..Self::get_device_count()? as i32 {
+            if peer == self.device {
+                continue;
+            }
+            let mut can_access = 0;
+            let res = unsafe {
+                cuda_runtime_sys::cudaDeviceCanAccessPeer(&mut can_access, self.device, peer)
+            };
+            to_result((), res, "fail to query peer access")?;
+            if can_access != 0 {
+                peers.push(peer as usize);
+            }
+        }
+
+        Ok(DeviceCapabilities {
+            device_id: self.device as usize,
+            driver_version,
+            runtime_version,
+            compute_capability: (value(CUDA_DEV_ATTR_COMPUTE_CAPABILITY_MAJOR) * 10
+                + value(CUDA_DEV_ATTR_COMPUTE_CAPABILITY_MINOR))
+                as u32,
+            multiprocessors: value(CUDA_DEV_ATTR_MULTIPROCESSOR_COUNT) as usize,
+            // the pool api is missing from the driver before 11.2 whatever the attribute says
+            memory_pools: driver_version >= 11020
+                && attribute(CUDA_DEV_ATTR_MEMORY_POOLS_SUPPORTED),
+            cooperative_launch: attribute(CUDA_DEV_ATTR_COOPERATIVE_LAUNCH),
+            max_persisting_l2: value(CUDA_DEV_ATTR_MAX_PERSISTING_L2_CACHE_SIZE) as usize,
+            max_access_policy_window: value(CUDA_DEV_ATTR_MAX_ACCESS_POLICY_WINDOW_SIZE) as usize,
+            managed_memory: attribute(CUDA_DEV_ATTR_MANAGED_MEMORY),
+            peers,
+            downgraded: vec![],
+        })
+    }
+
+    /// Fails with a `DeviceError` naming the architectures to build for when the
+    /// kernel library has no code this device can run, instead of the launch
+    /// failing later with cudaErrorNoKernelImageForDevice.
+    pub fn check_kernel_arch(&self) -> DeviceResult<()> {
+        let cc = self.capabilities()?.compute_capability;
+        let archs = kernel_archs();
+        if runs_kernels_for(cc, &archs) {
+            return Ok(());
+        }
+        Err(Error::DeviceError(format!(
+            "device {} has compute capability {}.{} but the kernels are built for {}, \
+             rebuild with CUDA_ARCH={}",
+            self.device,
+            cc / 10,
+            cc % 10,
+            archs
+                .iter()
+                .map(|arch| format!("sm_{}", arch))
+                .collect::<Vec<_>>()
+                .join(", "),
+            cc
+        )))
+    }
+
+    /// Records that `feature` was turned off on this device for lack of support,
+    /// warning the first time.
+    pub(crate) fn downgrade(&self, feature: &'static str) {
+        let mut capabilities = DEVICE_CAPABILITIES.lock().unwrap();
+        let downgraded = &mut capabilities
+            .entry(self.device)
+            .or_insert_with(|| DeviceCapabilities {
+                device_id: self.device as usize,
+                ..Default::default()
+            })
+            .downgraded;
+        if !downgraded.contains(&feature) {
+            tracing::warn!(
+                "{} is not supported by device {} or its driver, turned off",
+                feature,
+                self.device
+            );
+            downgraded.push(feature);
+        }
+    }
+
+    /// Marks the `bytes` from `ptr` as persisting in L2 for the launches on
+    /// `stream`, when `set_l2_persistence` is on. Ranges above the largest
+    /// window are hit in part, devices without persisting L2 are downgraded.
+    pub(crate) fn persist_in_l2(
+        &self,
+        stream: cudaStream_t,
+        ptr: *const c_void,
+        bytes: usize,
+    ) -> DeviceResult<()> {
+        if !l2_persistence() || bytes == 0 {
+            return Ok(());
+        }
+        let capabilities = self.capabilities()?;
+        if capabilities.max_persisting_l2 == 0 || capabilities.max_access_policy_window == 0 {
+            self.downgrade("l2_persistence");
+            return Ok(());
+        }
+        let window = bytes.min(capabilities.max_access_policy_window);
+        let persisting = window.min(capabilities.max_persisting_l2);
+
+        self.acitve_ctx()?;
+        let mut limits = PERSISTING_L2.lock().unwrap();
+        let (limit, _) = limits.entry(self.device).or_insert((0, 0));
+        if *limit < persisting {
+            let res =
+                unsafe { cudaDeviceSetLimitRaw(CUDA_LIMIT_PERSISTING_L2_CACHE_SIZE, persisting) };
+            to_result((), res, "fail to set aside persisting L2")?;
+            *limit = persisting;
+        }
+        let limit = *limit;
+        drop(limits);
+
+        let value = CudaStreamAttrValue {
+            access_policy_window: CudaAccessPolicyWindow {
+                base_ptr: ptr as *mut c_void,
+                num_bytes: window,
+                // hit lines beyond the set aside part would evict each other
+                hit_ratio: (limit as f32 / window as f32).min(1.0),
+                hit_prop: CUDA_ACCESS_PROPERTY_PERSISTING,
+                miss_prop: CUDA_ACCESS_PROPERTY_STREAMING,
+            },
+        };
+        let res = unsafe {
+            cudaStreamSetAttribute(stream, CUDA_STREAM_ATTRIBUTE_ACCESS_POLICY_WINDOW, &value)
+        };
+        to_result((), res, "fail to set the access policy window")
+    }
+
+    fn memory_pools_supported(&self) -> bool {
+        let supported = self.capabilities().map_or(false, |x| x.memory_pools);
+        if !supported {
+            self.downgrade("stream_ordered_alloc");
+        }
+        supported
+    }
+}
+
+/// Optional features of a device, see `CudaDevice::capabilities`. Features of
+/// the prover needing one the device lacks are turned off instead of failing
+/// with cudaErrorNotSupported, `downgraded` lists those turned off so far.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceCapabilities {
+    pub device_id: usize,
+    /// As reported by cudaDriverGetVersion, e.g. 12020 for 12.2.
+    pub driver_version: i32,
+    pub runtime_version: i32,
+    /// Major and minor version as one number, e.g. 89 for 8.9.
+    pub compute_capability: u32,
+    pub multiprocessors: usize,
+    /// Stream-ordered allocation (cudaMallocAsync), needed by `set_stream_ordered_alloc`.
+    pub memory_pools: bool,
+    pub cooperative_launch: bool,
+    /// Bytes of L2 that can be set aside for persisting accesses, 0 before Ampere.
+    pub max_persisting_l2: usize,
+    /// Largest range of one access policy window, needed by `set_l2_persistence`.
+    pub max_access_policy_window: usize,
+    pub managed_memory: bool,
+    /// Devices whose memory this one can access, needed by the multi-device extended fft.
+    pub peers: Vec<usize>,
+    pub downgraded: Vec<&'static str>,
+}
+
+/// Compute capabilities the kernel library embeds machine code for, the
+/// newest also as PTX, as set by `CUDA_ARCH` at build time.
+pub fn kernel_archs() -> Vec<u32> {
+    env!("ZKWASM_CUDA_ARCHS")
+        .split(',')
+        .map(|arch| arch.parse().unwrap())
+        .collect()
+}
+
+/// Whether a device of compute capability `cc` runs kernels built for
+/// `archs`: machine code for `sm_XY` runs on `X.Y` and later minor versions
+/// of `X`, the PTX of the newest arch is compiled by the driver for any later
+/// device.
+pub fn runs_kernels_for(cc: u32, archs: &[u32]) -> bool {
+    archs.iter().any(|arch| arch / 10 == cc / 10 && *arch <= cc)
+        || archs.iter().max().map_or(false, |newest| *newest <= cc)
+}
+
+/// Probes every visible device, the report of what the prover can use on this machine.
+pub fn probe_capabilities() -> DeviceResult<Vec<DeviceCapabilities>> {
+    (0..CudaDevice::get_device_count()?)
+        .map(|idx| CudaDevice::get_device(idx)?.capabilities())
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelectionPolicy {
+    /// Always use the device with this index.
+    Index(usize),
+    /// Read the device index from the environment variable.
+    EnvVar(String),
+    /// Pick the device with the most free memory at selection time.
+    MostFreeMemory,
+    /// Rotate through the devices, shared by all processes using the same counter file.
+    RoundRobin(String),
+    /// Pick the healthy device with the lowest NVML utilization, then the most
+    /// free memory. Without NVML the same as `MostFreeMemory`.
+    LeastLoaded,
+}
+
+pub const DEFAULT_DEVICE_ENV: &str = "ZKWASM_PROVER_DEVICE";
+pub const DEFAULT_ROUND_ROBIN_FILE: &str = "/tmp/zkwasm-prover-device-rr";
+
+impl Default for DeviceSelectionPolicy {
+    fn default() -> Self {
+        DeviceSelectionPolicy::Index(0)
+    }
+}
+
+pub struct DeviceManager {
+    policy: Mutex<DeviceSelectionPolicy>,
+    // devices that failed with a sticky error, left out of selection
+    unhealthy: Mutex<BTreeSet<usize>>,
+    // devices that hung or lost their context and were not reset since
+    failed: Mutex<BTreeSet<usize>>,
+}
+
+lazy_static! {
+    static ref DEVICE_MANAGER: DeviceManager = DeviceManager {
+        policy: Mutex::new(DeviceSelectionPolicy::default()),
+        unhealthy: Mutex::new(BTreeSet::new()),
+        failed: Mutex::new(BTreeSet::new()),
+    };
+}
+
+impl DeviceManager {
+    pub fn global() -> &'static DeviceManager {
+        &DEVICE_MANAGER
+    }
+
+    pub fn policy(&self) -> DeviceSelectionPolicy {
+        self.policy.lock().unwrap().clone()
+    }
+
+    pub fn set_policy(&self, policy: DeviceSelectionPolicy) {
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    /// Leaves device `idx` out of selection, done when it fails with an error
+    /// its context does not recover from, e.g. an uncorrectable ECC error.
+    pub fn mark_unhealthy(&self, idx: usize) {
+        self.unhealthy.lock().unwrap().insert(idx);
+    }
+
+    /// Selects device `idx` again, once it has been checked or replaced.
+    pub fn mark_healthy(&self, idx: usize) {
+        self.unhealthy.lock().unwrap().remove(&idx);
+    }
+
+    pub fn unhealthy_devices(&self) -> BTreeSet<usize> {
+        self.unhealthy.lock().unwrap().clone()
+    }
+
+    // marks device `idx` unhealthy and due for a reset
+    pub(crate) fn mark_failed(&self, idx: usize) {
+        self.mark_unhealthy(idx);
+        self.failed.lock().unwrap().insert(idx);
+    }
+
+    /// Devices that hung past the sync timeout or lost their context and were
+    /// not reset since, see `reset_failed_devices`.
+    pub fn failed_devices(&self) -> BTreeSet<usize> {
+        self.failed.lock().unwrap().clone()
+    }
+
+    /// Device picked by the policy, or by most free memory among the healthy
+    /// devices if the policy picks an unhealthy one.
+    pub fn select_device(&self) -> DeviceResult<CudaDevice> {
+        let policy = self.policy();
+        let count = CudaDevice::get_device_count()?;
+        let unhealthy = self.unhealthy_devices();
+        let idx = match policy {
+            DeviceSelectionPolicy::Index(idx) => idx,
+            DeviceSelectionPolicy::EnvVar(name) => match std::env::var(&name) {
+                Ok(v) => v.trim().parse::<usize>().map_err(|_| {
+                    Error::InvalidInput(format!(
+                        "Cuda Error(): Invalid device idx {} in {}",
+                        v, name
+                    ))
+                })?,
+                Err(_) => 0,
+            },
+            DeviceSelectionPolicy::MostFreeMemory => most_free_memory(count, &unhealthy)?,
+            DeviceSelectionPolicy::RoundRobin(path) => next_round_robin(&path)? % count.max(1),
+            DeviceSelectionPolicy::LeastLoaded => {
+                let healths = (0..count).filter_map(gpu_health).collect::<Vec<_>>();
+                match healths
+                    .iter()
+                    .filter(|x| x.is_healthy() && !unhealthy.contains(&x.device_id))
+                    .min_by_key(|x| (x.utilization, std::cmp::Reverse(x.free_memory)))
+                {
+                    Some(health) => health.device_id,
+                    None if healths.len() == count && count > 0 => return Err(Error::DeviceError(
+                        "no healthy device, every device is too hot or has uncorrected ECC errors"
+                            .to_string(),
+                    )),
+                    None => {
+                        tracing::warn!(
+                            "nvml not available, using the device with most free memory"
+                        );
+                        most_free_memory(count, &unhealthy)?
+                    }
+                }
+            }
+        };
+        if idx >= count {
+            return Err(Error::InvalidInput(format!(
+                "device index {} out of range, {} devices",
+                idx, count
+            )));
+        }
+        let idx = match unhealthy.contains(&idx) {
+            true => most_free_memory(count, &unhealthy)?,
+            false => idx,
+        };
+        CudaDevice::get_device(idx)
+    }
+}
+
+fn most_free_memory(count: usize, unhealthy: &BTreeSet<usize>) -> DeviceResult<usize> {
+    let mut best = None;
+    for idx in (0..count).filter(|idx| !unhealthy.contains(idx)) {
+        let (free, _) = CudaDevice::get_device(idx)?.get_memory_info()?;
+        if best.map_or(true, |(_, most)| free > most) {
+            best = Some((idx, free));
+        }
+    }
+    best.map(|(idx, _)| idx)
+        .ok_or_else(|| Error::DeviceError("Cuda Error(): no healthy device".to_string()))
+}
+
+// The counter file is shared between processes, flock serializes the read-increment-write.
+fn next_round_robin(path: &str) -> DeviceResult<usize> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::AsRawFd;
+
+    let err = |e: std::io::Error| {
+        Error::DeviceError(format!(
+            "fail to update round robin counter {}: {}",
+            path, e
+        ))
+    };
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .map_err(err)?;
+
+    unsafe {
+        if libc::flock(file.as_raw_fd(), libc::LOCK_EX) != 0 {
+            return Err(err(std::io::Error::last_os_error()));
+        }
+    }
+
+    let res = (|| {
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let current = content.trim().parse::<usize>().unwrap_or(0);
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", current.wrapping_add(1))?;
+        Ok(current)
+    })()
+    .map_err(err);
+
+    unsafe {
+        libc::flock(file.as_raw_fd(), libc::LOCK_UN);
+    }
+
+    res
+}
+
+// errors leaving the context of the device unusable until it is reset
+fn is_sticky(res: cudaError) -> bool {
+    matches!(
+        res,
+        cudaError::cudaErrorECCUncorrectable
+            | cudaError::cudaErrorNvlinkUncorrectable
+            | cudaError::cudaErrorIllegalAddress
+            | cudaError::cudaErrorIllegalInstruction
+            | cudaError::cudaErrorMisalignedAddress
+            | cudaError::cudaErrorInvalidAddressSpace
+            | cudaError::cudaErrorInvalidPc
+            | cudaError::cudaErrorHardwareStackError
+            | cudaError::cudaErrorLaunchFailure
+    )
 }
 
 #[inline]
+#[track_caller]
 pub(crate) fn to_result<T>(value: T, res: cudaError, msg: &'static str) -> DeviceResult<T> {
+    LAST_CUDA_CALL.with(|x| *x.borrow_mut() = msg);
+    let sync_debug = sync_debug();
+    let res = match res {
+        cudaError::cudaSuccess if sync_debug => unsafe {
+            match cuda_runtime_sys::cudaDeviceSynchronize() {
+                cudaError::cudaSuccess => cuda_runtime_sys::cudaGetLastError(),
+                res => res,
+            }
+        },
+        res => res,
+    };
     if res != cudaError::cudaSuccess {
-        Err(Error::DeviceError(format!(
-            "Cuda Error({:?}): {}",
-            res, msg
-        )))
-    } else {
-        Ok(value)
+        crate::metrics::count_cuda_error(res);
+        if is_sticky(res) {
+            let mut device = 0;
+            unsafe { cuda_runtime_sys::cudaGetDevice(&mut device) };
+            tracing::error!(device, error = ?res, "device context lost: {}", msg);
+            DeviceManager::global().mark_failed(device as usize);
+        }
+    }
+    let err = || {
+        if sync_debug {
+            format!("Cuda Error({:?}): {} at {}", res, msg, Location::caller())
+        } else {
+            format!("Cuda Error({:?}): {}", res, msg)
+        }
+    };
+    match res {
+        cudaError::cudaSuccess => Ok(value),
+        cudaError::cudaErrorMemoryAllocation => Err(Error::OutOfMemory(err())),
+        cudaError::cudaErrorLaunchFailure
+        | cudaError::cudaErrorLaunchTimeout
+        | cudaError::cudaErrorLaunchOutOfResources
+        | cudaError::cudaErrorInvalidConfiguration
+        | cudaError::cudaErrorInvalidDeviceFunction
+        | cudaError::cudaErrorNoKernelImageForDevice
+        | cudaError::cudaErrorIllegalAddress
+        | cudaError::cudaErrorIllegalInstruction
+        | cudaError::cudaErrorMisalignedAddress
+        | cudaError::cudaErrorAssert => Err(Error::KernelError(err())),
+        _ => Err(Error::DeviceError(err())),
     }
 }
 
@@ -85,41 +1508,599 @@ pub struct CudaDeviceBufRaw {
     pub(crate) ptr: *mut c_void,
     pub(crate) device: CudaDevice,
     pub(crate) size: usize,
+    // `CudaDevice::epoch` at allocation
+    pub(crate) epoch: usize,
+}
+
+#[allow(non_camel_case_types)]
+type cudaMemPool_t = *mut c_void;
+
+// cudaMemPoolAttr::cudaMemPoolAttrReleaseThreshold
+const CUDA_MEM_POOL_ATTR_RELEASE_THRESHOLD: i32 = 4;
+
+// cudaDeviceAttr values, some are newer than the bindings of cuda_runtime_sys
+const CUDA_DEV_ATTR_MULTIPROCESSOR_COUNT: i32 = 16;
+const CUDA_DEV_ATTR_COMPUTE_CAPABILITY_MAJOR: i32 = 75;
+const CUDA_DEV_ATTR_COMPUTE_CAPABILITY_MINOR: i32 = 76;
+const CUDA_DEV_ATTR_MANAGED_MEMORY: i32 = 83;
+const CUDA_DEV_ATTR_COOPERATIVE_LAUNCH: i32 = 95;
+const CUDA_DEV_ATTR_MAX_PERSISTING_L2_CACHE_SIZE: i32 = 108;
+const CUDA_DEV_ATTR_MAX_ACCESS_POLICY_WINDOW_SIZE: i32 = 109;
+const CUDA_DEV_ATTR_MEMORY_POOLS_SUPPORTED: i32 = 115;
+
+// cudaLimit::cudaLimitPersistingL2CacheSize
+const CUDA_LIMIT_PERSISTING_L2_CACHE_SIZE: i32 = 6;
+// cudaStreamAttrID::cudaStreamAttributeAccessPolicyWindow
+const CUDA_STREAM_ATTRIBUTE_ACCESS_POLICY_WINDOW: i32 = 1;
+// cudaAccessProperty values
+const CUDA_ACCESS_PROPERTY_STREAMING: i32 = 1;
+const CUDA_ACCESS_PROPERTY_PERSISTING: i32 = 2;
+
+// cudaError::cudaErrorGraphExecUpdateFailure, newer than the bindings
+const CUDA_ERROR_GRAPH_EXEC_UPDATE_FAILURE: u32 = 910;
+// cudaStreamCaptureMode::cudaStreamCaptureModeThreadLocal
+const CUDA_STREAM_CAPTURE_MODE_THREAD_LOCAL: i32 = 1;
+
+#[allow(non_camel_case_types)]
+type cudaGraph_t = *mut c_void;
+#[allow(non_camel_case_types)]
+type cudaGraphExec_t = *mut c_void;
+
+// cudaGraphExecUpdateResultInfo of CUDA 12
+#[allow(dead_code)]
+#[repr(C)]
+struct CudaGraphExecUpdateResultInfo {
+    result: i32,
+    error_node: *mut c_void,
+    error_from_node: *mut c_void,
+}
+
+// cudaAccessPolicyWindow
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CudaAccessPolicyWindow {
+    base_ptr: *mut c_void,
+    num_bytes: usize,
+    hit_ratio: f32,
+    hit_prop: i32,
+    miss_prop: i32,
+}
+
+// cudaStreamAttrValue, padded to the size of cudaLaunchAttributeValue
+#[allow(dead_code)]
+#[repr(C)]
+union CudaStreamAttrValue {
+    access_policy_window: CudaAccessPolicyWindow,
+    pad: [u8; 64],
 }
 
 extern "C" {
+    #[link_name = "cudaDeviceGetAttribute"]
+    fn cudaDeviceGetAttributeRaw(value: *mut i32, attr: i32, device: i32) -> cudaError;
     pub fn cudaFreeAsync(ptr: *mut c_void, stream: cudaStream_t) -> cudaError;
+    fn cudaMallocAsync(ptr: *mut *mut c_void, size: usize, stream: cudaStream_t) -> cudaError;
+    fn cudaDeviceGetDefaultMemPool(pool: *mut cudaMemPool_t, device: i32) -> cudaError;
+    fn cudaMemPoolSetAttribute(pool: cudaMemPool_t, attr: i32, value: *mut c_void) -> cudaError;
+    fn cudaMemPoolTrimTo(pool: cudaMemPool_t, min_bytes_to_keep: usize) -> cudaError;
+    fn cudaStreamBeginCapture(stream: cudaStream_t, mode: i32) -> cudaError;
+    fn cudaStreamEndCapture(stream: cudaStream_t, graph: *mut cudaGraph_t) -> cudaError;
+    fn cudaGraphInstantiateWithFlags(
+        exec: *mut cudaGraphExec_t,
+        graph: cudaGraph_t,
+        flags: u64,
+    ) -> cudaError;
+    fn cudaGraphExecUpdate(
+        exec: cudaGraphExec_t,
+        graph: cudaGraph_t,
+        info: *mut CudaGraphExecUpdateResultInfo,
+    ) -> cudaError;
+    fn cudaGraphLaunch(exec: cudaGraphExec_t, stream: cudaStream_t) -> cudaError;
+    fn cudaGraphExecDestroy(exec: cudaGraphExec_t) -> cudaError;
+    fn cudaGraphDestroy(graph: cudaGraph_t) -> cudaError;
+    #[link_name = "cudaDeviceSetLimit"]
+    fn cudaDeviceSetLimitRaw(limit: i32, value: usize) -> cudaError;
+    fn cudaCtxResetPersistingL2Cache() -> cudaError;
+    fn cudaStreamSetAttribute(
+        stream: cudaStream_t,
+        attr: i32,
+        value: *const CudaStreamAttrValue,
+    ) -> cudaError;
 }
 
 impl Drop for CudaDeviceBufRaw {
     fn drop(&mut self) {
-        if self.size < HUGE_BUFFER_SIZE {
-            if self.size >= HUGE_BUFFER_SIZE {
-                let mut cache = HUGE_CUDA_BUFFER_CACHE.lock().unwrap();
-                cache.push(self.ptr() as usize);
-            } else {
-                let mut cache = CUDA_BUFFER_CACHE.lock().unwrap();
-                let arr = cache
-                    .entry((self.device.device, self.size))
-                    .or_insert(vec![]);
-                assert!(!arr.contains(&(self.ptr() as usize)));
-                arr.push(self.ptr() as usize);
-            }
-        } else {
-            self.device().acitve_ctx().unwrap();
-            unsafe {
-                //let timer = start_timer!(|| "cuda free");
+        untrack_live_buffer(self);
+        // the maps stay consistent when a panic poisoned them, a drop must not panic
+        let stream_ordered = STREAM_ORDERED_BUFFERS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(self.ptr() as usize));
+        // freed with the context, its address may belong to a new buffer already
+        if self.is_stale() {
+            return;
+        }
+        if stream_ordered || self.size >= HUGE_BUFFER_SIZE {
+            let res = self.device().acitve_ctx().and_then(|_| unsafe {
                 let res = cudaFreeAsync(self.ptr(), 0usize as _);
-                to_result((), res, "fail to free device memory").unwrap();
-                //end_timer!(timer);
+                to_result((), res, "fail to free device memory")
+            });
+            match res {
+                Ok(()) => self.device.release_memory(self.size),
+                Err(e) => tracing::error!(
+                    device = self.device.device,
+                    size = self.size,
+                    error = %e,
+                    "free failed"
+                ),
             }
+            return;
+        }
+        let mut cache = CUDA_BUFFER_CACHE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let arr = cache
+            .entry((self.device.device, self.size))
+            .or_insert(vec![]);
+        if arr.contains(&(self.ptr() as usize)) {
+            tracing::error!(
+                device = self.device.device,
+                size = self.size,
+                "buffer dropped twice, not caching it again"
+            );
+            return;
         }
+        arr.push(self.ptr() as usize);
     }
 }
 
 impl DeviceBuf for CudaDeviceBufRaw {}
 
+impl CudaDeviceBufRaw {
+    /// Whether the device was reset since this buffer was allocated.
+    pub(crate) fn is_stale(&self) -> bool {
+        self.epoch != self.device.epoch()
+    }
+
+    /// Non-owning view of `len` elements of `T` starting at element `offset`,
+    /// usable wherever a `&CudaDeviceBufRaw` is expected.
+    pub fn slice<T>(&self, offset: usize, len: usize) -> DeviceResult<CudaDeviceBufView<'_>> {
+        let unit = size_of::<T>();
+        if (offset + len) * unit > self.size {
+            return Err(Error::DeviceError(format!(
+                "Cuda Error(): slice {}..{} out of buffer of {} bytes",
+                offset * unit,
+                (offset + len) * unit,
+                self.size
+            )));
+        }
+
+        Ok(CudaDeviceBufView {
+            buf: ManuallyDrop::new(CudaDeviceBufRaw {
+                ptr: unsafe { self.ptr.offset((offset * unit) as isize) },
+                device: self.device.clone(),
+                size: len * unit,
+                epoch: self.epoch,
+            }),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// Sub-range of a `CudaDeviceBufRaw`, never freed nor returned to the buffer cache.
+#[derive(Debug)]
+pub struct CudaDeviceBufView<'a> {
+    buf: ManuallyDrop<CudaDeviceBufRaw>,
+    _marker: PhantomData<&'a CudaDeviceBufRaw>,
+}
+
+impl<'a> Deref for CudaDeviceBufView<'a> {
+    type Target = CudaDeviceBufRaw;
+
+    fn deref(&self) -> &CudaDeviceBufRaw {
+        &self.buf
+    }
+}
+
+/// Device buffer of `len` elements of `T`.
+///
+/// Derefs to the raw buffer for the kernel wrappers not migrated yet; the typed
+/// wrappers take any `TypedBuffer<T>` and reject buffers of another element
+/// type at compile time and buffers too short for the kernel at runtime.
+#[derive(Debug)]
+pub struct CudaDeviceBuf<T> {
+    raw: CudaDeviceBufRaw,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T> CudaDeviceBuf<T> {
+    /// Takes ownership of `raw` as elements of `T`, its size must be a multiple of `T`.
+    pub fn from_raw(raw: CudaDeviceBufRaw) -> DeviceResult<Self> {
+        let unit = size_of::<T>();
+        if unit == 0 || raw.size % unit != 0 {
+            return Err(Error::DeviceError(format!(
+                "Cuda Error(): buffer of {} bytes isn't a whole number of {}",
+                raw.size,
+                std::any::type_name::<T>()
+            )));
+        }
+
+        Ok(CudaDeviceBuf {
+            len: raw.size / unit,
+            raw,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_raw(&self) -> &CudaDeviceBufRaw {
+        &self.raw
+    }
+
+    pub fn into_raw(self) -> CudaDeviceBufRaw {
+        self.raw
+    }
+
+    /// The same memory as elements of `U`, e.g. projective points as base field limbs.
+    pub fn reinterpret<U>(self) -> DeviceResult<CudaDeviceBuf<U>> {
+        CudaDeviceBuf::from_raw(self.raw)
+    }
+
+    /// Non-owning view of elements `offset..offset + len`.
+    pub fn slice(&self, offset: usize, len: usize) -> DeviceResult<CudaDeviceBufView<'_>> {
+        self.raw.slice::<T>(offset, len)
+    }
+}
+
+impl<T> Deref for CudaDeviceBuf<T> {
+    type Target = CudaDeviceBufRaw;
+
+    fn deref(&self) -> &CudaDeviceBufRaw {
+        &self.raw
+    }
+}
+
+/// Device buffer usable where elements of `T` are expected.
+pub trait TypedBuffer<T> {
+    fn raw(&self) -> &CudaDeviceBufRaw;
+
+    /// Element count, `None` for raw buffers whose element type isn't tracked.
+    fn typed_len(&self) -> Option<usize>;
+
+    /// Fails if the buffer is known to hold fewer than `len` elements.
+    fn check_len(&self, len: usize, what: &str) -> DeviceResult<()> {
+        match self.typed_len() {
+            Some(n) if n < len => Err(Error::DeviceError(format!(
+                "Cuda Error(): {} needs {} elements of {}, buffer has {}",
+                what,
+                len,
+                std::any::type_name::<T>(),
+                n
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<T> TypedBuffer<T> for CudaDeviceBuf<T> {
+    fn raw(&self) -> &CudaDeviceBufRaw {
+        &self.raw
+    }
+
+    fn typed_len(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+// untyped buffers are accepted as any type until their callers are migrated
+impl<T> TypedBuffer<T> for CudaDeviceBufRaw {
+    fn raw(&self) -> &CudaDeviceBufRaw {
+        self
+    }
+
+    fn typed_len(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<'a, T> TypedBuffer<T> for CudaDeviceBufView<'a> {
+    fn raw(&self) -> &CudaDeviceBufRaw {
+        &self.buf
+    }
+
+    fn typed_len(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<'a, T, B: TypedBuffer<T> + ?Sized> TypedBuffer<T> for &'a B {
+    fn raw(&self) -> &CudaDeviceBufRaw {
+        (**self).raw()
+    }
+
+    fn typed_len(&self) -> Option<usize> {
+        (**self).typed_len()
+    }
+}
+
+/// Owned CUDA stream of a device, synchronized and destroyed when dropped.
+///
+/// Kernel wrappers take the raw handle (`Option<cudaStream_t>`), work issued on
+/// different streams may overlap; order dependent work with `wait`.
+#[derive(Debug)]
+pub struct CudaStream {
+    device: CudaDevice,
+    stream: cudaStream_t,
+}
+
+// stream handles may be used from any host thread
+unsafe impl Send for CudaStream {}
+unsafe impl Sync for CudaStream {}
+
+impl CudaStream {
+    /// Creates a stream with the current `stream_priority`.
+    pub fn new(device: &CudaDevice) -> DeviceResult<Self> {
+        Ok(CudaStream {
+            device: device.clone(),
+            stream: device.create_stream()?,
+        })
+    }
+
+    /// Stream that doesn't synchronize with the legacy default stream, as
+    /// stream capture needs.
+    pub fn new_non_blocking(device: &CudaDevice) -> DeviceResult<Self> {
+        device.acitve_ctx()?;
+        unsafe {
+            let mut stream = mem::zeroed();
+            let res = cuda_runtime_sys::cudaStreamCreateWithFlags(
+                &mut stream,
+                cuda_runtime_sys::cudaStreamNonBlocking,
+            );
+            to_result(
+                CudaStream {
+                    device: device.clone(),
+                    stream,
+                },
+                res,
+                "fail to create stream",
+            )
+        }
+    }
+
+    pub fn raw(&self) -> cudaStream_t {
+        self.stream
+    }
+
+    pub fn device(&self) -> &CudaDevice {
+        &self.device
+    }
+
+    pub fn synchronize(&self) -> DeviceResult<()> {
+        self.device.acitve_ctx()?;
+        self.device.wait_for(
+            || unsafe { cuda_runtime_sys::cudaStreamQuery(self.stream) },
+            || unsafe { cuda_runtime_sys::cudaStreamSynchronize(self.stream) },
+            "fail to synchronize stream",
+        )
+    }
+
+    /// Marks the work issued on this stream so far.
+    pub fn record_event(&self) -> DeviceResult<CudaEvent> {
+        self.device.record_event(self.stream)
+    }
+
+    /// Work issued on this stream from now on waits for `event`, the host doesn't.
+    pub fn wait_event(&self, event: &CudaEvent) -> DeviceResult<()> {
+        self.device.acitve_ctx()?;
+        unsafe {
+            let res = cuda_runtime_sys::cudaStreamWaitEvent(self.stream, event.event, 0);
+            to_result((), res, "fail to wait event")
+        }
+    }
+
+    /// Records the work `f` issues on this stream into a graph instead of
+    /// running it. `f` must not allocate, copy synchronously or synchronize,
+    /// and the stream must not block on the legacy default stream, see
+    /// `new_non_blocking`.
+    pub fn capture(&self, f: impl FnOnce() -> DeviceResult<()>) -> DeviceResult<CudaGraph> {
+        self.device.acitve_ctx()?;
+        unsafe {
+            let res = cudaStreamBeginCapture(self.stream, CUDA_STREAM_CAPTURE_MODE_THREAD_LOCAL);
+            to_result((), res, "fail to begin capture")?;
+            CAPTURING.with(|x| x.set(true));
+            let captured = f();
+            CAPTURING.with(|x| x.set(false));
+            let mut graph = core::ptr::null_mut();
+            let res = cudaStreamEndCapture(self.stream, &mut graph);
+            captured?;
+            to_result(
+                CudaGraph {
+                    device: self.device.clone(),
+                    graph,
+                },
+                res,
+                "fail to end capture",
+            )
+        }
+    }
+
+    /// Work issued on this stream from now on waits for the work issued on `other` so far.
+    pub fn wait(&self, other: &CudaStream) -> DeviceResult<()> {
+        self.wait_event(&other.record_event()?)
+    }
+}
+
+impl Drop for CudaStream {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.device.acitve_ctx();
+            cuda_runtime_sys::cudaStreamSynchronize(self.stream);
+            cuda_runtime_sys::cudaStreamDestroy(self.stream);
+        }
+    }
+}
+
+/// Point in a stream's work, see `CudaStream::record_event`.
+#[derive(Debug)]
+pub struct CudaEvent {
+    device: CudaDevice,
+    event: cuda_runtime_sys::cudaEvent_t,
+}
+
+unsafe impl Send for CudaEvent {}
+unsafe impl Sync for CudaEvent {}
+
+impl CudaEvent {
+    pub fn raw(&self) -> cuda_runtime_sys::cudaEvent_t {
+        self.event
+    }
+
+    pub fn synchronize(&self) -> DeviceResult<()> {
+        self.device.acitve_ctx()?;
+        self.device.wait_for(
+            || unsafe { cuda_runtime_sys::cudaEventQuery(self.event) },
+            || unsafe { cuda_runtime_sys::cudaEventSynchronize(self.event) },
+            "fail to synchronize event",
+        )
+    }
+}
+
+impl Drop for CudaEvent {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.device.acitve_ctx();
+            cuda_runtime_sys::cudaEventDestroy(self.event);
+        }
+    }
+}
+
+/// Work issued on a stream recorded instead of run, see `CudaStream::capture`.
+#[derive(Debug)]
+pub struct CudaGraph {
+    device: CudaDevice,
+    graph: cudaGraph_t,
+}
+
+unsafe impl Send for CudaGraph {}
+unsafe impl Sync for CudaGraph {}
+
+impl CudaGraph {
+    pub(crate) fn raw(&self) -> *mut c_void {
+        self.graph
+    }
+
+    pub fn instantiate(&self) -> DeviceResult<CudaGraphExec> {
+        self.device.acitve_ctx()?;
+        unsafe {
+            let mut exec = core::ptr::null_mut();
+            let res = cudaGraphInstantiateWithFlags(&mut exec, self.graph, 0);
+            to_result(
+                CudaGraphExec {
+                    device: self.device.clone(),
+                    exec,
+                },
+                res,
+                "fail to instantiate graph",
+            )
+        }
+    }
+}
+
+impl Drop for CudaGraph {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.device.acitve_ctx();
+            cudaGraphDestroy(self.graph);
+        }
+    }
+}
+
+/// Instantiated `CudaGraph`, its whole sequence of launches runs with one launch.
+#[derive(Debug)]
+pub struct CudaGraphExec {
+    device: CudaDevice,
+    exec: cudaGraphExec_t,
+}
+
+unsafe impl Send for CudaGraphExec {}
+unsafe impl Sync for CudaGraphExec {}
+
+impl CudaGraphExec {
+    /// Rebinds the parameters of the launches, e.g. buffer addresses, to those
+    /// of `graph`, captured from the same sequence. Cheaper than instantiating
+    /// `graph`, false when its structure differs and nothing was changed.
+    pub fn update(&mut self, graph: &CudaGraph) -> DeviceResult<bool> {
+        self.device.acitve_ctx()?;
+        unsafe {
+            let mut info = mem::zeroed::<CudaGraphExecUpdateResultInfo>();
+            let res = cudaGraphExecUpdate(self.exec, graph.graph, &mut info);
+            if res as u32 == CUDA_ERROR_GRAPH_EXEC_UPDATE_FAILURE {
+                cuda_runtime_sys::cudaGetLastError();
+                return Ok(false);
+            }
+            to_result(true, res, "fail to update graph")
+        }
+    }
+
+    pub(crate) fn raw(&self) -> *mut c_void {
+        self.exec
+    }
+
+    pub fn launch(&self, stream: &CudaStream) -> DeviceResult<()> {
+        self.device.acitve_ctx()?;
+        let res = unsafe { cudaGraphLaunch(self.exec, stream.stream) };
+        to_result((), res, "fail to launch graph")
+    }
+}
+
+impl Drop for CudaGraphExec {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.device.acitve_ctx();
+            cudaGraphExecDestroy(self.exec);
+        }
+    }
+}
+
 impl CudaDevice {
+    /// Numeric priorities `(least, greatest)` of the device, lower values are higher priority.
+    pub fn stream_priority_range(&self) -> DeviceResult<(i32, i32)> {
+        self.acitve_ctx()?;
+        unsafe {
+            let mut least = 0;
+            let mut greatest = 0;
+            let res = cuda_runtime_sys::cudaDeviceGetStreamPriorityRange(&mut least, &mut greatest);
+            to_result((least, greatest), res, "fail to get stream priority range")
+        }
+    }
+
+    /// Create a stream with the current `stream_priority`.
+    pub fn create_stream(&self) -> DeviceResult<cudaStream_t> {
+        let (least, greatest) = self.stream_priority_range()?;
+        let priority = match stream_priority() {
+            StreamPriority::Background => least,
+            StreamPriority::Normal => (least + greatest) / 2,
+            StreamPriority::Critical => greatest,
+        };
+        unsafe {
+            let mut stream = mem::zeroed();
+            let res = cuda_runtime_sys::cudaStreamCreateWithPriority(
+                &mut stream,
+                cuda_runtime_sys::cudaStreamDefault,
+                priority,
+            );
+            to_result(stream, res, "fail to run cudaStreamCreateWithPriority")
+        }
+    }
+
     pub fn copy_from_host_to_device_async<T>(
         &self,
         dst: &CudaDeviceBufRaw,
@@ -139,6 +2120,17 @@ impl CudaDevice {
         }
     }
 
+    /// `alloc_device_buffer_from_slice` with the copy issued on `stream`.
+    pub fn alloc_device_buffer_from_slice_async<T>(
+        &self,
+        data: &[T],
+        stream: cudaStream_t,
+    ) -> DeviceResult<CudaDeviceBufRaw> {
+        let buf = self._alloc_device_buffer::<T>(data.len(), false)?;
+        self.copy_from_host_to_device_async(&buf, data, stream)?;
+        Ok(buf)
+    }
+
     pub fn copy_from_device_to_host_async<T>(
         &self,
         dst: &mut [T],
@@ -178,56 +2170,163 @@ impl CudaDevice {
         }
     }
 
+    /// Zeroed buffer of `len` elements of `T`.
+    #[track_caller]
+    pub fn alloc_typed_buffer<T>(&self, len: usize) -> DeviceResult<CudaDeviceBuf<T>> {
+        CudaDeviceBuf::from_raw(self._alloc_device_buffer::<T>(len, true)?)
+    }
+
+    #[track_caller]
+    pub fn alloc_typed_buffer_from_slice<T>(&self, data: &[T]) -> DeviceResult<CudaDeviceBuf<T>> {
+        CudaDeviceBuf::from_raw(self.alloc_device_buffer_from_slice(data)?)
+    }
+
+    #[track_caller]
     fn _alloc_device_buffer<T>(&self, size: usize, zero: bool) -> DeviceResult<CudaDeviceBufRaw> {
+        let buf = self._alloc_device_buffer_untracked::<T>(size, zero)?;
+        track_live_buffer(&buf);
+        Ok(buf)
+    }
+
+    fn _alloc_device_buffer_untracked<T>(
+        &self,
+        size: usize,
+        zero: bool,
+    ) -> DeviceResult<CudaDeviceBufRaw> {
         //println!("alloc device memory {}", size * mem::size_of::<T>());
         //self.print_memory_info()?;
+        if stream_ordered_alloc() && self.memory_pools_supported() {
+            return self.alloc_stream_ordered(size * mem::size_of::<T>(), zero);
+        }
         unsafe {
             let size = size * mem::size_of::<T>();
             {
-                let mut cache = CUDA_BUFFER_CACHE.lock().unwrap();
+                let mut cache = CUDA_BUFFER_CACHE.lock()?;
                 let arr = cache.entry((self.device, size)).or_insert(vec![]);
 
-                if arr.len() > 0 {
+                if let Some(ptr) = arr.pop() {
+                    count_buffer_cache(self.device, size, true);
                     let ret = CudaDeviceBufRaw {
-                        ptr: arr.pop().unwrap() as *mut c_void,
+                        ptr: ptr as *mut c_void,
                         device: self.clone(),
                         size,
+                        epoch: self.epoch(),
                     };
                     if zero {
-                        cuda_runtime_sys::cudaMemset(ret.ptr(), 0, size);
+                        self.acitve_ctx()?;
+                        let res = cuda_runtime_sys::cudaMemset(ret.ptr(), 0, size);
+                        to_result((), res, "fail to zero device memory")?;
                     }
                     return Ok(ret);
                 }
             }
 
             {
-                let mut cache = HUGE_CUDA_BUFFER_CACHE.lock().unwrap();
-                if cache.len() > 0 {
+                let mut cache = HUGE_CUDA_BUFFER_CACHE.lock()?;
+                if let Some(ptr) = cache.pop() {
+                    crate::metrics::count_buffer_cache(true);
                     let ret = CudaDeviceBufRaw {
-                        ptr: cache.pop().unwrap() as *mut c_void,
+                        ptr: ptr as *mut c_void,
                         device: self.clone(),
                         size: HUGE_BUFFER_SIZE,
+                        epoch: self.epoch(),
                     };
                     if zero {
-                        cuda_runtime_sys::cudaMemset(ret.ptr(), 0, size);
+                        self.acitve_ctx()?;
+                        let res = cuda_runtime_sys::cudaMemset(ret.ptr(), 0, size);
+                        to_result((), res, "fail to zero device memory")?;
                     }
                     return Ok(ret);
                 }
             }
 
+            count_buffer_cache(self.device, size, false);
             self.acitve_ctx()?;
+            self.reserve_memory(size)?;
             let mut ptr = 0 as *mut c_void;
-            let res = cuda_runtime_sys::cudaMalloc(&mut ptr, size);
+            let mut res = cuda_runtime_sys::cudaMalloc(&mut ptr, size);
+            if res == cudaError::cudaErrorMemoryAllocation {
+                // cached buffers of other sizes may be what fragments the device,
+                // give them all back to the driver before giving up
+                tracing::warn!(
+                    "cudaMalloc of {} bytes failed on device {}, flushing the buffer cache",
+                    size,
+                    self.device
+                );
+                cuda_runtime_sys::cudaGetLastError();
+                self.trim_buffer_cache(0)?;
+                res = cuda_runtime_sys::cudaMalloc(&mut ptr, size);
+            }
             //self.print_memory_info()?;
-            to_result(
-                CudaDeviceBufRaw {
-                    ptr,
-                    device: self.clone(),
+            if res != cudaError::cudaSuccess {
+                self.release_memory(size);
+                to_result((), res, "fail to alloc device memory")?;
+            }
+            Ok(CudaDeviceBufRaw {
+                ptr,
+                device: self.clone(),
+                size,
+                epoch: self.epoch(),
+            })
+        }
+    }
+
+    // The default pool of the device, set to keep freed memory instead of
+    // releasing it to the driver at every synchronization.
+    fn mem_pool(&self) -> DeviceResult<cudaMemPool_t> {
+        let mut pools = CUDA_MEM_POOLS.lock()?;
+        if let Some(pool) = pools.get(&self.device) {
+            return Ok(*pool as cudaMemPool_t);
+        }
+
+        self.acitve_ctx()?;
+        unsafe {
+            let mut pool = 0 as cudaMemPool_t;
+            let res = cudaDeviceGetDefaultMemPool(&mut pool, self.device);
+            to_result((), res, "fail to get the default memory pool")?;
+            let mut threshold = u64::MAX;
+            let res = cudaMemPoolSetAttribute(
+                pool,
+                CUDA_MEM_POOL_ATTR_RELEASE_THRESHOLD,
+                &mut threshold as *mut u64 as *mut c_void,
+            );
+            to_result((), res, "fail to set the memory pool release threshold")?;
+            pools.insert(self.device, pool as usize);
+            Ok(pool)
+        }
+    }
+
+    fn alloc_stream_ordered(&self, size: usize, zero: bool) -> DeviceResult<CudaDeviceBufRaw> {
+        self.mem_pool()?;
+        self.reserve_memory(size)?;
+        unsafe {
+            let mut ptr = 0 as *mut c_void;
+            let mut res = cudaMallocAsync(&mut ptr, size, 0usize as _);
+            if res == cudaError::cudaErrorMemoryAllocation {
+                tracing::warn!(
+                    "cudaMallocAsync of {} bytes failed on device {}, flushing the buffer cache",
                     size,
-                },
-                res,
-                "fail to alloc device memory",
-            )
+                    self.device
+                );
+                cuda_runtime_sys::cudaGetLastError();
+                self.trim_buffer_cache(0)?;
+                res = cudaMallocAsync(&mut ptr, size, 0usize as _);
+            }
+            if res != cudaError::cudaSuccess {
+                self.release_memory(size);
+                to_result((), res, "fail to alloc device memory")?;
+            }
+            if zero {
+                let res = cuda_runtime_sys::cudaMemsetAsync(ptr, 0, size, 0usize as _);
+                to_result((), res, "fail to zero device memory")?;
+            }
+            STREAM_ORDERED_BUFFERS.lock()?.insert(ptr as usize);
+            Ok(CudaDeviceBufRaw {
+                ptr,
+                device: self.clone(),
+                size,
+                epoch: self.epoch(),
+            })
         }
     }
 
@@ -279,15 +2378,17 @@ impl Device<CudaDeviceBufRaw> for CudaDevice {
             let mut free = 0;
             let mut total = 0;
             cuda_runtime_sys::cudaMemGetInfo(&mut free, &mut total);
-            println!("free is {},total is {}", free, total);
+            tracing::info!(free, total, "cuda device memory");
         }
         Ok(())
     }
 
+    #[track_caller]
     fn alloc_device_buffer<T>(&self, size: usize) -> DeviceResult<CudaDeviceBufRaw> {
         self._alloc_device_buffer::<T>(size, true)
     }
 
+    #[track_caller]
     fn alloc_device_buffer_from_slice<T>(&self, data: &[T]) -> DeviceResult<CudaDeviceBufRaw> {
         let buf = self._alloc_device_buffer::<T>(data.len(), false)?;
         self.copy_from_host_to_device(&buf, data)?;
@@ -347,10 +2448,15 @@ impl Device<CudaDeviceBufRaw> for CudaDevice {
 
     fn synchronize(&self) -> DeviceResult<()> {
         self.acitve_ctx()?;
-        unsafe {
-            let res = cuda_runtime_sys::cudaDeviceSynchronize();
-            to_result((), res, "fail to synchronize")
+        if sync_timeout().is_none() {
+            let res = unsafe { cuda_runtime_sys::cudaDeviceSynchronize() };
+            return to_result((), res, "fail to synchronize");
         }
+        // there is no query for the whole device, wait for the work issued so
+        // far on the legacy default stream, which waits for every blocking stream
+        self.record_event(core::ptr::null_mut())?.synchronize()?;
+        let res = unsafe { cuda_runtime_sys::cudaDeviceSynchronize() };
+        to_result((), res, "fail to synchronize")
     }
 
     fn pin_memory<T>(&self, dst: &[T]) -> DeviceResult<()> {
@@ -369,10 +2475,74 @@ impl Device<CudaDeviceBufRaw> for CudaDevice {
     }
 
     fn unpin_memory<T>(&self, dst: &[T]) -> DeviceResult<()> {
+        let ptr = dst.as_ptr() as usize;
+        if let Some(region) = PINNED_MEMORY_POOL
+            .lock()
+            .unwrap()
+            .remove(&(self.device, ptr))
+        {
+            // unregistered by its drop
+            drop(region);
+            return Ok(());
+        }
         self.acitve_ctx()?;
         unsafe {
             let res = cuda_runtime_sys::cudaHostUnregister(dst.as_ptr() as *mut _);
             to_result((), res, "fail to synchronize")
         }
     }
+
+    fn pin_memory_pooled<T>(&self, dst: &[T]) -> DeviceResult<()> {
+        let ptr = dst.as_ptr() as usize;
+        let size = dst.len() * size_of::<T>();
+        let mut pool = PINNED_MEMORY_POOL.lock().unwrap();
+        if let Some((&(_, start), region)) = pool
+            .range((self.device, 0)..=(self.device, ptr))
+            .next_back()
+        {
+            if ptr + size <= start + region.size {
+                return Ok(());
+            }
+            if start == ptr {
+                // grown, register again with the new size
+                pool.remove(&(self.device, ptr));
+            }
+        }
+
+        self.acitve_ctx()?;
+        unsafe {
+            let res = cuda_runtime_sys::cudaHostRegister(
+                ptr as *mut _,
+                size,
+                cuda_runtime_sys::cudaHostAllocMapped,
+            );
+            if res == cudaError::cudaErrorHostMemoryAlreadyRegistered {
+                // pinned with `pin_memory`, left to its owner
+                cuda_runtime_sys::cudaGetLastError();
+                return Ok(());
+            }
+            to_result((), res, "fail to pin memory")?;
+        }
+        pool.insert(
+            (self.device, ptr),
+            PinnedRegion {
+                device: self.clone(),
+                ptr,
+                size,
+            },
+        );
+        Ok(())
+    }
+
+    fn release_pinned_memory(&self) -> DeviceResult<()> {
+        let mut pool = PINNED_MEMORY_POOL.lock().unwrap();
+        let keys = pool
+            .range((self.device, 0)..=(self.device, usize::MAX))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in keys {
+            pool.remove(&key);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,86 @@
+//! GPU health from NVML, with the `nvml` feature: memory, utilization, ECC
+//! errors and temperature. Without the feature, or without a driver exposing
+//! NVML, nothing is reported and selection falls back to free memory.
+
+/// Devices at or above this temperature, in degrees Celsius, are not picked.
+pub const MAX_TEMPERATURE: u32 = 90;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuHealth {
+    /// CUDA device index.
+    pub device_id: usize,
+    pub free_memory: u64,
+    pub total_memory: u64,
+    /// Percent of time a kernel ran over the last sample period.
+    pub utilization: u32,
+    pub temperature: u32,
+    /// ECC errors since the driver loaded.
+    pub ecc_corrected: u64,
+    pub ecc_uncorrected: u64,
+}
+
+impl GpuHealth {
+    /// No uncorrected ECC error and below `MAX_TEMPERATURE`.
+    pub fn is_healthy(&self) -> bool {
+        self.ecc_uncorrected == 0 && self.temperature < MAX_TEMPERATURE
+    }
+}
+
+// NVML numbers devices differently from CUDA, they are matched by PCI bus id
+#[cfg(feature = "nvml")]
+fn pci_bus_id(device_id: usize) -> Option<String> {
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+
+    let mut id = [0 as c_char; 32];
+    let res = unsafe {
+        cuda_runtime_sys::cudaDeviceGetPCIBusId(id.as_mut_ptr(), id.len() as i32, device_id as i32)
+    };
+    if res != cuda_runtime_sys::cudaError::cudaSuccess {
+        return None;
+    }
+    let id = unsafe { CStr::from_ptr(id.as_ptr()) };
+    Some(id.to_string_lossy().into_owned())
+}
+
+/// Health of CUDA device `device_id`, `None` if NVML is not available.
+#[cfg(feature = "nvml")]
+pub fn gpu_health(device_id: usize) -> Option<GpuHealth> {
+    use nvml_wrapper::enum_wrappers::device::EccCounter;
+    use nvml_wrapper::enum_wrappers::device::MemoryError;
+    use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+    use nvml_wrapper::Nvml;
+
+    lazy_static! {
+        static ref NVML: Option<Nvml> = Nvml::init()
+            .map_err(|e| tracing::warn!(error = ?e, "fail to init nvml"))
+            .ok();
+    }
+
+    let device = NVML
+        .as_ref()?
+        .device_by_pci_bus_id(pci_bus_id(device_id)?)
+        .ok()?;
+    let memory = device.memory_info().ok()?;
+    // boards without ECC report an error, they have no ECC errors either
+    let ecc = |error| {
+        device
+            .total_ecc_errors(error, EccCounter::Volatile)
+            .unwrap_or(0)
+    };
+    Some(GpuHealth {
+        device_id,
+        free_memory: memory.free,
+        total_memory: memory.total,
+        utilization: device.utilization_rates().ok()?.gpu,
+        temperature: device.temperature(TemperatureSensor::Gpu).ok()?,
+        ecc_corrected: ecc(MemoryError::Corrected),
+        ecc_uncorrected: ecc(MemoryError::Uncorrected),
+    })
+}
+
+/// Health of CUDA device `device_id`, `None` if NVML is not available.
+#[cfg(not(feature = "nvml"))]
+pub fn gpu_health(_device_id: usize) -> Option<GpuHealth> {
+    None
+}
@@ -0,0 +1,248 @@
+use core::cell::RefCell;
+use std::mem::size_of;
+use std::ptr;
+use std::sync::Arc;
+
+use opencl3::command_queue::CommandQueue;
+use opencl3::context::Context;
+use opencl3::device::get_all_devices;
+use opencl3::device::Device as ClDevice;
+use opencl3::device::CL_DEVICE_TYPE_GPU;
+use opencl3::kernel::Kernel;
+use opencl3::memory::Buffer;
+use opencl3::memory::CL_MEM_READ_WRITE;
+use opencl3::program::Program;
+use opencl3::types::CL_BLOCKING;
+
+use super::{Device, DeviceBuf, Error};
+use crate::device::DeviceResult;
+
+const BN254_KERNEL_SOURCE: &str = include_str!("../../opencl/bn254.cl");
+
+fn to_result<T, E: core::fmt::Display>(res: Result<T, E>, msg: &'static str) -> DeviceResult<T> {
+    res.map_err(|e| Error::DeviceError(format!("OpenCL Error({}): {}", e, msg)))
+}
+
+struct OpenCLContext {
+    idx: usize,
+    device: ClDevice,
+    context: Context,
+    queue: CommandQueue,
+    program: Program,
+}
+
+#[derive(Clone)]
+pub struct OpenCLDevice {
+    inner: Arc<OpenCLContext>,
+}
+
+// All commands go through the single in-order queue of the context.
+unsafe impl Send for OpenCLDevice {}
+unsafe impl Sync for OpenCLDevice {}
+
+impl OpenCLDevice {
+    pub fn device_id(&self) -> usize {
+        self.inner.idx
+    }
+
+    pub fn name(&self) -> String {
+        self.inner.device.name().unwrap_or_default()
+    }
+
+    pub(crate) fn queue(&self) -> &CommandQueue {
+        &self.inner.queue
+    }
+
+    pub(crate) fn kernel(&self, name: &str) -> DeviceResult<Kernel> {
+        to_result(
+            Kernel::create(&self.inner.program, name),
+            "fail to create kernel",
+        )
+    }
+}
+
+pub struct OpenCLDeviceBuf {
+    pub(crate) buf: RefCell<Buffer<u8>>,
+    pub(crate) size: usize,
+}
+
+impl DeviceBuf for OpenCLDeviceBuf {}
+
+fn as_bytes<T>(data: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * size_of::<T>()) }
+}
+
+fn as_bytes_mut<T>(data: &mut [T]) -> &mut [u8] {
+    unsafe {
+        std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, data.len() * size_of::<T>())
+    }
+}
+
+impl Device<OpenCLDeviceBuf> for OpenCLDevice {
+    fn get_device_count() -> DeviceResult<usize> {
+        Ok(to_result(
+            get_all_devices(CL_DEVICE_TYPE_GPU),
+            "fail to get device count",
+        )?
+        .len())
+    }
+
+    fn get_device(idx: usize) -> DeviceResult<Self> {
+        let ids = to_result(get_all_devices(CL_DEVICE_TYPE_GPU), "fail to get device")?;
+        let id = *ids
+            .get(idx)
+            .ok_or_else(|| Error::DeviceError(format!("OpenCL device {} not found", idx)))?;
+        let device = ClDevice::new(id);
+        let context = to_result(Context::from_device(&device), "fail to create context")?;
+        let queue = to_result(
+            CommandQueue::create_default(&context, 0),
+            "fail to create command queue",
+        )?;
+        let program = Program::create_and_build_from_source(&context, BN254_KERNEL_SOURCE, "")
+            .map_err(|log| {
+                Error::DeviceError(format!("OpenCL Error: fail to build kernels\n{}", log))
+            })?;
+
+        Ok(Self {
+            inner: Arc::new(OpenCLContext {
+                idx,
+                device,
+                context,
+                queue,
+                program,
+            }),
+        })
+    }
+
+    fn alloc_device_buffer<T>(&self, size: usize) -> DeviceResult<OpenCLDeviceBuf> {
+        let bytes = size * size_of::<T>();
+        let mut buf = to_result(
+            unsafe {
+                Buffer::<u8>::create(
+                    &self.inner.context,
+                    CL_MEM_READ_WRITE,
+                    bytes.max(1),
+                    ptr::null_mut(),
+                )
+            },
+            "fail to alloc device buffer",
+        )?;
+        if bytes > 0 {
+            to_result(
+                unsafe {
+                    self.inner
+                        .queue
+                        .enqueue_fill_buffer(&mut buf, &[0u8], 0, bytes, &[])
+                },
+                "fail to clear device buffer",
+            )?;
+        }
+        Ok(OpenCLDeviceBuf {
+            buf: RefCell::new(buf),
+            size: bytes,
+        })
+    }
+
+    fn alloc_device_buffer_from_slice<T>(&self, data: &[T]) -> DeviceResult<OpenCLDeviceBuf> {
+        let buf = self.alloc_device_buffer::<T>(data.len())?;
+        self.copy_from_host_to_device(&buf, data)?;
+        Ok(buf)
+    }
+
+    fn copy_from_host_to_device<T>(&self, dst: &OpenCLDeviceBuf, src: &[T]) -> DeviceResult<()> {
+        let src = as_bytes(src);
+        assert!(src.len() <= dst.size);
+        to_result(
+            unsafe {
+                self.inner.queue.enqueue_write_buffer(
+                    &mut dst.buf.borrow_mut(),
+                    CL_BLOCKING,
+                    0,
+                    src,
+                    &[],
+                )
+            },
+            "fail to copy memory from host to device",
+        )?;
+        Ok(())
+    }
+
+    fn copy_from_device_to_host<T>(
+        &self,
+        dst: &mut [T],
+        src: &OpenCLDeviceBuf,
+    ) -> DeviceResult<()> {
+        let dst = as_bytes_mut(dst);
+        assert!(dst.len() <= src.size);
+        to_result(
+            unsafe {
+                self.inner
+                    .queue
+                    .enqueue_read_buffer(&src.buf.borrow(), CL_BLOCKING, 0, dst, &[])
+            },
+            "fail to copy memory from device to host",
+        )?;
+        Ok(())
+    }
+
+    fn copy_from_device_to_device<T>(
+        &self,
+        dst: &OpenCLDeviceBuf,
+        dst_offset: usize,
+        src: &OpenCLDeviceBuf,
+        src_offset: usize,
+        len: usize,
+    ) -> DeviceResult<()> {
+        let bytes = len * size_of::<T>();
+        assert!((dst_offset + len) * size_of::<T>() <= dst.size);
+        assert!((src_offset + len) * size_of::<T>() <= src.size);
+        to_result(
+            unsafe {
+                self.inner.queue.enqueue_copy_buffer(
+                    &src.buf.borrow(),
+                    &mut dst.buf.borrow_mut(),
+                    src_offset * size_of::<T>(),
+                    dst_offset * size_of::<T>(),
+                    bytes,
+                    &[],
+                )
+            },
+            "fail to copy memory from device to device",
+        )?;
+        Ok(())
+    }
+
+    fn synchronize(&self) -> DeviceResult<()> {
+        to_result(self.inner.queue.finish(), "fail to synchronize")
+    }
+
+    fn pin_memory<T>(&self, _dst: &[T]) -> DeviceResult<()> {
+        Ok(())
+    }
+
+    fn unpin_memory<T>(&self, _dst: &[T]) -> DeviceResult<()> {
+        Ok(())
+    }
+
+    fn pin_memory_pooled<T>(&self, _dst: &[T]) -> DeviceResult<()> {
+        Ok(())
+    }
+
+    fn release_pinned_memory(&self) -> DeviceResult<()> {
+        Ok(())
+    }
+
+    fn print_memory_info(&self) -> DeviceResult<()> {
+        let total = to_result(
+            self.inner.device.global_mem_size(),
+            "fail to get memory info",
+        )?;
+        tracing::info!(
+            device = self.inner.idx,
+            name = %self.name(),
+            total,
+            "opencl device global memory"
+        );
+        Ok(())
+    }
+}
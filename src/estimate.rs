@@ -0,0 +1,109 @@
+use std::mem::size_of;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::plonk::ProvingKey;
+
+use crate::config::ProverConfig;
+use crate::device::cuda::CudaDevice;
+use crate::hugetlb::huge_page_size;
+use crate::plan::CircuitShape;
+pub use crate::plan::DeviceMemoryEstimate;
+pub use crate::plan::HostMemoryEstimate;
+use crate::Error;
+
+/// Outcome of `estimate_device_memory`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceMemoryReport {
+    pub estimate: DeviceMemoryEstimate,
+    pub device_id: usize,
+    pub total_memory: usize,
+    pub free_memory: usize,
+    /// The lower of free memory and `ProverConfig::memory_cap`.
+    pub budget: usize,
+}
+
+impl DeviceMemoryReport {
+    pub fn fits(&self) -> bool {
+        self.estimate.peak <= self.budget
+    }
+
+    /// Bytes missing for the proof to fit, 0 if it does.
+    pub fn shortfall(&self) -> usize {
+        self.estimate.peak.saturating_sub(self.budget)
+    }
+}
+
+/// Estimates the peak device memory of proving with `pk` under `config` and
+/// compares it against the device the proof would run on, `config.device_id`
+/// or device 0.
+pub fn estimate_device_memory<C: CurveAffine>(
+    pk: &ProvingKey<C>,
+    config: &ProverConfig,
+) -> Result<DeviceMemoryReport, Error> {
+    let mut estimate = DeviceMemoryEstimate::new::<C>(
+        &CircuitShape::new(pk),
+        config.streams,
+        config.msm_window_bits,
+    );
+    // the precomputed Lagrange table stays on device next to the bases
+    if config.msm_precompute_factor > 1 {
+        let table = config.msm_precompute_factor * (1usize << estimate.k) * size_of::<C>();
+        estimate.resident += table;
+        estimate.peak += table;
+    }
+    // resident advices live next to the extended buffers of evaluate_h
+    if config.resident_advices {
+        let shape = CircuitShape::new(pk);
+        estimate.extended_buffers +=
+            shape.advice_columns * (1usize << estimate.k) * size_of::<C::Scalar>();
+        estimate.peak = estimate.resident
+            + estimate.domain_buffers.max(estimate.extended_buffers)
+            + estimate.msm;
+    }
+    // the permuted pairs wait on device until the lookup z polynomials, next to the msm
+    if config.resident_permuted {
+        let shape = CircuitShape::new(pk);
+        let pairs = 2 * shape.lookups * (1usize << estimate.k) * size_of::<C::Scalar>();
+        estimate.resident += pairs;
+        estimate.peak += pairs;
+    }
+    // an accumulator and ntt scratch buffer per extra stream of the gate groups
+    if config.expr_streams > 1 {
+        let shape = CircuitShape::new(pk);
+        estimate.extended_buffers +=
+            2 * (config.expr_streams - 1) * (1usize << shape.extended_k) * size_of::<C::Scalar>();
+        estimate.peak = estimate.resident
+            + estimate.domain_buffers.max(estimate.extended_buffers)
+            + estimate.msm;
+    }
+    // one coset of extended buffers at a time, next to the extended h
+    if config.coset_sliced_h {
+        let shape = CircuitShape::new(pk);
+        let slices = 1usize << (shape.extended_k - shape.k);
+        estimate.extended_buffers = estimate.extended_buffers / slices
+            + (1usize << shape.extended_k) * size_of::<C::Scalar>();
+        estimate.peak = estimate.resident
+            + estimate.domain_buffers.max(estimate.extended_buffers)
+            + estimate.msm;
+    }
+    let device_id = config.device_id.unwrap_or(0);
+    let device = CudaDevice::get_device(device_id)?;
+    let (free_memory, total_memory) = device.get_memory_info()?;
+    let budget = config
+        .memory_cap
+        .map_or(free_memory, |cap| cap.min(free_memory));
+
+    Ok(DeviceMemoryReport {
+        estimate,
+        device_id,
+        total_memory,
+        free_memory,
+        budget,
+    })
+}
+
+/// Estimates the huge pages and pinned host memory of proving with `pk`.
+/// Proofs running concurrently in the process each need their own.
+pub fn estimate_host_memory<C: CurveAffine>(pk: &ProvingKey<C>) -> HostMemoryEstimate {
+    HostMemoryEstimate::new::<C::Scalar>(&CircuitShape::new(pk), huge_page_size())
+}
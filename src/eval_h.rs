@@ -1,13 +1,20 @@
+use std::any::TypeId;
+use std::cell::Cell;
 use std::collections::BTreeMap;
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::marker::PhantomData;
 use std::mem::ManuallyDrop;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
 
 use ark_std::end_timer;
 use ark_std::iterable::Iterable;
 use ark_std::start_timer;
 use cuda_runtime_sys::cudaMemset;
+use cuda_runtime_sys::cudaMemsetAsync;
 use cuda_runtime_sys::cudaStream_t;
-use cuda_runtime_sys::CUstream_st;
 use halo2_proofs::arithmetic::CurveAffine;
 use halo2_proofs::arithmetic::Field;
 use halo2_proofs::arithmetic::FieldExt;
@@ -17,9 +24,14 @@ use halo2_proofs::plonk::evaluation_gpu::ProveExpression;
 use halo2_proofs::plonk::evaluation_gpu::ProveExpressionUnit;
 use halo2_proofs::plonk::Any;
 use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::poly::EvaluationDomain;
 use halo2_proofs::transcript::EncodedChallenge;
 use halo2_proofs::transcript::TranscriptWrite;
+use rayon::iter::IndexedParallelIterator as _;
+use rayon::iter::ParallelIterator as _;
+use rayon::prelude::ParallelSliceMut as _;
 
+use crate::cache::DeviceBufCache;
 use crate::cuda::bn254::buffer_copy_with_shift;
 use crate::cuda::bn254::extended_intt_after;
 use crate::cuda::bn254::extended_prepare;
@@ -27,9 +39,12 @@ use crate::cuda::bn254::field_mul;
 use crate::cuda::bn254::field_op_v2;
 use crate::cuda::bn254::field_op_v3;
 use crate::cuda::bn254::field_sub;
+use crate::cuda::bn254::four_step_transpose;
+use crate::cuda::bn254::four_step_twiddle;
 use crate::cuda::bn254::intt_raw;
 use crate::cuda::bn254::intt_raw_async;
 use crate::cuda::bn254::ntt_prepare;
+use crate::cuda::bn254::ntt_prepare_cached;
 use crate::cuda::bn254::ntt_raw;
 use crate::cuda::bn254::permutation_eval_h_l;
 use crate::cuda::bn254::permutation_eval_h_p1;
@@ -37,16 +52,210 @@ use crate::cuda::bn254::permutation_eval_h_p2;
 use crate::cuda::bn254::pick_from_buf;
 use crate::cuda::bn254::FieldOp;
 use crate::cuda::bn254_c;
-use crate::cuda::bn254_c::field_op_batch_mul_sum;
 use crate::cuda::bn254_c::lookup_eval_h;
 use crate::cuda::bn254_c::shuffle_eval_h;
+use crate::cuda::jit::batch_mul_sum;
+use crate::cuda_pk::CudaProvingKey;
+use crate::device::cuda::sync_debug;
+use crate::device::cuda::tag_buffer;
 use crate::device::cuda::to_result;
 use crate::device::cuda::CudaBuffer;
 use crate::device::cuda::CudaDevice;
 use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::cuda::CudaGraph;
+use crate::device::cuda::CudaGraphExec;
+use crate::device::cuda::CudaStream;
+use crate::device::cuda::TypedBuffer;
 use crate::device::Device as _;
 use crate::device::DeviceResult;
 use crate::hugetlb::HugePageAllocator;
+use crate::metrics::count_path;
+use crate::metrics::time_kernel;
+use crate::phases::Challenge;
+use crate::phases::Challenges;
+use crate::plan::analyze_expr_tree;
+use crate::Error;
+
+#[cfg(test)]
+mod test;
+
+thread_local! {
+    static INTERMEDIATE_DOMAIN: Cell<bool> = Cell::new(false);
+    static COSET_SLICED_H: Cell<bool> = Cell::new(false);
+    static EXPR_STREAMS: Cell<usize> = Cell::new(1);
+    static CUDA_GRAPHS: Cell<bool> = Cell::new(false);
+    static MULTI_DEVICE_FFT: Cell<bool> = Cell::new(true);
+}
+
+lazy_static! {
+    // (device, scalar, extended_k, blinding factors) -> extended l_active_row, it only depends
+    // on the domain and the blinding factors so proving keys of the same shape share it
+    static ref L_ACTIVE_ROW_CACHE: DeviceBufCache<(usize, TypeId, u32, usize)> =
+        DeviceBufCache::new();
+    // graphs of `coeff_to_extended_coset` no running evaluate_h holds
+    static ref EXTEND_GRAPHS: Mutex<HashMap<ExtendGraphKey, Vec<ExtendGraph>>> =
+        Mutex::new(HashMap::new());
+}
+
+// (device, scalar, k, extended_k, coset_powers_n), what the launches depend on
+// besides the buffers
+type ExtendGraphKey = (usize, TypeId, usize, usize, usize);
+
+// `coeff_to_extended_coset` captured on the first column of the first proof
+// of its shape and replayed for the other columns and proofs, its launches
+// pointed at the buffers of each column through the parameters of its nodes
+struct ExtendGraph {
+    key: ExtendGraphKey,
+    // `CudaDevice::epoch` at the capture
+    epoch: usize,
+    stream: CudaStream,
+    graph: CudaGraph,
+    exec: CudaGraphExec,
+    // buf, tmp, coset powers, pq and omegas the capture ran on, which the
+    // nodes of `graph` keep pointing at, and their sizes in bytes
+    captured: [usize; 5],
+    bytes: [usize; 5],
+    // the same buffers of the last replay
+    bound: [usize; 5],
+    // whether the ntt left its result in tmp
+    swapped: bool,
+}
+
+// the extend graph a context holds, given back to `EXTEND_GRAPHS` when it is dropped
+#[derive(Default)]
+struct ExtendGraphSlot(Option<ExtendGraph>);
+
+impl Drop for ExtendGraphSlot {
+    fn drop(&mut self) {
+        if let Some(graph) = self.0.take() {
+            EXTEND_GRAPHS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(graph.key)
+                .or_default()
+                .push(graph);
+        }
+    }
+}
+
+/// `pk.l_active_row` on device, in the extended coset form the permutation,
+/// lookup and shuffle contributions to h multiply by.
+pub(crate) struct ExtendedLActiveRow<F> {
+    buf: Arc<CudaDeviceBufRaw>,
+    len: usize,
+    _marker: PhantomData<F>,
+}
+
+impl<F> TypedBuffer<F> for ExtendedLActiveRow<F> {
+    fn raw(&self) -> &CudaDeviceBufRaw {
+        &self.buf
+    }
+
+    fn typed_len(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+impl<F> ExtendedLActiveRow<F> {
+    pub(crate) fn ptr(&self) -> *mut std::ffi::c_void {
+        self.buf.ptr()
+    }
+}
+
+/// Uploads `pk.l_active_row` on the first proof with its domain on `device`
+/// and reuses it afterwards, see `clear_pk_device_cache`.
+pub(crate) fn extended_l_active_row<C: CurveAffine>(
+    device: &CudaDevice,
+    pk: &ProvingKey<C>,
+) -> DeviceResult<ExtendedLActiveRow<C::Scalar>> {
+    let values = &pk.l_active_row.values[..];
+    let key = (
+        device.device_id(),
+        TypeId::of::<C::Scalar>(),
+        pk.vk.domain.extended_k(),
+        pk.vk.cs.blinding_factors(),
+    );
+
+    let [buf] = L_ACTIVE_ROW_CACHE.get_or_try_insert(key, || {
+        let buf = device.alloc_device_buffer_from_slice(values)?;
+        tag_buffer(&buf, "l_active_row cache");
+        Ok([buf])
+    })?;
+
+    Ok(ExtendedLActiveRow {
+        buf,
+        len: values.len(),
+        _marker: PhantomData,
+    })
+}
+
+/// Frees the device buffers kept across proofs for proving keys, currently the
+/// extended `l_active_row` of every domain proven on. Buffers still used by a
+/// running proof are freed when it completes.
+pub fn clear_pk_device_cache() {
+    L_ACTIVE_ROW_CACHE.clear();
+    EXTEND_GRAPHS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
+
+/// `clear_pk_device_cache` for `device_id` only.
+pub(crate) fn clear_device_pk_cache(device_id: usize) {
+    L_ACTIVE_ROW_CACHE.clear_where(|key| key.0 == device_id);
+    EXTEND_GRAPHS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|key, _| key.0 != device_id);
+}
+
+/// Evaluate expressions whose terms have degree at most 2 in the 2n domain and
+/// lift the result to the extended domain once, see `ProverConfig::intermediate_domain`.
+pub(crate) fn set_intermediate_domain(enabled: bool) {
+    INTERMEDIATE_DOMAIN.with(|x| x.set(enabled));
+}
+
+/// Evaluate h on one coset of the base domain at a time instead of on the
+/// whole extended coset, see `ProverConfig::coset_sliced_h`.
+pub(crate) fn set_coset_sliced_h(enabled: bool) {
+    COSET_SLICED_H.with(|x| x.set(enabled));
+}
+
+/// Spread the gate groups of evaluate_h over this many streams, see
+/// `ProverConfig::expr_streams`.
+pub(crate) fn set_expr_streams(streams: usize) {
+    EXPR_STREAMS.with(|x| x.set(streams.max(1)));
+}
+
+/// Replay the extension of each column to the extended coset from a CUDA
+/// graph, see `ProverConfig::cuda_graphs`.
+pub(crate) fn set_cuda_graphs(enabled: bool) {
+    CUDA_GRAPHS.with(|x| x.set(enabled));
+}
+
+/// Extend columns through `do_extended_fft` when the device is short of
+/// memory, see `ProverConfig::multi_device_fft`.
+pub(crate) fn set_multi_device_fft(enabled: bool) {
+    MULTI_DEVICE_FFT.with(|x| x.set(enabled));
+}
+
+// twiddles of the plain (not coset) 2n domain
+struct HalfDomain {
+    ntt_omegas_buf: Arc<CudaDeviceBufRaw>,
+    ntt_pq_buf: Arc<CudaDeviceBufRaw>,
+    intt_omegas_buf: Arc<CudaDeviceBufRaw>,
+    intt_pq_buf: Arc<CudaDeviceBufRaw>,
+    divisor_buf: CudaDeviceBufRaw,
+}
+
+// coefficient-form columns already on device, by column index
+#[derive(Clone, Default)]
+struct ResidentColumns {
+    // kept since their intt in this proof
+    advices: Arc<Vec<CudaDeviceBufRaw>>,
+    // uploaded with a `CudaProvingKey`
+    fixed: Arc<Vec<CudaDeviceBufRaw>>,
+}
 
 struct EvalHContext<F: FieldExt> {
     y: Vec<F>,
@@ -55,12 +264,113 @@ struct EvalHContext<F: FieldExt> {
     k: usize,
     size: usize,
     extended_size: usize,
-    extended_ntt_omegas_buf: CudaDeviceBufRaw,
-    extended_ntt_pq_buf: CudaDeviceBufRaw,
+    extended_ntt_omegas_buf: Arc<CudaDeviceBufRaw>,
+    extended_ntt_pq_buf: Arc<CudaDeviceBufRaw>,
     coset_powers_buf: CudaDeviceBufRaw,
+    coset_powers_n: usize,
+    // generator of the coset columns are evaluated on
+    coset: F,
+    // coset-extended fixed columns prepared outside this proof, never recycled
+    shared_fixed: Arc<BTreeMap<usize, CudaDeviceBufRaw>>,
+    resident: ResidentColumns,
+    half_omega: F,
+    half_domain: Option<HalfDomain>,
+    domain: EvaluationDomain<F>,
+    extend_graph: ExtendGraphSlot,
 }
 
 impl<F: FieldExt> EvalHContext<F> {
+    fn new<C: CurveAffine<ScalarExt = F>>(
+        device: &CudaDevice,
+        pk: &ProvingKey<C>,
+        y: F,
+        shared_fixed: Arc<BTreeMap<usize, CudaDeviceBufRaw>>,
+    ) -> DeviceResult<Self> {
+        Self::from_domain(device, &pk.vk.domain, y, shared_fixed)
+    }
+
+    fn from_domain(
+        device: &CudaDevice,
+        domain: &EvaluationDomain<F>,
+        y: F,
+        shared_fixed: Arc<BTreeMap<usize, CudaDeviceBufRaw>>,
+    ) -> DeviceResult<Self> {
+        let k = domain.k() as usize;
+        let extended_k = domain.extended_k() as usize;
+        let extended_omega = domain.get_extended_omega();
+
+        let (extended_ntt_omegas_buf, extended_ntt_pq_buf) =
+            ntt_prepare_cached(device, extended_omega, extended_k)?;
+        let coset_powers_buf =
+            device.alloc_device_buffer_from_slice(&[domain.g_coset, domain.g_coset_inv])?;
+        let mut half_omega = extended_omega;
+        for _ in k + 1..extended_k {
+            half_omega = half_omega.square();
+        }
+
+        Ok(EvalHContext {
+            y: vec![F::one(), y],
+            extended_allocator: vec![],
+            k,
+            extended_k,
+            size: 1 << k,
+            extended_size: 1 << extended_k,
+            extended_ntt_omegas_buf,
+            extended_ntt_pq_buf,
+            coset_powers_buf,
+            coset_powers_n: 3,
+            coset: F::ZETA,
+            shared_fixed,
+            resident: ResidentColumns::default(),
+            half_omega,
+            half_domain: None,
+            domain: domain.clone(),
+            extend_graph: ExtendGraphSlot::default(),
+        })
+    }
+
+    /// Context of the `slice`-th coset `g * extended_omega^slice * <omega>` of
+    /// the base domain, whose union over the slices is the extended coset. The
+    /// extended buffers are domain sized and rotations are plain row shifts.
+    fn new_coset_slice<C: CurveAffine<ScalarExt = F>>(
+        device: &CudaDevice,
+        pk: &ProvingKey<C>,
+        y: F,
+        slice: usize,
+    ) -> DeviceResult<Self> {
+        let domain = &pk.vk.domain;
+        let mut ctx = Self::new(device, pk, y, Arc::new(BTreeMap::new()))?;
+        let coset = domain.g_coset * domain.get_extended_omega().pow_vartime(&[slice as u64]);
+        let (omegas_buf, pq_buf) = ntt_prepare_cached(device, domain.get_omega(), ctx.k)?;
+        ctx.extended_k = ctx.k;
+        ctx.extended_size = ctx.size;
+        ctx.extended_ntt_omegas_buf = omegas_buf;
+        ctx.extended_ntt_pq_buf = pq_buf;
+        ctx.coset_powers_buf = coset_powers(device, coset, ctx.size)?;
+        ctx.coset_powers_n = ctx.size;
+        ctx.coset = coset;
+        Ok(ctx)
+    }
+
+    fn half_domain(&mut self, device: &CudaDevice) -> DeviceResult<&HalfDomain> {
+        if self.half_domain.is_none() {
+            let (ntt_omegas_buf, ntt_pq_buf) =
+                ntt_prepare_cached(device, self.half_omega, self.k + 1)?;
+            let (intt_omegas_buf, intt_pq_buf) =
+                ntt_prepare_cached(device, self.half_omega.invert().unwrap(), self.k + 1)?;
+            let divisor = F::from((self.size << 1) as u64).invert().unwrap();
+            let divisor_buf = device.alloc_device_buffer_from_slice(&[divisor][..])?;
+            self.half_domain = Some(HalfDomain {
+                ntt_omegas_buf,
+                ntt_pq_buf,
+                intt_omegas_buf,
+                intt_pq_buf,
+                divisor_buf,
+            });
+        }
+        Ok(self.half_domain.as_ref().unwrap())
+    }
+
     fn alloc(&mut self, device: &CudaDevice) -> DeviceResult<CudaDeviceBufRaw> {
         let buf = self.extended_allocator.pop();
         if buf.is_none() {
@@ -71,54 +381,51 @@ impl<F: FieldExt> EvalHContext<F> {
     }
 }
 
-pub(crate) fn analyze_expr_tree<F: FieldExt>(
-    expr: &ProveExpression<F>,
-    k: usize,
-) -> Vec<Vec<(BTreeMap<ProveExpressionUnit, u32>, BTreeMap<u32, F>)>> {
-    let tree = expr.clone().flatten();
-    let tree = tree
-        .into_iter()
-        .map(|(us, v)| {
-            let mut map = BTreeMap::new();
-            for mut u in us {
-                if let Some(c) = map.get_mut(&mut u) {
-                    *c = *c + 1;
-                } else {
-                    map.insert(u.clone(), 1);
-                }
-            }
-            (map, v.clone())
-        })
-        .collect::<Vec<_, _>>();
-
-    let limit = if k < 23 { 26 } else { 10 };
-    let mut v = HashSet::new();
-
-    let mut expr_group = vec![];
-    let mut expr_groups = vec![];
-    for (_, (units, coeff)) in tree.iter().enumerate() {
-        let mut v_new = v.clone();
-        let mut v_new_clean = HashSet::new();
-        let mut muls_new = 0;
-        for (unit, exp) in units {
-            v_new.insert(unit.get_group());
-            v_new_clean.insert(unit.get_group());
-            muls_new += exp;
-        }
-
-        if v_new.len() > limit {
-            v = v_new_clean;
-
-            expr_groups.push(expr_group);
-            expr_group = vec![(units.clone(), coeff.clone())];
-        } else {
-            v = v_new;
-            expr_group.push((units.clone(), coeff.clone()));
-        }
-    }
+// `[c, c^2, .., c^n]`, the powers `extended_prepare` multiplies n coefficients by
+fn coset_powers<F: FieldExt>(
+    device: &CudaDevice,
+    c: F,
+    n: usize,
+) -> DeviceResult<CudaDeviceBufRaw> {
+    let buf = device.alloc_device_buffer::<F>(n)?;
+    device.copy_from_host_to_device(&buf, &[F::one(), c][..])?;
+    let err = unsafe { bn254_c::expand_omega_buffer(buf.ptr(), n as i32) };
+    to_result((), err, "fail to run expand_omega_buffer")?;
+    let c_buf = device.alloc_device_buffer_from_slice(&[c][..])?;
+    field_op_v3(
+        device,
+        &buf,
+        Some(&buf),
+        Some(&c_buf),
+        None,
+        None,
+        n,
+        FieldOp::UOp,
+        None,
+    )?;
+    Ok(buf)
+}
 
-    expr_groups.push(expr_group);
-    expr_groups
+// slice of `pk.l_active_row` on the `slice`-th coset, see `EvalHContext::new_coset_slice`
+fn sliced_l_active_row<C: CurveAffine>(
+    device: &CudaDevice,
+    pk: &ProvingKey<C>,
+    slice: usize,
+) -> DeviceResult<ExtendedLActiveRow<C::Scalar>> {
+    let slices = 1 << (pk.vk.domain.extended_k() - pk.vk.domain.k());
+    let values = pk
+        .l_active_row
+        .values
+        .iter()
+        .skip(slice)
+        .step_by(slices)
+        .cloned()
+        .collect::<Vec<_>>();
+    Ok(ExtendedLActiveRow {
+        buf: Arc::new(device.alloc_device_buffer_from_slice(&values[..])?),
+        len: values.len(),
+        _marker: PhantomData,
+    })
 }
 
 pub fn _export_evaluate_h_gates<C: CurveAffine>(
@@ -142,7 +449,7 @@ pub fn _export_evaluate_h_gates<C: CurveAffine>(
     res: &mut [C::Scalar],
 ) {
     let device = CudaDevice::get_device(0).unwrap();
-    let (intt_omegas_buf, intt_pq_buf) = ntt_prepare(
+    let (intt_omegas_buf, intt_pq_buf) = ntt_prepare_cached(
         &device,
         pk.get_vk().domain.get_omega_inv(),
         pk.vk.domain.k() as usize,
@@ -165,15 +472,98 @@ pub fn _export_evaluate_h_gates<C: CurveAffine>(
         beta,
         gamma,
         theta,
-        intt_pq_buf,
-        intt_omegas_buf,
-        intt_divisor_buf,
+        &intt_pq_buf,
+        &intt_omegas_buf,
+        &intt_divisor_buf,
+        Arc::new(BTreeMap::new()),
+        Default::default(),
+        None,
+        None,
     )
     .unwrap();
 
     device.copy_from_device_to_host(res, &h_buf).unwrap();
 }
 
+/// Coset-extends fixed columns of `pk` ahead of time, so that evaluate_h of
+/// several proofs can read them without extending them again.
+pub(crate) fn extend_fixed_columns<C: CurveAffine>(
+    device: &CudaDevice,
+    pk: &ProvingKey<C>,
+    columns: impl IntoIterator<Item = usize>,
+) -> DeviceResult<BTreeMap<usize, CudaDeviceBufRaw>> {
+    let mut ctx = EvalHContext::new(device, pk, C::Scalar::one(), Arc::new(BTreeMap::new()))?;
+    let mut res = BTreeMap::new();
+    for column in columns {
+        // evaluate_h reads fixed columns in coefficient form
+        let buf = do_extended_ntt_v2(device, &mut ctx, &pk.fixed_polys[column].values[..])?;
+        res.insert(column, buf);
+    }
+    Ok(res)
+}
+
+/// Coset-extended `polys`, given in coefficient form.
+pub(crate) fn extend_polys<'a, C: CurveAffine>(
+    device: &CudaDevice,
+    pk: &ProvingKey<C>,
+    polys: impl IntoIterator<Item = &'a [C::Scalar]>,
+) -> DeviceResult<Vec<CudaDeviceBufRaw>> {
+    let mut ctx = EvalHContext::new(device, pk, C::Scalar::one(), Arc::new(BTreeMap::new()))?;
+    polys
+        .into_iter()
+        .map(|poly| do_extended_ntt_v2(device, &mut ctx, poly))
+        .collect()
+}
+
+/// Intt of host advice columns on device. The coefficients are written back
+/// to the host for the openings and kept in the returned buffers, handed to
+/// `evaluate_h_gates_and_vanishing_construct` in place of another upload.
+pub(crate) fn intt_resident<F: FieldExt>(
+    device: &CudaDevice,
+    values: Vec<&mut [F]>,
+    pq_buf: &CudaDeviceBufRaw,
+    omegas_buf: &CudaDeviceBufRaw,
+    divisor: &CudaDeviceBufRaw,
+    len_log: usize,
+) -> DeviceResult<Vec<CudaDeviceBufRaw>> {
+    const MAX_CONCURRENCY: usize = 3;
+
+    let size = 1 << len_log;
+    let mut tmp_bufs = (0..MAX_CONCURRENCY)
+        .map(|_| device.alloc_device_buffer::<F>(size))
+        .collect::<DeviceResult<Vec<_>>>()?;
+    let mut streams: Vec<Option<CudaStream>> = (0..MAX_CONCURRENCY).map(|_| None).collect();
+    let mut res = Vec::with_capacity(values.len());
+    for (i, value) in values.into_iter().enumerate() {
+        let idx = i % MAX_CONCURRENCY;
+        // the scratch buffer of idx is free once its last column is done
+        if let Some(stream) = streams[idx].take() {
+            stream.synchronize()?;
+        }
+        let stream = CudaStream::new(device)?;
+        let mut buf = device.alloc_device_buffer::<F>(size)?;
+        tag_buffer(&buf, "resident advice");
+        device.copy_from_host_to_device_async(&buf, &value[..], stream.raw())?;
+        intt_raw_async(
+            device,
+            &mut buf,
+            &mut tmp_bufs[idx],
+            pq_buf,
+            omegas_buf,
+            divisor,
+            len_log,
+            Some(stream.raw()),
+        )?;
+        device.copy_from_device_to_host_async(&mut value[..], &buf, stream.raw())?;
+        streams[idx] = Some(stream);
+        res.push(buf);
+    }
+    for stream in streams.into_iter().flatten() {
+        stream.synchronize()?;
+    }
+    Ok(res)
+}
+
 pub(crate) fn evaluate_h_gates_and_vanishing_construct<
     C: CurveAffine,
     E: EncodedChallenge<C>,
@@ -197,149 +587,152 @@ pub(crate) fn evaluate_h_gates_and_vanishing_construct<
     beta: C::Scalar,
     gamma: C::Scalar,
     theta: C::Scalar,
-    intt_pq_buf: CudaDeviceBufRaw,
-    intt_omegas_buf: CudaDeviceBufRaw,
-    intt_divisor_buf: CudaDeviceBufRaw,
+    intt_pq_buf: &CudaDeviceBufRaw,
+    intt_omegas_buf: &CudaDeviceBufRaw,
+    intt_divisor_buf: &CudaDeviceBufRaw,
     g_buf: &CudaDeviceBufRaw,
     transcript: &mut T,
-) -> DeviceResult<(C::Scalar, C::Scalar, Vec<C::Scalar, HugePageAllocator>)> {
+    challenges: &mut Challenges<C>,
+    shared_fixed: Arc<BTreeMap<usize, CudaDeviceBufRaw>>,
+    resident_advices: Vec<CudaDeviceBufRaw>,
+    cuda_pk: Option<&CudaProvingKey<C>>,
+) -> Result<(C::Scalar, C::Scalar, Vec<C::Scalar, HugePageAllocator>), Error> {
     let domain = &pk.vk.domain;
     let k = &pk.vk.domain.k();
     let size = 1 << k;
 
-    let (mut ctx, mut h_buf) = evaluate_h_gates_core(
-        &device,
-        pk,
-        fixed,
-        advice,
-        instance,
-        permutation_products,
-        lookup_products,
-        shuffle_products,
-        y,
-        beta,
-        gamma,
-        theta,
-        intt_pq_buf,
-        intt_omegas_buf,
-        intt_divisor_buf,
-    )
-    .unwrap();
-
-    // do vanishing construct
-
-    // divide zH
-    {
-        let t_evalutions_buf =
-            device.alloc_device_buffer_from_slice::<C::Scalar>(&domain.t_evaluations[..])?;
-
-        let err = unsafe {
-            bn254_c::field_mul_zip(
-                h_buf.ptr(),
-                t_evalutions_buf.ptr(),
-                domain.t_evaluations.len() as i32,
-                domain.extended_len() as i32,
-            )
-        };
-
-        to_result((), err, "failed to run field_mul_zip")?;
-    }
-
-    // intt
-    {
-        let intt_divisor_buf = device
-            .alloc_device_buffer_from_slice::<C::Scalar>(&[domain.extended_ifft_divisor])
-            .unwrap();
-
-        let (extended_intt_omegas_buf, extended_intt_pq_buf) = ntt_prepare(
+    let resident_advices = Arc::new(resident_advices);
+    let h_buf = if COSET_SLICED_H.with(|x| x.get()) {
+        count_path("coset_sliced_h");
+        evaluate_h_coset_sliced(
+            device,
+            pk,
+            fixed,
+            advice,
+            instance,
+            permutation_products,
+            lookup_products,
+            shuffle_products,
+            y,
+            beta,
+            gamma,
+            theta,
+            intt_pq_buf,
+            intt_omegas_buf,
+            intt_divisor_buf,
+            resident_advices,
+            cuda_pk,
+        )?
+    } else {
+        let (mut ctx, mut h_buf) = evaluate_h_gates_core(
             &device,
-            domain.extended_omega_inv,
-            pk.vk.domain.extended_k() as usize,
+            pk,
+            fixed,
+            advice,
+            instance,
+            permutation_products,
+            lookup_products,
+            shuffle_products,
+            y,
+            beta,
+            gamma,
+            theta,
+            intt_pq_buf,
+            intt_omegas_buf,
+            intt_divisor_buf,
+            shared_fixed,
+            resident_advices,
+            cuda_pk,
+            None,
         )?;
-        let mut tmp = ctx.alloc(&device)?;
+        // gates, permutation and lookups have read the advices, free them for the vanishing part
+        ctx.resident.advices = Arc::new(vec![]);
 
-        intt_raw(
-            &device,
-            &mut h_buf,
-            &mut tmp,
-            &extended_intt_pq_buf,
-            &extended_intt_omegas_buf,
-            &intt_divisor_buf,
-            pk.vk.domain.extended_k() as usize,
-        )?;
+        // do vanishing construct
 
-        let coset_powers_buf =
-            device.alloc_device_buffer_from_slice(&[domain.g_coset_inv, domain.g_coset])?;
+        // divide zH
+        {
+            let t_evalutions_buf =
+                device.alloc_device_buffer_from_slice::<C::Scalar>(&domain.t_evaluations[..])?;
+
+            let err = unsafe {
+                bn254_c::field_mul_zip(
+                    h_buf.ptr(),
+                    t_evalutions_buf.ptr(),
+                    domain.t_evaluations.len() as i32,
+                    domain.extended_len() as i32,
+                )
+            };
 
-        extended_intt_after(
-            &device,
-            &h_buf,
-            &coset_powers_buf,
-            3,
-            ctx.size,
-            ctx.extended_size,
-            None,
-        )?;
+            to_result((), err, "failed to run field_mul_zip")?;
+        }
+
+        // intt
+        {
+            let intt_divisor_buf = device
+                .alloc_device_buffer_from_slice::<C::Scalar>(&[domain.extended_ifft_divisor])
+                .unwrap();
+
+            let (extended_intt_omegas_buf, extended_intt_pq_buf) = ntt_prepare_cached(
+                &device,
+                domain.extended_omega_inv,
+                pk.vk.domain.extended_k() as usize,
+            )?;
+            let mut tmp = ctx.alloc(&device)?;
+
+            intt_raw(
+                &device,
+                &mut h_buf,
+                &mut tmp,
+                &extended_intt_pq_buf,
+                &extended_intt_omegas_buf,
+                &intt_divisor_buf,
+                pk.vk.domain.extended_k() as usize,
+            )?;
+
+            let coset_powers_buf =
+                device.alloc_device_buffer_from_slice(&[domain.g_coset_inv, domain.g_coset])?;
 
-        if ctx.size >= 1 << 23 {
-            ctx.extended_allocator.clear();
+            extended_intt_after(
+                &device,
+                &h_buf,
+                &coset_powers_buf,
+                3,
+                ctx.size,
+                ctx.extended_size,
+                None,
+            )?;
         }
+        h_buf
+    };
 
+    {
         let timer = start_timer!(|| format!("vanishing msm {}", domain.quotient_poly_degree));
         let mut buffers = vec![];
         for i in 0..domain.quotient_poly_degree as usize {
-            let s_buf = unsafe {
-                ManuallyDrop::new(CudaDeviceBufRaw {
-                    ptr: h_buf
-                        .ptr()
-                        .offset((i * size * core::mem::size_of::<C::Scalar>()) as isize),
-                    device: device.clone(),
-                    size: size * core::mem::size_of::<C::Scalar>(),
-                })
-            };
-            buffers.push(s_buf);
+            buffers.push(h_buf.slice::<C::Scalar>(i * size, size)?);
         }
 
-        let commitments = crate::cuda::bn254::batch_msm_v2(
-            &g_buf,
-            buffers.iter().map(|x| x as &CudaDeviceBufRaw).collect(),
-            size,
-        )?;
+        let commitments =
+            crate::cuda::bn254::batch_msm_v2(&g_buf, buffers.iter().map(|x| &**x).collect(), size)?;
         for commitment in commitments {
-            transcript.write_point(commitment).unwrap();
+            challenges.write_point(transcript, commitment)?;
         }
         end_timer!(timer);
     }
 
-    let x: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
+    let x: C::Scalar = challenges.squeeze(transcript, Challenge::X)?;
     let xn = x.pow_vartime(&[1u64 << k]);
 
     let mut h_pieces = Vec::new_in(HugePageAllocator);
     h_pieces.resize(size, C::Scalar::zero());
     // pre-compute h_pieces for multi open
     {
-        let last_ptr = unsafe {
-            ManuallyDrop::new(CudaDeviceBufRaw {
-                ptr: h_buf.ptr().offset(
-                    ((domain.quotient_poly_degree as usize - 1)
-                        * size
-                        * core::mem::size_of::<C::Scalar>()) as isize,
-                ),
-                device: device.clone(),
-                size: size * core::mem::size_of::<C::Scalar>(),
-            })
-        };
+        let last_ptr =
+            h_buf.slice::<C::Scalar>((domain.quotient_poly_degree as usize - 1) * size, size)?;
         let xn_buf = device.alloc_device_buffer_from_slice(&[xn][..])?;
         for i in (0..(domain.quotient_poly_degree - 1) as usize).rev() {
-            let curr_ptr = unsafe {
-                ManuallyDrop::new(CudaDeviceBufRaw {
-                    ptr: h_buf
-                        .ptr()
-                        .offset((i * size * core::mem::size_of::<C::Scalar>()) as isize),
-                    device: device.clone(),
-                    size: size * core::mem::size_of::<C::Scalar>(),
-                })
-            };
+            let curr_ptr = h_buf.slice::<C::Scalar>(i * size, size)?;
             field_op_v3(
                 device,
                 &last_ptr,
@@ -358,6 +751,143 @@ pub(crate) fn evaluate_h_gates_and_vanishing_construct<
     Ok((x, xn, h_pieces))
 }
 
+// h in coefficient form, evaluated one coset of the base domain at a time.
+// With `h = sum_t X^(tn) H_t` and `c = zeta^n` on the coset of generator zeta,
+// the values of h on that coset interpolate to `sum_t c^t H_t`, so the pieces
+// `H_t` are recovered by an inverse dft of size `slices` over the cosets.
+fn evaluate_h_coset_sliced<C: CurveAffine>(
+    device: &CudaDevice,
+    pk: &ProvingKey<C>,
+    fixed: &[&[C::Scalar]],
+    advice: &[&[C::Scalar]],
+    instance: &[&[C::Scalar]],
+    permutation_products: &[&[C::Scalar]],
+    lookup_products: &mut [(
+        &mut [C::Scalar],
+        &mut [C::Scalar],
+        &mut [C::Scalar],
+        &mut [C::Scalar],
+        &mut [C::Scalar],
+    )],
+    shuffle_products: &[&[C::Scalar]],
+    y: C::Scalar,
+    beta: C::Scalar,
+    gamma: C::Scalar,
+    theta: C::Scalar,
+    intt_pq_buf: &CudaDeviceBufRaw,
+    intt_omegas_buf: &CudaDeviceBufRaw,
+    intt_divisor_buf: &CudaDeviceBufRaw,
+    resident_advices: Arc<Vec<CudaDeviceBufRaw>>,
+    cuda_pk: Option<&CudaProvingKey<C>>,
+) -> DeviceResult<CudaDeviceBufRaw> {
+    let domain = &pk.vk.domain;
+    let k = domain.k() as usize;
+    let size = 1 << k;
+    let slices = 1 << (domain.extended_k() as usize - k);
+    let pieces = domain.quotient_poly_degree as usize;
+    let slices_inv = C::Scalar::from(slices as u64).invert().unwrap();
+
+    let h_buf = device.alloc_device_buffer::<C::Scalar>(domain.extended_len())?;
+    unsafe {
+        cudaMemset(
+            h_buf.ptr(),
+            0,
+            domain.extended_len() * core::mem::size_of::<C::Scalar>(),
+        );
+    }
+    let mut tmp = device.alloc_device_buffer::<C::Scalar>(size)?;
+    for slice in 0..slices {
+        let timer = start_timer!(|| format!("evaluate_h coset slice {}", slice));
+        let (ctx, mut slice_buf) = evaluate_h_gates_core(
+            device,
+            pk,
+            fixed,
+            advice,
+            instance,
+            permutation_products,
+            lookup_products,
+            shuffle_products,
+            y,
+            beta,
+            gamma,
+            theta,
+            intt_pq_buf,
+            intt_omegas_buf,
+            intt_divisor_buf,
+            Arc::new(BTreeMap::new()),
+            resident_advices.clone(),
+            cuda_pk,
+            Some(slice),
+        )?;
+        let coset = ctx.coset;
+        drop(ctx);
+
+        // divide zH, constant on the coset
+        let t_buf =
+            device.alloc_device_buffer_from_slice(&domain.t_evaluations[slice..slice + 1])?;
+        field_op_v3(
+            device,
+            &slice_buf,
+            Some(&slice_buf),
+            Some(&t_buf),
+            None,
+            None,
+            size,
+            FieldOp::UOp,
+            None,
+        )?;
+
+        // values on the coset to coefficients of sum_t c^t H_t
+        intt_raw(
+            device,
+            &mut slice_buf,
+            &mut tmp,
+            intt_pq_buf,
+            intt_omegas_buf,
+            intt_divisor_buf,
+            k,
+        )?;
+        let coset_inv_powers = coset_powers(device, coset.invert().unwrap(), size)?;
+        extended_intt_after(
+            device,
+            &slice_buf,
+            &coset_inv_powers,
+            size,
+            size,
+            size,
+            None,
+        )?;
+
+        // H_t += c^-t / slices * (sum_t c^t H_t)
+        let c_inv = coset.pow_vartime(&[size as u64]).invert().unwrap();
+        let mut scale = slices_inv;
+        let mut scales = vec![];
+        for _ in 0..pieces {
+            scales.push(scale);
+            scale *= c_inv;
+        }
+        let scales_buf = device.alloc_device_buffer_from_slice(&scales[..])?;
+        for t in 0..pieces {
+            let piece = h_buf.slice::<C::Scalar>(t * size, size)?;
+            let scale = scales_buf.slice::<C::Scalar>(t, 1)?;
+            field_op_v3(
+                device,
+                &piece,
+                Some(&piece),
+                None,
+                Some(&slice_buf),
+                Some(&scale),
+                size,
+                FieldOp::Add,
+                None,
+            )?;
+        }
+        end_timer!(timer);
+    }
+
+    Ok(h_buf)
+}
+
 fn evaluate_h_gates_core<C: CurveAffine>(
     device: &CudaDevice,
     pk: &ProvingKey<C>,
@@ -377,45 +907,54 @@ fn evaluate_h_gates_core<C: CurveAffine>(
     beta: C::Scalar,
     gamma: C::Scalar,
     theta: C::Scalar,
-    intt_pq_buf: CudaDeviceBufRaw,
-    intt_omegas_buf: CudaDeviceBufRaw,
-    intt_divisor_buf: CudaDeviceBufRaw,
+    intt_pq_buf: &CudaDeviceBufRaw,
+    intt_omegas_buf: &CudaDeviceBufRaw,
+    intt_divisor_buf: &CudaDeviceBufRaw,
+    shared_fixed: Arc<BTreeMap<usize, CudaDeviceBufRaw>>,
+    resident_advices: Arc<Vec<CudaDeviceBufRaw>>,
+    cuda_pk: Option<&CudaProvingKey<C>>,
+    slice: Option<usize>,
 ) -> DeviceResult<(EvalHContext<C::Scalar>, CudaDeviceBufRaw)> {
     let timer = start_timer!(|| "evaluate_h setup");
     let k = pk.get_vk().domain.k() as usize;
     let size = 1 << pk.get_vk().domain.k();
-    let extended_k = pk.get_vk().domain.extended_k() as usize;
-    let extended_size = 1 << extended_k;
-    let extended_omega = pk.vk.domain.get_extended_omega();
-
-    let (extended_ntt_omegas_buf, extended_ntt_pq_buf) =
-        ntt_prepare(device, extended_omega, extended_k)?;
-    let coset_powers_buf = device.alloc_device_buffer_from_slice(&[
-        pk.get_vk().domain.g_coset,
-        pk.get_vk().domain.g_coset_inv,
-    ])?;
-
-    let mut ctx = EvalHContext {
-        y: vec![C::Scalar::one(), y],
-        extended_allocator: vec![],
-        k,
-        extended_k,
-        size,
-        extended_size,
-        extended_ntt_omegas_buf,
-        extended_ntt_pq_buf,
-        coset_powers_buf,
+
+    let mut ctx = match slice {
+        Some(slice) => EvalHContext::new_coset_slice(device, pk, y, slice)?,
+        None => EvalHContext::new(device, pk, y, shared_fixed)?,
+    };
+    // rows of the extended buffers per row of the domain
+    let rot_scale: usize = 1 << (ctx.extended_k - k);
+    ctx.resident = ResidentColumns {
+        advices: resident_advices,
+        fixed: cuda_pk.map_or_else(Default::default, |x| x.fixed_polys.clone()),
     };
+    let resident = ctx.resident.clone();
     end_timer!(timer);
 
     let timer = start_timer!(|| "evaluate_h gates");
     if pk.ev.gpu_gates_expr.len() != 1 {
-        println!("Multi-GPU detected, please set CUDA_VISIBLE_DEVICES to use one GPU");
-        assert!(false);
+        tracing::error!("Multi-GPU detected, please set CUDA_VISIBLE_DEVICES to use one GPU");
+        return Err(crate::device::Error::InvalidInput(format!(
+            "proving key has gates expressions for {} gpus, expect 1",
+            pk.ev.gpu_gates_expr.len()
+        )));
     }
-    let exprs = analyze_expr_tree(&pk.ev.gpu_gates_expr[0], k);
-    let h_buf =
-        evaluate_prove_expr_with_async_ntt(device, &exprs, fixed, advice, instance, &mut ctx)?;
+    let analyzed;
+    let exprs = match cuda_pk {
+        Some(cuda_pk) => &cuda_pk.gates,
+        None => {
+            analyzed = analyze_expr_tree(&pk.ev.gpu_gates_expr[0], k);
+            &analyzed
+        }
+    };
+    let streams = EXPR_STREAMS.with(|x| x.get());
+    let h_buf = if streams > 1 {
+        count_path("expr_streams");
+        evaluate_prove_expr_on_streams(device, exprs, fixed, advice, instance, &mut ctx, streams)?
+    } else {
+        evaluate_prove_expr_with_async_ntt(device, exprs, fixed, advice, instance, &mut ctx)?
+    };
     end_timer!(timer);
 
     let timer = start_timer!(|| "evaluate_h prepare buffers for constants");
@@ -423,18 +962,35 @@ fn evaluate_h_gates_core<C: CurveAffine>(
     let beta_buf = device.alloc_device_buffer_from_slice(&[beta][..])?;
     let gamma_buf = device.alloc_device_buffer_from_slice(&[gamma][..])?;
 
-    let l0 = &pk.l0;
-    let l_last = &pk.l_last;
-    let l_active_row = &pk.l_active_row;
-    let l0_buf = do_extended_ntt_v2(device, &mut ctx, &l0.values[..])?;
-    let l_last_buf = do_extended_ntt_v2(device, &mut ctx, &l_last.values[..])?;
-    let l_active_buf = device.alloc_device_buffer_from_slice(&l_active_row.values[..])?;
+    let extended;
+    let (l0_buf, l_last_buf) = match cuda_pk.filter(|_| slice.is_none()) {
+        Some(cuda_pk) => (&cuda_pk.l0, &cuda_pk.l_last),
+        None => {
+            extended = [
+                do_extended_ntt_v2(device, &mut ctx, &pk.l0.values[..])?,
+                do_extended_ntt_v2(device, &mut ctx, &pk.l_last.values[..])?,
+            ];
+            (&extended[0], &extended[1])
+        }
+    };
+    let cached_l_active;
+    let l_active_buf = match (cuda_pk, slice) {
+        (_, Some(slice)) => {
+            cached_l_active = sliced_l_active_row(device, pk, slice)?;
+            &cached_l_active
+        }
+        (Some(cuda_pk), None) => &cuda_pk.l_active_row,
+        (None, None) => {
+            cached_l_active = extended_l_active_row(device, pk)?;
+            &cached_l_active
+        }
+    };
     end_timer!(timer);
 
     let timer = start_timer!(|| "evaluate_h permutation");
     if permutation_products.len() > 0 {
         let blinding_factors = pk.vk.cs.blinding_factors();
-        let last_rotation = (ctx.size - (blinding_factors + 1)) << (extended_k - k);
+        let last_rotation = (ctx.size - (blinding_factors + 1)) * rot_scale;
         let chunk_len = pk.vk.cs.degree() - 2;
 
         let extended_p_buf = permutation_products
@@ -448,8 +1004,8 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                 &h_buf,
                 extended_p_buf.first().unwrap(),
                 extended_p_buf.last().unwrap(),
-                &l0_buf,
-                &l_last_buf,
+                l0_buf,
+                l_last_buf,
                 &y_buf,
                 ctx.extended_size,
             )?;
@@ -458,45 +1014,77 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                 device,
                 &h_buf,
                 &extended_p_buf[..],
-                &l0_buf,
-                &l_last_buf,
+                l0_buf,
+                l_last_buf,
                 &y_buf,
                 last_rotation,
                 ctx.extended_size,
             )?;
 
-            let mut curr_delta = beta * &C::Scalar::ZETA;
+            let permutation_polys: Vec<Coeffs<C::Scalar>> = match cuda_pk {
+                Some(cuda_pk) => cuda_pk
+                    .permutation_polys
+                    .iter()
+                    .map(Coeffs::Device)
+                    .collect(),
+                None => pk
+                    .permutation
+                    .polys
+                    .iter()
+                    .map(|x| Coeffs::Host(&x.values[..]))
+                    .collect(),
+            };
+
+            // beta * X scaled like the coefficients of the coset
+            let mut curr_delta = beta * &ctx.coset;
             for ((extended_p_buf, columns), polys) in extended_p_buf
                 .into_iter()
                 .zip(pk.vk.cs.permutation.columns.chunks(chunk_len))
-                .zip(pk.permutation.polys.chunks(chunk_len))
+                .zip(permutation_polys.chunks(chunk_len))
             {
                 let l = ctx.alloc(device)?;
                 buffer_copy_with_shift::<C::Scalar>(
                     &device,
                     &l,
                     &extended_p_buf,
-                    1 << (extended_k - k),
+                    rot_scale as isize,
                     ctx.extended_size,
                 )?;
 
                 let r = extended_p_buf;
 
-                for (value, permutation) in columns
+                for ((value, coeffs), permutation) in columns
                     .iter()
                     .map(|&column| match column.column_type() {
-                        Any::Advice => &advice[column.index()],
-                        Any::Fixed => &fixed[column.index()],
-                        Any::Instance => &instance[column.index()],
+                        Any::Advice => {
+                            let value = advice[column.index()];
+                            let coeffs = resident
+                                .advices
+                                .get(column.index())
+                                .map_or(Coeffs::Host(value), Coeffs::Device);
+                            (value, coeffs)
+                        }
+                        Any::Fixed => {
+                            let value = fixed[column.index()];
+                            let coeffs = resident
+                                .fixed
+                                .get(column.index())
+                                .map_or(Coeffs::Host(value), Coeffs::Device);
+                            (value, coeffs)
+                        }
+                        Any::Instance => (
+                            instance[column.index()],
+                            Coeffs::Host(instance[column.index()]),
+                        ),
                     })
                     .zip(polys.iter())
                 {
                     let mut l_res = ctx.alloc(device)?;
                     let mut r_res = ctx.alloc(device)?;
                     let p_coset_buf = ctx.alloc(device)?;
-                    device.copy_from_host_to_device(&p_coset_buf, &permutation.values[..])?;
+                    upload_coeffs(device, &p_coset_buf, *permutation, ctx.size, None)?;
 
-                    device.copy_from_host_to_device(&l_res, value)?;
+                    upload_coeffs(device, &l_res, coeffs, ctx.size, None)?;
                     device
                         .copy_from_device_to_device::<C::Scalar>(&r_res, 0, &l_res, 0, ctx.size)?;
                     permutation_eval_h_l(
@@ -508,7 +1096,7 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                         ctx.size,
                     )?;
                     do_extended_ntt(&device, &mut ctx, &mut l_res)?;
-                    field_mul::<C::Scalar>(&device, &l, &l_res, ctx.extended_size)?;
+                    field_mul::<C::Scalar>(&device, &l, &l_res, ctx.extended_size, None)?;
 
                     do_extended_prepare(device, &mut ctx, &mut r_res, None)?;
                     let coeff =
@@ -517,7 +1105,7 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                     device.copy_from_host_to_device(&r_res, &short[..])?;
                     do_extended_ntt_pure(device, &mut ctx, &mut r_res)?;
 
-                    field_mul::<C::Scalar>(&device, &r, &r_res, ctx.extended_size)?;
+                    field_mul::<C::Scalar>(&device, &r, &r_res, ctx.extended_size, None)?;
                     curr_delta *= &C::Scalar::DELTA;
 
                     ctx.extended_allocator.push(l_res);
@@ -525,8 +1113,8 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                     ctx.extended_allocator.push(p_coset_buf);
                 }
 
-                field_sub::<C::Scalar>(&device, &l, &r, ctx.extended_size)?;
-                field_mul::<C::Scalar>(&device, &l, &l_active_buf, ctx.extended_size)?;
+                field_sub::<C::Scalar>(&device, &l, &r, ctx.extended_size, None)?;
+                field_mul::<C::Scalar>(&device, &l, l_active_buf, ctx.extended_size, None)?;
                 field_op_v2::<C::Scalar>(
                     &device,
                     &h_buf,
@@ -536,6 +1124,7 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                     None,
                     ctx.extended_size,
                     FieldOp::Add,
+                    None,
                 )?;
 
                 ctx.extended_allocator.push(l);
@@ -574,47 +1163,30 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                 None,
             )
         } else {
-            unsafe {
-                let mut stream = std::mem::zeroed();
-                let _ = cuda_runtime_sys::cudaStreamCreate(&mut stream);
-                let mut buf = ctx.alloc(device)?;
-                device.copy_from_host_to_device_async(&buf, &input, stream)?;
+            let stream = CudaStream::new(device)?;
+            let mut buf = ctx.alloc(device)?;
+            device.copy_from_host_to_device_async(&buf, &input, stream.raw())?;
 
-                let mut tmp_buf = ctx.alloc(device)?;
-                field_op_v3(
-                    device,
-                    &buf,
-                    Some(&buf),
-                    None,
-                    None,
-                    Some(&beta_buf),
-                    size,
-                    FieldOp::Add,
-                    Some(stream),
-                )?;
-                intt_raw_async(
-                    &device,
-                    &mut buf,
-                    &mut tmp_buf,
-                    &intt_pq_buf,
-                    &intt_omegas_buf,
-                    &intt_divisor_buf,
-                    k,
-                    Some(stream),
-                )?;
-                do_extended_prepare(device, &mut ctx, &mut buf, Some(stream))?;
-                ntt_raw(
-                    device,
-                    &mut buf,
-                    &mut tmp_buf,
-                    &ctx.extended_ntt_pq_buf,
-                    &ctx.extended_ntt_omegas_buf,
-                    ctx.extended_k,
-                    Some(stream),
-                )?;
+            field_op_v3(
+                device,
+                &buf,
+                Some(&buf),
+                None,
+                None,
+                Some(&beta_buf),
+                size,
+                FieldOp::Add,
+                Some(stream.raw()),
+            )?;
+            let tmp_buf = lagrange_to_extended_coset(
+                device,
+                &mut ctx,
+                &mut buf,
+                (intt_pq_buf, intt_omegas_buf, intt_divisor_buf),
+                Some(stream.raw()),
+            )?;
 
-                (buf, Some((stream, tmp_buf)))
-            }
+            (buf, Some((stream, tmp_buf)))
         };
 
         let (table_buf, stream_table) = if table_deg > 1 {
@@ -623,82 +1195,53 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                 None,
             )
         } else {
-            unsafe {
-                let mut stream = std::mem::zeroed();
-                let _ = cuda_runtime_sys::cudaStreamCreate(&mut stream);
-                let mut buf = ctx.alloc(device)?;
-                device.copy_from_host_to_device_async(&buf, &table, stream)?;
+            let stream = CudaStream::new(device)?;
+            let mut buf = ctx.alloc(device)?;
+            device.copy_from_host_to_device_async(&buf, &table, stream.raw())?;
 
-                let mut tmp_buf = ctx.alloc(device)?;
-                field_op_v3(
-                    device,
-                    &buf,
-                    Some(&buf),
-                    None,
-                    None,
-                    Some(&gamma_buf),
-                    size,
-                    FieldOp::Add,
-                    Some(stream),
-                )?;
-                intt_raw_async(
-                    &device,
-                    &mut buf,
-                    &mut tmp_buf,
-                    &intt_pq_buf,
-                    &intt_omegas_buf,
-                    &intt_divisor_buf,
-                    k,
-                    Some(stream),
-                )?;
-                do_extended_prepare(device, &mut ctx, &mut buf, Some(stream))?;
-                ntt_raw(
-                    device,
-                    &mut buf,
-                    &mut tmp_buf,
-                    &ctx.extended_ntt_pq_buf,
-                    &ctx.extended_ntt_omegas_buf,
-                    ctx.extended_k,
-                    Some(stream),
-                )?;
+            field_op_v3(
+                device,
+                &buf,
+                Some(&buf),
+                None,
+                None,
+                Some(&gamma_buf),
+                size,
+                FieldOp::Add,
+                Some(stream.raw()),
+            )?;
+            let tmp_buf = lagrange_to_extended_coset(
+                device,
+                &mut ctx,
+                &mut buf,
+                (intt_pq_buf, intt_omegas_buf, intt_divisor_buf),
+                Some(stream.raw()),
+            )?;
 
-                (buf, Some((stream, tmp_buf)))
-            }
+            (buf, Some((stream, tmp_buf)))
         };
 
-        let (z_buf, tmp2, stream0) = do_extended_ntt_v2_async(device, &mut ctx, *z)?;
+        let (z_buf, tmp2, stream0) =
+            do_extended_ntt_v2_async(device, &mut ctx, Coeffs::Host(&z[..]))?;
         let (permuted_input_buf, tmp0, stream1) =
-            do_extended_ntt_v2_async(device, &mut ctx, permuted_input)?;
+            do_extended_ntt_v2_async(device, &mut ctx, Coeffs::Host(&permuted_input[..]))?;
         let (permuted_table_buf, tmp1, stream2) =
-            do_extended_ntt_v2_async(device, &mut ctx, permuted_table)?;
-
-        unsafe {
-            cuda_runtime_sys::cudaStreamSynchronize(stream0);
-            cuda_runtime_sys::cudaStreamDestroy(stream0);
-            ctx.extended_allocator.push(tmp0);
-            cuda_runtime_sys::cudaStreamSynchronize(stream1);
-            cuda_runtime_sys::cudaStreamDestroy(stream1);
-            ctx.extended_allocator.push(tmp1);
-            cuda_runtime_sys::cudaStreamSynchronize(stream2);
-            cuda_runtime_sys::cudaStreamDestroy(stream2);
-            ctx.extended_allocator.push(tmp2);
-
-            if let Some((stream, buf)) = stream_input {
-                cuda_runtime_sys::cudaStreamSynchronize(stream);
-                cuda_runtime_sys::cudaStreamDestroy(stream);
-                ctx.extended_allocator.push(buf);
-            }
-
-            if let Some((stream, buf)) = stream_table {
-                cuda_runtime_sys::cudaStreamSynchronize(stream);
-                cuda_runtime_sys::cudaStreamDestroy(stream);
-                ctx.extended_allocator.push(buf);
-            }
+            do_extended_ntt_v2_async(device, &mut ctx, Coeffs::Host(&permuted_table[..]))?;
+
+        stream0.synchronize()?;
+        ctx.extended_allocator.push(tmp0);
+        stream1.synchronize()?;
+        ctx.extended_allocator.push(tmp1);
+        stream2.synchronize()?;
+        ctx.extended_allocator.push(tmp2);
+
+        for (stream, buf) in [stream_input, stream_table].into_iter().flatten() {
+            stream.synchronize()?;
+            ctx.extended_allocator.push(buf);
         }
 
+        let stream = CudaStream::new(device)?;
         unsafe {
-            let mut stream = std::mem::zeroed();
-            let _ = cuda_runtime_sys::cudaStreamCreate(&mut stream);
             let err = lookup_eval_h(
                 h_buf.ptr(),
                 input_buf.ptr(),
@@ -712,38 +1255,34 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                 y_buf.ptr(),
                 beta_buf.ptr(),
                 gamma_buf.ptr(),
-                1 << (extended_k - k),
+                rot_scale as i32,
                 ctx.extended_size as i32,
-                stream,
+                stream.raw(),
             );
 
             to_result((), err, "fail to run field_op_batch_mul_sum")?;
+        }
 
-            if let Some(stream) = last_stream.0 {
-                cuda_runtime_sys::cudaStreamSynchronize(stream);
-                cuda_runtime_sys::cudaStreamDestroy(stream);
-                ctx.extended_allocator.append(&mut last_stream.1)
-            }
-
-            last_stream = (
-                Some(stream),
-                vec![
-                    input_buf,
-                    table_buf,
-                    permuted_input_buf,
-                    permuted_table_buf,
-                    z_buf,
-                ],
-            );
+        if let Some(stream) = last_stream.0 {
+            stream.synchronize()?;
+            ctx.extended_allocator.append(&mut last_stream.1)
         }
+
+        last_stream = (
+            Some(stream),
+            vec![
+                input_buf,
+                table_buf,
+                permuted_input_buf,
+                permuted_table_buf,
+                z_buf,
+            ],
+        );
     }
 
     if let Some(stream) = last_stream.0 {
-        unsafe {
-            cuda_runtime_sys::cudaStreamSynchronize(stream);
-            cuda_runtime_sys::cudaStreamDestroy(stream);
-            ctx.extended_allocator.append(&mut last_stream.1)
-        }
+        stream.synchronize()?;
+        ctx.extended_allocator.append(&mut last_stream.1)
     }
     end_timer!(timer);
 
@@ -768,13 +1307,11 @@ fn evaluate_h_gates_core<C: CurveAffine>(
         let input_buf = evaluate_prove_expr(device, &vec![e1], fixed, advice, instance, &mut ctx)?;
         let table_buf = evaluate_prove_expr(device, &vec![e2], fixed, advice, instance, &mut ctx)?;
 
-        let (z_buf, tmp0, stream0) = do_extended_ntt_v2_async(device, &mut ctx, z)?;
+        let (z_buf, tmp0, stream0) =
+            do_extended_ntt_v2_async(device, &mut ctx, Coeffs::Host(&z[..]))?;
 
-        unsafe {
-            cuda_runtime_sys::cudaStreamSynchronize(stream0);
-            cuda_runtime_sys::cudaStreamDestroy(stream0);
-            ctx.extended_allocator.push(tmp0);
-        }
+        stream0.synchronize()?;
+        ctx.extended_allocator.push(tmp0);
 
         unsafe {
             let err = shuffle_eval_h(
@@ -786,7 +1323,7 @@ fn evaluate_h_gates_core<C: CurveAffine>(
                 l_last_buf.ptr(),
                 l_active_buf.ptr(),
                 y_buf.ptr(),
-                1 << (extended_k - k),
+                rot_scale as i32,
                 ctx.extended_size as i32,
             );
 
@@ -967,47 +1504,270 @@ fn flatten_shuffle_expression<F: FieldExt>(
     [expr_input, expr_table]
 }
 
+// Coefficients of a column, on the host or already on device.
+#[derive(Clone, Copy)]
+enum Coeffs<'a, F> {
+    Host(&'a [F]),
+    Device(&'a CudaDeviceBufRaw),
+}
+
+// `src` of `unit`, read from its resident buffer for columns kept on device.
+fn unit_coeffs<'a, F>(
+    unit: &ProveExpressionUnit,
+    src: &'a [F],
+    resident: &'a ResidentColumns,
+) -> Coeffs<'a, F> {
+    let buf = match unit {
+        ProveExpressionUnit::Advice { column_index, .. } => resident.advices.get(*column_index),
+        ProveExpressionUnit::Fixed { column_index, .. } => resident.fixed.get(*column_index),
+        ProveExpressionUnit::Instance { .. } => None,
+    };
+    buf.map_or(Coeffs::Host(src), Coeffs::Device)
+}
+
+fn upload_coeffs<F: FieldExt>(
+    device: &CudaDevice,
+    dst: &CudaDeviceBufRaw,
+    src: Coeffs<'_, F>,
+    size: usize,
+    stream: Option<cudaStream_t>,
+) -> DeviceResult<()> {
+    match (src, stream) {
+        (Coeffs::Host(data), None) => device.copy_from_host_to_device::<F>(dst, data),
+        (Coeffs::Host(data), Some(stream)) => {
+            device.copy_from_host_to_device_async::<F>(dst, data, stream)
+        }
+        (Coeffs::Device(buf), None) => device.copy_from_device_to_device::<F>(dst, 0, buf, 0, size),
+        (Coeffs::Device(buf), Some(stream)) => {
+            device.copy_from_device_to_device_async::<F>(dst, buf, size, stream)
+        }
+    }
+}
+
 fn do_extended_ntt_v2<F: FieldExt>(
     device: &CudaDevice,
     ctx: &mut EvalHContext<F>,
     data: &[F],
 ) -> DeviceResult<CudaDeviceBufRaw> {
-    let buf = ctx.extended_allocator.pop();
-    let mut buf = if buf.is_none() {
-        device.alloc_device_buffer::<F>(ctx.extended_size)?
+    do_extended_ntt_v2_coeffs(device, ctx, Coeffs::Host(data))
+}
+
+// The sharded extension only drops the tmp buffer: the column is staged on the
+// host and uploaded back as a full extended buffer, which is then held through
+// the evaluation like any other. It is taken when one extended buffer fits on
+// the device but the buffer and tmp of the in-place transform don't.
+fn sharded_extension_fits(available: usize, extended_bytes: usize) -> bool {
+    available >= extended_bytes && available < 2 * extended_bytes
+}
+
+fn do_extended_ntt_v2_coeffs<F: FieldExt>(
+    device: &CudaDevice,
+    ctx: &mut EvalHContext<F>,
+    data: Coeffs<'_, F>,
+) -> DeviceResult<CudaDeviceBufRaw> {
+    if let Coeffs::Host(coeffs) = data {
+        // the in-place transform needs a second extended buffer, without room for it
+        // the column is extended by `do_extended_fft`, sharded over the peers if any
+        if MULTI_DEVICE_FFT.with(|x| x.get())
+            && ctx.extended_k == ctx.domain.extended_k() as usize
+            && ctx.extended_allocator.is_empty()
+            && sharded_extension_fits(
+                device.available_memory()?,
+                ctx.extended_size * core::mem::size_of::<F>(),
+            )
+        {
+            let mut values = Vec::new_in(HugePageAllocator);
+            values.resize(ctx.extended_size, F::zero());
+            do_extended_fft(device, &ctx.domain, coeffs, &mut values[..])?;
+            let buf = ctx.alloc(device)?;
+            device.copy_from_host_to_device(&buf, &values[..])?;
+            return Ok(buf);
+        }
+    }
+
+    let mut buf = ctx.alloc(device)?;
+    upload_coeffs(device, &buf, data, ctx.size, None)?;
+    let tmp = if CUDA_GRAPHS.with(|x| x.get()) && !sync_debug() {
+        coeff_to_extended_coset_graph(device, ctx, &mut buf)?
     } else {
-        buf.unwrap()
+        let tmp = coeff_to_extended_coset(device, ctx, &mut buf, None)?;
+        device.synchronize()?;
+        tmp
     };
-    device.copy_from_host_to_device::<F>(&buf, data)?;
-    do_extended_ntt(device, ctx, &mut buf)?;
+    ctx.extended_allocator.push(tmp);
 
     Ok(buf)
 }
 
-fn do_extended_ntt_v2_async<F: FieldExt>(
+/// `coeff_to_extended_coset` replayed from a graph of its shape with one
+/// launch. The graph is captured on the first column of the first proof and
+/// bound to the buffers of the later ones through the parameters of its nodes.
+/// Work on the legacy default stream is ordered after the replay, the host
+/// doesn't wait for it.
+fn coeff_to_extended_coset_graph<F: FieldExt>(
+    device: &CudaDevice,
+    ctx: &mut EvalHContext<F>,
+    buf: &mut CudaDeviceBufRaw,
+) -> DeviceResult<CudaDeviceBufRaw> {
+    // drawn before the capture, which must not allocate
+    let mut tmp = ctx.alloc(device)?;
+    let key = (
+        device.device_id(),
+        TypeId::of::<F>(),
+        ctx.k,
+        ctx.extended_k,
+        ctx.coset_powers_n,
+    );
+    let buffers = [
+        &*buf,
+        &tmp,
+        &ctx.coset_powers_buf,
+        &*ctx.extended_ntt_pq_buf,
+        &*ctx.extended_ntt_omegas_buf,
+    ];
+    let bound = buffers.map(|x| x.ptr() as usize);
+    let bytes = buffers.map(|x| x.size);
+
+    let pooled = match ctx.extend_graph.0.take() {
+        Some(graph) => Some(graph),
+        None => {
+            let mut graphs = EXTEND_GRAPHS.lock()?;
+            let pool = graphs.entry(key).or_default();
+            // those of an earlier context of the device died with it
+            pool.retain(|graph| graph.epoch == device.epoch());
+            pool.pop()
+        }
+    };
+    let graph = match pooled {
+        Some(mut graph) => {
+            if graph.bound != bound {
+                device.acitve_ctx()?;
+                let captured = graph.captured.map(|x| x as *mut c_void);
+                let bound_ptrs = bound.map(|x| x as *mut c_void);
+                let res = unsafe {
+                    bn254_c::extend_graph_bind(
+                        graph.graph.raw(),
+                        graph.exec.raw(),
+                        captured.as_ptr(),
+                        bound_ptrs.as_ptr(),
+                        graph.bytes.as_ptr(),
+                        captured.len() as i32,
+                    )
+                };
+                to_result((), res, "fail to bind extend graph")?;
+                graph.bound = bound;
+            }
+            graph
+        }
+        None => {
+            let stream = CudaStream::new_non_blocking(device)?;
+            let mut ntt_tmp = Some(tmp);
+            let mut swapped = false;
+            let captured = stream.capture(|| {
+                do_extended_prepare(device, ctx, buf, Some(stream.raw()))?;
+                let before = buf.ptr();
+                let res = _do_extended_ntt_pure_async(
+                    device,
+                    ctx,
+                    buf,
+                    ntt_tmp.take(),
+                    Some(stream.raw()),
+                )?;
+                swapped = buf.ptr() != before;
+                ntt_tmp = Some(res);
+                Ok(())
+            })?;
+            tmp = ntt_tmp.unwrap();
+            // nothing ran yet, undo the swap of the capture, the replay redoes it below
+            if swapped {
+                std::mem::swap(buf, &mut tmp);
+            }
+            let exec = captured.instantiate()?;
+            ExtendGraph {
+                key,
+                epoch: device.epoch(),
+                stream,
+                graph: captured,
+                exec,
+                captured: bound,
+                bytes,
+                bound,
+                swapped,
+            }
+        }
+    };
+
+    // the coefficients were uploaded on the legacy default stream, and what
+    // reads the extended column is issued there too
+    let replayed = graph
+        .stream
+        .wait_event(&device.record_event(core::ptr::null_mut())?)
+        .and_then(|_| {
+            time_kernel("ntt", graph.stream.raw(), || {
+                graph.exec.launch(&graph.stream)
+            })
+        })
+        .and_then(|_| graph.stream.record_event())
+        .and_then(|done| device.wait_event(core::ptr::null_mut(), &done));
+    let swapped = graph.swapped;
+    ctx.extend_graph.0 = Some(graph);
+    replayed?;
+    count_path("cuda_graphs");
+    if swapped {
+        std::mem::swap(buf, &mut tmp);
+    }
+    Ok(tmp)
+}
+
+fn do_extended_ntt_v2_async<F: FieldExt>(
+    device: &CudaDevice,
+    ctx: &mut EvalHContext<F>,
+    data: Coeffs<'_, F>,
+) -> DeviceResult<(CudaDeviceBufRaw, CudaDeviceBufRaw, CudaStream)> {
+    let mut buf = ctx.alloc(device)?;
+    let stream = CudaStream::new(device)?;
+    upload_coeffs(device, &buf, data, ctx.size, Some(stream.raw()))?;
+    let tmp = coeff_to_extended_coset(device, ctx, &mut buf, Some(stream.raw()))?;
+
+    Ok((buf, tmp, stream))
+}
+
+/// Coefficients in the first `ctx.size` elements of `buf` to extended coset
+/// evaluations in place: zero-pad, coset multiply and NTT, all on device.
+/// Returns the scratch buffer drawn from the slab, to be given back once `stream` is done.
+fn coeff_to_extended_coset<F: FieldExt>(
     device: &CudaDevice,
     ctx: &mut EvalHContext<F>,
-    data: &[F],
-) -> DeviceResult<(CudaDeviceBufRaw, CudaDeviceBufRaw, *mut CUstream_st)> {
-    let buf = ctx.extended_allocator.pop();
-    let mut buf = if buf.is_none() {
-        device.alloc_device_buffer::<F>(ctx.extended_size)?
-    } else {
-        buf.unwrap()
-    };
-    let (tmp, stream) = unsafe {
-        let mut stream = std::mem::zeroed();
-        let err = cuda_runtime_sys::cudaStreamCreate(&mut stream);
-        assert_eq!(err, cuda_runtime_sys::cudaError::cudaSuccess);
-        device.copy_from_host_to_device_async::<F>(&buf, data, stream)?;
-        do_extended_prepare(device, ctx, &mut buf, Some(stream))?;
-        (
-            do_extended_ntt_pure_async(device, ctx, &mut buf, Some(stream))?,
-            stream,
-        )
-    };
+    buf: &mut CudaDeviceBufRaw,
+    stream: Option<cudaStream_t>,
+) -> DeviceResult<CudaDeviceBufRaw> {
+    do_extended_prepare(device, ctx, buf, stream)?;
+    do_extended_ntt_pure_async(device, ctx, buf, stream)
+}
 
-    Ok((buf, tmp, stream))
+/// Same as `coeff_to_extended_coset` for Lagrange evaluations, which are
+/// interpolated on device first with the `(pq, omegas, divisor)` intt buffers of the domain.
+fn lagrange_to_extended_coset<F: FieldExt>(
+    device: &CudaDevice,
+    ctx: &mut EvalHContext<F>,
+    buf: &mut CudaDeviceBufRaw,
+    intt: (&CudaDeviceBufRaw, &CudaDeviceBufRaw, &CudaDeviceBufRaw),
+    stream: Option<cudaStream_t>,
+) -> DeviceResult<CudaDeviceBufRaw> {
+    let (intt_pq_buf, intt_omegas_buf, intt_divisor_buf) = intt;
+    let mut tmp = ctx.alloc(device)?;
+    intt_raw_async(
+        device,
+        buf,
+        &mut tmp,
+        intt_pq_buf,
+        intt_omegas_buf,
+        intt_divisor_buf,
+        ctx.k,
+        stream,
+    )?;
+    do_extended_prepare(device, ctx, buf, stream)?;
+    _do_extended_ntt_pure_async(device, ctx, buf, Some(tmp), stream)
 }
 
 fn do_extended_prepare<F: FieldExt>(
@@ -1020,7 +1780,7 @@ fn do_extended_prepare<F: FieldExt>(
         device,
         data,
         &ctx.coset_powers_buf,
-        3,
+        ctx.coset_powers_n,
         ctx.size,
         ctx.extended_size,
         stream,
@@ -1082,6 +1842,341 @@ fn do_extended_ntt_pure<F: FieldExt>(
     Ok(())
 }
 
+/// Extended-domain NTT of host coefficients into host evaluations.
+/// Falls back to `do_extended_fft_multi` when `device` can't hold the extended buffers.
+pub fn do_extended_fft<F: FieldExt>(
+    device: &CudaDevice,
+    domain: &EvaluationDomain<F>,
+    coeffs: &[F],
+    res: &mut [F],
+) -> DeviceResult<()> {
+    let extended_k = domain.extended_k() as usize;
+    let extended_size = 1 << extended_k;
+    let required = 2 * extended_size * core::mem::size_of::<F>();
+
+    let free = device.available_memory()?;
+    let device_count = CudaDevice::get_device_count()?;
+    if free < required && device_count > 1 {
+        // the shards exchange blocks directly, only devices that are all peers of each other
+        let mut devices = vec![device.clone()];
+        for idx in 0..device_count {
+            let candidate = CudaDevice::get_device(idx)?;
+            if idx != device.device_id()
+                && devices.iter().try_fold(true, |all, x| -> DeviceResult<_> {
+                    Ok(all
+                        && x.capabilities()?.peers.contains(&idx)
+                        && candidate.capabilities()?.peers.contains(&x.device_id()))
+                })?
+            {
+                devices.push(candidate);
+            }
+        }
+        if devices.len() > 1 {
+            let n_devices =
+                (1 << (usize::BITS - 1 - devices.len().leading_zeros())).min(1 << (extended_k / 2));
+            devices.truncate(n_devices);
+            return do_extended_fft_multi(&devices[..], domain, coeffs, res);
+        }
+        device.downgrade("multi-device extended fft");
+    }
+
+    let mut buf = device.alloc_device_buffer::<F>(extended_size)?;
+    let mut tmp = device.alloc_device_buffer::<F>(extended_size)?;
+    let coset_powers_buf =
+        device.alloc_device_buffer_from_slice(&[domain.g_coset, domain.g_coset_inv])?;
+    let (omegas_buf, pq_buf) = ntt_prepare_cached(device, domain.get_extended_omega(), extended_k)?;
+    device.copy_from_host_to_device(&buf, coeffs)?;
+    extended_prepare(
+        device,
+        &buf,
+        &coset_powers_buf,
+        3,
+        coeffs.len(),
+        extended_size,
+        None,
+    )?;
+    ntt_raw(
+        device,
+        &mut buf,
+        &mut tmp,
+        &pq_buf,
+        &omegas_buf,
+        extended_k,
+        None,
+    )?;
+    device.copy_from_device_to_host(res, &buf)?;
+    Ok(())
+}
+
+struct FourStepShard {
+    // dropped first, waits for the work on the buffers below
+    stream: CudaStream,
+    device: CudaDevice,
+    buf: CudaDeviceBufRaw,
+    tmp: CudaDeviceBufRaw,
+    ntt_n1: (CudaDeviceBufRaw, CudaDeviceBufRaw),
+    ntt_n2: (CudaDeviceBufRaw, CudaDeviceBufRaw),
+    bases: CudaDeviceBufRaw,
+}
+
+fn four_step_sync(shards: &[FourStepShard]) -> DeviceResult<()> {
+    for shard in shards {
+        shard.stream.synchronize()?;
+    }
+    Ok(())
+}
+
+// Every shard holds `shards.len() * rows` rows of `width` elements in `tmp`;
+// block d of shard s lands in columns [s * width, (s + 1) * width) of shard d's `buf`.
+fn four_step_exchange<F: FieldExt>(
+    shards: &[FourStepShard],
+    rows: usize,
+    width: usize,
+) -> DeviceResult<()> {
+    four_step_sync(shards)?;
+    let unit = core::mem::size_of::<F>();
+    let n_shards = shards.len();
+    for (s, src) in shards.iter().enumerate() {
+        src.device.acitve_ctx()?;
+        for (d, dst) in shards.iter().enumerate() {
+            unsafe {
+                let err = cuda_runtime_sys::cudaMemcpy2DAsync(
+                    dst.buf.ptr().offset((s * width * unit) as isize),
+                    width * n_shards * unit,
+                    src.tmp.ptr().offset((d * rows * width * unit) as isize),
+                    width * unit,
+                    width * unit,
+                    rows,
+                    cuda_runtime_sys::cudaMemcpyKind::cudaMemcpyDefault,
+                    src.stream.raw(),
+                );
+                to_result((), err, "fail to exchange four-step blocks between devices")?;
+            }
+        }
+    }
+    four_step_sync(shards)
+}
+
+fn four_step_rows_ntt<F: FieldExt>(
+    shard: &mut FourStepShard,
+    rows: usize,
+    len_log: usize,
+    use_n1: bool,
+) -> DeviceResult<()> {
+    let unit = core::mem::size_of::<F>();
+    let (omegas_buf, pq_buf) = if use_n1 { &shard.ntt_n1 } else { &shard.ntt_n2 };
+
+    let mut swapped = false;
+    for row in 0..rows {
+        let offset = ((row << len_log) * unit) as isize;
+        let (buf_ptr, tmp_ptr) = unsafe {
+            (
+                shard.buf.ptr().offset(offset),
+                shard.tmp.ptr().offset(offset),
+            )
+        };
+        let mut s_view = ManuallyDrop::new(CudaDeviceBufRaw {
+            ptr: buf_ptr,
+            device: shard.device.clone(),
+            size: unit << len_log,
+            epoch: shard.buf.epoch,
+        });
+        let mut t_view = ManuallyDrop::new(CudaDeviceBufRaw {
+            ptr: tmp_ptr,
+            device: shard.device.clone(),
+            size: unit << len_log,
+            epoch: shard.buf.epoch,
+        });
+        ntt_raw(
+            &shard.device,
+            &mut s_view,
+            &mut t_view,
+            pq_buf,
+            omegas_buf,
+            len_log,
+            Some(shard.stream.raw()),
+        )?;
+        swapped = s_view.ptr() != buf_ptr;
+    }
+
+    if swapped {
+        std::mem::swap(&mut shard.buf, &mut shard.tmp);
+    }
+    Ok(())
+}
+
+/// Four-step extended-domain NTT sharded across `devices` (a power of two).
+///
+/// The extended buffer is viewed as an `n2 x n1` matrix: every device column-transforms
+/// its slice of rows, applies the twiddles, and exchanges blocks with its peers before
+/// the second pass, so each device only keeps `2 * extended_size / devices.len()` elements.
+pub fn do_extended_fft_multi<F: FieldExt>(
+    devices: &[CudaDevice],
+    domain: &EvaluationDomain<F>,
+    coeffs: &[F],
+    res: &mut [F],
+) -> DeviceResult<()> {
+    let extended_k = domain.extended_k() as usize;
+    let extended_size = 1 << extended_k;
+    let n_devices = devices.len();
+    let log_n1 = extended_k / 2;
+    let log_n2 = extended_k - log_n1;
+    let n1 = 1 << log_n1;
+    let n2 = 1 << log_n2;
+
+    if !n_devices.is_power_of_two() || n_devices > n1 {
+        return Err(crate::device::Error::InvalidInput(format!(
+            "four-step fft needs a power of two of at most {} devices, got {}",
+            n1, n_devices
+        )));
+    }
+    if coeffs.len() > extended_size || res.len() != extended_size {
+        return Err(crate::device::Error::InvalidInput(format!(
+            "four-step fft of {} coefficients into {} values, extended size is {}",
+            coeffs.len(),
+            res.len(),
+            extended_size
+        )));
+    }
+
+    let r1 = n1 / n_devices;
+    let r2 = n2 / n_devices;
+    let chunk = extended_size / n_devices;
+
+    let timer = start_timer!(|| format!("four-step extended fft on {} devices", n_devices));
+
+    // zero padding and coset shift, `res` doubles as the staging buffer
+    let coset_powers = [domain.g_coset, domain.g_coset_inv];
+    res.par_chunks_mut(chunk)
+        .enumerate()
+        .for_each(|(c, values)| {
+            for (i, v) in values.iter_mut().enumerate() {
+                let idx = c * chunk + i;
+                *v = if idx >= coeffs.len() {
+                    F::zero()
+                } else if idx % 3 == 0 {
+                    coeffs[idx]
+                } else {
+                    coeffs[idx] * coset_powers[idx % 3 - 1]
+                };
+            }
+        });
+
+    for (i, device) in devices.iter().enumerate() {
+        for (j, peer) in devices.iter().enumerate() {
+            if i != j {
+                device.enable_peer_access(peer)?;
+            }
+        }
+    }
+
+    let omega = domain.get_extended_omega();
+    let mut bases = vec![omega];
+    for _ in 1..extended_k {
+        bases.push(bases.last().unwrap().square());
+    }
+
+    let mut shards = devices
+        .iter()
+        .map(|device| -> DeviceResult<_> {
+            let buf = device.alloc_device_buffer::<F>(chunk)?;
+            let tmp = device.alloc_device_buffer::<F>(chunk)?;
+            let ntt_n1 = ntt_prepare(device, omega.pow_vartime([n2 as u64]), log_n1)?;
+            let ntt_n2 = ntt_prepare(device, omega.pow_vartime([n1 as u64]), log_n2)?;
+            let bases = device.alloc_device_buffer_from_slice(&bases[..])?;
+            let stream = CudaStream::new(device)?;
+            Ok(FourStepShard {
+                device: device.clone(),
+                buf,
+                tmp,
+                ntt_n1,
+                ntt_n2,
+                bases,
+                stream,
+            })
+        })
+        .collect::<DeviceResult<Vec<_>>>()?;
+
+    // step 1: rows [s * r2, (s + 1) * r2) of the n2 x n1 input, transposed and exchanged
+    // so that shard s owns columns [s * r1, (s + 1) * r1) as contiguous rows of n2
+    for (s, shard) in shards.iter().enumerate() {
+        shard.device.copy_from_host_to_device_async(
+            &shard.buf,
+            &res[s * chunk..(s + 1) * chunk],
+            shard.stream.raw(),
+        )?;
+        four_step_transpose(
+            &shard.device,
+            &shard.tmp,
+            &shard.buf,
+            r2,
+            n1,
+            Some(shard.stream.raw()),
+        )?;
+    }
+    four_step_exchange::<F>(&shards[..], r1, r2)?;
+
+    // step 2: n2-point NTTs and twiddles omega^(j1 * k2)
+    for (s, shard) in shards.iter_mut().enumerate() {
+        four_step_rows_ntt::<F>(shard, r1, log_n2, false)?;
+        four_step_twiddle(
+            &shard.device,
+            &shard.buf,
+            &shard.bases,
+            s * r1,
+            r1,
+            n2,
+            Some(shard.stream.raw()),
+        )?;
+        four_step_transpose(
+            &shard.device,
+            &shard.tmp,
+            &shard.buf,
+            r1,
+            n2,
+            Some(shard.stream.raw()),
+        )?;
+    }
+    four_step_exchange::<F>(&shards[..], r2, r1)?;
+
+    // step 3: n1-point NTTs, shard s now holds X[k2 + n2 * k1] for k2 in its rows
+    for shard in shards.iter_mut() {
+        four_step_rows_ntt::<F>(shard, r2, log_n1, true)?;
+        four_step_transpose(
+            &shard.device,
+            &shard.tmp,
+            &shard.buf,
+            r2,
+            n1,
+            Some(shard.stream.raw()),
+        )?;
+    }
+    four_step_sync(&shards[..])?;
+
+    // step 4: scatter the transposed blocks back in natural order
+    let unit = core::mem::size_of::<F>();
+    for (s, shard) in shards.iter().enumerate() {
+        shard.device.acitve_ctx()?;
+        unsafe {
+            let err = cuda_runtime_sys::cudaMemcpy2DAsync(
+                res.as_mut_ptr().offset((s * r2) as isize) as _,
+                n2 * unit,
+                shard.tmp.ptr(),
+                r2 * unit,
+                r2 * unit,
+                n1,
+                cuda_runtime_sys::cudaMemcpyKind::cudaMemcpyDeviceToHost,
+                shard.stream.raw(),
+            );
+            to_result((), err, "fail to copy four-step result to host")?;
+        }
+    }
+    four_step_sync(&shards[..])?;
+    end_timer!(timer);
+
+    Ok(())
+}
+
 fn eval_ys<F: FieldExt>(ys: &BTreeMap<u32, F>, ctx: &mut EvalHContext<F>) -> F {
     let max_y_order = *ys.keys().max().unwrap();
     for _ in (ctx.y.len() as u32)..=max_y_order {
@@ -1100,6 +2195,18 @@ fn evaluate_prove_expr<F: FieldExt>(
     instance: &[&[F]],
     ctx: &mut EvalHContext<F>,
 ) -> DeviceResult<CudaDeviceBufRaw> {
+    let resident = ctx.resident.clone();
+    if INTERMEDIATE_DOMAIN.with(|x| x.get())
+        && ctx.extended_k > ctx.k + 1
+        && exprs
+            .iter()
+            .flatten()
+            .all(|(units, _)| units.values().sum::<u32>() <= 2)
+    {
+        count_path("intermediate_domain");
+        return evaluate_prove_expr_in_half_domain(device, exprs, fixed, advice, instance, ctx);
+    }
+
     let res = ctx.alloc(device)?;
     unsafe {
         cudaMemset(res.ptr(), 0, ctx.extended_size * core::mem::size_of::<F>());
@@ -1155,8 +2262,18 @@ fn evaluate_prove_expr<F: FieldExt>(
                             rotation,
                         } => (&instance[*column_index], rotation),
                     };
+                    if let ProveExpressionUnit::Fixed { column_index, .. } = u {
+                        if let Some(buf) = ctx.shared_fixed.get(column_index) {
+                            for _ in 0..*exp {
+                                group.push(buf.ptr());
+                                rots.push(rot.0 << (ctx.extended_k - ctx.k));
+                            }
+                            continue;
+                        }
+                    }
                     if !bufs.contains_key(&id) {
-                        let buf = do_extended_ntt_v2(device, ctx, src)?;
+                        let src = unit_coeffs(u, src, &resident);
+                        let buf = do_extended_ntt_v2_coeffs(device, ctx, src)?;
                         bufs.insert(id, buf);
                     }
                     for _ in 0..*exp {
@@ -1168,18 +2285,14 @@ fn evaluate_prove_expr<F: FieldExt>(
                 group.push(0usize as _);
             }
 
-            let group_buf = device.alloc_device_buffer_from_slice(&group[..])?;
-            let rots_buf = device.alloc_device_buffer_from_slice(&rots[..])?;
-
-            let err = field_op_batch_mul_sum(
+            batch_mul_sum(
+                device,
                 res.ptr(),
-                group_buf.ptr(),
-                rots_buf.ptr(),
-                group.len() as i32,
-                ctx.extended_size as i32,
-            );
-
-            to_result((), err, "fail to run field_op_batch_mul_sum")?;
+                &group[..],
+                &rots[..],
+                ctx.extended_size,
+                None,
+            )?;
 
             last_bufs = bufs;
         }
@@ -1188,6 +2301,138 @@ fn evaluate_prove_expr<F: FieldExt>(
     Ok(res)
 }
 
+/// `evaluate_prove_expr` for expressions whose terms have degree at most 2.
+///
+/// Their value has degree below 2n, so the terms are multiplied on the plain 2n
+/// domain, with half size buffers and ntts. The sum is interpolated and moved
+/// to the extended coset domain once at the end.
+fn evaluate_prove_expr_in_half_domain<F: FieldExt>(
+    device: &CudaDevice,
+    exprs: &Vec<Vec<(BTreeMap<ProveExpressionUnit, u32>, BTreeMap<u32, F>)>>,
+    fixed: &[&[F]],
+    advice: &[&[F]],
+    instance: &[&[F]],
+    ctx: &mut EvalHContext<F>,
+) -> DeviceResult<CudaDeviceBufRaw> {
+    let resident = ctx.resident.clone();
+    let half_k = ctx.k + 1;
+    let half_size = ctx.size << 1;
+    ctx.half_domain(device)?;
+
+    let mut res_half = device.alloc_device_buffer::<F>(half_size)?;
+    let mut tmp = device.alloc_device_buffer::<F>(half_size)?;
+    unsafe {
+        cudaMemset(res_half.ptr(), 0, half_size * core::mem::size_of::<F>());
+    }
+
+    for expr in exprs.iter() {
+        let coeffs = expr
+            .iter()
+            .map(|(_, ys)| eval_ys(ys, ctx))
+            .collect::<Vec<_>>();
+        let coeffs_buf = device.alloc_device_buffer_from_slice(&coeffs[..])?;
+        let half = ctx.half_domain.as_ref().unwrap();
+
+        let mut bufs = BTreeMap::new();
+        let mut group = vec![];
+        let mut rots = vec![];
+        for (i, (units, _)) in expr.iter().enumerate() {
+            group.push(unsafe {
+                coeffs_buf
+                    .ptr()
+                    .offset((i * core::mem::size_of::<F>()) as isize)
+            });
+
+            for (u, exp) in units {
+                let (src, rot) = match u {
+                    ProveExpressionUnit::Fixed {
+                        column_index,
+                        rotation,
+                    } => (&fixed[*column_index], rotation),
+                    ProveExpressionUnit::Advice {
+                        column_index,
+                        rotation,
+                    } => (&advice[*column_index], rotation),
+                    ProveExpressionUnit::Instance {
+                        column_index,
+                        rotation,
+                    } => (&instance[*column_index], rotation),
+                };
+                let id = u.get_group();
+                if !bufs.contains_key(&id) {
+                    let mut buf = device.alloc_device_buffer::<F>(half_size)?;
+                    let src = unit_coeffs(u, src, &resident);
+                    upload_coeffs(device, &buf, src, ctx.size, None)?;
+                    // a single coset power leaves the coefficients as they are, only zero-pads
+                    extended_prepare(
+                        device,
+                        &buf,
+                        &ctx.coset_powers_buf,
+                        1,
+                        ctx.size,
+                        half_size,
+                        None,
+                    )?;
+                    ntt_raw(
+                        device,
+                        &mut buf,
+                        &mut tmp,
+                        &half.ntt_pq_buf,
+                        &half.ntt_omegas_buf,
+                        half_k,
+                        None,
+                    )?;
+                    bufs.insert(id, buf);
+                }
+                for _ in 0..*exp {
+                    group.push(bufs.get(&id).unwrap().ptr());
+                    rots.push(rot.0 << 1);
+                }
+            }
+
+            group.push(0usize as _);
+        }
+
+        unsafe {
+            batch_mul_sum(
+                device,
+                res_half.ptr(),
+                &group[..],
+                &rots[..],
+                half_size,
+                None,
+            )?;
+        }
+        device.synchronize()?;
+    }
+
+    let half = ctx.half_domain.as_ref().unwrap();
+    intt_raw(
+        device,
+        &mut res_half,
+        &mut tmp,
+        &half.intt_pq_buf,
+        &half.intt_omegas_buf,
+        &half.divisor_buf,
+        half_k,
+    )?;
+
+    let mut res = ctx.alloc(device)?;
+    device.copy_from_device_to_device::<F>(&res, 0, &res_half, 0, half_size)?;
+    extended_prepare(
+        device,
+        &res,
+        &ctx.coset_powers_buf,
+        3,
+        half_size,
+        ctx.extended_size,
+        None,
+    )?;
+    do_extended_ntt_pure(device, ctx, &mut res)?;
+
+    Ok(res)
+}
+
 fn evaluate_prove_expr_with_async_ntt<F: FieldExt>(
     device: &CudaDevice,
     exprs: &Vec<Vec<(BTreeMap<ProveExpressionUnit, u32>, BTreeMap<u32, F>)>>,
@@ -1196,6 +2441,7 @@ fn evaluate_prove_expr_with_async_ntt<F: FieldExt>(
     instance: &[&[F]],
     ctx: &mut EvalHContext<F>,
 ) -> DeviceResult<CudaDeviceBufRaw> {
+    let resident = ctx.resident.clone();
     let res = ctx.alloc(device)?;
     unsafe {
         cudaMemset(res.ptr(), 0, ctx.extended_size * core::mem::size_of::<F>());
@@ -1253,11 +2499,20 @@ fn evaluate_prove_expr_with_async_ntt<F: FieldExt>(
                             rotation,
                         } => (&instance[*column_index], rotation),
                     };
+                    if let ProveExpressionUnit::Fixed { column_index, .. } = u {
+                        if let Some(buf) = ctx.shared_fixed.get(column_index) {
+                            for _ in 0..*exp {
+                                group.push(buf.ptr());
+                                rots.push(rot.0 << (ctx.extended_k - ctx.k));
+                            }
+                            continue;
+                        }
+                    }
                     if !bufs.contains_key(&id) {
+                        let src = unit_coeffs(u, src, &resident);
                         let (buf, tmp, stream) = do_extended_ntt_v2_async(device, ctx, src)?;
                         if let Some(last_stream) = last_stream {
-                            cuda_runtime_sys::cudaStreamSynchronize(last_stream);
-                            cuda_runtime_sys::cudaStreamDestroy(last_stream);
+                            last_stream.synchronize()?;
                             ctx.extended_allocator.push(last_tmp.unwrap());
                             last_tmp = Some(tmp);
                         } else {
@@ -1276,26 +2531,189 @@ fn evaluate_prove_expr_with_async_ntt<F: FieldExt>(
             }
 
             if let Some(last_stream) = last_stream {
-                cuda_runtime_sys::cudaStreamSynchronize(last_stream);
-                cuda_runtime_sys::cudaStreamDestroy(last_stream);
+                last_stream.synchronize()?;
                 ctx.extended_allocator.push(last_tmp.unwrap());
             }
 
-            let group_buf = device.alloc_device_buffer_from_slice(&group[..])?;
-            let rots_buf = device.alloc_device_buffer_from_slice(&rots[..])?;
-
-            let err = field_op_batch_mul_sum(
+            batch_mul_sum(
+                device,
                 res.ptr(),
-                group_buf.ptr(),
-                rots_buf.ptr(),
-                group.len() as i32,
-                ctx.extended_size as i32,
+                &group[..],
+                &rots[..],
+                ctx.extended_size,
+                None,
+            )?;
+
+            last_bufs = bufs;
+        }
+    }
+
+    Ok(res)
+}
+
+/// `evaluate_prove_expr_with_async_ntt` with the groups spread over a pool of
+/// `streams` streams, so the extended ntts and the sums of different groups
+/// overlap instead of queuing on the default stream.
+///
+/// Each stream adds its groups into its own buffer, the buffers are summed at
+/// the end. Groups are issued in waves of one group per stream: a column is
+/// extended once per wave, on the stream of the first group reading it, and
+/// the other streams wait for its event. Waves are joined on the host before
+/// the scratch and column buffers go back to the allocator.
+fn evaluate_prove_expr_on_streams<F: FieldExt>(
+    device: &CudaDevice,
+    exprs: &Vec<Vec<(BTreeMap<ProveExpressionUnit, u32>, BTreeMap<u32, F>)>>,
+    fixed: &[&[F]],
+    advice: &[&[F]],
+    instance: &[&[F]],
+    ctx: &mut EvalHContext<F>,
+    streams: usize,
+) -> DeviceResult<CudaDeviceBufRaw> {
+    let resident = ctx.resident.clone();
+    let rot_shift = ctx.extended_k - ctx.k;
+
+    let mut pool = vec![];
+    for _ in 0..streams.min(exprs.len()).max(1) {
+        let stream = CudaStream::new(device)?;
+        let acc = ctx.alloc(device)?;
+        unsafe {
+            let err = cudaMemsetAsync(
+                acc.ptr(),
+                0,
+                ctx.extended_size * core::mem::size_of::<F>(),
+                stream.raw(),
             );
+            to_result((), err, "fail to run cudaMemsetAsync")?;
+        }
+        pool.push((stream, acc));
+    }
 
-            to_result((), err, "fail to run field_op_batch_mul_sum")?;
+    let mut last_bufs = BTreeMap::new();
+    for wave in exprs.chunks(pool.len()) {
+        // column -> extended buffer, and the event after it if it is computed in this wave
+        let mut bufs = BTreeMap::new();
+        for (units, _) in wave.iter().flatten() {
+            for u in units.keys() {
+                if let Some(buf) = last_bufs.remove(&u.get_group()) {
+                    bufs.insert(u.get_group(), (buf, None));
+                }
+            }
+        }
+        for (_, buf) in std::mem::take(&mut last_bufs) {
+            ctx.extended_allocator.push(buf);
+        }
 
-            last_bufs = bufs;
+        let mut tmps = vec![];
+        let mut uploads = vec![];
+        for (expr, (stream, acc)) in wave.iter().zip(pool.iter()) {
+            let coeffs = expr
+                .iter()
+                .map(|(_, ys)| eval_ys(ys, ctx))
+                .collect::<Vec<_>>();
+            let coeffs_buf =
+                device.alloc_device_buffer_from_slice_async(&coeffs[..], stream.raw())?;
+
+            let mut group = vec![];
+            let mut rots = vec![];
+            for (i, (units, _)) in expr.iter().enumerate() {
+                group.push(unsafe {
+                    coeffs_buf
+                        .ptr()
+                        .offset((i * core::mem::size_of::<F>()) as isize)
+                });
+
+                for (u, exp) in units {
+                    let id = u.get_group();
+                    let (src, rot) = match u {
+                        ProveExpressionUnit::Fixed {
+                            column_index,
+                            rotation,
+                        } => (&fixed[*column_index], rotation),
+                        ProveExpressionUnit::Advice {
+                            column_index,
+                            rotation,
+                        } => (&advice[*column_index], rotation),
+                        ProveExpressionUnit::Instance {
+                            column_index,
+                            rotation,
+                        } => (&instance[*column_index], rotation),
+                    };
+                    let shared = match u {
+                        ProveExpressionUnit::Fixed { column_index, .. } => {
+                            ctx.shared_fixed.get(column_index).map(|buf| buf.ptr())
+                        }
+                        _ => None,
+                    };
+                    let ptr = match (shared, bufs.get(&id)) {
+                        (Some(ptr), _) => ptr,
+                        (None, Some((buf, event))) => {
+                            if let Some(event) = event {
+                                stream.wait_event(event)?;
+                            }
+                            buf.ptr()
+                        }
+                        (None, None) => {
+                            let mut buf = ctx.alloc(device)?;
+                            let src = unit_coeffs(u, src, &resident);
+                            upload_coeffs(device, &buf, src, ctx.size, Some(stream.raw()))?;
+                            tmps.push(coeff_to_extended_coset(
+                                device,
+                                ctx,
+                                &mut buf,
+                                Some(stream.raw()),
+                            )?);
+                            let ptr = buf.ptr();
+                            bufs.insert(id, (buf, Some(stream.record_event()?)));
+                            ptr
+                        }
+                    };
+                    for _ in 0..*exp {
+                        group.push(ptr);
+                        rots.push(rot.0 << rot_shift);
+                    }
+                }
+
+                group.push(0usize as _);
+            }
+
+            uploads.push(coeffs_buf);
+            uploads.append(&mut unsafe {
+                batch_mul_sum(
+                    device,
+                    acc.ptr(),
+                    &group[..],
+                    &rots[..],
+                    ctx.extended_size,
+                    Some(stream.raw()),
+                )?
+            });
+        }
+
+        for (stream, _) in pool.iter() {
+            stream.synchronize()?;
         }
+        ctx.extended_allocator.append(&mut tmps);
+        last_bufs = bufs.into_iter().map(|(id, (buf, _))| (id, buf)).collect();
+    }
+    for (_, buf) in last_bufs {
+        ctx.extended_allocator.push(buf);
+    }
+
+    let mut pool = pool.into_iter().map(|(_, acc)| acc);
+    let res = pool.next().unwrap();
+    for acc in pool {
+        field_op_v3(
+            device,
+            &res,
+            Some(&res),
+            None,
+            Some(&acc),
+            None,
+            ctx.extended_size,
+            FieldOp::Add,
+            None,
+        )?;
+        ctx.extended_allocator.push(acc);
     }
 
     Ok(res)
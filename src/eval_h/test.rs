@@ -0,0 +1,212 @@
+use super::do_extended_ntt_v2;
+use super::evaluate_prove_expr;
+use super::evaluate_prove_expr_on_streams;
+use super::evaluate_prove_expr_with_async_ntt;
+use super::sharded_extension_fits;
+use super::EvalHContext;
+use super::CUDA_GRAPHS;
+use super::INTERMEDIATE_DOMAIN;
+use crate::device::cuda::CudaDevice;
+use crate::device::Device as _;
+use crate::evaluate_expr;
+use crate::plan::analyze_expr_tree;
+use ark_std::rand::rngs::StdRng;
+use ark_std::rand::SeedableRng as _;
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::pairing::bn256::Fr;
+use halo2_proofs::plonk::evaluation_gpu::Bop;
+use halo2_proofs::plonk::evaluation_gpu::ProveExpression;
+use halo2_proofs::plonk::Expression;
+use halo2_proofs::poly::EvaluationDomain;
+use halo2_proofs::poly::Rotation;
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const K: u32 = 6;
+const COLUMNS: usize = 3;
+
+fn query() -> impl Strategy<Value = (usize, Rotation)> {
+    (0..COLUMNS, -2i32..=2).prop_map(|(column_index, rotation)| (column_index, Rotation(rotation)))
+}
+
+fn expression() -> impl Strategy<Value = Expression<Fr>> {
+    let leaf = prop_oneof![
+        any::<u64>().prop_map(|c| Expression::Constant(Fr::from(c))),
+        query().prop_map(|(column_index, rotation)| Expression::Fixed {
+            query_index: 0,
+            column_index,
+            rotation,
+        }),
+        query().prop_map(|(column_index, rotation)| Expression::Advice {
+            query_index: 0,
+            column_index,
+            rotation,
+        }),
+        query().prop_map(|(column_index, rotation)| Expression::Instance {
+            query_index: 0,
+            column_index,
+            rotation,
+        }),
+    ];
+    leaf.prop_recursive(5, 32, 2, |inner| {
+        prop_oneof![
+            inner.clone().prop_map(|a| Expression::Negated(Box::new(a))),
+            (inner.clone(), inner.clone())
+                .prop_map(|(a, b)| Expression::Sum(Box::new(a), Box::new(b))),
+            (inner.clone(), inner.clone())
+                .prop_map(|(a, b)| Expression::Product(Box::new(a), Box::new(b))),
+            (inner, any::<u64>()).prop_map(|(a, c)| Expression::Scaled(Box::new(a), Fr::from(c))),
+        ]
+    })
+}
+
+fn slices<C: std::ops::Deref<Target = [Fr]>>(columns: &[C]) -> Vec<&[Fr]> {
+    columns.iter().map(|c| &c[..]).collect()
+}
+
+// Evaluates `expr + y^2 * y_coeff` on random columns with each device
+// evaluator and compares with `evaluate_expr` on the host extended columns.
+fn check_expression(expr: &Expression<Fr>, y_coeff: Fr, seed: u64) {
+    let device = CudaDevice::get_device(0).unwrap();
+    let domain = EvaluationDomain::<Fr>::new(4, K);
+    let extended_size = 1 << domain.extended_k();
+    let rot_scale = 1 << (domain.extended_k() - K);
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut columns = || {
+        (0..COLUMNS)
+            .map(|_| {
+                (0..1 << K)
+                    .map(|_| Fr::random(&mut rng))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    let (fixed, advice, instance) = (columns(), columns(), columns());
+    let y = Fr::random(&mut rng);
+
+    let extend = |columns: &[Vec<Fr>]| {
+        columns
+            .iter()
+            .map(|c| domain.coeff_to_extended(domain.coeff_from_vec(c.clone())))
+            .collect::<Vec<_>>()
+    };
+    let (fixed_ext, advice_ext, instance_ext) =
+        (extend(&fixed), extend(&advice), extend(&instance));
+
+    let mut expected = vec![Fr::zero(); extended_size];
+    evaluate_expr(
+        expr,
+        extended_size,
+        rot_scale,
+        &slices(&fixed_ext)[..],
+        &slices(&advice_ext)[..],
+        &slices(&instance_ext)[..],
+        &mut expected[..],
+    );
+    let y_term = y.square() * y_coeff;
+    expected.iter_mut().for_each(|v| *v += y_term);
+
+    let prove_expr = ProveExpression::Op(
+        Box::new(ProveExpression::from_expr(expr)),
+        Box::new(ProveExpression::Y(BTreeMap::from([(2, y_coeff)]))),
+        Bop::Sum,
+    );
+    let exprs = analyze_expr_tree(&prove_expr, K as usize);
+    let (fixed, advice, instance) = (slices(&fixed), slices(&advice), slices(&instance));
+    for variant in ["plain", "half domain", "async ntt", "streams"] {
+        let mut ctx =
+            EvalHContext::from_domain(&device, &domain, y, Arc::new(BTreeMap::new())).unwrap();
+        INTERMEDIATE_DOMAIN.with(|x| x.set(variant == "half domain"));
+        let res = match variant {
+            "async ntt" => evaluate_prove_expr_with_async_ntt(
+                &device, &exprs, &fixed, &advice, &instance, &mut ctx,
+            ),
+            "streams" => evaluate_prove_expr_on_streams(
+                &device, &exprs, &fixed, &advice, &instance, &mut ctx, 2,
+            ),
+            _ => evaluate_prove_expr(&device, &exprs, &fixed, &advice, &instance, &mut ctx),
+        };
+        INTERMEDIATE_DOMAIN.with(|x| x.set(false));
+
+        let mut res_host = vec![Fr::zero(); extended_size];
+        device
+            .copy_from_device_to_host(&mut res_host[..], &res.unwrap())
+            .unwrap();
+        assert!(res_host == expected, "{} evaluation of {:?}", variant, expr);
+    }
+}
+
+// Extends columns with the graph replayed on other buffers for each of them,
+// including in a second context as a later proof would, and compares with the
+// launches issued one by one and with the host.
+#[test]
+fn test_extend_graph_rebind() {
+    let device = CudaDevice::get_device(0).unwrap();
+    let domain = EvaluationDomain::<Fr>::new(4, K);
+    let mut rng = StdRng::seed_from_u64(0);
+    let columns = (0..6)
+        .map(|_| {
+            (0..1 << K)
+                .map(|_| Fr::random(&mut rng))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let extend = |graphs: bool| {
+        CUDA_GRAPHS.with(|x| x.set(graphs));
+        let mut res = vec![];
+        for _ in 0..2 {
+            let mut ctx =
+                EvalHContext::from_domain(&device, &domain, Fr::one(), Arc::new(BTreeMap::new()))
+                    .unwrap();
+            // every extended column is held, so each one lands on other buffers
+            let bufs = columns
+                .iter()
+                .map(|c| do_extended_ntt_v2(&device, &mut ctx, c).unwrap())
+                .collect::<Vec<_>>();
+            for buf in bufs {
+                let mut host = vec![Fr::zero(); 1 << domain.extended_k()];
+                device
+                    .copy_from_device_to_host(&mut host[..], &buf)
+                    .unwrap();
+                res.push(host);
+            }
+        }
+        CUDA_GRAPHS.with(|x| x.set(false));
+        res
+    };
+
+    let expected = extend(false);
+    for (i, c) in columns.iter().enumerate() {
+        let host = domain.coeff_to_extended(domain.coeff_from_vec(c.clone()));
+        assert!(expected[i][..] == host[..], "column {}", i);
+    }
+    assert!(extend(true) == expected);
+}
+
+// The sharded extension saves the tmp buffer only, the extended buffer itself
+// must fit on the device.
+#[test]
+fn test_sharded_extension_bound() {
+    let bytes = (1 << 10) * core::mem::size_of::<Fr>();
+    assert!(!sharded_extension_fits(2 * bytes, bytes));
+    assert!(sharded_extension_fits(2 * bytes - 1, bytes));
+    assert!(sharded_extension_fits(bytes, bytes));
+    assert!(!sharded_extension_fits(bytes - 1, bytes));
+    assert!(!sharded_extension_fits(0, bytes));
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_evaluate_prove_expr(
+        expr in expression(),
+        y_coeff in any::<u64>(),
+        seed in any::<u64>(),
+    ) {
+        check_expression(&expr, Fr::from(y_coeff), seed);
+    }
+}
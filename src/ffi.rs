@@ -0,0 +1,316 @@
+//! C ABI for linking the prover into services written in other languages,
+//! on bn254. Params, proving keys and advices are opaque handles freed by
+//! their `_free` function, scalars are 32-byte little endian canonical
+//! encodings and every call returns a `ZkwStatus`, with the message of the
+//! last failure on the calling thread in `zkw_last_error`. The declarations
+//! are in `include/zkwasm_prover.h`.
+//!
+//! The crate builds as a `cdylib` and a `staticlib` to link against.
+//!
+//! A proving key is read back with the constraint system of its circuit, so
+//! `zkw_proving_key_read` reads it with the reader the library of the circuit
+//! registered with `cli::set_pk_reader`, or that library builds it in Rust and
+//! hands it over with `zkw_proving_key_from`.
+
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::pairing::bn256::Fr;
+use halo2_proofs::pairing::bn256::G1Affine;
+use halo2_proofs::pairing::group::ff::PrimeField;
+use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::poly::commitment::Params;
+use halo2_proofs::transcript::Blake2bWrite;
+use halo2_proofs::transcript::Challenge255;
+use halo2_proofs::transcript::TranscriptWrite as _;
+use rand::rngs::OsRng;
+
+use crate::cli::read_registered_pk;
+use crate::config::ProverConfig;
+use crate::create_proof_from_advices_with_config;
+use crate::hugetlb::HugePageAllocator;
+use crate::prepare_advice_buffer;
+use crate::Error;
+
+const SCALAR_BYTES: usize = 32;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZkwStatus {
+    Ok = 0,
+    InvalidArgument = 1,
+    Io = 2,
+    Device = 3,
+    OutOfMemory = 4,
+    HostOutOfMemory = 5,
+    Kernel = 6,
+    Cancelled = 7,
+    Panic = 8,
+    Timeout = 9,
+}
+
+impl From<&Error> for ZkwStatus {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::DeviceError(_) => ZkwStatus::Device,
+            Error::OutOfMemory(_) => ZkwStatus::OutOfMemory,
+            Error::HostOutOfMemory(_) => ZkwStatus::HostOutOfMemory,
+            Error::TranscriptError(_) => ZkwStatus::Io,
+            Error::InvalidInput(_) => ZkwStatus::InvalidArgument,
+            Error::KernelError(_) => ZkwStatus::Kernel,
+            Error::Cancelled => ZkwStatus::Cancelled,
+            Error::Timeout(_) => ZkwStatus::Timeout,
+            Error::Panicked(_) => ZkwStatus::Panic,
+        }
+    }
+}
+
+pub struct ZkwParams(Params<G1Affine>);
+
+pub struct ZkwProvingKey(Arc<ProvingKey<G1Affine>>);
+
+pub struct ZkwAdvices(Arc<Vec<Vec<Fr, HugePageAllocator>>>);
+
+/// Bytes allocated by the prover, released with `zkw_bytes_free`.
+#[repr(C)]
+pub struct ZkwBytes {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn fail(status: ZkwStatus, msg: impl ToString) -> ZkwStatus {
+    let msg = CString::new(msg.to_string().replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|x| *x.borrow_mut() = msg);
+    status
+}
+
+// runs `f` with panics turned into `ZkwStatus::Panic`
+fn guard(f: impl FnOnce() -> Result<(), ZkwStatus>) -> ZkwStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => ZkwStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(_) => fail(ZkwStatus::Panic, "prover panicked"),
+    }
+}
+
+fn error(e: Error) -> ZkwStatus {
+    fail(ZkwStatus::from(&e), e)
+}
+
+fn invalid(msg: &str) -> ZkwStatus {
+    fail(ZkwStatus::InvalidArgument, msg)
+}
+
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Result<&'a [u8], ZkwStatus> {
+    match (ptr.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => Err(invalid("null buffer")),
+        (false, _) => Ok(slice::from_raw_parts(ptr, len)),
+    }
+}
+
+fn read_scalars(bytes: &[u8], out: &mut [Fr]) -> Result<(), ZkwStatus> {
+    for (chunk, out) in bytes.chunks(SCALAR_BYTES).zip(out.iter_mut()) {
+        let mut repr = <Fr as PrimeField>::Repr::default();
+        repr.as_mut().copy_from_slice(chunk);
+        *out = Option::from(Fr::from_repr(repr)).ok_or_else(|| invalid("non canonical scalar"))?;
+    }
+    Ok(())
+}
+
+/// Hands a proving key built in Rust over to C callers.
+pub fn zkw_proving_key_from(pk: Arc<ProvingKey<G1Affine>>) -> *mut ZkwProvingKey {
+    Box::into_raw(Box::new(ZkwProvingKey(pk)))
+}
+
+/// Message of the last failed call on this thread, valid until the next failure.
+#[no_mangle]
+pub extern "C" fn zkw_last_error() -> *const c_char {
+    LAST_ERROR.with(|x| x.borrow().as_ptr())
+}
+
+/// Reads params serialized by `Params::write`.
+#[no_mangle]
+pub unsafe extern "C" fn zkw_params_read(
+    data: *const u8,
+    len: usize,
+    out: *mut *mut ZkwParams,
+) -> ZkwStatus {
+    guard(|| {
+        if out.is_null() {
+            return Err(invalid("null output"));
+        }
+        let params = Params::read(&mut bytes(data, len)?).map_err(|e| fail(ZkwStatus::Io, e))?;
+        *out = Box::into_raw(Box::new(ZkwParams(params)));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn zkw_params_free(params: *mut ZkwParams) {
+    if !params.is_null() {
+        drop(Box::from_raw(params));
+    }
+}
+
+/// Reads the proving key file at `path`, a nul terminated utf-8 path, for
+/// `params` with the reader registered by the circuit.
+#[no_mangle]
+pub unsafe extern "C" fn zkw_proving_key_read(
+    params: *const ZkwParams,
+    path: *const c_char,
+    out: *mut *mut ZkwProvingKey,
+) -> ZkwStatus {
+    guard(|| {
+        if params.is_null() || path.is_null() || out.is_null() {
+            return Err(invalid("null handle"));
+        }
+        let path = CStr::from_ptr(path)
+            .to_str()
+            .map_err(|_| invalid("path is not utf-8"))?;
+        let pk = read_registered_pk(&(*params).0, Path::new(path)).map_err(error)?;
+        *out = zkw_proving_key_from(Arc::new(pk));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn zkw_proving_key_free(pk: *mut ZkwProvingKey) {
+    if !pk.is_null() {
+        drop(Box::from_raw(pk));
+    }
+}
+
+/// Allocates the zeroed advice columns of `pk` in huge page buffers.
+#[no_mangle]
+pub unsafe extern "C" fn zkw_advices_new(
+    pk: *const ZkwProvingKey,
+    out: *mut *mut ZkwAdvices,
+) -> ZkwStatus {
+    guard(|| {
+        if pk.is_null() || out.is_null() {
+            return Err(invalid("null handle"));
+        }
+        let advices = prepare_advice_buffer(&*(*pk).0, false);
+        *out = Box::into_raw(Box::new(ZkwAdvices(Arc::new(advices))));
+        Ok(())
+    })
+}
+
+/// Writes `rows` scalars to the start of advice column `column`.
+#[no_mangle]
+pub unsafe extern "C" fn zkw_advices_set_column(
+    advices: *mut ZkwAdvices,
+    column: usize,
+    data: *const u8,
+    rows: usize,
+) -> ZkwStatus {
+    guard(|| {
+        if advices.is_null() {
+            return Err(invalid("null handle"));
+        }
+        let columns =
+            Arc::get_mut(&mut (*advices).0).ok_or_else(|| invalid("advices are being proven"))?;
+        let column = columns
+            .get_mut(column)
+            .ok_or_else(|| invalid("advice column out of range"))?;
+        if rows > column.len() {
+            return Err(invalid("more rows than the domain"));
+        }
+        read_scalars(bytes(data, rows * SCALAR_BYTES)?, &mut column[..rows])
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn zkw_advices_free(advices: *mut ZkwAdvices) {
+    if !advices.is_null() {
+        drop(Box::from_raw(advices));
+    }
+}
+
+/// Proves `advices` with the instance columns `instances[i]` of
+/// `instance_rows[i]` scalars, on `device_id` or the default device when it
+/// is negative. The blake2b transcript is returned in `proof`.
+#[no_mangle]
+pub unsafe extern "C" fn zkw_create_proof(
+    params: *const ZkwParams,
+    pk: *const ZkwProvingKey,
+    instances: *const *const u8,
+    instance_rows: *const usize,
+    instance_columns: usize,
+    advices: *const ZkwAdvices,
+    use_gwc: bool,
+    device_id: i32,
+    proof: *mut ZkwBytes,
+) -> ZkwStatus {
+    guard(|| {
+        if params.is_null() || pk.is_null() || advices.is_null() || proof.is_null() {
+            return Err(invalid("null handle"));
+        }
+        let pk = &*(*pk).0;
+        if instance_columns != pk.get_vk().cs.num_instance_columns {
+            return Err(invalid("wrong number of instance columns"));
+        }
+        if instance_columns > 0 && (instances.is_null() || instance_rows.is_null()) {
+            return Err(invalid("null instances"));
+        }
+        let size = 1usize << pk.get_vk().domain.k();
+        let instances = (0..instance_columns)
+            .map(|i| {
+                let rows = *instance_rows.add(i);
+                if rows > size {
+                    return Err(invalid("more instance rows than the domain"));
+                }
+                let mut column = vec![Fr::zero(); rows];
+                read_scalars(bytes(*instances.add(i), rows * SCALAR_BYTES)?, &mut column)?;
+                Ok(column)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let config = ProverConfig {
+            device_id: (device_id >= 0).then(|| device_id as usize),
+            ..Default::default()
+        };
+        let mut transcript = Blake2bWrite::<_, G1Affine, Challenge255<_>>::init(vec![]);
+        create_proof_from_advices_with_config(
+            &(*params).0,
+            pk,
+            &instances.iter().map(|x| &x[..]).collect::<Vec<_>>()[..],
+            (*advices).0.clone(),
+            &mut transcript,
+            OsRng,
+            use_gwc,
+            &config,
+        )
+        .map_err(error)?;
+
+        let transcript = Box::leak(transcript.finalize().into_boxed_slice());
+        *proof = ZkwBytes {
+            ptr: transcript.as_mut_ptr(),
+            len: transcript.len(),
+        };
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn zkw_bytes_free(bytes: ZkwBytes) {
+    if !bytes.ptr.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            bytes.ptr, bytes.len,
+        )));
+    }
+}
@@ -0,0 +1,447 @@
+//! The phases after the permutation products on the host, for backends
+//! without a CUDA device (`CpuBackend`, `OpenCLBackend`): the lookup z
+//! polynomials, h, the evaluations and the multiopen. Commitments still go
+//! through the backend.
+
+use std::collections::BTreeMap;
+
+use halo2_proofs::arithmetic::eval_polynomial_st;
+use halo2_proofs::arithmetic::lagrange_interpolate;
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::pairing::group::ff::BatchInvert as _;
+use halo2_proofs::plonk::Any;
+use halo2_proofs::plonk::Expression;
+use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::poly::Rotation;
+use halo2_proofs::transcript::EncodedChallenge;
+use halo2_proofs::transcript::TranscriptWrite;
+use rayon::iter::IndexedParallelIterator as _;
+use rayon::iter::IntoParallelRefIterator as _;
+use rayon::iter::IntoParallelRefMutIterator as _;
+use rayon::iter::ParallelIterator as _;
+use rayon::prelude::ParallelSliceMut as _;
+
+use crate::backend::CommitmentBasis;
+use crate::backend::ProverBackend;
+use crate::evaluate_exprs;
+use crate::hugetlb::HugePageAllocator;
+use crate::multiopen::shplonk::construct_intermediate_sets;
+use crate::multiopen::ProverQuery;
+use crate::phases::Challenge;
+use crate::phases::Challenges;
+use crate::Error;
+
+const INVERT_CHUNK: usize = 1 << 12;
+
+/// Lagrange values of the product polynomial of a lookup, like `eval_lookup_z`
+/// on device: `z[0] = 1` and each row multiplies in
+/// `(input + beta)(table + gamma) / (permuted_input + beta)(permuted_table + gamma)`
+/// up to `z[unusable_rows_start]`. The rows after it are left to the caller.
+pub(crate) fn lookup_z<F: FieldExt>(
+    permuted_input: &[F],
+    permuted_table: &[F],
+    input: &[F],
+    table: &[F],
+    z: &mut [F],
+    beta: F,
+    gamma: F,
+    unusable_rows_start: usize,
+) {
+    let mut denominators = permuted_input[..unusable_rows_start]
+        .par_iter()
+        .zip(permuted_table.par_iter())
+        .map(|(a, s)| (*a + beta) * (*s + gamma))
+        .collect::<Vec<_>>();
+    denominators
+        .par_chunks_mut(INVERT_CHUNK)
+        .for_each(|x| x.iter_mut().batch_invert());
+
+    z[0] = F::one();
+    for (i, denominator) in denominators.into_iter().enumerate() {
+        z[i + 1] = z[i] * (input[i] + beta) * (table[i] + gamma) * denominator;
+    }
+}
+
+// `h = h * y + f(row)` on every row of the extended domain, in parallel
+fn accumulate<F: FieldExt>(h: &mut [F], y: F, f: impl Fn(usize) -> F + Sync) {
+    h.par_iter_mut()
+        .enumerate()
+        .for_each(|(i, h)| *h = *h * y + f(i));
+}
+
+// `[c * w^i]` for the rows of the extended domain
+fn powers<F: FieldExt>(c: F, w: F, n: usize) -> Vec<F> {
+    let mut res = vec![F::zero(); n];
+    res.par_chunks_mut(INVERT_CHUNK)
+        .enumerate()
+        .for_each(|(chunk, values)| {
+            let mut v = c * w.pow_vartime([(chunk * INVERT_CHUNK) as u64]);
+            for x in values.iter_mut() {
+                *x = v;
+                v = v * w;
+            }
+        });
+    res
+}
+
+/// `evaluate_h_gates_and_vanishing_construct` on the host: evaluates the
+/// constraints on the extended coset in the order the verifier folds them with
+/// `y`, divides by the vanishing polynomial, commits the pieces of h, squeezes
+/// x and returns it with `x^n` and the pieces folded with `x^n`. All columns
+/// are in coefficient form; `lookups` are `(permuted_input, permuted_table, z)`.
+pub(crate) fn evaluate_h<C: CurveAffine, E: EncodedChallenge<C>, T: TranscriptWrite<C, E>>(
+    backend: &dyn ProverBackend<C>,
+    pk: &ProvingKey<C>,
+    fixed: &[&[C::Scalar]],
+    advice: &[&[C::Scalar]],
+    instance: &[&[C::Scalar]],
+    permutation_products: &[&[C::Scalar]],
+    lookups: &[[&[C::Scalar]; 3]],
+    shuffle_products: &[&[C::Scalar]],
+    y: C::Scalar,
+    beta: C::Scalar,
+    gamma: C::Scalar,
+    theta: C::Scalar,
+    transcript: &mut T,
+    challenges: &mut Challenges<C>,
+) -> Result<(C::Scalar, C::Scalar, Vec<C::Scalar, HugePageAllocator>), Error> {
+    let domain = &pk.vk.domain;
+    let cs = &pk.vk.cs;
+    let k = domain.k() as usize;
+    let size = 1 << k;
+    let extended_size = 1 << domain.extended_k();
+    let rot_scale = 1 << (domain.extended_k() as usize - k);
+    let next = |i: usize| (i + rot_scale) & (extended_size - 1);
+    let prev = |i: usize| (i + extended_size - rot_scale) & (extended_size - 1);
+
+    let extend = |poly: &[C::Scalar]| {
+        domain
+            .coeff_to_extended(domain.coeff_from_vec(poly.to_vec()))
+            .to_vec()
+    };
+    let extend_all =
+        |polys: &[&[C::Scalar]]| polys.par_iter().map(|x| extend(x)).collect::<Vec<_>>();
+
+    let fixed = extend_all(fixed);
+    let advice = extend_all(advice);
+    let instance = extend_all(instance);
+    let fixed = fixed.iter().map(|x| &x[..]).collect::<Vec<_>>();
+    let advice = advice.iter().map(|x| &x[..]).collect::<Vec<_>>();
+    let instance = instance.iter().map(|x| &x[..]).collect::<Vec<_>>();
+    let l0 = extend(&pk.l0.values[..]);
+    let l_last = extend(&pk.l_last.values[..]);
+    let l_active = &pk.l_active_row.values[..];
+
+    // compressed with theta, like the lookup and shuffle inputs of the products
+    let compress = |exprs: &[Expression<C::Scalar>]| {
+        let mut res = vec![C::Scalar::zero(); extended_size];
+        evaluate_exprs(
+            exprs,
+            extended_size,
+            rot_scale as i32,
+            &fixed[..],
+            &advice[..],
+            &instance[..],
+            theta,
+            &mut res[..],
+        );
+        res
+    };
+
+    let mut h = vec![C::Scalar::zero(); extended_size];
+
+    for gate in cs.gates.iter() {
+        for poly in gate.polynomials() {
+            let values = compress(std::slice::from_ref(poly));
+            accumulate(&mut h, y, |i| values[i]);
+        }
+    }
+
+    if permutation_products.len() > 0 {
+        let products = extend_all(permutation_products);
+        let (first, last) = (products.first().unwrap(), products.last().unwrap());
+        accumulate(&mut h, y, |i| l0[i] * (C::Scalar::one() - first[i]));
+        accumulate(&mut h, y, |i| l_last[i] * (last[i].square() - last[i]));
+        let last_rotation = (size - (cs.blinding_factors() + 1)) * rot_scale;
+        for (z, z_prev) in products.iter().skip(1).zip(products.iter()) {
+            accumulate(&mut h, y, |i| {
+                l0[i] * (z[i] - z_prev[(i + last_rotation) & (extended_size - 1)])
+            });
+        }
+
+        // X on the extended coset, for the identity permutation
+        let xs = powers(C::Scalar::ZETA, domain.get_extended_omega(), extended_size);
+        let sigmas = pk
+            .permutation
+            .polys
+            .par_iter()
+            .map(|x| extend(&x.values[..]))
+            .collect::<Vec<_>>();
+        let chunk_len = cs.degree() - 2;
+        let mut delta = beta;
+        for ((z, columns), sigmas) in products
+            .iter()
+            .zip(cs.permutation.columns.chunks(chunk_len))
+            .zip(sigmas.chunks(chunk_len))
+        {
+            let mut left = (0..extended_size).map(|i| z[next(i)]).collect::<Vec<_>>();
+            let mut right = z.clone();
+            for (column, sigma) in columns.iter().zip(sigmas.iter()) {
+                let values = match column.column_type() {
+                    Any::Advice => advice[column.index()],
+                    Any::Fixed => fixed[column.index()],
+                    Any::Instance => instance[column.index()],
+                };
+                left.par_iter_mut()
+                    .zip(right.par_iter_mut())
+                    .enumerate()
+                    .for_each(|(i, (l, r))| {
+                        *l = *l * (values[i] + beta * sigma[i] + gamma);
+                        *r = *r * (values[i] + delta * xs[i] + gamma);
+                    });
+                delta = delta * C::Scalar::DELTA;
+            }
+            accumulate(&mut h, y, |i| l_active[i] * (left[i] - right[i]));
+        }
+    }
+
+    for (lookup, [permuted_input, permuted_table, z]) in cs.lookups.iter().zip(lookups.iter()) {
+        let input = compress(&lookup.input_expressions[..]);
+        let table = compress(&lookup.table_expressions[..]);
+        let [a, s, z] = [permuted_input, permuted_table, z].map(|x| extend(*x));
+        accumulate(&mut h, y, |i| l0[i] * (C::Scalar::one() - z[i]));
+        accumulate(&mut h, y, |i| l_last[i] * (z[i].square() - z[i]));
+        accumulate(&mut h, y, |i| {
+            l_active[i]
+                * (z[next(i)] * (a[i] + beta) * (s[i] + gamma)
+                    - z[i] * (input[i] + beta) * (table[i] + gamma))
+        });
+        accumulate(&mut h, y, |i| l0[i] * (a[i] - s[i]));
+        accumulate(&mut h, y, |i| {
+            l_active[i] * (a[i] - s[i]) * (a[i] - a[prev(i)])
+        });
+    }
+
+    for (group, z) in cs
+        .shuffles
+        .group(cs.degree())
+        .iter()
+        .zip(shuffle_products.iter())
+    {
+        let mut input = vec![C::Scalar::one(); extended_size];
+        let mut table = vec![C::Scalar::one(); extended_size];
+        let mut beta_pow = beta;
+        for element in group.0.iter() {
+            for (res, exprs) in [
+                (&mut input, &element.input_expressions),
+                (&mut table, &element.shuffle_expressions),
+            ] {
+                let values = compress(&exprs[..]);
+                res.par_iter_mut()
+                    .zip(values.par_iter())
+                    .for_each(|(r, v)| *r = *r * (*v + beta_pow));
+            }
+            beta_pow = beta_pow * beta;
+        }
+        let z = extend(z);
+        accumulate(&mut h, y, |i| l0[i] * (C::Scalar::one() - z[i]));
+        accumulate(&mut h, y, |i| l_last[i] * (z[i].square() - z[i]));
+        accumulate(&mut h, y, |i| {
+            l_active[i] * (table[i] * z[next(i)] - input[i] * z[i])
+        });
+    }
+
+    let mut h_poly = domain.empty_extended();
+    h_poly.copy_from_slice(&h[..]);
+    let h = domain.extended_to_coeff(domain.divide_by_vanishing_poly(h_poly));
+    let pieces = h.chunks(size).collect::<Vec<_>>();
+
+    for commitment in backend.commit(CommitmentBasis::Monomial, pieces.clone())? {
+        challenges.write_point(transcript, commitment)?;
+    }
+
+    let x: C::Scalar = challenges.squeeze(transcript, Challenge::X)?;
+    let xn = x.pow_vartime(&[size as u64]);
+
+    let mut h_pieces = Vec::new_in(HugePageAllocator);
+    h_pieces.extend_from_slice(pieces.last().unwrap());
+    for piece in pieces.iter().rev().skip(1) {
+        h_pieces
+            .par_iter_mut()
+            .zip(piece.par_iter())
+            .for_each(|(h, p)| *h = *h * xn + p);
+    }
+
+    Ok((x, xn, h_pieces))
+}
+
+/// Evaluations of the `(poly, point)` inputs, in coefficient form.
+pub(crate) fn evaluate_polys<F: FieldExt>(inputs: &[(&[F], F)]) -> Vec<F> {
+    inputs
+        .par_iter()
+        .map(|(poly, x)| eval_polynomial_st(poly, *x))
+        .collect()
+}
+
+// `(poly(X) - poly(z)) / (X - z)` in place, the constant term is ignored
+fn divide_by_linear<F: FieldExt>(poly: &mut [F], z: F) {
+    let mut tmp = *poly.last().unwrap();
+    *poly.last_mut().unwrap() = F::zero();
+    for i in (1..poly.len() - 1).rev() {
+        let p = poly[i] + tmp * z;
+        poly[i] = tmp;
+        tmp = p;
+    }
+    poly[0] = tmp;
+}
+
+/// `multiopen::gwc::multiopen` with the batching done on the host.
+pub(crate) fn gwc_multiopen<
+    'a,
+    I,
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E>,
+>(
+    backend: &dyn ProverBackend<C>,
+    queries: I,
+    size: usize,
+    eval_map: BTreeMap<(usize, C::Scalar), C::Scalar>,
+    transcript: &mut T,
+    challenges: &mut Challenges<C>,
+) -> Result<(), Error>
+where
+    I: IntoIterator<Item = ProverQuery<'a, C::Scalar>>,
+{
+    let v: C::Scalar = challenges.squeeze(transcript, Challenge::Opening(0))?;
+
+    let mut point_query_map: BTreeMap<Rotation, Vec<_>> = BTreeMap::new();
+    for query in queries {
+        point_query_map
+            .entry(query.rotation)
+            .or_default()
+            .push(query);
+    }
+
+    let ws = point_query_map
+        .into_values()
+        .map(|queries| {
+            let point = queries[0].point;
+            let mut poly_batch = vec![C::Scalar::zero(); size];
+            let mut eval_batch = C::Scalar::zero();
+            for query in queries {
+                poly_batch
+                    .par_iter_mut()
+                    .zip(query.poly.par_iter())
+                    .for_each(|(acc, p)| *acc = *acc * v + p);
+                eval_batch = eval_batch * v + eval_map[&(query.poly.as_ptr() as usize, point)];
+            }
+            poly_batch[0] -= eval_batch;
+            divide_by_linear(&mut poly_batch[..], point);
+            poly_batch
+        })
+        .collect::<Vec<_>>();
+
+    let commitments = backend.commit(
+        CommitmentBasis::Monomial,
+        ws.iter().map(|x| &x[..]).collect(),
+    )?;
+    for commitment in commitments {
+        challenges.write_point(transcript, commitment)?;
+    }
+
+    Ok(())
+}
+
+/// `multiopen::shplonk::multiopen` with the quotients computed on the host.
+pub(crate) fn shplonk_multiopen<
+    'a,
+    I,
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E>,
+>(
+    backend: &dyn ProverBackend<C>,
+    queries: I,
+    size: usize,
+    eval_map: BTreeMap<(usize, C::Scalar), C::Scalar>,
+    transcript: &mut T,
+    challenges: &mut Challenges<C>,
+) -> Result<(), Error>
+where
+    I: IntoIterator<Item = ProverQuery<'a, C::Scalar>>,
+{
+    let y: C::Scalar = challenges.squeeze(transcript, Challenge::Opening(0))?;
+    let v: C::Scalar = challenges.squeeze(transcript, Challenge::Opening(1))?;
+
+    let (rotation_sets, super_point_set) = construct_intermediate_sets(queries, eval_map);
+
+    // the polys of each set folded with y, and the interpolation of their evals
+    let rotation_sets = rotation_sets
+        .into_iter()
+        .map(|(queries, points)| {
+            let mut poly = vec![C::Scalar::zero(); size];
+            let mut r = vec![C::Scalar::zero(); points.len()];
+            for (p, evals) in queries {
+                poly.par_iter_mut()
+                    .zip(p.par_iter())
+                    .for_each(|(acc, p)| *acc = *acc * y + p);
+                let evals = lagrange_interpolate(&points[..], &evals[..]);
+                for (acc, e) in r.iter_mut().zip(evals) {
+                    *acc = *acc * y + e;
+                }
+            }
+            (points, poly, r)
+        })
+        .collect::<Vec<_>>();
+
+    let mut hx = vec![C::Scalar::zero(); size];
+    for (points, poly, r) in rotation_sets.iter() {
+        let mut quotient = poly.clone();
+        for (q, r) in quotient.iter_mut().zip(r.iter()) {
+            *q -= r;
+        }
+        for point in points {
+            divide_by_linear(&mut quotient[..], *point);
+        }
+        hx.par_iter_mut()
+            .zip(quotient.par_iter())
+            .for_each(|(acc, q)| *acc = *acc * v + q);
+    }
+
+    let commitment = backend.commit(CommitmentBasis::Monomial, vec![&hx[..]])?;
+    challenges.write_point(transcript, commitment[0])?;
+
+    let u: C::Scalar = challenges.squeeze(transcript, Challenge::Opening(2))?;
+
+    let zt_eval = super_point_set
+        .iter()
+        .fold(C::Scalar::one(), |acc, root| acc * (u - root));
+
+    let mut lx = vec![C::Scalar::zero(); size];
+    let mut z_diff_0 = None;
+    for (points, mut poly, r) in rotation_sets.into_iter() {
+        poly[0] -= eval_polynomial_st(&r[..], u);
+        let z_i = super_point_set
+            .iter()
+            .filter(|point| !points.contains(point))
+            .fold(C::Scalar::one(), |acc, root| acc * (u - root));
+        z_diff_0.get_or_insert(z_i);
+        lx.par_iter_mut()
+            .zip(poly.par_iter())
+            .for_each(|(acc, p)| *acc = *acc * v + *p * z_i);
+    }
+
+    let z_diff_0_inv = z_diff_0.unwrap().invert().unwrap();
+    lx.par_iter_mut()
+        .zip(hx.par_iter())
+        .for_each(|(l, h)| *l = (*l - *h * zt_eval) * z_diff_0_inv);
+    divide_by_linear(&mut lx[..], u);
+
+    let commitment = backend.commit(CommitmentBasis::Monomial, vec![&lx[..]])?;
+    challenges.write_point(transcript, commitment[0])?;
+
+    Ok(())
+}
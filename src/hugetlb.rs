@@ -1,57 +1,332 @@
 use core::slice;
 use libc::{
-    c_void, mmap, MAP_ANONYMOUS, MAP_FAILED, MAP_HUGETLB, MAP_PRIVATE, PROT_READ,
-    PROT_WRITE,
+    c_void, madvise, mmap, munmap, MADV_HUGEPAGE, MAP_ANONYMOUS, MAP_FAILED, MAP_HUGETLB,
+    MAP_HUGE_SHIFT, MAP_PRIVATE, MAP_SHARED, PROT_READ, PROT_WRITE,
 };
 use std::{
     alloc::{AllocError, Allocator, Layout},
     collections::HashMap,
+    os::unix::io::AsRawFd,
+    path::PathBuf,
     ptr::{null_mut, NonNull},
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, Once, RwLock,
+    },
 };
 
 use crate::device::{cuda::CudaDevice, Device};
+use crate::scoped::{LimitGuard, ScopedLimit};
 
 lazy_static! {
     pub static ref PINNED_BUFFER_CACHE: Mutex<HashMap::<usize, Vec<usize>>> =
         Mutex::new(HashMap::new());
     pub static ref UNPINNED_BUFFER_CACHE: Mutex<HashMap::<usize, Vec<usize>>> =
         Mutex::new(HashMap::new());
+    static ref HUGE_PAGE_CONFIG: RwLock<HugePageConfig> = RwLock::new(HugePageConfig::from_env());
 }
 
-const HUGEPAGE_SIZE: usize = 2 << 20;
+const DEFAULT_HUGEPAGE_SIZE: usize = 2 << 20;
+
+/// Where the huge page allocators get their pages from.
+///
+/// Read from `ZKWASM_HUGEPAGE_SIZE` (bytes, or with a `K`, `M` or `G` suffix) and
+/// `ZKWASM_HUGETLBFS` (mount point) unless set with `set_huge_page_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HugePageConfig {
+    /// Size of the anonymous huge pages, 2MB or 1GB on x86_64. The kernel must have
+    /// a pool of that size, see /sys/kernel/mm/hugepages.
+    pub page_size: usize,
+    /// Map files of this hugetlbfs mount instead of anonymous huge pages. The page
+    /// size is then the one of the mount and `page_size` only sizes the estimates.
+    pub hugetlbfs: Option<PathBuf>,
+}
+
+impl Default for HugePageConfig {
+    fn default() -> Self {
+        HugePageConfig {
+            page_size: DEFAULT_HUGEPAGE_SIZE,
+            hugetlbfs: None,
+        }
+    }
+}
+
+impl HugePageConfig {
+    pub fn from_env() -> Self {
+        let page_size = std::env::var("ZKWASM_HUGEPAGE_SIZE")
+            .ok()
+            .and_then(|v| {
+                let size = parse_size(&v);
+                if size.is_none() {
+                    tracing::warn!("invalid ZKWASM_HUGEPAGE_SIZE {}, using 2M", v);
+                }
+                size
+            })
+            .unwrap_or(DEFAULT_HUGEPAGE_SIZE);
+
+        HugePageConfig {
+            page_size,
+            hugetlbfs: std::env::var_os("ZKWASM_HUGETLBFS").map(PathBuf::from),
+        }
+    }
+}
+
+fn parse_size(v: &str) -> Option<usize> {
+    let v = v.trim();
+    let (digits, shift) = match v.chars().last()?.to_ascii_uppercase() {
+        'K' => (&v[..v.len() - 1], 10),
+        'M' => (&v[..v.len() - 1], 20),
+        'G' => (&v[..v.len() - 1], 30),
+        _ => (v, 0),
+    };
+    let size = digits.parse::<usize>().ok()? << shift;
+    size.is_power_of_two().then_some(size)
+}
+
+/// Applies to buffers mapped from now on, cached buffers keep their pages.
+pub fn set_huge_page_config(config: HugePageConfig) {
+    *HUGE_PAGE_CONFIG.write().unwrap() = config;
+}
+
+pub fn huge_page_config() -> HugePageConfig {
+    HUGE_PAGE_CONFIG.read().unwrap().clone()
+}
+
+pub(crate) fn huge_page_size() -> usize {
+    HUGE_PAGE_CONFIG.read().unwrap().page_size
+}
+
+static FALLBACK_WARNING: Once = Once::new();
+static HUGETLBFS_FILES: AtomicUsize = AtomicUsize::new(0);
+
+static PINNED_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static UNPINNED_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static MAPPED: AtomicUsize = AtomicUsize::new(0);
+static HOST_MEMORY_LIMIT: ScopedLimit = ScopedLimit::new();
+static LIMIT_WARNING: Once = Once::new();
+
+/// Host memory of the huge page allocators, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostMemoryUsage {
+    /// Handed out by `HugePageAllocator` and not deallocated yet.
+    pub pinned: usize,
+    /// Handed out by `UnpinnedHugePageAllocator` and not deallocated yet.
+    pub unpinned: usize,
+    /// Mapped by both allocators, including the buffers parked in their caches.
+    pub mapped: usize,
+    /// Resident set size of the whole process, 0 where /proc isn't available.
+    pub rss: usize,
+}
+
+pub fn host_memory_usage() -> HostMemoryUsage {
+    HostMemoryUsage {
+        pinned: PINNED_IN_USE.load(Ordering::Relaxed),
+        unpinned: UNPINNED_IN_USE.load(Ordering::Relaxed),
+        mapped: MAPPED.load(Ordering::Relaxed),
+        rss: resident_set_size(),
+    }
+}
+
+fn resident_set_size() -> usize {
+    std::fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<usize>().ok())
+        .map_or(0, |pages| {
+            pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize
+        })
+}
+
+/// Ceiling on the memory mapped by the huge page allocators, `None` is unbounded.
+///
+/// Going past it first unmaps the buffers parked in the caches. If that is not
+/// enough the allocation still goes through, with a warning: proofs are
+/// expected to be admitted against the ceiling beforehand, see
+/// `ProverConfig::host_memory_limit`.
+pub fn set_host_memory_limit(limit: Option<usize>) {
+    HOST_MEMORY_LIMIT.set(limit);
+}
+
+/// The ceiling in effect: the lowest of the one set with
+/// `set_host_memory_limit` and those of the running proofs.
+pub fn host_memory_limit() -> Option<usize> {
+    HOST_MEMORY_LIMIT.get()
+}
+
+/// Lowers the ceiling to `limit` for the duration of one proof.
+pub(crate) fn scope_host_memory_limit(limit: usize) -> LimitGuard {
+    HOST_MEMORY_LIMIT.enter(limit)
+}
+
+/// Unmaps the buffers parked in the caches of both allocators, unpinning the
+/// pinned ones. Returns the bytes released.
+pub fn trim_host_buffer_cache() -> usize {
+    let mut released = 0;
+    let device = CudaDevice::get_device(0).ok();
+    for (cache, pinned) in [
+        (&*PINNED_BUFFER_CACHE, true),
+        (&*UNPINNED_BUFFER_CACHE, false),
+    ] {
+        let buffers = std::mem::take(&mut *cache.lock().unwrap());
+        for (size, ptrs) in buffers {
+            for ptr in ptrs {
+                unsafe {
+                    if let (true, Some(device)) = (pinned, &device) {
+                        let _ = device.unpin_memory(slice::from_raw_parts(ptr as *const u8, size));
+                    }
+                    munmap(ptr as *mut c_void, size);
+                }
+                MAPPED.fetch_sub(size, Ordering::Relaxed);
+                released += size;
+            }
+        }
+    }
+    released
+}
+
+// Makes room for mapping `size` more bytes under the ceiling.
+fn reserve_mapping(size: usize) {
+    let limit = match host_memory_limit() {
+        Some(limit) => limit,
+        None => return,
+    };
+    if MAPPED.load(Ordering::Relaxed) + size <= limit {
+        return;
+    }
+    let released = trim_host_buffer_cache();
+    let mapped = MAPPED.load(Ordering::Relaxed);
+    tracing::debug!(
+        released,
+        "host memory limit reached, unmapped cached buffers"
+    );
+    if mapped + size > limit {
+        LIMIT_WARNING.call_once(|| {
+            tracing::warn!(
+                mapped,
+                requested = size,
+                limit,
+                "host memory limit exceeded by the huge page allocators"
+            );
+        });
+    }
+}
+
+// Backs the mapping with an unlinked file of the hugetlbfs mount, the pages are
+// released once the mapping is gone.
+unsafe fn mmap_hugetlbfs(dir: &PathBuf, size: usize) -> *mut c_void {
+    let path = dir.join(format!(
+        "zkwasm-prover-{}-{}",
+        std::process::id(),
+        HUGETLBFS_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "fail to create hugetlbfs file");
+            return MAP_FAILED;
+        }
+    };
+    let _ = std::fs::remove_file(&path);
+
+    let p = mmap(
+        null_mut(),
+        size,
+        PROT_READ | PROT_WRITE,
+        MAP_SHARED,
+        file.as_raw_fd(),
+        0,
+    );
+    if p == MAP_FAILED {
+        tracing::warn!(
+            path = %path.display(),
+            error = %std::io::Error::last_os_error(),
+            "fail to map hugetlbfs file"
+        );
+    }
+    p
+}
+
+// Maps `size` bytes of huge pages. Without huge pages configured (vm.nr_hugepages)
+// falls back to regular pages, asking for transparent huge pages where enabled.
+unsafe fn mmap_huge_pages(size: usize) -> *mut c_void {
+    let config = huge_page_config();
+    let p = match &config.hugetlbfs {
+        Some(dir) => mmap_hugetlbfs(dir, size),
+        None => mmap(
+            null_mut(),
+            size,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE
+                | MAP_ANONYMOUS
+                | MAP_HUGETLB
+                | ((config.page_size.trailing_zeros() as i32) << MAP_HUGE_SHIFT),
+            -1,
+            0,
+        ),
+    };
+    if p != MAP_FAILED {
+        return p;
+    }
+
+    FALLBACK_WARNING.call_once(|| {
+        tracing::warn!(
+            error = %std::io::Error::last_os_error(),
+            "no huge pages available, falling back to regular pages"
+        );
+    });
+    let p = mmap(
+        null_mut(),
+        size,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if p != MAP_FAILED {
+        // advisory only, THP may be disabled
+        madvise(p, size, MADV_HUGEPAGE);
+    }
+    p
+}
 
 #[derive(Clone)]
 pub struct HugePageAllocator;
 
 unsafe impl Allocator for HugePageAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let aligned_layout = layout.align_to(HUGEPAGE_SIZE).unwrap();
+        let aligned_layout = layout.align_to(huge_page_size()).unwrap();
         unsafe {
-            let mut cache = PINNED_BUFFER_CACHE.lock().unwrap();
-            let arr = cache.entry(aligned_layout.size()).or_insert(vec![]);
-            let p = if arr.len() > 0 {
-                arr.pop().unwrap() as *mut c_void
-            } else {
-                let p = mmap(
-                    null_mut(),
-                    aligned_layout.size(),
-                    PROT_READ | PROT_WRITE,
-                    MAP_PRIVATE | MAP_ANONYMOUS | MAP_HUGETLB,
-                    -1,
-                    0,
-                );
-                let device = CudaDevice::get_device(0).unwrap();
+            let cached = PINNED_BUFFER_CACHE
+                .lock()
+                .unwrap()
+                .get_mut(&aligned_layout.size())
+                .and_then(|arr| arr.pop());
+            let p = match cached {
+                Some(p) => p as *mut c_void,
+                None => {
+                    reserve_mapping(aligned_layout.size());
+                    let p = mmap_huge_pages(aligned_layout.size());
+                    if p == MAP_FAILED {
+                        return Err(AllocError {});
+                    }
+                    MAPPED.fetch_add(aligned_layout.size(), Ordering::Relaxed);
+                    p
+                }
+            };
+            // a no-op for cached buffers unless the pool was released in between,
+            // and skipped on hosts without a cuda device, proving with the cpu backend
+            if let Ok(device) = CudaDevice::get_device(0) {
                 device
-                    .pin_memory(slice::from_raw_parts_mut(p as *mut _, layout.size()))
+                    .pin_memory_pooled(slice::from_raw_parts(p as *const u8, aligned_layout.size()))
                     .unwrap();
-                p
-            };
-
-            if p == MAP_FAILED {
-                return Err(AllocError {});
             }
 
+            crate::metrics::host_memory_acquired(layout.size());
+            PINNED_IN_USE.fetch_add(layout.size(), Ordering::Relaxed);
             Ok(NonNull::new_unchecked(slice::from_raw_parts_mut(
                 p as *mut _,
                 layout.size(),
@@ -61,8 +336,12 @@ unsafe impl Allocator for HugePageAllocator {
 
     unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: Layout) {
         //munmap(ptr.as_ptr() as *mut c_void, layout.size());
+        crate::metrics::host_memory_released(layout.size());
+        PINNED_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        // parked under the size it was mapped with, as looked up by allocate
+        let aligned_size = layout.align_to(huge_page_size()).unwrap().size();
         let mut cache = PINNED_BUFFER_CACHE.lock().unwrap();
-        let arr = cache.entry(layout.size()).or_insert(vec![]);
+        let arr = cache.entry(aligned_size).or_insert(vec![]);
         arr.push(ptr.as_ptr() as usize);
     }
 }
@@ -72,28 +351,28 @@ pub struct UnpinnedHugePageAllocator;
 
 unsafe impl Allocator for UnpinnedHugePageAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let aligned_layout = layout.align_to(HUGEPAGE_SIZE).unwrap();
+        let aligned_layout = layout.align_to(huge_page_size()).unwrap();
         unsafe {
-            let mut cache = UNPINNED_BUFFER_CACHE.lock().unwrap();
-            let arr = cache.entry(aligned_layout.size()).or_insert(vec![]);
-            let p = if arr.len() > 0 {
-                arr.pop().unwrap() as *mut c_void
-            } else {
-                let p = mmap(
-                    null_mut(),
-                    aligned_layout.size(),
-                    PROT_READ | PROT_WRITE,
-                    MAP_PRIVATE | MAP_ANONYMOUS | MAP_HUGETLB,
-                    -1,
-                    0,
-                );
-                p
+            let cached = UNPINNED_BUFFER_CACHE
+                .lock()
+                .unwrap()
+                .get_mut(&aligned_layout.size())
+                .and_then(|arr| arr.pop());
+            let p = match cached {
+                Some(p) => p as *mut c_void,
+                None => {
+                    reserve_mapping(aligned_layout.size());
+                    let p = mmap_huge_pages(aligned_layout.size());
+                    if p == MAP_FAILED {
+                        return Err(AllocError {});
+                    }
+                    MAPPED.fetch_add(aligned_layout.size(), Ordering::Relaxed);
+                    p
+                }
             };
 
-            if p == MAP_FAILED {
-                return Err(AllocError {});
-            }
-
+            crate::metrics::host_memory_acquired(layout.size());
+            UNPINNED_IN_USE.fetch_add(layout.size(), Ordering::Relaxed);
             Ok(NonNull::new_unchecked(slice::from_raw_parts_mut(
                 p as *mut _,
                 layout.size(),
@@ -103,8 +382,11 @@ unsafe impl Allocator for UnpinnedHugePageAllocator {
 
     unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: Layout) {
         //munmap(ptr.as_ptr() as *mut c_void, layout.size());
+        crate::metrics::host_memory_released(layout.size());
+        UNPINNED_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        let aligned_size = layout.align_to(huge_page_size()).unwrap().size();
         let mut cache = UNPINNED_BUFFER_CACHE.lock().unwrap();
-        let arr = cache.entry(layout.size()).or_insert(vec![]);
+        let arr = cache.entry(aligned_size).or_insert(vec![]);
         arr.push(ptr.as_ptr() as usize);
     }
 }
@@ -5,18 +5,21 @@
 extern crate lazy_static;
 
 use std::collections::BTreeMap;
+use std::io;
 use std::iter;
 use std::mem::ManuallyDrop;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::Condvar;
 use std::sync::Mutex;
+use std::sync::PoisonError;
 use std::thread;
 
 use ark_std::end_timer;
-use ark_std::rand::rngs::OsRng;
+use ark_std::rand::rngs::StdRng;
+use ark_std::rand::RngCore;
+use ark_std::rand::SeedableRng as _;
 use ark_std::start_timer;
-use cuda::bn254::batch_msm_v2;
 use cuda::bn254::intt_raw_async;
 use halo2_proofs::arithmetic::CurveAffine;
 use halo2_proofs::arithmetic::Field;
@@ -36,34 +39,125 @@ use rayon::iter::IntoParallelRefMutIterator as _;
 use rayon::iter::ParallelIterator as _;
 use rayon::prelude::ParallelSliceMut as _;
 use rayon::slice::ParallelSlice as _;
-
-use crate::cuda::bn254::batch_intt_raw;
+use tracing::error;
+use tracing::info_span;
+
+use crate::audit::enter_audit_mode;
+use crate::backend::select_backend_with_params;
+use crate::backend::CommitmentBasis;
+use crate::backend::CudaParams;
+use crate::backend::ProverBackend;
+use crate::cache::clear_device_ntt_cache;
+use crate::config::ProverConfig;
 use crate::cuda::bn254::intt_raw;
-use crate::cuda::bn254::ntt_prepare;
+use crate::cuda::bn254::lookup_permute_table;
+use crate::cuda::bn254::scope_msm_window_bits;
 use crate::cuda::bn254_c::eval_lookup_z;
+use crate::cuda::jit::clear_kernels as clear_jit_kernels;
+use crate::cuda::jit::set_jit_gates;
+use crate::cuda::precompute::clear_device_precomputed_bases;
+use crate::cuda_pk::CudaProvingKey;
+use crate::device::cuda::scope_stream_priority;
+use crate::device::cuda::scope_sync_debug;
+use crate::device::cuda::scope_sync_timeout;
 use crate::device::cuda::to_result;
 use crate::device::cuda::CudaBuffer;
 use crate::device::cuda::CudaDevice;
 use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::cuda::CudaStream;
+use crate::device::cuda::DeviceManager;
+use crate::device::nvml::gpu_health;
 use crate::device::Device as _;
+use crate::device::DeviceResult;
+use crate::estimate::estimate_host_memory;
+use crate::eval_h::clear_device_pk_cache;
 use crate::eval_h::evaluate_h_gates_and_vanishing_construct;
+use crate::eval_h::intt_resident;
+use crate::eval_h::set_coset_sliced_h;
+use crate::eval_h::set_cuda_graphs;
+use crate::eval_h::set_expr_streams;
+use crate::eval_h::set_intermediate_domain;
+use crate::eval_h::set_multi_device_fft;
+use crate::hugetlb::scope_host_memory_limit;
 use crate::hugetlb::HugePageAllocator;
 use crate::hugetlb::UnpinnedHugePageAllocator;
+use crate::metrics::count_path;
+use crate::metrics::MetricsCollector;
+use crate::metrics::ProofMetrics;
 use crate::multiopen::gwc;
 use crate::multiopen::lookup_open;
 use crate::multiopen::permutation_product_open;
 use crate::multiopen::shplonk;
 use crate::multiopen::shuffle_open;
 use crate::multiopen::ProverQuery;
-
+use crate::permutation::ColumnValues;
+use crate::permutation::PermutationProducts;
+use crate::phases::Challenge;
+use crate::phases::Challenges;
+use crate::phases::ProofPhases;
+use crate::proof::absorb_domain_separation;
+use crate::shared_tables::SharedStaticTables;
+use crate::state::check_group_len;
+use crate::state::proof_digest;
+use crate::state::ProofState;
+use crate::transcript::TranscriptPipeline;
+
+pub mod audit;
+pub mod backend;
+mod cache;
+pub mod cli;
+pub mod compression;
+pub mod config;
+#[cfg(feature = "cross-check")]
+mod cross_check;
 pub mod cuda;
+pub mod cuda_pk;
 pub mod device;
+pub mod estimate;
+pub mod ffi;
+#[cfg(feature = "opencl")]
+pub mod opencl;
 
 mod eval_h;
+mod host;
 mod hugetlb;
+pub mod metrics;
 mod multiopen;
-
-const ADD_RANDOM: bool = true;
+#[cfg(feature = "node")]
+pub mod node;
+mod permutation;
+pub mod phases;
+pub mod plan;
+mod prefetch;
+pub mod proof;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod scheduler;
+mod scoped;
+pub mod selftest;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod shared_tables;
+pub mod state;
+pub mod stats;
+pub mod task;
+mod transcript;
+pub mod vk;
+pub mod witness;
+
+#[cfg(test)]
+mod test;
+
+pub use cache::clear_ntt_cache;
+pub use eval_h::clear_pk_device_cache;
+pub use hugetlb::host_memory_limit;
+pub use hugetlb::host_memory_usage;
+pub use hugetlb::huge_page_config;
+pub use hugetlb::set_host_memory_limit;
+pub use hugetlb::set_huge_page_config;
+pub use hugetlb::trim_host_buffer_cache;
+pub use hugetlb::HostMemoryUsage;
+pub use hugetlb::HugePageConfig;
 
 pub fn prepare_advice_buffer<C: CurveAffine>(
     pk: &ProvingKey<C>,
@@ -113,17 +207,64 @@ pub fn unpin_advice_buffer<C: CurveAffine>(
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("device error: {0}")]
     DeviceError(device::Error),
+    #[error("out of device memory: {0}")]
+    OutOfMemory(String),
+    #[error("out of host memory: {0}")]
+    HostOutOfMemory(String),
+    #[error("transcript error: {0}")]
+    TranscriptError(#[from] io::Error),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("kernel error: {0}")]
+    KernelError(String),
+    #[error("proof cancelled")]
+    Cancelled,
+    #[error("device timeout: {0}")]
+    Timeout(String),
+    #[error("panicked: {0}")]
+    Panicked(String),
 }
 
 impl From<device::Error> for Error {
     fn from(e: device::Error) -> Self {
-        Error::DeviceError(e)
+        match e {
+            device::Error::OutOfMemory(msg) => Error::OutOfMemory(msg),
+            device::Error::KernelError(msg) => Error::KernelError(msg),
+            device::Error::Timeout(msg) => Error::Timeout(msg),
+            device::Error::InvalidInput(msg) => Error::InvalidInput(msg),
+            device::Error::MsmError => Error::KernelError(device::Error::MsmError.to_string()),
+            e => Error::DeviceError(e),
+        }
     }
 }
 
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(e: std::sync::PoisonError<T>) -> Self {
+        Error::Panicked(e.to_string())
+    }
+}
+
+/// Turns a caught panic payload into `Error::Panicked`, keeping its message.
+pub(crate) fn panic_error(what: &str, e: Box<dyn std::any::Any + Send>) -> Error {
+    let msg = e
+        .downcast_ref::<&str>()
+        .map(|x| x.to_string())
+        .or_else(|| e.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+    Error::Panicked(format!("{}: {}", what, msg))
+}
+
+/// Joins a prover worker thread, turning its panic into an error.
+fn join_worker<T>(handler: thread::ScopedJoinHandle<'_, T>, name: &str) -> Result<T, Error> {
+    handler
+        .join()
+        .map_err(|e| panic_error(&format!("{} worker", name), e))
+}
+
 fn is_expression_pure_unit<F: FieldExt>(x: &Expression<F>) -> bool {
     x.is_constant().is_some()
         || x.is_pure_fixed().is_some()
@@ -165,33 +306,29 @@ fn lookup_classify<'a, 'b, C: CurveAffine, T>(
     return [single_unit_lookups, single_comp_lookups, tuple_lookups];
 }
 
-fn handle_lookup_pair<F: FieldExt>(
-    input: &mut Vec<F, HugePageAllocator>,
-    table: &mut Vec<F, HugePageAllocator>,
-    mut permuted_input: Vec<F, HugePageAllocator>,
-    mut permuted_table: Vec<F, HugePageAllocator>,
-    unusable_rows_start: usize,
-) -> (Vec<F, HugePageAllocator>, Vec<F, HugePageAllocator>) {
-    let compare = |a: &_, b: &_| unsafe {
+fn compare_scalar<F: FieldExt>(a: &F, b: &F) -> std::cmp::Ordering {
+    unsafe {
         let a: &[u64; 4] = std::mem::transmute(a);
         let b: &[u64; 4] = std::mem::transmute(b);
         a.cmp(b)
-    };
-
-    permuted_input[..].clone_from_slice(&input[..]);
-    let mut sorted_table = table.clone();
-
-    permuted_input[0..unusable_rows_start].sort_unstable_by(compare);
-    sorted_table[0..unusable_rows_start].sort_unstable_by(compare);
+    }
+}
 
+// Keeps the first occurrences of the sorted input values in place and fills
+// the other rows with the unused entries of the sorted table, in order.
+fn permute_table_on_host<F: FieldExt>(
+    permuted_input: &[F],
+    sorted_table: &[F],
+    permuted_table: &mut [F],
+) {
+    let n = permuted_input.len();
     let mut permuted_table_state = Vec::new_in(UnpinnedHugePageAllocator);
-    permuted_table_state.resize(input.len(), false);
+    permuted_table_state.resize(n, false);
 
     permuted_input
         .iter()
-        .take(unusable_rows_start)
-        .zip(permuted_table_state.iter_mut().take(unusable_rows_start))
-        .zip(permuted_table.iter_mut().take(unusable_rows_start))
+        .zip(permuted_table_state.iter_mut())
+        .zip(permuted_table.iter_mut())
         .enumerate()
         .for_each(|(row, ((input_value, table_state), table_value))| {
             // If this is the first occurrence of `input_value` in the input expression
@@ -202,16 +339,16 @@ fn handle_lookup_pair<F: FieldExt>(
         });
 
     let to_next_unique = |i: &mut usize| {
-        while *i < unusable_rows_start && !permuted_table_state[*i] {
+        while *i < n && !permuted_table_state[*i] {
             *i += 1;
         }
     };
 
     let mut i_unique_input_idx = 0;
     let mut i_sorted_table_idx = 0;
-    for i in 0..unusable_rows_start {
+    for i in 0..n {
         to_next_unique(&mut i_unique_input_idx);
-        while i_unique_input_idx < unusable_rows_start
+        while i_unique_input_idx < n
             && permuted_table[i_unique_input_idx] == sorted_table[i_sorted_table_idx]
         {
             i_unique_input_idx += 1;
@@ -223,15 +360,52 @@ fn handle_lookup_pair<F: FieldExt>(
             i_sorted_table_idx += 1;
         }
     }
+}
 
-    if ADD_RANDOM {
-        for cell in &mut permuted_input[unusable_rows_start..] {
-            *cell = F::random(&mut OsRng);
+fn handle_lookup_pair<F: FieldExt>(
+    input: &mut Vec<F, HugePageAllocator>,
+    table: &mut Vec<F, HugePageAllocator>,
+    mut permuted_input: Vec<F, HugePageAllocator>,
+    mut permuted_table: Vec<F, HugePageAllocator>,
+    unusable_rows_start: usize,
+    blinding: bool,
+    sorted_table: Option<&[F]>,
+    device: Option<&CudaDevice>,
+) -> (Vec<F, HugePageAllocator>, Vec<F, HugePageAllocator>) {
+    permuted_input[..].clone_from_slice(&input[..]);
+    permuted_input[0..unusable_rows_start].sort_unstable_by(compare_scalar);
+
+    let owned_sorted_table;
+    let sorted_table = match sorted_table {
+        Some(sorted_table) => sorted_table,
+        None => {
+            let mut sorted_table = table.clone();
+            sorted_table[0..unusable_rows_start].sort_unstable_by(compare_scalar);
+            owned_sorted_table = sorted_table;
+            &owned_sorted_table[..]
         }
-        for cell in &mut permuted_table[unusable_rows_start..] {
-            *cell = F::random(&mut OsRng);
+    };
+
+    let n = unusable_rows_start;
+    match device.map(|device| {
+        lookup_permute_table(
+            device,
+            &permuted_input[..n],
+            &sorted_table[..n],
+            &mut permuted_table[..n],
+        )
+    }) {
+        Some(Ok(())) => {}
+        Some(Err(e)) => {
+            tracing::warn!(error = ?e, "fail to permute lookup table on device, using host");
+            permute_table_on_host(&permuted_input[..n], sorted_table, &mut permuted_table[..n]);
         }
-    } else {
+        None => permute_table_on_host(&permuted_input[..n], sorted_table, &mut permuted_table[..n]),
+    }
+
+    // when blinding, the tails get blinders drawn on the host from the caller's
+    // rng before the msm, see `ProverBackend::blind_tails`
+    if !blinding {
         for cell in &mut permuted_input[unusable_rows_start..] {
             *cell = F::zero();
         }
@@ -354,43 +528,580 @@ pub fn evaluate_exprs<F: FieldExt>(
 pub fn create_proof_from_advices<
     C: CurveAffine,
     E: EncodedChallenge<C>,
-    T: TranscriptWrite<C, E>,
+    T: TranscriptWrite<C, E> + Send,
 >(
     params: &Params<C>,
     pk: &ProvingKey<C>,
     instances: &[&[C::Scalar]],
     advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
     transcript: &mut T,
+    rng: impl RngCore + Send,
 ) -> Result<(), Error> {
-    create_proof_from_advices_with_gwc(params, pk, instances, advices, transcript)
+    create_proof_from_advices_with_gwc(params, pk, instances, advices, transcript, rng)
 }
 
 pub fn create_proof_from_advices_with_gwc<
     C: CurveAffine,
     E: EncodedChallenge<C>,
-    T: TranscriptWrite<C, E>,
+    T: TranscriptWrite<C, E> + Send,
 >(
     params: &Params<C>,
     pk: &ProvingKey<C>,
     instances: &[&[C::Scalar]],
     advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
     transcript: &mut T,
+    rng: impl RngCore + Send,
 ) -> Result<(), Error> {
-    _create_proof_from_advices(params, pk, instances, advices, transcript, true)
+    _create_proof_from_advices(
+        params,
+        pk,
+        instances,
+        advices,
+        transcript,
+        rng,
+        true,
+        &ProverConfig::default(),
+        None,
+        None,
+        None,
+        None,
+    )
+    .map(|_| ())
 }
 
 pub fn create_proof_from_advices_with_shplonk<
     C: CurveAffine,
     E: EncodedChallenge<C>,
-    T: TranscriptWrite<C, E>,
+    T: TranscriptWrite<C, E> + Send,
 >(
     params: &Params<C>,
     pk: &ProvingKey<C>,
     instances: &[&[C::Scalar]],
     advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
     transcript: &mut T,
+    rng: impl RngCore + Send,
 ) -> Result<(), Error> {
-    _create_proof_from_advices(params, pk, instances, advices, transcript, false)
+    _create_proof_from_advices(
+        params,
+        pk,
+        instances,
+        advices,
+        transcript,
+        rng,
+        false,
+        &ProverConfig::default(),
+        None,
+        None,
+        None,
+        None,
+    )
+    .map(|_| ())
+}
+
+/// Like `create_proof_from_advices_with_gwc`/`_with_shplonk`, but every challenge
+/// (theta, beta, gamma, y, x and the multiopen ones) comes from `phases` instead
+/// of being squeezed from `transcript`.
+pub fn create_proof_from_advices_with_phases<
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send,
+>(
+    params: &Params<C>,
+    pk: &ProvingKey<C>,
+    instances: &[&[C::Scalar]],
+    advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+    transcript: &mut T,
+    rng: impl RngCore + Send,
+    use_gwc: bool,
+    phases: &mut dyn ProofPhases<C>,
+) -> Result<(), Error> {
+    _create_proof_from_advices(
+        params,
+        pk,
+        instances,
+        advices,
+        transcript,
+        rng,
+        use_gwc,
+        &ProverConfig::default(),
+        Some(phases),
+        None,
+        None,
+        None,
+    )
+    .map(|_| ())
+}
+
+/// Like `create_proof_from_advices_with_gwc`/`_with_shplonk`, with `blinding` controlling
+/// whether advice tails, lookup permuted columns, z polynomials and the vanishing random
+/// poly are randomized. Passing `false` produces a proof that is not zero-knowledge and is
+/// only meant for benchmarking.
+pub fn create_proof_from_advices_with_blinding<
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send,
+>(
+    params: &Params<C>,
+    pk: &ProvingKey<C>,
+    instances: &[&[C::Scalar]],
+    advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+    transcript: &mut T,
+    rng: impl RngCore + Send,
+    use_gwc: bool,
+    blinding: bool,
+) -> Result<(), Error> {
+    create_proof_from_advices_with_config(
+        params,
+        pk,
+        instances,
+        advices,
+        transcript,
+        rng,
+        use_gwc,
+        &ProverConfig {
+            blinding,
+            ..Default::default()
+        },
+    )
+    .map(|_| ())
+}
+
+/// Proves with the device, stream count, msm window, blinding, memory cap,
+/// host thread count and stream priority taken from `config`, and reports
+/// phase times, kernel times, memory peaks and buffer cache use.
+pub fn create_proof_from_advices_with_config<
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send,
+>(
+    params: &Params<C>,
+    pk: &ProvingKey<C>,
+    instances: &[&[C::Scalar]],
+    advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+    transcript: &mut T,
+    rng: impl RngCore + Send,
+    use_gwc: bool,
+    config: &ProverConfig,
+) -> Result<ProofMetrics, Error> {
+    let prove = || {
+        _create_proof_from_advices(
+            params, pk, instances, advices, transcript, rng, use_gwc, config, None, None, None,
+            None,
+        )
+    };
+    let res = install_cpu_threads(config, prove);
+    if res.is_err() {
+        // the buffers of the proof are dropped, its device can be reset
+        let _ = reset_failed_devices();
+    }
+    res
+}
+
+/// Frees everything kept on device `device_id` across proofs (ntt tables, the
+/// proving key caches, precomputed msm tables and jit kernels) and resets it,
+/// after an error that left its context unusable. `CudaParams` and
+/// `CudaProvingKey` uploaded to it before are stale afterwards and must be
+/// uploaded again. Call it once no proof runs on the device.
+pub fn invalidate_device(device_id: usize) -> Result<(), Error> {
+    clear_device_ntt_cache(device_id);
+    clear_device_pk_cache(device_id);
+    clear_device_precomputed_bases(device_id);
+    clear_jit_kernels(device_id);
+    CudaDevice::get_device(device_id)?.reset()?;
+    Ok(())
+}
+
+/// Resets with `invalidate_device` every device in
+/// `DeviceManager::failed_devices`, those that hung past the sync timeout or
+/// lost their context. The reset is deferred to here so it doesn't free the
+/// buffers of the proof that failed: `create_proof_from_advices_with_config`,
+/// `create_proofs_from_advices` and `create_proof_with_failover` call it when
+/// a proof fails, callers of the other entry points call it themselves once
+/// the failed proof has returned. The devices stay out of selection until
+/// `DeviceManager::mark_healthy`. Returns the last reset error.
+pub fn reset_failed_devices() -> Result<(), Error> {
+    let mut res = Ok(());
+    for device_id in DeviceManager::global().failed_devices() {
+        if let Err(e) = invalidate_device(device_id) {
+            tracing::warn!(device_id, error = ?e, "fail to reset device");
+            res = Err(e);
+        }
+    }
+    res
+}
+
+/// Like `create_proof_from_advices_with_config`, restarting the proof on
+/// another device, up to `config.failover_attempts` times, when its device
+/// fails with an error leaving the context unusable, e.g. an uncorrectable ECC
+/// error. The device is reset before the next attempt, or before the error of
+/// the last one is returned, and left out of selection until
+/// `DeviceManager::mark_healthy`. Each attempt writes to a transcript from
+/// `new_transcript`, the one of the attempt that succeeded is returned.
+pub fn create_proof_with_failover<
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send,
+>(
+    params: &Params<C>,
+    pk: &ProvingKey<C>,
+    instances: &[&[C::Scalar]],
+    advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+    mut new_transcript: impl FnMut() -> T,
+    rng: impl RngCore + Clone + Send,
+    use_gwc: bool,
+    config: &ProverConfig,
+) -> Result<(T, ProofMetrics), Error> {
+    let mut config = config.clone();
+    let mut attempt = 0;
+    loop {
+        let unhealthy = DeviceManager::global().unhealthy_devices();
+        let mut transcript = new_transcript();
+        let err = match create_proof_from_advices_with_config(
+            params,
+            pk,
+            instances,
+            advices.clone(),
+            &mut transcript,
+            rng.clone(),
+            use_gwc,
+            &config,
+        ) {
+            Ok(metrics) => return Ok((transcript, metrics)),
+            Err(err) => err,
+        };
+
+        let failed = DeviceManager::global()
+            .unhealthy_devices()
+            .difference(&unhealthy)
+            .copied()
+            .collect::<Vec<_>>();
+        // the failed devices were reset by `reset_failed_devices` as the attempt returned
+        if failed.is_empty() || attempt == config.failover_attempts {
+            return Err(err);
+        }
+        attempt += 1;
+        for device_id in failed {
+            tracing::error!(
+                device_id,
+                error = %err,
+                attempt,
+                "device failed, proving on another one"
+            );
+        }
+        config.device_id = None;
+    }
+}
+
+/// Proves against a proving key already on device, see `CudaProvingKey`. The
+/// proof runs on the device of `cuda_pk`, other settings are taken from `config`.
+pub fn create_proof_from_advices_with_cuda_pk<
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send,
+>(
+    params: &Params<C>,
+    cuda_pk: &CudaProvingKey<C>,
+    instances: &[&[C::Scalar]],
+    advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+    transcript: &mut T,
+    rng: impl RngCore + Send,
+    use_gwc: bool,
+    config: &ProverConfig,
+) -> Result<ProofMetrics, Error> {
+    if cuda_pk.is_stale() {
+        return Err(Error::InvalidInput(format!(
+            "device {} was reset since the proving key was uploaded",
+            cuda_pk.device_id()
+        )));
+    }
+    if config
+        .device_id
+        .map_or(false, |id| id != cuda_pk.device_id())
+    {
+        return Err(Error::InvalidInput(format!(
+            "config selects device {}, the proving key is on device {}",
+            config.device_id.unwrap(),
+            cuda_pk.device_id()
+        )));
+    }
+    let config = ProverConfig {
+        device_id: Some(cuda_pk.device_id()),
+        ..config.clone()
+    };
+    let prove = || {
+        _create_proof_from_advices(
+            params,
+            cuda_pk.pk,
+            instances,
+            advices,
+            transcript,
+            rng,
+            use_gwc,
+            &config,
+            None,
+            None,
+            Some(cuda_pk),
+            None,
+        )
+    };
+    install_cpu_threads(&config, prove)
+}
+
+/// Proves with the SRS bases already on device, see `backend::CudaParams`.
+/// A proof on a device `cuda_params` doesn't cover uploads its own copy.
+pub fn create_proof_from_advices_with_cuda_params<
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send,
+>(
+    cuda_params: &CudaParams<C>,
+    pk: &ProvingKey<C>,
+    instances: &[&[C::Scalar]],
+    advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+    transcript: &mut T,
+    rng: impl RngCore + Send,
+    use_gwc: bool,
+    config: &ProverConfig,
+) -> Result<ProofMetrics, Error> {
+    let prove = || {
+        _create_proof_from_advices(
+            cuda_params.params,
+            pk,
+            instances,
+            advices,
+            transcript,
+            rng,
+            use_gwc,
+            config,
+            None,
+            None,
+            None,
+            Some(cuda_params),
+        )
+    };
+    install_cpu_threads(config, prove)
+}
+
+/// Proves several advice sets of the same circuit, in order, one proof per
+/// transcript. The proving key and the SRS bases are uploaded once to the
+/// device of `config`, or the one `DeviceManager` selects when it names none,
+/// and kept for all proofs; device scratch buffers are
+/// recycled between the proofs by the buffer cache. Returns the metrics of
+/// each proof.
+pub fn create_proofs_from_advices<
+    'a,
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send + 'a,
+>(
+    params: &Params<C>,
+    pk: &ProvingKey<C>,
+    proofs: Vec<(&[&[C::Scalar]], Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>)>,
+    transcripts: impl IntoIterator<Item = &'a mut T>,
+    mut rng: impl RngCore + Send,
+    use_gwc: bool,
+    config: &ProverConfig,
+) -> Result<Vec<ProofMetrics>, Error> {
+    let transcripts = transcripts.into_iter().collect::<Vec<_>>();
+    if transcripts.len() != proofs.len() {
+        return Err(Error::InvalidInput(format!(
+            "{} transcripts given for {} proofs",
+            transcripts.len(),
+            proofs.len()
+        )));
+    }
+
+    let device_id = match config.device_id {
+        Some(idx) => idx,
+        None => DeviceManager::global().select_device()?.device_id(),
+    };
+    let timer = start_timer!(|| "upload proving key and srs");
+    let cuda_pk = CudaProvingKey::new(pk, device_id)?;
+    let cuda_params = CudaParams::new(params, &[device_id])?;
+    end_timer!(timer);
+    let config = ProverConfig {
+        device_id: Some(device_id),
+        ..config.clone()
+    };
+
+    let prove = || {
+        let mut metrics = vec![];
+        for ((instances, advices), transcript) in proofs.into_iter().zip(transcripts) {
+            metrics.push(_create_proof_from_advices(
+                params,
+                pk,
+                instances,
+                advices,
+                transcript,
+                &mut rng,
+                use_gwc,
+                &config,
+                None,
+                None,
+                Some(&cuda_pk),
+                Some(&cuda_params),
+            )?);
+        }
+        Ok(metrics)
+    };
+    let res = install_cpu_threads(&config, prove);
+    if res.is_err() {
+        let _ = reset_failed_devices();
+    }
+    res
+}
+
+// runs `prove` in a pool of `config.cpu_threads` threads if set
+fn install_cpu_threads<R: Send>(
+    config: &ProverConfig,
+    prove: impl FnOnce() -> Result<R, Error> + Send,
+) -> Result<R, Error> {
+    match config.cpu_threads {
+        Some(threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| {
+                Error::DeviceError(device::Error::DeviceError(format!(
+                    "fail to build thread pool: {}",
+                    e
+                )))
+            })?
+            .install(prove),
+        None => prove(),
+    }
+}
+
+/// Proves one segment of a batch whose static lookup tables were prepared once
+/// in `shared_tables`, see `create_segment_proofs_from_advices`. The proof runs
+/// on the device of the tables.
+pub fn create_proof_from_advices_with_shared_tables<
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send,
+>(
+    params: &Params<C>,
+    pk: &ProvingKey<C>,
+    instances: &[&[C::Scalar]],
+    advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+    transcript: &mut T,
+    rng: impl RngCore + Send,
+    use_gwc: bool,
+    shared_tables: &SharedStaticTables<C>,
+) -> Result<(), Error> {
+    _create_proof_from_advices(
+        params,
+        pk,
+        instances,
+        advices,
+        transcript,
+        rng,
+        use_gwc,
+        &ProverConfig {
+            device_id: Some(shared_tables.device_id()),
+            ..Default::default()
+        },
+        None,
+        Some(shared_tables),
+        None,
+        None,
+    )
+    .map(|_| ())
+}
+
+/// Proves the segments of a zkWasm continuation, preparing the tables of
+/// `static_lookups` once and reusing them for every segment.
+pub fn create_segment_proofs_from_advices<
+    'a,
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send + 'a,
+>(
+    params: &Params<C>,
+    pk: &ProvingKey<C>,
+    static_lookups: &[usize],
+    segments: impl IntoIterator<
+        Item = (
+            &'a [&'a [C::Scalar]],
+            Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+            &'a mut T,
+        ),
+    >,
+    use_gwc: bool,
+    mut rng: impl RngCore + Send,
+) -> Result<(), Error> {
+    let timer = start_timer!(|| "prepare shared static tables");
+    let device = DeviceManager::global().select_device()?;
+    let shared_tables = SharedStaticTables::new(pk, static_lookups, &device)?;
+    end_timer!(timer);
+
+    for (instances, advices, transcript) in segments {
+        create_proof_from_advices_with_shared_tables(
+            params,
+            pk,
+            instances,
+            advices,
+            transcript,
+            &mut rng,
+            use_gwc,
+            &shared_tables,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Like `create_segment_proofs_from_advices`, with the next segments pulled from
+/// `segments` on a worker thread while the current one is proven. Segments are
+/// fetched ahead as long as the advices fetched but not yet proven fit in
+/// `host_memory_budget` bytes.
+pub fn create_segment_proofs_from_advices_with_prefetch<
+    'a,
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send + 'a,
+    S: IntoIterator<
+        Item = (
+            &'a [&'a [C::Scalar]],
+            Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+            &'a mut T,
+        ),
+    >,
+>(
+    params: &Params<C>,
+    pk: &ProvingKey<C>,
+    static_lookups: &[usize],
+    segments: S,
+    use_gwc: bool,
+    mut rng: impl RngCore + Send,
+    host_memory_budget: usize,
+) -> Result<(), Error>
+where
+    S::IntoIter: Send,
+{
+    let timer = start_timer!(|| "prepare shared static tables");
+    let device = DeviceManager::global().select_device()?;
+    let shared_tables = SharedStaticTables::new(pk, static_lookups, &device)?;
+    end_timer!(timer);
+
+    prefetch::prefetch(
+        segments,
+        host_memory_budget,
+        |(_, advices, _)| {
+            advices.iter().map(|x| x.len()).sum::<usize>() * std::mem::size_of::<C::Scalar>()
+        },
+        |(instances, advices, transcript)| {
+            create_proof_from_advices_with_shared_tables(
+                params,
+                pk,
+                instances,
+                advices,
+                transcript,
+                &mut rng,
+                use_gwc,
+                &shared_tables,
+            )
+        },
+    )
 }
 
 pub fn prepare_lookup_buffer<C: CurveAffine>(
@@ -499,20 +1210,56 @@ pub fn prepare_shuffle_buffers<C: CurveAffine>(
     Ok(buffers)
 }
 
-fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: TranscriptWrite<C, E>>(
+// "k = 22" for a power of two row count, "not a power of two" otherwise
+fn describe_rows(rows: usize) -> String {
+    if rows.is_power_of_two() {
+        format!("k = {}", rows.trailing_zeros())
+    } else {
+        "not a power of two".to_string()
+    }
+}
+
+fn _create_proof_from_advices<
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send,
+>(
     params: &Params<C>,
     pk: &ProvingKey<C>,
     instances: &[&[C::Scalar]],
     mut advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
     transcript: &mut T,
+    mut rng: impl RngCore + Send,
     use_gwc: bool,
-) -> Result<(), Error> {
+    config: &ProverConfig,
+    phases: Option<&mut dyn ProofPhases<C>>,
+    shared_tables: Option<&SharedStaticTables<C>>,
+    cuda_pk: Option<&CudaProvingKey<C>>,
+    cuda_params: Option<&CudaParams<C>>,
+) -> Result<ProofMetrics, Error> {
+    let blinding = config.blinding;
     if pk.ev.gpu_gates_expr.len() != 1 {
-        println!("Multi-GPU detected, please set CUDA_VISIBLE_DEVICES to use one GPU");
-        assert!(false);
+        error!("Multi-GPU detected, please set CUDA_VISIBLE_DEVICES to use one GPU");
+        return Err(Error::InvalidInput(format!(
+            "proving key has gates expressions for {} gpus, expect 1",
+            pk.ev.gpu_gates_expr.len()
+        )));
     }
 
-    println!("k is {}", pk.get_vk().domain.k());
+    let _proof_span = info_span!("create_proof", k = pk.get_vk().domain.k()).entered();
+    let mut metrics = MetricsCollector::start().with_observer(config.observer.clone());
+
+    let _host_memory_limit = config.host_memory_limit.map(scope_host_memory_limit);
+    if let Some(limit) = config.host_memory_limit {
+        let estimate = estimate_host_memory(pk);
+        if estimate.pinned + estimate.unpinned > limit {
+            return Err(Error::HostOutOfMemory(format!(
+                "proof needs about {} bytes of host buffers, the limit is {}",
+                estimate.pinned + estimate.unpinned,
+                limit
+            )));
+        }
+    }
 
     thread::scope(|s| {
         let k = pk.get_vk().domain.k() as usize;
@@ -523,9 +1270,83 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
 
         let domain = &pk.vk.domain;
 
-        pk.vk.hash_into(transcript).unwrap();
+        if instances.len() != pk.get_vk().cs.num_instance_columns {
+            return Err(Error::InvalidInput(format!(
+                "{} instance columns given, circuit has {}",
+                instances.len(),
+                pk.get_vk().cs.num_instance_columns
+            )));
+        }
+        if advices.len() != pk.get_vk().cs.num_advice_columns {
+            return Err(Error::InvalidInput(format!(
+                "{} advice columns given, circuit has {}",
+                advices.len(),
+                pk.get_vk().cs.num_advice_columns
+            )));
+        }
+        for (i, instance) in instances.iter().enumerate() {
+            if instance.len() > unusable_rows_start {
+                return Err(Error::InvalidInput(format!(
+                    "instance column {} has {} rows, the proving key (k = {}) has {} usable rows",
+                    i,
+                    instance.len(),
+                    k,
+                    unusable_rows_start
+                )));
+            }
+        }
+        if let Some((i, advice)) = advices.iter().enumerate().find(|(_, x)| x.len() != size) {
+            let short = advice.len() < size;
+            // only the short columns are padded
+            let fits = advices
+                .iter()
+                .all(|x| x.len() == size || x.len() <= unusable_rows_start);
+            if !(config.pad_short_advices && fits) {
+                return Err(Error::InvalidInput(format!(
+                    "advice column {} has {} rows ({}), the proving key has k = {} ({} rows){}",
+                    i,
+                    advice.len(),
+                    describe_rows(advice.len()),
+                    k,
+                    size,
+                    if short && fits && !config.pad_short_advices {
+                        ", set ProverConfig::pad_short_advices to zero-pad it"
+                    } else {
+                        ""
+                    }
+                )));
+            }
+            // the caller may hold other references to the columns, they are
+            // padded in place only when this is the last one
+            match Arc::get_mut(&mut advices) {
+                Some(advices) => {
+                    for advice in advices.iter_mut() {
+                        advice.resize(size, C::Scalar::zero());
+                    }
+                }
+                None => {
+                    advices = Arc::new(
+                        advices
+                            .par_iter()
+                            .map(|x| {
+                                let mut advice = Vec::with_capacity_in(size, HugePageAllocator);
+                                advice.extend_from_slice(&x[..]);
+                                advice.resize(size, C::Scalar::zero());
+                                advice
+                            })
+                            .collect::<Vec<_>>(),
+                    );
+                }
+            }
+        }
 
-        assert!(instances.len() == pk.get_vk().cs.num_instance_columns);
+        // rejected input leaves the transcript untouched
+        if let Some(tag) = &config.domain_separation {
+            absorb_domain_separation::<C, E, _>(transcript, tag)?;
+        }
+        pk.vk.hash_into(transcript)?;
+        let mut challenges = Challenges::new(phases);
+        let pipeline = TranscriptPipeline::<C, T>::new::<E>(s, &mut *transcript);
 
         let mut instances = Arc::new(
             instances
@@ -539,44 +1360,102 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                 .collect::<Vec<_>>(),
         );
 
-        let device = CudaDevice::get_device(0).unwrap();
-
-        device.synchronize()?;
-        device.print_memory_info()?;
-
         // add random value
-        if ADD_RANDOM {
+        let mut seeds = vec![];
+        if blinding {
             let named = &pk.vk.cs.named_advices;
+            seeds = advices
+                .iter()
+                .map(|_| chacha_seed(&mut rng))
+                .collect::<Vec<_>>();
             unsafe { Arc::get_mut_unchecked(&mut advices) }
                 .par_iter_mut()
+                .zip(seeds.par_iter())
                 .enumerate()
-                .for_each(|(i, advice)| {
+                .for_each(|(i, (advice, seed))| {
                     if named.iter().find(|n| n.1 as usize == i).is_none() {
+                        let mut rng = StdRng::from_seed(*seed);
                         for cell in &mut advice[unusable_rows_start..] {
-                            *cell = C::Scalar::random(&mut OsRng);
+                            *cell = C::Scalar::random(&mut rng);
                         }
                     }
                 });
         }
+        // commitment groups already in the state are written instead of committed
+        let state = config.state.clone().unwrap_or_default();
+        if config.state.is_some() {
+            state.bind(proof_digest(pk, &instances[..], &advices[..], &seeds[..])?)?;
+        }
 
-        let timer = start_timer!(|| "copy g_lagrange buffer");
-        let g_lagrange_buf = device
-            .alloc_device_buffer_from_slice(&params.g_lagrange[..])
-            .unwrap();
-        let g_buf = device
-            .alloc_device_buffer_from_slice(&params.g[..])
-            .unwrap();
+        enter_phase(&mut metrics, config, "advice")?;
+        let timer = start_timer!(|| "prepare backend");
+        let _sync_debug = config.sync_debug.then(scope_sync_debug);
+        let backend = select_backend_with_params(
+            params,
+            domain,
+            config.device_id,
+            cuda_params,
+            config.backend,
+        )?;
+        #[cfg(feature = "cross-check")]
+        let backend: Box<dyn ProverBackend<C> + '_> =
+            Box::new(cross_check::CrossCheckBackend::new(backend, params, domain));
+        let _stream_ordered_alloc = match (backend.as_cuda(), config.stream_ordered_alloc) {
+            (Some(cuda), true) => Some(cuda.device.scope_stream_ordered_alloc()?),
+            _ => None,
+        };
+        let _l2_persistence = match (backend.as_cuda(), config.l2_persistence) {
+            (Some(cuda), true) => Some(cuda.device.scope_l2_persistence()),
+            _ => None,
+        };
+        let _memory_cap = match (backend.as_cuda(), config.memory_cap) {
+            (Some(cuda), Some(cap)) => Some(cuda.device.scope_memory_cap(cap)),
+            _ => None,
+        };
+        // held until the proof is done, a later proof may lower the grant
+        let _reservation = match (backend.as_cuda(), config.memory_reservation) {
+            (Some(cuda), Some((preferred, minimum))) => {
+                Some(cuda.device.negotiate_reservation(preferred, minimum)?)
+            }
+            _ => None,
+        };
+        let _msm_window_bits = config.msm_window_bits.map(scope_msm_window_bits);
+        // the table is computed for the window bits the msm will use
+        if let (Some(cuda), true) = (backend.as_cuda(), config.autotune) {
+            cuda.autotune(domain)?;
+        }
+        if let Some(cuda) = backend.as_cuda() {
+            cuda.use_precomputed_lagrange(
+                params,
+                config.msm_precompute_factor,
+                config.msm_precompute_dir.as_deref(),
+            )?;
+        }
+        let _stream_priority = scope_stream_priority(config.stream_priority);
+        let _sync_timeout = config.sync_timeout.map(scope_sync_timeout);
+        set_intermediate_domain(config.intermediate_domain);
+        set_coset_sliced_h(config.coset_sliced_h);
+        set_multi_device_fft(config.multi_device_fft);
+        set_jit_gates(config.jit_gates);
+        set_expr_streams(config.expr_streams);
+        set_cuda_graphs(config.cuda_graphs);
+        let _audit = config.audit.then(enter_audit_mode);
         end_timer!(timer);
 
         // thread for part of lookups
+        let lookup_device = backend
+            .as_cuda()
+            .filter(|cuda| config.gpu_permuted_table && cuda.curve.full_prover())
+            .map(|cuda| cuda.device.clone());
+        let tuple_lookup_device = lookup_device.clone();
         let sub_pk = pk.clone();
         let sub_advices = advices.clone();
         let sub_instances = instances.clone();
         let lookup_handler = s.spawn(move || {
             let timer = start_timer!(|| "prepare buffers");
-            let lookups = prepare_lookup_buffer(pk).unwrap();
-            let permutations = prepare_permutation_buffers(pk).unwrap();
-            let shuffles = prepare_shuffle_buffers(pk).unwrap();
+            let lookups = prepare_lookup_buffer(pk)?;
+            let permutations = prepare_permutation_buffers(pk)?;
+            let shuffles = prepare_shuffle_buffers(pk)?;
             end_timer!(timer);
 
             let pk = sub_pk;
@@ -613,6 +1492,11 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                             permuted_input,
                             permuted_table,
                             unusable_rows_start,
+                            blinding,
+                            shared_tables
+                                .and_then(|x| x.sorted_tables.get(&i))
+                                .map(|x| &x[..]),
+                            lookup_device.as_ref(),
                         );
                         (i, (permuted_input, permuted_table, input, table, z))
                     },
@@ -650,6 +1534,11 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                             permuted_input,
                             permuted_table,
                             unusable_rows_start,
+                            blinding,
+                            shared_tables
+                                .and_then(|x| x.sorted_tables.get(&i))
+                                .map(|x| &x[..]),
+                            lookup_device.as_ref(),
                         );
                         (i, (permuted_input, permuted_table, input, table, z))
                     },
@@ -657,42 +1546,40 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                 .collect::<Vec<_>>();
             end_timer!(timer);
 
-            (
+            Ok::<_, Error>((
                 single_unit_lookups,
                 single_comp_lookups,
                 tuple_lookups,
                 permutations,
                 shuffles,
-            )
+            ))
         });
 
-        let s_buf = device.alloc_device_buffer::<C::Scalar>(size)?;
-        let t_buf = device.alloc_device_buffer::<C::Scalar>(size)?;
-
         // Advice MSM
         let timer = start_timer!(|| format!(
             "instances and advices msm {}",
             instances.len() + advices.len()
         ));
-        let commitments = crate::cuda::bn254::batch_msm::<C>(
-            &g_lagrange_buf,
-            [&s_buf, &t_buf],
-            instances
-                .iter()
-                .chain(advices.iter())
-                .map(|x| &x[..])
-                .collect(),
-            size,
-        )?;
+        let commitments = state.commit("advice", instances.len() + advices.len(), || {
+            backend.commit(
+                CommitmentBasis::Lagrange,
+                instances
+                    .iter()
+                    .chain(advices.iter())
+                    .map(|x| &x[..])
+                    .collect(),
+            )
+        })?;
         for commitment in commitments.iter().take(instances.len()) {
-            transcript.common_point(*commitment).unwrap();
+            pipeline.common_point(*commitment)?;
         }
         for commitment in commitments.into_iter().skip(instances.len()) {
-            transcript.write_point(commitment).unwrap();
+            pipeline.write_point(commitment)?;
         }
         end_timer!(timer);
 
-        let theta: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
+        let theta: C::Scalar = pipeline.squeeze(&mut challenges, Challenge::Theta)?;
+        state.squeezed("theta", theta)?;
 
         let timer = start_timer!(|| "wait single lookups");
         let (
@@ -701,7 +1588,7 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
             mut tuple_lookups,
             permutations,
             shuffles,
-        ) = lookup_handler.join().unwrap();
+        ) = join_worker(lookup_handler, "lookup")??;
         end_timer!(timer);
 
         // After theta
@@ -709,6 +1596,7 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
         let sub_advices = advices.clone();
         let sub_instance = instances.clone();
         let tuple_lookup_handler = s.spawn(move || {
+            let lookup_device = tuple_lookup_device;
             let pk = sub_pk;
             let advices = sub_advices;
             let instances = sub_instance;
@@ -747,6 +1635,9 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                             permuted_input,
                             permuted_table,
                             unusable_rows_start,
+                            blinding,
+                            None,
+                            lookup_device.as_ref(),
                         );
                         (i, (permuted_input, permuted_table, input, table, z))
                     },
@@ -757,7 +1648,11 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
             tuple_lookups
         });
 
+        enter_phase(&mut metrics, config, "lookup")?;
+        let replayed_lookups = state.commitments::<C>("lookup")?;
         let mut lookup_permuted_commitments = vec![C::identity(); pk.vk.cs.lookups.len() * 2];
+        // lookup -> its permuted input and table on device, with `resident_permuted`
+        let mut resident_permuted = BTreeMap::new();
 
         let timer = start_timer!(|| format!(
             "single lookup msm {} {}",
@@ -766,67 +1661,88 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
         ));
 
         {
-            let mut lookup_scalars = vec![];
-            for (_, (permuted_input, permuted_table, _, _, _)) in single_unit_lookups.iter() {
-                lookup_scalars.push(&permuted_input[..]);
-                lookup_scalars.push(&permuted_table[..])
-            }
-            for (_, (permuted_input, permuted_table, _, _, _)) in single_comp_lookups.iter() {
-                lookup_scalars.push(&permuted_input[..]);
-                lookup_scalars.push(&permuted_table[..])
-            }
-            let commitments = crate::cuda::bn254::batch_msm::<C>(
-                &g_lagrange_buf,
-                [&s_buf, &t_buf],
-                lookup_scalars,
-                size,
-            )?;
-            let mut tidx = 0;
-            for (i, _) in single_unit_lookups.iter() {
-                lookup_permuted_commitments[i * 2] = commitments[tidx];
-                lookup_permuted_commitments[i * 2 + 1] = commitments[tidx + 1];
-                tidx += 2;
+            if blinding {
+                let mut tails = vec![];
+                for (_, (permuted_input, permuted_table, _, _, _)) in single_unit_lookups
+                    .iter_mut()
+                    .chain(single_comp_lookups.iter_mut())
+                {
+                    tails.push(&mut permuted_input[..]);
+                    tails.push(&mut permuted_table[..]);
+                }
+                backend.blind_tails(tails, unusable_rows_start, &mut rng)?;
             }
-            for (i, _) in single_comp_lookups.iter() {
-                lookup_permuted_commitments[i * 2] = commitments[tidx];
-                lookup_permuted_commitments[i * 2 + 1] = commitments[tidx + 1];
-                tidx += 2;
+
+            let lookup_pairs = single_unit_lookups
+                .iter()
+                .chain(single_comp_lookups.iter())
+                .map(|(i, (permuted_input, permuted_table, _, _, _))| {
+                    (*i, [&permuted_input[..], &permuted_table[..]])
+                })
+                .collect();
+            if replayed_lookups.is_none() {
+                commit_permuted_pairs(
+                    backend.as_ref(),
+                    config,
+                    lookup_pairs,
+                    &mut lookup_permuted_commitments,
+                    &mut resident_permuted,
+                )?;
             }
         }
         end_timer!(timer);
 
         let timer = start_timer!(|| "wait tuple lookup");
-        let mut tuple_lookups = tuple_lookup_handler.join().unwrap();
+        let mut tuple_lookups = join_worker(tuple_lookup_handler, "tuple lookup")?;
         end_timer!(timer);
 
         let timer = start_timer!(|| format!("tuple lookup msm {}", tuple_lookups.len()));
         {
-            let mut lookup_scalars = vec![];
-            for (_, (permuted_input, permuted_table, _, _, _)) in tuple_lookups.iter() {
-                lookup_scalars.push(&permuted_input[..]);
-                lookup_scalars.push(&permuted_table[..])
+            if blinding {
+                let mut tails = vec![];
+                for (_, (permuted_input, permuted_table, _, _, _)) in tuple_lookups.iter_mut() {
+                    tails.push(&mut permuted_input[..]);
+                    tails.push(&mut permuted_table[..]);
+                }
+                backend.blind_tails(tails, unusable_rows_start, &mut rng)?;
             }
-            let commitments = crate::cuda::bn254::batch_msm::<C>(
-                &g_lagrange_buf,
-                [&s_buf, &t_buf],
-                lookup_scalars,
-                size,
-            )?;
-            let mut tidx = 0;
-            for (i, _) in tuple_lookups.iter() {
-                lookup_permuted_commitments[i * 2] = commitments[tidx];
-                lookup_permuted_commitments[i * 2 + 1] = commitments[tidx + 1];
-                tidx += 2;
+
+            let lookup_pairs = tuple_lookups
+                .iter()
+                .map(|(i, (permuted_input, permuted_table, _, _, _))| {
+                    (*i, [&permuted_input[..], &permuted_table[..]])
+                })
+                .collect();
+            if replayed_lookups.is_none() {
+                commit_permuted_pairs(
+                    backend.as_ref(),
+                    config,
+                    lookup_pairs,
+                    &mut lookup_permuted_commitments,
+                    &mut resident_permuted,
+                )?;
             }
         }
         end_timer!(timer);
 
+        let lookup_permuted_commitments = match replayed_lookups {
+            Some(commitments) => {
+                check_group_len("lookup", &commitments[..], pk.vk.cs.lookups.len() * 2)?;
+                commitments
+            }
+            None => {
+                state.replace("lookup", &lookup_permuted_commitments[..])?;
+                lookup_permuted_commitments
+            }
+        };
         for commitment in lookup_permuted_commitments.into_iter() {
-            transcript.write_point(commitment).unwrap();
+            pipeline.write_point(commitment)?;
         }
 
-        let beta: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
-        let gamma: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
+        let beta: C::Scalar = pipeline.squeeze(&mut challenges, Challenge::Beta)?;
+        let gamma: C::Scalar = pipeline.squeeze(&mut challenges, Challenge::Gamma)?;
+        state.squeezed("beta", beta)?;
+        state.squeezed("gamma", gamma)?;
 
         let mut lookups = vec![];
         lookups.append(&mut single_unit_lookups);
@@ -838,12 +1754,17 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
 
         let waker = Arc::new((Mutex::new(false), Condvar::new()));
         let waiter = Arc::clone(&waker);
+        let (permutation_sender, permutation_receiver) = std::sync::mpsc::channel();
         let permutation_products_handler = {
             let timer = start_timer!(|| format!(
                 "product permutation {}",
                 (&pk).vk.cs.permutation.columns.chunks(chunk_len).len()
             ));
 
+            let permutation_device = backend
+                .as_cuda()
+                .filter(|cuda| config.gpu_permutation_products && cuda.curve.full_prover())
+                .map(|cuda| cuda.device.clone());
             let sub_pk = pk.clone();
             let sub_advices = advices.clone();
             let sub_instance = instances.clone();
@@ -855,118 +1776,155 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                 let fixed_ref = &pk.fixed_values.iter().map(|x| &x[..]).collect::<Vec<_>>()[..];
                 let advice_ref = &advices.iter().map(|x| &x[..]).collect::<Vec<_>>()[..];
                 let instance_ref = &instances.iter().map(|x| &x[..]).collect::<Vec<_>>()[..];
-                let mut p_z = pk
-                    .vk
-                    .cs
-                    .permutation
-                    .columns
-                    .par_chunks(chunk_len)
-                    .zip((&pk).permutation.permutations.par_chunks(chunk_len))
-                    .zip(permutations)
-                    .enumerate()
-                    .map(|(i, ((columns, permutations), mut modified_values))| {
-                        let mut delta_omega =
-                            C::Scalar::DELTA.pow_vartime([i as u64 * chunk_len as u64]);
-
-                        let chunk_size = size >> 2;
-                        // Iterate over each column of the permutation
-                        for (j, (&column, permuted_column_values)) in
-                            columns.iter().zip(permutations.iter()).enumerate()
+                let res = match permutation_device {
+                    Some(device) => (|| {
+                        count_path("gpu_permutation_products");
+                        let mut products =
+                            PermutationProducts::new(&device, size, omega, beta, gamma)?;
+                        for (i, ((columns, sigmas), mut modified_values)) in pk
+                            .vk
+                            .cs
+                            .permutation
+                            .columns
+                            .chunks(chunk_len)
+                            .zip(pk.permutation.permutations.chunks(chunk_len))
+                            .zip(permutations)
+                            .enumerate()
                         {
-                            let values = match column.column_type() {
-                                Any::Advice => advice_ref,
-                                Any::Fixed => fixed_ref,
-                                Any::Instance => instance_ref,
-                            };
-                            modified_values
-                                .par_chunks_mut(chunk_size)
-                                .zip(permuted_column_values.par_chunks(chunk_size))
-                                .zip(values[column.index()].par_chunks(chunk_size))
-                                .for_each(|((res, p), v)| {
-                                    for i in 0..chunk_size {
-                                        if j == 0 {
-                                            res[i] = beta * p[i] + &gamma + v[i];
-                                        } else {
-                                            res[i] *= &(beta * p[i] + &gamma + v[i]);
+                            let columns = columns
+                                .iter()
+                                .zip(sigmas)
+                                .map(|(column, sigma)| {
+                                    let values = match (column.column_type(), cuda_pk) {
+                                        (Any::Fixed, Some(cuda_pk))
+                                            if cuda_pk.device_id() == device.device_id() =>
+                                        {
+                                            ColumnValues::Device(
+                                                &cuda_pk.fixed_values[column.index()],
+                                            )
                                         }
-                                    }
-                                });
+                                        (Any::Fixed, _) => {
+                                            ColumnValues::Host(fixed_ref[column.index()])
+                                        }
+                                        (Any::Advice, _) => {
+                                            ColumnValues::Host(advice_ref[column.index()])
+                                        }
+                                        (Any::Instance, _) => {
+                                            ColumnValues::Host(instance_ref[column.index()])
+                                        }
+                                    };
+                                    (values, &sigma[..])
+                                })
+                                .collect::<Vec<_>>();
+                            let delta = C::Scalar::DELTA.pow_vartime([i as u64 * chunk_len as u64]);
+                            products.chunk(&columns, delta, &mut modified_values[..])?;
+
+                            let tail = permutation_chunk_tail(
+                                &mut modified_values,
+                                unusable_rows_start,
+                                blinding,
+                            );
+                            if permutation_sender.send((i, modified_values, tail)).is_err() {
+                                break;
+                            }
                         }
+                        Ok::<_, Error>(())
+                    })(),
+                    None => {
+                        pk.vk
+                            .cs
+                            .permutation
+                            .columns
+                            .par_chunks(chunk_len)
+                            .zip((&pk).permutation.permutations.par_chunks(chunk_len))
+                            .zip(permutations)
+                            .enumerate()
+                            .for_each_with(
+                                permutation_sender,
+                                |sender, (i, ((columns, permutations), mut modified_values))| {
+                                    let mut delta_omega =
+                                        C::Scalar::DELTA.pow_vartime([i as u64 * chunk_len as u64]);
+
+                                    let chunk_size = size >> 2;
+                                    // Iterate over each column of the permutation
+                                    for (j, (&column, permuted_column_values)) in
+                                        columns.iter().zip(permutations.iter()).enumerate()
+                                    {
+                                        let values = match column.column_type() {
+                                            Any::Advice => advice_ref,
+                                            Any::Fixed => fixed_ref,
+                                            Any::Instance => instance_ref,
+                                        };
+                                        modified_values
+                                            .par_chunks_mut(chunk_size)
+                                            .zip(permuted_column_values.par_chunks(chunk_size))
+                                            .zip(values[column.index()].par_chunks(chunk_size))
+                                            .for_each(|((res, p), v)| {
+                                                for i in 0..chunk_size {
+                                                    if j == 0 {
+                                                        res[i] = beta * p[i] + &gamma + v[i];
+                                                    } else {
+                                                        res[i] *= &(beta * p[i] + &gamma + v[i]);
+                                                    }
+                                                }
+                                            });
+                                    }
 
-                        // Invert to obtain the denominator for the permutation product polynomial
-                        modified_values.par_chunks_mut(chunk_size).for_each(|x| {
-                            x.iter_mut().batch_invert();
-                        });
-
-                        // Iterate over each column again, this time finishing the computation
-                        // of the entire fraction by computing the numerators
-                        for &column in columns.iter() {
-                            let values = match column.column_type() {
-                                Any::Advice => advice_ref,
-                                Any::Fixed => fixed_ref,
-                                Any::Instance => instance_ref,
-                            };
-
-                            modified_values
-                                .par_chunks_mut(chunk_size)
-                                .zip(values[column.index()].par_chunks(chunk_size))
-                                .enumerate()
-                                .for_each(|(idx, (res, v))| {
-                                    let mut delta_omega = delta_omega
-                                        * omega.pow_vartime([(idx * chunk_size) as u64])
-                                        * &beta;
-                                    for i in 0..chunk_size {
-                                        res[i] *= &(delta_omega + &gamma + v[i]);
-                                        delta_omega *= &omega;
+                                    // Invert to obtain the denominator of the permutation product
+                                    modified_values.par_chunks_mut(chunk_size).for_each(|x| {
+                                        x.iter_mut().batch_invert();
+                                    });
+
+                                    // Iterate over each column again, this time finishing
+                                    // the computation of the entire fraction by computing
+                                    // the numerators
+                                    for &column in columns.iter() {
+                                        let values = match column.column_type() {
+                                            Any::Advice => advice_ref,
+                                            Any::Fixed => fixed_ref,
+                                            Any::Instance => instance_ref,
+                                        };
+
+                                        modified_values
+                                            .par_chunks_mut(chunk_size)
+                                            .zip(values[column.index()].par_chunks(chunk_size))
+                                            .enumerate()
+                                            .for_each(|(idx, (res, v))| {
+                                                let mut delta_omega = delta_omega
+                                                    * omega
+                                                        .pow_vartime([(idx * chunk_size) as u64])
+                                                    * &beta;
+                                                for i in 0..chunk_size {
+                                                    res[i] *= &(delta_omega + &gamma + v[i]);
+                                                    delta_omega *= &omega;
+                                                }
+                                            });
+
+                                        delta_omega *= &C::Scalar::DELTA;
                                     }
-                                });
 
-                            delta_omega *= &C::Scalar::DELTA;
-                        }
+                                    let z = &mut modified_values;
+                                    let mut tmp = C::Scalar::one();
+                                    for row in 0..size {
+                                        std::mem::swap(&mut tmp, &mut z[row]);
+                                        tmp = tmp * z[row];
+                                    }
 
-                        modified_values
-                    })
-                    .collect::<Vec<_>>();
+                                    let tail =
+                                        permutation_chunk_tail(z, unusable_rows_start, blinding);
+                                    // the receiver is gone if the proof failed meanwhile
+                                    let _ = sender.send((i, modified_values, tail));
+                                },
+                            );
+                        Ok(())
+                    }
+                };
 
+                // wake the shuffle worker whatever happened, it reports a poisoned lock
                 let (lock, cvar) = &*waker;
-                let mut started = lock.lock().unwrap();
-                *started = true;
+                *lock.lock().unwrap_or_else(PoisonError::into_inner) = true;
                 cvar.notify_one();
-
-                let mut tails: Vec<_> = p_z
-                    .par_iter_mut()
-                    .map(|z| {
-                        let mut tmp = C::Scalar::one();
-                        for i in 0..size {
-                            std::mem::swap(&mut tmp, &mut z[i]);
-                            tmp = tmp * z[i];
-                        }
-
-                        if ADD_RANDOM {
-                            for v in z[unusable_rows_start + 1..].iter_mut() {
-                                *v = C::Scalar::random(&mut OsRng);
-                            }
-                        } else {
-                            for v in z[unusable_rows_start + 1..].iter_mut() {
-                                *v = C::Scalar::zero();
-                            }
-                        }
-
-                        z[unusable_rows_start]
-                    })
-                    .collect();
-
-                for i in 1..tails.len() {
-                    tails[i] = tails[i] * tails[i - 1];
-                }
-
-                p_z.par_iter_mut().skip(1).enumerate().for_each(|(i, z)| {
-                    for row in 0..=unusable_rows_start {
-                        z[row] = z[row] * tails[i];
-                    }
-                });
-
-                p_z
+                res
             });
             end_timer!(timer);
             permutation_products_handler
@@ -985,10 +1943,7 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
             let sub_instance = instances.clone();
             let shuffle_products_handler = s.spawn(move || {
                 let (lock, cvar) = &*waiter;
-                let mut started = lock.lock().unwrap();
-                while !*started {
-                    started = cvar.wait(started).unwrap();
-                }
+                drop(cvar.wait_while(lock.lock()?, |started| !*started)?);
 
                 let pk = sub_pk;
                 let advices = sub_advices;
@@ -1177,60 +2132,93 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                             }
                         });
 
-                    if ADD_RANDOM {
-                        for v in z[unusable_rows_start + 1..].iter_mut() {
-                            *v = C::Scalar::random(&mut OsRng);
-                        }
-                    } else {
+                    if !blinding {
                         for v in z[unusable_rows_start + 1..].iter_mut() {
                             *v = C::Scalar::zero();
                         }
                     }
                 });
-                p_z
+                Ok::<_, Error>(p_z)
             });
             end_timer!(timer);
             shuffle_products_handler
         };
 
-        let timer = start_timer!(|| "prepare ntt");
-        let (intt_omegas_buf, intt_pq_buf) =
-            ntt_prepare(&device, pk.get_vk().domain.get_omega_inv(), k)?;
-        let intt_divisor_buf = device
-            .alloc_device_buffer_from_slice::<C::Scalar>(&[pk.get_vk().domain.ifft_divisor])?;
-        end_timer!(timer);
+        // without a cuda device, or for curves with only commitment and ntt
+        // kernels such as the Pasta cycle, lookup z, h, the evaluations and the
+        // multiopen run on the host, see `host`
+        let cuda = backend.as_cuda().filter(|cuda| cuda.curve.full_prover());
 
         let timer = start_timer!(|| "generate lookup z");
-        {
-            const MAX_CONCURRENCY: usize = 3;
-            let mut streams = [None; MAX_CONCURRENCY];
-            let mut buffers = [0; MAX_CONCURRENCY].map(|_| {
-                Rc::new([0; 5].map(|_| (device.alloc_device_buffer::<C::Scalar>(size).unwrap())))
-            });
+        if let Some(cuda) = cuda {
+            let device = cuda.device.clone();
+            let (intt_omegas_buf, intt_pq_buf, intt_divisor_buf) = (
+                &*cuda.intt_omegas_buf,
+                &*cuda.intt_pq_buf,
+                &cuda.intt_divisor_buf,
+            );
+            let concurrency = config.streams.max(1);
+            let mut streams: Vec<Option<CudaStream>> = (0..concurrency).map(|_| None).collect();
+            let mut buffers = (0..concurrency)
+                .map(|_| {
+                    let [a, b, c, d, e] =
+                        [0; 5].map(|_| device.alloc_device_buffer::<C::Scalar>(size));
+                    Ok(Rc::new([a?, b?, c?, d?, e?]))
+                })
+                .collect::<DeviceResult<Vec<_>>>()?;
 
             let beta_gamma_buf = device.alloc_device_buffer_from_slice(&[beta, gamma])?;
+            let mut copied_permuted = vec![];
+            let mut blinders = vec![];
             for (i, (permuted_input, permuted_table, input, table, z)) in lookups.iter_mut() {
                 unsafe {
-                    let idx = *i % MAX_CONCURRENCY;
+                    let idx = *i % concurrency;
                     let [z_buf, input_buf, table_buf, permuted_input_buf, permuted_table_buf] =
                         Rc::get_mut(&mut buffers[idx]).unwrap();
 
-                    if let Some(last_stream) = streams[idx] {
-                        cuda_runtime_sys::cudaStreamSynchronize(last_stream);
-                        cuda_runtime_sys::cudaStreamDestroy(last_stream);
+                    if let Some(last_stream) = streams[idx].take() {
+                        last_stream.synchronize()?;
                     }
 
-                    let mut stream = std::mem::zeroed();
-                    let err = cuda_runtime_sys::cudaStreamCreate(&mut stream);
-                    crate::device::cuda::to_result((), err, "fail to run cudaStreamCreate")?;
+                    let owned_stream = CudaStream::new(&device)?;
+                    let stream = owned_stream.raw();
 
-                    for (d_buf, h_buf) in [
-                        (&*input_buf, &mut input[..]),
-                        (&*table_buf, &mut table[..]),
-                        (&*permuted_input_buf, &mut permuted_input[..]),
-                        (&*permuted_table_buf, &mut permuted_table[..]),
-                    ] {
-                        device.copy_from_host_to_device_async(d_buf, h_buf, stream)?;
+                    device.copy_from_host_to_device_async(&*input_buf, &input[..], stream)?;
+                    match resident_permuted.remove(&*i) {
+                        Some(pair) => {
+                            count_path("resident_permuted");
+                            for (j, d_buf) in [&*permuted_input_buf, &*permuted_table_buf]
+                                .into_iter()
+                                .enumerate()
+                            {
+                                let src = pair.slice::<C::Scalar>(j * size, size)?;
+                                device.copy_from_device_to_device_async::<C::Scalar>(
+                                    d_buf, &src, size, stream,
+                                )?;
+                            }
+                            // read by the copies above until the stream is synchronized
+                            copied_permuted.push(pair);
+                        }
+                        None => {
+                            for (d_buf, h_buf) in [
+                                (&*permuted_input_buf, &permuted_input[..]),
+                                (&*permuted_table_buf, &permuted_table[..]),
+                            ] {
+                                device.copy_from_host_to_device_async(d_buf, h_buf, stream)?;
+                            }
+                        }
+                    }
+                    // eval_lookup_z uses table_buf as scratch, so the shared table is copied
+                    match shared_tables
+                        .and_then(|x| x.table_bufs.get(&*i))
+                        .or_else(|| cuda_pk.and_then(|x| x.lookup_table(*i)))
+                    {
+                        Some(shared) => device.copy_from_device_to_device_async::<C::Scalar>(
+                            table_buf, shared, size, stream,
+                        )?,
+                        None => {
+                            device.copy_from_host_to_device_async(table_buf, &table[..], stream)?
+                        }
                     }
 
                     let err = eval_lookup_z(
@@ -1246,6 +2234,20 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
 
                     to_result((), err, "failed to run eval_lookup_z")?;
 
+                    if blinding {
+                        let tail = z_buf.slice::<C::Scalar>(
+                            unusable_rows_start + 1,
+                            size - unusable_rows_start - 1,
+                        )?;
+                        // blinders are drawn on the host, see `ProverBackend::blind_tails`
+                        let values = (unusable_rows_start + 1..size)
+                            .map(|_| C::Scalar::random(&mut rng))
+                            .collect::<Vec<_>>();
+                        device.copy_from_host_to_device_async(&tail, &values[..], stream)?;
+                        // read by the copy above until the stream is synchronized
+                        blinders.push(values);
+                    }
+
                     for s_buf in [
                         &mut *permuted_input_buf,
                         &mut *permuted_table_buf,
@@ -1255,9 +2257,9 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                             &device,
                             s_buf,
                             &mut *input_buf,
-                            &intt_pq_buf,
-                            &intt_omegas_buf,
-                            &intt_divisor_buf,
+                            intt_pq_buf,
+                            intt_omegas_buf,
+                            intt_divisor_buf,
                             k,
                             Some(stream),
                         )?;
@@ -1271,130 +2273,217 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                         device.copy_from_device_to_host_async(col, &s_buf, stream)?;
                     }
 
-                    streams[idx] = Some(stream);
+                    streams[idx] = Some(owned_stream);
                 }
             }
 
-            unsafe {
-                for last_stream in streams {
-                    if let Some(last_stream) = last_stream {
-                        cuda_runtime_sys::cudaStreamSynchronize(last_stream);
-                        cuda_runtime_sys::cudaStreamDestroy(last_stream);
+            for last_stream in streams.into_iter().flatten() {
+                last_stream.synchronize()?;
+            }
+        } else {
+            lookups.par_iter_mut().for_each(
+                |(_, (permuted_input, permuted_table, input, table, z))| {
+                    host::lookup_z(
+                        &permuted_input[..],
+                        &permuted_table[..],
+                        &input[..],
+                        &table[..],
+                        &mut z[..],
+                        beta,
+                        gamma,
+                        unusable_rows_start,
+                    );
+                    if !blinding {
+                        z[unusable_rows_start + 1..].fill(C::Scalar::zero());
                     }
-                }
+                },
+            );
+            if blinding {
+                backend.blind_tails(
+                    lookups.iter_mut().map(|(_, x)| &mut x.4[..]).collect(),
+                    unusable_rows_start + 1,
+                    &mut rng,
+                )?;
             }
+            backend.batch_intt(
+                lookups
+                    .iter_mut()
+                    .flat_map(|(_, (permuted_input, permuted_table, _, _, z))| {
+                        [&mut permuted_input[..], &mut permuted_table[..], &mut z[..]]
+                    })
+                    .collect(),
+            )?;
         }
 
         let mut lookups = lookups.into_iter().map(|(_, b)| b).collect::<Vec<_>>();
         end_timer!(timer);
 
         let timer = start_timer!(|| format!("lookup z msm {}", lookups.len()));
-        let lookup_z_commitments = crate::cuda::bn254::batch_msm::<C>(
-            &g_buf,
-            [&s_buf, &t_buf],
-            lookups.iter().map(|x| &x.4[..]).collect::<Vec<_>>(),
-            size,
-        )?;
-        end_timer!(timer);
-
-        let timer = start_timer!(|| "wait permutation_products");
-        let mut permutation_products = permutation_products_handler.join().unwrap();
+        let lookup_z_commitments = state.commit("lookup_z", lookups.len(), || {
+            backend.commit(
+                CommitmentBasis::Monomial,
+                lookups.iter().map(|x| &x.4[..]).collect::<Vec<_>>(),
+            )
+        })?;
         end_timer!(timer);
 
+        enter_phase(&mut metrics, config, "permutation")?;
         let timer = start_timer!(|| "permutation z msm and intt");
-        let permutation_commitments = crate::cuda::bn254::batch_msm::<C>(
-            &g_lagrange_buf,
-            [&s_buf, &t_buf],
-            permutation_products
-                .iter()
-                .map(|x| &x[..])
-                .collect::<Vec<_>>(),
-            size,
-        )?;
+        // chunks are committed and intt-ed as soon as they and all their
+        // predecessors are ready, instead of after the whole product
+        let replayed_permutations = state.commitments::<C>("permutation")?;
+        let mut permutation_products = vec![];
+        let mut permutation_commitments = vec![];
+        let mut pending = BTreeMap::new();
+        let mut tails_product = C::Scalar::one();
+        for (i, z, tail) in permutation_receiver.iter() {
+            pending.insert(i, (z, tail));
+
+            let mut ready = vec![];
+            while let Some((mut z, tail)) =
+                pending.remove(&(permutation_products.len() + ready.len()))
+            {
+                if permutation_products.len() + ready.len() > 0 {
+                    let scale = tails_product;
+                    z[..=unusable_rows_start]
+                        .par_iter_mut()
+                        .for_each(|v| *v = *v * scale);
+                }
+                tails_product = tails_product * tail;
+                ready.push(z);
+            }
+            if ready.is_empty() {
+                continue;
+            }
 
-        batch_intt_raw(
-            &device,
-            permutation_products
-                .iter_mut()
-                .map(|x| &mut x[..])
-                .collect::<Vec<_>>(),
-            &intt_pq_buf,
-            &intt_omegas_buf,
-            &intt_divisor_buf,
-            k,
-        )?;
+            if blinding {
+                backend.blind_tails(
+                    ready.iter_mut().map(|x| &mut x[..]).collect::<Vec<_>>(),
+                    unusable_rows_start + 1,
+                    &mut rng,
+                )?;
+            }
+            if replayed_permutations.is_none() {
+                permutation_commitments.extend(backend.commit(
+                    CommitmentBasis::Lagrange,
+                    ready.iter().map(|x| &x[..]).collect::<Vec<_>>(),
+                )?);
+            }
+            backend.batch_intt(ready.iter_mut().map(|x| &mut x[..]).collect::<Vec<_>>())?;
+            permutation_products.append(&mut ready);
+        }
+        join_worker(permutation_products_handler, "permutation product")??;
+        let permutation_commitments = match replayed_permutations {
+            Some(commitments) => {
+                check_group_len("permutation", &commitments[..], permutation_products.len())?;
+                commitments
+            }
+            None => {
+                state.replace("permutation", &permutation_commitments[..])?;
+                permutation_commitments
+            }
+        };
         end_timer!(timer);
 
         let timer = start_timer!(|| "wait shuffle_products");
-        let mut shuffle_products = shuffle_products_handler.join().unwrap();
+        let mut shuffle_products = join_worker(shuffle_products_handler, "shuffle product")??;
         end_timer!(timer);
 
         let timer = start_timer!(|| "shuffle z msm and intt");
-        let shuffle_commitments = crate::cuda::bn254::batch_msm::<C>(
-            &g_lagrange_buf,
-            [&s_buf, &t_buf],
-            shuffle_products.iter().map(|x| &x[..]).collect::<Vec<_>>(),
-            size,
-        )?;
+        if blinding {
+            backend.blind_tails(
+                shuffle_products
+                    .iter_mut()
+                    .map(|x| &mut x[..])
+                    .collect::<Vec<_>>(),
+                unusable_rows_start + 1,
+                &mut rng,
+            )?;
+        }
+        let shuffle_commitments = state.commit("shuffle", shuffle_products.len(), || {
+            backend.commit(
+                CommitmentBasis::Lagrange,
+                shuffle_products.iter().map(|x| &x[..]).collect::<Vec<_>>(),
+            )
+        })?;
 
-        batch_intt_raw(
-            &device,
+        backend.batch_intt(
             shuffle_products
                 .iter_mut()
                 .map(|x| &mut x[..])
                 .collect::<Vec<_>>(),
-            &intt_pq_buf,
-            &intt_omegas_buf,
-            &intt_divisor_buf,
-            k,
         )?;
         end_timer!(timer);
 
         for commitment in permutation_commitments {
-            transcript.write_point(commitment).unwrap();
+            pipeline.write_point(commitment)?;
         }
 
         for (_i, commitment) in lookup_z_commitments.into_iter().enumerate() {
-            transcript.write_point(commitment).unwrap();
+            pipeline.write_point(commitment)?;
         }
 
         for commitment in shuffle_commitments {
-            transcript.write_point(commitment).unwrap();
+            pipeline.write_point(commitment)?;
         }
 
-        let g_buf = g_lagrange_buf;
-        device.copy_from_host_to_device(&g_buf, &params.g[..])?;
-
+        enter_phase(&mut metrics, config, "vanishing")?;
         // TODO: move to sub-thread
         let timer = start_timer!(|| "random_poly");
-        let random_poly = vanish_commit(&device, &s_buf, &g_buf, size, transcript).unwrap();
+        let random_poly = vanish_commit(
+            backend.as_ref(),
+            size,
+            &pipeline,
+            &state,
+            blinding,
+            &mut rng,
+        )?;
         end_timer!(timer);
 
-        let y: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
+        let y: C::Scalar = pipeline.squeeze(&mut challenges, Challenge::Y)?;
+        state.squeezed("y", y)?;
+        let transcript = pipeline.finish()?;
 
+        enter_phase(&mut metrics, config, "h")?;
         let timer = start_timer!(|| "h_poly");
+        let mut resident_advices = vec![];
         {
             let timer = start_timer!(|| "instances and advices intt");
 
-            let buffers = unsafe {
-                Arc::get_mut_unchecked(&mut instances)
-                    .iter_mut()
-                    .map(|x| &mut x[..])
-                    .chain(
+            if let (Some(cuda), true) = (cuda, config.resident_advices) {
+                backend.batch_intt(unsafe {
+                    Arc::get_mut_unchecked(&mut instances)
+                        .iter_mut()
+                        .map(|x| &mut x[..])
+                        .collect::<Vec<_>>()
+                })?;
+                resident_advices = intt_resident(
+                    &cuda.device,
+                    unsafe {
                         Arc::get_mut_unchecked(&mut advices)
                             .iter_mut()
-                            .map(|x| &mut x[..]),
-                    )
-                    .collect::<Vec<_>>()
-            };
-            batch_intt_raw(
-                &device,
-                buffers,
-                &intt_pq_buf,
-                &intt_omegas_buf,
-                &intt_divisor_buf,
-                k,
-            )?;
+                            .map(|x| &mut x[..])
+                            .collect::<Vec<_>>()
+                    },
+                    &*cuda.intt_pq_buf,
+                    &*cuda.intt_omegas_buf,
+                    &cuda.intt_divisor_buf,
+                    cuda.k,
+                )?;
+            } else {
+                let buffers = unsafe {
+                    Arc::get_mut_unchecked(&mut instances)
+                        .iter_mut()
+                        .map(|x| &mut x[..])
+                        .chain(
+                            Arc::get_mut_unchecked(&mut advices)
+                                .iter_mut()
+                                .map(|x| &mut x[..]),
+                        )
+                        .collect::<Vec<_>>()
+                };
+                backend.batch_intt(buffers)?;
+            }
 
             end_timer!(timer);
         }
@@ -1403,39 +2492,68 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
         let advice_ref = &advices.iter().map(|x| &x[..]).collect::<Vec<_>>()[..];
         let instance_ref = &instances.iter().map(|x| &x[..]).collect::<Vec<_>>()[..];
 
-        let (x, _xn, h_pieces) = evaluate_h_gates_and_vanishing_construct(
-            &device,
-            &pk,
-            fixed_ref,
-            advice_ref,
-            instance_ref,
-            &permutation_products
-                .iter()
-                .map(|x| &x[..])
-                .collect::<Vec<_>>()[..],
-            &mut lookups
-                .iter_mut()
-                .map(|(v0, v1, v2, v3, v4)| {
-                    (
-                        &mut v0[..],
-                        &mut v1[..],
-                        &mut v2[..],
-                        &mut v3[..],
-                        &mut v4[..],
-                    )
-                })
-                .collect::<Vec<_>>()[..],
-            &shuffle_products.iter().map(|x| &x[..]).collect::<Vec<_>>()[..],
-            y,
-            beta,
-            gamma,
-            theta,
-            intt_pq_buf,
-            intt_omegas_buf,
-            intt_divisor_buf,
-            &g_buf,
-            transcript,
-        )?;
+        let permutation_ref = &permutation_products
+            .iter()
+            .map(|x| &x[..])
+            .collect::<Vec<_>>()[..];
+        let shuffle_ref = &shuffle_products.iter().map(|x| &x[..]).collect::<Vec<_>>()[..];
+        let (x, _xn, h_pieces) = match cuda {
+            Some(cuda) => evaluate_h_gates_and_vanishing_construct(
+                &cuda.device,
+                &pk,
+                fixed_ref,
+                advice_ref,
+                instance_ref,
+                permutation_ref,
+                &mut lookups
+                    .iter_mut()
+                    .map(|(v0, v1, v2, v3, v4)| {
+                        (
+                            &mut v0[..],
+                            &mut v1[..],
+                            &mut v2[..],
+                            &mut v3[..],
+                            &mut v4[..],
+                        )
+                    })
+                    .collect::<Vec<_>>()[..],
+                shuffle_ref,
+                y,
+                beta,
+                gamma,
+                theta,
+                &*cuda.intt_pq_buf,
+                &*cuda.intt_omegas_buf,
+                &cuda.intt_divisor_buf,
+                &*cuda.g_buf,
+                transcript,
+                &mut challenges,
+                shared_tables.map_or_else(Default::default, |x| x.extended_fixed.clone()),
+                resident_advices,
+                cuda_pk,
+            ),
+            None => host::evaluate_h(
+                backend.as_ref(),
+                &pk,
+                fixed_ref,
+                advice_ref,
+                instance_ref,
+                permutation_ref,
+                &lookups
+                    .iter()
+                    .map(|(permuted_input, permuted_table, _, _, z)| {
+                        [&permuted_input[..], &permuted_table[..], &z[..]]
+                    })
+                    .collect::<Vec<_>>()[..],
+                shuffle_ref,
+                y,
+                beta,
+                gamma,
+                theta,
+                transcript,
+                &mut challenges,
+            ),
+        }?;
         end_timer!(timer);
 
         let mut inputs = vec![(&h_pieces[..], x)];
@@ -1504,122 +2622,124 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
             }
         }
 
-        let x_buf = device.alloc_device_buffer_from_slice(&x_extend_sets)?;
-        let mut x_map = BTreeMap::new();
-        for (i, x) in x_sets.into_iter().enumerate() {
-            x_map.insert(
-                x,
-                ManuallyDrop::new(CudaDeviceBufRaw {
-                    ptr: unsafe {
-                        x_buf
-                            .ptr()
-                            .offset((i * k * core::mem::size_of::<C::Scalar>()) as isize)
-                    },
-                    device: device.clone(),
-                    size: core::mem::size_of::<C::Scalar>(),
-                }),
-            );
-        }
-
-        let mut poly_buf_cache = BTreeMap::new();
-        let extended_buffers_count = if k < 23 { 30 } else { 15 };
+        enter_phase(&mut metrics, config, "eval")?;
+        let timer = start_timer!(|| format!("compute eval {}", collection.len()));
         let mut extended_buffers = vec![];
         let mut cache_buffers = vec![];
-        let extended_k = pk.vk.domain.extended_k() as usize;
-        for _ in 0..extended_buffers_count {
-            let buf = device.alloc_device_buffer::<C::Scalar>(1 << extended_k)?;
-            for i in 0..1 << (extended_k - k) {
-                cache_buffers.push(ManuallyDrop::new(CudaDeviceBufRaw {
-                    ptr: unsafe {
-                        buf.ptr()
-                            .offset(((i << k) * core::mem::size_of::<C::Scalar>()) as isize)
-                    },
-                    device: device.clone(),
-                    size: core::mem::size_of::<C::Scalar>(),
-                }));
-            }
-            extended_buffers.push(buf);
-        }
+        let mut poly_buf_cache = BTreeMap::new();
+        let (evals, eval_map) = match cuda {
+            Some(cuda) => {
+                let device = &cuda.device;
+                let x_buf = device.alloc_device_buffer_from_slice(&x_extend_sets)?;
+                let mut x_map = BTreeMap::new();
+                for (i, x) in x_sets.into_iter().enumerate() {
+                    x_map.insert(x, x_buf.slice::<C::Scalar>(i * k, k)?);
+                }
 
-        let mut evals = vec![C::Scalar::zero(); inputs.len()];
+                let extended_buffers_count = if k < 23 { 30 } else { 15 };
+                let extended_k = pk.vk.domain.extended_k() as usize;
+                for _ in 0..extended_buffers_count {
+                    let buf = device.alloc_device_buffer::<C::Scalar>(1 << extended_k)?;
+                    for i in 0..1 << (extended_k - k) {
+                        cache_buffers.push(ManuallyDrop::new(CudaDeviceBufRaw {
+                            ptr: unsafe {
+                                buf.ptr()
+                                    .offset(((i << k) * core::mem::size_of::<C::Scalar>()) as isize)
+                            },
+                            device: device.clone(),
+                            size: core::mem::size_of::<C::Scalar>(),
+                            epoch: buf.epoch,
+                        }));
+                    }
+                    extended_buffers.push(buf);
+                }
 
-        let timer = start_timer!(|| format!("compute eval {}", collection.len()));
-        let mut eval_map = BTreeMap::new();
-
-        let mut streams = vec![];
-        let mut bufs = vec![];
-        let max = 6;
-        for _ in 0..max {
-            bufs.push((
-                device.alloc_device_buffer::<C::Scalar>(size)?,
-                device.alloc_device_buffer::<C::Scalar>(size)?,
-                device.alloc_device_buffer::<C::Scalar>(size)?,
-            ));
-            unsafe {
-                let mut stream = std::mem::zeroed();
-                let err = cuda_runtime_sys::cudaStreamCreate(&mut stream);
-                assert_eq!(err, cuda_runtime_sys::cudaError::cudaSuccess);
-                streams.push(stream);
-            }
-        }
+                let mut evals = vec![C::Scalar::zero(); inputs.len()];
+                let mut eval_map = BTreeMap::new();
+
+                let mut streams = vec![];
+                let mut bufs = vec![];
+                let max = 6;
+                for _ in 0..max {
+                    bufs.push((
+                        device.alloc_device_buffer::<C::Scalar>(size)?,
+                        device.alloc_device_buffer::<C::Scalar>(size)?,
+                        device.alloc_device_buffer::<C::Scalar>(size)?,
+                    ));
+                    streams.push(CudaStream::new(&device)?);
+                }
 
-        let mut collection = collection.into_iter().collect::<Vec<_>>();
-        collection.sort_by(|a, b| a.1 .1.len().cmp(&b.1 .1.len()));
-
-        let mut l = 0;
-        let mut r = collection.len();
-        let mut inc = false;
-        let mut used_cache_idx = 0;
-        while l < r {
-            let i = if inc { l } else { r - 1 };
-            if inc {
-                l += 1;
-            } else {
-                r -= 1;
-            }
-            inc = !inc;
-            let (p, arr) = &collection[i].1;
-            let p = *p;
-            unsafe {
-                let stream = streams[i % max];
-                let (poly_buf, eval_buf, tmp_buf) = &bufs[i % max];
-                let poly_buf = if used_cache_idx < cache_buffers.len() {
-                    let buf = &cache_buffers[used_cache_idx];
-                    poly_buf_cache.insert((*p).as_ptr() as usize, buf);
-                    used_cache_idx += 1;
-                    buf
-                } else {
-                    poly_buf
-                };
-                device.copy_from_host_to_device_async(poly_buf, p, stream)?;
-                for (idx, x) in arr {
-                    let err = crate::cuda::bn254_c::poly_eval(
-                        poly_buf.ptr(),
-                        eval_buf.ptr(),
-                        tmp_buf.ptr(),
-                        x_map.get(x).unwrap().ptr(),
-                        size as i32,
-                        stream,
-                    );
-                    crate::device::cuda::to_result((), err, "fail to run poly_eval")?;
-                    device.copy_from_device_to_host_async(
-                        &mut evals[*idx..*idx + 1],
-                        eval_buf,
-                        stream,
-                    )?;
-                    eval_map.insert(((*p).as_ptr() as usize, **x), *idx);
+                let mut collection = collection.into_iter().collect::<Vec<_>>();
+                collection.sort_by(|a, b| a.1 .1.len().cmp(&b.1 .1.len()));
+
+                let mut l = 0;
+                let mut r = collection.len();
+                let mut inc = false;
+                let mut used_cache_idx = 0;
+                while l < r {
+                    let i = if inc { l } else { r - 1 };
+                    if inc {
+                        l += 1;
+                    } else {
+                        r -= 1;
+                    }
+                    inc = !inc;
+                    let (p, arr) = &collection[i].1;
+                    let p = *p;
+                    unsafe {
+                        let stream = streams[i % max].raw();
+                        let (poly_buf, eval_buf, tmp_buf) = &bufs[i % max];
+                        let poly_buf = if used_cache_idx < cache_buffers.len() {
+                            let buf = &cache_buffers[used_cache_idx];
+                            poly_buf_cache.insert((*p).as_ptr() as usize, buf);
+                            used_cache_idx += 1;
+                            buf
+                        } else {
+                            poly_buf
+                        };
+                        device.copy_from_host_to_device_async(poly_buf, p, stream)?;
+                        for (idx, x) in arr {
+                            let err = crate::cuda::bn254_c::poly_eval(
+                                poly_buf.ptr(),
+                                eval_buf.ptr(),
+                                tmp_buf.ptr(),
+                                x_map.get(x).unwrap().ptr(),
+                                size as i32,
+                                stream,
+                            );
+                            crate::device::cuda::to_result((), err, "fail to run poly_eval")?;
+                            device.copy_from_device_to_host_async(
+                                &mut evals[*idx..*idx + 1],
+                                eval_buf,
+                                stream,
+                            )?;
+                            eval_map.insert(((*p).as_ptr() as usize, **x), *idx);
+                        }
+                    }
+                }
+
+                for stream in streams {
+                    stream.synchronize()?;
                 }
-            }
-        }
 
-        for stream in streams {
-            unsafe {
-                cuda_runtime_sys::cudaStreamSynchronize(stream);
-                cuda_runtime_sys::cudaStreamDestroy(stream);
+                drop(bufs);
+                (evals, eval_map)
             }
-        }
+            None => {
+                let evals = host::evaluate_polys(
+                    &inputs.iter().map(|(p, x)| (&p[..], *x)).collect::<Vec<_>>()[..],
+                );
+                let eval_map = inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, (p, x))| ((p.as_ptr() as usize, *x), idx))
+                    .collect::<BTreeMap<_, _>>();
+                (evals, eval_map)
+            }
+        };
 
-        drop(bufs);
+        #[cfg(feature = "cross-check")]
+        cross_check::check_evaluations(&inputs[..], &evals[..])?;
 
         let eval_map = eval_map
             .into_iter()
@@ -1627,11 +2747,12 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
             .collect::<BTreeMap<(usize, C::ScalarExt), C::ScalarExt>>();
 
         for (_i, eval) in evals.into_iter().skip(1).enumerate() {
-            transcript.write_scalar(eval).unwrap();
+            challenges.write_scalar(transcript, eval)?;
         }
 
         end_timer!(timer);
 
+        enter_phase(&mut metrics, config, "multiopen")?;
         let timer = start_timer!(|| "multi open");
         let instance_arr = [instances];
         let advices_arr = [advices];
@@ -1718,66 +2839,172 @@ fn _create_proof_from_advices<C: CurveAffine, E: EncodedChallenge<C>, T: Transcr
                         poly: &random_poly,
                     })),
             );
-        if use_gwc {
-            gwc::multiopen(
-                &device,
-                &g_buf,
+        match cuda {
+            Some(cuda) if use_gwc => gwc::multiopen(
+                &cuda.device,
+                &*cuda.g_buf,
                 queries,
                 size,
-                [&s_buf, &t_buf],
+                [&cuda.s_buf, &cuda.t_buf],
                 eval_map,
                 transcript,
-            )?;
-        } else {
-            shplonk::multiopen(
+                &mut challenges,
+            ),
+            Some(cuda) => shplonk::multiopen(
                 &pk,
-                &device,
-                &g_buf,
+                &cuda.device,
+                &*cuda.g_buf,
                 queries,
                 size,
-                [&s_buf, &t_buf],
+                [&cuda.s_buf, &cuda.t_buf],
                 eval_map,
                 poly_buf_cache,
                 transcript,
-            )?;
-        }
+                &mut challenges,
+            ),
+            None if use_gwc => host::gwc_multiopen(
+                backend.as_ref(),
+                queries,
+                size,
+                eval_map,
+                transcript,
+                &mut challenges,
+            ),
+            None => host::shplonk_multiopen(
+                backend.as_ref(),
+                queries,
+                size,
+                eval_map,
+                transcript,
+                &mut challenges,
+            ),
+        }?;
         end_timer!(timer);
 
-        Ok(())
+        let mut metrics = metrics.finish();
+        metrics.backend = backend.name();
+        if let Some(cuda) = cuda {
+            metrics.gpu_health = gpu_health(cuda.device.device_id());
+        }
+        Ok(metrics)
     })
 }
 
-fn vanish_commit<C: CurveAffine, E: EncodedChallenge<C>, T: TranscriptWrite<C, E>>(
-    device: &CudaDevice,
-    s_buf: &CudaDeviceBufRaw,
-    g_buf: &CudaDeviceBufRaw,
+/// Commits the permuted input and table of each `(lookup, pair)`. With
+/// `ProverConfig::resident_permuted` the pairs stay on device in `resident`,
+/// from where the lookup z polynomials read them instead of uploading them again.
+fn commit_permuted_pairs<C: CurveAffine>(
+    backend: &dyn ProverBackend<C>,
+    config: &ProverConfig,
+    lookups: Vec<(usize, [&[C::Scalar]; 2])>,
+    commitments: &mut [C],
+    resident: &mut BTreeMap<usize, CudaDeviceBufRaw>,
+) -> Result<(), Error> {
+    let (ids, pairs): (Vec<_>, Vec<_>) = lookups.into_iter().unzip();
+    match backend
+        .as_cuda()
+        .filter(|cuda| config.resident_permuted && cuda.curve.full_prover())
+    {
+        Some(cuda) => {
+            for (i, ([input, table], buf)) in
+                ids.into_iter().zip(cuda.commit_pairs_resident(pairs)?)
+            {
+                commitments[i * 2] = input;
+                commitments[i * 2 + 1] = table;
+                resident.insert(i, buf);
+            }
+        }
+        None => {
+            let pairs = backend.commit_pairs(CommitmentBasis::Lagrange, pairs)?;
+            for (i, [input, table]) in ids.into_iter().zip(pairs) {
+                commitments[i * 2] = input;
+                commitments[i * 2 + 1] = table;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Starts the next phase of a proof, unless it was cancelled meanwhile or
+// while the observer held it.
+fn enter_phase(
+    metrics: &mut MetricsCollector,
+    config: &ProverConfig,
+    phase: &'static str,
+) -> Result<(), Error> {
+    let cancelled = || config.cancel.as_ref().map_or(false, |x| x.is_cancelled());
+    if cancelled() {
+        return Err(Error::Cancelled);
+    }
+    metrics.enter_phase(phase);
+    if cancelled() {
+        return Err(Error::Cancelled);
+    }
+    Ok(())
+}
+
+// Zeroes the rows of a permutation product chunk after the last usable one,
+// unless they are randomized later, and returns the product at the last
+// usable row, which the following chunks are scaled by.
+fn permutation_chunk_tail<F: FieldExt>(
+    z: &mut [F],
+    unusable_rows_start: usize,
+    blinding: bool,
+) -> F {
+    // when blinding, the tails are overwritten with blinders drawn on the host
+    // before the msm, see `ProverBackend::blind_tails`
+    if !blinding {
+        for v in z[unusable_rows_start + 1..].iter_mut() {
+            *v = F::zero();
+        }
+    }
+    // the product of the chunk still has to be scaled by the tails of
+    // the previous chunks, done by the receiver as they arrive in order
+    z[unusable_rows_start]
+}
+
+// 32 bytes of the caller's rng, the full key of a `StdRng` (ChaCha12) expanding
+// blinders in parallel. Seeding from a single `u64` would cap their entropy.
+fn chacha_seed(rng: &mut impl RngCore) -> [u8; 32] {
+    let mut seed = [0u8; 32];
+    rng.fill_bytes(&mut seed);
+    seed
+}
+
+fn vanish_commit<C: CurveAffine, T>(
+    backend: &dyn ProverBackend<C>,
     size: usize,
-    transcript: &mut T,
+    transcript: &TranscriptPipeline<C, T>,
+    state: &ProofState,
+    blinding: bool,
+    rng: &mut impl RngCore,
 ) -> Result<Vec<C::Scalar, HugePageAllocator>, Error> {
-    use rand::thread_rng;
-    use rand::RngCore;
+    const CHUNK_SIZE: usize = 1 << 12;
 
-    let random_nr = 32;
     let mut random_poly = Vec::new_in(HugePageAllocator);
     random_poly.resize(size, C::Scalar::zero());
 
-    let random = vec![0; 32usize]
-        .iter()
-        .map(|_| C::Scalar::random(&mut OsRng))
-        .collect::<Vec<_>>();
-
-    random_poly.par_iter_mut().for_each(|coeff| {
-        if ADD_RANDOM {
-            let mut rng = thread_rng();
-            *coeff = (C::Scalar::random(&mut rng) + random[rng.next_u64() as usize % random_nr])
-                * (C::Scalar::random(&mut rng) + random[rng.next_u64() as usize % random_nr])
-        }
-    });
+    if blinding {
+        let seeds = (0..(size + CHUNK_SIZE - 1) / CHUNK_SIZE)
+            .map(|_| chacha_seed(&mut *rng))
+            .collect::<Vec<_>>();
+
+        random_poly
+            .par_chunks_mut(CHUNK_SIZE)
+            .zip(seeds.into_par_iter())
+            .for_each(|(chunk, seed)| {
+                let mut rng = StdRng::from_seed(seed);
+                for coeff in chunk {
+                    *coeff = C::Scalar::random(&mut rng);
+                }
+            });
+    }
 
     // Commit
-    device.copy_from_host_to_device(&s_buf, &random_poly[..])?;
-    let commitment = batch_msm_v2(&g_buf, vec![&s_buf], size)?;
-    transcript.write_point(commitment[0]).unwrap();
+    let commitment = state.commit("vanishing", 1, || {
+        backend.commit(CommitmentBasis::Monomial, vec![&random_poly[..]])
+    })?;
+    transcript.write_point(commitment[0])?;
 
     Ok(random_poly)
 }
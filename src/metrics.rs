@@ -0,0 +1,486 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use cuda_runtime_sys::cudaError;
+use cuda_runtime_sys::cudaEvent_t;
+use cuda_runtime_sys::cudaStream_t;
+use tracing::info_span;
+use tracing::span::EnteredSpan;
+
+use crate::device::cuda::capturing;
+use crate::device::cuda::max_allocated_memory;
+use crate::device::cuda::set_buffer_phase;
+use crate::device::nvml::GpuHealth;
+
+#[cfg(feature = "prometheus")]
+mod export;
+
+/// Measurements of one proof, returned by `create_proof_from_advices_with_config`.
+///
+/// The counters behind it are process wide, proofs running concurrently in the
+/// same process see each other's kernels and allocations.
+#[derive(Debug, Clone, Default)]
+pub struct ProofMetrics {
+    /// Wall time of each proving phase, in order.
+    pub phase_times: Vec<(&'static str, Duration)>,
+    /// Wall time of the whole proof.
+    pub total_time: Duration,
+    /// Device time by kernel kind, "ntt" is timed with cuda events, "msm" is
+    /// the wall time of the blocking msm calls.
+    pub kernel_times: BTreeMap<&'static str, Duration>,
+    /// Number of msm, a batch counts once per scalar vector.
+    pub msm_count: usize,
+    /// Number of ntt and intt.
+    pub ntt_count: usize,
+    /// Peak bytes obtained from cudaMalloc on a single device.
+    pub peak_device_memory: usize,
+    /// Peak bytes handed out by the huge page allocators.
+    pub peak_host_memory: usize,
+    /// Device buffer allocations served from the buffer cache.
+    pub buffer_cache_hits: usize,
+    /// Device buffer allocations that went to cudaMalloc.
+    pub buffer_cache_misses: usize,
+    /// Phases, timed kernels and blocking msm calls in the order they started.
+    pub timeline: Vec<TimelineEvent>,
+    /// State of the device at the end of the proof, with the `nvml` feature.
+    pub gpu_health: Option<GpuHealth>,
+    /// Name of the backend the proof ran on, see `ProverBackend::name`.
+    pub backend: &'static str,
+    /// Times each optional path of `ProverConfig` ran, e.g. gate groups run
+    /// through "jit_gates" kernels or "cuda_graphs" replayed. Paths that never
+    /// ran, disabled or fallen back from, are missing.
+    pub paths: BTreeMap<&'static str, usize>,
+}
+
+/// A span of the proof's timeline, relative to the start of the proof.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineEvent {
+    pub name: &'static str,
+    /// "phase", "kernel" for device time between cuda events, "host" for blocking calls.
+    pub category: &'static str,
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+impl ProofMetrics {
+    pub fn buffer_cache_hit_rate(&self) -> f64 {
+        let total = self.buffer_cache_hits + self.buffer_cache_misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.buffer_cache_hits as f64 / total as f64
+        }
+    }
+
+    /// Writes `timeline` in the Chrome trace event format, to be opened with
+    /// chrome://tracing or ui.perfetto.dev. Each category gets a track of its own.
+    pub fn write_chrome_trace<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let tracks = ["phase", "host", "kernel"];
+        write!(writer, "{{\"displayTimeUnit\":\"ms\",\"traceEvents\":[")?;
+        for (i, track) in tracks.iter().enumerate() {
+            write!(
+                writer,
+                "{}{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\
+                 \"args\":{{\"name\":\"{}\"}}}}",
+                if i == 0 { "" } else { "," },
+                i,
+                track
+            )?;
+        }
+        for event in self.timeline.iter() {
+            let tid = tracks
+                .iter()
+                .position(|x| *x == event.category)
+                .unwrap_or(tracks.len());
+            write!(
+                writer,
+                ",{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"pid\":1,\"tid\":{},\
+                 \"ts\":{:.3},\"dur\":{:.3}}}",
+                event.name,
+                event.category,
+                tid,
+                event.start.as_secs_f64() * 1e6,
+                event.duration.as_secs_f64() * 1e6
+            )?;
+        }
+        write!(writer, "]}}")
+    }
+}
+
+/// A proof crossing a phase boundary, see `ProofObserver`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseEvent {
+    /// The phase that ended and its wall time, `None` before the first phase.
+    pub finished: Option<(&'static str, Duration)>,
+    /// The phase starting, `None` once the proof is done.
+    pub started: Option<&'static str>,
+    /// Wall time since the proof started.
+    pub elapsed: Duration,
+    /// Bytes obtained from cudaMalloc on the device using the most.
+    pub device_memory: usize,
+    /// Bytes handed out by the huge page allocators.
+    pub host_memory: usize,
+}
+
+/// Called by the prover at its phase boundaries (advice, lookup, permutation,
+/// vanishing, h, eval, multiopen) on the proof thread, e.g. to drive a
+/// progress bar or to alert when a phase runs for too long. It shouldn't block.
+pub trait ProofObserver: Send + Sync {
+    fn on_phase(&self, event: &PhaseEvent);
+}
+
+/// A `ProofObserver` shared with `ProverConfig::observer`.
+#[derive(Clone)]
+pub struct SharedObserver(pub Arc<dyn ProofObserver>);
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedObserver")
+    }
+}
+
+impl PartialEq for SharedObserver {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+static ACTIVE_COLLECTORS: AtomicUsize = AtomicUsize::new(0);
+static MSM_COUNT: AtomicUsize = AtomicUsize::new(0);
+static NTT_COUNT: AtomicUsize = AtomicUsize::new(0);
+static BUFFER_CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+static BUFFER_CACHE_MISSES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_DEVICE_MEMORY: AtomicUsize = AtomicUsize::new(0);
+static HOST_MEMORY: AtomicUsize = AtomicUsize::new(0);
+static PEAK_HOST_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref KERNEL_TIMES: Mutex<BTreeMap<&'static str, Duration>> = Mutex::new(BTreeMap::new());
+    static ref PATH_COUNTS: Mutex<BTreeMap<&'static str, usize>> = Mutex::new(BTreeMap::new());
+    // (kind, start, end, enqueued) recorded on streams still in flight
+    static ref PENDING_KERNEL_EVENTS: Mutex<Vec<(&'static str, usize, usize, Instant)>> =
+        Mutex::new(vec![]);
+    // (name, category, start, duration) of the proofs in flight
+    static ref TIMELINE: Mutex<Vec<(&'static str, &'static str, Instant, Duration)>> =
+        Mutex::new(vec![]);
+    // event recorded on the default stream once the device caught up with the host,
+    // the device clock of kernel events is read relative to it
+    static ref TIMELINE_ANCHOR: Mutex<Option<(usize, Instant)>> = Mutex::new(None);
+}
+
+fn record_timeline(name: &'static str, category: &'static str, start: Instant, time: Duration) {
+    if ACTIVE_COLLECTORS.load(Ordering::Relaxed) > 0 {
+        TIMELINE.lock().unwrap().push((name, category, start, time));
+    }
+}
+
+fn set_timeline_anchor() {
+    unsafe {
+        let mut anchor: cudaEvent_t = std::mem::zeroed();
+        if cuda_runtime_sys::cudaEventCreate(&mut anchor)
+            != cuda_runtime_sys::cudaError::cudaSuccess
+        {
+            cuda_runtime_sys::cudaGetLastError();
+            return;
+        }
+        cuda_runtime_sys::cudaEventRecord(anchor, 0usize as _);
+        cuda_runtime_sys::cudaEventSynchronize(anchor);
+        let old = TIMELINE_ANCHOR
+            .lock()
+            .unwrap()
+            .replace((anchor as usize, Instant::now()));
+        if let Some((old, _)) = old {
+            cuda_runtime_sys::cudaEventDestroy(old as cudaEvent_t);
+        }
+    }
+}
+
+pub(crate) fn count_ntt() {
+    NTT_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a run of the optional path `name`, see `ProofMetrics::paths`.
+pub(crate) fn count_path(name: &'static str) {
+    if ACTIVE_COLLECTORS.load(Ordering::Relaxed) > 0 {
+        *PATH_COUNTS.lock().unwrap().entry(name).or_default() += 1;
+    }
+}
+
+pub(crate) fn count_buffer_cache(hit: bool) {
+    #[cfg(feature = "prometheus")]
+    export::buffer_cache(hit);
+    if hit {
+        BUFFER_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        BUFFER_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a failed CUDA call by its error code.
+pub(crate) fn count_cuda_error(error: cudaError) {
+    #[cfg(feature = "prometheus")]
+    export::cuda_error(format!("{:?}", error));
+    #[cfg(not(feature = "prometheus"))]
+    let _ = error;
+}
+
+pub(crate) fn record_device_memory(allocated: usize) {
+    PEAK_DEVICE_MEMORY.fetch_max(allocated, Ordering::Relaxed);
+}
+
+pub(crate) fn host_memory_acquired(size: usize) {
+    let current = HOST_MEMORY.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_HOST_MEMORY.fetch_max(current, Ordering::Relaxed);
+}
+
+pub(crate) fn host_memory_released(size: usize) {
+    HOST_MEMORY.fetch_sub(size, Ordering::Relaxed);
+}
+
+fn record_kernel_time(kind: &'static str, time: Duration) {
+    if ACTIVE_COLLECTORS.load(Ordering::Relaxed) > 0 {
+        *KERNEL_TIMES.lock().unwrap().entry(kind).or_default() += time;
+    }
+}
+
+/// Counts `n` msm and records the wall time until dropped, msm calls block
+/// until their results are on the host.
+pub(crate) struct MsmTimer(Instant);
+
+impl MsmTimer {
+    pub(crate) fn start(n: usize) -> Self {
+        MSM_COUNT.fetch_add(n, Ordering::Relaxed);
+        MsmTimer(Instant::now())
+    }
+}
+
+impl Drop for MsmTimer {
+    fn drop(&mut self) {
+        let time = self.0.elapsed();
+        record_kernel_time("msm", time);
+        record_timeline("msm", "host", self.0, time);
+    }
+}
+
+/// Runs `f`, which enqueues kernels of `kind` on `stream`, between two cuda events.
+/// The elapsed time is read when the proof finishes, so `f` doesn't have to block.
+/// Kernels captured into a graph are not timed, the launch of the graph is.
+pub(crate) fn time_kernel<R>(kind: &'static str, stream: cudaStream_t, f: impl FnOnce() -> R) -> R {
+    if ACTIVE_COLLECTORS.load(Ordering::Relaxed) == 0 || capturing() {
+        return f();
+    }
+
+    unsafe {
+        let mut start: cudaEvent_t = std::mem::zeroed();
+        let mut end: cudaEvent_t = std::mem::zeroed();
+        cuda_runtime_sys::cudaEventCreate(&mut start);
+        cuda_runtime_sys::cudaEventCreate(&mut end);
+        let enqueued = Instant::now();
+        cuda_runtime_sys::cudaEventRecord(start, stream);
+        let res = f();
+        cuda_runtime_sys::cudaEventRecord(end, stream);
+        PENDING_KERNEL_EVENTS
+            .lock()
+            .unwrap()
+            .push((kind, start as usize, end as usize, enqueued));
+        res
+    }
+}
+
+fn drain_kernel_events() {
+    let events = std::mem::take(&mut *PENDING_KERNEL_EVENTS.lock().unwrap());
+    let anchor = *TIMELINE_ANCHOR.lock().unwrap();
+    let mut times = KERNEL_TIMES.lock().unwrap();
+    for (kind, start, end, enqueued) in events {
+        unsafe {
+            let start = start as cudaEvent_t;
+            let end = end as cudaEvent_t;
+            let mut ms = 0f32;
+            cuda_runtime_sys::cudaEventSynchronize(end);
+            if cuda_runtime_sys::cudaEventElapsedTime(&mut ms, start, end)
+                == cuda_runtime_sys::cudaError::cudaSuccess
+            {
+                let time = Duration::from_secs_f32(ms / 1000.0);
+                *times.entry(kind).or_default() += time;
+
+                // events of another device than the anchor's can't be placed, nor
+                // can those before it, they are shown when they were enqueued
+                let mut since_anchor = 0f32;
+                let began = match anchor {
+                    Some((anchor, at))
+                        if cuda_runtime_sys::cudaEventElapsedTime(
+                            &mut since_anchor,
+                            anchor as cudaEvent_t,
+                            start,
+                        ) == cuda_runtime_sys::cudaError::cudaSuccess
+                            && since_anchor >= 0.0 =>
+                    {
+                        at + Duration::from_secs_f32(since_anchor / 1000.0)
+                    }
+                    _ => {
+                        cuda_runtime_sys::cudaGetLastError();
+                        enqueued
+                    }
+                };
+                record_timeline(kind, "kernel", began, time);
+            }
+            cuda_runtime_sys::cudaEventDestroy(start);
+            cuda_runtime_sys::cudaEventDestroy(end);
+        }
+    }
+}
+
+/// Tracks the phases of the proof in flight and the counters at its start.
+pub(crate) struct MetricsCollector {
+    start: Instant,
+    phase: Option<(&'static str, Instant, EnteredSpan)>,
+    phase_times: Vec<(&'static str, Duration)>,
+    phase_spans: Vec<TimelineEvent>,
+    msm_count: usize,
+    ntt_count: usize,
+    buffer_cache_hits: usize,
+    buffer_cache_misses: usize,
+    observer: Option<SharedObserver>,
+    #[cfg(feature = "prometheus")]
+    finished: bool,
+}
+
+impl MetricsCollector {
+    pub(crate) fn start() -> Self {
+        if ACTIVE_COLLECTORS.fetch_add(1, Ordering::Relaxed) == 0 {
+            KERNEL_TIMES.lock().unwrap().clear();
+            PATH_COUNTS.lock().unwrap().clear();
+            TIMELINE.lock().unwrap().clear();
+            set_timeline_anchor();
+            PEAK_DEVICE_MEMORY.store(max_allocated_memory(), Ordering::Relaxed);
+            PEAK_HOST_MEMORY.store(HOST_MEMORY.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+
+        MetricsCollector {
+            start: Instant::now(),
+            phase: None,
+            phase_times: vec![],
+            phase_spans: vec![],
+            msm_count: MSM_COUNT.load(Ordering::Relaxed),
+            ntt_count: NTT_COUNT.load(Ordering::Relaxed),
+            buffer_cache_hits: BUFFER_CACHE_HITS.load(Ordering::Relaxed),
+            buffer_cache_misses: BUFFER_CACHE_MISSES.load(Ordering::Relaxed),
+            observer: None,
+            #[cfg(feature = "prometheus")]
+            finished: false,
+        }
+    }
+
+    pub(crate) fn with_observer(mut self, observer: Option<SharedObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    fn notify(&self, finished: Option<(&'static str, Duration)>, started: Option<&'static str>) {
+        #[cfg(feature = "prometheus")]
+        export::memory_in_use(HOST_MEMORY.load(Ordering::Relaxed));
+        if let Some(observer) = &self.observer {
+            observer.0.on_phase(&PhaseEvent {
+                finished,
+                started,
+                elapsed: self.start.elapsed(),
+                device_memory: max_allocated_memory(),
+                host_memory: HOST_MEMORY.load(Ordering::Relaxed),
+            });
+        }
+    }
+
+    /// Ends the current phase and starts `name`: its span is entered and device
+    /// buffers allocated from now on are tagged with it.
+    pub(crate) fn enter_phase(&mut self, name: &'static str) {
+        let finished = self.end_phase();
+        self.notify(finished, Some(name));
+        set_buffer_phase(name);
+        self.phase = Some((name, Instant::now(), info_span!("phase", name).entered()));
+    }
+
+    fn end_phase(&mut self) -> Option<(&'static str, Duration)> {
+        let (name, start, span) = self.phase.take()?;
+        drop(span);
+        let time = start.elapsed();
+        #[cfg(feature = "prometheus")]
+        export::phase_finished(name, time);
+        self.phase_times.push((name, time));
+        self.phase_spans.push(TimelineEvent {
+            name,
+            category: "phase",
+            start: start.duration_since(self.start),
+            duration: time,
+        });
+        Some((name, time))
+    }
+
+    /// Ends the last phase, waits for the timed kernels and reports.
+    pub(crate) fn finish(mut self) -> ProofMetrics {
+        let finished = self.end_phase();
+        self.notify(finished, None);
+        set_buffer_phase("none");
+        drain_kernel_events();
+        #[cfg(feature = "prometheus")]
+        {
+            self.finished = true;
+            export::proof_finished(true, self.start.elapsed());
+        }
+
+        // the timeline is process wide like the counters, keep what started during this proof
+        let mut timeline = std::mem::take(&mut self.phase_spans);
+        timeline.extend(
+            TIMELINE
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, _, start, _)| *start >= self.start)
+                .map(|(name, category, start, duration)| TimelineEvent {
+                    name: *name,
+                    category: *category,
+                    start: start.duration_since(self.start),
+                    duration: *duration,
+                }),
+        );
+        timeline.sort_by_key(|event| event.start);
+
+        ProofMetrics {
+            phase_times: std::mem::take(&mut self.phase_times),
+            total_time: self.start.elapsed(),
+            kernel_times: KERNEL_TIMES.lock().unwrap().clone(),
+            msm_count: MSM_COUNT.load(Ordering::Relaxed) - self.msm_count,
+            ntt_count: NTT_COUNT.load(Ordering::Relaxed) - self.ntt_count,
+            peak_device_memory: PEAK_DEVICE_MEMORY.load(Ordering::Relaxed),
+            peak_host_memory: PEAK_HOST_MEMORY.load(Ordering::Relaxed),
+            buffer_cache_hits: BUFFER_CACHE_HITS.load(Ordering::Relaxed) - self.buffer_cache_hits,
+            buffer_cache_misses: BUFFER_CACHE_MISSES.load(Ordering::Relaxed)
+                - self.buffer_cache_misses,
+            timeline,
+            gpu_health: None,
+            backend: "",
+            paths: PATH_COUNTS.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl Drop for MetricsCollector {
+    fn drop(&mut self) {
+        self.end_phase();
+        #[cfg(feature = "prometheus")]
+        if !self.finished {
+            export::proof_finished(false, self.start.elapsed());
+        }
+        if ACTIVE_COLLECTORS.fetch_sub(1, Ordering::Relaxed) == 1 {
+            // nobody reads the events of a failed proof, release them
+            drain_kernel_events();
+        }
+    }
+}
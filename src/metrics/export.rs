@@ -0,0 +1,39 @@
+//! Reports to the `metrics` facade, for fleet monitoring. The process installs
+//! a recorder, e.g. `metrics-exporter-prometheus`, to scrape them.
+
+use std::time::Duration;
+
+use metrics_facade::counter;
+use metrics_facade::gauge;
+use metrics_facade::histogram;
+
+use crate::device::cuda::allocated_memory_by_device;
+
+pub(super) fn phase_finished(name: &'static str, time: Duration) {
+    histogram!("zkwasm_prover_phase_seconds", "phase" => name).record(time.as_secs_f64());
+}
+
+pub(super) fn proof_finished(ok: bool, time: Duration) {
+    let result = if ok { "ok" } else { "failed" };
+    counter!("zkwasm_prover_proofs_total", "result" => result).increment(1);
+    if ok {
+        histogram!("zkwasm_prover_proof_seconds").record(time.as_secs_f64());
+    }
+}
+
+pub(super) fn memory_in_use(host: usize) {
+    for (device, allocated) in allocated_memory_by_device() {
+        gauge!("zkwasm_prover_device_memory_bytes", "device" => device.to_string())
+            .set(allocated as f64);
+    }
+    gauge!("zkwasm_prover_host_memory_bytes").set(host as f64);
+}
+
+pub(super) fn buffer_cache(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    counter!("zkwasm_prover_buffer_cache_total", "result" => result).increment(1);
+}
+
+pub(super) fn cuda_error(error: String) {
+    counter!("zkwasm_prover_cuda_errors_total", "error" => error).increment(1);
+}
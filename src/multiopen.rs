@@ -1,18 +1,30 @@
+use std::fmt;
 use std::iter;
 
+use crate::audit::Secret;
 use crate::hugetlb::HugePageAllocator;
 use halo2_proofs::arithmetic::CurveAffine;
 use halo2_proofs::arithmetic::FieldExt;
 use halo2_proofs::plonk::ProvingKey;
 use halo2_proofs::poly::Rotation;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 pub struct ProverQuery<'a, F: FieldExt> {
     pub point: F,
     pub rotation: Rotation,
     pub poly: &'a [F],
 }
 
+impl<'a, F: FieldExt> fmt::Debug for ProverQuery<'a, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProverQuery")
+            .field("point", &Secret("challenge", &self.point))
+            .field("rotation", &self.rotation)
+            .field("poly", &Secret("poly", self.poly))
+            .finish()
+    }
+}
+
 pub(crate) mod gwc {
     use ark_std::end_timer;
     use ark_std::start_timer;
@@ -35,9 +47,11 @@ pub(crate) mod gwc {
     use crate::device::cuda::CudaDevice;
     use crate::device::cuda::CudaDeviceBufRaw;
     use crate::device::Device as _;
-    use crate::device::DeviceResult;
     use crate::hugetlb::HugePageAllocator;
     use crate::multiopen::ProverQuery;
+    use crate::phases::Challenge;
+    use crate::phases::Challenges;
+    use crate::Error;
 
     pub struct CommitmentData<'a, F: FieldExt> {
         queries: Vec<ProverQuery<'a, F>>,
@@ -79,11 +93,12 @@ pub(crate) mod gwc {
         s_buf: [&CudaDeviceBufRaw; 2],
         eval_map: BTreeMap<(usize, C::Scalar), C::Scalar>,
         transcript: &mut T,
-    ) -> DeviceResult<()>
+        challenges: &mut Challenges<C>,
+    ) -> Result<(), Error>
     where
         I: IntoIterator<Item = ProverQuery<'a, C::Scalar>>,
     {
-        let v: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
+        let v: C::Scalar = challenges.squeeze(transcript, Challenge::Opening(0))?;
         let commitment_data = construct_intermediate_sets(queries);
 
         let mut eval_batch = vec![C::Scalar::zero(); commitment_data.len()];
@@ -163,7 +178,7 @@ pub(crate) mod gwc {
 
         let commitments = batch_msm::<C>(&g_buf, s_buf, ws.iter().map(|x| &x[..]).collect(), size)?;
         for commitment in commitments {
-            transcript.write_point(commitment).unwrap();
+            challenges.write_point(transcript, commitment)?;
         }
 
         end_timer!(timer);
@@ -192,12 +207,16 @@ pub mod shplonk {
     use crate::device::cuda::CudaBuffer;
     use crate::device::cuda::CudaDevice;
     use crate::device::cuda::CudaDeviceBufRaw;
+    use crate::device::cuda::CudaStream;
     use crate::device::Device as _;
     use crate::device::DeviceResult;
     use crate::hugetlb::HugePageAllocator;
     use crate::multiopen::ProverQuery;
+    use crate::phases::Challenge;
+    use crate::phases::Challenges;
+    use crate::Error;
 
-    fn construct_intermediate_sets<'a, F: FieldExt, I>(
+    pub(crate) fn construct_intermediate_sets<'a, F: FieldExt, I>(
         queries: I,
         eval_map: BTreeMap<(usize, F), F>,
     ) -> (Vec<(Vec<(&'a [F], Vec<F>)>, Vec<F>)>, Vec<F>)
@@ -256,7 +275,11 @@ pub mod shplonk {
                             .map(|x| {
                                 let eval = eval_map.get(&(poly.as_ptr() as usize, *x)).cloned();
                                 if eval.is_none() {
-                                    println!("miss eval on {:?} on set {}", *x, i);
+                                    tracing::error!(set = i, "missing evaluation");
+                                    tracing::trace!(
+                                        "missing evaluation at {:?}",
+                                        Secret("challenge", x)
+                                    );
                                 }
                                 eval.unwrap()
                             })
@@ -288,12 +311,13 @@ pub mod shplonk {
         eval_map: BTreeMap<(usize, C::Scalar), C::Scalar>,
         poly_cache: BTreeMap<usize, &ManuallyDrop<CudaDeviceBufRaw>>,
         transcript: &mut T,
-    ) -> DeviceResult<()>
+        challenges: &mut Challenges<C>,
+    ) -> Result<(), Error>
     where
         I: IntoIterator<Item = ProverQuery<'a, C::Scalar>>,
     {
-        let y: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
-        let v: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
+        let y: C::Scalar = challenges.squeeze(transcript, Challenge::Opening(0))?;
+        let v: C::Scalar = challenges.squeeze(transcript, Challenge::Opening(1))?;
 
         let (rotation_sets, super_point_set) = construct_intermediate_sets(queries, eval_map);
 
@@ -324,13 +348,8 @@ pub mod shplonk {
 
                     for (poly, evals) in queries.iter() {
                         unsafe {
-                            let mut stream = std::mem::zeroed();
-                            let err = cuda_runtime_sys::cudaStreamCreate(&mut stream);
-                            crate::device::cuda::to_result(
-                                (),
-                                err,
-                                "fail to run cudaStreamCreate",
-                            )?;
+                            let owned_stream = CudaStream::new(device)?;
+                            let stream = owned_stream.raw();
                             let poly_buf =
                                 if let Some(buf) = poly_cache.get(&(poly.as_ptr() as usize)) {
                                     *buf
@@ -342,9 +361,8 @@ pub mod shplonk {
                                     )?;
                                     &tmp_buf
                                 };
-                            if let Some(last_stream) = last_stream {
-                                cuda_runtime_sys::cudaStreamSynchronize(last_stream);
-                                cuda_runtime_sys::cudaStreamDestroy(last_stream);
+                            if let Some(last_stream) = last_stream.take() {
+                                last_stream.synchronize()?;
                             }
                             field_op_v3(
                                 device,
@@ -357,7 +375,7 @@ pub mod shplonk {
                                 FieldOp::Add,
                                 Some(stream),
                             )?;
-                            last_stream = Some(stream);
+                            last_stream = Some(owned_stream);
                             std::mem::swap(&mut last_buf, &mut tmp_buf);
                         }
 
@@ -367,11 +385,8 @@ pub mod shplonk {
                             evals_acc[i] = evals_acc[i] * y + evals[i];
                         }
                     }
-                    unsafe {
-                        if let Some(last_stream) = last_stream {
-                            cuda_runtime_sys::cudaStreamSynchronize(last_stream);
-                            cuda_runtime_sys::cudaStreamDestroy(last_stream);
-                        }
+                    if let Some(last_stream) = last_stream {
+                        last_stream.synchronize()?;
                     }
                     Ok((points, v_buf, evals_acc))
                 }
@@ -380,9 +395,9 @@ pub mod shplonk {
 
         let k = pk.vk.domain.k as usize;
         let (ntt_omegas_buf, ntt_pq_buf) =
-            crate::ntt_prepare(&device, pk.get_vk().domain.get_omega(), k)?;
+            crate::cuda::bn254::ntt_prepare_cached(&device, pk.get_vk().domain.get_omega(), k)?;
         let (intt_omegas_buf, intt_pq_buf) =
-            crate::ntt_prepare(&device, pk.get_vk().domain.get_omega_inv(), k)?;
+            crate::cuda::bn254::ntt_prepare_cached(&device, pk.get_vk().domain.get_omega_inv(), k)?;
         let intt_divisor_buf = device
             .alloc_device_buffer_from_slice::<C::Scalar>(&[pk.get_vk().domain.ifft_divisor])?;
 
@@ -405,6 +420,7 @@ pub mod shplonk {
                 None,
                 evals.len(),
                 FieldOp::Sub,
+                None,
             )?;
 
             let diffs: Vec<C::Scalar> = super_point_set
@@ -464,9 +480,9 @@ pub mod shplonk {
         )?;
 
         let commitment = batch_msm_v2::<C>(&g_buf, vec![&hx_buf], size)?;
-        transcript.write_point(commitment[0]).unwrap();
+        challenges.write_point(transcript, commitment[0])?;
 
-        let u: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
+        let u: C::Scalar = challenges.squeeze(transcript, Challenge::Opening(2))?;
 
         let zt_eval = super_point_set
             .iter()
@@ -567,7 +583,7 @@ pub mod shplonk {
 
         let commitments = batch_msm::<C>(&g_buf, s_buf, vec![&lx[..]], size)?;
         for commitment in commitments {
-            transcript.write_point(commitment).unwrap();
+            challenges.write_point(transcript, commitment)?;
         }
 
         Ok(())
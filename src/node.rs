@@ -0,0 +1,132 @@
+//! Node.js bindings, with the `node` feature, for services submitting proof
+//! jobs from TypeScript. `ProvingKey.load` reads a key with the reader given
+//! to `cli::set_pk_reader`, and `prove` returns a promise settled by the
+//! async proving API, so the event loop keeps running during the proof. The
+//! addon of a circuit links this crate, registers its reader and sets up
+//! `napi-build`.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use halo2_proofs::pairing::bn256::G1Affine;
+use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::poly::commitment::Params;
+use halo2_proofs::transcript::Blake2bWrite;
+use halo2_proofs::transcript::Challenge255;
+use halo2_proofs::transcript::TranscriptWrite as _;
+use napi::bindgen_prelude::Buffer;
+use napi_derive::napi;
+use rand::rngs::OsRng;
+
+use crate::cli::read_proving_key;
+use crate::config::ProverConfig;
+use crate::device::cuda::CudaDevice;
+use crate::device::Device as _;
+use crate::task::create_proof_async;
+use crate::witness::map_witness;
+
+fn js_error(e: impl ToString) -> napi::Error {
+    napi::Error::from_reason(e.to_string())
+}
+
+#[napi(object)]
+pub struct ProveOptions {
+    /// Device to prove on, the `DeviceManager` policy decides when unset.
+    pub device_id: Option<u32>,
+    pub use_gwc: Option<bool>,
+}
+
+#[napi(object)]
+pub struct PhaseTime {
+    pub name: String,
+    pub seconds: f64,
+}
+
+#[napi(object)]
+pub struct ProofResult {
+    /// The blake2b transcript.
+    pub proof: Buffer,
+    pub total_seconds: f64,
+    pub phase_times: Vec<PhaseTime>,
+    pub peak_device_memory: i64,
+    pub peak_host_memory: i64,
+}
+
+#[napi]
+pub fn device_count() -> napi::Result<u32> {
+    CudaDevice::get_device_count()
+        .map(|count| count as u32)
+        .map_err(js_error)
+}
+
+/// Params and the proving key read with them.
+#[napi(js_name = "ProvingKey")]
+pub struct JsProvingKey {
+    params: Arc<Params<G1Affine>>,
+    pk: Arc<ProvingKey<G1Affine>>,
+}
+
+#[napi]
+impl JsProvingKey {
+    #[napi(factory)]
+    pub fn load(params: String, pk: String) -> napi::Result<Self> {
+        let (params, pk) =
+            read_proving_key(Path::new(&params), Path::new(&pk)).map_err(js_error)?;
+        Ok(JsProvingKey {
+            params: Arc::new(params),
+            pk: Arc::new(pk),
+        })
+    }
+
+    #[napi(getter)]
+    pub fn k(&self) -> u32 {
+        self.pk.get_vk().domain.k()
+    }
+
+    /// Proves a witness file written by `write_witness`.
+    #[napi]
+    pub async fn prove(
+        &self,
+        witness: String,
+        options: Option<ProveOptions>,
+    ) -> napi::Result<ProofResult> {
+        let options = options.unwrap_or(ProveOptions {
+            device_id: None,
+            use_gwc: None,
+        });
+        let use_gwc = options.use_gwc.unwrap_or(false);
+        let witness = map_witness(&witness, &self.pk).map_err(js_error)?;
+        let config = ProverConfig {
+            device_id: options.device_id.map(|x| x as usize),
+            ..Default::default()
+        };
+
+        let (transcript, metrics) = create_proof_async(
+            self.params.clone(),
+            self.pk.clone(),
+            witness.instances,
+            witness.advices,
+            Blake2bWrite::<_, G1Affine, Challenge255<_>>::init(vec![]),
+            OsRng,
+            use_gwc,
+            config,
+        )
+        .await
+        .map_err(js_error)?;
+
+        Ok(ProofResult {
+            proof: transcript.finalize().into(),
+            total_seconds: metrics.total_time.as_secs_f64(),
+            phase_times: metrics
+                .phase_times
+                .iter()
+                .map(|(name, time)| PhaseTime {
+                    name: name.to_string(),
+                    seconds: time.as_secs_f64(),
+                })
+                .collect(),
+            peak_device_memory: metrics.peak_device_memory as i64,
+            peak_host_memory: metrics.peak_host_memory as i64,
+        })
+    }
+}
@@ -0,0 +1,160 @@
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::pairing::group::Curve as _;
+use halo2_proofs::pairing::group::Group as _;
+use opencl3::kernel::ExecuteKernel;
+use opencl3::kernel::Kernel;
+use rayon::iter::IntoParallelRefIterator as _;
+use rayon::iter::ParallelIterator as _;
+
+use crate::cuda::bn254::FieldOp;
+use crate::device::opencl::OpenCLDevice;
+use crate::device::opencl::OpenCLDeviceBuf;
+use crate::device::Device;
+use crate::device::DeviceResult;
+use crate::device::Error;
+
+// work items of msm_chunk, each one folds a contiguous chunk of points
+const MSM_WORK_ITEMS: usize = 8192;
+
+fn run(
+    device: &OpenCLDevice,
+    kernel: &Kernel,
+    global_size: usize,
+    set_args: impl FnOnce(&mut ExecuteKernel),
+) -> DeviceResult<()> {
+    let mut exec = ExecuteKernel::new(kernel);
+    set_args(&mut exec);
+    unsafe {
+        exec.set_global_work_size(global_size)
+            .enqueue_nd_range(device.queue())
+            .map_err(|e| Error::DeviceError(format!("OpenCL Error({}): fail to run kernel", e)))?;
+    }
+    Ok(())
+}
+
+/// `omega ^ i` for `i < 2 ^ (len_log - 1)`, the twiddle table of `ntt_raw`.
+pub fn ntt_prepare<F: FieldExt>(
+    device: &OpenCLDevice,
+    omega: F,
+    len_log: usize,
+) -> DeviceResult<OpenCLDeviceBuf> {
+    let half = 1usize << len_log >> 1;
+    let mut twiddles = vec![F::one(); half.max(1)];
+    for i in 1..half {
+        twiddles[i] = twiddles[i - 1] * omega;
+    }
+    device.alloc_device_buffer_from_slice(&twiddles[..])
+}
+
+pub fn ntt_raw(
+    device: &OpenCLDevice,
+    buf: &OpenCLDeviceBuf,
+    twiddles_buf: &OpenCLDeviceBuf,
+    len_log: usize,
+) -> DeviceResult<()> {
+    if len_log == 0 {
+        return Ok(());
+    }
+
+    let len = 1usize << len_log;
+    let bit_reverse = device.kernel("ntt_bit_reverse")?;
+    run(device, &bit_reverse, len, |exec| unsafe {
+        exec.set_arg(&*buf.buf.borrow()).set_arg(&(len_log as u32));
+    })?;
+
+    let stage = device.kernel("ntt_stage")?;
+    for i in 0..len_log {
+        run(device, &stage, len >> 1, |exec| unsafe {
+            exec.set_arg(&*buf.buf.borrow())
+                .set_arg(&*twiddles_buf.buf.borrow())
+                .set_arg(&(len_log as u32))
+                .set_arg(&(i as u32));
+        })?;
+    }
+    Ok(())
+}
+
+pub fn intt_raw(
+    device: &OpenCLDevice,
+    buf: &OpenCLDeviceBuf,
+    twiddles_buf: &OpenCLDeviceBuf,
+    divisor_buf: &OpenCLDeviceBuf,
+    len_log: usize,
+) -> DeviceResult<()> {
+    ntt_raw(device, buf, twiddles_buf, len_log)?;
+    let scale = device.kernel("field_scale")?;
+    run(device, &scale, 1 << len_log, |exec| unsafe {
+        exec.set_arg(&*buf.buf.borrow())
+            .set_arg(&*divisor_buf.buf.borrow());
+    })
+}
+
+pub(crate) fn field_op(
+    device: &OpenCLDevice,
+    res: &OpenCLDeviceBuf,
+    l: &OpenCLDeviceBuf,
+    r: &OpenCLDeviceBuf,
+    size: usize,
+    op: FieldOp,
+) -> DeviceResult<()> {
+    assert!(op != FieldOp::UOp);
+    let kernel = device.kernel("field_op")?;
+    run(device, &kernel, size, |exec| unsafe {
+        exec.set_arg(&*res.buf.borrow())
+            .set_arg(&*l.buf.borrow())
+            .set_arg(&*r.buf.borrow())
+            .set_arg(&(op as u32));
+    })
+}
+
+/// `sum(scalars[i] * points[i])`; the chunk results are folded on the host.
+pub fn msm<C: CurveAffine>(
+    device: &OpenCLDevice,
+    points_buf: &OpenCLDeviceBuf,
+    scalars: &[C::Scalar],
+) -> DeviceResult<C> {
+    let len = scalars.len();
+    if len == 0 {
+        return Ok(C::identity());
+    }
+
+    let work_items = MSM_WORK_ITEMS.min(len);
+    let chunk = (len + work_items - 1) / work_items;
+
+    let scalars_buf = device.alloc_device_buffer_from_slice(scalars)?;
+    let canonical_buf = device.alloc_device_buffer::<C::Scalar>(len)?;
+    let res_buf = device.alloc_device_buffer::<[C::Base; 3]>(work_items)?;
+
+    let unmont = device.kernel("field_unmont")?;
+    run(device, &unmont, len, |exec| unsafe {
+        exec.set_arg(&*canonical_buf.buf.borrow())
+            .set_arg(&*scalars_buf.buf.borrow());
+    })?;
+
+    let kernel = device.kernel("msm_chunk")?;
+    run(device, &kernel, work_items, |exec| unsafe {
+        exec.set_arg(&*points_buf.buf.borrow())
+            .set_arg(&*canonical_buf.buf.borrow())
+            .set_arg(&*res_buf.buf.borrow())
+            .set_arg(&(len as u32))
+            .set_arg(&(chunk as u32));
+    })?;
+
+    let mut res = vec![[C::Base::zero(); 3]; work_items];
+    device.copy_from_device_to_host(&mut res[..], &res_buf)?;
+
+    let sum = res
+        .par_iter()
+        .filter_map(|[x, y, z]| {
+            let z_inv: Option<C::Base> = z.invert().into();
+            z_inv.map(|z_inv| {
+                let z_inv2 = z_inv.square();
+                C::from_xy(*x * z_inv2, *y * z_inv2 * z_inv).unwrap()
+            })
+        })
+        .map(|p| p.to_curve())
+        .reduce(|| C::Curve::identity(), |a, b| a + b);
+    Ok(sum.to_affine())
+}
@@ -0,0 +1,151 @@
+//! The permutation product polynomials computed on device.
+//!
+//! For a chunk of the permutation columns, z is the running product of
+//! `Π (δ^j ω^i β + γ + v_j) / Π (β σ_j + γ + v_j)` from one. The host path
+//! in `_create_proof_from_advices` accumulates, inverts and multiplies every
+//! chunk with rayon; here both products are accumulated column by column on
+//! device and the inversion and running product reuse the kernels of the
+//! lookup z polynomials.
+
+use cuda_runtime_sys::cudaStream_t;
+use halo2_proofs::arithmetic::FieldExt;
+
+use crate::cuda::bn254_c;
+use crate::device::cuda::tag_buffer;
+use crate::device::cuda::to_result;
+use crate::device::cuda::CudaBuffer as _;
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::cuda::CudaStream;
+use crate::device::Device as _;
+use crate::device::DeviceResult;
+
+/// Values of a permutation column, on the host or already on device.
+#[derive(Clone, Copy)]
+pub(crate) enum ColumnValues<'a, F> {
+    Host(&'a [F]),
+    Device(&'a CudaDeviceBufRaw),
+}
+
+/// Device buffers of the permutation products, reused across the chunks of a proof.
+pub(crate) struct PermutationProducts<F> {
+    device: CudaDevice,
+    stream: CudaStream,
+    size: usize,
+    beta: F,
+    gamma: F,
+    beta_gamma: CudaDeviceBufRaw,
+    // [1, ω, ω^2, ..]
+    omega_powers: CudaDeviceBufRaw,
+    z: CudaDeviceBufRaw,
+    numerator: CudaDeviceBufRaw,
+    values: CudaDeviceBufRaw,
+    sigma: CudaDeviceBufRaw,
+}
+
+impl<F: FieldExt> PermutationProducts<F> {
+    pub(crate) fn new(
+        device: &CudaDevice,
+        size: usize,
+        omega: F,
+        beta: F,
+        gamma: F,
+    ) -> DeviceResult<Self> {
+        let omega_powers = device.alloc_device_buffer::<F>(size)?;
+        device.copy_from_host_to_device(&omega_powers, &[F::one(), omega][..])?;
+        let err = unsafe { bn254_c::expand_omega_buffer(omega_powers.ptr(), size as i32) };
+        to_result((), err, "fail to run expand_omega_buffer")?;
+
+        let [z, numerator, values, sigma] = [0; 4].map(|_| device.alloc_device_buffer::<F>(size));
+        let (z, numerator, values, sigma) = (z?, numerator?, values?, sigma?);
+        tag_buffer(&z, "permutation product");
+
+        Ok(PermutationProducts {
+            device: device.clone(),
+            stream: CudaStream::new(device)?,
+            size,
+            beta,
+            gamma,
+            beta_gamma: device.alloc_device_buffer_from_slice(&[beta, gamma][..])?,
+            omega_powers,
+            z,
+            numerator,
+            values,
+            sigma,
+        })
+    }
+
+    // res = a * x + b + y, or res *= a * x + b + y unless first
+    fn step(
+        &self,
+        res: &CudaDeviceBufRaw,
+        x: &CudaDeviceBufRaw,
+        y: &CudaDeviceBufRaw,
+        a_b: &CudaDeviceBufRaw,
+        first: bool,
+    ) -> DeviceResult<()> {
+        let err = unsafe {
+            bn254_c::permutation_product_step(
+                res.ptr(),
+                x.ptr(),
+                y.ptr(),
+                a_b.ptr(),
+                first,
+                self.size as i32,
+                self.stream.raw(),
+            )
+        };
+        to_result((), err, "fail to run permutation_product_step")
+    }
+
+    /// Writes the product polynomial of a chunk to `z`, `columns` being the
+    /// values and σ of its columns and `delta` the δ power of its first column.
+    pub(crate) fn chunk(
+        &mut self,
+        columns: &[(ColumnValues<'_, F>, &[F])],
+        delta: F,
+        z: &mut [F],
+    ) -> DeviceResult<()> {
+        let stream: cudaStream_t = self.stream.raw();
+        // [δ^j β, γ] of the numerators, alive until the stream is synchronized
+        let mut numerator_coeffs = vec![];
+        let mut delta = delta;
+        for (j, (values, sigma)) in columns.iter().enumerate() {
+            // the previous column has to be read before its buffers are overwritten
+            self.stream.synchronize()?;
+            let values = match values {
+                ColumnValues::Host(values) => {
+                    self.device
+                        .copy_from_host_to_device_async(&self.values, values, stream)?;
+                    &self.values
+                }
+                ColumnValues::Device(buf) => *buf,
+            };
+            self.device
+                .copy_from_host_to_device_async(&self.sigma, sigma, stream)?;
+
+            self.step(&self.z, &self.sigma, values, &self.beta_gamma, j == 0)?;
+            let a_b = self.device.alloc_device_buffer_from_slice_async(
+                &[delta * self.beta, self.gamma][..],
+                stream,
+            )?;
+            self.step(&self.numerator, &self.omega_powers, values, &a_b, j == 0)?;
+            numerator_coeffs.push(a_b);
+            delta *= F::DELTA;
+        }
+
+        let err = unsafe {
+            bn254_c::eval_fraction_product_z(
+                self.z.ptr(),
+                self.numerator.ptr(),
+                self.values.ptr(),
+                self.size as i32,
+                stream,
+            )
+        };
+        to_result((), err, "fail to run eval_fraction_product_z")?;
+        self.device
+            .copy_from_device_to_host_async(z, &self.z, stream)?;
+        self.stream.synchronize()
+    }
+}
@@ -0,0 +1,108 @@
+use std::io;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::transcript::EncodedChallenge;
+use halo2_proofs::transcript::TranscriptWrite;
+
+use crate::Error;
+
+/// Challenges squeezed by the prover, in transcript order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Challenge {
+    /// After instance and advice commitments.
+    Theta,
+    /// After permuted lookup commitments.
+    Beta,
+    Gamma,
+    /// After permutation/lookup/shuffle products and the random poly commitment.
+    Y,
+    /// After h piece commitments.
+    X,
+    /// Multiopen challenges after the evaluations, numbered from 0:
+    /// `v` for gwc, `y, v, u` for shplonk.
+    Opening(usize),
+}
+
+/// External Fiat–Shamir driver for `create_proof_from_advices_with_phases`.
+///
+/// Instead of squeezing its own transcript, the prover stops before every
+/// challenge and asks the driver, passing the points and scalars written to
+/// the transcript since the previous challenge. The transcript still receives
+/// every write; the driver is responsible for handing out challenges its
+/// verifier reproduces.
+pub trait ProofPhases<C: CurveAffine> {
+    fn challenge(
+        &mut self,
+        challenge: Challenge,
+        points: &[C],
+        scalars: &[C::Scalar],
+    ) -> Result<C::Scalar, Error>;
+}
+
+/// Routes challenges to the transcript (eager) or to a `ProofPhases` driver
+/// (synchronized), recording writes for the latter.
+pub(crate) struct Challenges<'a, C: CurveAffine> {
+    external: Option<&'a mut dyn ProofPhases<C>>,
+    points: Vec<C>,
+    scalars: Vec<C::Scalar>,
+}
+
+impl<'a, C: CurveAffine> Challenges<'a, C> {
+    pub(crate) fn new(external: Option<&'a mut dyn ProofPhases<C>>) -> Self {
+        Self {
+            external,
+            points: vec![],
+            scalars: vec![],
+        }
+    }
+
+    pub(crate) fn record_point(&mut self, point: C) {
+        if self.external.is_some() {
+            self.points.push(point);
+        }
+    }
+
+    pub(crate) fn record_scalar(&mut self, scalar: C::Scalar) {
+        if self.external.is_some() {
+            self.scalars.push(scalar);
+        }
+    }
+
+    /// `None` in eager mode, the caller squeezes the transcript itself.
+    pub(crate) fn external(&mut self, challenge: Challenge) -> Option<Result<C::Scalar, Error>> {
+        let points = std::mem::take(&mut self.points);
+        let scalars = std::mem::take(&mut self.scalars);
+        self.external
+            .as_mut()
+            .map(|driver| driver.challenge(challenge, &points[..], &scalars[..]))
+    }
+
+    pub(crate) fn write_point<E: EncodedChallenge<C>, T: TranscriptWrite<C, E>>(
+        &mut self,
+        transcript: &mut T,
+        point: C,
+    ) -> io::Result<()> {
+        self.record_point(point);
+        transcript.write_point(point)
+    }
+
+    pub(crate) fn write_scalar<E: EncodedChallenge<C>, T: TranscriptWrite<C, E>>(
+        &mut self,
+        transcript: &mut T,
+        scalar: C::Scalar,
+    ) -> io::Result<()> {
+        self.record_scalar(scalar);
+        transcript.write_scalar(scalar)
+    }
+
+    pub(crate) fn squeeze<E: EncodedChallenge<C>, T: TranscriptWrite<C, E>>(
+        &mut self,
+        transcript: &mut T,
+        challenge: Challenge,
+    ) -> Result<C::Scalar, Error> {
+        match self.external(challenge) {
+            Some(res) => res,
+            None => Ok(*transcript.squeeze_challenge_scalar::<()>()),
+        }
+    }
+}
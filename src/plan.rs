@@ -0,0 +1,375 @@
+//! Planning of the prover's work from the circuit alone: how evaluate_h groups
+//! the gate terms and how much device and host memory a proof takes.
+//!
+//! Nothing here touches a device, circuit tooling can use it to estimate the
+//! cost of proving a circuit on a machine without a GPU.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::mem::size_of;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::pairing::group::ff::PrimeField as _;
+use halo2_proofs::plonk::evaluation_gpu::ProveExpression;
+use halo2_proofs::plonk::evaluation_gpu::ProveExpressionUnit;
+use halo2_proofs::plonk::ProvingKey;
+
+use crate::cuda::bn254::MSM_BATCH_SCALARS;
+
+#[cfg(test)]
+mod test;
+
+// icicle keeps buckets in projective coordinates
+const PROJECTIVE_COORDINATES: usize = 3;
+// batch msm keeps the next msm in flight while the previous one is read back
+const MSM_IN_FLIGHT: usize = 2;
+// lookup z, permuted input and table, input and table expressions
+const LOOKUP_EXTENDED_BUFFERS: usize = 5;
+// resident threads of a multiprocessor the msm kernels are launched with
+const MSM_THREADS_PER_MULTIPROCESSOR: usize = 1024;
+const MIN_MSM_WINDOW_BITS: usize = 4;
+const MAX_MSM_WINDOW_BITS: usize = 22;
+
+/// Terms of an expression, as the multiset of units multiplied and the
+/// coefficient as a polynomial in y, split in groups evaluated one at a time.
+pub type ExprGroups<F> = Vec<Vec<(BTreeMap<ProveExpressionUnit, u32>, BTreeMap<u32, F>)>>;
+
+/// Flattens `expr` into terms and groups consecutive terms so that a group
+/// queries a bounded number of columns, the columns of one group are extended
+/// and held on device together.
+pub fn analyze_expr_tree<F: FieldExt>(expr: &ProveExpression<F>, k: usize) -> ExprGroups<F> {
+    let tree = expr.clone().flatten();
+    let tree = fold_terms(tree.into_iter().map(|(us, v)| {
+        let mut map = BTreeMap::new();
+        for mut u in us {
+            if let Some(c) = map.get_mut(&mut u) {
+                *c = *c + 1;
+            } else {
+                map.insert(u.clone(), 1);
+            }
+        }
+        (map, v.clone())
+    }));
+
+    let limit = if k < 23 { 26 } else { 10 };
+    let mut v = HashSet::new();
+
+    let mut expr_group = vec![];
+    let mut expr_groups = vec![];
+    for (_, (units, coeff)) in tree.iter().enumerate() {
+        let mut v_new = v.clone();
+        let mut v_new_clean = HashSet::new();
+        let mut muls_new = 0;
+        for (unit, exp) in units {
+            v_new.insert(unit.get_group());
+            v_new_clean.insert(unit.get_group());
+            muls_new += exp;
+        }
+
+        if v_new.len() > limit {
+            v = v_new_clean;
+
+            expr_groups.push(expr_group);
+            expr_group = vec![(units.clone(), coeff.clone())];
+        } else {
+            v = v_new;
+            expr_group.push((units.clone(), coeff.clone()));
+        }
+    }
+
+    expr_groups.push(expr_group);
+    expr_groups
+}
+
+/// Merges the terms multiplying the same units, the same products show up in
+/// many gates, and drops the coefficients and terms folding to zero. The terms
+/// are ordered by the columns they query, so terms sharing columns land in the
+/// same group and their columns are extended once.
+pub fn fold_terms<F: FieldExt>(
+    terms: impl IntoIterator<Item = (BTreeMap<ProveExpressionUnit, u32>, BTreeMap<u32, F>)>,
+) -> Vec<(BTreeMap<ProveExpressionUnit, u32>, BTreeMap<u32, F>)> {
+    let mut merged: BTreeMap<_, BTreeMap<u32, F>> = BTreeMap::new();
+    for (units, ys) in terms {
+        let coeff = merged.entry(units).or_default();
+        for (exp, c) in ys {
+            *coeff.entry(exp).or_insert(F::zero()) += c;
+        }
+    }
+
+    let mut terms = merged
+        .into_iter()
+        .filter_map(|(units, mut ys)| {
+            ys.retain(|_, c| *c != F::zero());
+            (!ys.is_empty()).then_some((units, ys))
+        })
+        .collect::<Vec<_>>();
+    terms.sort_by_cached_key(|(units, _)| units.keys().map(|u| u.get_group()).collect::<Vec<_>>());
+    terms
+}
+
+/// Distinct columns queried by the largest group of `groups`.
+pub fn max_group_columns<F: FieldExt>(groups: &ExprGroups<F>) -> usize {
+    groups
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .flat_map(|(units, _)| units.keys().map(|u| u.get_group()))
+                .collect::<BTreeSet<_>>()
+                .len()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// icicle temporaries in bytes of a batch of `batch` msm of `len` points with windows of `c` bits.
+pub fn msm_memory<C: CurveAffine>(len: usize, batch: usize, c: usize) -> usize {
+    let windows = (C::Scalar::NUM_BITS as usize + c - 1) / c;
+    // unsorted and sorted (bucket, point) index pairs, plus the buckets
+    batch
+        * (len * windows * 4 * size_of::<u32>()
+            + (windows << c) * PROJECTIVE_COORDINATES * size_of::<C::Base>())
+}
+
+/// Window bits of the bucketed msm for a batch of `batch` msm of `len` points on
+/// a device with `multiprocessors` SMs and `free_memory` bytes free, by a
+/// Pippenger cost model: every point is added to one bucket per window, in
+/// parallel over the device, then the buckets of every window are summed. Wider
+/// windows mean fewer additions of points but more buckets to sum.
+pub fn msm_window_bits<C: CurveAffine>(
+    len: usize,
+    batch: usize,
+    multiprocessors: usize,
+    free_memory: usize,
+) -> usize {
+    let threads = (multiprocessors.max(1) * MSM_THREADS_PER_MULTIPROCESSOR) as f64;
+    let cost = |c: usize| {
+        let windows = (C::Scalar::NUM_BITS as usize + c - 1) / c;
+        let accumulation = (batch * len * windows) as f64 / threads;
+        // running sums over the buckets, a tree of depth c across the device
+        let reduction = (2 * batch * (windows << c)) as f64 / threads + c as f64;
+        accumulation + reduction
+    };
+
+    (MIN_MSM_WINDOW_BITS..=MAX_MSM_WINDOW_BITS)
+        .filter(|&c| msm_memory::<C>(len, batch, c) <= free_memory)
+        .min_by(|a, b| cost(*a).total_cmp(&cost(*b)))
+        .unwrap_or(MIN_MSM_WINDOW_BITS)
+}
+
+/// Points per chunk of a streamed msm of `columns` columns over `len` bases,
+/// see `cuda::bn254::msm_streamed`: the largest power of two whose two windows
+/// of bases and scalars and msm temporaries fit in `free_memory` bytes. `None`
+/// when not even a small window fits.
+pub fn msm_stream_window<C: CurveAffine>(
+    len: usize,
+    columns: usize,
+    multiprocessors: usize,
+    free_memory: usize,
+) -> Option<usize> {
+    let columns = columns.max(1);
+    let fits = |window: usize| {
+        let windows = 2 * window * (size_of::<C>() + columns * size_of::<C::Scalar>());
+        let c = msm_window_bits::<C>(window, columns, multiprocessors, free_memory);
+        windows + msm_memory::<C>(window, columns, c) <= free_memory
+    };
+    let mut window = len.next_power_of_two();
+    while window > 1 << MIN_MSM_WINDOW_BITS && !fits(window) {
+        window >>= 1;
+    }
+    fits(window).then_some(window.min(len.max(1)))
+}
+
+/// The dimensions of a circuit the cost of proving it depends on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitShape {
+    pub k: u32,
+    pub extended_k: u32,
+    pub advice_columns: usize,
+    pub instance_columns: usize,
+    pub lookups: usize,
+    pub shuffles: usize,
+    pub shuffle_groups: usize,
+    pub permutation_columns: usize,
+    /// Degree of the constraint system, permutation columns are chunked by `degree - 2`.
+    pub degree: usize,
+    /// Distinct rotations of the column queries.
+    pub rotations: usize,
+    /// Distinct columns of the largest gate group, see `analyze_expr_tree`.
+    pub gate_group_columns: usize,
+}
+
+impl CircuitShape {
+    pub fn new<C: CurveAffine>(pk: &ProvingKey<C>) -> Self {
+        let k = pk.get_vk().domain.k();
+        let cs = &pk.vk.cs;
+
+        CircuitShape {
+            k,
+            extended_k: pk.get_vk().domain.extended_k(),
+            advice_columns: cs.num_advice_columns,
+            instance_columns: cs.num_instance_columns,
+            lookups: cs.lookups.len(),
+            shuffles: cs.shuffles.0.len(),
+            shuffle_groups: cs.shuffles.group(cs.degree()).len(),
+            permutation_columns: cs.permutation.columns.len(),
+            degree: cs.degree(),
+            rotations: cs
+                .advice_queries
+                .iter()
+                .map(|(_, rotation)| rotation.0)
+                .chain(cs.instance_queries.iter().map(|(_, rotation)| rotation.0))
+                .chain(cs.fixed_queries.iter().map(|(_, rotation)| rotation.0))
+                .collect::<BTreeSet<_>>()
+                .len(),
+            gate_group_columns: pk
+                .ev
+                .gpu_gates_expr
+                .first()
+                .map(|expr| max_group_columns(&analyze_expr_tree(expr, k as usize)))
+                .unwrap_or(0),
+        }
+    }
+
+    pub fn permutation_products(&self) -> usize {
+        let chunk_len = self.degree - 2;
+        (self.permutation_columns + chunk_len - 1) / chunk_len
+    }
+
+    /// Extended buffers held by the busiest step of evaluate_h, besides h itself.
+    pub fn extended_buffers_in_use(&self) -> usize {
+        // a gate group ntts its distinct columns into buffers, with two scratch buffers in flight
+        let gates = self.gate_group_columns + 2;
+        let lookups = if self.lookups == 0 {
+            0
+        } else {
+            // each with its ntt scratch buffer
+            2 * LOOKUP_EXTENDED_BUFFERS
+        };
+        // products, l0 and l_last
+        let permutation = self.permutation_products() + 3;
+
+        gates.max(lookups).max(permutation)
+    }
+}
+
+/// Peak device memory of a proof, split by the buffers making it up. All sizes in bytes.
+///
+/// The model follows the allocations of `create_proof_from_advices_with_config`
+/// and rounds up where the prover's choice depends on the witness.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceMemoryEstimate {
+    pub k: u32,
+    /// SRS bases, ntt twiddles and scratch buffers held by the backend for the whole proof.
+    pub resident: usize,
+    /// Domain sized buffers of the concurrent lookup z streams.
+    pub domain_buffers: usize,
+    /// Extended domain buffers live at once during evaluate_h.
+    pub extended_buffers: usize,
+    /// icicle temporaries of the msm in flight and the staging of batched columns.
+    pub msm: usize,
+    pub peak: usize,
+}
+
+impl DeviceMemoryEstimate {
+    /// Estimate for a proof over curve `C` with `streams` lookup z streams and
+    /// msm windows of `msm_window_bits`, `None` being the autotuned choice.
+    pub fn new<C: CurveAffine>(
+        shape: &CircuitShape,
+        streams: usize,
+        msm_window_bits: Option<usize>,
+    ) -> Self {
+        let k = shape.k;
+        let size = 1usize << k;
+        let scalar = size_of::<C::Scalar>();
+        let point = size_of::<C>();
+
+        // g_lagrange, g, s, t, and the omegas of the ntt and the intt
+        let resident = 2 * size * point + 4 * size * scalar;
+        let domain_buffers = streams.max(1) * 5 * size * scalar;
+
+        let extended_size = (1usize << shape.extended_k) * scalar;
+        let extended_buffers = shape.extended_buffers_in_use() * extended_size + extended_size;
+
+        let c = msm_window_bits.unwrap_or_else(|| (k as usize).saturating_sub(4).max(1));
+        // columns committed several per launch are staged in two batch buffers
+        let batch_buffers = if size < MSM_BATCH_SCALARS {
+            2 * MSM_BATCH_SCALARS * scalar
+        } else {
+            0
+        };
+        let msm = MSM_IN_FLIGHT * msm_memory::<C>(size, 1, c) + batch_buffers;
+
+        DeviceMemoryEstimate {
+            k,
+            resident,
+            domain_buffers,
+            extended_buffers,
+            msm,
+            peak: resident + domain_buffers.max(extended_buffers) + msm,
+        }
+    }
+}
+
+/// Host memory of a proof served by the huge page allocators, in bytes and in
+/// huge pages. Every buffer is mapped on its own, so it takes whole pages.
+///
+/// Witness buffers filled by the caller (`prepare_advice_buffer`,
+/// `prepare_lookup_buffer`, ...) are included, the proving key is not.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostMemoryEstimate {
+    pub k: u32,
+    /// Size of one huge page.
+    pub huge_page_size: usize,
+    /// Bytes allocated with `HugePageAllocator`, pinned for the device.
+    pub pinned: usize,
+    /// Bytes allocated with `UnpinnedHugePageAllocator`.
+    pub unpinned: usize,
+    /// Huge pages backing both, the minimum for `vm.nr_hugepages`.
+    pub huge_pages: usize,
+}
+
+impl HostMemoryEstimate {
+    /// Estimate for a proof over scalars of `F` with huge pages of `huge_page_size` bytes.
+    pub fn new<F: FieldExt>(shape: &CircuitShape, huge_page_size: usize) -> Self {
+        let size = 1usize << shape.k;
+        let column = size * size_of::<F>();
+        let pages = |bytes: usize| (bytes + huge_page_size - 1) / huge_page_size;
+
+        let pinned_columns = shape.advice_columns
+            + shape.instance_columns
+            // input, table, permuted input, permuted table and z
+            + 5 * shape.lookups
+            + shape.permutation_products()
+            + shape.shuffle_groups
+            // random poly and h pieces
+            + 2
+            // one batch poly per point set of the multiopen, distinct rotations bound them,
+            // permutation products are opened at 0, 1 and the last row as well
+            + shape.rotations
+            + 2;
+        // the permuted table state of every lookup, input and shuffle expressions of every shuffle
+        let unpinned_buffers = [
+            (shape.lookups, size * size_of::<bool>()),
+            (2 * shape.shuffles, column),
+        ];
+
+        let pinned = pinned_columns * column;
+        let unpinned = unpinned_buffers.iter().map(|(n, bytes)| n * bytes).sum();
+        let huge_pages = pinned_columns * pages(column)
+            + unpinned_buffers
+                .iter()
+                .map(|(n, bytes)| n * pages(*bytes))
+                .sum::<usize>();
+
+        HostMemoryEstimate {
+            k: shape.k,
+            huge_page_size,
+            pinned,
+            unpinned,
+            huge_pages,
+        }
+    }
+}
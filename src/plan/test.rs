@@ -0,0 +1,141 @@
+use super::CircuitShape;
+use super::DeviceMemoryEstimate;
+use super::HostMemoryEstimate;
+use super::{fold_terms, msm_memory, msm_stream_window, msm_window_bits};
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::pairing::bn256::{Fr, G1Affine};
+use halo2_proofs::plonk::evaluation_gpu::ProveExpressionUnit;
+use halo2_proofs::poly::Rotation;
+use std::collections::BTreeMap;
+
+fn shape(k: u32) -> CircuitShape {
+    CircuitShape {
+        k,
+        extended_k: k + 3,
+        advice_columns: 60,
+        instance_columns: 1,
+        lookups: 12,
+        shuffles: 2,
+        shuffle_groups: 1,
+        permutation_columns: 50,
+        degree: 9,
+        rotations: 4,
+        gate_group_columns: 20,
+    }
+}
+
+#[test]
+fn test_permutation_products() {
+    let mut shape = shape(18);
+    assert_eq!(shape.permutation_products(), 8);
+    shape.permutation_columns = 49;
+    assert_eq!(shape.permutation_products(), 7);
+}
+
+#[test]
+fn test_extended_buffers_in_use() {
+    let mut shape = shape(18);
+    assert_eq!(shape.extended_buffers_in_use(), 22);
+    shape.gate_group_columns = 0;
+    assert_eq!(shape.extended_buffers_in_use(), 11);
+    shape.permutation_columns = 7;
+    assert_eq!(shape.extended_buffers_in_use(), 10);
+    shape.lookups = 0;
+    assert_eq!(shape.extended_buffers_in_use(), 4);
+}
+
+#[test]
+fn test_device_memory_estimate() {
+    let shape = shape(20);
+    let one = DeviceMemoryEstimate::new::<G1Affine>(&shape, 1, Some(16));
+    let many = DeviceMemoryEstimate::new::<G1Affine>(&shape, 8, Some(16));
+    assert_eq!(one.resident, many.resident);
+    assert_eq!(one.domain_buffers * 8, many.domain_buffers);
+    assert!(one.peak <= many.peak);
+    assert_eq!(
+        many.peak,
+        many.resident + many.domain_buffers.max(many.extended_buffers) + many.msm
+    );
+}
+
+#[test]
+fn test_host_memory_estimate() {
+    // a column at k = 18 takes 8MB, 4 pages of 2MB or one page of 1GB
+    let shape = shape(18);
+    let small = HostMemoryEstimate::new::<Fr>(&shape, 2 << 20);
+    let large = HostMemoryEstimate::new::<Fr>(&shape, 1 << 30);
+    assert_eq!(small.pinned, large.pinned);
+    assert_eq!(small.unpinned, large.unpinned);
+    assert_eq!(small.pinned % (8 << 20), 0);
+    assert_eq!(
+        small.huge_pages * (2 << 20),
+        small.pinned + small.unpinned + (shape.lookups * ((2 << 20) - (1 << 18)))
+    );
+    assert_eq!(
+        large.huge_pages,
+        small.pinned / (8 << 20) + shape.lookups + 2 * shape.shuffles
+    );
+}
+
+#[test]
+fn test_msm_window_bits() {
+    let sms = 80;
+    let plenty = usize::MAX;
+    let small = msm_window_bits::<G1Affine>(1 << 12, 1, sms, plenty);
+    let large = msm_window_bits::<G1Affine>(1 << 22, 1, sms, plenty);
+    assert!(small < large);
+    // a batch adds more points per bucket sum, like a longer msm
+    assert!(msm_window_bits::<G1Affine>(1 << 12, 16, sms, plenty) >= small);
+
+    let memory = msm_memory::<G1Affine>(1 << 22, 1, large) - 1;
+    let c = msm_window_bits::<G1Affine>(1 << 22, 1, sms, memory);
+    assert_ne!(c, large);
+    assert!(msm_memory::<G1Affine>(1 << 22, 1, c) <= memory);
+}
+
+#[test]
+fn test_msm_stream_window() {
+    let sms = 80;
+    let len = 1 << 26;
+    assert_eq!(
+        msm_stream_window::<G1Affine>(len, 1, sms, usize::MAX),
+        Some(len)
+    );
+
+    let small = msm_stream_window::<G1Affine>(len, 4, sms, 2 << 30).unwrap();
+    assert!(small.is_power_of_two() && small < len);
+    assert!(msm_stream_window::<G1Affine>(len, 1, sms, 2 << 30).unwrap() >= small);
+    assert_eq!(msm_stream_window::<G1Affine>(len, 4, sms, 1 << 10), None);
+}
+
+#[test]
+fn test_fold_terms() {
+    let advice = |column_index| ProveExpressionUnit::Advice {
+        column_index,
+        rotation: Rotation(0),
+    };
+    let term = |units: &[usize], ys: &[(u32, u64)]| {
+        let mut map = BTreeMap::new();
+        for &u in units {
+            *map.entry(advice(u)).or_insert(0) += 1;
+        }
+        let ys = ys
+            .iter()
+            .map(|&(e, c)| (e, Fr::from(c)))
+            .collect::<BTreeMap<_, _>>();
+        (map, ys)
+    };
+
+    let terms = fold_terms(vec![
+        term(&[2], &[(0, 1)]),
+        term(&[0, 1], &[(0, 3), (1, 1)]),
+        term(&[2], &[(0, 2)]),
+        term(&[1, 0], &[(1, 4)]),
+        term(&[3], &[(0, 1)]),
+        (term(&[3], &[]).0, [(0, -Fr::one())].into_iter().collect()),
+    ]);
+    assert_eq!(
+        terms,
+        vec![term(&[0, 1], &[(0, 3), (1, 5)]), term(&[2], &[(0, 3)]),]
+    );
+}
@@ -0,0 +1,75 @@
+use std::sync::mpsc::channel;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+use crate::Error;
+
+/// Bytes of fetched items not yet consumed, the producer waits on it.
+struct InFlight {
+    bytes: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Pulls `items` on a worker thread while `consume` runs on the caller's thread.
+///
+/// Fetching happens in the iterator's `next`, e.g. reading a witness from disk or
+/// running the witness generator. The worker fetches ahead as long as the items
+/// fetched but not yet consumed, sized by `bytes_of`, fit in `budget`. It never
+/// waits with nothing in flight, so a budget smaller than one item degrades to
+/// fetching the next item once the current one is consumed.
+pub(crate) fn prefetch<I, F, C>(
+    items: I,
+    budget: usize,
+    bytes_of: F,
+    mut consume: C,
+) -> Result<(), Error>
+where
+    I: IntoIterator,
+    I::IntoIter: Send,
+    I::Item: Send,
+    F: Fn(&I::Item) -> usize + Sync,
+    C: FnMut(I::Item) -> Result<(), Error>,
+{
+    let in_flight = InFlight {
+        bytes: Mutex::new(0),
+        released: Condvar::new(),
+    };
+    let (sender, receiver) = channel();
+    let items = items.into_iter();
+
+    std::thread::scope(|s| {
+        let in_flight = &in_flight;
+        let bytes_of = &bytes_of;
+        s.spawn(move || {
+            for item in items {
+                let bytes = bytes_of(&item);
+                {
+                    let mut current = in_flight.bytes.lock().unwrap();
+                    while *current != 0 && *current + bytes > budget {
+                        current = in_flight.released.wait(current).unwrap();
+                    }
+                    *current += bytes;
+                }
+                // the consumer is gone after an error, stop fetching
+                if sender.send((item, bytes)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let res = (|| {
+            for (item, bytes) in receiver.iter() {
+                let res = consume(item);
+                *in_flight.bytes.lock().unwrap() -= bytes;
+                in_flight.released.notify_one();
+                res?;
+            }
+            Ok(())
+        })();
+        // unblock the worker, it may wait for room before noticing the receiver is dropped
+        drop(receiver);
+        *in_flight.bytes.lock().unwrap() = 0;
+        in_flight.released.notify_one();
+        res
+    })
+}
@@ -0,0 +1,135 @@
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::transcript::EncodedChallenge;
+use halo2_proofs::transcript::Transcript;
+
+use crate::compression::Compression;
+use crate::config::ProverConfig;
+
+const MAGIC: &[u8; 4] = b"ZKWP";
+// version 1 has no compression byte
+const VERSION: u8 = 2;
+// bytes of the tag per scalar, below the modulus of any supported field
+const TAG_CHUNK: usize = 31;
+
+/// Absorbs a domain separation tag, e.g. the chain id and contract of a
+/// deployment, into `transcript`. The prover does it before anything else when
+/// `ProverConfig::domain_separation` is set, the verifier has to do the same on
+/// its transcript before `verify_proof`: a proof made for one context then
+/// fails to verify in any other.
+pub fn absorb_domain_separation<C: CurveAffine, E: EncodedChallenge<C>, T: Transcript<C, E>>(
+    transcript: &mut T,
+    tag: &[u8],
+) -> io::Result<()> {
+    transcript.common_scalar(C::Scalar::from(tag.len() as u64))?;
+    for chunk in tag.chunks(TAG_CHUNK) {
+        let mut wide = [0u8; 64];
+        wide[..chunk.len()].copy_from_slice(chunk);
+        transcript.common_scalar(C::Scalar::from_bytes_wide(&wide))?;
+    }
+    Ok(())
+}
+
+/// A proof with what a verifier needs besides the verifying key and instances
+/// to check it: the multiopen it was made with and the domain separation tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proof {
+    pub use_gwc: bool,
+    pub domain_separation: Option<Vec<u8>>,
+    /// The finalized transcript.
+    pub transcript: Vec<u8>,
+}
+
+impl Proof {
+    pub fn new(transcript: Vec<u8>, use_gwc: bool, config: &ProverConfig) -> Self {
+        Proof {
+            use_gwc,
+            domain_separation: config.domain_separation.clone(),
+            transcript,
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.write_compressed(writer, Compression::None)
+    }
+
+    /// Writes the proof with everything after the header compressed by `compression`,
+    /// `read` finds out which from the header.
+    pub fn write_compressed<W: Write>(
+        &self,
+        writer: &mut W,
+        compression: Compression,
+    ) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION, self.use_gwc as u8, compression.tag()])?;
+        let mut writer = compression.writer(writer)?;
+        match &self.domain_separation {
+            Some(tag) => {
+                writer.write_all(&[1])?;
+                writer.write_all(&(tag.len() as u32).to_le_bytes())?;
+                writer.write_all(tag)?;
+            }
+            None => writer.write_all(&[0])?,
+        }
+        writer.write_all(&(self.transcript.len() as u64).to_le_bytes())?;
+        writer.write_all(&self.transcript)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a zkwasm proof"));
+        }
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header)?;
+        let compression = match header[0] {
+            1 => Compression::None,
+            VERSION => {
+                let mut tag = [0u8; 1];
+                reader.read_exact(&mut tag)?;
+                Compression::from_tag(tag[0])?
+            }
+            _ => return Err(invalid("unsupported proof version")),
+        };
+        let use_gwc = header[1] != 0;
+        let mut reader = compression.reader(reader)?;
+
+        let mut flag = [0u8; 1];
+        reader.read_exact(&mut flag)?;
+        let domain_separation = match flag[0] {
+            0 => None,
+            1 => {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len)?;
+                let mut tag = vec![0u8; u32::from_le_bytes(len) as usize];
+                reader.read_exact(&mut tag)?;
+                Some(tag)
+            }
+            _ => return Err(invalid("invalid domain separation flag")),
+        };
+        let mut len = [0u8; 8];
+        reader.read_exact(&mut len)?;
+        let mut transcript = vec![];
+        (&mut reader)
+            .take(u64::from_le_bytes(len))
+            .read_to_end(&mut transcript)?;
+        if transcript.len() as u64 != u64::from_le_bytes(len) {
+            return Err(invalid("truncated proof"));
+        }
+
+        Ok(Proof {
+            use_gwc,
+            domain_separation,
+            transcript,
+        })
+    }
+}
@@ -0,0 +1,156 @@
+//! Python bindings, with the `python` feature, to enumerate devices, estimate
+//! memory and prove witnesses written by `write_witness` from scripts and
+//! notebooks. Reading a proving key needs the circuit, so the extension
+//! module of a circuit registers its reader with `cli::set_pk_reader` and adds
+//! the bindings with `add_to_module` in its `#[pymodule]`.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use halo2_proofs::pairing::bn256::G1Affine;
+use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::poly::commitment::Params;
+use halo2_proofs::transcript::Blake2bWrite;
+use halo2_proofs::transcript::Challenge255;
+use halo2_proofs::transcript::TranscriptWrite as _;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use pyo3::types::PyDict;
+use rand::rngs::OsRng;
+
+use crate::cli::read_proving_key;
+use crate::config::ProverConfig;
+use crate::create_proof_from_advices_with_config;
+use crate::device::cuda::CudaDevice;
+use crate::device::Device as _;
+use crate::estimate::estimate_device_memory;
+use crate::estimate::estimate_host_memory;
+use crate::witness::map_witness;
+
+fn runtime_error(e: impl ToString) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+/// Params and the proving key read with them.
+#[pyclass(name = "ProvingKey")]
+pub struct PyProvingKey {
+    params: Arc<Params<G1Affine>>,
+    pk: Arc<ProvingKey<G1Affine>>,
+}
+
+#[pymethods]
+impl PyProvingKey {
+    #[getter]
+    fn k(&self) -> u32 {
+        self.pk.get_vk().domain.k()
+    }
+}
+
+#[pyfunction]
+fn device_count() -> PyResult<usize> {
+    CudaDevice::get_device_count().map_err(runtime_error)
+}
+
+/// `(free, total)` bytes of device memory.
+#[pyfunction]
+fn device_memory(device_id: usize) -> PyResult<(usize, usize)> {
+    let device = CudaDevice::get_device(device_id).map_err(runtime_error)?;
+    device.get_memory_info().map_err(runtime_error)
+}
+
+#[pyfunction]
+fn load_proving_key(params: &str, pk: &str) -> PyResult<PyProvingKey> {
+    let (params, pk) = read_proving_key(Path::new(params), Path::new(pk)).map_err(runtime_error)?;
+    Ok(PyProvingKey {
+        params: Arc::new(params),
+        pk: Arc::new(pk),
+    })
+}
+
+/// Device and host memory a proof with `pk` needs, and what the device has.
+#[pyfunction]
+#[pyo3(signature = (pk, device_id = None))]
+fn estimate_memory<'py>(
+    py: Python<'py>,
+    pk: &PyProvingKey,
+    device_id: Option<usize>,
+) -> PyResult<&'py PyDict> {
+    let config = ProverConfig {
+        device_id,
+        ..Default::default()
+    };
+    let device = estimate_device_memory(&pk.pk, &config).map_err(runtime_error)?;
+    let host = estimate_host_memory(&pk.pk);
+
+    let res = PyDict::new(py);
+    res.set_item("device_peak", device.estimate.peak)?;
+    res.set_item("device_free", device.free_memory)?;
+    res.set_item("device_total", device.total_memory)?;
+    res.set_item("fits", device.fits())?;
+    res.set_item("host_pinned", host.pinned)?;
+    res.set_item("host_unpinned", host.unpinned)?;
+    res.set_item("huge_pages", host.huge_pages)?;
+    Ok(res)
+}
+
+/// Proves the witness file with a blake2b transcript, the GIL released.
+/// Returns the proof bytes and the phase times in seconds.
+#[pyfunction]
+#[pyo3(signature = (pk, witness, device_id = None, use_gwc = false))]
+fn prove<'py>(
+    py: Python<'py>,
+    pk: &PyProvingKey,
+    witness: &str,
+    device_id: Option<usize>,
+    use_gwc: bool,
+) -> PyResult<&'py PyDict> {
+    let (params, pk) = (pk.params.clone(), pk.pk.clone());
+    let (proof, metrics) = py
+        .allow_threads(|| -> Result<_, crate::Error> {
+            let witness = map_witness(witness, &pk)?;
+            let config = ProverConfig {
+                device_id,
+                ..Default::default()
+            };
+            let mut transcript = Blake2bWrite::<_, G1Affine, Challenge255<_>>::init(vec![]);
+            let metrics = create_proof_from_advices_with_config(
+                &params,
+                &pk,
+                &witness.instance_slices()[..],
+                witness.advices.clone(),
+                &mut transcript,
+                OsRng,
+                use_gwc,
+                &config,
+            )?;
+            Ok((transcript.finalize(), metrics))
+        })
+        .map_err(runtime_error)?;
+
+    let res = PyDict::new(py);
+    res.set_item("proof", PyBytes::new(py, &proof))?;
+    res.set_item("total_time", metrics.total_time.as_secs_f64())?;
+    res.set_item(
+        "phase_times",
+        metrics
+            .phase_times
+            .iter()
+            .map(|(name, time)| (*name, time.as_secs_f64()))
+            .collect::<Vec<_>>(),
+    )?;
+    res.set_item("peak_device_memory", metrics.peak_device_memory)?;
+    res.set_item("peak_host_memory", metrics.peak_host_memory)?;
+    Ok(res)
+}
+
+/// Adds the bindings to the module of a circuit's extension.
+pub fn add_to_module(m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyProvingKey>()?;
+    m.add_function(wrap_pyfunction!(device_count, m)?)?;
+    m.add_function(wrap_pyfunction!(device_memory, m)?)?;
+    m.add_function(wrap_pyfunction!(load_proving_key, m)?)?;
+    m.add_function(wrap_pyfunction!(estimate_memory, m)?)?;
+    m.add_function(wrap_pyfunction!(prove, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,586 @@
+//! Two-level scheduling of proofs over several GPUs and processes.
+//!
+//! A `Scheduler` runs proofs on per-device queues inside the process, each
+//! job going to the device with the fewest jobs waiting. A device runs up to
+//! `DeviceLimits::max_jobs` jobs at once, as long as their device memory
+//! estimates fit in `DeviceLimits::memory`. With a `ClusterCoordinator`, a job
+//! is only queued once its lease is acquired, so processes fed the same jobs
+//! split them without proving one twice. The lease is renewed when the job
+//! leaves the queue and while it runs; a job whose lease was taken over in the
+//! meantime is cancelled. A job proven successfully is marked done, so no
+//! process proves it again, a failed one is released for another to retry.
+//!
+//! `FileLeaseCoordinator` keeps leases in a directory shared by the processes
+//! of one host. Coordinators backed by etcd or redis, needed across hosts,
+//! implement the same trait outside of this crate.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::Read as _;
+use std::io::Write as _;
+use std::os::unix::io::AsRawFd;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::panic_error;
+use crate::task::CancelToken;
+use crate::Error;
+
+#[cfg(test)]
+mod test;
+
+/// Exclusive claim of `owner` on a job until `expires`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub job_id: String,
+    pub owner: String,
+    pub expires: SystemTime,
+}
+
+/// The cluster level of the scheduler, shared by every process proving the same jobs.
+pub trait ClusterCoordinator: Send + Sync {
+    /// The lease of `job_id` for `owner`, `None` if another owner holds an unexpired one.
+    fn try_acquire(&self, job_id: &str, owner: &str, ttl: Duration)
+        -> Result<Option<Lease>, Error>;
+
+    /// Extends `lease` by `ttl`, `false` if it expired and another owner took the job.
+    fn renew(&self, lease: &mut Lease, ttl: Duration) -> Result<bool, Error>;
+
+    /// Gives the job up, another owner may acquire it.
+    fn release(&self, lease: Lease) -> Result<(), Error>;
+
+    /// Marks the job done: no owner acquires it again. Coordinators without
+    /// completion records only release it.
+    fn complete(&self, lease: Lease) -> Result<(), Error> {
+        self.release(lease)
+    }
+}
+
+/// Leases as files of a directory: `<hash>.lease` holds the owner and the
+/// expiry of the lease of the job id hashing to `<hash>`, `<hash>.done` marks
+/// a completed job. Updates are serialized by an flock on `<dir>/.lock`.
+///
+/// flock only coordinates the processes of one host. On NFS it is emulated
+/// with byte-range locks or not shared at all depending on the client and
+/// server, so a directory on a network filesystem is not a coordinator for
+/// processes on several hosts. The done markers are kept until removed.
+pub struct FileLeaseCoordinator {
+    dir: PathBuf,
+}
+
+impl FileLeaseCoordinator {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileLeaseCoordinator { dir })
+    }
+
+    // distinct ids get distinct files whatever characters they contain
+    fn job_path(&self, job_id: &str, extension: &str) -> PathBuf {
+        let hash = blake2b_simd::Params::new()
+            .hash_length(16)
+            .hash(job_id.as_bytes());
+        self.dir.join(format!("{}.{}", hash.to_hex(), extension))
+    }
+
+    fn lease_path(&self, job_id: &str) -> PathBuf {
+        self.job_path(job_id, "lease")
+    }
+
+    fn done_path(&self, job_id: &str) -> PathBuf {
+        self.job_path(job_id, "done")
+    }
+
+    fn locked<T>(&self, f: impl FnOnce() -> io::Result<T>) -> Result<T, Error> {
+        let lock = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(self.dir.join(".lock"))?;
+        unsafe {
+            if libc::flock(lock.as_raw_fd(), libc::LOCK_EX) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        let res = f();
+        unsafe {
+            libc::flock(lock.as_raw_fd(), libc::LOCK_UN);
+        }
+        Ok(res?)
+    }
+
+    fn read_lease(&self, job_id: &str) -> io::Result<Option<Lease>> {
+        let mut content = String::new();
+        match fs::File::open(self.lease_path(job_id)) {
+            Ok(mut file) => file.read_to_string(&mut content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut lines = content.lines();
+        let owner = lines.next().unwrap_or_default().to_string();
+        let expires = lines
+            .next()
+            .and_then(|x| x.trim().parse::<u64>().ok())
+            .map_or(UNIX_EPOCH, |ms| UNIX_EPOCH + Duration::from_millis(ms));
+        Ok(Some(Lease {
+            job_id: job_id.to_string(),
+            owner,
+            expires,
+        }))
+    }
+
+    fn write_lease(&self, lease: &Lease) -> io::Result<()> {
+        let ms = lease
+            .expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self.lease_path(&lease.job_id);
+        let tmp = path.with_extension("lease.tmp");
+        let mut file = fs::File::create(&tmp)?;
+        write!(file, "{}\n{}\n", lease.owner, ms)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)
+    }
+}
+
+impl ClusterCoordinator for FileLeaseCoordinator {
+    fn try_acquire(
+        &self,
+        job_id: &str,
+        owner: &str,
+        ttl: Duration,
+    ) -> Result<Option<Lease>, Error> {
+        self.locked(|| {
+            let now = SystemTime::now();
+            if self.done_path(job_id).exists() {
+                return Ok(None);
+            }
+            match self.read_lease(job_id)? {
+                Some(lease) if lease.owner != owner && lease.expires > now => Ok(None),
+                _ => {
+                    let lease = Lease {
+                        job_id: job_id.to_string(),
+                        owner: owner.to_string(),
+                        expires: now + ttl,
+                    };
+                    self.write_lease(&lease)?;
+                    Ok(Some(lease))
+                }
+            }
+        })
+    }
+
+    fn renew(&self, lease: &mut Lease, ttl: Duration) -> Result<bool, Error> {
+        self.locked(|| {
+            let now = SystemTime::now();
+            if self.done_path(&lease.job_id).exists() {
+                return Ok(false);
+            }
+            match self.read_lease(&lease.job_id)? {
+                Some(current) if current.owner != lease.owner && current.expires > now => Ok(false),
+                _ => {
+                    lease.expires = now + ttl;
+                    self.write_lease(lease)?;
+                    Ok(true)
+                }
+            }
+        })
+    }
+
+    fn release(&self, lease: Lease) -> Result<(), Error> {
+        self.locked(|| match self.read_lease(&lease.job_id)? {
+            Some(current) if current.owner == lease.owner => {
+                fs::remove_file(self.lease_path(&lease.job_id))
+            }
+            _ => Ok(()),
+        })
+    }
+
+    fn complete(&self, lease: Lease) -> Result<(), Error> {
+        self.locked(|| {
+            fs::File::create(self.done_path(&lease.job_id))?.sync_all()?;
+            match self.read_lease(&lease.job_id)? {
+                Some(current) if current.owner == lease.owner => {
+                    fs::remove_file(self.lease_path(&lease.job_id))
+                }
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+type Job = Box<dyn FnOnce(usize) + Send>;
+
+/// Limits of the jobs running at once on one device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceLimits {
+    /// Jobs running at once, each on its own worker thread.
+    pub max_jobs: usize,
+    /// Bytes of device memory the running jobs share, see `Scheduler::submit_with_memory`.
+    pub memory: usize,
+}
+
+impl Default for DeviceLimits {
+    fn default() -> Self {
+        DeviceLimits {
+            max_jobs: 1,
+            memory: usize::MAX,
+        }
+    }
+}
+
+/// Where a job submitted to a `Scheduler` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Queued,
+    Running { device_id: usize },
+    Done,
+}
+
+struct QueuedJob {
+    job: Job,
+    memory: usize,
+}
+
+#[derive(Default)]
+struct QueueState {
+    waiting: VecDeque<QueuedJob>,
+    running: usize,
+    memory_in_use: usize,
+}
+
+struct DeviceQueue {
+    device_id: usize,
+    limits: DeviceLimits,
+    jobs: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+struct Shared {
+    queues: Vec<DeviceQueue>,
+    closed: Mutex<bool>,
+}
+
+/// Result of a job submitted to a `Scheduler`.
+pub struct JobHandle<R> {
+    receiver: Receiver<Result<R, Error>>,
+    status: Arc<Mutex<JobStatus>>,
+}
+
+impl<R> JobHandle<R> {
+    pub fn status(&self) -> JobStatus {
+        *self.status.lock().unwrap()
+    }
+
+    /// Blocks until the job is done.
+    pub fn wait(self) -> Result<R, Error> {
+        self.receiver.recv().unwrap_or_else(|_| Err(shut_down()))
+    }
+
+    /// The result of the job if it is done, handed out once.
+    pub fn try_wait(&self) -> Option<Result<R, Error>> {
+        match self.receiver.try_recv() {
+            Ok(res) => Some(res),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(shut_down())),
+        }
+    }
+}
+
+fn shut_down() -> Error {
+    Error::InvalidInput("scheduler shut down before the job ran".to_string())
+}
+
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    coordinator: Option<(Arc<dyn ClusterCoordinator>, String, Duration)>,
+}
+
+impl Scheduler {
+    /// One queue and worker thread per device of `device_ids`.
+    pub fn new(device_ids: &[usize]) -> Self {
+        let limits = vec![DeviceLimits::default(); device_ids.len()];
+        Self::with_limits(device_ids, &limits)
+    }
+
+    /// One queue per device of `device_ids`, with `limits[i].max_jobs` worker
+    /// threads for `device_ids[i]`. The memory limit can be taken from
+    /// `estimate::estimate_device_memory(..).budget`.
+    pub fn with_limits(device_ids: &[usize], limits: &[DeviceLimits]) -> Self {
+        assert_eq!(device_ids.len(), limits.len());
+        let shared = Arc::new(Shared {
+            queues: device_ids
+                .iter()
+                .zip(limits)
+                .map(|(&device_id, &limits)| DeviceQueue {
+                    device_id,
+                    limits,
+                    jobs: Mutex::new(QueueState::default()),
+                    ready: Condvar::new(),
+                })
+                .collect(),
+            closed: Mutex::new(false),
+        });
+        let workers = limits
+            .iter()
+            .enumerate()
+            .flat_map(|(i, limits)| (0..limits.max_jobs.max(1)).map(move |_| i))
+            .map(|i| {
+                let shared = shared.clone();
+                thread::spawn(move || run_queue(&shared, i))
+            })
+            .collect();
+
+        Scheduler {
+            shared,
+            workers,
+            coordinator: None,
+        }
+    }
+
+    /// Only runs the jobs whose lease `owner` acquires from `coordinator`,
+    /// `ttl` being how long a lease outlives a process that died holding it.
+    pub fn with_coordinator(
+        mut self,
+        coordinator: Arc<dyn ClusterCoordinator>,
+        owner: impl Into<String>,
+        ttl: Duration,
+    ) -> Self {
+        self.coordinator = Some((coordinator, owner.into(), ttl));
+        self
+    }
+
+    /// Jobs queued or running on each device, in the order of `new`.
+    pub fn queue_lengths(&self) -> Vec<usize> {
+        self.shared
+            .queues
+            .iter()
+            .map(|queue| queue.load())
+            .collect()
+    }
+
+    /// Device memory taken by the running jobs of each device, in the order of `new`.
+    pub fn memory_in_use(&self) -> Vec<usize> {
+        self.shared
+            .queues
+            .iter()
+            .map(|queue| queue.jobs.lock().unwrap().memory_in_use)
+            .collect()
+    }
+
+    /// Queues `job` on the least loaded device, it gets the device id to prove
+    /// on, e.g. as `ProverConfig::device_id`. Returns `None` when another
+    /// process of the cluster holds the lease of `job_id`.
+    pub fn submit<R, F>(&self, job_id: &str, job: F) -> Result<Option<JobHandle<R>>, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(usize) -> Result<R, Error> + Send + 'static,
+    {
+        self.submit_with_memory(job_id, 0, job)
+    }
+
+    /// Like `submit`, for a job taking `memory` bytes of device memory, e.g.
+    /// `estimate::estimate_device_memory(..).estimate.peak`. It only goes to
+    /// devices whose memory limit covers it, and waits there until the jobs
+    /// running on the device leave enough of it.
+    pub fn submit_with_memory<R, F>(
+        &self,
+        job_id: &str,
+        memory: usize,
+        job: F,
+    ) -> Result<Option<JobHandle<R>>, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(usize) -> Result<R, Error> + Send + 'static,
+    {
+        self.submit_cancellable(job_id, memory, move |device_id, _| job(device_id))
+    }
+
+    /// Like `submit_with_memory`, the job also gets a token cancelled when its
+    /// lease is lost while it runs, to pass as `ProverConfig::cancel`. Without
+    /// it the job runs to the end, and its result is dropped for
+    /// `Error::Cancelled`.
+    pub fn submit_cancellable<R, F>(
+        &self,
+        job_id: &str,
+        memory: usize,
+        job: F,
+    ) -> Result<Option<JobHandle<R>>, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(usize, CancelToken) -> Result<R, Error> + Send + 'static,
+    {
+        if self.shared.queues.is_empty() {
+            return Err(Error::InvalidInput("scheduler has no devices".to_string()));
+        }
+        let queue = self
+            .shared
+            .queues
+            .iter()
+            .filter(|queue| queue.limits.memory >= memory)
+            .min_by_key(|queue| queue.load())
+            .ok_or_else(|| {
+                Error::InvalidInput(format!(
+                    "job needs {} bytes of device memory, more than any device allows",
+                    memory
+                ))
+            })?;
+
+        let lease = match &self.coordinator {
+            Some((coordinator, owner, ttl)) => {
+                match coordinator.try_acquire(job_id, owner, *ttl)? {
+                    Some(lease) => Some((coordinator.clone(), lease, *ttl)),
+                    None => return Ok(None),
+                }
+            }
+            None => None,
+        };
+
+        let (sender, receiver) = channel();
+        let status = Arc::new(Mutex::new(JobStatus::Queued));
+        let job_status = status.clone();
+        let job: Job = Box::new(move |device_id| {
+            *job_status.lock().unwrap() = JobStatus::Running { device_id };
+            let run = |cancel| {
+                panic::catch_unwind(AssertUnwindSafe(|| job(device_id, cancel))).unwrap_or_else(
+                    |e| Err(panic_error(&format!("job on device {}", device_id), e)),
+                )
+            };
+            let res = match lease {
+                Some((coordinator, lease, ttl)) => with_lease(coordinator, lease, ttl, run),
+                None => run(CancelToken::new()),
+            };
+            *job_status.lock().unwrap() = JobStatus::Done;
+            let _ = sender.send(res);
+        });
+
+        queue
+            .jobs
+            .lock()
+            .unwrap()
+            .waiting
+            .push_back(QueuedJob { job, memory });
+        queue.ready.notify_one();
+
+        Ok(Some(JobHandle { receiver, status }))
+    }
+}
+
+impl DeviceQueue {
+    fn load(&self) -> usize {
+        let state = self.jobs.lock().unwrap();
+        state.waiting.len() + state.running
+    }
+}
+
+impl Drop for Scheduler {
+    /// Runs the jobs already queued, then stops the workers.
+    fn drop(&mut self) {
+        *self.shared.closed.lock().unwrap() = true;
+        for queue in self.shared.queues.iter() {
+            let _guard = queue.jobs.lock().unwrap();
+            queue.ready.notify_all();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run_queue(shared: &Shared, idx: usize) {
+    let queue = &shared.queues[idx];
+    loop {
+        let QueuedJob { job, memory } = {
+            let mut jobs = queue.jobs.lock().unwrap();
+            loop {
+                // jobs start in order, the next one waits for memory even if later ones fit
+                let fits = jobs.waiting.front().map_or(false, |job| {
+                    jobs.running < queue.limits.max_jobs.max(1)
+                        && jobs.memory_in_use + job.memory <= queue.limits.memory
+                });
+                if fits {
+                    let job = jobs.waiting.pop_front().unwrap();
+                    jobs.running += 1;
+                    jobs.memory_in_use += job.memory;
+                    break job;
+                }
+                if jobs.waiting.is_empty() && *shared.closed.lock().unwrap() {
+                    return;
+                }
+                jobs = queue.ready.wait(jobs).unwrap();
+            }
+        };
+        job(queue.device_id);
+        let mut jobs = queue.jobs.lock().unwrap();
+        jobs.running -= 1;
+        jobs.memory_in_use -= memory;
+        // the other workers of the device may start the next job now
+        queue.ready.notify_all();
+    }
+}
+
+// Renews the lease as the job leaves the queue, where it may have waited past
+// the ttl, then every third of the ttl while `f` runs. A lost lease cancels the
+// job. The lease is completed if `f` succeeds and released otherwise.
+fn with_lease<R>(
+    coordinator: Arc<dyn ClusterCoordinator>,
+    mut lease: Lease,
+    ttl: Duration,
+    f: impl FnOnce(CancelToken) -> Result<R, Error>,
+) -> Result<R, Error> {
+    if !coordinator.renew(&mut lease, ttl)? {
+        tracing::warn!(job = %lease.job_id, "lease lost to another owner while queued");
+        return Err(Error::Cancelled);
+    }
+
+    let cancel = CancelToken::new();
+    let (done, stop) = channel::<()>();
+    let coordinator = &coordinator;
+    let lost = cancel.clone();
+    let (res, lease) = thread::scope(|s| {
+        let renewer = s.spawn(move || {
+            while let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(ttl / 3) {
+                match coordinator.renew(&mut lease, ttl) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        tracing::warn!(job = %lease.job_id, "lease lost to another owner");
+                        lost.cancel();
+                        break;
+                    }
+                    Err(e) => tracing::warn!(job = %lease.job_id, "fail to renew lease: {}", e),
+                }
+            }
+            lease
+        });
+        let res = f(cancel.clone());
+        drop(done);
+        (res, renewer.join().unwrap())
+    });
+
+    // the job belongs to the new owner, which may be proving it too
+    if cancel.is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+    let finished = match &res {
+        Ok(_) => coordinator.complete(lease),
+        Err(_) => coordinator.release(lease),
+    };
+    if let Err(e) = finished {
+        tracing::warn!("fail to release lease: {}", e);
+    }
+    res
+}
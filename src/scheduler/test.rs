@@ -0,0 +1,174 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::ClusterCoordinator;
+use super::DeviceLimits;
+use super::FileLeaseCoordinator;
+use super::JobStatus;
+use super::Scheduler;
+use crate::Error;
+
+fn lease_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("zkwasm-prover-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_file_lease() {
+    let coordinator = FileLeaseCoordinator::new(lease_dir("lease")).unwrap();
+    let ttl = Duration::from_secs(60);
+
+    let lease = coordinator.try_acquire("job/1", "a", ttl).unwrap().unwrap();
+    assert!(coordinator
+        .try_acquire("job/1", "b", ttl)
+        .unwrap()
+        .is_none());
+    // the owner can take its own lease again
+    assert!(coordinator
+        .try_acquire("job/1", "a", ttl)
+        .unwrap()
+        .is_some());
+    coordinator.release(lease).unwrap();
+    let mut lease = coordinator
+        .try_acquire("job/1", "b", Duration::ZERO)
+        .unwrap()
+        .unwrap();
+
+    // expired leases are taken over, the previous owner can't renew them
+    assert!(coordinator
+        .try_acquire("job/1", "a", ttl)
+        .unwrap()
+        .is_some());
+    assert!(!coordinator.renew(&mut lease, ttl).unwrap());
+}
+
+#[test]
+fn test_file_lease_done() {
+    let coordinator = FileLeaseCoordinator::new(lease_dir("lease-done")).unwrap();
+    let ttl = Duration::from_secs(60);
+
+    // ids differing only in characters a file name can't hold are distinct jobs
+    let lease = coordinator.try_acquire("a/b", "a", ttl).unwrap().unwrap();
+    assert!(coordinator.try_acquire("a_b", "b", ttl).unwrap().is_some());
+
+    // a completed job is not proven again, even by its owner
+    let mut stale = lease.clone();
+    coordinator.complete(lease).unwrap();
+    assert!(coordinator.try_acquire("a/b", "a", ttl).unwrap().is_none());
+    assert!(coordinator.try_acquire("a/b", "b", ttl).unwrap().is_none());
+    assert!(!coordinator.renew(&mut stale, ttl).unwrap());
+}
+
+#[test]
+fn test_scheduler_lease_lost_while_queued() {
+    let coordinator = Arc::new(FileLeaseCoordinator::new(lease_dir("queued")).unwrap());
+    let scheduler =
+        Scheduler::new(&[0]).with_coordinator(coordinator.clone(), "a", Duration::from_millis(50));
+
+    let first = scheduler
+        .submit("first", |_| {
+            std::thread::sleep(Duration::from_millis(300));
+            Ok(false)
+        })
+        .unwrap()
+        .unwrap();
+    let second = scheduler.submit("second", |_| Ok(true)).unwrap().unwrap();
+
+    // the lease of the queued job expires and another process takes it
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(coordinator
+        .try_acquire("second", "b", Duration::from_secs(60))
+        .unwrap()
+        .is_some());
+
+    assert!(!first.wait().unwrap());
+    assert!(matches!(second.wait(), Err(Error::Cancelled)));
+}
+
+#[test]
+fn test_scheduler_splits_jobs() {
+    let dir = lease_dir("scheduler");
+    let coordinator = Arc::new(FileLeaseCoordinator::new(&dir).unwrap());
+    let ttl = Duration::from_secs(60);
+    let schedulers = ["a", "b"]
+        .map(|owner| Scheduler::new(&[0, 1]).with_coordinator(coordinator.clone(), owner, ttl));
+
+    // the jobs outlast the submissions, so the other scheduler finds every lease held
+    let mut handles = vec![];
+    for job in 0..4 {
+        for scheduler in schedulers.iter() {
+            let handle = scheduler
+                .submit(&format!("job-{}", job), |device_id| {
+                    std::thread::sleep(Duration::from_millis(100));
+                    Ok(device_id)
+                })
+                .unwrap();
+            handles.extend(handle);
+        }
+    }
+
+    assert_eq!(handles.len(), 4);
+    for handle in handles {
+        assert!(handle.wait().unwrap() < 2);
+    }
+}
+
+#[test]
+fn test_scheduler_memory_limit() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let limits = DeviceLimits {
+        max_jobs: 2,
+        memory: 10,
+    };
+    let scheduler = Scheduler::with_limits(&[0], &[limits]);
+    assert!(scheduler.submit_with_memory("big", 11, |_| Ok(())).is_err());
+
+    // two jobs of 6 bytes don't fit together, two of 4 do
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let mut handles = vec![];
+    for (job, memory) in [6, 6, 4, 4].into_iter().enumerate() {
+        let running = running.clone();
+        let peak = peak.clone();
+        let handle = scheduler
+            .submit_with_memory(&format!("job-{}", job), memory, move |_| {
+                peak.fetch_max(
+                    running.fetch_add(memory, Ordering::SeqCst) + memory,
+                    Ordering::SeqCst,
+                );
+                std::thread::sleep(Duration::from_millis(50));
+                running.fetch_sub(memory, Ordering::SeqCst);
+                Ok(())
+            })
+            .unwrap()
+            .unwrap();
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.wait().unwrap();
+    }
+    assert!(peak.load(Ordering::SeqCst) <= 10);
+    assert_eq!(scheduler.memory_in_use(), vec![0]);
+}
+
+#[test]
+fn test_job_status() {
+    let scheduler = Scheduler::new(&[3]);
+    let (start, started) = std::sync::mpsc::channel();
+    let (finish, finished) = std::sync::mpsc::channel::<()>();
+    let handle = scheduler
+        .submit("job", move |device_id| {
+            start.send(()).unwrap();
+            finished.recv().unwrap();
+            Ok(device_id)
+        })
+        .unwrap()
+        .unwrap();
+    started.recv().unwrap();
+    assert_eq!(handle.status(), JobStatus::Running { device_id: 3 });
+    assert!(handle.try_wait().is_none());
+    finish.send(()).unwrap();
+    assert_eq!(handle.wait().unwrap(), 3);
+}
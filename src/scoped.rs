@@ -0,0 +1,102 @@
+//! Process-wide switches and limits that a proof sets for its own duration.
+//! The value set through the public setter is the base, the proofs running
+//! with the setting in their `ProverConfig` hold a guard on top of it. Nothing
+//! a proof sets outlives it, and concurrent proofs with different configs
+//! don't overwrite each other: a flag is on while any of them wants it, a
+//! limit is the strictest of theirs.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+pub(crate) struct ScopedFlag {
+    base: AtomicBool,
+    proofs: AtomicUsize,
+}
+
+impl ScopedFlag {
+    pub(crate) const fn new(base: bool) -> Self {
+        Self {
+            base: AtomicBool::new(base),
+            proofs: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn set(&self, enabled: bool) {
+        self.base.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The value set with `set`, whatever the proofs hold.
+    pub(crate) fn base(&self) -> bool {
+        self.base.load(Ordering::Relaxed)
+    }
+
+    /// On if the base is, or while any proof holds a guard.
+    pub(crate) fn get(&self) -> bool {
+        self.base.load(Ordering::Relaxed) || self.proofs.load(Ordering::Relaxed) > 0
+    }
+
+    /// Turns the flag on until the guard is dropped.
+    pub(crate) fn enter(&'static self) -> FlagGuard {
+        self.proofs.fetch_add(1, Ordering::Relaxed);
+        FlagGuard(self)
+    }
+}
+
+pub(crate) struct FlagGuard(&'static ScopedFlag);
+
+impl Drop for FlagGuard {
+    fn drop(&mut self) {
+        self.0.proofs.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct ScopedLimit {
+    // 0 is unbounded
+    base: AtomicUsize,
+    proofs: Mutex<Vec<usize>>,
+}
+
+impl ScopedLimit {
+    pub(crate) const fn new() -> Self {
+        Self {
+            base: AtomicUsize::new(0),
+            proofs: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn set(&self, limit: Option<usize>) {
+        self.base.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// The lowest of the base and the limits of the running proofs.
+    pub(crate) fn get(&self) -> Option<usize> {
+        let base = match self.base.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
+        };
+        let proofs = self.proofs.lock().unwrap().iter().copied().min();
+        match (base, proofs) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Applies `limit` until the guard is dropped.
+    pub(crate) fn enter(&'static self, limit: usize) -> LimitGuard {
+        self.proofs.lock().unwrap().push(limit);
+        LimitGuard(self, limit)
+    }
+}
+
+pub(crate) struct LimitGuard(&'static ScopedLimit, usize);
+
+impl Drop for LimitGuard {
+    fn drop(&mut self) {
+        let mut proofs = self.0.proofs.lock().unwrap();
+        if let Some(i) = proofs.iter().position(|x| *x == self.1) {
+            proofs.swap_remove(i);
+        }
+    }
+}
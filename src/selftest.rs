@@ -0,0 +1,541 @@
+use std::fmt;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use halo2_proofs::arithmetic::BaseExt as _;
+use halo2_proofs::arithmetic::CurveAffine as _;
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::circuit::Layouter;
+use halo2_proofs::circuit::SimpleFloorPlanner;
+use halo2_proofs::pairing::bn256::Bn256;
+use halo2_proofs::pairing::bn256::Fr;
+use halo2_proofs::pairing::bn256::G1Affine;
+use halo2_proofs::pairing::group::ff::PrimeField as _;
+use halo2_proofs::pairing::group::Curve as _;
+use halo2_proofs::plonk::generate_advice_from_synthesize;
+use halo2_proofs::plonk::keygen_pk;
+use halo2_proofs::plonk::keygen_vk;
+use halo2_proofs::plonk::verify_proof;
+use halo2_proofs::plonk::Advice;
+use halo2_proofs::plonk::Circuit;
+use halo2_proofs::plonk::Column;
+use halo2_proofs::plonk::ConstraintSystem;
+use halo2_proofs::plonk::Fixed;
+use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::plonk::SingleVerifier;
+use halo2_proofs::plonk::TableColumn;
+use halo2_proofs::poly::commitment::Params;
+use halo2_proofs::poly::commitment::ParamsVerifier;
+use halo2_proofs::poly::Rotation;
+use halo2_proofs::transcript::Blake2bRead;
+use halo2_proofs::transcript::Blake2bWrite;
+use halo2_proofs::transcript::Challenge255;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::config::ProverConfig;
+use crate::cuda::bn254::batch_msm;
+use crate::cuda::bn254::intt_raw;
+use crate::cuda::bn254::ntt_prepare;
+use crate::cuda::bn254::ntt_raw;
+use crate::cuda::bn254_c;
+use crate::device::cuda::kernel_archs;
+use crate::device::cuda::to_result;
+use crate::device::cuda::CudaBuffer as _;
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::DeviceCapabilities;
+use crate::device::Device as _;
+use crate::hugetlb::HugePageAllocator;
+use crate::metrics::ProofMetrics;
+use crate::prepare_advice_buffer;
+use crate::Error;
+
+const NTT_K: usize = 16;
+const MSM_K: usize = 16;
+const PROOF_K: u32 = 12;
+const BANDWIDTH_BYTES: usize = 256 << 20;
+// a healthy PCIe 3.0 x16 link moves 10GB/s, below this the link is degraded
+const MIN_BANDWIDTH: f64 = 2.0;
+
+/// Outcome of one check of `selftest`.
+#[derive(Debug, Clone)]
+pub struct SelfTestCheck {
+    pub name: &'static str,
+    pub passed: bool,
+    /// Measurement of a passed check, reason of a failed one.
+    pub detail: String,
+    pub time: Duration,
+}
+
+/// Pass/fail report of `selftest`.
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub device_id: usize,
+    /// `None` if the device couldn't be probed.
+    pub capabilities: Option<DeviceCapabilities>,
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "selftest of cuda device {}", self.device_id)?;
+        if let Some(capabilities) = &self.capabilities {
+            writeln!(
+                f,
+                "  sm_{} (kernels for {:?}), driver {}, runtime {}, memory pools {}, \
+                 cooperative launch {}, managed memory {}, peers {:?}",
+                capabilities.compute_capability,
+                kernel_archs(),
+                capabilities.driver_version,
+                capabilities.runtime_version,
+                capabilities.memory_pools,
+                capabilities.cooperative_launch,
+                capabilities.managed_memory,
+                capabilities.peers
+            )?;
+        }
+        for check in self.checks.iter() {
+            writeln!(
+                f,
+                "  [{}] {:<10} {:>8.2?}  {}",
+                if check.passed { "PASS" } else { "FAIL" },
+                check.name,
+                check.time,
+                check.detail
+            )?;
+        }
+        write!(f, "{}", if self.passed() { "PASS" } else { "FAIL" })
+    }
+}
+
+/// Qualifies the CUDA device `device_id` before it proves in production:
+/// - field kernels of both limb backends against the CPU,
+/// - NTT and inverse NTT against the CPU,
+/// - MSM against a result known from the discrete logs of the bases,
+/// - host to device and device to host bandwidth,
+/// - a small proof, verified on the CPU.
+///
+/// Every check runs even if an earlier one failed.
+pub fn selftest(device_id: usize) -> SelfTestReport {
+    let checks: [(
+        &'static str,
+        fn(&CudaDevice, usize) -> Result<String, Error>,
+    ); 5] = [
+        ("field", check_field),
+        ("ntt", check_ntt),
+        ("msm", check_msm),
+        ("bandwidth", check_bandwidth),
+        ("proof", check_proof),
+    ];
+
+    let device = CudaDevice::get_device(device_id).map_err(|e| e.to_string());
+    let capabilities = device.as_ref().ok().and_then(|x| x.capabilities().ok());
+    let checks = checks
+        .into_iter()
+        .map(|(name, check)| {
+            let start = Instant::now();
+            let res = match &device {
+                Ok(device) => catch_unwind(AssertUnwindSafe(|| check(device, device_id)))
+                    .unwrap_or_else(|e| {
+                        let msg = e
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| e.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        Err(Error::KernelError(format!("panicked: {}", msg)))
+                    }),
+                Err(msg) => Err(Error::DeviceError(crate::device::Error::DeviceError(
+                    msg.clone(),
+                ))),
+            };
+            let (passed, detail) = match res {
+                Ok(detail) => (true, detail),
+                Err(e) => (false, e.to_string()),
+            };
+            SelfTestCheck {
+                name,
+                passed,
+                detail,
+                time: start.elapsed(),
+            }
+        })
+        .collect();
+
+    SelfTestReport {
+        device_id,
+        capabilities,
+        checks,
+    }
+}
+
+fn invalid(e: impl fmt::Debug) -> Error {
+    Error::InvalidInput(format!("{:?}", e))
+}
+
+fn mismatch(what: &str) -> Error {
+    Error::KernelError(format!("{} differs from the cpu", what))
+}
+
+fn check_field(device: &CudaDevice, _: usize) -> Result<String, Error> {
+    const OUTPUTS: usize = 7;
+
+    let size = 1 << 12;
+    let a = (0..size).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let b = (0..size).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let a_buf = device.alloc_device_buffer_from_slice(&a[..])?;
+    let b_buf = device.alloc_device_buffer_from_slice(&b[..])?;
+
+    for (limbs, f) in [
+        (
+            32,
+            bn254_c::field_ops_limb32 as unsafe extern "C" fn(_, _, _, _, _) -> _,
+        ),
+        (64, bn254_c::field_ops_limb64),
+    ] {
+        let out_buf = device.alloc_device_buffer::<Fr>(size * OUTPUTS)?;
+        unsafe {
+            let err = f(
+                a_buf.ptr(),
+                b_buf.ptr(),
+                out_buf.ptr(),
+                size as i32,
+                0usize as _,
+            );
+            to_result((), err, "fail to run field_ops")?;
+        }
+        let mut out = vec![Fr::zero(); size * OUTPUTS];
+        device.copy_from_device_to_host(&mut out[..], &out_buf)?;
+
+        for (i, (a, b)) in a.iter().zip(b.iter()).enumerate() {
+            let res = &out[i * OUTPUTS..(i + 1) * OUTPUTS];
+            let unmont =
+                unsafe { std::slice::from_raw_parts(&res[6] as *const Fr as *const u8, 32) };
+            if res[0] != *a + b
+                || res[1] != *a - b
+                || res[2] != *a * b
+                || res[3] != a.square()
+                || res[4] != -*a
+                || res[5] != a.invert().unwrap()
+                || unmont != a.to_repr().as_ref()
+            {
+                return Err(mismatch(&format!("{} bit limb field arithmetic", limbs)));
+            }
+        }
+    }
+
+    Ok(format!("{} elements, 32 and 64 bit limbs", size))
+}
+
+fn check_ntt(device: &CudaDevice, _: usize) -> Result<String, Error> {
+    let len = 1 << NTT_K;
+    let mut omega = Fr::ROOT_OF_UNITY;
+    for _ in NTT_K..Fr::S as usize {
+        omega = omega.square();
+    }
+    let omega_inv = omega.invert().unwrap();
+
+    let (omegas_buf, pq_buf) = ntt_prepare(device, omega, NTT_K)?;
+    let (intt_omegas_buf, intt_pq_buf) = ntt_prepare(device, omega_inv, NTT_K)?;
+    let divisor_buf =
+        device.alloc_device_buffer_from_slice(&[Fr::from(len as u64).invert().unwrap()][..])?;
+
+    let values = (0..len).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let mut expected = values.clone();
+    halo2_proofs::arithmetic::best_fft_cpu(&mut expected[..], omega, NTT_K as u32);
+
+    let mut buf = device.alloc_device_buffer_from_slice(&values[..])?;
+    let mut tmp_buf = device.alloc_device_buffer::<Fr>(len)?;
+    let mut res = vec![Fr::zero(); len];
+
+    ntt_raw(
+        device,
+        &mut buf,
+        &mut tmp_buf,
+        &pq_buf,
+        &omegas_buf,
+        NTT_K,
+        None,
+    )?;
+    device.copy_from_device_to_host(&mut res[..], &buf)?;
+    if res != expected {
+        return Err(mismatch("ntt"));
+    }
+
+    intt_raw(
+        device,
+        &mut buf,
+        &mut tmp_buf,
+        &intt_pq_buf,
+        &intt_omegas_buf,
+        &divisor_buf,
+        NTT_K,
+    )?;
+    device.copy_from_device_to_host(&mut res[..], &buf)?;
+    if res != values {
+        return Err(mismatch("intt"));
+    }
+
+    Ok(format!("k = {}", NTT_K))
+}
+
+fn check_msm(device: &CudaDevice, _: usize) -> Result<String, Error> {
+    // bases are multiples of the generator by known scalars, so the expected
+    // msm is a single scalar multiplication instead of a cpu msm
+    const DISTINCT_BASES: usize = 256;
+
+    let len = 1 << MSM_K;
+    let logs = (0..DISTINCT_BASES).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let bases = logs
+        .iter()
+        .map(|x| (G1Affine::generator() * x).to_affine())
+        .collect::<Vec<_>>();
+    let points = (0..len)
+        .map(|i| bases[i % DISTINCT_BASES])
+        .collect::<Vec<_>>();
+    let scalars = (0..len).map(|_| Fr::rand()).collect::<Vec<_>>();
+    let expected = scalars.iter().enumerate().fold(Fr::zero(), |acc, (i, s)| {
+        acc + *s * logs[i % DISTINCT_BASES]
+    });
+    let expected = (G1Affine::generator() * expected).to_affine();
+
+    let p_buf = device.alloc_device_buffer_from_slice(&points[..])?;
+    let s_bufs = [
+        device.alloc_device_buffer::<Fr>(len)?,
+        device.alloc_device_buffer::<Fr>(len)?,
+    ];
+    let res = batch_msm::<G1Affine>(&p_buf, [&s_bufs[0], &s_bufs[1]], vec![&scalars[..]], len)?;
+    if res[0] != expected {
+        return Err(mismatch("msm"));
+    }
+
+    Ok(format!("k = {}", MSM_K))
+}
+
+fn check_bandwidth(device: &CudaDevice, _: usize) -> Result<String, Error> {
+    let len = BANDWIDTH_BYTES / std::mem::size_of::<Fr>();
+    let mut host = Vec::new_in(HugePageAllocator);
+    host.resize(len, Fr::one());
+    let buf = device.alloc_device_buffer::<Fr>(len)?;
+
+    let gbps = |time: Duration| BANDWIDTH_BYTES as f64 / time.as_secs_f64() / 1e9;
+
+    let start = Instant::now();
+    device.copy_from_host_to_device(&buf, &host[..])?;
+    device.synchronize()?;
+    let h2d = gbps(start.elapsed());
+
+    let start = Instant::now();
+    device.copy_from_device_to_host(&mut host[..], &buf)?;
+    device.synchronize()?;
+    let d2h = gbps(start.elapsed());
+
+    let detail = format!("h2d {:.1} GB/s, d2h {:.1} GB/s", h2d, d2h);
+    if h2d < MIN_BANDWIDTH || d2h < MIN_BANDWIDTH {
+        return Err(Error::DeviceError(crate::device::Error::DeviceError(
+            format!("{}, below {} GB/s", detail, MIN_BANDWIDTH),
+        )));
+    }
+    Ok(detail)
+}
+
+#[derive(Clone)]
+struct SelfTestConfig {
+    q: Column<Fixed>,
+    a: Column<Advice>,
+    b: Column<Advice>,
+    c: Column<Advice>,
+    d: Column<Advice>,
+    range: TableColumn,
+}
+
+/// `b = a * a + a` on every row, with every argument of the prover:
+/// - `c` is a copy of `a` through the permutation,
+/// - `a` is looked up in the table of its range,
+/// - `d` holds `a` in reverse order, a shuffle of it.
+#[derive(Clone, Default)]
+struct SelfTestCircuit;
+
+impl Circuit<Fr> for SelfTestCircuit {
+    type Config = SelfTestConfig;
+    type FloorPlanner = SimpleFloorPlanner;
+
+    fn without_witnesses(&self) -> Self {
+        Self
+    }
+
+    fn configure(meta: &mut ConstraintSystem<Fr>) -> Self::Config {
+        let q = meta.fixed_column();
+        let a = meta.advice_column();
+        let b = meta.advice_column();
+        let c = meta.advice_column();
+        let d = meta.advice_column();
+        let range = meta.lookup_table_column();
+        meta.enable_equality(a);
+        meta.enable_equality(c);
+
+        meta.create_gate("square", |meta| {
+            let q = meta.query_fixed(q, Rotation::cur());
+            let a = meta.query_advice(a, Rotation::cur());
+            let b = meta.query_advice(b, Rotation::cur());
+            vec![q * (a.clone() * a.clone() + a - b)]
+        });
+
+        meta.lookup("range", |meta| {
+            let q = meta.query_fixed(q, Rotation::cur());
+            let a = meta.query_advice(a, Rotation::cur());
+            vec![(q * a, range)]
+        });
+
+        meta.shuffle("reverse", |meta| {
+            let q = meta.query_fixed(q, Rotation::cur());
+            let a = meta.query_advice(a, Rotation::cur());
+            let d = meta.query_advice(d, Rotation::cur());
+            vec![(q.clone() * a, q * d)]
+        });
+
+        SelfTestConfig {
+            q,
+            a,
+            b,
+            c,
+            d,
+            range,
+        }
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<Fr>,
+    ) -> Result<(), halo2_proofs::plonk::Error> {
+        let rows = 1 << (PROOF_K - 1);
+        layouter.assign_table(
+            || "range",
+            |mut table| {
+                for i in 0..rows {
+                    table.assign_cell(|| "range", config.range, i, || Ok(Fr::from(i as u64)))?;
+                }
+                Ok(())
+            },
+        )?;
+        layouter.assign_region(
+            || "square",
+            |mut region| {
+                for i in 0..rows {
+                    let a = Fr::from(i as u64);
+                    region.assign_fixed(|| "q", config.q, i, || Ok(Fr::one()))?;
+                    let a_cell = region.assign_advice(|| "a", config.a, i, || Ok(a))?;
+                    region.assign_advice(|| "b", config.b, i, || Ok(a * a + a))?;
+                    let c_cell = region.assign_advice(|| "c", config.c, i, || Ok(a))?;
+                    region.constrain_equal(a_cell.cell(), c_cell.cell())?;
+                    let reversed = Fr::from((rows - 1 - i) as u64);
+                    region.assign_advice(|| "d", config.d, i, || Ok(reversed))?;
+                }
+                Ok(())
+            },
+        )
+    }
+}
+
+fn check_proof(_: &CudaDevice, device_id: usize) -> Result<String, Error> {
+    prove_and_verify(&ProverConfig {
+        device_id: Some(device_id),
+        ..Default::default()
+    })
+}
+
+// The parameters, proving key and advices of the self-test circuit.
+pub(crate) fn setup() -> Result<
+    (
+        Params<G1Affine>,
+        ProvingKey<G1Affine>,
+        Arc<Vec<Vec<Fr, HugePageAllocator>>>,
+    ),
+    Error,
+> {
+    let params = Params::<G1Affine>::unsafe_setup::<Bn256>(PROOF_K);
+    let circuit = SelfTestCircuit;
+    let vk = keygen_vk(&params, &circuit).map_err(invalid)?;
+    let pk = keygen_pk(&params, vk, &circuit).map_err(invalid)?;
+
+    let mut advices = Arc::new(prepare_advice_buffer(&pk, false));
+    generate_advice_from_synthesize(
+        &params,
+        &pk,
+        &circuit,
+        &[],
+        &unsafe { Arc::get_mut_unchecked(&mut advices) }
+            .iter_mut()
+            .map(|x| (&mut x[..]) as *mut [_])
+            .collect::<Vec<_>>()[..],
+    );
+    Ok((params, pk, advices))
+}
+
+/// Proves the self-test circuit with `config` and verifies the proof.
+pub fn prove_and_verify(config: &ProverConfig) -> Result<String, Error> {
+    prove_and_verify_with_rng(config, OsRng)
+}
+
+// A proof replaying recorded commitments needs the blinders of the first attempt.
+pub(crate) fn prove_and_verify_with_rng(
+    config: &ProverConfig,
+    rng: impl RngCore + Send,
+) -> Result<String, Error> {
+    let (params, pk, advices) = setup()?;
+    let (proof, _) = prove_and_verify_advices(&params, &pk, advices, config, rng)?;
+    Ok(format!("k = {}, {} bytes", PROOF_K, proof.len()))
+}
+
+// The metrics of a verified proof of the self-test circuit, to check which
+// paths of `config` it took.
+pub(crate) fn prove_and_verify_with_metrics(config: &ProverConfig) -> Result<ProofMetrics, Error> {
+    let (params, pk, advices) = setup()?;
+    let (_, metrics) = prove_and_verify_advices(&params, &pk, advices, config, OsRng)?;
+    Ok(metrics)
+}
+
+// Proves and verifies advices of the self-test circuit other than those of
+// `setup`, returns the proof and its metrics.
+pub(crate) fn prove_and_verify_advices(
+    params: &Params<G1Affine>,
+    pk: &ProvingKey<G1Affine>,
+    advices: Arc<Vec<Vec<Fr, HugePageAllocator>>>,
+    config: &ProverConfig,
+    rng: impl RngCore + Send,
+) -> Result<(Vec<u8>, ProofMetrics), Error> {
+    let mut transcript = Blake2bWrite::<_, G1Affine, Challenge255<_>>::init(vec![]);
+    let metrics = crate::create_proof_from_advices_with_config(
+        params,
+        pk,
+        &[],
+        advices,
+        &mut transcript,
+        rng,
+        true,
+        config,
+    )?;
+    let proof = transcript.finalize();
+
+    let params_verifier: ParamsVerifier<Bn256> = params.verifier(0).map_err(invalid)?;
+    let strategy = SingleVerifier::new(&params_verifier);
+    verify_proof(
+        &params_verifier,
+        pk.get_vk(),
+        strategy,
+        &[&[]],
+        &mut Blake2bRead::<_, G1Affine, Challenge255<_>>::init(&proof[..]),
+    )
+    .map_err(|e| Error::InvalidInput(format!("proof doesn't verify: {:?}", e)))?;
+
+    Ok((proof, metrics))
+}
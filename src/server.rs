@@ -0,0 +1,197 @@
+//! gRPC proving daemon, with the `server` feature: `SubmitProof`, `GetStatus`
+//! and `GetProof` of `proto/prover.proto` over a `Scheduler`, for one circuit.
+//! Witnesses come in the `write_witness` format, proofs go out as blake2b
+//! transcripts. Results are kept until the daemon stops. Building it needs
+//! `protoc`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use halo2_proofs::pairing::bn256::G1Affine;
+use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::poly::commitment::Params;
+use halo2_proofs::transcript::Blake2bWrite;
+use halo2_proofs::transcript::Challenge255;
+use halo2_proofs::transcript::TranscriptWrite as _;
+use rand::rngs::OsRng;
+use tonic::Request;
+use tonic::Response;
+use tonic::Status;
+
+use crate::config::ProverConfig;
+use crate::create_proof_from_advices_with_config;
+use crate::estimate::estimate_device_memory;
+use crate::scheduler::JobHandle;
+use crate::scheduler::JobStatus;
+use crate::scheduler::Scheduler;
+use crate::witness::read_witness;
+use crate::Error;
+
+pub mod proto {
+    tonic::include_proto!("zkwasm.prover");
+}
+
+use proto::prover_server::Prover;
+use proto::prover_server::ProverServer;
+use proto::GetProofRequest;
+use proto::GetProofResponse;
+use proto::GetStatusRequest;
+use proto::GetStatusResponse;
+use proto::JobState;
+use proto::SubmitProofRequest;
+use proto::SubmitProofResponse;
+
+// proof and wall time
+type ProofOutput = (Vec<u8>, f64);
+
+enum Job {
+    Pending(JobHandle<ProofOutput>),
+    Finished(Result<ProofOutput, String>),
+}
+
+impl Job {
+    fn poll(&mut self) {
+        if let Job::Pending(handle) = self {
+            if let Some(res) = handle.try_wait() {
+                *self = Job::Finished(res.map_err(|e| e.to_string()));
+            }
+        }
+    }
+}
+
+pub struct ProverService {
+    params: Arc<Params<G1Affine>>,
+    pk: Arc<ProvingKey<G1Affine>>,
+    config: ProverConfig,
+    scheduler: Scheduler,
+    // device memory estimate of a proof, for `Scheduler::submit_with_memory`
+    memory: usize,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl ProverService {
+    /// Proves with `pk` on the devices of `scheduler`, `config` with the
+    /// device id of each job set by the scheduler.
+    pub fn new(
+        params: Arc<Params<G1Affine>>,
+        pk: Arc<ProvingKey<G1Affine>>,
+        config: ProverConfig,
+        scheduler: Scheduler,
+    ) -> Result<Self, Error> {
+        let memory = estimate_device_memory(&pk, &config)?.estimate.peak;
+        Ok(ProverService {
+            params,
+            pk,
+            config,
+            scheduler,
+            memory,
+            jobs: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Prover for ProverService {
+    async fn submit_proof(
+        &self,
+        request: Request<SubmitProofRequest>,
+    ) -> Result<Response<SubmitProofResponse>, Status> {
+        let request = request.into_inner();
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.contains_key(&request.job_id) {
+            return Err(Status::already_exists(request.job_id));
+        }
+
+        let (params, pk) = (self.params.clone(), self.pk.clone());
+        let mut config = self.config.clone();
+        let (witness, use_gwc) = (request.witness, request.use_gwc);
+        let handle = self
+            .scheduler
+            .submit_cancellable(&request.job_id, self.memory, move |device_id, cancel| {
+                let witness = read_witness(&mut &witness[..], &pk)?;
+                config.device_id = Some(device_id);
+                config.cancel = Some(cancel);
+                let mut transcript = Blake2bWrite::<_, G1Affine, Challenge255<_>>::init(vec![]);
+                let metrics = create_proof_from_advices_with_config(
+                    &params,
+                    &pk,
+                    &witness.instance_slices()[..],
+                    witness.advices.clone(),
+                    &mut transcript,
+                    OsRng,
+                    use_gwc,
+                    &config,
+                )?;
+                Ok((transcript.finalize(), metrics.total_time.as_secs_f64()))
+            })
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let accepted = handle.is_some();
+        if let Some(handle) = handle {
+            jobs.insert(request.job_id, Job::Pending(handle));
+        }
+        Ok(Response::new(SubmitProofResponse { accepted }))
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<GetStatusRequest>,
+    ) -> Result<Response<GetStatusResponse>, Status> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut response = GetStatusResponse::default();
+        match jobs.get_mut(&request.get_ref().job_id) {
+            None => response.set_state(JobState::Unknown),
+            Some(job) => {
+                job.poll();
+                match job {
+                    Job::Pending(handle) => match handle.status() {
+                        JobStatus::Running { device_id } => {
+                            response.set_state(JobState::Running);
+                            response.device_id = device_id as u32;
+                        }
+                        // done but not yet received
+                        JobStatus::Queued | JobStatus::Done => response.set_state(JobState::Queued),
+                    },
+                    Job::Finished(Ok(_)) => response.set_state(JobState::Done),
+                    Job::Finished(Err(e)) => {
+                        response.set_state(JobState::Failed);
+                        response.error = e.clone();
+                    }
+                }
+            }
+        }
+        Ok(Response::new(response))
+    }
+
+    async fn get_proof(
+        &self,
+        request: Request<GetProofRequest>,
+    ) -> Result<Response<GetProofResponse>, Status> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .get_mut(&request.get_ref().job_id)
+            .ok_or_else(|| Status::not_found(request.get_ref().job_id.clone()))?;
+        job.poll();
+        match job {
+            Job::Pending(_) => Err(Status::failed_precondition("proof is not ready")),
+            Job::Finished(Err(e)) => Err(Status::aborted(e.clone())),
+            Job::Finished(Ok((proof, total_seconds))) => Ok(Response::new(GetProofResponse {
+                proof: proof.clone(),
+                total_seconds: *total_seconds,
+            })),
+        }
+    }
+}
+
+/// Serves `service` on `addr` until the future is dropped.
+pub async fn serve(
+    addr: SocketAddr,
+    service: ProverService,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(ProverServer::new(service))
+        .serve(addr)
+        .await
+}
@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::plonk::ProvingKey;
+use rayon::prelude::ParallelSliceMut as _;
+
+use crate::device::cuda::CudaDevice;
+use crate::device::cuda::CudaDeviceBufRaw;
+use crate::device::Device as _;
+use crate::eval_h::extend_fixed_columns;
+use crate::hugetlb::HugePageAllocator;
+use crate::Error;
+
+/// Static lookup tables shared by the segment proofs of a zkWasm continuation.
+///
+/// All segments prove against the same proving key, so a lookup whose table
+/// expressions only query fixed columns has the same table in every segment.
+/// The table side of such lookups is prepared once per batch:
+/// - the sorted table used to build permuted columns,
+/// - the table column on device used to generate lookup z,
+/// - the coset-extended fixed columns read by evaluate_h.
+///
+/// The table commitments are the fixed commitments of the verifying key, so
+/// nothing is committed per segment either.
+pub struct SharedStaticTables<C: CurveAffine> {
+    pub(crate) lookups: BTreeSet<usize>,
+    pub(crate) sorted_tables: BTreeMap<usize, Vec<C::Scalar, HugePageAllocator>>,
+    pub(crate) table_bufs: BTreeMap<usize, CudaDeviceBufRaw>,
+    pub(crate) extended_fixed: Arc<BTreeMap<usize, CudaDeviceBufRaw>>,
+    pub(crate) device_id: usize,
+}
+
+unsafe impl<C: CurveAffine> Send for SharedStaticTables<C> {}
+unsafe impl<C: CurveAffine> Sync for SharedStaticTables<C> {}
+
+impl<C: CurveAffine> SharedStaticTables<C> {
+    /// Prepares the tables of `lookups`, given as indices into `pk.vk.cs.lookups`,
+    /// on `device`, the device the segments are proven on.
+    /// Fails if a table expression of one of them is not a fixed column or a constant.
+    pub fn new(pk: &ProvingKey<C>, lookups: &[usize], device: &CudaDevice) -> Result<Self, Error> {
+        let size = 1 << pk.get_vk().domain.k();
+        let unusable_rows_start = size - (pk.vk.cs.blinding_factors() + 1);
+
+        let mut fixed_columns = BTreeSet::new();
+        let mut sorted_tables = BTreeMap::new();
+        let mut table_bufs = BTreeMap::new();
+        for &i in lookups {
+            let lookup = pk
+                .vk
+                .cs
+                .lookups
+                .get(i)
+                .ok_or_else(|| Error::InvalidInput(format!("lookup {} doesn't exist", i)))?;
+
+            for expr in lookup.table_expressions.iter() {
+                if let Some(idx) = expr.is_pure_fixed() {
+                    fixed_columns.insert(idx);
+                } else if expr.is_constant().is_none() {
+                    return Err(Error::InvalidInput(format!(
+                        "table of lookup {} is not static",
+                        i
+                    )));
+                }
+            }
+
+            // single column tables don't depend on theta, keep their sorted and device copies
+            if lookup.table_expressions.len() == 1 {
+                if let Some(idx) = lookup.table_expressions[0].is_pure_fixed() {
+                    let table = &pk.fixed_values[idx].values[..];
+                    let mut sorted_table = Vec::new_in(HugePageAllocator);
+                    sorted_table.extend_from_slice(table);
+                    sorted_table[0..unusable_rows_start]
+                        .par_sort_unstable_by(crate::compare_scalar);
+                    sorted_tables.insert(i, sorted_table);
+                    table_bufs.insert(i, device.alloc_device_buffer_from_slice(table)?);
+                }
+            }
+        }
+
+        let extended_fixed = extend_fixed_columns(device, pk, fixed_columns)?;
+
+        Ok(Self {
+            lookups: lookups.iter().cloned().collect(),
+            sorted_tables,
+            table_bufs,
+            extended_fixed: Arc::new(extended_fixed),
+            device_id: device.device_id(),
+        })
+    }
+
+    /// The device the tables are on, which proofs using them run on.
+    pub fn device_id(&self) -> usize {
+        self.device_id
+    }
+
+    /// Indices of the lookups whose tables are shared.
+    pub fn lookups(&self) -> impl Iterator<Item = usize> + '_ {
+        self.lookups.iter().cloned()
+    }
+}
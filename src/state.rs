@@ -0,0 +1,371 @@
+//! The commitments and challenges of a proof, shared with the caller through
+//! `ProverConfig::state`.
+//!
+//! The prover records every group of commitments it makes before the h phase
+//! (`advice`, `lookup`, `lookup_z`, `permutation`, `shuffle` and `vanishing`,
+//! in that order) and the challenges squeezed after them (`theta`, `beta`,
+//! `gamma`, `y`), so a `task::SteppedProof` can be inspected between its
+//! steps. A group already in the state when the prover reaches it, e.g. set
+//! with `ProofState::replace`, is written to the transcript as is instead of
+//! being committed; a challenge already in it must be squeezed again.
+//!
+//! A state belongs to one proof: the first proof using it binds it to a digest
+//! of the verifying key, the inputs and the blinding seeds, any other proof
+//! fails with `InvalidInput`.
+//!
+//! A state from `ProofState::replay_file` is also kept in a file, rewritten
+//! whenever a group or challenge is recorded. This is a commitment replay, not
+//! a checkpoint of the proof: proving again with the state read back, the same
+//! inputs and an rng seeded the same way recomputes every column and
+//! polynomial, h and the openings, and only skips the msms of the groups
+//! recorded. Resuming with an rng seeded otherwise would draw other blinders,
+//! so it fails the digest and is rejected with `InvalidInput`. The file holds
+//! `ZKPS`, a version, the digest, the groups and the challenges, and a blake2b
+//! checksum of all of them.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::pairing::group::ff::PrimeField;
+use halo2_proofs::pairing::group::GroupEncoding;
+use halo2_proofs::plonk::ProvingKey;
+use rayon::iter::IntoParallelRefIterator as _;
+use rayon::iter::ParallelIterator as _;
+
+use crate::witness::vk_digest;
+use crate::Error;
+
+/// Commitments of one group, in transcript order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitmentGroup {
+    pub name: String,
+    /// Compressed points, see `GroupEncoding::to_bytes`.
+    pub points: Vec<Vec<u8>>,
+}
+
+const MAGIC: &[u8; 4] = b"ZKPS";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Default)]
+struct Inner {
+    // blake2b of the verifying key, the inputs and the blinding seeds
+    digest: Option<[u8; 32]>,
+    groups: Vec<CommitmentGroup>,
+    // name and canonical encoding
+    challenges: Vec<(String, Vec<u8>)>,
+    // replay file, rewritten on every change
+    path: Option<PathBuf>,
+}
+
+fn checksum(bytes: &[u8]) -> [u8; 32] {
+    let hash = blake2b_simd::Params::new().hash_length(32).hash(bytes);
+    hash.as_bytes().try_into().unwrap()
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend(bytes);
+}
+
+fn invalid_file() -> Error {
+    Error::InvalidInput("not a valid proof state file".to_string())
+}
+
+// reads the fields of a state file front to back
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(invalid_file());
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn len(&mut self) -> Result<usize, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.len()?;
+        self.take(len).map(|x| x.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid_file())
+    }
+}
+
+impl Inner {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(VERSION.to_le_bytes());
+        match &self.digest {
+            Some(digest) => {
+                out.push(1);
+                out.extend(digest);
+            }
+            None => out.push(0),
+        }
+        out.extend((self.groups.len() as u32).to_le_bytes());
+        for group in self.groups.iter() {
+            put_bytes(&mut out, group.name.as_bytes());
+            out.extend((group.points.len() as u32).to_le_bytes());
+            for point in group.points.iter() {
+                put_bytes(&mut out, point);
+            }
+        }
+        out.extend((self.challenges.len() as u32).to_le_bytes());
+        for (name, value) in self.challenges.iter() {
+            put_bytes(&mut out, name.as_bytes());
+            put_bytes(&mut out, value);
+        }
+        let checksum = checksum(&out);
+        out.extend(checksum);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 32 || checksum(&bytes[..bytes.len() - 32]) != bytes[bytes.len() - 32..] {
+            return Err(invalid_file());
+        }
+        let mut reader = Reader(&bytes[..bytes.len() - 32]);
+        if reader.take(4)? != MAGIC || reader.take(4)? != VERSION.to_le_bytes() {
+            return Err(Error::InvalidInput(
+                "not a proof state file of this version".to_string(),
+            ));
+        }
+        let digest = match reader.take(1)?[0] {
+            0 => None,
+            _ => Some(reader.take(32)?.try_into().unwrap()),
+        };
+        let mut groups = vec![];
+        for _ in 0..reader.len()? {
+            let name = reader.string()?;
+            let points = (0..reader.len()?)
+                .map(|_| reader.bytes())
+                .collect::<Result<Vec<_>, _>>()?;
+            groups.push(CommitmentGroup { name, points });
+        }
+        let mut challenges = vec![];
+        for _ in 0..reader.len()? {
+            challenges.push((reader.string()?, reader.bytes()?));
+        }
+        Ok(Inner {
+            digest,
+            groups,
+            challenges,
+            path: None,
+        })
+    }
+
+    // written next to the file and renamed over it, a crash keeps the previous one
+    fn save(&self) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, self.encode())?;
+            fs::File::open(&tmp)?.sync_all()?;
+            fs::rename(tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Commitment groups and challenges of a proof, cloned handles share them.
+#[derive(Debug, Clone, Default)]
+pub struct ProofState(Arc<Mutex<Inner>>);
+
+impl PartialEq for ProofState {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+fn invalid_point(name: &str) -> Error {
+    Error::InvalidInput(format!("commitment of group {} is not a point", name))
+}
+
+impl ProofState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The state recorded at `path`, or an empty one if there is no file yet,
+    /// written back to `path` from now on.
+    pub fn replay_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut inner = match fs::read(path) {
+            Ok(bytes) => Inner::decode(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Inner::default(),
+            Err(e) => return Err(e.into()),
+        };
+        inner.path = Some(path.to_owned());
+        Ok(ProofState(Arc::new(Mutex::new(inner))))
+    }
+
+    /// The groups committed or replaced so far.
+    pub fn groups(&self) -> Vec<CommitmentGroup> {
+        self.0.lock().unwrap().groups.clone()
+    }
+
+    /// The commitments of group `name`, `None` until it is committed.
+    pub fn commitments<C: CurveAffine>(&self, name: &str) -> Result<Option<Vec<C>>, Error> {
+        let inner = self.0.lock().unwrap();
+        let Some(group) = inner.groups.iter().find(|x| x.name == name) else {
+            return Ok(None);
+        };
+        group
+            .points
+            .iter()
+            .map(|bytes| {
+                let mut repr = <C as GroupEncoding>::Repr::default();
+                if repr.as_ref().len() != bytes.len() {
+                    return Err(invalid_point(name));
+                }
+                repr.as_mut().copy_from_slice(bytes);
+                Option::from(C::from_bytes(&repr)).ok_or_else(|| invalid_point(name))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+
+    /// The challenge `name`, `None` until it is squeezed.
+    pub fn challenge<F: PrimeField>(&self, name: &str) -> Result<Option<F>, Error> {
+        let inner = self.0.lock().unwrap();
+        let Some((_, bytes)) = inner.challenges.iter().find(|x| x.0 == name) else {
+            return Ok(None);
+        };
+        let mut repr = F::Repr::default();
+        if repr.as_ref().len() != bytes.len() {
+            return Err(Error::InvalidInput(format!(
+                "challenge {} is not a scalar",
+                name
+            )));
+        }
+        repr.as_mut().copy_from_slice(bytes);
+        Option::from(F::from_repr(repr))
+            .map(Some)
+            .ok_or_else(|| Error::InvalidInput(format!("challenge {} is not a scalar", name)))
+    }
+
+    /// Sets the commitments of group `name`, which the prover then writes
+    /// instead of committing the group, e.g. ones computed by another prover.
+    /// They must commit to the same polynomials, blinders included, or the
+    /// proof won't verify.
+    pub fn replace<C: CurveAffine>(&self, name: &str, points: &[C]) -> Result<(), Error> {
+        let group = CommitmentGroup {
+            name: name.to_owned(),
+            points: points
+                .iter()
+                .map(|x| x.to_bytes().as_ref().to_vec())
+                .collect(),
+        };
+        let mut inner = self.0.lock().unwrap();
+        match inner.groups.iter_mut().find(|x| x.name == name) {
+            Some(old) => *old = group,
+            None => inner.groups.push(group),
+        }
+        inner.save()
+    }
+
+    /// Binds the state to the proof of `digest`, see `proof_digest`.
+    pub(crate) fn bind(&self, digest: [u8; 32]) -> Result<(), Error> {
+        let mut inner = self.0.lock().unwrap();
+        match inner.digest {
+            Some(bound) if bound != digest => Err(Error::InvalidInput(
+                "proof state is of another proof, or of other blinders".to_string(),
+            )),
+            Some(_) => Ok(()),
+            None => {
+                inner.digest = Some(digest);
+                inner.save()
+            }
+        }
+    }
+
+    /// The `len` commitments of group `name` already in the state, or those of
+    /// `commit`.
+    pub(crate) fn commit<C: CurveAffine>(
+        &self,
+        name: &str,
+        len: usize,
+        commit: impl FnOnce() -> Result<Vec<C>, Error>,
+    ) -> Result<Vec<C>, Error> {
+        if let Some(points) = self.commitments(name)? {
+            check_group_len(name, &points[..], len)?;
+            return Ok(points);
+        }
+        let points = commit()?;
+        self.replace(name, &points[..])?;
+        Ok(points)
+    }
+
+    /// Records challenge `name`, or checks it against the recorded one.
+    pub(crate) fn squeezed<F: PrimeField>(&self, name: &str, value: F) -> Result<(), Error> {
+        let bytes = value.to_repr().as_ref().to_vec();
+        let mut inner = self.0.lock().unwrap();
+        match inner.challenges.iter().find(|x| x.0 == name) {
+            Some((_, recorded)) if *recorded != bytes => Err(Error::InvalidInput(format!(
+                "challenge {} differs from the proof state",
+                name
+            ))),
+            Some(_) => Ok(()),
+            None => {
+                inner.challenges.push((name.to_owned(), bytes));
+                inner.save()
+            }
+        }
+    }
+}
+
+/// Checks that group `name` read back from the state has the `len` commitments
+/// the proof writes, a truncated file or a replaced group may not.
+pub(crate) fn check_group_len<C>(name: &str, points: &[C], len: usize) -> Result<(), Error> {
+    if points.len() != len {
+        return Err(Error::InvalidInput(format!(
+            "group {} of the proof state has {} commitments, expected {}",
+            name,
+            points.len(),
+            len
+        )));
+    }
+    Ok(())
+}
+
+/// blake2b of the verifying key, the instances, the advices and the seeds of
+/// their blinders, columns hashed in parallel.
+pub(crate) fn proof_digest<C: CurveAffine, A: AsRef<[C::Scalar]> + Sync>(
+    pk: &ProvingKey<C>,
+    instances: &[A],
+    advices: &[A],
+    seeds: &[[u8; 32]],
+) -> Result<[u8; 32], Error> {
+    let column_digest = |column: &A| {
+        let mut state = blake2b_simd::Params::new().hash_length(32).to_state();
+        for value in column.as_ref() {
+            state.update(value.to_repr().as_ref());
+        }
+        state.finalize()
+    };
+    let instances = instances.par_iter().map(column_digest).collect::<Vec<_>>();
+    let advices = advices.par_iter().map(column_digest).collect::<Vec<_>>();
+
+    let mut state = blake2b_simd::Params::new().hash_length(32).to_state();
+    state.update(&vk_digest(pk)?);
+    for columns in [instances, advices] {
+        state.update(&(columns.len() as u64).to_le_bytes());
+        for digest in columns {
+            state.update(digest.as_bytes());
+        }
+    }
+    for seed in seeds {
+        state.update(seed);
+    }
+    Ok(state.finalize().as_bytes().try_into().unwrap())
+}
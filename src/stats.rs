@@ -0,0 +1,126 @@
+//! Statistics of witness columns, for circuit authors to see which advice
+//! columns the prover commits on its fast paths and which ones it could with
+//! a different table layout.
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::pairing::group::ff::PrimeField as _;
+use halo2_proofs::plonk::ProvingKey;
+use rayon::iter::IntoParallelRefIterator as _;
+use rayon::iter::ParallelIterator as _;
+
+use crate::cuda::bn254::count_distinct;
+use crate::cuda::bn254::SPARSE_MSM_DENSITY;
+use crate::cuda::bn254::TINY_MSM_THRESHOLD;
+use crate::device::cuda::CudaDevice;
+use crate::device::Device as _;
+use crate::Error;
+
+/// Statistics of one advice column over its usable rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnStats {
+    pub index: usize,
+    /// Name given in the constraint system, if any.
+    pub name: Option<String>,
+    pub usable_rows: usize,
+    pub nonzeros: usize,
+    /// Cells equal to one, summed without an msm on the sparse path.
+    pub ones: usize,
+    /// Last nonzero row plus one, the msm skips the rows after it.
+    pub effective_len: usize,
+    pub distinct_values: usize,
+    /// Bits of the largest value as an integer below the modulus.
+    pub max_bits: u32,
+    /// Rows of the domain, the share of the sparse path is taken over them.
+    domain_size: usize,
+}
+
+impl ColumnStats {
+    /// Share of the usable rows holding a nonzero value.
+    pub fn fill_rate(&self) -> f64 {
+        self.nonzeros as f64 / self.usable_rows.max(1) as f64
+    }
+
+    /// Committed by the msm over its nonzero scalars only.
+    pub fn sparse_msm(&self) -> bool {
+        ((self.nonzeros - self.ones) as f64) < SPARSE_MSM_DENSITY * self.domain_size as f64
+    }
+
+    /// Committed by the msm of short columns.
+    pub fn tiny_msm(&self) -> bool {
+        self.effective_len <= TINY_MSM_THRESHOLD
+    }
+
+    /// Fits in a u64, the bucket windows above it are empty.
+    pub fn small_scalars(&self) -> bool {
+        self.max_bits <= 64
+    }
+}
+
+fn bit_len<F: FieldExt>(x: &F) -> u32 {
+    let repr = x.to_repr();
+    let bytes = repr.as_ref();
+    bytes
+        .iter()
+        .rposition(|b| *b != 0)
+        .map_or(0, |i| i as u32 * 8 + (8 - bytes[i].leading_zeros()))
+}
+
+/// Statistics of each of `advices`, as passed to `create_proof_from_advices`.
+/// Distinct values are counted by a histogram on device 0, one column at a time.
+pub fn analyze_advices<C: CurveAffine, A: AsRef<[C::Scalar]>>(
+    pk: &ProvingKey<C>,
+    advices: &[A],
+) -> Result<Vec<ColumnStats>, Error> {
+    let domain_size = 1usize << pk.get_vk().domain.k();
+    let usable_rows = domain_size - (pk.vk.cs.blinding_factors() + 1);
+    if advices.len() != pk.vk.cs.num_advice_columns {
+        return Err(Error::InvalidInput(format!(
+            "{} advice columns given, circuit has {}",
+            advices.len(),
+            pk.vk.cs.num_advice_columns
+        )));
+    }
+
+    let device = CudaDevice::get_device(0)?;
+    let buf = device.alloc_device_buffer::<C::Scalar>(usable_rows)?;
+    let named = &pk.vk.cs.named_advices;
+
+    let mut stats = Vec::with_capacity(advices.len());
+    for (index, advice) in advices.iter().enumerate() {
+        let values = &advice.as_ref()[..advice.as_ref().len().min(usable_rows)];
+        let (nonzeros, ones, max_bits) = values
+            .par_iter()
+            .map(|x| {
+                if *x == C::Scalar::zero() {
+                    (0, 0, 0)
+                } else {
+                    (1, (*x == C::Scalar::one()) as usize, bit_len(x))
+                }
+            })
+            .reduce(|| (0, 0, 0), |a, b| (a.0 + b.0, a.1 + b.1, a.2.max(b.2)));
+        let effective_len = values
+            .iter()
+            .rposition(|x| *x != C::Scalar::zero())
+            .map_or(0, |i| i + 1);
+
+        device.copy_from_host_to_device(&buf, values)?;
+        let distinct_values = count_distinct::<C::Scalar>(&device, &buf, values.len())?;
+
+        stats.push(ColumnStats {
+            index,
+            name: named
+                .iter()
+                .find(|n| n.1 as usize == index)
+                .map(|n| n.0.clone()),
+            usable_rows,
+            nonzeros,
+            ones,
+            effective_len,
+            distinct_values,
+            max_bits,
+            domain_size,
+        });
+    }
+    Ok(stats)
+}
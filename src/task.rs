@@ -0,0 +1,338 @@
+//! Proofs on a dedicated thread, awaited as a `Future`.
+//!
+//! The future only relies on `std::task`, so it can be awaited from tokio or
+//! any other executor without blocking it. A `CancelToken` stops the proof at
+//! the next phase boundary with `Error::Cancelled`; the device buffers of the
+//! proof are dropped on the way out and go back to the buffer cache.
+//!
+//! A `SteppedProof` runs the same thread one phase at a time, the caller
+//! deciding when the next phase starts. Between two steps its `ProofState`
+//! holds the commitments and challenges of the phases done, and commitment
+//! groups of the next phases can be put in it to replace the prover's.
+
+use std::future::Future;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+use std::thread;
+use std::thread::JoinHandle;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::poly::commitment::Params;
+use halo2_proofs::transcript::EncodedChallenge;
+use halo2_proofs::transcript::TranscriptWrite;
+use rand::RngCore;
+
+use crate::config::ProverConfig;
+use crate::create_proof_from_advices_with_config;
+use crate::hugetlb::HugePageAllocator;
+use crate::metrics::PhaseEvent;
+use crate::metrics::ProofMetrics;
+use crate::metrics::ProofObserver;
+use crate::metrics::SharedObserver;
+use crate::panic_error;
+use crate::state::ProofState;
+use crate::Error;
+
+#[cfg(test)]
+mod test;
+
+/// Shared flag asking a proof to stop, see `ProverConfig::cancel`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+struct TaskState<R> {
+    result: Option<Result<R, Error>>,
+    waker: Option<Waker>,
+}
+
+/// Result of a proof running on its own thread. Dropping the task before it
+/// resolves cancels the proof.
+pub struct ProofTask<R> {
+    state: Arc<Mutex<TaskState<R>>>,
+    cancel: CancelToken,
+}
+
+impl<R> ProofTask<R> {
+    /// Stops the proof at its next phase boundary.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+}
+
+impl<R> Future for ProofTask<R> {
+    type Output = Result<R, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<R> Drop for ProofTask<R> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+/// Runs `prove` on a new thread, handing it `cancel` to check.
+pub fn spawn_proof<R: Send + 'static>(
+    cancel: CancelToken,
+    prove: impl FnOnce(CancelToken) -> Result<R, Error> + Send + 'static,
+) -> ProofTask<R> {
+    let state = Arc::new(Mutex::new(TaskState {
+        result: None,
+        waker: None,
+    }));
+    let task_state = state.clone();
+    let token = cancel.clone();
+    thread::spawn(move || {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| prove(token)))
+            .unwrap_or_else(|e| Err(panic_error("proof thread", e)));
+        let mut state = task_state.lock().unwrap();
+        state.result = Some(res);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    });
+    ProofTask { state, cancel }
+}
+
+/// `create_proof_from_advices_with_config` on its own thread. The future
+/// resolves to the transcript holding the proof and the metrics.
+pub fn create_proof_async<C, E, T>(
+    params: Arc<Params<C>>,
+    pk: Arc<ProvingKey<C>>,
+    instances: Vec<Vec<C::Scalar>>,
+    advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+    mut transcript: T,
+    rng: impl RngCore + Send + 'static,
+    use_gwc: bool,
+    mut config: ProverConfig,
+) -> ProofTask<(T, ProofMetrics)>
+where
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send + 'static,
+{
+    let cancel = config.cancel.get_or_insert_with(CancelToken::new).clone();
+    spawn_proof(cancel, move |_| {
+        let instances = instances.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        let metrics = create_proof_from_advices_with_config(
+            &params,
+            &pk,
+            &instances[..],
+            advices,
+            &mut transcript,
+            rng,
+            use_gwc,
+            &config,
+        )?;
+        Ok((transcript, metrics))
+    })
+}
+
+// phases a stepped proof stops before
+const STEPS: [&str; 5] = ["advice", "lookup", "permutation", "vanishing", "eval"];
+
+// Holds the proof thread at the start of each step until the controller resumes it.
+struct StepObserver {
+    inner: Option<SharedObserver>,
+    events: Mutex<Sender<PhaseEvent>>,
+    resume: Mutex<Receiver<()>>,
+}
+
+impl ProofObserver for StepObserver {
+    fn on_phase(&self, event: &PhaseEvent) {
+        if let Some(inner) = &self.inner {
+            inner.0.on_phase(event);
+        }
+        if event.started.map_or(false, |phase| STEPS.contains(&phase)) {
+            let _ = self.events.lock().unwrap().send(event.clone());
+            // the controller is gone when the proof was cancelled
+            let _ = self.resume.lock().unwrap().recv();
+        }
+    }
+}
+
+/// A proof on its own thread, run one phase at a time: the thread waits at
+/// each phase boundary, holding its device buffers, until the next step is
+/// called, so the caller can do other work in between. Each step returns the
+/// `PhaseEvent` of the boundary it stopped at. Dropping the proof before
+/// `open` cancels it and waits for its thread.
+pub struct SteppedProof<R> {
+    events: Receiver<PhaseEvent>,
+    resume: Option<Sender<()>>,
+    cancel: CancelToken,
+    state: ProofState,
+    handle: Option<JoinHandle<Result<R, Error>>>,
+}
+
+impl<R> SteppedProof<R> {
+    /// The commitment groups and challenges of the steps done so far. A group
+    /// replaced in it before the step making it is used instead of committed.
+    pub fn state(&self) -> &ProofState {
+        &self.state
+    }
+
+    fn step(&mut self) -> Result<PhaseEvent, Error> {
+        if let Some(resume) = &self.resume {
+            let _ = resume.send(());
+        }
+        match self.events.recv() {
+            Ok(event) => Ok(event),
+            // the proof ended before the next boundary, i.e. it failed
+            Err(_) => match self.join() {
+                Ok(_) => Err(Error::InvalidInput(
+                    "proof finished before its last phase".to_string(),
+                )),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    fn join(&mut self) -> Result<R, Error> {
+        match self.handle.take() {
+            Some(handle) => handle
+                .join()
+                .unwrap_or_else(|e| Err(panic_error("proof thread", e))),
+            None => Err(Error::InvalidInput("proof already finished".to_string())),
+        }
+    }
+
+    /// Commits the instance and advice columns.
+    pub fn commit_advices(&mut self) -> Result<PhaseEvent, Error> {
+        self.step()
+    }
+
+    /// Permutes the lookups and commits the permuted columns.
+    pub fn commit_lookups(&mut self) -> Result<PhaseEvent, Error> {
+        self.step()
+    }
+
+    /// Commits the permutation, lookup and shuffle products.
+    pub fn commit_permutations(&mut self) -> Result<PhaseEvent, Error> {
+        self.step()
+    }
+
+    /// Commits the vanishing random poly, evaluates h and commits its pieces.
+    pub fn evaluate_h(&mut self) -> Result<PhaseEvent, Error> {
+        self.step()
+    }
+
+    /// Writes the evaluations and the multiopen proof, and waits for the result.
+    pub fn open(mut self) -> Result<R, Error> {
+        if let Some(resume) = self.resume.take() {
+            let _ = resume.send(());
+        }
+        self.join()
+    }
+}
+
+impl<R> Drop for SteppedProof<R> {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            self.cancel.cancel();
+            self.resume.take();
+            let _ = self.join();
+        }
+    }
+}
+
+/// `create_proof_from_advices_with_config` as a `SteppedProof`, stopped
+/// before its first phase. `open` returns the transcript holding the proof
+/// and the metrics. The proof records into `config.state`, or a new state.
+pub fn create_proof_stepped<C, E, T>(
+    params: Arc<Params<C>>,
+    pk: Arc<ProvingKey<C>>,
+    instances: Vec<Vec<C::Scalar>>,
+    advices: Arc<Vec<Vec<C::Scalar, HugePageAllocator>>>,
+    mut transcript: T,
+    rng: impl RngCore + Send + 'static,
+    use_gwc: bool,
+    mut config: ProverConfig,
+) -> Result<SteppedProof<(T, ProofMetrics)>, Error>
+where
+    C: CurveAffine,
+    E: EncodedChallenge<C>,
+    T: TranscriptWrite<C, E> + Send + 'static,
+{
+    let (event_sender, events) = channel();
+    let (resume, resume_receiver) = channel();
+    let cancel = config.cancel.get_or_insert_with(CancelToken::new).clone();
+    let state = config.state.get_or_insert_with(ProofState::new).clone();
+    config.observer = Some(SharedObserver(Arc::new(StepObserver {
+        inner: config.observer.take(),
+        events: Mutex::new(event_sender),
+        resume: Mutex::new(resume_receiver),
+    })));
+
+    let handle = thread::spawn(move || {
+        let instances = instances.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        let metrics = create_proof_from_advices_with_config(
+            &params,
+            &pk,
+            &instances[..],
+            advices,
+            &mut transcript,
+            rng,
+            use_gwc,
+            &config,
+        )?;
+        Ok((transcript, metrics))
+    });
+
+    let mut proof = SteppedProof {
+        events,
+        resume: Some(resume),
+        cancel,
+        state,
+        handle: Some(handle),
+    };
+    // the first boundary is the start of the advice phase, no resume needed
+    match proof.events.recv() {
+        Ok(_) => Ok(proof),
+        Err(_) => Err(proof.join().err().unwrap_or(Error::Cancelled)),
+    }
+}
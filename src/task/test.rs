@@ -0,0 +1,57 @@
+use std::future::Future;
+use std::pin::pin;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Wake;
+use std::time::Duration;
+
+use super::spawn_proof;
+use super::CancelToken;
+use crate::Error;
+
+struct ThreadWaker(std::thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// a minimal executor, polls on every wake up
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(res) => return res,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[test]
+fn test_proof_task() {
+    let task = spawn_proof(CancelToken::new(), |_| {
+        std::thread::sleep(Duration::from_millis(20));
+        Ok(7)
+    });
+    assert_eq!(block_on(task).unwrap(), 7);
+}
+
+#[test]
+fn test_proof_task_cancel() {
+    let (started, start) = channel();
+    let task = spawn_proof(CancelToken::new(), move |cancel| {
+        started.send(()).unwrap();
+        while !cancel.is_cancelled() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Err::<(), _>(Error::Cancelled)
+    });
+    start.recv().unwrap();
+    task.cancel();
+    assert!(matches!(block_on(task), Err(Error::Cancelled)));
+}
@@ -0,0 +1,490 @@
+use super::compare_scalar;
+use super::handle_lookup_pair;
+use crate::hugetlb::HugePageAllocator;
+use halo2_proofs::arithmetic::Field as _;
+use halo2_proofs::pairing::bn256::Fr;
+use proptest::prelude::*;
+
+fn column(values: &[u64]) -> Vec<Fr, HugePageAllocator> {
+    let mut column = Vec::new_in(HugePageAllocator);
+    column.extend(values.iter().map(|v| Fr::from(*v)));
+    column
+}
+
+fn sorted(values: &[Fr]) -> Vec<Fr> {
+    let mut values = values.to_vec();
+    values.sort_unstable_by(compare_scalar);
+    values
+}
+
+fn check_lookup_pair(input: &[u64], table: &[u64], usable: usize, blinding: bool, presort: bool) {
+    let n = input.len();
+    let mut input = column(input);
+    let mut table = column(table);
+    let sorted_table = presort.then(|| {
+        let mut sorted_table = table.clone();
+        sorted_table[..usable].sort_unstable_by(compare_scalar);
+        sorted_table
+    });
+    let (permuted_input, permuted_table) = handle_lookup_pair(
+        &mut input,
+        &mut table,
+        column(&vec![0; n]),
+        column(&vec![0; n]),
+        usable,
+        blinding,
+        sorted_table.as_deref(),
+        None,
+    );
+
+    assert_eq!(permuted_input.len(), n);
+    assert_eq!(permuted_table.len(), n);
+    assert_eq!(sorted(&permuted_input[..usable]), sorted(&input[..usable]));
+    assert_eq!(sorted(&permuted_table[..usable]), sorted(&table[..usable]));
+    for row in 0..usable {
+        let first = row == 0 || permuted_input[row] != permuted_input[row - 1];
+        if first {
+            assert_eq!(permuted_input[row], permuted_table[row], "row {}", row);
+        } else {
+            assert_eq!(permuted_input[row], permuted_input[row - 1], "row {}", row);
+        }
+    }
+    if !blinding {
+        assert!(permuted_input[usable..].iter().all(|v| *v == Fr::zero()));
+        assert!(permuted_table[usable..].iter().all(|v| *v == Fr::zero()));
+    }
+}
+
+// Tables with values in 0..alphabet, inputs picked from the usable rows of
+// the table, followed by `tail` unusable rows of arbitrary values.
+fn lookup_pair() -> impl Strategy<Value = (Vec<u64>, Vec<u64>, usize)> {
+    (0usize..64, 0usize..6, 1u64..16).prop_flat_map(|(usable, tail, alphabet)| {
+        let table = prop::collection::vec(0..alphabet, usable);
+        let picks = prop::collection::vec(any::<prop::sample::Index>(), usable);
+        let tails = prop::collection::vec(any::<u64>(), 2 * tail);
+        (table, picks, tails).prop_map(move |(mut table, picks, tails)| {
+            let mut input: Vec<u64> = picks.iter().map(|i| table[i.index(usable)]).collect();
+            input.extend(&tails[..tail]);
+            table.extend(&tails[tail..]);
+            (input, table, usable)
+        })
+    })
+}
+
+proptest! {
+    #[test]
+    fn test_handle_lookup_pair(
+        (input, table, usable) in lookup_pair(),
+        blinding in any::<bool>(),
+        presort in any::<bool>(),
+    ) {
+        check_lookup_pair(&input, &table, usable, blinding, presort);
+    }
+}
+
+#[test]
+fn test_handle_lookup_pair_edge_cases() {
+    check_lookup_pair(&[3; 8], &[3; 8], 8, false, false);
+    check_lookup_pair(&[3, 3, 3, 3, 1], &[5, 3, 4, 3, 2], 4, false, true);
+    check_lookup_pair(&[7, 8], &[9, 10], 0, false, false);
+    check_lookup_pair(&[], &[], 0, true, false);
+}
+
+#[test]
+fn test_host_lookup_z() {
+    let (beta, gamma) = (Fr::from(3), Fr::from(5));
+    let input = column(&[1, 2, 2, 4, 0, 0, 0, 0]);
+    let table = column(&[4, 2, 1, 3, 0, 0, 0, 0]);
+    let permuted_input = column(&[1, 2, 2, 4, 0, 0, 0, 0]);
+    let permuted_table = column(&[1, 2, 3, 4, 0, 0, 0, 0]);
+    let mut z = column(&[0; 8]);
+    crate::host::lookup_z(
+        &permuted_input,
+        &permuted_table,
+        &input,
+        &table,
+        &mut z,
+        beta,
+        gamma,
+        4,
+    );
+
+    // the product over all usable rows is one
+    assert_eq!(z[0], Fr::one());
+    assert_eq!(z[4], Fr::one());
+    let z1 = (input[0] + beta)
+        * (table[0] + gamma)
+        * ((permuted_input[0] + beta) * (permuted_table[0] + gamma))
+            .invert()
+            .unwrap();
+    assert_eq!(z[1], z1);
+}
+
+// Proves the self-test circuit with each optional path of `ProverConfig` and
+// checks from the metrics that the proof took it.
+#[test]
+fn test_config_paths_proof() {
+    use crate::backend::BackendKind;
+    use crate::config::ProverConfig;
+
+    // (config, backend, path taken)
+    let cases = vec![
+        (
+            ProverConfig {
+                backend: Some(BackendKind::Cpu),
+                ..Default::default()
+            },
+            "cpu",
+            None,
+        ),
+        (
+            ProverConfig {
+                intermediate_domain: true,
+                ..Default::default()
+            },
+            "cuda",
+            Some("intermediate_domain"),
+        ),
+        (
+            ProverConfig {
+                coset_sliced_h: true,
+                ..Default::default()
+            },
+            "cuda",
+            Some("coset_sliced_h"),
+        ),
+        (
+            ProverConfig {
+                expr_streams: 2,
+                ..Default::default()
+            },
+            "cuda",
+            Some("expr_streams"),
+        ),
+        (
+            ProverConfig {
+                resident_permuted: true,
+                ..Default::default()
+            },
+            "cuda",
+            Some("resident_permuted"),
+        ),
+        (
+            ProverConfig {
+                gpu_permutation_products: true,
+                ..Default::default()
+            },
+            "cuda",
+            Some("gpu_permutation_products"),
+        ),
+        (
+            ProverConfig {
+                cuda_graphs: true,
+                ..Default::default()
+            },
+            "cuda",
+            Some("cuda_graphs"),
+        ),
+        // without nvrtc the groups fall back to field_op_batch_mul_sum
+        (
+            ProverConfig {
+                jit_gates: true,
+                ..Default::default()
+            },
+            "cuda",
+            cfg!(feature = "nvrtc").then_some("jit_gates"),
+        ),
+    ];
+    #[cfg(feature = "opencl")]
+    let cases = cases.into_iter().chain([(
+        ProverConfig {
+            device_id: Some(0),
+            backend: Some(BackendKind::OpenCL),
+            ..Default::default()
+        },
+        "opencl",
+        None,
+    )]);
+
+    for (config, backend, path) in cases {
+        let metrics = crate::selftest::prove_and_verify_with_metrics(&config)
+            .unwrap_or_else(|e| panic!("{:?}: {}", config, e));
+        assert_eq!(metrics.backend, backend, "{:?}", config);
+        if let Some(path) = path {
+            assert!(
+                metrics.paths.get(path).copied().unwrap_or(0) > 0,
+                "{} not taken: {:?}",
+                path,
+                metrics.paths
+            );
+        }
+    }
+}
+
+#[test]
+fn test_proof_state() {
+    use crate::state::ProofState;
+    use halo2_proofs::pairing::bn256::{Fr, G1Affine};
+
+    let state = ProofState::new();
+    crate::selftest::prove_and_verify(&crate::config::ProverConfig {
+        state: Some(state.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let groups = state.groups();
+    assert_eq!(
+        groups.iter().map(|x| &x.name[..]).collect::<Vec<_>>(),
+        [
+            "advice",
+            "lookup",
+            "lookup_z",
+            "permutation",
+            "shuffle",
+            "vanishing"
+        ]
+    );
+    assert_eq!(
+        state
+            .commitments::<G1Affine>("vanishing")
+            .unwrap()
+            .map(|x| x.len()),
+        Some(1)
+    );
+    for challenge in ["theta", "beta", "gamma", "y"] {
+        assert!(state.challenge::<Fr>(challenge).unwrap().is_some());
+    }
+}
+
+#[test]
+fn test_proof_state_replay() {
+    use crate::metrics::{PhaseEvent, ProofObserver, SharedObserver};
+    use crate::state::ProofState;
+    use crate::task::CancelToken;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng as _;
+    use std::sync::Arc;
+
+    struct CancelAt(CancelToken);
+    impl ProofObserver for CancelAt {
+        fn on_phase(&self, event: &PhaseEvent) {
+            if event.started == Some("permutation") {
+                self.0.cancel();
+            }
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!("zkps-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let cancel = CancelToken::new();
+    let result = crate::selftest::prove_and_verify_with_rng(
+        &crate::config::ProverConfig {
+            state: Some(ProofState::replay_file(&path).unwrap()),
+            cancel: Some(cancel.clone()),
+            observer: Some(SharedObserver(Arc::new(CancelAt(cancel)))),
+            ..Default::default()
+        },
+        StdRng::from_seed([7; 32]),
+    );
+    assert!(matches!(result, Err(crate::Error::Cancelled)));
+
+    let state = ProofState::replay_file(&path).unwrap();
+    let groups = state.groups();
+    assert!(!groups.is_empty() && groups.iter().all(|x| x.name != "vanishing"));
+
+    // another rng means other blinders, the recorded groups don't apply
+    let result = crate::selftest::prove_and_verify_with_rng(
+        &crate::config::ProverConfig {
+            state: Some(state.clone()),
+            ..Default::default()
+        },
+        StdRng::from_seed([8; 32]),
+    );
+    assert!(matches!(result, Err(crate::Error::InvalidInput(_))));
+
+    crate::selftest::prove_and_verify_with_rng(
+        &crate::config::ProverConfig {
+            state: Some(state.clone()),
+            ..Default::default()
+        },
+        StdRng::from_seed([7; 32]),
+    )
+    .unwrap();
+    assert_eq!(
+        ProofState::replay_file(&path).unwrap().groups(),
+        state.groups()
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_proof_state_truncated_group() {
+    use crate::state::ProofState;
+    use halo2_proofs::pairing::bn256::G1Affine;
+
+    // a group read back with fewer commitments than the proof writes is
+    // rejected, not indexed
+    let state = ProofState::new();
+    state.replace::<G1Affine>("vanishing", &[]).unwrap();
+    let result = crate::selftest::prove_and_verify(&crate::config::ProverConfig {
+        state: Some(state),
+        ..Default::default()
+    });
+    assert!(matches!(result, Err(crate::Error::InvalidInput(_))));
+}
+
+#[test]
+fn test_proof_state_replay_other_rng() {
+    use crate::state::ProofState;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng as _;
+
+    let path = std::env::temp_dir().join(format!("zkps-rng-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    crate::selftest::prove_and_verify_with_rng(
+        &crate::config::ProverConfig {
+            state: Some(ProofState::replay_file(&path).unwrap()),
+            ..Default::default()
+        },
+        StdRng::from_seed([7; 32]),
+    )
+    .unwrap();
+    let groups = ProofState::replay_file(&path).unwrap().groups();
+
+    // blinders are drawn again on replay, those of another seed don't match
+    // the recorded commitments
+    let result = crate::selftest::prove_and_verify_with_rng(
+        &crate::config::ProverConfig {
+            state: Some(ProofState::replay_file(&path).unwrap()),
+            ..Default::default()
+        },
+        StdRng::from_seed([9; 32]),
+    );
+    assert!(matches!(result, Err(crate::Error::InvalidInput(_))));
+    assert_eq!(ProofState::replay_file(&path).unwrap().groups(), groups);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_pad_short_advices() {
+    use std::sync::Arc;
+
+    let (params, pk, advices) = crate::selftest::setup().unwrap();
+    // the circuit assigns the first half of the rows, the others are zero
+    let rows = advices[1].len() / 2;
+    let mut short = (*advices).clone();
+    short[1].truncate(rows);
+    let short = Arc::new(short);
+    let kept = short.clone();
+
+    let config = crate::config::ProverConfig::default();
+    assert!(matches!(
+        crate::selftest::prove_and_verify_advices(
+            &params,
+            &pk,
+            short.clone(),
+            &config,
+            rand::rngs::OsRng
+        ),
+        Err(crate::Error::InvalidInput(_))
+    ));
+    crate::selftest::prove_and_verify_advices(
+        &params,
+        &pk,
+        short,
+        &crate::config::ProverConfig {
+            pad_short_advices: true,
+            ..config
+        },
+        rand::rngs::OsRng,
+    )
+    .unwrap();
+    // padded into a copy, the shared columns are left as they were
+    assert_eq!(kept[1].len(), rows);
+    assert!(kept[1][..] == advices[1][..rows]);
+}
+
+#[test]
+fn test_witness_file() {
+    use crate::witness::{map_witness, read_witness, write_witness};
+
+    let (_, pk, advices) = crate::selftest::setup().unwrap();
+    let mut advices = (*advices).clone();
+    advices[0][0] = Fr::one();
+    let mut bytes = vec![];
+    write_witness(&mut bytes, &pk, &[], &advices[..]).unwrap();
+    let path = std::env::temp_dir().join(format!("zkww-{}", std::process::id()));
+    std::fs::write(&path, &bytes).unwrap();
+    assert!(*map_witness(&path, &pk).unwrap().advices == advices);
+    assert!(*read_witness(&mut &bytes[..], &pk).unwrap().advices == advices);
+
+    // header, no instances, the advice count and the length of the first column
+    bytes[57..89].fill(0xff);
+    std::fs::write(&path, &bytes).unwrap();
+    assert!(matches!(
+        map_witness(&path, &pk),
+        Err(crate::Error::InvalidInput(_))
+    ));
+    assert!(matches!(
+        read_witness(&mut &bytes[..], &pk),
+        Err(crate::Error::InvalidInput(_))
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_ffi_proof_arguments() {
+    use crate::ffi::*;
+    use std::sync::Arc;
+
+    let (params, pk, _) = crate::selftest::setup().unwrap();
+    let mut bytes = vec![];
+    params.write(&mut bytes).unwrap();
+    unsafe {
+        let mut params = std::ptr::null_mut();
+        assert_eq!(
+            zkw_params_read(bytes.as_ptr(), bytes.len(), &mut params),
+            ZkwStatus::Ok
+        );
+        let pk = zkw_proving_key_from(Arc::new(pk));
+        let mut advices = std::ptr::null_mut();
+        assert_eq!(zkw_advices_new(pk, &mut advices), ZkwStatus::Ok);
+        let mut proof = ZkwBytes {
+            ptr: std::ptr::null_mut(),
+            len: 0,
+        };
+
+        // the self-test circuit has no instance columns
+        let status = zkw_create_proof(
+            params,
+            pk,
+            std::ptr::null(),
+            std::ptr::null(),
+            1,
+            advices,
+            false,
+            -1,
+            &mut proof,
+        );
+        assert_eq!(status, ZkwStatus::InvalidArgument);
+        let status = zkw_create_proof(
+            params,
+            pk,
+            std::ptr::null(),
+            std::ptr::null(),
+            0,
+            advices,
+            false,
+            -1,
+            &mut proof,
+        );
+        assert_eq!(status, ZkwStatus::Ok);
+        assert!(proof.len > 0);
+
+        zkw_bytes_free(proof);
+        zkw_advices_free(advices);
+        zkw_proving_key_free(pk);
+        zkw_params_free(params);
+    }
+}
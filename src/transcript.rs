@@ -0,0 +1,154 @@
+use std::cell::RefCell;
+use std::io;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Sender;
+use std::thread::Scope;
+use std::thread::ScopedJoinHandle;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::transcript::EncodedChallenge;
+use halo2_proofs::transcript::TranscriptWrite;
+
+use crate::panic_error;
+use crate::phases::Challenge;
+use crate::phases::Challenges;
+use crate::Error;
+
+enum TranscriptOp<C: CurveAffine> {
+    CommonPoint(C),
+    WritePoint(C),
+    WriteScalar(C::Scalar),
+    Squeeze(Sender<C::Scalar>),
+}
+
+/// Absorbs transcript writes on a worker thread in submission order, so point
+/// serialization and hashing overlap with the following GPU work.
+///
+/// Squeezing waits for every write queued before it. The worker is a thread
+/// of `scope` and holds the transcript until `finish` joins it and hands the
+/// transcript back.
+pub(crate) struct TranscriptPipeline<'scope, C: CurveAffine, T> {
+    sender: Option<Sender<TranscriptOp<C>>>,
+    handler: Option<ScopedJoinHandle<'scope, (io::Result<()>, &'scope mut T)>>,
+    // writes since the last squeeze, handed to an external challenge driver
+    written: RefCell<(Vec<C>, Vec<C::Scalar>)>,
+}
+
+impl<'scope, C: CurveAffine, T> TranscriptPipeline<'scope, C, T> {
+    pub(crate) fn new<'env, E: EncodedChallenge<C>>(
+        scope: &'scope Scope<'scope, 'env>,
+        transcript: &'scope mut T,
+    ) -> Self
+    where
+        T: TranscriptWrite<C, E> + Send,
+    {
+        let (sender, receiver) = channel::<TranscriptOp<C>>();
+        let handler = scope.spawn(move || {
+            let mut res = Ok(());
+            for op in receiver {
+                match op {
+                    TranscriptOp::CommonPoint(p) => {
+                        res = res.and_then(|_| transcript.common_point(p));
+                    }
+                    TranscriptOp::WritePoint(p) => {
+                        res = res.and_then(|_| transcript.write_point(p));
+                    }
+                    TranscriptOp::WriteScalar(v) => {
+                        res = res.and_then(|_| transcript.write_scalar(v));
+                    }
+                    TranscriptOp::Squeeze(reply) => {
+                        let challenge = *transcript.squeeze_challenge_scalar::<()>();
+                        // the receiver only disappears if the prover already bailed out
+                        let _ = reply.send(challenge);
+                    }
+                }
+            }
+            (res, transcript)
+        });
+
+        Self {
+            sender: Some(sender),
+            handler: Some(handler),
+            written: RefCell::new((vec![], vec![])),
+        }
+    }
+
+    fn send(&self, op: TranscriptOp<C>) -> Result<(), Error> {
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(op)
+            .map_err(|_| transcript_error("transcript worker exited"))
+    }
+
+    pub(crate) fn common_point(&self, point: C) -> Result<(), Error> {
+        self.written.borrow_mut().0.push(point);
+        self.send(TranscriptOp::CommonPoint(point))
+    }
+
+    pub(crate) fn write_point(&self, point: C) -> Result<(), Error> {
+        self.written.borrow_mut().0.push(point);
+        self.send(TranscriptOp::WritePoint(point))
+    }
+
+    pub(crate) fn write_scalar(&self, scalar: C::Scalar) -> Result<(), Error> {
+        self.written.borrow_mut().1.push(scalar);
+        self.send(TranscriptOp::WriteScalar(scalar))
+    }
+
+    /// Squeeze `challenge` from the transcript, or ask the external driver of
+    /// `challenges` with the writes made since the previous squeeze.
+    pub(crate) fn squeeze(
+        &self,
+        challenges: &mut Challenges<C>,
+        challenge: Challenge,
+    ) -> Result<C::Scalar, Error> {
+        let (points, scalars) = std::mem::take(&mut *self.written.borrow_mut());
+        points.into_iter().for_each(|p| challenges.record_point(p));
+        scalars
+            .into_iter()
+            .for_each(|s| challenges.record_scalar(s));
+        match challenges.external(challenge) {
+            Some(res) => res,
+            None => self.squeeze_challenge_scalar(),
+        }
+    }
+
+    pub(crate) fn squeeze_challenge_scalar(&self) -> Result<C::Scalar, Error> {
+        let (reply, receiver) = channel();
+        self.send(TranscriptOp::Squeeze(reply))?;
+        receiver
+            .recv()
+            .map_err(|_| transcript_error("transcript worker exited"))
+    }
+
+    /// Waits for the queued writes and returns the transcript.
+    pub(crate) fn finish(mut self) -> Result<&'scope mut T, Error> {
+        self.join()?
+            .ok_or_else(|| transcript_error("transcript worker already joined"))
+    }
+
+    fn join(&mut self) -> Result<Option<&'scope mut T>, Error> {
+        drop(self.sender.take());
+        match self.handler.take() {
+            Some(handler) => {
+                let (res, transcript) = handler
+                    .join()
+                    .map_err(|e| panic_error("transcript worker", e))?;
+                res.map_err(|e| transcript_error(&format!("fail to write transcript: {}", e)))?;
+                Ok(Some(transcript))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'scope, C: CurveAffine, T> Drop for TranscriptPipeline<'scope, C, T> {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+fn transcript_error(msg: &str) -> Error {
+    Error::TranscriptError(io::Error::new(io::ErrorKind::Other, msg))
+}
@@ -0,0 +1,241 @@
+//! Facts about a proof derived from the `VerifyingKey` alone: domain
+//! parameters, the number of commitments and evaluations, and the order they
+//! appear in the transcript. Services checking or storing proofs can use them
+//! without loading the proving key or a GPU.
+
+use std::collections::BTreeSet;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::pairing::group::ff::PrimeField;
+use halo2_proofs::pairing::group::GroupEncoding;
+use halo2_proofs::plonk::VerifyingKey;
+
+use crate::phases::Challenge;
+use crate::Error;
+
+/// Domain parameters of the circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DomainParams {
+    pub k: u32,
+    pub extended_k: u32,
+    /// Rows of the domain, `1 << k`.
+    pub n: usize,
+    /// Number of h pieces.
+    pub quotient_poly_degree: usize,
+    pub blinding_factors: usize,
+    /// Rows left to the witness, the last one is reserved for the permutation argument.
+    pub usable_rows: usize,
+}
+
+impl DomainParams {
+    pub fn new<C: CurveAffine>(vk: &VerifyingKey<C>) -> Self {
+        let n = 1usize << vk.domain.k();
+        let blinding_factors = vk.cs.blinding_factors();
+
+        DomainParams {
+            k: vk.domain.k(),
+            extended_k: vk.domain.extended_k(),
+            n,
+            quotient_poly_degree: vk.domain.quotient_poly_degree as usize,
+            blinding_factors,
+            usable_rows: n - (blinding_factors + 1),
+        }
+    }
+}
+
+/// Commitments and evaluations of a single-instance proof.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitmentCounts {
+    /// Absorbed by the transcript but not written to the proof.
+    pub instance: usize,
+    pub advice: usize,
+    /// Permuted input and permuted table of every lookup.
+    pub lookup_permuted: usize,
+    pub permutation_products: usize,
+    pub lookup_products: usize,
+    pub shuffle_products: usize,
+    pub random_poly: usize,
+    pub h_pieces: usize,
+    /// Written by the multiopen argument.
+    pub opening: usize,
+    pub evaluations: usize,
+}
+
+impl CommitmentCounts {
+    pub fn new<C: CurveAffine>(vk: &VerifyingKey<C>, use_gwc: bool) -> Self {
+        let cs = &vk.cs;
+        let chunk_len = cs.degree() - 2;
+        let permutation_products = (cs.permutation.columns.len() + chunk_len - 1) / chunk_len;
+        let lookups = cs.lookups.len();
+        let shuffle_products = cs.shuffles.group(cs.degree()).len();
+
+        let opening = if use_gwc {
+            // one batch per distinct point
+            let mut rotations = cs
+                .instance_queries
+                .iter()
+                .map(|(_, at)| at.0)
+                .chain(cs.advice_queries.iter().map(|(_, at)| at.0))
+                .chain(cs.fixed_queries.iter().map(|(_, at)| at.0))
+                .collect::<BTreeSet<_>>();
+            // h, the random poly and the permutation polys are opened at x
+            rotations.insert(0);
+            if permutation_products > 0 {
+                rotations.insert(1);
+            }
+            if permutation_products > 1 {
+                rotations.insert(-((cs.blinding_factors() + 1) as i32));
+            }
+            if lookups > 0 {
+                rotations.insert(1);
+                rotations.insert(-1);
+            }
+            if shuffle_products > 0 {
+                rotations.insert(1);
+            }
+            rotations.len()
+        } else {
+            // h(X) and the final quotient
+            2
+        };
+
+        let evaluations = cs.instance_queries.len()
+            + cs.advice_queries.len()
+            + cs.fixed_queries.len()
+            // random poly
+            + 1
+            + cs.permutation.columns.len()
+            // at x and x_next, all but the last at the last usable row
+            + (3 * permutation_products).saturating_sub(1)
+            + 5 * lookups
+            + 2 * shuffle_products;
+
+        CommitmentCounts {
+            instance: cs.num_instance_columns,
+            advice: cs.num_advice_columns,
+            lookup_permuted: 2 * lookups,
+            permutation_products,
+            lookup_products: lookups,
+            shuffle_products,
+            random_poly: 1,
+            h_pieces: vk.domain.quotient_poly_degree as usize,
+            opening,
+            evaluations,
+        }
+    }
+
+    /// Points written to the proof.
+    pub fn points(&self) -> usize {
+        self.advice
+            + self.lookup_permuted
+            + self.permutation_products
+            + self.lookup_products
+            + self.shuffle_products
+            + self.random_poly
+            + self.h_pieces
+            + self.opening
+    }
+}
+
+/// A run of the transcript, in the order the prover writes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptSection {
+    /// Points absorbed by both sides, not part of the proof.
+    CommonPoints(&'static str, usize),
+    Points(&'static str, usize),
+    Scalars(&'static str, usize),
+    Challenge(Challenge),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptLayout {
+    pub sections: Vec<TranscriptSection>,
+}
+
+impl TranscriptLayout {
+    /// Layout of a single-instance proof of `create_proof_from_advices` with
+    /// the gwc or shplonk multiopen.
+    pub fn new<C: CurveAffine>(vk: &VerifyingKey<C>, use_gwc: bool) -> Self {
+        use TranscriptSection as S;
+
+        let counts = CommitmentCounts::new(vk, use_gwc);
+        let mut sections = vec![
+            S::CommonPoints("instance", counts.instance),
+            S::Points("advice", counts.advice),
+            S::Challenge(Challenge::Theta),
+            S::Points("lookup permuted", counts.lookup_permuted),
+            S::Challenge(Challenge::Beta),
+            S::Challenge(Challenge::Gamma),
+            S::Points("permutation products", counts.permutation_products),
+            S::Points("lookup products", counts.lookup_products),
+            S::Points("shuffle products", counts.shuffle_products),
+            S::Points("random poly", counts.random_poly),
+            S::Challenge(Challenge::Y),
+            S::Points("h pieces", counts.h_pieces),
+            S::Challenge(Challenge::X),
+            S::Scalars("evaluations", counts.evaluations),
+        ];
+        if use_gwc {
+            sections.extend([
+                S::Challenge(Challenge::Opening(0)),
+                S::Points("opening", counts.opening),
+            ]);
+        } else {
+            sections.extend([
+                S::Challenge(Challenge::Opening(0)),
+                S::Challenge(Challenge::Opening(1)),
+                S::Points("opening h", 1),
+                S::Challenge(Challenge::Opening(2)),
+                S::Points("opening quotient", 1),
+            ]);
+        }
+
+        TranscriptLayout { sections }
+    }
+
+    /// Points written to the proof.
+    pub fn points(&self) -> usize {
+        self.sections
+            .iter()
+            .map(|section| match section {
+                TranscriptSection::Points(_, n) => *n,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    pub fn scalars(&self) -> usize {
+        self.sections
+            .iter()
+            .map(|section| match section {
+                TranscriptSection::Scalars(_, n) => *n,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Size in bytes of the proof over curve `C`, points and scalars in their canonical encoding.
+    pub fn proof_size<C: CurveAffine>(&self) -> usize {
+        let point = <C as GroupEncoding>::Repr::default().as_ref().len();
+        let scalar = <C::Scalar as PrimeField>::Repr::default().as_ref().len();
+        self.points() * point + self.scalars() * scalar
+    }
+}
+
+/// Rejects a proof whose length doesn't match the transcript layout of `vk`,
+/// before running the verifier on it.
+pub fn check_proof_size<C: CurveAffine>(
+    vk: &VerifyingKey<C>,
+    proof: &[u8],
+    use_gwc: bool,
+) -> Result<(), Error> {
+    let expected = TranscriptLayout::new(vk, use_gwc).proof_size::<C>();
+    if proof.len() != expected {
+        return Err(Error::InvalidInput(format!(
+            "proof is {} bytes, the verifying key expects {}",
+            proof.len(),
+            expected
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,289 @@
+//! Witnesses on disk, so one generated on another machine can be proven on a
+//! GPU box and a failing proof can be reproduced offline.
+//!
+//! A file starts with `ZKWW`, a version byte, `k` and a digest of the
+//! verifying key, followed by the instance and advice columns. Each column is
+//! stored up to its last nonzero row and zero-padded again on load. Scalars
+//! are written in their canonical encoding (`PrimeField::to_repr`), and a
+//! value outside the field is rejected on load. `map_witness` maps the file
+//! instead of reading it, decoding the columns straight from the page cache.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::fd::AsRawFd as _;
+use std::path::Path;
+use std::sync::Arc;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::arithmetic::FieldExt;
+use halo2_proofs::pairing::group::ff::PrimeField as _;
+use halo2_proofs::plonk::ProvingKey;
+use halo2_proofs::transcript::Blake2bWrite;
+use halo2_proofs::transcript::Challenge255;
+use halo2_proofs::transcript::Transcript as _;
+use libc::c_void;
+use libc::madvise;
+use libc::mmap;
+use libc::munmap;
+use libc::MADV_SEQUENTIAL;
+use libc::MAP_FAILED;
+use libc::MAP_PRIVATE;
+use libc::PROT_READ;
+use rayon::iter::IndexedParallelIterator as _;
+use rayon::iter::IntoParallelRefMutIterator as _;
+use rayon::iter::ParallelIterator as _;
+use rayon::slice::ParallelSlice as _;
+
+use crate::hugetlb::HugePageAllocator;
+use crate::Error;
+
+const MAGIC: &[u8; 4] = b"ZKWW";
+// 1 stored scalars in their in-memory form
+const VERSION: u8 = 2;
+// scalars encoded or decoded at a time
+const CHUNK: usize = 1 << 16;
+
+/// Instances and advices of a proof, as read by `read_witness`.
+pub struct Witness<F: FieldExt> {
+    pub instances: Vec<Vec<F>>,
+    pub advices: Arc<Vec<Vec<F, HugePageAllocator>>>,
+}
+
+impl<F: FieldExt> Witness<F> {
+    /// The instances as `create_proof_from_advices` takes them.
+    pub fn instance_slices(&self) -> Vec<&[F]> {
+        self.instances.iter().map(|x| &x[..]).collect()
+    }
+}
+
+// the first challenge of a transcript that absorbed the verifying key
+pub(crate) fn vk_digest<C: CurveAffine>(pk: &ProvingKey<C>) -> Result<[u8; 32], Error> {
+    let mut transcript = Blake2bWrite::<_, C, Challenge255<C>>::init(vec![]);
+    pk.vk.hash_into(&mut transcript)?;
+    let digest: C::Scalar = *transcript.squeeze_challenge_scalar::<()>();
+    let mut bytes = [0u8; 32];
+    let repr = digest.to_repr();
+    let len = repr.as_ref().len().min(32);
+    bytes[..len].copy_from_slice(&repr.as_ref()[..len]);
+    Ok(bytes)
+}
+
+fn repr_len<F: FieldExt>() -> usize {
+    F::Repr::default().as_ref().len()
+}
+
+fn write_column<F: FieldExt, W: Write>(writer: &mut W, column: &[F]) -> io::Result<()> {
+    let zero = F::zero();
+    let len = column.iter().rposition(|x| *x != zero).map_or(0, |x| x + 1);
+    writer.write_all(&(len as u64).to_le_bytes())?;
+    let mut bytes = Vec::with_capacity(CHUNK.min(len) * repr_len::<F>());
+    for chunk in column[..len].chunks(CHUNK) {
+        bytes.clear();
+        for x in chunk {
+            bytes.extend_from_slice(x.to_repr().as_ref());
+        }
+        writer.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+// rows of the next column, at most `size`
+fn read_len<R: Read>(reader: &mut R, size: usize) -> Result<usize, Error> {
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len) as usize;
+    if len > size {
+        return Err(Error::InvalidInput(format!(
+            "witness column of {} rows, the domain has {}",
+            len, size
+        )));
+    }
+    Ok(len)
+}
+
+fn decode_scalars<F: FieldExt>(bytes: &[u8], column: &mut [F]) -> Result<(), Error> {
+    column
+        .par_iter_mut()
+        .zip(bytes.par_chunks(repr_len::<F>()))
+        .try_for_each(|(x, bytes)| {
+            let mut repr = F::Repr::default();
+            repr.as_mut().copy_from_slice(bytes);
+            *x = Option::from(F::from_repr(repr)).ok_or_else(|| {
+                Error::InvalidInput("witness holds a value outside the field".to_string())
+            })?;
+            Ok(())
+        })
+}
+
+fn read_scalars<F: FieldExt, R: Read>(reader: &mut R, column: &mut [F]) -> Result<(), Error> {
+    let mut bytes = vec![0u8; CHUNK.min(column.len()) * repr_len::<F>()];
+    for chunk in column.chunks_mut(CHUNK) {
+        let bytes = &mut bytes[..chunk.len() * repr_len::<F>()];
+        reader.read_exact(bytes)?;
+        decode_scalars(bytes, chunk)?;
+    }
+    Ok(())
+}
+
+// decodes from the mapping in place of copying into a buffer first
+fn take_scalars<F: FieldExt>(reader: &mut &[u8], column: &mut [F]) -> Result<(), Error> {
+    let len = column.len() * repr_len::<F>();
+    if reader.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let (bytes, rest) = reader.split_at(len);
+    decode_scalars(bytes, column)?;
+    *reader = rest;
+    Ok(())
+}
+
+/// Writes the witness of a proof with `pk`.
+pub fn write_witness<C: CurveAffine, W: Write>(
+    writer: &mut W,
+    pk: &ProvingKey<C>,
+    instances: &[&[C::Scalar]],
+    advices: &[Vec<C::Scalar, HugePageAllocator>],
+) -> Result<(), Error> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&pk.get_vk().domain.k().to_le_bytes())?;
+    writer.write_all(&vk_digest(pk)?)?;
+    writer.write_all(&(instances.len() as u32).to_le_bytes())?;
+    for instance in instances {
+        write_column(writer, instance)?;
+    }
+    writer.write_all(&(advices.len() as u32).to_le_bytes())?;
+    for advice in advices {
+        write_column(writer, advice)?;
+    }
+    Ok(())
+}
+
+/// Reads a witness written by `write_witness` for the same circuit as `pk`,
+/// advices padded to the domain in huge page buffers.
+pub fn read_witness<C: CurveAffine, R: Read>(
+    reader: &mut R,
+    pk: &ProvingKey<C>,
+) -> Result<Witness<C::Scalar>, Error> {
+    parse_witness(reader, pk, read_scalars)
+}
+
+// a read-only private mapping of a file, unmapped on drop
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// `read_witness` of the file at `path`, mapped rather than read so its
+/// columns are decoded in parallel from the page cache without a copy.
+pub fn map_witness<C: CurveAffine>(
+    path: impl AsRef<Path>,
+    pk: &ProvingKey<C>,
+) -> Result<Witness<C::Scalar>, Error> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len() as usize;
+    // mmap rejects empty mappings
+    if len == 0 {
+        return Err(Error::InvalidInput("not a zkwasm witness".to_string()));
+    }
+    let ptr = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            PROT_READ,
+            MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == MAP_FAILED {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mapping = Mapping { ptr, len };
+    unsafe {
+        madvise(mapping.ptr, mapping.len, MADV_SEQUENTIAL);
+    }
+    let mut bytes = unsafe { std::slice::from_raw_parts(mapping.ptr as *const u8, mapping.len) };
+    parse_witness(&mut bytes, pk, take_scalars)
+}
+
+fn parse_witness<C: CurveAffine, R: Read>(
+    reader: &mut R,
+    pk: &ProvingKey<C>,
+    read_scalars: impl Fn(&mut R, &mut [C::Scalar]) -> Result<(), Error>,
+) -> Result<Witness<C::Scalar>, Error> {
+    let mut header = [0u8; 41];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(Error::InvalidInput("not a zkwasm witness".to_string()));
+    }
+    if header[4] != VERSION {
+        return Err(Error::InvalidInput(format!(
+            "unsupported witness version {}",
+            header[4]
+        )));
+    }
+    let k = u32::from_le_bytes(header[5..9].try_into().unwrap());
+    if k != pk.get_vk().domain.k() {
+        return Err(Error::InvalidInput(format!(
+            "witness is for k = {}, the proving key has k = {}",
+            k,
+            pk.get_vk().domain.k()
+        )));
+    }
+    if header[9..] != vk_digest(pk)? {
+        return Err(Error::InvalidInput(
+            "witness is for another circuit".to_string(),
+        ));
+    }
+    let size = 1usize << k;
+    let cs = &pk.get_vk().cs;
+
+    let mut count = [0u8; 4];
+    reader.read_exact(&mut count)?;
+    if u32::from_le_bytes(count) as usize != cs.num_instance_columns {
+        return Err(Error::InvalidInput(format!(
+            "witness has {} instance columns, circuit has {}",
+            u32::from_le_bytes(count),
+            cs.num_instance_columns
+        )));
+    }
+    let mut instances = vec![];
+    for _ in 0..cs.num_instance_columns {
+        let mut instance = vec![C::Scalar::zero(); read_len(reader, size)?];
+        read_scalars(reader, &mut instance[..])?;
+        instances.push(instance);
+    }
+
+    reader.read_exact(&mut count)?;
+    if u32::from_le_bytes(count) as usize != cs.num_advice_columns {
+        return Err(Error::InvalidInput(format!(
+            "witness has {} advice columns, circuit has {}",
+            u32::from_le_bytes(count),
+            cs.num_advice_columns
+        )));
+    }
+    let mut advices = vec![];
+    for _ in 0..cs.num_advice_columns {
+        let mut advice = Vec::new_in(HugePageAllocator);
+        advice.resize(size, C::Scalar::zero());
+        let len = read_len(reader, size)?;
+        read_scalars(reader, &mut advice[..len])?;
+        advices.push(advice);
+    }
+
+    Ok(Witness {
+        instances,
+        advices: Arc::new(advices),
+    })
+}
@@ -0,0 +1,103 @@
+//! Proves with kernels that only cover commitments and ntts, the path curves
+//! like the Pasta cycle take: the remaining phases run on the host. The curve
+//! is registered process-wide, so this runs in its own test binary.
+
+use std::sync::Arc;
+
+use halo2_proofs::arithmetic::CurveAffine;
+use halo2_proofs::pairing::bn256::G1Affine;
+use zkwasm_prover::config::ProverConfig;
+use zkwasm_prover::cuda::bn254::FieldOp;
+use zkwasm_prover::cuda::curve::register_gpu_curve;
+use zkwasm_prover::cuda::curve::Bn254Kernels;
+use zkwasm_prover::cuda::curve::GpuCurve;
+use zkwasm_prover::device::cuda::CudaDevice;
+use zkwasm_prover::device::cuda::CudaDeviceBufRaw;
+use zkwasm_prover::device::DeviceResult;
+
+// the bn254 kernels, without the full prover ones
+struct CommitOnly;
+
+impl GpuCurve<G1Affine> for CommitOnly {
+    fn name(&self) -> &'static str {
+        "bn254 commit only"
+    }
+
+    fn ntt_prepare(
+        &self,
+        device: &CudaDevice,
+        omega: <G1Affine as CurveAffine>::ScalarExt,
+        len_log: usize,
+    ) -> DeviceResult<(Arc<CudaDeviceBufRaw>, Arc<CudaDeviceBufRaw>)> {
+        GpuCurve::<G1Affine>::ntt_prepare(&Bn254Kernels, device, omega, len_log)
+    }
+
+    fn ntt(
+        &self,
+        device: &CudaDevice,
+        s_buf: &mut CudaDeviceBufRaw,
+        tmp_buf: &mut CudaDeviceBufRaw,
+        omegas_buf: &CudaDeviceBufRaw,
+        pq_buf: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()> {
+        GpuCurve::<G1Affine>::ntt(
+            &Bn254Kernels,
+            device,
+            s_buf,
+            tmp_buf,
+            omegas_buf,
+            pq_buf,
+            len_log,
+        )
+    }
+
+    fn batch_intt(
+        &self,
+        device: &CudaDevice,
+        values: Vec<&mut [<G1Affine as CurveAffine>::ScalarExt]>,
+        omegas_buf: &CudaDeviceBufRaw,
+        pq_buf: &CudaDeviceBufRaw,
+        divisor: &CudaDeviceBufRaw,
+        len_log: usize,
+    ) -> DeviceResult<()> {
+        GpuCurve::<G1Affine>::batch_intt(
+            &Bn254Kernels,
+            device,
+            values,
+            omegas_buf,
+            pq_buf,
+            divisor,
+            len_log,
+        )
+    }
+
+    fn batch_msm(
+        &self,
+        device: &CudaDevice,
+        p_buf: &CudaDeviceBufRaw,
+        s_buf: [&CudaDeviceBufRaw; 2],
+        values: Vec<&[<G1Affine as CurveAffine>::ScalarExt]>,
+        len: usize,
+    ) -> DeviceResult<Vec<G1Affine>> {
+        GpuCurve::<G1Affine>::batch_msm(&Bn254Kernels, device, p_buf, s_buf, values, len)
+    }
+
+    fn field_op(
+        &self,
+        device: &CudaDevice,
+        res: &CudaDeviceBufRaw,
+        l: &CudaDeviceBufRaw,
+        r: &CudaDeviceBufRaw,
+        size: usize,
+        op: FieldOp,
+    ) -> DeviceResult<()> {
+        GpuCurve::<G1Affine>::field_op(&Bn254Kernels, device, res, l, r, size, op)
+    }
+}
+
+#[test]
+fn test_commit_only_curve_proof() {
+    register_gpu_curve::<G1Affine>(Arc::new(CommitOnly));
+    zkwasm_prover::selftest::prove_and_verify(&ProverConfig::default()).unwrap();
+}